@@ -0,0 +1,68 @@
+//! Budget accounting simulator, for what-if analysis of capacity and
+//! scheduling policies without writing Rust. Replays a JSON scenario, see
+//! `pdslib::simulator::scenario`, and prints allocation and utilization
+//! statistics.
+//!
+//! Usage: `pdslib-sim <scenario.json> [--json]`. With `--json`, prints the
+//! result of every query and the utilization of every filter instead.
+
+use std::{env, path::PathBuf, process::ExitCode};
+
+use anyhow::{bail, Result};
+use pdslib::simulator::scenario::Scenario;
+
+const USAGE: &str = "Usage: pdslib-sim <scenario.json> [--json]";
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {err:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run() -> Result<()> {
+    let mut scenario_path = None;
+    let mut json = false;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--json" => json = true,
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(());
+            }
+            _ if scenario_path.is_none() => {
+                scenario_path = Some(PathBuf::from(arg))
+            }
+            _ => bail!("Unexpected argument {arg:?}\n{USAGE}"),
+        }
+    }
+    let Some(scenario_path) = scenario_path else {
+        bail!("Missing scenario\n{USAGE}");
+    };
+
+    let scenario = Scenario::from_file(&scenario_path)?;
+    let results = scenario.run()?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&results)?);
+        return Ok(());
+    }
+
+    let n_allocated = results.queries.iter().filter(|q| q.allocated).count();
+    println!("Queries: {}", results.queries.len());
+    println!(
+        "Allocated: {n_allocated} ({:.1}%)",
+        100.0 * results.allocation_rate()
+    );
+    println!(
+        "Global utilization: {:.1}%",
+        100.0 * results.global_utilization()
+    );
+    println!("Out-of-budget filters:");
+    for (filter_kind, count) in results.oob_filter_counts() {
+        println!("  {filter_kind}: {count}");
+    }
+    Ok(())
+}
@@ -0,0 +1,254 @@
+use alloc::format;
+use core::ops::{Add, Sub};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    budget::traits::{Budget, BudgetOps, Scale},
+    error::PdsError,
+    util::math,
+};
+
+/// Number of budget units per unit of epsilon.
+pub const MICROS_PER_EPSILON: u64 = 1_000_000;
+
+/// A fixed-point budget for pure differential privacy, counted in
+/// micro-epsilons, with `u64::MAX` as infinite budget.
+///
+/// Unlike `PureDPBudget`, additions and subtractions are exact, so repeated
+/// consumptions and refunds don't drift, and a filter is full exactly when
+/// the consumed budgets add up to its capacity. Arithmetic saturates instead
+/// of overflowing.
+///
+/// Conversions from epsilons round in the conservative direction: losses
+/// round up, so that any nonzero loss costs at least one micro-epsilon, and
+/// capacities round down.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Default,
+    Serialize,
+    Deserialize,
+)]
+#[serde(transparent)]
+pub struct FixedPointBudget(u64);
+
+impl FixedPointBudget {
+    pub const fn from_micros(micros: u64) -> Self {
+        Self(micros)
+    }
+
+    pub const fn micros(&self) -> u64 {
+        self.0
+    }
+
+    /// Converts the epsilon of a loss, rounded up to the next micro-epsilon.
+    /// See `from_epsilon` for the rejected epsilons.
+    pub fn loss_from_epsilon(epsilon: f64) -> Result<Self, PdsError> {
+        Self::from_epsilon(epsilon, math::ceil)
+    }
+
+    /// Converts the epsilon of a capacity, rounded down to the previous
+    /// micro-epsilon. See `from_epsilon` for the rejected epsilons.
+    pub fn capacity_from_epsilon(epsilon: f64) -> Result<Self, PdsError> {
+        Self::from_epsilon(epsilon, math::floor)
+    }
+
+    /// Converts an epsilon, rounded to a micro-epsilon with `round`. Infinite
+    /// epsilons are infinite budgets, and epsilons too large to be
+    /// represented are rejected, as are negative or NaN epsilons.
+    fn from_epsilon(
+        epsilon: f64,
+        round: fn(f64) -> f64,
+    ) -> Result<Self, PdsError> {
+        if epsilon.is_nan() || epsilon < 0.0 {
+            return Err(PdsError::InvalidRequest(format!(
+                "can't convert epsilon {epsilon} to a fixed-point budget"
+            )));
+        }
+        if epsilon.is_infinite() {
+            return Ok(Self::infinity());
+        }
+
+        let micros = round(epsilon * MICROS_PER_EPSILON as f64);
+        if micros >= u64::MAX as f64 {
+            return Err(PdsError::InvalidRequest(format!(
+                "epsilon {epsilon} is too large for a fixed-point budget"
+            )));
+        }
+        Ok(Self(micros as u64))
+    }
+
+    /// Converts back to an epsilon, e.g. to compute noise scales.
+    pub fn to_epsilon(&self) -> f64 {
+        match self.is_infinite() {
+            true => f64::INFINITY,
+            false => self.0 as f64 / MICROS_PER_EPSILON as f64,
+        }
+    }
+}
+
+impl From<FixedPointBudget> for f64 {
+    fn from(budget: FixedPointBudget) -> Self {
+        budget.to_epsilon()
+    }
+}
+
+impl Add for FixedPointBudget {
+    type Output = Self;
+
+    /// Saturates at infinity.
+    fn add(self, other: Self) -> Self {
+        Self(self.0.saturating_add(other.0))
+    }
+}
+
+impl Sub for FixedPointBudget {
+    type Output = Self;
+
+    /// Clamped at zero. Infinity minus a finite budget is still infinity.
+    fn sub(self, other: Self) -> Self {
+        match self.is_infinite() {
+            true => self,
+            false => Self(self.0.saturating_sub(other.0)),
+        }
+    }
+}
+
+impl Budget for FixedPointBudget {}
+
+impl FixedPointBudget {
+    fn scale_with(&self, factor: f64, round: fn(f64) -> f64) -> Self {
+        if self.is_infinite() {
+            return *self;
+        }
+        // Saturating cast, so large factors give infinite budgets.
+        Self(round(self.0 as f64 * factor) as u64)
+    }
+}
+
+impl Scale for FixedPointBudget {
+    fn scale(&self, factor: f64) -> Self {
+        self.scale_with(factor, math::ceil)
+    }
+
+    fn scale_capacity(&self, factor: f64) -> Self {
+        self.scale_with(factor, math::floor)
+    }
+}
+
+impl BudgetOps for FixedPointBudget {
+    fn zero() -> Self {
+        Self(0)
+    }
+
+    fn infinity() -> Self {
+        Self(u64::MAX)
+    }
+
+    fn is_infinite(&self) -> bool {
+        self.0 == u64::MAX
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::budget::{
+        pure_dp_filter::PureDPBudgetFilter,
+        traits::{Filter, FilterStatus},
+    };
+
+    #[test]
+    fn test_conversions() -> Result<(), PdsError> {
+        let budget = FixedPointBudget::loss_from_epsilon(0.1)?;
+        assert_eq!(budget.micros(), 100_000);
+        assert_eq!(budget.to_epsilon(), 0.1);
+        assert_eq!(
+            FixedPointBudget::capacity_from_epsilon(1.5)?.micros(),
+            1_500_000
+        );
+        assert_eq!(f64::from(FixedPointBudget::from_micros(250_000)), 0.25);
+
+        // Losses round up, so that tiny losses are still charged, and
+        // capacities round down.
+        assert_eq!(FixedPointBudget::loss_from_epsilon(1e-7)?.micros(), 1);
+        assert_eq!(FixedPointBudget::capacity_from_epsilon(1e-7)?.micros(), 0);
+        assert_eq!(
+            FixedPointBudget::loss_from_epsilon(0.3 - 0.2)?.micros(),
+            100_000
+        );
+        assert_eq!(
+            FixedPointBudget::capacity_from_epsilon(0.3 - 0.2)?.micros(),
+            99_999
+        );
+        assert_eq!(FixedPointBudget::loss_from_epsilon(0.0)?.micros(), 0);
+
+        let infinite = FixedPointBudget::loss_from_epsilon(f64::INFINITY)?;
+        assert!(infinite.is_infinite());
+        assert_eq!(infinite.to_epsilon(), f64::INFINITY);
+
+        assert!(FixedPointBudget::loss_from_epsilon(-0.1).is_err());
+        assert!(FixedPointBudget::capacity_from_epsilon(f64::NAN).is_err());
+        assert!(FixedPointBudget::loss_from_epsilon(1e20).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_budget_ops() -> Result<(), PdsError> {
+        let budget = FixedPointBudget::capacity_from_epsilon(1.0)?;
+        let quarter = FixedPointBudget::loss_from_epsilon(0.25)?;
+        assert_eq!(budget.scale(0.25), quarter);
+        assert_eq!(budget.scale_capacity(0.25), quarter);
+
+        // Scaled losses round up, scaled capacities round down.
+        let third = 1.0 / 3.0;
+        assert_eq!(budget.scale(third).micros(), 333_334);
+        assert_eq!(budget.scale_capacity(third).micros(), 333_333);
+        assert_eq!(budget.saturating_sub(&quarter).to_epsilon(), 0.75);
+        assert_eq!(quarter - budget, FixedPointBudget::zero());
+
+        let infinite = FixedPointBudget::infinity();
+        assert!((infinite + budget).is_infinite());
+        assert!((infinite - budget).is_infinite());
+        assert!(infinite.scale(0.5).is_infinite());
+        assert!(budget.scale(f64::INFINITY).is_infinite());
+        assert_eq!(infinite.min_budget(&budget), budget);
+        Ok(())
+    }
+
+    /// Ten consumptions of 0.1 fill a capacity of 1.0 exactly, and refunds
+    /// give back exactly what was consumed, unlike with floats.
+    #[test]
+    fn test_no_drift() -> Result<(), PdsError> {
+        let tenth = FixedPointBudget::loss_from_epsilon(0.1)?;
+        let capacity = FixedPointBudget::capacity_from_epsilon(1.0)?;
+        let mut filter = PureDPBudgetFilter::new(capacity)?;
+        for _ in 0..10 {
+            assert_eq!(filter.try_consume(&tenth)?, FilterStatus::Continue);
+        }
+        assert_eq!(filter.consumed, capacity);
+        let smallest = FixedPointBudget::from_micros(1);
+        assert_eq!(filter.try_consume(&smallest)?, FilterStatus::OutOfBudget);
+
+        for _ in 0..10 {
+            filter.refund(&tenth)?;
+        }
+        assert_eq!(filter.consumed, FixedPointBudget::zero());
+
+        // The same consumptions with floats leave budget unaccounted for.
+        let mut float_filter = PureDPBudgetFilter::new(1.0)?;
+        for _ in 0..10 {
+            float_filter.try_consume(&0.1)?;
+        }
+        assert_ne!(float_filter.consumed, 1.0);
+        assert_eq!(float_filter.try_consume(&1e-16)?, FilterStatus::Continue);
+        Ok(())
+    }
+}
@@ -1,53 +1,228 @@
 use std::{fmt::Debug, hash::Hash};
 
+use log::warn;
 use serde::{ser::SerializeStruct, Serialize};
 
 use crate::{
-    budget::traits::{Filter, FilterCapacities, FilterStorage},
+    budget::{
+        integrity::{
+            FilterSealer, IntegrityPolicy, SealedFilter, SealedFilterStorage,
+        },
+        reservation::{Reservation, ReservationToken},
+        traits::{
+            BudgetOps, EpochFilterId, Filter, FilterCapacities, FilterStorage,
+        },
+    },
+    error::PdsError,
     util::hashmap::HashMap,
 };
 
+/// Epoch of the filters of a storage.
+type FilterEpoch<C> =
+    <<C as FilterCapacities>::FilterId as EpochFilterId>::EpochId;
+
+/// Sealed version of a storage with capacities `C` and filters `F`.
+type SealedStorage<C, F> = SealedFilterStorage<
+    <C as FilterCapacities>::FilterId,
+    F,
+    FilterEpoch<C>,
+    <C as FilterCapacities>::Budget,
+>;
+
 /// Simple implementation of FilterStorage using a HashMap.
 /// Works for any Filter that implements the Filter trait.
 #[derive(Debug, Default)]
 pub struct HashMapFilterStorage<F, C>
 where
     C: FilterCapacities,
+    C::FilterId: EpochFilterId,
     F: Filter<C::Budget>,
 {
     capacities: C,
     filters: HashMap<C::FilterId, F>,
+
+    /// Capacity policy version under which each filter was created or last
+    /// tightened.
+    policy_versions: HashMap<C::FilterId, u64>,
+
+    /// Pending budget reservations.
+    reservations:
+        HashMap<ReservationToken, Reservation<C::FilterId, C::Budget>>,
+
+    /// Budget borrowed from filters that are not initialized yet.
+    repayments: HashMap<C::FilterId, C::Budget>,
+
+    /// Total budget carried over into each filter from older epochs.
+    carryovers: HashMap<C::FilterId, C::Budget>,
+
+    /// Epochs strictly older than this one have been pruned.
+    pruned_before: Option<FilterEpoch<C>>,
+
+    /// Number of writes so far.
+    generation: u64,
+}
+
+impl<F, C> HashMapFilterStorage<F, C>
+where
+    C: FilterCapacities,
+    C::FilterId: EpochFilterId + Eq + Hash,
+    F: Filter<C::Budget>,
+{
+    /// Get the capacity policy version under which the filter with the given
+    /// ID was created or last tightened. Returns None if the filter has not
+    /// been set yet.
+    pub fn policy_version(&self, filter_id: &C::FilterId) -> Option<u64> {
+        self.policy_versions.get(filter_id).copied()
+    }
+}
+
+impl<F, C> HashMapFilterStorage<F, C>
+where
+    F: Filter<C::Budget, Error = PdsError> + Clone + Serialize,
+    C: FilterCapacities<Error = PdsError>,
+    C::FilterId: EpochFilterId<EpochId: Serialize>
+        + Clone
+        + Eq
+        + Hash
+        + Debug
+        + Serialize,
+    C::Budget: BudgetOps + Serialize,
+{
+    /// Tags each filter with the device key of `sealer`, to persist them
+    /// along with the pruning watermark and the carryovers. Repayments are
+    /// settled first, i.e. lenders are sealed with their capacity minus
+    /// what they lent, whether they were initialized or not, so that the
+    /// tags cover them too.
+    ///
+    /// NOTE: pending reservations are not persisted. Their budget stays
+    /// deducted once the filters are loaded back, as if they were abandoned.
+    pub fn seal(
+        &self,
+        sealer: &FilterSealer,
+    ) -> Result<SealedStorage<C, F>, PdsError> {
+        let mut filters = vec![];
+        for (filter_id, filter) in &self.filters {
+            let mut filter = filter.clone();
+            if let Some(repayment) = self.repayments.get(filter_id) {
+                let capacity = self.capacities.capacity(filter_id)?;
+                filter.tighten_capacity(&capacity.saturating_sub(repayment))?;
+            }
+            filters.push(sealer.seal_filter(
+                filter_id.clone(),
+                filter,
+                self.policy_version(filter_id),
+            )?);
+        }
+        for (filter_id, repayment) in &self.repayments {
+            if self.filters.contains_key(filter_id) {
+                continue;
+            }
+            let capacity = self.capacities.capacity(filter_id)?;
+            filters.push(sealer.seal_filter(
+                filter_id.clone(),
+                F::new(capacity.saturating_sub(repayment))?,
+                Some(self.capacities.policy_version()),
+            )?);
+        }
+        let carryovers = self
+            .carryovers
+            .iter()
+            .map(|(filter_id, budget)| (filter_id.clone(), budget.clone()))
+            .collect();
+        sealer.seal_storage(filters, self.pruned_before.clone(), carryovers)
+    }
+
+    /// Loads filters persisted with `seal`, after checking their tags.
+    /// Mismatching filters are handled according to the sealer's policy.
+    pub fn unseal(
+        capacities: C,
+        sealed: SealedStorage<C, F>,
+        sealer: &FilterSealer,
+    ) -> Result<Self, PdsError> {
+        sealer.verify_storage(&sealed)?;
+
+        let mut storage = Self::new(capacities)?;
+        storage.pruned_before = sealed.pruned_before;
+        storage.carryovers = sealed.carryovers.into_iter().collect();
+        for sealed_filter in sealed.filters {
+            let SealedFilter {
+                filter_id,
+                filter,
+                policy_version,
+                ..
+            } = match sealer.verify_filter(&sealed_filter)? {
+                true => sealed_filter,
+                false => storage.tampered_filter(sealer, sealed_filter)?,
+            };
+            storage.filters.insert(filter_id.clone(), filter);
+            if let Some(policy_version) = policy_version {
+                storage.policy_versions.insert(filter_id, policy_version);
+            }
+        }
+        Ok(storage)
+    }
+
+    /// Applies the integrity policy to a filter whose tag doesn't match.
+    fn tampered_filter(
+        &self,
+        sealer: &FilterSealer,
+        sealed_filter: SealedFilter<C::FilterId, F>,
+    ) -> Result<SealedFilter<C::FilterId, F>, PdsError> {
+        let filter_id = sealed_filter.filter_id;
+        match sealer.policy {
+            IntegrityPolicy::FailClosed => Err(PdsError::IntegrityViolation(
+                format!("filter {filter_id:?} doesn't match its tag"),
+            )),
+            IntegrityPolicy::ZeroRemaining => {
+                warn!(
+                    "Filter {filter_id:?} doesn't match its tag, loading it with zero remaining budget"
+                );
+                Ok(SealedFilter {
+                    filter_id,
+                    filter: F::new(C::Budget::zero())?,
+                    policy_version: Some(self.capacities.policy_version()),
+                    tag: sealed_filter.tag,
+                })
+            }
+        }
+    }
 }
 
 impl<F, C, FID> Serialize for HashMapFilterStorage<F, C>
 where
     C: FilterCapacities<FilterId = FID> + Serialize,
     F: Filter<C::Budget> + Serialize,
-    FID: Serialize + Eq + Hash + Debug,
+    C::Budget: Serialize,
+    FID: EpochFilterId<EpochId: Serialize> + Serialize + Eq + Hash + Debug,
 {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
         let mut state =
-            serializer.serialize_struct("HashMapFilterStorage", 2)?;
+            serializer.serialize_struct("HashMapFilterStorage", 7)?;
         state.serialize_field("capacities", &self.capacities)?;
         state.serialize_field("filters", &self.filters)?;
+        state.serialize_field("policy_versions", &self.policy_versions)?;
+        state.serialize_field("reservations", &self.reservations)?;
+        state.serialize_field("repayments", &self.repayments)?;
+        state.serialize_field("carryovers", &self.carryovers)?;
+        state.serialize_field("pruned_before", &self.pruned_before)?;
         state.end()
     }
 }
 
 impl<F, C> FilterStorage for HashMapFilterStorage<F, C>
 where
-    F: Filter<C::Budget, Error = anyhow::Error> + Clone,
-    C: FilterCapacities<Error = anyhow::Error>,
-    C::FilterId: Clone + Eq + Hash + Debug,
+    F: Filter<C::Budget, Error = PdsError> + Clone,
+    C: FilterCapacities<Error = PdsError>,
+    C::FilterId: EpochFilterId + Clone + Eq + Hash + Debug,
 {
     type FilterId = C::FilterId;
     type Filter = F;
     type Budget = C::Budget;
     type Capacities = C;
-    type Error = anyhow::Error;
+    type Error = PdsError;
 
     fn new(capacities: Self::Capacities) -> Result<Self, Self::Error>
     where
@@ -56,6 +231,12 @@ where
         let this = Self {
             capacities,
             filters: HashMap::new(),
+            policy_versions: HashMap::new(),
+            reservations: HashMap::new(),
+            repayments: HashMap::new(),
+            carryovers: HashMap::new(),
+            pruned_before: None,
+            generation: 0,
         };
         Ok(this)
     }
@@ -64,6 +245,26 @@ where
         &self.capacities
     }
 
+    fn set_capacities(
+        &mut self,
+        capacities: Self::Capacities,
+        tighten_existing: bool,
+    ) -> Result<(), Self::Error> {
+        self.capacities = capacities;
+        self.generation += 1;
+
+        if tighten_existing {
+            let policy_version = self.capacities.policy_version();
+            for (filter_id, filter) in self.filters.iter_mut() {
+                let capacity = self.capacities.capacity(filter_id)?;
+                filter.tighten_capacity(&capacity)?;
+                self.policy_versions
+                    .insert(filter_id.clone(), policy_version);
+            }
+        }
+        Ok(())
+    }
+
     fn get_filter(
         &mut self,
         filter_id: &Self::FilterId,
@@ -78,8 +279,125 @@ where
         filter: Self::Filter,
     ) -> Result<(), Self::Error> {
         self.filters.insert(filter_id.clone(), filter);
+        self.generation += 1;
+        self.policy_versions
+            .entry(filter_id.clone())
+            .or_insert(self.capacities.policy_version());
         Ok(())
     }
+
+    fn prune(
+        &mut self,
+        is_pruned: &dyn Fn(&Self::FilterId) -> bool,
+    ) -> Result<usize, Self::Error> {
+        let n_filters = self.filters.len();
+        self.filters.retain(|filter_id, _| !is_pruned(filter_id));
+        self.policy_versions
+            .retain(|filter_id, _| !is_pruned(filter_id));
+        self.repayments.retain(|filter_id, _| !is_pruned(filter_id));
+        self.carryovers.retain(|filter_id, _| !is_pruned(filter_id));
+        self.generation += 1;
+        Ok(n_filters - self.filters.len())
+    }
+
+    fn is_empty(&self) -> Result<bool, Self::Error> {
+        Ok(self.filters.is_empty()
+            && self.repayments.is_empty()
+            && self.carryovers.is_empty())
+    }
+
+    fn pruned_before(&self) -> Option<FilterEpoch<C>> {
+        self.pruned_before.clone()
+    }
+
+    fn set_pruned_before(
+        &mut self,
+        epoch_id: FilterEpoch<C>,
+    ) -> Result<(), Self::Error> {
+        self.pruned_before = Some(epoch_id);
+        self.generation += 1;
+        Ok(())
+    }
+
+    fn set_reservation(
+        &mut self,
+        token: ReservationToken,
+        reservation: Reservation<Self::FilterId, Self::Budget>,
+    ) -> Result<(), Self::Error> {
+        self.reservations.insert(token, reservation);
+        self.generation += 1;
+        Ok(())
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn take_reservation(
+        &mut self,
+        token: ReservationToken,
+    ) -> Result<Option<Reservation<Self::FilterId, Self::Budget>>, Self::Error>
+    {
+        self.generation += 1;
+        Ok(self.reservations.remove(&token))
+    }
+
+    fn reservations(
+        &self,
+    ) -> Result<
+        Vec<(ReservationToken, Reservation<Self::FilterId, Self::Budget>)>,
+        Self::Error,
+    > {
+        let reservations = self
+            .reservations
+            .iter()
+            .map(|(token, reservation)| (*token, reservation.clone()))
+            .collect();
+        Ok(reservations)
+    }
+
+    fn set_repayment(
+        &mut self,
+        filter_id: &Self::FilterId,
+        budget: Self::Budget,
+    ) -> Result<(), Self::Error> {
+        self.repayments.insert(filter_id.clone(), budget);
+        self.generation += 1;
+        Ok(())
+    }
+
+    fn take_repayment(
+        &mut self,
+        filter_id: &Self::FilterId,
+    ) -> Result<Option<Self::Budget>, Self::Error> {
+        self.generation += 1;
+        Ok(self.repayments.remove(filter_id))
+    }
+
+    fn get_repayment(
+        &mut self,
+        filter_id: &Self::FilterId,
+    ) -> Result<Option<Self::Budget>, Self::Error> {
+        Ok(self.repayments.get(filter_id).cloned())
+    }
+
+    fn set_carryover(
+        &mut self,
+        filter_id: &Self::FilterId,
+        budget: Self::Budget,
+    ) -> Result<(), Self::Error> {
+        self.carryovers.insert(filter_id.clone(), budget);
+        self.generation += 1;
+        Ok(())
+    }
+
+    fn get_carryover(
+        &mut self,
+        filter_id: &Self::FilterId,
+    ) -> Result<Option<Self::Budget>, Self::Error> {
+        Ok(self.carryovers.get(filter_id).cloned())
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation
+    }
 }
 
 #[cfg(test)]
@@ -103,6 +421,150 @@ mod tests {
             FilterStatus::OutOfBudget,
         );
 
+        // Writes bump the generation, reads don't.
+        let generation = storage.generation();
+        assert!(generation > 0);
+        storage.can_consume(&fid, &1.0)?;
+        assert_eq!(storage.generation(), generation);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_capacities() -> Result<(), anyhow::Error> {
+        let capacities = StaticCapacities::mock();
+        let mut storage: HashMapFilterStorage<PureDPBudgetFilter, _> =
+            HashMapFilterStorage::new(capacities)?;
+
+        let fid: FilterId<i32, ()> = FilterId::Global(1);
+        assert_eq!(storage.try_consume(&fid, &10.0)?, FilterStatus::Continue);
+        assert_eq!(storage.policy_version(&fid), Some(0));
+
+        // Lower the global capacity, without touching existing filters.
+        let mut capacities = StaticCapacities::mock().with_policy_version(1);
+        capacities.global = 12.0;
+        storage.set_capacities(capacities.clone(), false)?;
+        assert_eq!(storage.try_consume(&fid, &5.0)?, FilterStatus::Continue);
+        assert_eq!(storage.policy_version(&fid), Some(0));
+
+        // New filters use the new capacities.
+        let new_fid = FilterId::Global(2);
+        assert_eq!(
+            storage.try_consume(&new_fid, &13.0)?,
+            FilterStatus::OutOfBudget
+        );
+        assert_eq!(storage.policy_version(&new_fid), Some(1));
+
+        // Tighten existing filters too.
+        storage.set_capacities(capacities.with_policy_version(2), true)?;
+        assert_eq!(storage.try_consume(&fid, &0.0)?, FilterStatus::OutOfBudget);
+        assert_eq!(storage.policy_version(&fid), Some(2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune_filters() -> Result<(), anyhow::Error> {
+        let capacities = StaticCapacities::mock();
+        let mut storage: HashMapFilterStorage<PureDPBudgetFilter, _> =
+            HashMapFilterStorage::new(capacities)?;
+
+        for epoch in 1..=3 {
+            let fid: FilterId<u64, String> = FilterId::Global(epoch);
+            storage.try_consume(&fid, &1.0)?;
+            let fid = FilterId::SourceQuota(epoch, "blog.com".to_string());
+            storage.try_consume(&fid, &1.0)?;
+        }
+
+        // Epochs 1 and 2 are pruned, epoch 3 is kept.
+        assert_eq!(storage.prune(&|fid| *fid.epoch_id() < 3)?, 4);
+        assert!(storage.get_filter(&FilterId::Global(2))?.is_none());
+        assert!(storage.get_filter(&FilterId::Global(3))?.is_some());
+
+        // Pruning again is a no-op.
+        assert_eq!(storage.prune(&|fid| *fid.epoch_id() < 3)?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sealed_storage() -> Result<(), anyhow::Error> {
+        let sealer = FilterSealer::new(b"device key");
+        let mut storage: HashMapFilterStorage<PureDPBudgetFilter, _> =
+            HashMapFilterStorage::new(StaticCapacities::mock())?;
+        let fid1: FilterId = FilterId::Global(1);
+        let fid2 = FilterId::Global(2);
+        storage.try_consume(&fid1, &15.0)?;
+        storage.try_consume(&fid2, &5.0)?;
+        let lender = FilterId::PerQuerier(3, "adtech.com".to_string());
+        storage.set_repayment(&lender, 0.25)?;
+        let initialized_lender =
+            FilterId::PerQuerier(2, "adtech.com".to_string());
+        storage.try_consume(&initialized_lender, &0.5)?;
+        storage.set_repayment(&initialized_lender, 0.25)?;
+        storage.set_pruned_before(1)?;
+        storage.set_carryover(&fid2, 0.5)?;
+
+        // Filters, carryovers and the pruning watermark survive a round trip
+        // through disk, and lenders are sealed with their repayment
+        // deducted, even if they were initialized before the loan was
+        // repaid.
+        let json = serde_json::to_string(&storage.seal(&sealer)?)?;
+        let mut loaded = HashMapFilterStorage::<PureDPBudgetFilter, _>::unseal(
+            StaticCapacities::mock(),
+            serde_json::from_str(&json)?,
+            &sealer,
+        )?;
+        assert_eq!(loaded.can_consume(&fid1, &5.0)?, FilterStatus::Continue);
+        assert_eq!(loaded.can_consume(&fid1, &5.1)?, FilterStatus::OutOfBudget);
+        assert_eq!(loaded.policy_version(&fid1), Some(0));
+        assert_eq!(loaded.pruned_before(), Some(1));
+        assert_eq!(loaded.get_carryover(&fid2)?, Some(0.5));
+        assert_eq!(loaded.get_carryover(&fid1)?, None);
+        assert_eq!(
+            loaded.can_consume(&lender, &0.8)?,
+            FilterStatus::OutOfBudget
+        );
+        assert_eq!(
+            loaded.can_consume(&initialized_lender, &0.25)?,
+            FilterStatus::Continue
+        );
+        assert_eq!(
+            loaded.can_consume(&initialized_lender, &0.3)?,
+            FilterStatus::OutOfBudget
+        );
+
+        // Refund budget by editing the store.
+        let mut sealed: SealedFilterStorage<
+            FilterId,
+            PureDPBudgetFilter,
+            u64,
+            f64,
+        > = serde_json::from_str(&json)?;
+        let tampered = sealed
+            .filters
+            .iter_mut()
+            .find(|sealed| sealed.filter_id == fid1)
+            .unwrap();
+        tampered.filter.consumed = 0.0;
+
+        let result = HashMapFilterStorage::<PureDPBudgetFilter, _>::unseal(
+            StaticCapacities::mock(),
+            sealed.clone(),
+            &sealer,
+        );
+        assert!(matches!(result, Err(PdsError::IntegrityViolation(_))));
+
+        // Only the tampered filter is exhausted.
+        let sealer = sealer.with_policy(IntegrityPolicy::ZeroRemaining);
+        let mut loaded = HashMapFilterStorage::<PureDPBudgetFilter, _>::unseal(
+            StaticCapacities::mock(),
+            sealed,
+            &sealer,
+        )?;
+        assert_eq!(loaded.can_consume(&fid1, &0.1)?, FilterStatus::OutOfBudget);
+        assert_eq!(loaded.can_consume(&fid2, &15.0)?, FilterStatus::Continue);
+
         Ok(())
     }
 }
@@ -0,0 +1,319 @@
+//! Integrity protection for persisted filters. A corrupted or hand-edited
+//! store could inflate the remaining budgets, so each persisted filter is
+//! tagged with an HMAC under a device key, and the tags are checked when the
+//! filters are loaded back.
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::error::PdsError;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What to do with persisted filters whose tag doesn't match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub enum IntegrityPolicy {
+    /// Refuse to load the store.
+    #[default]
+    FailClosed,
+
+    /// Load the mismatching filters with zero remaining budget, so they only
+    /// give null reports. The other filters are loaded as usual.
+    ZeroRemaining,
+}
+
+/// Filter persisted along with its ID, its capacity policy version, and a
+/// tag covering all three.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SealedFilter<FID, F> {
+    pub filter_id: FID,
+    pub filter: F,
+    pub policy_version: Option<u64>,
+
+    /// Hex-encoded HMAC-SHA256 tag.
+    pub tag: String,
+}
+
+/// Filters of a storage, persisted with a tag over the list of filter tags,
+/// the pruning watermark and the carryovers. Removing, duplicating or
+/// reordering filters, moving the watermark back, or editing the carryovers
+/// changes that tag.
+///
+/// NOTE: replacing the whole store with an older sealed version is not
+/// detected. Embedders that need rollback protection can keep the generation
+/// of the storage in a monotonic counter.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SealedFilterStorage<FID, F, E, B> {
+    pub filters: Vec<SealedFilter<FID, F>>,
+
+    /// Epochs strictly older than this one have been pruned. Missing from
+    /// stores sealed before it was persisted.
+    #[serde(default)]
+    pub pruned_before: Option<E>,
+
+    /// Total budget carried over into each filter, so that an epoch is not
+    /// topped up again after a restart. Missing from stores sealed before
+    /// they were persisted.
+    #[serde(default = "Vec::new")]
+    pub carryovers: Vec<(FID, B)>,
+
+    pub tag: String,
+}
+
+/// Seals and verifies filters with a device key.
+#[derive(Clone)]
+pub struct FilterSealer {
+    key: Vec<u8>,
+    pub policy: IntegrityPolicy,
+}
+
+impl std::fmt::Debug for FilterSealer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never log the device key.
+        f.debug_struct("FilterSealer")
+            .field("policy", &self.policy)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FilterSealer {
+    pub fn new(device_key: &[u8]) -> Self {
+        Self {
+            key: device_key.to_vec(),
+            policy: IntegrityPolicy::default(),
+        }
+    }
+
+    pub fn with_policy(mut self, policy: IntegrityPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// MAC over the serialization of `fields`. Loaded filters are serialized
+    /// again to be verified, so any format that round-trips them exactly can
+    /// be used on disk.
+    fn mac(&self, fields: &impl Serialize) -> Result<HmacSha256, PdsError> {
+        let bytes = serde_json::to_vec(fields).map_err(|err| {
+            PdsError::StorageError(format!("can't serialize filter: {err}"))
+        })?;
+        let mut mac = HmacSha256::new_from_slice(&self.key)
+            .map_err(|err| PdsError::Internal(format!("invalid key: {err}")))?;
+        mac.update(&bytes);
+        Ok(mac)
+    }
+
+    fn tag(&self, fields: &impl Serialize) -> Result<String, PdsError> {
+        let tag = self.mac(fields)?.finalize().into_bytes();
+        Ok(tag.iter().map(|byte| format!("{byte:02x}")).collect())
+    }
+
+    /// Compares the tags in constant time. Tags that are not valid hex don't
+    /// match.
+    fn verify(
+        &self,
+        fields: &impl Serialize,
+        tag: &str,
+    ) -> Result<bool, PdsError> {
+        let tag = (0..tag.len())
+            .step_by(2)
+            .map(|i| {
+                tag.get(i..i + 2)
+                    .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+            })
+            .collect::<Option<Vec<_>>>();
+        let Some(tag) = tag else {
+            return Ok(false);
+        };
+        Ok(self.mac(fields)?.verify_slice(&tag).is_ok())
+    }
+
+    pub fn seal_filter<FID: Serialize, F: Serialize>(
+        &self,
+        filter_id: FID,
+        filter: F,
+        policy_version: Option<u64>,
+    ) -> Result<SealedFilter<FID, F>, PdsError> {
+        let tag = self.tag(&(&filter_id, &filter, policy_version))?;
+        Ok(SealedFilter {
+            filter_id,
+            filter,
+            policy_version,
+            tag,
+        })
+    }
+
+    /// Whether the tag of the filter matches its content.
+    pub fn verify_filter<FID: Serialize, F: Serialize>(
+        &self,
+        sealed: &SealedFilter<FID, F>,
+    ) -> Result<bool, PdsError> {
+        self.verify(
+            &(&sealed.filter_id, &sealed.filter, sealed.policy_version),
+            &sealed.tag,
+        )
+    }
+
+    pub fn seal_storage<FID: Serialize, F, E: Serialize, B: Serialize>(
+        &self,
+        filters: Vec<SealedFilter<FID, F>>,
+        pruned_before: Option<E>,
+        carryovers: Vec<(FID, B)>,
+    ) -> Result<SealedFilterStorage<FID, F, E, B>, PdsError> {
+        let tag = self.storage_tag(&filters, &pruned_before, &carryovers)?;
+        Ok(SealedFilterStorage {
+            filters,
+            pruned_before,
+            carryovers,
+            tag,
+        })
+    }
+
+    /// Checks the tag over the list of filters. The list itself can't be
+    /// repaired, since removed filters can't be told apart from filters that
+    /// were never created, so a mismatch fails closed regardless of the
+    /// policy.
+    pub fn verify_storage<FID: Serialize, F, E: Serialize, B: Serialize>(
+        &self,
+        sealed: &SealedFilterStorage<FID, F, E, B>,
+    ) -> Result<(), PdsError> {
+        let fields = Self::storage_fields(
+            &sealed.filters,
+            &sealed.pruned_before,
+            &sealed.carryovers,
+        );
+        if !self.verify(&fields, &sealed.tag)? {
+            return Err(PdsError::IntegrityViolation(
+                "the list of persisted filters was modified".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn storage_tag<FID: Serialize, F, E: Serialize, B: Serialize>(
+        &self,
+        filters: &[SealedFilter<FID, F>],
+        pruned_before: &Option<E>,
+        carryovers: &[(FID, B)],
+    ) -> Result<String, PdsError> {
+        self.tag(&Self::storage_fields(filters, pruned_before, carryovers))
+    }
+
+    /// Fields covered by the storage tag. Without carryovers, the tag doesn't
+    /// cover them, and without a watermark either, it only covers the filter
+    /// tags, like for stores sealed before they were persisted.
+    fn storage_fields<'a, FID, F, E, B>(
+        filters: &'a [SealedFilter<FID, F>],
+        pruned_before: &'a Option<E>,
+        carryovers: &'a [(FID, B)],
+    ) -> StorageFields<'a, FID, E, B> {
+        let filter_tags = Self::filter_tags(filters);
+        match (pruned_before, carryovers) {
+            (None, []) => StorageFields::Filters(filter_tags),
+            (Some(pruned_before), []) => {
+                StorageFields::Pruned(filter_tags, pruned_before)
+            }
+            _ => StorageFields::CarriedOver(
+                filter_tags,
+                pruned_before,
+                carryovers,
+            ),
+        }
+    }
+
+    fn filter_tags<FID, F>(filters: &[SealedFilter<FID, F>]) -> Vec<&str> {
+        filters.iter().map(|sealed| sealed.tag.as_str()).collect()
+    }
+}
+
+/// Fields covered by the tag of a `SealedFilterStorage`, serialized as
+/// tuples, depending on what the storage persisted.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum StorageFields<'a, FID, E, B> {
+    Filters(Vec<&'a str>),
+    Pruned(Vec<&'a str>, &'a E),
+    CarriedOver(Vec<&'a str>, &'a Option<E>, &'a [(FID, B)]),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        budget::pure_dp_filter::PureDPBudgetFilter, pds::quotas::FilterId,
+    };
+
+    #[test]
+    fn test_filter_tags() -> Result<(), PdsError> {
+        let sealer = FilterSealer::new(b"device key");
+        let filter = PureDPBudgetFilter {
+            consumed: 0.5,
+            capacity: Some(1.0),
+        };
+        let filter_id: FilterId = FilterId::Global(1);
+        let mut sealed =
+            sealer.seal_filter(filter_id.clone(), filter, Some(0))?;
+        assert!(sealer.verify_filter(&sealed)?);
+
+        // Tags depend on the device key.
+        assert!(!FilterSealer::new(b"other key").verify_filter(&sealed)?);
+
+        // Tags cover the ID, so filters can't be swapped between IDs.
+        let mut moved = sealed.clone();
+        moved.filter_id = FilterId::Global(2);
+        assert!(!sealer.verify_filter(&moved)?);
+
+        sealed.filter.consumed = 0.0;
+        assert!(!sealer.verify_filter(&sealed)?);
+
+        // Dropping a filter invalidates the storage tag.
+        let other = sealer.seal_filter(
+            FilterId::Global(2),
+            sealed.filter.clone(),
+            None,
+        )?;
+        let mut storage = sealer.seal_storage(
+            vec![sealed, other],
+            Some(3),
+            vec![(FilterId::Global(3), 0.5)],
+        )?;
+        assert!(sealer.verify_storage(&storage).is_ok());
+        let mut unpruned = storage.clone();
+        let mut topped_up = storage.clone();
+        storage.filters.pop();
+        assert!(matches!(
+            sealer.verify_storage(&storage),
+            Err(PdsError::IntegrityViolation(_))
+        ));
+
+        // So does moving the pruning watermark back.
+        unpruned.pruned_before = None;
+        assert!(matches!(
+            sealer.verify_storage(&unpruned),
+            Err(PdsError::IntegrityViolation(_))
+        ));
+
+        // And dropping carryovers, which would allow topping up an epoch
+        // again.
+        topped_up.carryovers.clear();
+        assert!(matches!(
+            sealer.verify_storage(&topped_up),
+            Err(PdsError::IntegrityViolation(_))
+        ));
+
+        // Stores sealed before watermarks and carryovers were persisted
+        // still verify.
+        let no_filters = Vec::<&str>::new();
+        let legacy = |pruned_before: Option<u64>| {
+            sealer.seal_storage::<FilterId, PureDPBudgetFilter, _, f64>(
+                vec![],
+                pruned_before,
+                vec![],
+            )
+        };
+        assert_eq!(legacy(None)?.tag, sealer.tag(&no_filters)?);
+        assert_eq!(legacy(Some(3))?.tag, sealer.tag(&(&no_filters, 3))?);
+
+        Ok(())
+    }
+}
@@ -1,5 +1,12 @@
+pub mod fixed_point;
+#[cfg(feature = "std")]
 pub mod hashmap_filter_storage;
+#[cfg(feature = "std")]
+pub mod integrity;
 pub mod pure_dp_filter;
 #[cfg(feature = "experimental")]
 pub mod release_filter;
+pub mod reservation;
+#[cfg(feature = "std")]
+pub mod sharded_filter_storage;
 pub mod traits;
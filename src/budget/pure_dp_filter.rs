@@ -1,9 +1,12 @@
 use core::f64;
 
-use log::{debug, warn};
-use serde::Serialize;
+use log::debug;
+use serde::{Deserialize, Serialize};
 
-use crate::budget::traits::{Budget, Filter, FilterStatus};
+use crate::{
+    budget::traits::{Budget, BudgetOps, Filter, FilterStatus, Scale},
+    error::PdsError,
+};
 
 /// A simple floating-point budget for pure differential privacy, with support
 /// for infinite budget
@@ -12,48 +15,60 @@ use crate::budget::traits::{Budget, Filter, FilterStatus};
 /// filters by setting their capacity to `PureDPBudget::Infinite`. We use a
 /// simple f64 for epsilon and ignore floating point arithmetic issues.
 ///
+/// See `FixedPointBudget` for exact arithmetic.
+///
 /// TODO(https://github.com/columbia/pdslib/issues/14): use OpenDP accountant or
-///     move to a positive rational type or fixed point.
+///     move to a positive rational type.
 ///     We could also generalize to RDP/zCDP.
 pub type PureDPBudget = f64;
 
 impl Budget for PureDPBudget {}
 
-/// A filter for pure differential privacy.
-#[derive(Debug, Clone, Serialize)]
-pub struct PureDPBudgetFilter {
-    pub consumed: PureDPBudget,
-    pub capacity: Option<PureDPBudget>, // None = infinite budget
+impl Scale for PureDPBudget {
+    fn scale(&self, factor: f64) -> Self {
+        self * factor
+    }
+}
+
+impl BudgetOps for PureDPBudget {
+    fn zero() -> Self {
+        0.0
+    }
+
+    fn infinity() -> Self {
+        f64::INFINITY
+    }
+
+    fn is_infinite(&self) -> bool {
+        f64::is_infinite(*self)
+    }
 }
 
-impl Filter<PureDPBudget> for PureDPBudgetFilter {
-    type Error = anyhow::Error;
+/// A filter for pure differential privacy, with floating-point budgets by
+/// default, or e.g. `FixedPointBudget` to avoid float drift.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PureDPBudgetFilter<B = PureDPBudget> {
+    pub consumed: B,
+    pub capacity: Option<B>, // None = infinite budget
+}
 
-    fn new(capacity: PureDPBudget) -> Result<Self, Self::Error> {
+impl<B: BudgetOps> Filter<B> for PureDPBudgetFilter<B> {
+    type Error = PdsError;
+
+    fn new(capacity: B) -> Result<Self, Self::Error> {
         let this = Self {
-            consumed: 0.0,
+            consumed: B::zero(),
             capacity: Some(capacity),
         };
         Ok(this)
     }
 
-    fn can_consume(
-        &self,
-        budget: &PureDPBudget,
-    ) -> Result<FilterStatus, Self::Error> {
-        match self.capacity {
+    fn can_consume(&self, budget: &B) -> Result<FilterStatus, Self::Error> {
+        match &self.capacity {
             None => Ok(FilterStatus::Continue),
             Some(capacity) => {
-                let remaining = capacity - self.consumed;
-
-                let diff = (remaining - budget).abs();
-                if diff < 1e-9 && diff > 0.0 {
-                    warn!(
-                        "can_consume: difference between remaining budget ({remaining}) and requested budget ({budget}) is very small, diff = {diff}",
-                    );
-                }
-
-                let out_of_budget = self.consumed + budget > capacity;
+                let out_of_budget =
+                    self.consumed.clone() + budget.clone() > *capacity;
                 let status = match out_of_budget {
                     true => FilterStatus::OutOfBudget,
                     false => FilterStatus::Continue,
@@ -63,24 +78,43 @@ impl Filter<PureDPBudget> for PureDPBudgetFilter {
         }
     }
 
-    fn try_consume(
-        &mut self,
-        budget: &PureDPBudget,
-    ) -> Result<FilterStatus, Self::Error> {
+    fn try_consume(&mut self, budget: &B) -> Result<FilterStatus, Self::Error> {
         debug!("The budget consumed in this epoch is {:?}, budget capacity for this epoch is  {:?}, and we need to consume this much budget {:?}", self.consumed, self.capacity, budget);
 
         let status = self.can_consume(budget)?;
         if status == FilterStatus::Continue {
-            self.consumed += budget;
+            self.consumed = self.consumed.clone() + budget.clone();
         }
         Ok(status)
     }
 
+    fn tighten_capacity(&mut self, capacity: &B) -> Result<(), Self::Error> {
+        let capacity = match &self.capacity {
+            Some(current) => current.min_budget(capacity),
+            None => capacity.clone(),
+        };
+        self.capacity = Some(capacity);
+        Ok(())
+    }
+
+    fn raise_capacity(&mut self, amount: &B) -> Result<(), Self::Error> {
+        // Infinite capacities stay infinite.
+        if let Some(capacity) = &mut self.capacity {
+            *capacity = capacity.clone() + amount.clone();
+        }
+        Ok(())
+    }
+
+    fn refund(&mut self, budget: &B) -> Result<(), Self::Error> {
+        self.consumed = self.consumed.saturating_sub(budget);
+        Ok(())
+    }
+
     #[cfg(feature = "experimental")]
-    fn remaining_budget(&self) -> Result<PureDPBudget, anyhow::Error> {
-        match self.capacity {
-            None => Ok(f64::INFINITY),
-            Some(capacity) => Ok(capacity - self.consumed),
+    fn remaining_budget(&self) -> Result<B, Self::Error> {
+        match &self.capacity {
+            None => Ok(B::infinity()),
+            Some(capacity) => Ok(capacity.clone() - self.consumed.clone()),
         }
     }
 }
@@ -107,4 +141,35 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_tighten_capacity() -> Result<(), anyhow::Error> {
+        let mut filter = PureDPBudgetFilter::new(1.0)?;
+        assert_eq!(filter.try_consume(&0.5)?, FilterStatus::Continue);
+
+        // Tightening below the consumed budget doesn't refund anything.
+        filter.tighten_capacity(&0.4)?;
+        assert_eq!(filter.try_consume(&0.0)?, FilterStatus::OutOfBudget);
+
+        // Capacity can't be loosened.
+        filter.tighten_capacity(&2.0)?;
+        assert_eq!(filter.capacity, Some(0.4));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_budget_ops() {
+        let budget: PureDPBudget = 1.0;
+        assert_eq!(budget.scale(0.25), 0.25);
+        assert_eq!(budget.saturating_sub(&0.25), 0.75);
+        assert_eq!(budget.saturating_sub(&2.0), PureDPBudget::zero());
+        assert_eq!(budget.min_budget(&0.5), 0.5);
+        assert_eq!(budget.max_budget(&0.5), 1.0);
+
+        let infinite = PureDPBudget::infinity();
+        assert!(infinite.is_infinite());
+        assert!(infinite.saturating_sub(&budget).is_infinite());
+        assert_eq!(infinite.min_budget(&budget), budget);
+    }
 }
@@ -1,98 +1,286 @@
-use anyhow::Result;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use super::{
-    pure_dp_filter::PureDPBudget,
-    traits::{Filter, FilterStatus, ReleaseFilter},
+    pure_dp_filter::{PureDPBudget, PureDPBudgetFilter},
+    traits::{BudgetOps, Filter, FilterStatus, ReleaseFilter},
 };
+use crate::error::PdsError;
+
+/// [Experimental] A filter that has additional functionality to release
+/// budget over time, for any budget type with arithmetic.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BudgetReleaseFilter<B> {
+    pub consumed: B,
+    pub unlocked: B,
+    pub capacity: B,
+}
 
 /// [Experimental] A pure DP filter that has additional functionality to release
 /// budget over time.
-#[derive(Debug, Clone, Serialize)]
-pub struct PureDPBudgetReleaseFilter {
-    pub consumed: PureDPBudget,
-    pub unlocked: PureDPBudget,
-    pub capacity: PureDPBudget,
-}
+pub type PureDPBudgetReleaseFilter = BudgetReleaseFilter<PureDPBudget>;
 
-impl Filter<PureDPBudget> for PureDPBudgetReleaseFilter {
-    type Error = anyhow::Error;
+impl<B: BudgetOps> Filter<B> for BudgetReleaseFilter<B> {
+    type Error = PdsError;
 
-    fn new(capacity: PureDPBudget) -> Result<Self, Self::Error> {
+    fn new(capacity: B) -> Result<Self, Self::Error> {
         let this = Self {
-            consumed: 0.0,
-            unlocked: 0.0,
+            consumed: B::zero(),
+            unlocked: B::zero(),
             capacity,
         };
         Ok(this)
     }
 
-    fn can_consume(
-        &self,
-        budget: &PureDPBudget,
-    ) -> Result<FilterStatus, Self::Error> {
+    fn can_consume(&self, budget: &B) -> Result<FilterStatus, Self::Error> {
         // Infinite filters accept all requests, even if they are infinite too.
-        if self.capacity == f64::INFINITY {
+        if self.capacity.is_infinite() {
             return Ok(FilterStatus::Continue);
         }
 
-        if budget == &f64::INFINITY {
+        if budget.is_infinite() {
             // Finite capacity can't allow infinite requests
             return Ok(FilterStatus::OutOfBudget);
         }
 
-        let can_consume = self.consumed + budget <= self.unlocked;
+        let can_consume =
+            self.consumed.clone() + budget.clone() <= self.unlocked;
         match can_consume {
             true => Ok(FilterStatus::Continue),
             false => Ok(FilterStatus::OutOfBudget),
         }
     }
 
-    fn try_consume(
-        &mut self,
-        budget: &PureDPBudget,
-    ) -> Result<FilterStatus, Self::Error> {
+    fn try_consume(&mut self, budget: &B) -> Result<FilterStatus, Self::Error> {
         let status = self.can_consume(budget)?;
 
         if status == FilterStatus::Continue {
             // If we can consume, update the consumed budget.
-            self.consumed += *budget;
+            self.consumed = self.consumed.clone() + budget.clone();
         }
 
         Ok(status)
     }
 
-    fn remaining_budget(&self) -> Result<PureDPBudget, anyhow::Error> {
-        let remaining = self.capacity - self.consumed;
-        Ok(remaining)
+    fn tighten_capacity(&mut self, capacity: &B) -> Result<(), Self::Error> {
+        self.capacity = self.capacity.min_budget(capacity);
+        self.unlocked = self.unlocked.min_budget(&self.capacity);
+        Ok(())
+    }
+
+    /// The granted budget is locked until it is released, like the rest of
+    /// the capacity.
+    fn raise_capacity(&mut self, amount: &B) -> Result<(), Self::Error> {
+        self.capacity = self.capacity.clone() + amount.clone();
+        Ok(())
+    }
+
+    fn refund(&mut self, budget: &B) -> Result<(), Self::Error> {
+        self.consumed = self.consumed.saturating_sub(budget);
+        Ok(())
+    }
+
+    fn remaining_budget(&self) -> Result<B, Self::Error> {
+        self.unlocked_remaining()
     }
 }
 
-impl ReleaseFilter<PureDPBudget> for PureDPBudgetReleaseFilter {
-    fn get_capacity(&self) -> Result<PureDPBudget, Self::Error> {
-        Ok(self.capacity)
+impl<B: BudgetOps> ReleaseFilter<B> for BudgetReleaseFilter<B> {
+    fn get_capacity(&self) -> Result<B, Self::Error> {
+        Ok(self.capacity.clone())
+    }
+
+    fn consumed(&self) -> Result<B, Self::Error> {
+        Ok(self.consumed.clone())
     }
 
-    fn set_capacity(
-        &mut self,
-        capacity: PureDPBudget,
-    ) -> Result<(), Self::Error> {
+    fn unlocked_remaining(&self) -> Result<B, Self::Error> {
+        // Infinite filters accept all requests, see `can_consume`.
+        if self.capacity.is_infinite() {
+            return Ok(B::infinity());
+        }
+        Ok(self.unlocked.saturating_sub(&self.consumed))
+    }
+
+    fn capacity_remaining(&self) -> Result<B, Self::Error> {
+        Ok(self.capacity.saturating_sub(&self.consumed))
+    }
+
+    fn shortfall(&self, budget: &B) -> Result<B, Self::Error> {
+        Ok(budget.saturating_sub(&self.unlocked_remaining()?))
+    }
+
+    fn set_capacity(&mut self, capacity: B) -> Result<(), Self::Error> {
         self.capacity = capacity;
         Ok(())
     }
 
-    fn release(
-        &mut self,
-        budget_to_unlock: &PureDPBudget,
-    ) -> Result<(), Self::Error> {
-        if self.capacity == f64::INFINITY {
+    fn release(&mut self, budget_to_unlock: &B) -> Result<(), Self::Error> {
+        let unlocked = self.unlocked.clone() + budget_to_unlock.clone();
+        if self.capacity.is_infinite() {
             // Infinite filters can be released infinitely
-            self.unlocked += budget_to_unlock;
+            self.unlocked = unlocked;
         } else {
-            self.unlocked = self.capacity.min(self.unlocked + budget_to_unlock);
+            self.unlocked = self.capacity.min_budget(&unlocked);
         }
         Ok(())
     }
+
+    fn lock(&mut self) -> Result<(), Self::Error> {
+        self.unlocked = self.consumed.clone();
+        Ok(())
+    }
+}
+
+/// [Experimental] Adds release semantics to any filter, e.g. so that the
+/// batch scheduler can run on the same filters as a regular PDS. Unlike
+/// `BudgetReleaseFilter`, new filters are fully unlocked, and behave like the
+/// inner filter until they are locked.
+///
+/// The inner filter still checks every consumption, the wrapper only keeps
+/// track of the capacity and of the consumed budget to enforce the unlocked
+/// budget on top of it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Unlockable<F, B = PureDPBudget> {
+    pub filter: F,
+    pub consumed: B,
+    pub capacity: B,
+
+    /// Budget unlocked so far, or None if the whole capacity is unlocked.
+    pub unlocked: Option<B>,
+}
+
+/// [Experimental] A pure DP filter that can be locked and released over
+/// time.
+pub type UnlockablePureDPBudgetFilter = Unlockable<PureDPBudgetFilter>;
+
+impl<F: Filter<B>, B: BudgetOps> Filter<B> for Unlockable<F, B> {
+    type Error = F::Error;
+
+    fn new(capacity: B) -> Result<Self, Self::Error> {
+        let this = Self {
+            filter: F::new(capacity.clone())?,
+            consumed: B::zero(),
+            capacity,
+            unlocked: None,
+        };
+        Ok(this)
+    }
+
+    fn can_consume(&self, budget: &B) -> Result<FilterStatus, Self::Error> {
+        if let Some(unlocked) = &self.unlocked {
+            // Infinite filters accept all requests, like in
+            // `BudgetReleaseFilter`.
+            let exceeds_unlocked = !self.capacity.is_infinite()
+                && (budget.is_infinite()
+                    || self.consumed.clone() + budget.clone() > *unlocked);
+            if exceeds_unlocked {
+                return Ok(FilterStatus::OutOfBudget);
+            }
+        }
+        self.filter.can_consume(budget)
+    }
+
+    fn try_consume(&mut self, budget: &B) -> Result<FilterStatus, Self::Error> {
+        if self.can_consume(budget)? == FilterStatus::OutOfBudget {
+            return Ok(FilterStatus::OutOfBudget);
+        }
+
+        let status = self.filter.try_consume(budget)?;
+        if status == FilterStatus::Continue {
+            self.consumed = self.consumed.clone() + budget.clone();
+        }
+        Ok(status)
+    }
+
+    fn tighten_capacity(&mut self, capacity: &B) -> Result<(), Self::Error> {
+        self.filter.tighten_capacity(capacity)?;
+        self.capacity = self.capacity.min_budget(capacity);
+        self.unlocked = self
+            .unlocked
+            .as_ref()
+            .map(|unlocked| unlocked.min_budget(&self.capacity));
+        Ok(())
+    }
+
+    /// The granted budget is locked until it is released, unless the filter
+    /// is fully unlocked.
+    fn raise_capacity(&mut self, amount: &B) -> Result<(), Self::Error> {
+        self.filter.raise_capacity(amount)?;
+        self.capacity = self.capacity.clone() + amount.clone();
+        Ok(())
+    }
+
+    fn refund(&mut self, budget: &B) -> Result<(), Self::Error> {
+        self.filter.refund(budget)?;
+        self.consumed = self.consumed.saturating_sub(budget);
+        Ok(())
+    }
+
+    fn remaining_budget(&self) -> Result<B, Self::Error> {
+        self.unlocked_remaining()
+    }
+}
+
+impl<F: Filter<B>, B: BudgetOps> ReleaseFilter<B> for Unlockable<F, B> {
+    fn get_capacity(&self) -> Result<B, Self::Error> {
+        Ok(self.capacity.clone())
+    }
+
+    fn consumed(&self) -> Result<B, Self::Error> {
+        Ok(self.consumed.clone())
+    }
+
+    fn unlocked_remaining(&self) -> Result<B, Self::Error> {
+        if self.capacity.is_infinite() {
+            return Ok(B::infinity());
+        }
+        match &self.unlocked {
+            Some(unlocked) => Ok(unlocked.saturating_sub(&self.consumed)),
+            None => self.capacity_remaining(),
+        }
+    }
+
+    fn capacity_remaining(&self) -> Result<B, Self::Error> {
+        Ok(self.capacity.saturating_sub(&self.consumed))
+    }
+
+    fn shortfall(&self, budget: &B) -> Result<B, Self::Error> {
+        Ok(budget.saturating_sub(&self.unlocked_remaining()?))
+    }
+
+    /// The inner filter is tightened or raised to the new capacity.
+    fn set_capacity(&mut self, capacity: B) -> Result<(), Self::Error> {
+        if capacity < self.capacity {
+            self.filter.tighten_capacity(&capacity)?;
+        } else if capacity > self.capacity {
+            let amount = match capacity.is_infinite() {
+                true => B::infinity(),
+                false => capacity.clone() - self.capacity.clone(),
+            };
+            self.filter.raise_capacity(&amount)?;
+        }
+        self.capacity = capacity;
+        Ok(())
+    }
+
+    fn release(&mut self, budget_to_unlock: &B) -> Result<(), Self::Error> {
+        // Fully unlocked filters stay fully unlocked.
+        let Some(unlocked) = &self.unlocked else {
+            return Ok(());
+        };
+        let unlocked = unlocked.clone() + budget_to_unlock.clone();
+        if self.capacity.is_infinite() {
+            self.unlocked = Some(unlocked);
+        } else {
+            self.unlocked = Some(self.capacity.min_budget(&unlocked));
+        }
+        Ok(())
+    }
+
+    fn lock(&mut self) -> Result<(), Self::Error> {
+        self.unlocked = Some(self.consumed.clone());
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -118,4 +306,67 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_release_filter_accessors() -> Result<(), anyhow::Error> {
+        let mut filter = PureDPBudgetReleaseFilter::new(1.0)?;
+        filter.release(&0.5)?;
+        filter.try_consume(&0.2)?;
+
+        assert_eq!(filter.consumed()?, 0.2);
+        assert_eq!(filter.unlocked_remaining()?, 0.3);
+        assert_eq!(filter.capacity_remaining()?, 0.8);
+        assert_eq!(filter.remaining_budget()?, filter.unlocked_remaining()?);
+
+        // The remaining budget is exactly what can be consumed right now.
+        assert_eq!(filter.can_consume(&0.3)?, FilterStatus::Continue);
+        assert_eq!(filter.can_consume(&0.31)?, FilterStatus::OutOfBudget);
+
+        // The shortfall is what exceeds the unlocked remaining budget.
+        assert_eq!(filter.shortfall(&0.3)?, 0.0);
+        assert!((filter.shortfall(&0.5)? - 0.2).abs() < 1e-9);
+
+        // Tightening below the consumed budget leaves nothing to consume.
+        filter.tighten_capacity(&0.1)?;
+        assert_eq!(filter.unlocked_remaining()?, 0.0);
+        assert_eq!(filter.capacity_remaining()?, 0.0);
+        assert_eq!(filter.consumed()?, 0.2);
+
+        // Infinite filters always have infinite remaining budget.
+        let mut filter = PureDPBudgetReleaseFilter::new(f64::INFINITY)?;
+        filter.try_consume(&1.0)?;
+        assert_eq!(filter.unlocked_remaining()?, f64::INFINITY);
+        assert_eq!(filter.capacity_remaining()?, f64::INFINITY);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unlockable_filter() -> Result<(), anyhow::Error> {
+        // New filters are fully unlocked, like the inner filter.
+        let mut filter = UnlockablePureDPBudgetFilter::new(1.0)?;
+        filter.release(&0.5)?;
+        assert_eq!(filter.try_consume(&0.4)?, FilterStatus::Continue);
+        assert!((filter.unlocked_remaining()? - 0.6).abs() < 1e-9);
+
+        // Once locked, budget has to be released again.
+        filter.lock()?;
+        assert_eq!(filter.try_consume(&0.1)?, FilterStatus::OutOfBudget);
+        filter.release(&0.3)?;
+        assert_eq!(filter.try_consume(&0.3)?, FilterStatus::Continue);
+        assert_eq!(filter.try_consume(&0.1)?, FilterStatus::OutOfBudget);
+        filter.release(&2.0)?;
+        assert!((filter.unlocked_remaining()? - 0.3).abs() < 1e-9);
+        assert!((filter.shortfall(&0.5)? - 0.2).abs() < 1e-9);
+
+        // The inner filter follows capacity changes, e.g. quota toggles.
+        filter.set_capacity(f64::INFINITY)?;
+        assert_eq!(filter.try_consume(&5.0)?, FilterStatus::Continue);
+        filter.set_capacity(4.0)?;
+        assert_eq!(filter.filter.capacity, Some(4.0));
+        assert_eq!(filter.try_consume(&0.1)?, FilterStatus::OutOfBudget);
+        assert_eq!(filter.consumed()?, filter.filter.consumed);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,20 @@
+use alloc::vec::Vec;
+
+use serde::Serialize;
+
+/// Identifier of a budget reservation, returned by
+/// `PrivateDataService::reserve_budget`.
+pub type ReservationToken = u64;
+
+/// Budget deducted from filters on behalf of a report that has not been
+/// released yet. Committing the reservation makes the deduction final,
+/// releasing it refunds the budget to the filters.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Reservation<FID, B> {
+    /// Budget deducted from each filter.
+    pub deductions: Vec<(FID, B)>,
+
+    /// Time after which the reservation is released automatically, in
+    /// seconds since the Unix epoch.
+    pub expires_at: u64,
+}
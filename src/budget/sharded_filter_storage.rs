@@ -0,0 +1,703 @@
+//! Filter storage sharded by epoch, for long-lived deployments where a single
+//! map over all the filters keeps growing with the number of epochs. Each
+//! epoch has its own inner storage, called a shard. Shards can be evicted to a
+//! `ShardStore`, and are loaded back lazily the next time one of their
+//! filters is accessed.
+
+use std::collections::BTreeMap;
+
+use crate::{
+    budget::{
+        reservation::{Reservation, ReservationToken},
+        traits::{EpochFilterId, FilterStorage},
+    },
+    error::PdsError,
+    util::hashmap::HashMap,
+};
+
+/// Epoch of the filters of an inner storage.
+type ShardEpoch<FS> =
+    <<FS as FilterStorage>::FilterId as EpochFilterId>::EpochId;
+
+/// Persistence for the shards of a `ShardedFilterStorage`, e.g. one file or
+/// one database row per epoch, and for its pending reservations and pruning
+/// watermark.
+pub trait ShardStore<E, FS: FilterStorage> {
+    /// Loads the stored shard of the given epoch, if any. The store can keep
+    /// its copy, since the shard is stored again when it is evicted.
+    fn load_shard(&mut self, epoch_id: &E) -> Result<Option<FS>, PdsError>;
+
+    /// Stores the shard of the given epoch, replacing any previous version.
+    fn store_shard(&mut self, epoch_id: &E, shard: FS) -> Result<(), PdsError>;
+
+    /// Removes the stored shard of the given epoch, if any.
+    fn remove_shard(&mut self, epoch_id: &E) -> Result<(), PdsError>;
+
+    /// Epochs that have a stored shard.
+    fn stored_epochs(&self) -> Result<Vec<E>, PdsError>;
+
+    /// Stores the pending reservations, replacing the previous ones.
+    /// Reservations span several epochs, so they are stored apart from the
+    /// shards, on every change.
+    #[allow(clippy::type_complexity)]
+    fn store_reservations(
+        &mut self,
+        reservations: Vec<(
+            ReservationToken,
+            Reservation<FS::FilterId, FS::Budget>,
+        )>,
+    ) -> Result<(), PdsError>;
+
+    /// Loads the stored reservations.
+    #[allow(clippy::type_complexity)]
+    fn load_reservations(
+        &mut self,
+    ) -> Result<
+        Vec<(ReservationToken, Reservation<FS::FilterId, FS::Budget>)>,
+        PdsError,
+    >;
+
+    /// Stores the pruning watermark, see `FilterStorage::pruned_before`.
+    fn store_pruned_before(&mut self, epoch_id: &E) -> Result<(), PdsError>;
+
+    /// Loads the stored pruning watermark, if any.
+    fn load_pruned_before(&mut self) -> Result<Option<E>, PdsError>;
+}
+
+/// Shard kept in memory.
+struct ResidentShard<FS> {
+    storage: FS,
+
+    /// Value of the access clock when the shard was last accessed, to evict
+    /// the least recently used shards first.
+    last_access: u64,
+}
+
+/// `FilterStorage` made of one inner storage per epoch. Without a store, all
+/// the shards stay in memory, and the storage behaves like its inner storage,
+/// except that pruning drops whole shards. With a store, at most
+/// `max_resident_shards` shards are kept in memory, and the least recently
+/// used ones are written to the store when more are needed.
+///
+/// Reservations can span several epochs, so they are kept at the top level
+/// rather than in the shards, and written to the store on every change.
+pub struct ShardedFilterStorage<FS>
+where
+    FS: FilterStorage,
+    FS::FilterId: EpochFilterId,
+{
+    capacities: FS::Capacities,
+    shards: BTreeMap<ShardEpoch<FS>, ResidentShard<FS>>,
+    store: Option<Box<dyn ShardStore<ShardEpoch<FS>, FS>>>,
+    max_resident_shards: Option<usize>,
+
+    /// Pending budget reservations.
+    reservations:
+        HashMap<ReservationToken, Reservation<FS::FilterId, FS::Budget>>,
+
+    /// Epochs strictly older than this one have been pruned.
+    pruned_before: Option<ShardEpoch<FS>>,
+
+    /// Incremented on every shard access.
+    access_clock: u64,
+
+    /// Number of writes so far. Kept at the top level, since the generations
+    /// of the shards are lost when they are evicted.
+    generation: u64,
+}
+
+impl<FS> ShardedFilterStorage<FS>
+where
+    FS: FilterStorage<Error = PdsError>,
+    FS::FilterId: EpochFilterId + Clone,
+    FS::Capacities: Clone,
+    ShardEpoch<FS>: Clone,
+{
+    /// Persists the shards to the given store, and keeps at most
+    /// `max_resident_shards` of them in memory. Reservations and the pruning
+    /// watermark already in the store, e.g. from before a restart, are
+    /// loaded.
+    pub fn with_shard_store(
+        mut self,
+        store: impl ShardStore<ShardEpoch<FS>, FS> + 'static,
+        max_resident_shards: usize,
+    ) -> Result<Self, PdsError> {
+        if max_resident_shards == 0 {
+            return Err(PdsError::InvalidRequest(
+                "at least one shard must stay in memory".into(),
+            ));
+        }
+        let mut store = Box::new(store);
+        self.reservations.extend(store.load_reservations()?);
+        if let Some(stored) = store.load_pruned_before()? {
+            self.pruned_before = self.pruned_before.max(Some(stored));
+        }
+        if let Some(pruned_before) = &self.pruned_before {
+            store.store_pruned_before(pruned_before)?;
+        }
+        self.store = Some(store);
+        self.max_resident_shards = Some(max_resident_shards);
+        self.store_reservations()?;
+        self.evict_excess_shards()?;
+        Ok(self)
+    }
+
+    /// Epochs of the shards currently in memory.
+    pub fn resident_epochs(&self) -> Vec<ShardEpoch<FS>> {
+        self.shards.keys().cloned().collect()
+    }
+
+    /// Writes the shard of the given epoch to the store and drops it from
+    /// memory. Returns false if the shard was not in memory.
+    pub fn evict_shard(
+        &mut self,
+        epoch_id: &ShardEpoch<FS>,
+    ) -> Result<bool, PdsError> {
+        let Some(store) = self.store.as_mut() else {
+            return Err(PdsError::InvalidRequest(
+                "shards can't be evicted without a shard store".into(),
+            ));
+        };
+        let Some(shard) = self.shards.remove(epoch_id) else {
+            return Ok(false);
+        };
+        store.store_shard(epoch_id, shard.storage)?;
+        Ok(true)
+    }
+
+    /// Evicts all the shards in memory, e.g. before shutting down.
+    pub fn flush(&mut self) -> Result<(), PdsError> {
+        for epoch_id in self.resident_epochs() {
+            self.evict_shard(&epoch_id)?;
+        }
+        Ok(())
+    }
+
+    /// Shard of the given epoch, loaded from the store if needed. If the
+    /// epoch has no shard yet, creates one only if `create` is true.
+    fn shard_mut(
+        &mut self,
+        epoch_id: &ShardEpoch<FS>,
+        create: bool,
+    ) -> Result<Option<&mut FS>, PdsError> {
+        if !self.shards.contains_key(epoch_id) {
+            let stored = match self.store.as_mut() {
+                Some(store) => store.load_shard(epoch_id)?,
+                None => None,
+            };
+            let storage = match stored {
+                // New filters of stored shards use the current capacities.
+                Some(mut storage) => {
+                    storage.set_capacities(self.capacities.clone(), false)?;
+                    storage
+                }
+                None if create => FS::new(self.capacities.clone())?,
+                None => return Ok(None),
+            };
+            self.shards.insert(
+                epoch_id.clone(),
+                ResidentShard {
+                    storage,
+                    last_access: 0,
+                },
+            );
+            self.evict_excess_shards_except(Some(epoch_id))?;
+        }
+
+        self.access_clock += 1;
+        let shard = self.shards.get_mut(epoch_id).map(|shard| {
+            shard.last_access = self.access_clock;
+            &mut shard.storage
+        });
+        Ok(shard)
+    }
+
+    fn evict_excess_shards(&mut self) -> Result<(), PdsError> {
+        self.evict_excess_shards_except(None)
+    }
+
+    /// Evicts the least recently used shards until at most
+    /// `max_resident_shards` are in memory, never evicting `keep`.
+    fn evict_excess_shards_except(
+        &mut self,
+        keep: Option<&ShardEpoch<FS>>,
+    ) -> Result<(), PdsError> {
+        let Some(max_resident_shards) = self.max_resident_shards else {
+            return Ok(());
+        };
+        while self.shards.len() > max_resident_shards {
+            let lru_epoch = self
+                .shards
+                .iter()
+                .filter(|(epoch_id, _)| Some(*epoch_id) != keep)
+                .min_by_key(|(_, shard)| shard.last_access)
+                .map(|(epoch_id, _)| epoch_id.clone());
+            match lru_epoch {
+                Some(epoch_id) => self.evict_shard(&epoch_id)?,
+                None => break,
+            };
+        }
+        Ok(())
+    }
+
+    /// Writes the pending reservations to the store, if any.
+    fn store_reservations(&mut self) -> Result<(), PdsError> {
+        let Some(store) = self.store.as_mut() else {
+            return Ok(());
+        };
+        let reservations = self
+            .reservations
+            .iter()
+            .map(|(token, reservation)| (*token, reservation.clone()))
+            .collect();
+        store.store_reservations(reservations)
+    }
+
+    /// Epochs with a shard, in memory or in the store.
+    fn all_epochs(&self) -> Result<Vec<ShardEpoch<FS>>, PdsError> {
+        let mut epochs = self.resident_epochs();
+        if let Some(store) = self.store.as_ref() {
+            for epoch_id in store.stored_epochs()? {
+                if !self.shards.contains_key(&epoch_id) {
+                    epochs.push(epoch_id);
+                }
+            }
+        }
+        Ok(epochs)
+    }
+}
+
+impl<FS> FilterStorage for ShardedFilterStorage<FS>
+where
+    FS: FilterStorage<Error = PdsError>,
+    FS::FilterId: EpochFilterId + Clone,
+    FS::Capacities: Clone,
+    ShardEpoch<FS>: Clone,
+{
+    type FilterId = FS::FilterId;
+    type Filter = FS::Filter;
+    type Budget = FS::Budget;
+    type Capacities = FS::Capacities;
+    type Error = PdsError;
+
+    fn new(capacities: Self::Capacities) -> Result<Self, Self::Error>
+    where
+        Self: Sized,
+    {
+        let this = Self {
+            capacities,
+            shards: BTreeMap::new(),
+            store: None,
+            max_resident_shards: None,
+            reservations: HashMap::new(),
+            pruned_before: None,
+            access_clock: 0,
+            generation: 0,
+        };
+        Ok(this)
+    }
+
+    fn capacities(&self) -> &Self::Capacities {
+        &self.capacities
+    }
+
+    /// Stored shards are loaded one at a time to be tightened.
+    fn set_capacities(
+        &mut self,
+        capacities: Self::Capacities,
+        tighten_existing: bool,
+    ) -> Result<(), Self::Error> {
+        self.capacities = capacities;
+        self.generation += 1;
+
+        let epochs = match tighten_existing {
+            true => self.all_epochs()?,
+            false => self.resident_epochs(),
+        };
+        for epoch_id in epochs {
+            let capacities = self.capacities.clone();
+            if let Some(shard) = self.shard_mut(&epoch_id, false)? {
+                shard.set_capacities(capacities, tighten_existing)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_filter(
+        &mut self,
+        filter_id: &Self::FilterId,
+    ) -> Result<Option<Self::Filter>, Self::Error> {
+        match self.shard_mut(filter_id.epoch_id(), false)? {
+            Some(shard) => shard.get_filter(filter_id),
+            None => Ok(None),
+        }
+    }
+
+    fn set_filter(
+        &mut self,
+        filter_id: &Self::FilterId,
+        filter: Self::Filter,
+    ) -> Result<(), Self::Error> {
+        self.generation += 1;
+        match self.shard_mut(filter_id.epoch_id(), true)? {
+            Some(shard) => shard.set_filter(filter_id, filter),
+            None => Err(PdsError::InvalidRequest(format!(
+                "no shard for filter {filter_id:?}"
+            ))),
+        }
+    }
+
+    /// Prunes each shard, loading stored shards one at a time, since filters
+    /// in the epochs of coarser queriers can outlive the base epochs with the
+    /// same number. Shards left empty are dropped, from memory and from the
+    /// store.
+    fn prune(
+        &mut self,
+        is_pruned: &dyn Fn(&Self::FilterId) -> bool,
+    ) -> Result<usize, Self::Error> {
+        self.generation += 1;
+        let mut n_filters = 0;
+        for epoch_id in self.all_epochs()? {
+            let Some(shard) = self.shard_mut(&epoch_id, false)? else {
+                continue;
+            };
+            n_filters += shard.prune(is_pruned)?;
+            if shard.is_empty()? {
+                self.shards.remove(&epoch_id);
+                if let Some(store) = self.store.as_mut() {
+                    store.remove_shard(&epoch_id)?;
+                }
+            }
+        }
+        Ok(n_filters)
+    }
+
+    fn is_empty(&self) -> Result<bool, Self::Error> {
+        Ok(self.all_epochs()?.is_empty())
+    }
+
+    fn pruned_before(&self) -> Option<ShardEpoch<FS>> {
+        self.pruned_before.clone()
+    }
+
+    fn set_pruned_before(
+        &mut self,
+        epoch_id: ShardEpoch<FS>,
+    ) -> Result<(), Self::Error> {
+        self.generation += 1;
+        if let Some(store) = self.store.as_mut() {
+            store.store_pruned_before(&epoch_id)?;
+        }
+        self.pruned_before = Some(epoch_id);
+        Ok(())
+    }
+
+    fn set_reservation(
+        &mut self,
+        token: ReservationToken,
+        reservation: Reservation<Self::FilterId, Self::Budget>,
+    ) -> Result<(), Self::Error> {
+        self.reservations.insert(token, reservation);
+        self.generation += 1;
+        self.store_reservations()
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn take_reservation(
+        &mut self,
+        token: ReservationToken,
+    ) -> Result<Option<Reservation<Self::FilterId, Self::Budget>>, Self::Error>
+    {
+        self.generation += 1;
+        let reservation = self.reservations.remove(&token);
+        if reservation.is_some() {
+            self.store_reservations()?;
+        }
+        Ok(reservation)
+    }
+
+    fn reservations(
+        &self,
+    ) -> Result<
+        Vec<(ReservationToken, Reservation<Self::FilterId, Self::Budget>)>,
+        Self::Error,
+    > {
+        let reservations = self
+            .reservations
+            .iter()
+            .map(|(token, reservation)| (*token, reservation.clone()))
+            .collect();
+        Ok(reservations)
+    }
+
+    /// Repayments and carryovers are kept in the shard of their filter, so
+    /// that they are stored and pruned along with it.
+    fn set_repayment(
+        &mut self,
+        filter_id: &Self::FilterId,
+        budget: Self::Budget,
+    ) -> Result<(), Self::Error> {
+        self.generation += 1;
+        match self.shard_mut(filter_id.epoch_id(), true)? {
+            Some(shard) => shard.set_repayment(filter_id, budget),
+            None => Err(PdsError::InvalidRequest(format!(
+                "no shard for filter {filter_id:?}"
+            ))),
+        }
+    }
+
+    fn take_repayment(
+        &mut self,
+        filter_id: &Self::FilterId,
+    ) -> Result<Option<Self::Budget>, Self::Error> {
+        self.generation += 1;
+        match self.shard_mut(filter_id.epoch_id(), false)? {
+            Some(shard) => shard.take_repayment(filter_id),
+            None => Ok(None),
+        }
+    }
+
+    fn get_repayment(
+        &mut self,
+        filter_id: &Self::FilterId,
+    ) -> Result<Option<Self::Budget>, Self::Error> {
+        match self.shard_mut(filter_id.epoch_id(), false)? {
+            Some(shard) => shard.get_repayment(filter_id),
+            None => Ok(None),
+        }
+    }
+
+    fn set_carryover(
+        &mut self,
+        filter_id: &Self::FilterId,
+        budget: Self::Budget,
+    ) -> Result<(), Self::Error> {
+        self.generation += 1;
+        match self.shard_mut(filter_id.epoch_id(), true)? {
+            Some(shard) => shard.set_carryover(filter_id, budget),
+            None => Err(PdsError::InvalidRequest(format!(
+                "no shard for filter {filter_id:?}"
+            ))),
+        }
+    }
+
+    fn get_carryover(
+        &mut self,
+        filter_id: &Self::FilterId,
+    ) -> Result<Option<Self::Budget>, Self::Error> {
+        match self.shard_mut(filter_id.epoch_id(), false)? {
+            Some(shard) => shard.get_carryover(filter_id),
+            None => Ok(None),
+        }
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+#[cfg(all(test, feature = "experimental"))]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{
+        budget::traits::FilterStatus,
+        pds::{
+            aliases::SimpleFilterStorage,
+            quotas::{FilterId, StaticCapacities},
+        },
+    };
+
+    type Sharded = ShardedFilterStorage<SimpleFilterStorage>;
+
+    type MockReservations = Vec<(ReservationToken, Reservation<FilterId, f64>)>;
+
+    /// In-memory store, sharing its shards with the test.
+    #[derive(Default, Clone)]
+    struct MockShardStore {
+        shards: Rc<RefCell<HashMap<u64, SimpleFilterStorage>>>,
+        reservations: Rc<RefCell<MockReservations>>,
+        pruned_before: Rc<RefCell<Option<u64>>>,
+    }
+
+    impl ShardStore<u64, SimpleFilterStorage> for MockShardStore {
+        fn load_shard(
+            &mut self,
+            epoch_id: &u64,
+        ) -> Result<Option<SimpleFilterStorage>, PdsError> {
+            Ok(self.shards.borrow_mut().remove(epoch_id))
+        }
+
+        fn store_shard(
+            &mut self,
+            epoch_id: &u64,
+            shard: SimpleFilterStorage,
+        ) -> Result<(), PdsError> {
+            self.shards.borrow_mut().insert(*epoch_id, shard);
+            Ok(())
+        }
+
+        fn remove_shard(&mut self, epoch_id: &u64) -> Result<(), PdsError> {
+            self.shards.borrow_mut().remove(epoch_id);
+            Ok(())
+        }
+
+        fn stored_epochs(&self) -> Result<Vec<u64>, PdsError> {
+            Ok(self.shards.borrow().keys().copied().collect())
+        }
+
+        fn store_reservations(
+            &mut self,
+            reservations: MockReservations,
+        ) -> Result<(), PdsError> {
+            *self.reservations.borrow_mut() = reservations;
+            Ok(())
+        }
+
+        fn load_reservations(&mut self) -> Result<MockReservations, PdsError> {
+            Ok(self.reservations.borrow().clone())
+        }
+
+        fn store_pruned_before(
+            &mut self,
+            epoch_id: &u64,
+        ) -> Result<(), PdsError> {
+            *self.pruned_before.borrow_mut() = Some(*epoch_id);
+            Ok(())
+        }
+
+        fn load_pruned_before(&mut self) -> Result<Option<u64>, PdsError> {
+            Ok(*self.pruned_before.borrow())
+        }
+    }
+
+    fn global(epoch: u64) -> FilterId<u64, String> {
+        FilterId::Global(epoch)
+    }
+
+    #[test]
+    fn test_sharded_filter_storage() -> Result<(), anyhow::Error> {
+        let mut storage = Sharded::new(StaticCapacities::mock())?;
+        for epoch in 1..=3 {
+            let fid = FilterId::PerQuerier(epoch, "adtech.com".to_string());
+            assert_eq!(
+                storage.try_consume(&fid, &0.5)?,
+                FilterStatus::Continue
+            );
+            assert_eq!(
+                storage.try_consume(&fid, &0.6)?,
+                FilterStatus::OutOfBudget
+            );
+            storage.try_consume(&global(epoch), &1.0)?;
+        }
+        assert_eq!(storage.resident_epochs(), vec![1, 2, 3]);
+
+        // Reads don't create shards.
+        assert!(storage.get_filter(&global(4))?.is_none());
+        assert_eq!(storage.resident_epochs(), vec![1, 2, 3]);
+
+        // Pruning drops the shards left empty.
+        assert_eq!(storage.prune(&|fid| *fid.epoch_id() < 3)?, 4);
+        assert_eq!(storage.resident_epochs(), vec![3]);
+        assert_eq!(storage.remaining_budget(&global(1))?, 20.0);
+
+        // Shards with filters left are kept, e.g. for coarser queriers.
+        let weekly = FilterId::PerQuerier(3, "weekly.com".to_string());
+        storage.try_consume(&weekly, &0.5)?;
+        assert_eq!(storage.prune(&|fid| *fid == global(3))?, 1);
+        assert_eq!(storage.resident_epochs(), vec![3]);
+        assert_eq!(storage.remaining_budget(&weekly)?, 0.5);
+
+        // Eviction needs a store.
+        assert!(storage.evict_shard(&3).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_shard_eviction() -> Result<(), anyhow::Error> {
+        let store = MockShardStore::default();
+        let mut storage = Sharded::new(StaticCapacities::mock())?
+            .with_shard_store(store.clone(), 2)?;
+
+        for epoch in 1..=3 {
+            storage.try_consume(&global(epoch), &(epoch as f64))?;
+        }
+
+        // The least recently used shard was evicted.
+        assert_eq!(storage.resident_epochs(), vec![2, 3]);
+        assert_eq!(store.stored_epochs()?, vec![1]);
+
+        // Evicted shards are loaded back lazily, with their consumed budget.
+        storage.try_consume(&global(2), &0.0)?;
+        assert_eq!(storage.remaining_budget(&global(1))?, 19.0);
+        assert_eq!(storage.resident_epochs(), vec![1, 2]);
+        assert_eq!(store.stored_epochs()?, vec![3]);
+
+        // Tightening reaches the stored shards too.
+        let mut capacities = StaticCapacities::mock();
+        capacities.global = 10.0;
+        storage.set_capacities(capacities, true)?;
+        assert_eq!(storage.remaining_budget(&global(3))?, 7.0);
+
+        // Pruning removes stored shards as well. Shards that still have
+        // filters are loaded to be pruned.
+        storage.flush()?;
+        assert!(storage.resident_epochs().is_empty());
+        assert_eq!(storage.prune(&|fid| *fid.epoch_id() < 3)?, 2);
+        assert!(store.stored_epochs()?.is_empty());
+        assert_eq!(storage.resident_epochs(), vec![3]);
+
+        assert!(Sharded::new(StaticCapacities::mock())?
+            .with_shard_store(store, 0)
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reservations_survive_restart() -> Result<(), anyhow::Error> {
+        let store = MockShardStore::default();
+        let mut storage = Sharded::new(StaticCapacities::mock())?
+            .with_shard_store(store.clone(), 2)?;
+        let reservation = |epoch| Reservation {
+            deductions: vec![(global(epoch), 1.0)],
+            expires_at: 10,
+        };
+        for token in 1..=2 {
+            storage.try_consume(&global(token), &1.0)?;
+            storage.set_reservation(token, reservation(token))?;
+        }
+        storage.flush()?;
+
+        // After a restart, reservations can still be committed or released.
+        let mut storage = Sharded::new(StaticCapacities::mock())?
+            .with_shard_store(store.clone(), 2)?;
+        assert_eq!(storage.reservations()?.len(), 2);
+        assert_eq!(storage.take_reservation(1)?, Some(reservation(1)));
+        assert_eq!(storage.take_reservation(1)?, None);
+
+        let mut storage = Sharded::new(StaticCapacities::mock())?
+            .with_shard_store(store, 2)?;
+        assert_eq!(storage.reservations()?, vec![(2, reservation(2))]);
+        assert_eq!(storage.remaining_budget(&global(2))?, 19.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pruning_watermark_survives_restart() -> Result<(), anyhow::Error> {
+        let store = MockShardStore::default();
+        let mut storage = Sharded::new(StaticCapacities::mock())?
+            .with_shard_store(store.clone(), 2)?;
+        assert_eq!(storage.pruned_before(), None);
+        storage.set_pruned_before(3)?;
+
+        let storage = Sharded::new(StaticCapacities::mock())?
+            .with_shard_store(store, 2)?;
+        assert_eq!(storage.pruned_before(), Some(3));
+
+        Ok(())
+    }
+}
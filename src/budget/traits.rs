@@ -1,10 +1,74 @@
-use std::fmt::Debug;
+use alloc::vec::Vec;
+use core::{
+    fmt::Debug,
+    ops::{Add, Sub},
+};
+
+use super::reservation::{Reservation, ReservationToken};
+use crate::pds::rate_limit::RateLimit;
 
 /// Trait for privacy budgets
 pub trait Budget: Clone + Debug {
     // For now just a marker trait requiring Clone
 }
 
+/// Multiplication of a budget by a non-negative scalar. Budgets with limited
+/// precision round in the conservative direction.
+pub trait Scale {
+    /// Scales a loss, rounded up.
+    fn scale(&self, factor: f64) -> Self;
+
+    /// Scales a capacity, rounded down, e.g. to split it into releases that
+    /// don't add up to more than the capacity.
+    fn scale_capacity(&self, factor: f64) -> Self
+    where
+        Self: Sized,
+    {
+        self.scale(factor)
+    }
+}
+
+/// Trait for budgets with arithmetic and ordering, so that filters and
+/// schedulers don't need to know the concrete budget type (e.g. pure DP
+/// epsilons, epsilon-delta pairs or RDP vectors). The order can be partial,
+/// e.g. for budgets with multiple dimensions.
+pub trait BudgetOps:
+    Budget + Add<Output = Self> + Sub<Output = Self> + PartialOrd + Scale
+{
+    /// Empty budget, e.g. the loss of a query without relevant events.
+    fn zero() -> Self;
+
+    /// Budget larger than any other, e.g. the capacity of deactivated
+    /// filters or the loss of noiseless queries.
+    fn infinity() -> Self;
+
+    fn is_infinite(&self) -> bool;
+
+    /// Subtraction clamped at zero.
+    fn saturating_sub(&self, other: &Self) -> Self {
+        match other >= self {
+            true => Self::zero(),
+            false => self.clone() - other.clone(),
+        }
+    }
+
+    /// Minimum of two budgets. Returns `self` if they are not comparable.
+    fn min_budget(&self, other: &Self) -> Self {
+        match other < self {
+            true => other.clone(),
+            false => self.clone(),
+        }
+    }
+
+    /// Maximum of two budgets. Returns `self` if they are not comparable.
+    fn max_budget(&self, other: &Self) -> Self {
+        match other > self {
+            true => other.clone(),
+            false => self.clone(),
+        }
+    }
+}
+
 /// Trait for a privacy filter.
 pub trait Filter<B: Budget> {
     type Error;
@@ -22,24 +86,63 @@ pub trait Filter<B: Budget> {
     /// Continue corresponds to CONTINUE, and OutOfBudget corresponds to HALT.
     fn try_consume(&mut self, budget: &B) -> Result<FilterStatus, Self::Error>;
 
-    /// [Experimental] Gets the remaining budget for this filter.
+    /// Lowers the capacity of the filter to `capacity`. This is a no-op if
+    /// the current capacity is already lower. Consumed budget is never
+    /// refunded, so the filter can end up out of budget.
+    fn tighten_capacity(&mut self, capacity: &B) -> Result<(), Self::Error>;
+
+    /// Adds `amount` to the capacity of the filter. Only sound for budget
+    /// that the user explicitly granted, see `ConsentVerifier`.
+    fn raise_capacity(&mut self, amount: &B) -> Result<(), Self::Error>;
+
+    /// Gives back budget consumed by `try_consume`. Only sound if the
+    /// consumption never influenced any output released off the device, e.g.
+    /// for a reservation whose report is dropped.
+    fn refund(&mut self, budget: &B) -> Result<(), Self::Error>;
+
+    /// [Experimental] Gets the remaining budget for this filter, i.e. the
+    /// largest budget that `can_consume` currently accepts.
     /// WARNING: this method is for local visualization only.
     /// Its output should not be shared outside the device.
     #[cfg(feature = "experimental")]
     fn remaining_budget(&self) -> Result<B, Self::Error>;
 }
 
-/// Trait for a filter that can release budget over time.
+/// Trait for a filter that can release budget over time. Budget is consumed
+/// from the unlocked budget, which grows up to the capacity as budget is
+/// released, so `consumed <= unlocked <= capacity` for finite capacities.
+/// `remaining_budget` is the same as `unlocked_remaining`.
 pub trait ReleaseFilter<B: Budget>: Filter<B> {
     /// Gets the current capacity of the filter.
     fn get_capacity(&self) -> Result<B, Self::Error>;
 
+    /// Gets the budget consumed so far.
+    fn consumed(&self) -> Result<B, Self::Error>;
+
+    /// Gets the budget that can be consumed right now, i.e. unlocked but not
+    /// consumed yet.
+    fn unlocked_remaining(&self) -> Result<B, Self::Error>;
+
+    /// Gets the budget that can be consumed once the whole capacity is
+    /// unlocked, i.e. the capacity minus the consumed budget.
+    fn capacity_remaining(&self) -> Result<B, Self::Error>;
+
+    /// Gets how much `budget` exceeds what can be consumed right now, or zero
+    /// if it can be consumed. Tells requests that barely miss from hopeless
+    /// ones. Internal scheduling information only: computed on public
+    /// filters, and never returned to queriers.
+    fn shortfall(&self, budget: &B) -> Result<B, Self::Error>;
+
     /// Updates the capacity of the filter.
     fn set_capacity(&mut self, capacity: B) -> Result<(), Self::Error>;
 
     /// Only release up to the capacity. `release` becomes a no-op once the
     /// unlocked budget reaches capacity.
     fn release(&mut self, budget_to_unlock: &B) -> Result<(), Self::Error>;
+
+    /// Locks the budget that is not consumed yet, so it can only be consumed
+    /// once released again. Filters may start locked or fully unlocked.
+    fn lock(&mut self) -> Result<(), Self::Error>;
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -48,6 +151,14 @@ pub enum FilterStatus {
     OutOfBudget,
 }
 
+/// Trait for filter IDs that are attached to an epoch. Used to prune filters
+/// once their epoch falls out of any possible attribution window.
+pub trait EpochFilterId {
+    type EpochId: Ord + Clone + Debug;
+
+    fn epoch_id(&self) -> &Self::EpochId;
+}
+
 pub trait FilterCapacities {
     type FilterId: Eq;
     type Budget: Budget;
@@ -57,6 +168,24 @@ pub trait FilterCapacities {
         &self,
         filter_id: &Self::FilterId,
     ) -> Result<Self::Budget, Self::Error>;
+
+    /// Version of the capacity policy, recorded by filter storages for
+    /// auditability when capacities are updated.
+    fn policy_version(&self) -> u64 {
+        0
+    }
+
+    /// Limit on the request rate of each querier, checked before any
+    /// accounting. Unlimited if None.
+    fn rate_limit(&self) -> Option<RateLimit> {
+        None
+    }
+
+    /// Whether requests are charged to the `SourceTriggerQuota` filter of
+    /// each pair of source and trigger URIs.
+    fn source_trigger_quotas(&self) -> bool {
+        false
+    }
 }
 
 /// Trait for an interface or object that maintains a collection of filters.
@@ -76,10 +205,19 @@ pub trait FilterStorage {
     where
         Self: Sized;
 
-    /// Get the capacities object that was passed to the constructor.
-    /// Note: for the privacy proof to be valid, the capacities must not change.
+    /// Get the current capacities object.
     fn capacities(&self) -> &Self::Capacities;
 
+    /// Replace the capacities used for new filters. If `tighten_existing` is
+    /// true, existing filters are also tightened to the new capacities.
+    /// Note: for the privacy proof to be valid, the capacity of an existing
+    /// filter must never increase, so existing filters are never loosened.
+    fn set_capacities(
+        &mut self,
+        capacities: Self::Capacities,
+        tighten_existing: bool,
+    ) -> Result<(), Self::Error>;
+
     /// Get the filter with the given ID from the storage.
     /// Returns None if the filter has not been set yet.
     /// Note: for the privacy proof to be valid, get_filter() must always
@@ -96,6 +234,128 @@ pub trait FilterStorage {
         filter: Self::Filter,
     ) -> Result<(), Self::Error>;
 
+    /// Remove all the filters for which `is_pruned` returns true, e.g. the
+    /// filters of the epochs older than the pruning watermark, along with
+    /// their repayments and carryovers, and return the number of removed
+    /// filters.
+    /// Note: a pruned filter is recreated with full capacity if it is
+    /// requested again, so for the privacy proof to remain valid, callers
+    /// must never consume budget from pruned epochs again.
+    fn prune(
+        &mut self,
+        is_pruned: &dyn Fn(&Self::FilterId) -> bool,
+    ) -> Result<usize, Self::Error>;
+
+    /// Whether the storage holds no filters, repayments nor carryovers, e.g.
+    /// to drop empty shards.
+    fn is_empty(&self) -> Result<bool, Self::Error>;
+
+    /// Epoch before which all the filters have been pruned, if any. The
+    /// pruning watermark must be persisted along with the filters, otherwise
+    /// pruned epochs could be charged again after a restart, with their full
+    /// capacity.
+    fn pruned_before(
+        &self,
+    ) -> Option<<Self::FilterId as EpochFilterId>::EpochId>
+    where
+        Self::FilterId: EpochFilterId;
+
+    /// Move the pruning watermark, see `pruned_before`.
+    fn set_pruned_before(
+        &mut self,
+        epoch_id: <Self::FilterId as EpochFilterId>::EpochId,
+    ) -> Result<(), Self::Error>
+    where
+        Self::FilterId: EpochFilterId;
+
+    /// Store a budget reservation, replacing any reservation with the same
+    /// token. Reservations must be persisted along with the filters, so that
+    /// reserved budget is accounted for across restarts.
+    fn set_reservation(
+        &mut self,
+        token: ReservationToken,
+        reservation: Reservation<Self::FilterId, Self::Budget>,
+    ) -> Result<(), Self::Error>;
+
+    /// Remove the reservation with the given token from the storage and
+    /// return it. Returns None if there is no such reservation.
+    #[allow(clippy::type_complexity)]
+    fn take_reservation(
+        &mut self,
+        token: ReservationToken,
+    ) -> Result<Option<Reservation<Self::FilterId, Self::Budget>>, Self::Error>;
+
+    /// Get all the pending reservations.
+    #[allow(clippy::type_complexity)]
+    fn reservations(
+        &self,
+    ) -> Result<
+        Vec<(ReservationToken, Reservation<Self::FilterId, Self::Budget>)>,
+        Self::Error,
+    >;
+
+    /// Store the budget borrowed from a filter that is not initialized yet,
+    /// replacing any previous amount for the same filter. Repayments must be
+    /// persisted along with the filters, otherwise a lender initialized after
+    /// a restart would get its full capacity back.
+    fn set_repayment(
+        &mut self,
+        filter_id: &Self::FilterId,
+        budget: Self::Budget,
+    ) -> Result<(), Self::Error>;
+
+    /// Remove the repayment of the filter with the given ID from the storage
+    /// and return it. Returns None if the filter didn't lend any budget.
+    fn take_repayment(
+        &mut self,
+        filter_id: &Self::FilterId,
+    ) -> Result<Option<Self::Budget>, Self::Error>;
+
+    /// Get the budget borrowed so far from the filter with the given ID.
+    fn get_repayment(
+        &mut self,
+        filter_id: &Self::FilterId,
+    ) -> Result<Option<Self::Budget>, Self::Error>;
+
+    /// Store the total budget carried over into the filter with the given ID
+    /// from older epochs, replacing any previous amount. Carryovers must be
+    /// persisted along with the filters, so that the cap on the carried
+    /// budget still holds after a restart.
+    fn set_carryover(
+        &mut self,
+        filter_id: &Self::FilterId,
+        budget: Self::Budget,
+    ) -> Result<(), Self::Error>;
+
+    /// Get the total budget carried over into the filter with the given ID.
+    fn get_carryover(
+        &mut self,
+        filter_id: &Self::FilterId,
+    ) -> Result<Option<Self::Budget>, Self::Error>;
+
+    /// Version of the stored filters, bumped by every write. Hosts reading
+    /// several filters from a storage shared across threads, e.g. to display
+    /// the remaining budgets, can retry when the generation changed in
+    /// between. Storages that are only written through `&mut self` can keep
+    /// the default.
+    fn generation(&self) -> u64 {
+        0
+    }
+
+    /// Give back budget to the filter with the given ID. This is a no-op if
+    /// the filter does not exist anymore, e.g. because it was pruned.
+    fn refund(
+        &mut self,
+        filter_id: &Self::FilterId,
+        budget: &Self::Budget,
+    ) -> Result<(), Self::Error> {
+        if let Some(mut filter) = self.get_filter(filter_id)? {
+            filter.refund(budget)?;
+            self.set_filter(filter_id, filter)?;
+        }
+        Ok(())
+    }
+
     /// Get the filter with the given ID from the storage, or return a new one
     /// with default capacity if it does not exist.
     fn get_filter_or_new(
@@ -0,0 +1,83 @@
+use alloc::string::String;
+
+use thiserror::Error;
+
+/// Errors returned by the filters, storages and the PDS core. Embedders can
+/// match on the variants, or convert them into their own error type through
+/// the `ERR: From<PdsError>` bound of the PDS.
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum PdsError {
+    /// The underlying filter or event storage failed.
+    #[error("storage error: {0}")]
+    StorageError(String),
+
+    /// Reports could not be delivered to the aggregation service.
+    #[error("delivery error: {0}")]
+    DeliveryError(String),
+
+    /// A filter was accessed before being initialized, for storages that
+    /// don't create filters lazily.
+    #[error("filter {0} is not initialized")]
+    FilterUninitialized(String),
+
+    /// The request or its configuration is invalid.
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+
+    /// A report was already issued for this request, e.g. to the same
+    /// querier.
+    #[error("report already issued: {0}")]
+    ReportAlreadyIssued(String),
+
+    /// The epochs requested are inverted, too many, or in the future.
+    #[error("invalid epoch window: {0}")]
+    InvalidEpochWindow(String),
+
+    /// A filter consumed more than its capacity, e.g. because a deduction
+    /// failed after its dry run succeeded.
+    #[error("capacity exceeded: {0}")]
+    CapacityExceeded(String),
+
+    /// The storage kept changing while it was being read, e.g. because
+    /// events arrived during the computation of a report. The caller can
+    /// retry later.
+    #[error("concurrent modification: {0}")]
+    ConcurrentModification(String),
+
+    /// The request asks for less noise than the device allows for its
+    /// sensitivity, i.e. its reports would have too large an epsilon.
+    #[error("noise below floor: {0}")]
+    NoiseBelowFloor(String),
+
+    /// The querier sent too many requests recently, regardless of its
+    /// remaining budget. The caller can retry later.
+    #[error("rate limited: {0}")]
+    RateLimited(String),
+
+    /// Too many requests are waiting to be scheduled. The caller can retry
+    /// after the next scheduling interval.
+    #[error("queue full: {0}")]
+    QueueFull(String),
+
+    /// Persisted filters don't match their integrity tag, e.g. because the
+    /// store was corrupted or edited by hand.
+    #[error("integrity violation: {0}")]
+    IntegrityViolation(String),
+
+    /// A configuration file could not be read, or has invalid values.
+    #[error("invalid config: {0}")]
+    InvalidConfig(String),
+
+    /// A budget grant was not backed by a valid user consent.
+    #[error("consent rejected: {0}")]
+    ConsentRejected(String),
+
+    /// An event was registered for an epoch that is too old, e.g. after the
+    /// device clock jumped backwards.
+    #[error("stale event: {0}")]
+    StaleEvent(String),
+
+    /// Unexpected internal state.
+    #[error("internal error: {0}")]
+    Internal(String),
+}
@@ -0,0 +1,44 @@
+use crate::events::{
+    ppa_event::PpaEvent,
+    simple_event::SimpleEvent,
+    traits::{Event, EventUris},
+};
+
+/// Event of any of the supported types, so that a single event storage can
+/// serve `AnyEpochReportRequest`s of different types.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnyEvent {
+    Simple(SimpleEvent),
+    Ppa(PpaEvent),
+}
+
+impl Event for AnyEvent {
+    type EpochId = u64;
+    type Uri = String;
+
+    fn epoch_id(&self) -> Self::EpochId {
+        match self {
+            AnyEvent::Simple(event) => event.epoch_id(),
+            AnyEvent::Ppa(event) => event.epoch_id(),
+        }
+    }
+
+    fn event_uris(&self) -> &EventUris<Self::Uri> {
+        match self {
+            AnyEvent::Simple(event) => event.event_uris(),
+            AnyEvent::Ppa(event) => event.event_uris(),
+        }
+    }
+}
+
+impl From<SimpleEvent> for AnyEvent {
+    fn from(event: SimpleEvent) -> Self {
+        AnyEvent::Simple(event)
+    }
+}
+
+impl From<PpaEvent> for AnyEvent {
+    fn from(event: PpaEvent) -> Self {
+        AnyEvent::Ppa(event)
+    }
+}
@@ -0,0 +1,64 @@
+use std::collections::BTreeMap;
+
+use super::traits::Uri;
+use crate::{
+    events::traits::{Event, EventUris},
+    queries::ara_histogram::{AraBucketKey, AraEpochId},
+};
+
+/// Filter data of an ARA source, or filters of an ARA trigger: lists of
+/// values by filter name.
+pub type AraFilterData = BTreeMap<String, Vec<String>>;
+
+/// Source registration in the Attribution Reporting API (ARA). Maps are
+/// ordered so that events can be hashed, like other events.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AraEvent<U: Uri = String> {
+    /// Event ID in the storage, for debugging purposes.
+    pub id: u64,
+
+    /// ID chosen by the source site, returned in event-level reports.
+    pub source_event_id: u64,
+
+    /// Registration time, in seconds. Used to order events for last-touch
+    /// attribution.
+    pub timestamp: u64,
+
+    pub epoch_number: AraEpochId,
+
+    /// Time after the registration during which the source can be
+    /// attributed, in seconds.
+    pub expiry: u64,
+
+    /// Sources with a higher priority are attributed first.
+    pub priority: i64,
+
+    /// Filter data matched against the filters of the triggers.
+    pub filter_data: AraFilterData,
+
+    /// Source-side key pieces, by aggregation key ID. A trigger contributes
+    /// to one bucket per key that it has a value for.
+    pub aggregation_keys: BTreeMap<String, AraBucketKey>,
+
+    pub uris: EventUris<U>,
+}
+
+impl<U: Uri> AraEvent<U> {
+    /// Whether the source can no longer be attributed at `time`.
+    pub fn is_expired_at(&self, time: u64) -> bool {
+        time >= self.timestamp.saturating_add(self.expiry)
+    }
+}
+
+impl<U: Uri> Event for AraEvent<U> {
+    type EpochId = AraEpochId;
+    type Uri = U;
+
+    fn epoch_id(&self) -> Self::EpochId {
+        self.epoch_number
+    }
+
+    fn event_uris(&self) -> &EventUris<U> {
+        &self.uris
+    }
+}
@@ -1,14 +1,37 @@
+use std::fmt;
+
+use log::debug;
+
 use crate::{
-    events::traits::{Event, EventStorage},
-    util::hashmap::HashMap,
+    error::PdsError,
+    events::{
+        retention::RetentionPolicy,
+        traits::{Event, EventStorage},
+    },
+    util::{
+        clock::{Clock, SystemClock},
+        hashmap::HashMap,
+    },
 };
 
 /// A simple in-memory event storage. Stores a mapping of epoch id to epoch
-/// events, where each epoch events is just a vec of events.
-/// Clones events when asked to retrieve events for an epoch.
-#[derive(Debug, Default)]
+/// events, where each epoch events is just a vec of events along with their
+/// ingestion time. Only relevant events are cloned when building a report.
 pub struct HashMapEventStorage<E: Event> {
-    epochs: HashMap<E::EpochId, Vec<E>>,
+    epochs: HashMap<E::EpochId, Vec<(u64, E)>>,
+
+    /// Limits on how long events are kept. Unbounded by default.
+    retention_policy: RetentionPolicy,
+
+    /// Epochs up to this one (included) have been retired by the retention
+    /// policy.
+    retired_through: Option<E::EpochId>,
+
+    /// Clock used to timestamp ingested events.
+    clock: Box<dyn Clock>,
+
+    /// Number of writes so far.
+    generation: u64,
 }
 
 /// Simple in-memory event storage. Stores a mapping of epoch id to events
@@ -17,7 +40,61 @@ impl<E: Event> HashMapEventStorage<E> {
     pub fn new() -> Self {
         Self {
             epochs: HashMap::new(),
+            retention_policy: RetentionPolicy::default(),
+            retired_through: None,
+            clock: Box::new(SystemClock),
+            generation: 0,
+        }
+    }
+
+    /// Sets the retention policy enforced at ingestion and by
+    /// `drop_stale_events`.
+    pub fn with_retention_policy(
+        mut self,
+        retention_policy: RetentionPolicy,
+    ) -> Self {
+        self.retention_policy = retention_policy;
+        self
+    }
+
+    /// Replaces the clock, e.g. with a `MockClock` for simulations.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Retires the oldest epochs until at most `max_epochs` epochs are left.
+    fn retire_epochs(&mut self) {
+        let Some(max_epochs) = self.retention_policy.max_epochs else {
+            return;
+        };
+        if self.epochs.len() <= max_epochs {
+            return;
         }
+
+        let mut epoch_ids = self.epochs.keys().copied().collect::<Vec<_>>();
+        epoch_ids.sort();
+        let retired_through = epoch_ids[epoch_ids.len() - max_epochs - 1];
+        self.retired_through = self.retired_through.max(Some(retired_through));
+        self.epochs
+            .retain(|epoch_id, _| *epoch_id > retired_through);
+        debug!("Retired epochs up to {retired_through:?}");
+    }
+}
+
+impl<E: Event> Default for HashMapEventStorage<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<E: Event> fmt::Debug for HashMapEventStorage<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HashMapEventStorage")
+            .field("epochs", &self.epochs)
+            .field("retention_policy", &self.retention_policy)
+            .field("retired_through", &self.retired_through)
+            .finish_non_exhaustive()
     }
 }
 
@@ -26,22 +103,168 @@ where
     E: Event + Clone,
 {
     type Event = E;
-    type Error = anyhow::Error;
+    type Error = PdsError;
 
     fn add_event(&mut self, event: E) -> Result<(), Self::Error> {
         let epoch_id = event.epoch_id();
+        if self.is_retired(&epoch_id) {
+            debug!("Dropping event for retired epoch {epoch_id:?}");
+            return Ok(());
+        }
+
+        let ingested_at = self.clock.now();
         let epoch = self.epochs.entry(epoch_id).or_default();
-        epoch.push(event);
+        epoch.push((ingested_at, event));
+        self.generation += 1;
+        self.retire_epochs();
         Ok(())
     }
 
     fn events_for_epoch(
-        &mut self,
+        &self,
         epoch_id: &<Self::Event as Event>::EpochId,
-    ) -> Result<impl Iterator<Item = Self::Event>, Self::Error> {
-        let events = self.epochs.get(epoch_id).cloned().unwrap_or_default();
+    ) -> Result<impl Iterator<Item = &Self::Event>, Self::Error> {
+        // Skip the events that became stale since the last sweep.
+        let now = self.clock.now();
+        let events = self
+            .epochs
+            .get(epoch_id)
+            .map(|events| events.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .filter(move |(ingested_at, _)| {
+                self.retention_policy.is_fresh(*ingested_at, now)
+            })
+            .map(|(_, event)| event);
+        Ok(events)
+    }
+
+    fn drop_stale_events(&mut self) -> Result<usize, Self::Error> {
+        let now = self.clock.now();
+        let mut n_dropped = 0;
+        for events in self.epochs.values_mut() {
+            let n_events = events.len();
+            events.retain(|(ingested_at, _)| {
+                self.retention_policy.is_fresh(*ingested_at, now)
+            });
+            n_dropped += n_events - events.len();
+        }
+        self.epochs.retain(|_, events| !events.is_empty());
+        if n_dropped > 0 {
+            self.generation += 1;
+        }
+        debug!("Dropped {n_dropped} stale events");
+        Ok(n_dropped)
+    }
 
-        let iterator = events.into_iter();
-        Ok(iterator)
+    fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    fn is_retired(&self, epoch_id: &<Self::Event as Event>::EpochId) -> bool {
+        self.retired_through
+            .is_some_and(|retired_through| *epoch_id <= retired_through)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        events::{
+            relevant_events::RelevantEvents, simple_event::SimpleEvent,
+            traits::EventUris,
+        },
+        queries::simple_last_touch_histogram::SimpleRelevantEventSelector,
+        util::clock::MockClock,
+    };
+
+    fn event(id: u64, epoch_number: u64) -> SimpleEvent {
+        SimpleEvent {
+            id,
+            epoch_number,
+            event_key: 1,
+            uris: EventUris::mock(),
+        }
+    }
+
+    #[test]
+    fn test_max_epochs_retention() -> Result<(), PdsError> {
+        let mut storage = HashMapEventStorage::new().with_retention_policy(
+            RetentionPolicy::default().with_max_epochs(2),
+        );
+
+        for epoch_number in 1..=4 {
+            storage.add_event(event(epoch_number, epoch_number))?;
+        }
+        assert!(storage.is_retired(&2));
+        assert!(!storage.is_retired(&3));
+        assert_eq!(storage.events_for_epoch(&2)?.count(), 0);
+        assert_eq!(storage.events_for_epoch(&3)?.count(), 1);
+
+        // Late events for retired epochs are dropped at ingestion.
+        storage.add_event(event(5, 1))?;
+        assert_eq!(storage.events_for_epoch(&1)?.count(), 0);
+
+        let selector = SimpleRelevantEventSelector { lambda: |_| true };
+        let relevant_events =
+            RelevantEvents::from_event_storage(&storage, &[1, 3], &selector)?;
+        assert!(!relevant_events.events_per_epoch.contains_key(&1));
+        assert_eq!(relevant_events.for_epoch(&3).len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_relevant_events_for_epoch() -> Result<(), PdsError> {
+        let mut storage = HashMapEventStorage::new();
+        storage.add_event(event(1, 1))?;
+        storage.add_event(SimpleEvent {
+            event_key: 2,
+            ..event(2, 1)
+        })?;
+
+        // Events are streamed by reference, and only relevant ones are
+        // copied.
+        let ids = storage
+            .events_for_epoch(&1)?
+            .map(|event| event.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![1, 2]);
+        let selector = SimpleRelevantEventSelector {
+            lambda: |event: &SimpleEvent| event.event_key == 2,
+        };
+        let events = storage.relevant_events_for_epoch(&1, &selector)?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].id, 2);
+        assert!(storage.relevant_events_for_epoch(&2, &selector)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_age_retention() -> Result<(), PdsError> {
+        let clock = MockClock::new(0);
+        let mut storage = HashMapEventStorage::new()
+            .with_retention_policy(RetentionPolicy::default().with_max_age(10))
+            .with_clock(clock.clone());
+
+        storage.add_event(event(1, 1))?;
+        clock.advance(5);
+        storage.add_event(event(2, 1))?;
+        storage.add_event(event(3, 2))?;
+
+        // Stale events are skipped even before the sweep.
+        clock.advance(6);
+        assert_eq!(storage.events_for_epoch(&1)?.count(), 1);
+
+        let generation = storage.generation();
+        assert_eq!(storage.drop_stale_events()?, 1);
+        assert_eq!(storage.generation(), generation + 1);
+        clock.advance(5);
+        assert_eq!(storage.drop_stale_events()?, 2);
+        assert_eq!(storage.events_for_epoch(&2)?.count(), 0);
+
+        Ok(())
     }
 }
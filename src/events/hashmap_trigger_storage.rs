@@ -0,0 +1,131 @@
+use crate::{
+    error::PdsError,
+    events::{
+        traits::{TriggerStorage, Uri},
+        trigger_event::TriggerEvent,
+    },
+    util::hashmap::{HashMap, HashSet},
+};
+
+/// A simple in-memory trigger storage, keyed by trigger ID.
+#[derive(Debug)]
+pub struct HashMapTriggerStorage<U: Uri = String> {
+    triggers: HashMap<u64, TriggerEvent<U>>,
+}
+
+impl<U: Uri> HashMapTriggerStorage<U> {
+    pub fn new() -> Self {
+        Self {
+            triggers: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.triggers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.triggers.is_empty()
+    }
+}
+
+impl<U: Uri> Default for HashMapTriggerStorage<U> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<U: Uri> TriggerStorage for HashMapTriggerStorage<U> {
+    type Uri = U;
+    type Error = PdsError;
+
+    fn add_trigger(
+        &mut self,
+        trigger: TriggerEvent<U>,
+    ) -> Result<(), PdsError> {
+        if self.triggers.contains_key(&trigger.id) {
+            return Err(PdsError::InvalidRequest(format!(
+                "trigger {} is already stored",
+                trigger.id
+            )));
+        }
+        self.triggers.insert(trigger.id, trigger);
+        Ok(())
+    }
+
+    fn triggers_for_uri(
+        &self,
+        trigger_uri: &U,
+    ) -> Result<Vec<&TriggerEvent<U>>, PdsError> {
+        let mut triggers = self
+            .triggers
+            .values()
+            .filter(|trigger| trigger.trigger_uri == *trigger_uri)
+            .collect::<Vec<_>>();
+        triggers.sort_by_key(|trigger| (trigger.timestamp, trigger.id));
+        Ok(triggers)
+    }
+
+    fn take_triggers(
+        &mut self,
+        trigger_ids: &[u64],
+    ) -> Result<Vec<TriggerEvent<U>>, PdsError> {
+        let mut seen = HashSet::new();
+        for trigger_id in trigger_ids {
+            if !self.triggers.contains_key(trigger_id)
+                || !seen.insert(trigger_id)
+            {
+                return Err(PdsError::InvalidRequest(format!(
+                    "trigger {trigger_id} is not stored or was already taken"
+                )));
+            }
+        }
+        Ok(trigger_ids
+            .iter()
+            .filter_map(|trigger_id| self.triggers.remove(trigger_id))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trigger(id: u64, timestamp: u64, trigger_uri: &str) -> TriggerEvent {
+        TriggerEvent {
+            id,
+            timestamp,
+            epoch_number: 1,
+            trigger_uri: trigger_uri.to_string(),
+            querier_uris: vec!["adtech.com".to_string()],
+            value: 10.0,
+        }
+    }
+
+    #[test]
+    fn test_trigger_storage() -> Result<(), PdsError> {
+        let mut storage = HashMapTriggerStorage::new();
+        storage.add_trigger(trigger(1, 20, "shoes.com"))?;
+        storage.add_trigger(trigger(2, 10, "shoes.com"))?;
+        storage.add_trigger(trigger(3, 5, "hats.com"))?;
+        assert!(storage.add_trigger(trigger(1, 30, "shoes.com")).is_err());
+
+        let ids = storage
+            .triggers_for_uri(&"shoes.com".to_string())?
+            .iter()
+            .map(|trigger| trigger.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [2, 1]);
+
+        // Triggers are taken all at once or not at all.
+        assert!(storage.take_triggers(&[1, 4]).is_err());
+        assert!(storage.take_triggers(&[1, 1]).is_err());
+        assert_eq!(storage.len(), 3);
+
+        let taken = storage.take_triggers(&[1, 2])?;
+        assert_eq!(taken.iter().map(|t| t.id).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(storage.len(), 1);
+        assert!(storage.take_triggers(&[1]).is_err());
+        Ok(())
+    }
+}
@@ -1,5 +1,18 @@
+#[cfg(feature = "std")]
+pub mod any_event;
+#[cfg(feature = "std")]
+pub mod ara_event;
+#[cfg(feature = "std")]
 pub mod hashmap_event_storage;
+#[cfg(feature = "std")]
+pub mod hashmap_trigger_storage;
 pub mod ppa_event;
 pub mod relevant_events;
+#[cfg(feature = "std")]
+pub mod retention;
+#[cfg(feature = "std")]
+pub mod selectors;
+#[cfg(feature = "std")]
 pub mod simple_event;
 pub mod traits;
+pub mod trigger_event;
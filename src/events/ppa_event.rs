@@ -1,4 +1,7 @@
-use std::fmt::Debug;
+use alloc::string::String;
+use core::fmt::Debug;
+
+use serde::{Deserialize, Serialize};
 
 use super::traits::Uri;
 use crate::{
@@ -7,7 +10,7 @@ use crate::{
 };
 
 /// Impression event
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct PpaEvent<U: Uri = String> {
     /// Event ID, e.g., counter or random ID. Unused in Firefox but kept for
     /// debugging purposes.
@@ -28,6 +31,11 @@ pub struct PpaEvent<U: Uri = String> {
     /// has explicit campaign_id or ad_id fields, the PPA spec uses
     /// filter_data as a more generic mechanism for filtering events.
     pub filter_data: PpaFilterData,
+
+    /// Source priority, as in ARA. Only used by
+    /// `AttributionLogic::PriorityThenLastTouch`, where events with a higher
+    /// priority are attributed before more recent ones. Defaults to 0.
+    pub priority: i64,
 }
 
 impl<U: Uri> Event for PpaEvent<U> {
@@ -1,3 +1,5 @@
+use alloc::vec::Vec;
+
 use super::traits::{Event, EventStorage, RelevantEventSelector};
 use crate::util::hashmap::{HashMap, HashSet};
 
@@ -12,9 +14,9 @@ pub struct RelevantEvents<E: Event> {
 
 impl<E: Event> RelevantEvents<E> {
     /// Fetches and filters relevant events from the given event storage,
-    /// for the specified epochs.
+    /// for the specified epochs. Epochs retired by the storage are skipped.
     pub fn from_event_storage<ES>(
-        event_storage: &mut ES,
+        event_storage: &ES,
         epoch_ids: &[E::EpochId],
         selector: &impl RelevantEventSelector<Event = E>,
     ) -> Result<Self, ES::Error>
@@ -24,12 +26,14 @@ impl<E: Event> RelevantEvents<E> {
         let mut events_per_epoch = HashMap::new();
 
         for epoch_id in epoch_ids {
-            // fetch all events at that epoch from storage
-            let events = event_storage
-                .events_for_epoch(epoch_id)?
-                // filter relevant events using the selector
-                .filter(|event| selector.is_relevant_event(event))
-                .collect();
+            // retired epochs have no events left, don't even look them up
+            if event_storage.is_retired(epoch_id) {
+                continue;
+            }
+
+            // fetch the relevant events at that epoch from storage
+            let events =
+                event_storage.relevant_events_for_epoch(epoch_id, selector)?;
 
             // store the events in the map
             events_per_epoch.insert(*epoch_id, events);
@@ -78,6 +82,13 @@ impl<E: Event> RelevantEvents<E> {
             .collect::<HashSet<&E::Uri>>()
     }
 
+    /// Keeps only the events for which `keep` returns true, in every epoch.
+    pub fn retain(&mut self, mut keep: impl FnMut(&E) -> bool) {
+        for events in self.events_per_epoch.values_mut() {
+            events.retain(|event| keep(event));
+        }
+    }
+
     /// Drop and forget the given epoch and all its events.
     pub fn drop_epoch(&mut self, epoch_id: &E::EpochId) {
         self.events_per_epoch.remove(epoch_id);
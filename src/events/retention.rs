@@ -0,0 +1,33 @@
+/// Policy bounding how long events are kept in an event storage, so that
+/// they don't outlive the maximum attribution window. Both limits can be
+/// combined, and events are dropped as soon as they exceed either one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Only keep the events of the `max_epochs` most recent epochs that have
+    /// events. Older epochs are retired, and events for them are dropped at
+    /// ingestion.
+    pub max_epochs: Option<usize>,
+
+    /// Maximum age of an event since its ingestion, in seconds.
+    pub max_age: Option<u64>,
+}
+
+impl RetentionPolicy {
+    /// Keeps the events of the `max_epochs` most recent epochs.
+    pub fn with_max_epochs(mut self, max_epochs: usize) -> Self {
+        self.max_epochs = Some(max_epochs);
+        self
+    }
+
+    /// Keeps events for `max_age` seconds after their ingestion.
+    pub fn with_max_age(mut self, max_age: u64) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Whether an event ingested at `ingested_at` is still fresh at `now`.
+    pub fn is_fresh(&self, ingested_at: u64, now: u64) -> bool {
+        self.max_age
+            .is_none_or(|max_age| now.saturating_sub(ingested_at) <= max_age)
+    }
+}
@@ -0,0 +1,269 @@
+//! Composable relevant event selectors, to combine conditions without
+//! writing ad-hoc closures, e.g.
+//! ```ignore
+//! let selector = ppa_selector
+//!     .and(ByTimestampWindow::new(start, end))
+//!     .and(Not(BySource::new(["blocked.com".to_string()])));
+//! ```
+//! Combinators accept any `RelevantEventSelector` with the same event type,
+//! including the selectors of the queries.
+
+use std::{fmt, marker::PhantomData};
+
+use super::{
+    ara_event::AraEvent,
+    ppa_event::PpaEvent,
+    traits::{Event, RelevantEventSelector, Uri},
+};
+use crate::{queries::ppa_histogram::PpaFilterData, util::hashmap::HashSet};
+
+/// Event with a timestamp, in seconds.
+pub trait TimestampedEvent: Event {
+    fn timestamp(&self) -> u64;
+}
+
+impl<U: Uri> TimestampedEvent for PpaEvent<U> {
+    fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+}
+
+impl<U: Uri> TimestampedEvent for AraEvent<U> {
+    fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+}
+
+/// Event with PPA filter data.
+pub trait FilterDataEvent: Event {
+    fn filter_data(&self) -> PpaFilterData;
+}
+
+impl<U: Uri> FilterDataEvent for PpaEvent<U> {
+    fn filter_data(&self) -> PpaFilterData {
+        self.filter_data
+    }
+}
+
+/// Selects the events selected by both selectors.
+#[derive(Debug, Clone)]
+pub struct And<A, B>(pub A, pub B);
+
+impl<A, B> RelevantEventSelector for And<A, B>
+where
+    A: RelevantEventSelector,
+    B: RelevantEventSelector<Event = A::Event>,
+{
+    type Event = A::Event;
+
+    fn is_relevant_event(&self, event: &Self::Event) -> bool {
+        self.0.is_relevant_event(event) && self.1.is_relevant_event(event)
+    }
+}
+
+/// Selects the events selected by any of the selectors.
+#[derive(Debug, Clone)]
+pub struct Or<A, B>(pub A, pub B);
+
+impl<A, B> RelevantEventSelector for Or<A, B>
+where
+    A: RelevantEventSelector,
+    B: RelevantEventSelector<Event = A::Event>,
+{
+    type Event = A::Event;
+
+    fn is_relevant_event(&self, event: &Self::Event) -> bool {
+        self.0.is_relevant_event(event) || self.1.is_relevant_event(event)
+    }
+}
+
+/// Selects the events rejected by the selector.
+#[derive(Debug, Clone)]
+pub struct Not<S>(pub S);
+
+impl<S: RelevantEventSelector> RelevantEventSelector for Not<S> {
+    type Event = S::Event;
+
+    fn is_relevant_event(&self, event: &Self::Event) -> bool {
+        !self.0.is_relevant_event(event)
+    }
+}
+
+/// Selects the events registered by one of the given sources.
+pub struct BySource<E: Event> {
+    pub source_uris: HashSet<E::Uri>,
+}
+
+impl<E: Event> BySource<E> {
+    pub fn new(source_uris: impl IntoIterator<Item = E::Uri>) -> Self {
+        Self {
+            source_uris: source_uris.into_iter().collect(),
+        }
+    }
+}
+
+impl<E: Event> RelevantEventSelector for BySource<E> {
+    type Event = E;
+
+    fn is_relevant_event(&self, event: &E) -> bool {
+        self.source_uris.contains(&event.event_uris().source_uri)
+    }
+}
+
+impl<E: Event> fmt::Debug for BySource<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BySource")
+            .field("source_uris", &self.source_uris)
+            .finish()
+    }
+}
+
+/// Selects the events whose filter data is in `start..=end`.
+pub struct ByFilterDataRange<E> {
+    pub start: PpaFilterData,
+    pub end: PpaFilterData,
+    _phantom: PhantomData<fn(&E)>,
+}
+
+impl<E> ByFilterDataRange<E> {
+    pub fn new(start: PpaFilterData, end: PpaFilterData) -> Self {
+        Self {
+            start,
+            end,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<E: FilterDataEvent> RelevantEventSelector for ByFilterDataRange<E> {
+    type Event = E;
+
+    fn is_relevant_event(&self, event: &E) -> bool {
+        (self.start..=self.end).contains(&event.filter_data())
+    }
+}
+
+impl<E> fmt::Debug for ByFilterDataRange<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ByFilterDataRange")
+            .field("start", &self.start)
+            .field("end", &self.end)
+            .finish()
+    }
+}
+
+/// Selects the events whose timestamp is in `start..end`, in seconds.
+pub struct ByTimestampWindow<E> {
+    pub start: u64,
+    pub end: u64,
+    _phantom: PhantomData<fn(&E)>,
+}
+
+impl<E> ByTimestampWindow<E> {
+    pub fn new(start: u64, end: u64) -> Self {
+        Self {
+            start,
+            end,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<E: TimestampedEvent> RelevantEventSelector for ByTimestampWindow<E> {
+    type Event = E;
+
+    fn is_relevant_event(&self, event: &E) -> bool {
+        (self.start..self.end).contains(&event.timestamp())
+    }
+}
+
+impl<E> fmt::Debug for ByTimestampWindow<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ByTimestampWindow")
+            .field("start", &self.start)
+            .field("end", &self.end)
+            .finish()
+    }
+}
+
+/// Selectors can be borrowed, e.g. to combine the selector of a request
+/// without cloning it.
+impl<S: RelevantEventSelector + ?Sized> RelevantEventSelector for &S {
+    type Event = S::Event;
+
+    fn is_relevant_event(&self, event: &Self::Event) -> bool {
+        (**self).is_relevant_event(event)
+    }
+}
+
+/// Combinator methods, for all selectors.
+pub trait SelectorExt: RelevantEventSelector + Sized {
+    fn and<S>(self, other: S) -> And<Self, S>
+    where
+        S: RelevantEventSelector<Event = Self::Event>,
+    {
+        And(self, other)
+    }
+
+    fn or<S>(self, other: S) -> Or<Self, S>
+    where
+        S: RelevantEventSelector<Event = Self::Event>,
+    {
+        Or(self, other)
+    }
+}
+
+impl<S: RelevantEventSelector> SelectorExt for S {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        events::traits::EventUris,
+        queries::{
+            ppa_histogram::{
+                FilterDataPredicate, PpaRelevantEventSelector, RequestedBuckets,
+            },
+            traits::ReportRequestUris,
+        },
+    };
+
+    fn event(id: u64, timestamp: u64, filter_data: PpaFilterData) -> PpaEvent {
+        PpaEvent {
+            id,
+            timestamp,
+            epoch_number: 1,
+            histogram_index: 0,
+            uris: EventUris::mock(),
+            filter_data,
+            priority: 0,
+        }
+    }
+
+    #[test]
+    fn test_selector_combinators() {
+        let ppa_selector = PpaRelevantEventSelector {
+            report_request_uris: ReportRequestUris::mock(),
+            is_matching_event: FilterDataPredicate::Any,
+            requested_buckets: RequestedBuckets::AllBuckets,
+            lookback: None,
+        };
+        let selector = (&ppa_selector)
+            .and(ByTimestampWindow::new(10, 20))
+            .and(Not(ByFilterDataRange::new(5, 9)));
+        assert!(selector.is_relevant_event(&event(1, 10, 1)));
+        assert!(!selector.is_relevant_event(&event(2, 20, 1)));
+        assert!(!selector.is_relevant_event(&event(3, 15, 5)));
+
+        // Events of other sources are rejected by the PPA selector anyway.
+        let mut other_source = event(4, 15, 1);
+        other_source.uris.source_uri = "other.com".to_string();
+        assert!(!selector.is_relevant_event(&other_source));
+
+        let selector = BySource::new(["other.com".to_string()])
+            .or(ByFilterDataRange::new(5, 9));
+        assert!(selector.is_relevant_event(&other_source));
+        assert!(selector.is_relevant_event(&event(5, 0, 7)));
+        assert!(!selector.is_relevant_event(&event(6, 0, 1)));
+    }
+}
@@ -5,7 +5,7 @@ use crate::events::traits::{Event, EventUris};
 
 /// A barebones event type for testing and demo purposes. See ppa_event for a
 /// richer type.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct SimpleEvent<U: Uri = String> {
     pub id: u64,
     pub epoch_number: u64,
@@ -1,18 +1,30 @@
-use std::{fmt::Debug, hash::Hash};
+use alloc::vec::Vec;
+use core::{fmt::Debug, hash::Hash};
 
-/// Marker trait with bounds for epoch identifiers.
-pub trait EpochId: Clone + Copy + Debug + Eq + Hash {}
+use serde::{Deserialize, Serialize};
+
+use crate::{events::trigger_event::TriggerEvent, util::parallel::ThreadSafe};
+
+/// Marker trait with bounds for epoch identifiers. Epochs are ordered in time,
+/// and serializable so that reports mentioning them are.
+pub trait EpochId:
+    Clone + Copy + Debug + Eq + Hash + Ord + Serialize + ThreadSafe
+{
+}
 
 /// Implement EpochId for all eligible types
-impl<T: Clone + Copy + Debug + Eq + Hash> EpochId for T {}
+impl<T: Clone + Copy + Debug + Eq + Hash + Ord + Serialize + ThreadSafe> EpochId
+    for T
+{
+}
 
-/// Marker trait for URIs.
-pub trait Uri: Hash + Eq + Clone + Debug {}
+/// Marker trait for URIs. Serializable so that reports mentioning them are.
+pub trait Uri: Hash + Eq + Clone + Debug + Serialize + ThreadSafe {}
 
 /// Implement URI for all eligible types
-impl<T: Hash + Eq + Clone + Debug> Uri for T {}
+impl<T: Hash + Eq + Clone + Debug + Serialize + ThreadSafe> Uri for T {}
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct EventUris<U> {
     /// URI of the entity that registered this event.
     pub source_uri: U,
@@ -24,8 +36,19 @@ pub struct EventUris<U> {
     pub querier_uris: Vec<U>,
 }
 
+impl<U> EventUris<U> {
+    /// Converts each URI, e.g. to intern them with `InternedUri::from`.
+    pub fn map_uris<V>(self, mut f: impl FnMut(U) -> V) -> EventUris<V> {
+        EventUris {
+            source_uri: f(self.source_uri),
+            trigger_uris: self.trigger_uris.into_iter().map(&mut f).collect(),
+            querier_uris: self.querier_uris.into_iter().map(f).collect(),
+        }
+    }
+}
+
 /// Event with an associated epoch.
-pub trait Event: Debug + Clone {
+pub trait Event: Debug + Clone + PartialEq {
     type EpochId: EpochId;
     type Uri: Uri;
 
@@ -53,9 +76,73 @@ pub trait EventStorage {
     /// Stores a new event.
     fn add_event(&mut self, event: Self::Event) -> Result<(), Self::Error>;
 
-    /// Retrieves all events for a given epoch.
+    /// Streams the events of a given epoch, without copying them.
     fn events_for_epoch(
-        &mut self,
+        &self,
+        epoch_id: &<Self::Event as Event>::EpochId,
+    ) -> Result<impl Iterator<Item = &Self::Event>, Self::Error>;
+
+    /// Collects the events of a given epoch that are relevant for the
+    /// selector. Only relevant events are copied. Storages that can filter in
+    /// bulk, e.g. with an index on URIs, can override this.
+    fn relevant_events_for_epoch(
+        &self,
         epoch_id: &<Self::Event as Event>::EpochId,
-    ) -> Result<impl Iterator<Item = Self::Event>, Self::Error>;
+        selector: &impl RelevantEventSelector<Event = Self::Event>,
+    ) -> Result<Vec<Self::Event>, Self::Error> {
+        let events = self
+            .events_for_epoch(epoch_id)?
+            .filter(|event| selector.is_relevant_event(event))
+            .cloned()
+            .collect();
+        Ok(events)
+    }
+
+    /// Drops the events that are stale under the retention policy of the
+    /// storage, if any. Meant to be called periodically. Returns the number
+    /// of dropped events.
+    fn drop_stale_events(&mut self) -> Result<usize, Self::Error> {
+        Ok(0)
+    }
+
+    /// Version of the stored events, bumped by every write. Readers compare
+    /// generations before and after a sequence of reads to check that they
+    /// saw a consistent state, for storages written by other threads.
+    /// Storages that are only written through `&mut self` can keep the
+    /// default.
+    fn generation(&self) -> u64 {
+        0
+    }
+
+    /// Whether the given epoch has been retired by the retention policy of
+    /// the storage, in which case it has no events anymore.
+    fn is_retired(&self, _epoch_id: &<Self::Event as Event>::EpochId) -> bool {
+        false
+    }
+}
+
+/// Interface to store conversions until they are attributed.
+pub trait TriggerStorage {
+    type Uri: Uri;
+    type Error;
+
+    /// Stores a new trigger.
+    fn add_trigger(
+        &mut self,
+        trigger: TriggerEvent<Self::Uri>,
+    ) -> Result<(), Self::Error>;
+
+    /// Stored triggers registered by the given URI, oldest first.
+    fn triggers_for_uri(
+        &self,
+        trigger_uri: &Self::Uri,
+    ) -> Result<Vec<&TriggerEvent<Self::Uri>>, Self::Error>;
+
+    /// Removes and returns the triggers with the given IDs, so that each
+    /// trigger is attributed at most once. Fails without removing anything
+    /// if one of them is not stored.
+    fn take_triggers(
+        &mut self,
+        trigger_ids: &[u64],
+    ) -> Result<Vec<TriggerEvent<Self::Uri>>, Self::Error>;
 }
@@ -0,0 +1,31 @@
+use alloc::{string::String, vec::Vec};
+
+use serde::{Deserialize, Serialize};
+
+use super::traits::Uri;
+use crate::queries::ppa_histogram::PpaEpochId;
+
+/// Conversion event, recorded on the device when the conversion happens, so
+/// that it can be attributed later, possibly with other conversions in the
+/// same report. See `PpaHistogramRequest::for_triggers`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TriggerEvent<U: Uri = String> {
+    /// Trigger ID, unique within a trigger storage.
+    pub id: u64,
+
+    pub timestamp: u64,
+
+    /// Epoch of the conversion. Impressions from later epochs can't be
+    /// attributed to it.
+    pub epoch_number: PpaEpochId,
+
+    /// URI of the entity that registered the conversion.
+    pub trigger_uri: U,
+
+    /// URI of entities that can receive reports that include this
+    /// conversion.
+    pub querier_uris: Vec<U>,
+
+    /// Raw conversion value, before the value policy of the request.
+    pub value: f64,
+}
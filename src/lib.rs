@@ -1,6 +1,22 @@
+//! Without the default `std` feature, only the core accounting is built:
+//! budgets and filters, events and relevant events, request traits and PPA
+//! histograms, and privacy loss computation. It only needs `alloc`, e.g. to
+//! run in a TEE enclave, and embedders provide their own storages and PDS
+//! on top of it.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
 pub mod budget;
+pub mod error;
 pub mod events;
 pub mod mechanisms;
 pub mod pds;
 pub mod queries;
+#[cfg(feature = "std")]
+pub mod reports;
+#[cfg(feature = "simulator")]
+pub mod simulator;
+#[cfg(feature = "testing")]
+pub mod testing;
 pub mod util;
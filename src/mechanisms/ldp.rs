@@ -0,0 +1,223 @@
+use rand::Rng;
+
+use crate::{
+    budget::pure_dp_filter::PureDPBudget,
+    events::traits::Uri,
+    queries::{
+        histogram::{HistogramReport, HistogramRequest},
+        ppa_histogram::PpaHistogramRequest,
+        threshold::ThresholdRequest,
+        traits::EpochReportRequest,
+    },
+    util::{
+        hashmap::HashMap,
+        rng::{new_rng, PdsRng},
+    },
+};
+
+/// Local-DP mechanism, randomizing a report on the device so that the report
+/// itself is `epsilon`-DP with respect to the device's events. Used as a
+/// fallback when the regular filters are out of budget.
+pub trait LdpMechanism<Q: EpochReportRequest> {
+    /// Randomizes `report`, computed for `request`, with privacy loss
+    /// `epsilon`.
+    fn randomize(
+        &mut self,
+        request: &Q,
+        report: &Q::Report,
+        epsilon: PureDPBudget,
+    ) -> Q::Report;
+}
+
+/// Opt-in local-DP fallback: out-of-budget requests get a randomized report
+/// instead of a null report. Each fallback report costs `epsilon` on the
+/// `Ldp` filter of each out-of-budget epoch.
+pub struct LdpFallback<Q: EpochReportRequest> {
+    pub epsilon: PureDPBudget,
+    pub mechanism: Box<dyn LdpMechanism<Q>>,
+}
+
+impl<Q: EpochReportRequest> LdpFallback<Q> {
+    pub fn new(
+        epsilon: PureDPBudget,
+        mechanism: impl LdpMechanism<Q> + 'static,
+    ) -> Self {
+        Self {
+            epsilon,
+            mechanism: Box::new(mechanism),
+        }
+    }
+}
+
+/// k-ary randomized response for PPA histograms. A PPA report is either null
+/// or a single bucket holding the attributable value, so there are
+/// `histogram_size + 1` possible reports. The true report is kept with
+/// probability `e^eps / (e^eps + histogram_size)`, otherwise one of the other
+/// reports is picked uniformly at random.
+pub struct PpaRandomizedResponse {
+    rng: PdsRng,
+}
+
+impl PpaRandomizedResponse {
+    /// Creates the mechanism, with a seed for simulations only.
+    pub fn new(seed: Option<u64>) -> Self {
+        Self { rng: new_rng(seed) }
+    }
+}
+
+impl PpaRandomizedResponse {
+    /// Randomizes a report with at most one of `n_buckets` buckets, holding
+    /// `value`. Outcome 0 is the null report, outcome i + 1 is bucket i.
+    fn randomize_one_hot(
+        &mut self,
+        n_buckets: u64,
+        value: f64,
+        report: &HistogramReport<u64>,
+        epsilon: PureDPBudget,
+    ) -> HistogramReport<u64> {
+        let n_outcomes = n_buckets + 1;
+        let true_outcome = report
+            .bin_values
+            .keys()
+            .next()
+            .map_or(0, |bucket| bucket + 1);
+
+        // Non-private if epsilon is infinite, e.g. for debugging.
+        let keep_probability = match epsilon.is_finite() {
+            true => epsilon.exp() / (epsilon.exp() + (n_outcomes - 1) as f64),
+            false => 1.0,
+        };
+        let outcome = if self.rng.gen_bool(keep_probability) {
+            true_outcome
+        } else {
+            // Uniform over the other outcomes.
+            let other = self.rng.gen_range(0..n_outcomes - 1);
+            if other >= true_outcome {
+                other + 1
+            } else {
+                other
+            }
+        };
+
+        let mut bin_values = HashMap::new();
+        if outcome > 0 {
+            bin_values.insert(outcome - 1, value);
+        }
+        HistogramReport { bin_values }
+    }
+}
+
+impl<U: Uri> LdpMechanism<PpaHistogramRequest<U>> for PpaRandomizedResponse {
+    fn randomize(
+        &mut self,
+        request: &PpaHistogramRequest<U>,
+        report: &HistogramReport<u64>,
+        epsilon: PureDPBudget,
+    ) -> HistogramReport<u64> {
+        self.randomize_one_hot(
+            request.histogram_size(),
+            request.attributable_value(),
+            report,
+            epsilon,
+        )
+    }
+}
+
+/// Threshold reports are also null or a single bucket, so a single-bit
+/// request gets binary randomized response.
+impl<U: Uri> LdpMechanism<ThresholdRequest<U>> for PpaRandomizedResponse {
+    fn randomize(
+        &mut self,
+        request: &ThresholdRequest<U>,
+        report: &HistogramReport<u64>,
+        epsilon: PureDPBudget,
+    ) -> HistogramReport<u64> {
+        self.randomize_one_hot(
+            request.n_buckets(),
+            request.attributable_value(),
+            report,
+            epsilon,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queries::{
+        builder::PpaHistogramRequestBuilder,
+        ppa_histogram::{FilterDataPredicate, RequestedBuckets},
+        traits::ReportRequestUris,
+    };
+
+    fn request(histogram_size: u64) -> PpaHistogramRequest {
+        let uris = ReportRequestUris {
+            trigger_uri: "trigger.ex".to_string(),
+            source_uris: vec!["source.ex".to_string()],
+            querier_uris: vec!["querier.ex".to_string()],
+            campaign_id: None,
+        };
+        PpaHistogramRequestBuilder::new()
+            .epoch_range(1, 1)
+            .unwrap()
+            .value(10.0, 10.0, 1.0)
+            .unwrap()
+            .uris(uris)
+            .unwrap()
+            .selector(
+                FilterDataPredicate::Any,
+                histogram_size,
+                RequestedBuckets::AllBuckets,
+            )
+            .unwrap()
+            .build()
+    }
+
+    #[test]
+    fn test_ppa_randomized_response() {
+        let request = request(4);
+        let true_report = HistogramReport {
+            bin_values: HashMap::from_iter([(2, 10.0)]),
+        };
+
+        // With a large epsilon, the true report is (almost) always kept.
+        let mut mechanism = PpaRandomizedResponse::new(Some(42));
+        for _ in 0..100 {
+            let report = mechanism.randomize(&request, &true_report, 50.0);
+            assert_eq!(report.bin_values, true_report.bin_values);
+        }
+
+        // With epsilon = 0, the outcomes are uniform, including null reports.
+        let mut n_null = 0;
+        for _ in 0..1000 {
+            let report = mechanism.randomize(&request, &true_report, 0.0);
+            assert!(report.bin_values.len() <= 1);
+            for (bucket, value) in &report.bin_values {
+                assert!(*bucket < 4);
+                assert_eq!(*value, 10.0);
+            }
+            n_null += report.bin_values.is_empty() as usize;
+        }
+        assert!((100..300).contains(&n_null));
+    }
+
+    #[test]
+    fn test_threshold_randomized_response() {
+        let request = ThresholdRequest::at_least(request(4), 3).unwrap();
+        let true_report = HistogramReport {
+            bin_values: HashMap::from_iter([(0, 10.0)]),
+        };
+
+        // A single bit is flipped with probability 1 / (e^eps + 1).
+        let mut mechanism = PpaRandomizedResponse::new(Some(42));
+        let mut n_null = 0;
+        for _ in 0..1000 {
+            let report = mechanism.randomize(&request, &true_report, 1.0);
+            for (bucket, value) in &report.bin_values {
+                assert_eq!((*bucket, *value), (0, 10.0));
+            }
+            n_null += report.bin_values.is_empty() as usize;
+        }
+        assert!((200..350).contains(&n_null));
+    }
+}
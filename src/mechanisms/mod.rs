@@ -1,4 +1,12 @@
+use rand::Rng;
+
+use crate::util::math;
+
+#[cfg(feature = "std")]
+pub mod ldp;
+
 /// L1 and L2 norms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum NormType {
     L1,
     L2, // Unused for now
@@ -9,3 +17,40 @@ pub enum NormType {
 pub enum NoiseScale {
     Laplace(f64), // b parameter for Lap(b)
 }
+
+impl NoiseScale {
+    /// Samples noise from the mechanism, for on-device noising. Pass a seeded
+    /// `PdsRng` to get reproducible noise in simulations.
+    pub fn sample_noise<R: Rng + ?Sized>(&self, rng: &mut R) -> f64 {
+        match self {
+            // The difference of two i.i.d. exponentials is Laplace. `1 - U` is
+            // in (0, 1], so the logarithm is always finite.
+            NoiseScale::Laplace(b) => {
+                let e1 = -math::ln(1.0 - rng.gen::<f64>());
+                let e2 = -math::ln(1.0 - rng.gen::<f64>());
+                b * (e1 - e2)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::rng::new_rng;
+
+    #[test]
+    fn test_seeded_noise_is_reproducible() {
+        let noise_scale = NoiseScale::Laplace(2.0);
+        let sample = |seed| {
+            let mut rng = new_rng(Some(seed));
+            (0..10)
+                .map(|_| noise_scale.sample_noise(&mut rng))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(sample(42), sample(42));
+        assert_ne!(sample(42), sample(43));
+        assert!(sample(42).iter().all(|noise| noise.is_finite()));
+    }
+}
@@ -1,14 +1,148 @@
+use alloc::{format, vec::Vec};
 use core::f64;
 
 use log::debug;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    budget::pure_dp_filter::PureDPBudget,
+    budget::{pure_dp_filter::PureDPBudget, traits::BudgetOps},
+    error::PdsError,
+    events::{relevant_events::RelevantEvents, traits::Event},
     mechanisms::{NoiseScale, NormType},
     queries::traits::EpochReportRequest,
-    util::hashmap::{HashMap, HashSet},
+    util::{
+        hashmap::{HashMap, HashSet},
+        math,
+        parallel::par_map,
+    },
 };
 
+/// Privacy losses of a request for one epoch.
+#[derive(Debug, Clone)]
+pub struct EpochLosses<U> {
+    pub has_events: bool,
+
+    /// Loss of the device-epoch filters.
+    pub loss: PureDPBudget,
+
+    /// Loss of the device-epoch-source filters.
+    pub source_losses: HashMap<U, PureDPBudget>,
+}
+
+/// Query-time sampling of the relevant events: the events of each epoch are
+/// kept together with probability `rate`, independently across epochs,
+/// before computing the filtered report. The report is then only affected by
+/// a device-epoch if it is sampled, so the loss charged for the epoch is
+/// discounted by amplification by subsampling.
+///
+/// The sampling unit is the device-epoch, i.e. the privacy unit of the
+/// filters, and not the individual events: the discount then only depends on
+/// the public `rate`. Sampling events one by one would make the discount
+/// depend on how many events the device has, which would leak through the
+/// charged budget.
+///
+/// The randomness comes from the device, see
+/// `PrivateDataServiceCore::sampling_rng`: a querier that knew which epochs
+/// are sampled would get the report of the sampled epochs without any
+/// amplification, so requests can't carry a seed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EventSampling {
+    pub rate: f64,
+}
+
+impl EventSampling {
+    pub fn new(rate: f64) -> Result<Self, PdsError> {
+        if !(rate > 0.0 && rate <= 1.0) {
+            return Err(PdsError::InvalidRequest(format!(
+                "sampling rate {rate} is not in (0, 1]"
+            )));
+        }
+        Ok(Self { rate })
+    }
+
+    /// Discounts the pure DP `loss` of a device-epoch to
+    /// `ln(1 + q (e^loss - 1))` where `q` is the sampling rate. Computed as
+    /// `loss + ln(q + (1 - q) e^-loss)` so that large losses don't overflow.
+    pub fn amplify(&self, loss: PureDPBudget) -> PureDPBudget {
+        if loss.is_infinite() {
+            return loss;
+        }
+        let q = self.rate;
+        let amplified = loss + math::ln(q + (1.0 - q) * math::exp(-loss));
+        // Rounding can't make sampling cost more than no sampling.
+        amplified.clamp(0.0, loss)
+    }
+
+    /// Samples the epochs in place, dropping all the events of the epochs
+    /// that are not sampled. Epochs are sampled in order, so that a sampling
+    /// with a seeded `rng` is reproducible.
+    pub fn sample<E: Event, R: Rng + ?Sized>(
+        &self,
+        relevant_events: &mut RelevantEvents<E>,
+        rng: &mut R,
+    ) {
+        let mut epochs = relevant_events
+            .events_per_epoch
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        epochs.sort();
+        for epoch_id in epochs {
+            if let Some(events) =
+                relevant_events.events_per_epoch.get_mut(&epoch_id)
+            {
+                if !rng.gen_bool(self.rate) {
+                    events.clear();
+                }
+            }
+        }
+    }
+}
+
+/// Computes the losses of each of the given epochs, out of the `num_epochs`
+/// epochs of the request. Epochs only depend on their own events and on the
+/// unfiltered report, so they are computed in parallel with the `parallel`
+/// feature. With event sampling, losses are discounted by the sampling rate.
+pub fn compute_losses_per_epoch<Q: EpochReportRequest>(
+    request: &Q,
+    relevant_events: &RelevantEvents<Q::Event>,
+    unfiltered_report: &Q::Report,
+    epochs: &[Q::EpochId],
+    num_epochs: usize,
+) -> Vec<EpochLosses<Q::Uri>> {
+    par_map(epochs, |epoch_id| {
+        let epoch_relevant_events = relevant_events.for_epoch(epoch_id);
+        let loss = compute_epoch_loss(
+            request,
+            epoch_relevant_events,
+            unfiltered_report,
+            num_epochs,
+        );
+        let mut source_losses = compute_epoch_source_losses(
+            request,
+            relevant_events.sources_for_epoch(epoch_id),
+            unfiltered_report,
+            num_epochs,
+        );
+        let loss = match request.event_sampling() {
+            Some(sampling) => {
+                for source_loss in source_losses.values_mut() {
+                    *source_loss = sampling.amplify(*source_loss);
+                }
+                sampling.amplify(loss)
+            }
+            None => loss,
+        };
+        EpochLosses {
+            has_events: !epoch_relevant_events.is_empty(),
+            loss,
+            source_losses,
+        }
+    })
+}
+
 /// Pure DP individual privacy loss, following
 /// `compute_individual_privacy_loss` from Code Listing 1 in Cookie Monster (https://arxiv.org/pdf/2405.16719).
 pub fn compute_epoch_loss<Q: EpochReportRequest>(
@@ -16,10 +150,28 @@ pub fn compute_epoch_loss<Q: EpochReportRequest>(
     epoch_relevant_events: &[Q::Event],
     computed_attribution: &Q::Report,
     num_epochs: usize,
+) -> PureDPBudget {
+    compute_epoch_loss_with_noise_scale(
+        request,
+        epoch_relevant_events,
+        computed_attribution,
+        num_epochs,
+        &request.noise_scale(),
+    )
+}
+
+/// Same as `compute_epoch_loss`, for a report noised with `noise_scale`
+/// instead of the noise scale of the request.
+pub fn compute_epoch_loss_with_noise_scale<Q: EpochReportRequest>(
+    request: &Q,
+    epoch_relevant_events: &[Q::Event],
+    computed_attribution: &Q::Report,
+    num_epochs: usize,
+    noise_scale: &NoiseScale,
 ) -> PureDPBudget {
     // Case 1: Epoch with no relevant events
     if epoch_relevant_events.is_empty() {
-        return PureDPBudget::from(0.0);
+        return PureDPBudget::zero();
     }
 
     let individual_sensitivity = match num_epochs {
@@ -30,22 +182,30 @@ pub fn compute_epoch_loss<Q: EpochReportRequest>(
                 NormType::L1,
             )
         }
-        _ => {
+        _ => match request.multi_epoch_individual_sensitivity(
+            epoch_relevant_events,
+            NormType::L1,
+        ) {
+            // Case 2b: Multiple epochs with independent contributions. The
+            // epoch's events only change its own contribution, so we can use
+            // the actual contribution as in the single epoch case.
+            Some(epoch_sensitivity) => epoch_sensitivity,
+
             // Case 3: Multiple epochs.
-            request.report_global_sensitivity()
-        }
+            None => request.report_global_sensitivity(),
+        },
     };
 
     debug!("Individual sensitivity: {individual_sensitivity} for {num_epochs} epochs");
 
-    let NoiseScale::Laplace(noise_scale) = request.noise_scale();
+    let NoiseScale::Laplace(noise_scale) = *noise_scale;
 
     // Treat near-zero noise scales as non-private, i.e. requesting infinite
     // budget, which can only go through if filters are also set to
     // infinite capacity, e.g. for debugging. The machine precision
     // `f64::EPSILON` is not related to privacy.
     if noise_scale.abs() < f64::EPSILON {
-        return PureDPBudget::from(f64::INFINITY);
+        return PureDPBudget::infinity();
     }
 
     // In Cookie Monster, we have `query_global_sensitivity` /
@@ -85,6 +245,15 @@ pub fn compute_epoch_source_losses<Q: EpochReportRequest>(
            sensitivity.
         */
 
+        let split_source_sensitivity = match num_epochs {
+            1 => request.single_epoch_split_source_sensitivity(
+                computed_attribution,
+                source,
+                NormType::L1,
+            ),
+            _ => None,
+        };
+
         let individual_sensitivity = if !has_relevant_events {
             // Case 1: Epoch-source with no relevant events.
             0.0
@@ -96,6 +265,11 @@ pub fn compute_epoch_source_losses<Q: EpochReportRequest>(
                 computed_attribution,
                 NormType::L1,
             )
+        } else if let Some(split_source_sensitivity) = split_source_sensitivity
+        {
+            // Case 2b: Single epoch and report split by source. Use the
+            // actual individual sensitivity of this source's buckets.
+            split_source_sensitivity
         } else {
             // Case 3: Multiple epochs or multiple sources.
             // Use global sensitivity as an upper bound.
@@ -108,8 +282,7 @@ pub fn compute_epoch_source_losses<Q: EpochReportRequest>(
         // debugging. The machine precision `f64::EPSILON` is
         // not related to privacy.
         if noise_scale.abs() < f64::EPSILON {
-            per_source_losses
-                .insert(source.clone(), PureDPBudget::from(f64::INFINITY));
+            per_source_losses.insert(source.clone(), PureDPBudget::infinity());
         } else {
             // In Cookie Monster, we have `query_global_sensitivity` /
             // `requested_epsilon` instead of just `noise_scale`.
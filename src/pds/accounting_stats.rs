@@ -0,0 +1,171 @@
+//! Budget consumed by the PDS, aggregated by filter kind and by epoch.
+//! Counters are updated at deduction time, so summaries never scan the
+//! filter storage.
+//!
+//! WARNING: like observers, statistics reflect private filter state. They
+//! must stay on the device.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::{
+    budget::{pure_dp_filter::PureDPBudget, traits::EpochFilterId},
+    events::traits::{EpochId, Uri},
+    pds::quotas::{FilterId, FilterKind},
+    util::hashmap::HashMap,
+};
+
+/// Incremental counters of the budget consumed by each filter.
+#[derive(Debug, Clone)]
+pub struct AccountingStats<E: EpochId, U: Uri> {
+    by_filter: HashMap<FilterId<E, U>, PureDPBudget>,
+    by_kind: BTreeMap<FilterKind, PureDPBudget>,
+    by_epoch: BTreeMap<E, BTreeMap<FilterKind, PureDPBudget>>,
+}
+
+impl<E: EpochId, U: Uri> Default for AccountingStats<E, U> {
+    fn default() -> Self {
+        Self {
+            by_filter: HashMap::new(),
+            by_kind: BTreeMap::new(),
+            by_epoch: BTreeMap::new(),
+        }
+    }
+}
+
+/// Snapshot of the statistics.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AccountingSummary<E: EpochId, U: Uri> {
+    /// Budget consumed by each kind of filter, across all epochs.
+    pub totals: BTreeMap<FilterKind, PureDPBudget>,
+
+    /// Budget consumed by each kind of filter, in each epoch.
+    pub epochs: BTreeMap<E, BTreeMap<FilterKind, PureDPBudget>>,
+
+    /// Filters that consumed the most budget, in decreasing order.
+    pub top_consumers: Vec<(FilterId<E, U>, PureDPBudget)>,
+}
+
+impl<E: EpochId, U: Uri> AccountingStats<E, U> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a deduction from a filter.
+    pub fn record_deduction(
+        &mut self,
+        filter_id: &FilterId<E, U>,
+        budget: PureDPBudget,
+    ) {
+        self.add(filter_id, budget);
+    }
+
+    /// Records a refund to a filter, e.g. when a reservation is released.
+    pub fn record_refund(
+        &mut self,
+        filter_id: &FilterId<E, U>,
+        budget: PureDPBudget,
+    ) {
+        self.add(filter_id, -budget);
+    }
+
+    fn add(&mut self, filter_id: &FilterId<E, U>, budget: PureDPBudget) {
+        let kind = filter_id.kind();
+        *self.by_filter.entry(filter_id.clone()).or_default() += budget;
+        *self.by_kind.entry(kind).or_default() += budget;
+        *self
+            .by_epoch
+            .entry(*filter_id.epoch_id())
+            .or_default()
+            .entry(kind)
+            .or_default() += budget;
+    }
+
+    /// Budget consumed by the given kind of filter, across all epochs.
+    pub fn total(&self, kind: FilterKind) -> PureDPBudget {
+        self.by_kind.get(&kind).copied().unwrap_or_default()
+    }
+
+    /// Budget consumed by each kind of filter in the given epoch.
+    pub fn epoch_totals(
+        &self,
+        epoch_id: &E,
+    ) -> BTreeMap<FilterKind, PureDPBudget> {
+        self.by_epoch.get(epoch_id).cloned().unwrap_or_default()
+    }
+
+    /// Filters that were charged so far, including the ones that were fully
+    /// refunded since.
+    pub fn filter_ids(&self) -> impl Iterator<Item = &FilterId<E, U>> {
+        self.by_filter.keys()
+    }
+
+    /// The `k` filters that consumed the most budget, in decreasing order.
+    pub fn top_consumers(
+        &self,
+        k: usize,
+    ) -> Vec<(FilterId<E, U>, PureDPBudget)> {
+        let mut consumers = self
+            .by_filter
+            .iter()
+            .map(|(filter_id, budget)| (filter_id, *budget))
+            .collect::<Vec<_>>();
+        let by_budget =
+            |a: &(_, PureDPBudget), b: &(_, PureDPBudget)| b.1.total_cmp(&a.1);
+        if k < consumers.len() {
+            consumers.select_nth_unstable_by(k, by_budget);
+            consumers.truncate(k);
+        }
+        consumers.sort_by(by_budget);
+        consumers
+            .into_iter()
+            .map(|(filter_id, budget)| (filter_id.clone(), budget))
+            .collect()
+    }
+
+    /// Totals, per-epoch totals and the `top_k` consumers.
+    pub fn summary(&self, top_k: usize) -> AccountingSummary<E, U> {
+        AccountingSummary {
+            totals: self.by_kind.clone(),
+            epochs: self.by_epoch.clone(),
+            top_consumers: self.top_consumers(top_k),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accounting_stats() {
+        let mut stats = AccountingStats::<u64, String>::new();
+        let querier =
+            |epoch_id| FilterId::PerQuerier(epoch_id, "adtech.com".to_string());
+        stats.record_deduction(&querier(1), 0.5);
+        stats.record_deduction(&querier(2), 1.5);
+        stats.record_deduction(&FilterId::Global(1), 0.5);
+        stats.record_deduction(&FilterId::Global(2), 1.0);
+        stats.record_refund(&FilterId::Global(2), 0.25);
+
+        assert_eq!(stats.total(FilterKind::PerQuerier), 2.0);
+        assert_eq!(stats.total(FilterKind::Global), 1.25);
+        assert_eq!(stats.total(FilterKind::Ldp), 0.0);
+        assert_eq!(
+            stats.epoch_totals(&2),
+            BTreeMap::from_iter([
+                (FilterKind::PerQuerier, 1.5),
+                (FilterKind::Global, 0.75),
+            ])
+        );
+
+        let summary = stats.summary(2);
+        assert_eq!(
+            summary.top_consumers,
+            [(querier(2), 1.5), (FilterId::Global(2), 0.75)]
+        );
+        assert_eq!(summary.epochs.len(), 2);
+        assert_eq!(stats.top_consumers(10).len(), 4);
+    }
+}
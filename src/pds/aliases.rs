@@ -8,13 +8,17 @@ use crate::{
         hashmap_filter_storage::HashMapFilterStorage,
         pure_dp_filter::{PureDPBudget, PureDPBudgetFilter},
     },
+    error::PdsError,
     events::{
+        any_event::AnyEvent, ara_event::AraEvent,
         hashmap_event_storage::HashMapEventStorage, ppa_event::PpaEvent,
         simple_event::SimpleEvent,
     },
     queries::{
+        any_request::AnyEpochReportRequest, ara_histogram::AraHistogramRequest,
         ppa_histogram::PpaHistogramRequest,
         simple_last_touch_histogram::SimpleLastTouchHistogramRequest,
+        source_keyed_histogram::SourceKeyedHistogramRequest,
     },
 };
 
@@ -26,9 +30,9 @@ pub type SimpleFilterStorage = HashMapFilterStorage<
 >;
 pub type SimpleEventStorage = HashMapEventStorage<SimpleEvent>;
 pub type SimplePdsCore<FS = SimpleFilterStorage> =
-    PrivateDataServiceCore<SimpleLastTouchHistogramRequest, FS, anyhow::Error>;
+    PrivateDataServiceCore<SimpleLastTouchHistogramRequest, FS, PdsError>;
 pub type SimplePds<FS = SimpleFilterStorage, ES = SimpleEventStorage> =
-    PrivateDataService<SimpleLastTouchHistogramRequest, FS, ES, anyhow::Error>;
+    PrivateDataService<SimpleLastTouchHistogramRequest, FS, ES, PdsError>;
 
 // === PPA aliases ===
 
@@ -37,11 +41,34 @@ pub type PpaFilterStorage<U = String> = HashMapFilterStorage<
     StaticCapacities<FilterId<u64, U>, PureDPBudget>,
 >;
 pub type PpaEventStorage<U = String> = HashMapEventStorage<PpaEvent<U>>;
-pub type PpaPdsCore<FS = PpaFilterStorage, U = String, ERR = anyhow::Error> =
+pub type PpaPdsCore<FS = PpaFilterStorage, U = String, ERR = PdsError> =
     PrivateDataServiceCore<PpaHistogramRequest<U>, FS, ERR>;
 pub type PpaPds<
     FS = PpaFilterStorage,
     ES = PpaEventStorage,
     U = String,
-    ERR = anyhow::Error,
+    ERR = PdsError,
 > = PrivateDataService<PpaHistogramRequest<U>, FS, ES, ERR>;
+pub type SourceKeyedPds<
+    FS = PpaFilterStorage,
+    ES = PpaEventStorage,
+    U = String,
+    ERR = PdsError,
+> = PrivateDataService<SourceKeyedHistogramRequest<U>, FS, ES, ERR>;
+
+// === ARA aliases ===
+
+/// ARA uses the same filters as PPA.
+pub type AraEventStorage<U = String> = HashMapEventStorage<AraEvent<U>>;
+pub type AraPds<
+    FS = PpaFilterStorage,
+    ES = AraEventStorage,
+    U = String,
+    ERR = PdsError,
+> = PrivateDataService<AraHistogramRequest<U>, FS, ES, ERR>;
+
+// === Aliases for heterogeneous requests ===
+
+pub type AnyEventStorage = HashMapEventStorage<AnyEvent>;
+pub type AnyPds<FS = SimpleFilterStorage, ES = AnyEventStorage> =
+    PrivateDataService<AnyEpochReportRequest, FS, ES, PdsError>;
@@ -0,0 +1,66 @@
+//! [Experimental] Attribution traces, to tell why a report is empty: every
+//! event stored in the requested epochs, with the reason why it could not be
+//! attributed, if any.
+//! WARNING: traces contain raw events, they are for local debugging only and
+//! should not be shared outside the device.
+
+/// Why an event of the requested epochs could not be attributed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectionReason {
+    /// The relevant event selector of the request rejected the event, e.g.
+    /// because of its URIs or its filter data.
+    SelectorMismatch,
+
+    /// The event can't contribute to the report, e.g. its histogram index is
+    /// out of the range of the request.
+    BucketOutOfRange,
+
+    /// The filters of the epoch were pruned.
+    EpochPruned,
+
+    /// The epoch was dropped because one of its filters was out of budget.
+    EpochOutOfBudget,
+}
+
+/// Event of the requested epochs. Events without a rejection reason were
+/// passed to the attribution logic of the request, which can still pick
+/// other events, e.g. for last-touch attribution.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CandidateEvent<E> {
+    pub event: E,
+    pub rejection: Option<RejectionReason>,
+}
+
+/// Candidate events of a report: the relevant events, in the order of the
+/// requested epochs, then the events rejected by the selector.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttributionTrace<E> {
+    pub candidates: Vec<CandidateEvent<E>>,
+}
+
+impl<E> Default for AttributionTrace<E> {
+    fn default() -> Self {
+        Self { candidates: vec![] }
+    }
+}
+
+impl<E> AttributionTrace<E> {
+    /// Events passed to the attribution logic.
+    pub fn considered(&self) -> impl Iterator<Item = &E> {
+        self.candidates
+            .iter()
+            .filter(|candidate| candidate.rejection.is_none())
+            .map(|candidate| &candidate.event)
+    }
+
+    /// Events rejected for the given reason.
+    pub fn rejected(
+        &self,
+        reason: RejectionReason,
+    ) -> impl Iterator<Item = &E> {
+        self.candidates
+            .iter()
+            .filter(move |candidate| candidate.rejection == Some(reason))
+            .map(|candidate| &candidate.event)
+    }
+}
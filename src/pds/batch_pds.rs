@@ -1,31 +1,50 @@
 //! [Experimental] Batch API for fair scheduling of report requests.
 
-use core::panic;
 use std::{
-    borrow::Borrow,
-    cmp::Ordering::{Greater, Less},
+    borrow::{Borrow, Cow},
+    cmp::{Ordering, Reverse},
+    collections::{BTreeMap, BinaryHeap},
     fmt::Debug,
     mem::take,
+    ops::{Index, IndexMut},
     vec,
 };
 
 use anyhow::Result;
-use log::debug;
+use log::warn;
+use serde::Serialize;
 
 use super::{
-    private_data_service::{PdsReport, PrivateDataService},
+    core::PrivateDataServiceCore,
+    private_data_service::{
+        NullReason, PdsReport, PrivateDataService, RetryAdvice,
+    },
     quotas::{PdsFilterStatus, StaticCapacities},
+    subscription::{
+        Subscription, SubscriptionDelivery, SubscriptionId, SubscriptionReport,
+        SubscriptionReservation,
+    },
 };
 use crate::{
     budget::{
         pure_dp_filter::PureDPBudget,
-        traits::{Filter, FilterStatus, FilterStorage, ReleaseFilter},
+        traits::{
+            BudgetOps, Filter, FilterStatus, FilterStorage, ReleaseFilter,
+            Scale,
+        },
     },
+    error::PdsError,
     events::traits::EventStorage,
     mechanisms::NoiseScale,
     pds::quotas::FilterId,
-    queries::traits::EpochReportRequest,
-    util::hashmap::{HashMap, HashSet},
+    queries::{epoch_selection::unique_epochs, traits::EpochReportRequest},
+    util::{
+        clock::{Clock, SystemClock},
+        correlation::{debug, CorrelationScope},
+        hashmap::{HashMap, HashSet},
+        parallel::{par_map, ThreadSafe},
+        spans::timed_span,
+    },
 };
 
 #[derive(Debug)]
@@ -39,39 +58,226 @@ pub struct BatchedRequest<Q: EpochReportRequest> {
     /// `schedule_batch` call. It has to be answered by the end of the
     /// call. If it didn't get allocated in the initialization, online or batch
     /// phase, then it is answered with a null report.
+    /// If this is equal to 0, this is a real-time request that is answered
+    /// right away when it is registered, without going through any batch.
     n_remaining_scheduling_attempts: u64,
 
+    /// Time at which the request was registered, from the batch PDS clock.
+    registered_at: u64,
+
+    /// Hint from the querier, e.g. for billing-critical conversions.
+    priority: RequestPriority,
+
     /// The actual request.
     request: Q,
 }
 
+/// Priority of a batched request. Priorities only change the order in which
+/// requests are tried, never the quotas they are subject to. Requests on
+/// their final scheduling attempt are tried first whatever their priority,
+/// so a request can't be delayed by higher-priority ones for more than its
+/// number of scheduling attempts.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize,
+)]
+pub enum RequestPriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
 impl<Q: EpochReportRequest> BatchedRequest<Q> {
     pub fn new(
         request_id: u64,
         n_scheduling_attempts: u64,
         request: Q,
     ) -> Self {
-        if n_scheduling_attempts == 0 {
-            // TODO(https://github.com/columbia/pdslib/issues/90): allow requests \
-            // with 0 batch scheduling attempt for
-            // real-time queries. But for now we only consider batched, where a
-            // query goes through at least one batch phase.
-            panic!("The request should have at least one scheduling attempt.");
-        }
-
         BatchedRequest {
             request_id,
             n_remaining_scheduling_attempts: n_scheduling_attempts,
+            registered_at: 0,
+            priority: RequestPriority::default(),
             request,
         }
     }
+
+    /// Sets the priority hint of the request.
+    pub fn with_priority(mut self, priority: RequestPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Order in which requests are tried, higher first: requests on their
+    /// final attempt, then by priority.
+    fn scheduling_rank(&self) -> (bool, RequestPriority) {
+        (self.n_remaining_scheduling_attempts == 0, self.priority)
+    }
+}
+
+/// Index of a request in the `RequestArena` of a batch PDS. It stays valid
+/// until the request is answered or canceled, after which it can be reused.
+pub type RequestIndex = usize;
+
+/// Requests waiting in a batch PDS. The queues and the phases of the
+/// scheduler only move indices around, so requests stay in place until they
+/// are answered or canceled, however often they are re-sorted. Requests are
+/// boxed, so lending one to the scheduler only moves a pointer.
+#[derive(Debug)]
+pub struct RequestArena<Q: EpochReportRequest> {
+    slots: Vec<Option<Box<BatchedRequest<Q>>>>,
+    free_slots: Vec<RequestIndex>,
+}
+
+impl<Q: EpochReportRequest> Default for RequestArena<Q> {
+    fn default() -> Self {
+        Self {
+            slots: vec![],
+            free_slots: vec![],
+        }
+    }
+}
+
+impl<Q: EpochReportRequest> RequestArena<Q> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of requests in the arena.
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free_slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Stores a request, reusing the slot of an answered one if any.
+    pub fn insert(&mut self, request: BatchedRequest<Q>) -> RequestIndex {
+        let request = Some(Box::new(request));
+        match self.free_slots.pop() {
+            Some(index) => {
+                self.slots[index] = request;
+                index
+            }
+            None => {
+                self.slots.push(request);
+                self.slots.len() - 1
+            }
+        }
+    }
+
+    pub fn get(&self, index: RequestIndex) -> Option<&BatchedRequest<Q>> {
+        self.slots.get(index)?.as_deref()
+    }
+
+    /// Removes a request, e.g. once it is answered, and frees its slot.
+    pub fn remove(&mut self, index: RequestIndex) -> Box<BatchedRequest<Q>> {
+        let request = self.lend(index);
+        self.free_slots.push(index);
+        request
+    }
+
+    /// IDs of the requests at the given indices, e.g. to inspect a queue.
+    pub fn request_ids(&self, indices: &[RequestIndex]) -> Vec<u64> {
+        indices
+            .iter()
+            .map(|index| self[*index].request_id)
+            .collect()
+    }
+
+    /// Sorts indices by decreasing scheduling rank of their requests,
+    /// keeping the arrival order otherwise.
+    fn sort_by_rank(&self, indices: &mut [RequestIndex]) {
+        indices.sort_by_key(|index| Reverse(self[*index].scheduling_rank()));
+    }
+
+    /// Takes a request out of its slot without freeing it, until it is put
+    /// back with `restore`.
+    fn lend(&mut self, index: RequestIndex) -> Box<BatchedRequest<Q>> {
+        self.slots[index]
+            .take()
+            .unwrap_or_else(|| panic!("no request at index {index}"))
+    }
+
+    fn restore(
+        &mut self,
+        index: RequestIndex,
+        request: Box<BatchedRequest<Q>>,
+    ) {
+        self.slots[index] = Some(request);
+    }
+}
+
+impl<Q: EpochReportRequest> Index<RequestIndex> for RequestArena<Q> {
+    type Output = BatchedRequest<Q>;
+
+    fn index(&self, index: RequestIndex) -> &Self::Output {
+        self.get(index)
+            .unwrap_or_else(|| panic!("no request at index {index}"))
+    }
+}
+
+impl<Q: EpochReportRequest> IndexMut<RequestIndex> for RequestArena<Q> {
+    fn index_mut(&mut self, index: RequestIndex) -> &mut Self::Output {
+        self.slots
+            .get_mut(index)
+            .and_then(|slot| slot.as_deref_mut())
+            .unwrap_or_else(|| panic!("no request at index {index}"))
+    }
+}
+
+/// Wiring between scheduling intervals and epochs. Each epoch lasts
+/// `intervals_per_epoch` scheduling intervals, starting with `first_epoch`
+/// at interval 0, and `schedule_batch` moves the base PDS to the next epoch
+/// when the current one is over.
+#[derive(Debug, Clone)]
+pub struct EpochSchedule<E> {
+    pub intervals_per_epoch: u64,
+
+    /// Epoch of the first scheduling interval.
+    pub first_epoch: E,
+
+    /// Epoch following the given epoch.
+    pub next_epoch: fn(&E) -> E,
+}
+
+impl EpochSchedule<u64> {
+    /// Schedule over integer epochs.
+    pub fn new(
+        intervals_per_epoch: u64,
+        first_epoch: u64,
+    ) -> Result<Self, PdsError> {
+        if intervals_per_epoch == 0 {
+            return Err(PdsError::InvalidConfig(
+                "epochs must last at least one scheduling interval".into(),
+            ));
+        }
+        Ok(Self {
+            intervals_per_epoch,
+            first_epoch,
+            next_epoch: |epoch_id| epoch_id + 1,
+        })
+    }
+}
+
+impl<E: Copy> EpochSchedule<E> {
+    /// Epoch of the given scheduling interval.
+    pub fn epoch_at(&self, scheduling_interval: u64) -> E {
+        (0..scheduling_interval / self.intervals_per_epoch)
+            .fold(self.first_epoch, |epoch_id, _| (self.next_epoch)(&epoch_id))
+    }
+
+    /// Whether an epoch starts at the given scheduling interval.
+    pub fn starts_epoch(&self, scheduling_interval: u64) -> bool {
+        scheduling_interval.is_multiple_of(self.intervals_per_epoch)
+    }
 }
 
 /// [Experimental] Batch wrapper for private data service.
 pub struct BatchPrivateDataService<Q, FS, ES, ERR>
 where
     Q: EpochReportRequest,
-    Q::Report: Clone,
     FS: FilterStorage<
         Budget = PureDPBudget,
         FilterId = FilterIdQ<Q>,
@@ -79,17 +285,33 @@ where
     >,
     FS::Filter: ReleaseFilter<FS::Budget, Error = FS::Error>,
     ES: EventStorage<Event = Q::Event>,
-    ERR: From<FS::Error> + From<ES::Error>,
+    ERR: From<FS::Error> + From<ES::Error> + From<PdsError>,
 {
     /// Current scheduling interval.
     /// Used to release budget for the Global filter.
     pub current_scheduling_interval: u64,
 
+    /// Requests that are parked, pending or batched. The queues below are
+    /// indices into the arena.
+    pub requests: RequestArena<Q>,
+
     /// Queries that arrived during the current interval.
-    pub new_pending_requests: Vec<BatchedRequest<Q>>,
+    pub new_pending_requests: Vec<RequestIndex>,
+
+    /// Maximum number of pending requests. Registering more fails with
+    /// `PdsError::QueueFull` until the next scheduling interval. If None,
+    /// pending requests are unbounded.
+    pub max_pending_requests: Option<usize>,
 
     /// Queries that are still waiting.
-    pub batched_requests: Vec<BatchedRequest<Q>>,
+    pub batched_requests: Vec<RequestIndex>,
+
+    /// Queries whose attribution window includes epochs that are not over
+    /// yet, e.g. post-view windows extending forward. They don't use their
+    /// scheduling attempts, and their epochs are not tracked, until the
+    /// window closes. Filters of future epochs are only created once budget
+    /// is consumed from them.
+    pub parked_requests: Vec<RequestIndex>,
 
     /// Reports for requests that have already been answered but need to wait
     /// for more scheduling intervals until they can be released.
@@ -101,12 +323,49 @@ where
     /// Range of epochs from start to end (included).
     pub epochs: Option<(Q::EpochId, Q::EpochId)>,
 
-    /// List of all the different sources that appear in each epoch.
+    /// List of all the different sources that appear in each active epoch.
     pub sources_per_epoch: HashMap<Q::EpochId, HashSet<Q::Uri>>,
 
+    /// Number of scheduling intervals during which an epoch gets budget
+    /// releases and quota toggles, counted from the first interval where it
+    /// was requested. The epoch is retired afterwards. If None, epochs are
+    /// never retired.
+    pub epoch_lifetime: Option<u64>,
+
+    /// Scheduling interval where each active epoch was first requested.
+    pub epoch_first_intervals: HashMap<Q::EpochId, u64>,
+
+    /// Last scheduling interval where the budget of each active epoch was
+    /// released, so that a retried interval doesn't release it twice.
+    pub last_releases: HashMap<Q::EpochId, u64>,
+
+    /// Epochs up to this one (included) have been retired. Since epochs are
+    /// ordered in time, older epochs are past their attribution window too.
+    pub retired_through: Option<Q::EpochId>,
+
     /// Amount of Global filter budget to be released per scheduling interval.
     pub eps_c_per_release: FS::Budget,
 
+    /// Number of scheduling intervals over which the Global filter of an
+    /// epoch is released, counted from the first interval where the epoch
+    /// is active. Older epochs are past their release horizon.
+    pub n_releases: usize,
+
+    /// Wiring between scheduling intervals and epochs. If None, the current
+    /// epoch of the base PDS is left to the caller, and epochs only become
+    /// active when they are requested.
+    pub epoch_schedule: Option<EpochSchedule<Q::EpochId>>,
+
+    /// Recurring requests, in registration order. Their budget is reserved
+    /// each time an epoch starts.
+    pub subscriptions: Vec<Subscription<Q>>,
+
+    /// Budget reserved for subscriptions that were not answered yet.
+    pub subscription_reservations: Vec<SubscriptionReservation<Q>>,
+
+    /// Reports of push subscriptions, until they are taken.
+    pub subscription_reports: Vec<SubscriptionReport<Q>>,
+
     /// NOTE: these filters are not actually directly visible to a querier,
     /// because of report identifiers, to clarify.
     pub public_filters: FS,
@@ -114,6 +373,14 @@ where
     /// Base private data service.
     /// Filters need to have functionality to unlock budget.
     pub pds: PrivateDataService<Q, FS, ES, ERR>,
+
+    /// Clock used to timestamp requests and reports. Can be replaced by a
+    /// mock clock for deterministic simulations.
+    pub clock: Box<dyn Clock>,
+
+    /// Called with a snapshot of the scheduler at the end of each phase of
+    /// `schedule_batch`, e.g. to trace experiments.
+    pub phase_callback: Option<PhaseCallback<Q::EpochId, Q::Uri>>,
 }
 
 /// Report for a batched request. Guaranteed to be returned after the number of
@@ -123,25 +390,180 @@ pub struct BatchedReport<Q: EpochReportRequest> {
     /// The request that asked for this report, potentially a long time ago.
     pub request_id: u64,
 
+    /// Querier receiving this report. Requests with several queriers get one
+    /// report per querier, all with the same content.
+    pub querier_uri: Q::Uri,
+
+    /// Time at which the request was registered.
+    pub registered_at: u64,
+
+    /// Time at which the report was computed. The report might be released
+    /// later.
+    pub computed_at: u64,
+
     /// The report answering that request.
     pub report: PdsReport<Q>,
 }
 
+/// Phases of `schedule_batch`, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum BatchPhase {
+    Initialization,
+    Online,
+    Batch,
+}
+
+/// Sort key of a request in the batch phase, smallest first: by shortfall,
+/// then by decreasing scheduling rank, then by the budget consumed by its
+/// smallest source, then by requested budget, and finally by position in the
+/// batch.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct BatchKey {
+    shortfall: PureDPBudget,
+    rank: (bool, RequestPriority),
+    min_source_budget: PureDPBudget,
+    requested_budget: PureDPBudget,
+    position: usize,
+}
+
+impl Eq for BatchKey {}
+
+impl PartialOrd for BatchKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for BatchKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.shortfall
+            .total_cmp(&other.shortfall)
+            .then_with(|| other.rank.cmp(&self.rank))
+            .then_with(|| {
+                self.min_source_budget.total_cmp(&other.min_source_budget)
+            })
+            .then_with(|| {
+                self.requested_budget.total_cmp(&other.requested_budget)
+            })
+            .then_with(|| self.position.cmp(&other.position))
+    }
+}
+
+/// Public filter state of an active epoch.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EpochSchedulerStats<U: Eq + std::hash::Hash> {
+    /// Global budget released so far.
+    pub global_unlocked: PureDPBudget,
+
+    /// Global budget consumed so far.
+    pub global_consumed: PureDPBudget,
+
+    /// Budget consumed on the SourceQuota of each source requested in this
+    /// epoch.
+    pub source_consumed: HashMap<U, PureDPBudget>,
+}
+
+/// Snapshot of the batch scheduler, for experiments. Budgets come from the
+/// public filters, so they are upper bounds on what the base PDS consumed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BatchSchedulerStats<E: Ord, U: Eq + std::hash::Hash> {
+    pub scheduling_interval: u64,
+    pub n_parked_requests: usize,
+
+    /// Requests registered during this interval that did not go through the
+    /// online phase yet.
+    pub n_pending_requests: usize,
+
+    /// Requests that went through a phase without being allocated.
+    pub n_batched_requests: usize,
+
+    /// Reports waiting to be released, including the ones released at the
+    /// end of the current `schedule_batch` call.
+    pub n_delayed_reports: usize,
+
+    pub epochs: BTreeMap<E, EpochSchedulerStats<U>>,
+}
+
+/// Divergence between a public filter and the matching filter of the base
+/// PDS.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FilterDrift<FID> {
+    pub filter_id: FID,
+
+    /// Budget consumed on the public filter.
+    pub public_consumed: PureDPBudget,
+
+    /// Budget actually consumed by the base PDS, e.g. lower than the public
+    /// consumption when IDP charges less than the upper bound.
+    pub private_consumed: PureDPBudget,
+}
+
+impl<FID> FilterDrift<FID> {
+    /// How much the public filter overestimates the consumption. Negative if
+    /// the public filter is not an upper bound anymore, e.g. after requests
+    /// were allocated on their final attempt without fitting in the public
+    /// filters.
+    pub fn drift(&self) -> PureDPBudget {
+        self.public_consumed - self.private_consumed
+    }
+}
+
+/// Outcome of `reconcile_public_filters`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DriftReport<FID> {
+    /// Number of filters that were compared.
+    pub n_filters: usize,
+
+    /// Filters whose public and private consumption differ, by decreasing
+    /// drift.
+    pub drifted_filters: Vec<FilterDrift<FID>>,
+
+    /// Sum of the positive drifts, i.e. budget the scheduler can't allocate
+    /// although the base PDS still has it.
+    pub total_drift: PureDPBudget,
+
+    /// Largest positive drift, or zero if no filter overestimates.
+    pub max_drift: PureDPBudget,
+
+    /// Whether the public filters were tightened back to the private
+    /// consumption.
+    pub tightened: bool,
+}
+
+/// Callback invoked at the end of each phase of `schedule_batch`.
+pub type PhaseCallback<E, U> =
+    Box<dyn FnMut(BatchPhase, &BatchSchedulerStats<E, U>)>;
+
+/// Acknowledgement of a call to `cancel_report_request`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CancellationAck {
+    /// The request was still waiting to be allocated, so it was withdrawn
+    /// without consuming any budget.
+    Withdrawn,
+
+    /// The request was already allocated and its budget is spent, but its
+    /// delayed report was dropped and will never be released.
+    ReportSuppressed,
+
+    /// No waiting request or delayed report has this ID, e.g. because its
+    /// report was already released.
+    NotFound,
+}
+
 #[allow(type_alias_bounds)]
 type FilterIdQ<Q: EpochReportRequest> = FilterId<Q::EpochId, Q::Uri>;
 
 impl<Q, FS, ES, ERR> BatchPrivateDataService<Q, FS, ES, ERR>
 where
     Q: EpochReportRequest,
-    Q::Report: Clone,
     FS: FilterStorage<
         Budget = PureDPBudget,
         FilterId = FilterIdQ<Q>,
         Capacities = StaticCapacities<FilterIdQ<Q>, PureDPBudget>,
     >,
-    FS::Filter: ReleaseFilter<FS::Budget, Error = FS::Error>,
+    FS::Filter: ReleaseFilter<FS::Budget, Error = FS::Error> + ThreadSafe,
     ES: EventStorage<Event = Q::Event>,
-    ERR: From<FS::Error> + From<ES::Error>,
+    ERR: From<FS::Error> + From<ES::Error> + From<PdsError>,
 {
     /// Create a new batch private data service.
     pub fn new(
@@ -151,14 +573,15 @@ where
         let capacities = pds.core.filter_storage.capacities().clone();
 
         // Release the Global filter over T scheduling intervals.
-        let eps_c_per_release = match capacities.global {
-            f64::INFINITY => {
+        let eps_c = capacities.global;
+        let eps_c_per_release = match eps_c.is_infinite() {
+            true => {
                 debug!(
                     "Global filter has infinite capacity. Release is a no-op"
                 );
-                0.0
+                PureDPBudget::zero()
             }
-            eps_c => eps_c / (n_releases as f64),
+            false => eps_c.scale_capacity(1.0 / n_releases as f64),
         };
 
         debug!(
@@ -168,198 +591,1104 @@ where
         Ok(BatchPrivateDataService {
             pds,
             eps_c_per_release,
+            n_releases,
+            epoch_schedule: None,
+            subscriptions: vec![],
+            subscription_reservations: vec![],
+            subscription_reports: vec![],
             public_filters: FS::new(capacities)?,
             current_scheduling_interval: 0,
+            requests: RequestArena::new(),
             new_pending_requests: vec![],
+            max_pending_requests: None,
             batched_requests: vec![],
+            parked_requests: vec![],
             delayed_reports: HashMap::new(),
             epochs: None,
             sources_per_epoch: HashMap::new(),
+            epoch_lifetime: None,
+            epoch_first_intervals: HashMap::new(),
+            last_releases: HashMap::new(),
+            retired_through: None,
+            clock: Box::new(SystemClock),
+            phase_callback: None,
         })
     }
 
-    pub fn register_report_request(
-        &mut self,
-        request: BatchedRequest<Q>,
-    ) -> Result<(), ERR> {
-        // Update the sources that have been publicly requested for each epoch
-        let sources = &request.request.report_uris().source_uris;
-        for epoch in request.request.epoch_ids() {
-            for source in sources {
-                self.sources_per_epoch
-                    .entry(epoch)
-                    .or_default()
-                    .insert(source.clone());
-            }
-        }
-
-        self.new_pending_requests.push(request);
-        Ok(())
+    /// Replaces the clock, e.g. with a `MockClock` for simulations.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
     }
 
-    pub fn schedule_batch(&mut self) -> Result<Vec<BatchedReport<Q>>, ERR> {
-        debug!(
-            "Scheduling batch for interval {}",
-            self.current_scheduling_interval
-        );
-
-        let mut previous_batch = take(&mut self.batched_requests);
-        let mut new_requests = take(&mut self.new_pending_requests);
-
-        // We are starting a scheduling attempt. Decrement the number
-        // of remaining attempts for all requests in the system.
-        for request in &mut previous_batch {
-            request.n_remaining_scheduling_attempts -= 1;
-        }
-        for request in &mut new_requests {
-            request.n_remaining_scheduling_attempts -= 1;
-        }
+    /// Sets a callback invoked with a snapshot of the scheduler at the end of
+    /// each phase of `schedule_batch`.
+    pub fn with_phase_callback(
+        mut self,
+        callback: impl FnMut(BatchPhase, &BatchSchedulerStats<Q::EpochId, Q::Uri>)
+            + 'static,
+    ) -> Self {
+        self.phase_callback = Some(Box::new(callback));
+        self
+    }
 
-        // Previous batch gets the first shot.
-        let unallocated_from_previous_batch =
-            self.initialization_phase(previous_batch)?;
+    /// Retires epochs after `epoch_lifetime` scheduling intervals.
+    pub fn with_epoch_lifetime(mut self, epoch_lifetime: u64) -> Self {
+        self.epoch_lifetime = Some(epoch_lifetime);
+        self
+    }
 
-        // New online queries try next.
-        let unallocated_new_requests = self.online_phase(new_requests)?;
+    /// Drives the current epoch of the base PDS from the scheduling
+    /// intervals, see `EpochSchedule`.
+    pub fn with_epoch_schedule(
+        mut self,
+        epoch_schedule: EpochSchedule<Q::EpochId>,
+    ) -> Self {
+        self.epoch_schedule = Some(epoch_schedule);
+        self
+    }
 
-        // Put all the unallocated requests into the batch.
-        let mut batched_requests = vec![];
-        batched_requests.extend(unallocated_from_previous_batch);
-        batched_requests.extend(unallocated_new_requests);
+    /// Bounds the number of requests pending for the next scheduling
+    /// interval, see `max_pending_requests`.
+    pub fn with_max_pending_requests(
+        mut self,
+        max_pending_requests: usize,
+    ) -> Self {
+        self.max_pending_requests = Some(max_pending_requests);
+        self
+    }
 
-        // Requests with 0 remaining attempts will be answered and removed from
-        // the batch.
-        let unallocated_requests = self.batch_phase(batched_requests)?;
+    /// Runs `f` with the request at `index`, lent out of the arena so that
+    /// `f` can mutate the scheduler.
+    fn with_request<T>(
+        &mut self,
+        index: RequestIndex,
+        f: impl FnOnce(&mut Self, &BatchedRequest<Q>) -> T,
+    ) -> T {
+        let request = self.requests.lend(index);
+        let result = f(self, &request);
+        self.requests.restore(index, request);
+        result
+    }
 
-        // Store the batch for next scheduling interval.
-        self.batched_requests = unallocated_requests;
+    /// Whether the given epoch has been retired.
+    pub fn is_retired(&self, epoch_id: &Q::EpochId) -> bool {
+        self.retired_through
+            .is_some_and(|retired_through| *epoch_id <= retired_through)
+    }
 
-        // Take all the reports that are ready to be released.
-        let reports = self
-            .delayed_reports
-            .remove(&self.current_scheduling_interval)
-            .unwrap_or_default();
+    /// Takes a snapshot of the queues and of the public filters of the active
+    /// epochs.
+    pub fn stats(
+        &mut self,
+    ) -> Result<BatchSchedulerStats<Q::EpochId, Q::Uri>, ERR> {
+        let mut epochs = BTreeMap::new();
+        for (epoch_id, sources) in &self.sources_per_epoch {
+            let global_filter = self
+                .public_filters
+                .get_filter_or_new(&FilterId::Global(*epoch_id))?;
+            let global_consumed = global_filter.consumed()?;
+            let global_unlocked =
+                global_consumed + global_filter.unlocked_remaining()?;
+
+            let mut source_consumed = HashMap::new();
+            for source in sources {
+                let filter_id =
+                    FilterId::SourceQuota(*epoch_id, source.clone());
+                let filter =
+                    self.public_filters.get_filter_or_new(&filter_id)?;
+                source_consumed.insert(source.clone(), filter.consumed()?);
+            }
 
-        self.current_scheduling_interval += 1;
+            epochs.insert(
+                *epoch_id,
+                EpochSchedulerStats {
+                    global_unlocked,
+                    global_consumed,
+                    source_consumed,
+                },
+            );
+        }
 
-        Ok(reports)
+        Ok(BatchSchedulerStats {
+            scheduling_interval: self.current_scheduling_interval,
+            n_parked_requests: self.parked_requests.len(),
+            n_pending_requests: self.new_pending_requests.len(),
+            n_batched_requests: self.batched_requests.len(),
+            n_delayed_reports: self
+                .delayed_reports
+                .values()
+                .map(Vec::len)
+                .sum(),
+            epochs,
+        })
     }
 
-    /// Unlock fresh eps_c, enable imp quota with fresh capacity, and try to
-    /// allocate requests from the previous batch.
-    fn initialization_phase(
+    /// Compares the public filters of the active epochs, i.e. their Global
+    /// filters and the SourceQuota filters of their requested sources, with
+    /// the filters of the base PDS, and reports each drift to the observer.
+    /// With `tighten`, public filters that overestimate the consumption get
+    /// the difference refunded, so that the scheduler can allocate it again.
+    ///
+    /// WARNING: tightening makes the public filters depend on the private
+    /// consumption, which depends on the events on the device. The scheduling
+    /// decisions are not based only on public information anymore.
+    pub fn reconcile_public_filters(
         &mut self,
-        batched_requests: Vec<BatchedRequest<Q>>,
-    ) -> Result<Vec<BatchedRequest<Q>>, ERR> {
-        let imp_capacity =
-            self.pds.core.filter_storage.capacities().source_quota;
-
-        // Just try all the past epochs since our experiments just have a
-        // few. Could eventually discard epochs that have reached their
-        // lifetime if that becomes a bottleneck.
-        let epoch_ids =
-            self.sources_per_epoch.keys().copied().collect::<Vec<_>>();
-        for epoch_id in epoch_ids {
-            // Release (unlock) a fraction of this epoch's global budget
-            self.release_budget(epoch_id)?;
+        tighten: bool,
+    ) -> Result<DriftReport<FilterIdQ<Q>>, ERR> {
+        let mut filter_ids = vec![];
+        for (epoch_id, sources) in &self.sources_per_epoch {
+            filter_ids.push(FilterId::Global(*epoch_id));
+            for source in sources {
+                filter_ids
+                    .push(FilterId::SourceQuota(*epoch_id, source.clone()));
+            }
+        }
 
-            // Turn on the impression-site quota
-            self.set_imp_quota_capacity(epoch_id, imp_capacity)?;
+        let mut n_filters = 0;
+        let mut drifted_filters = vec![];
+        for filter_id in filter_ids {
+            let Some(public_filter) =
+                self.public_filters.get_filter(&filter_id)?
+            else {
+                continue;
+            };
+            let private_consumed =
+                match self.pds.core.filter_storage.get_filter(&filter_id)? {
+                    Some(filter) => filter.consumed()?,
+                    None => PureDPBudget::zero(),
+                };
+            n_filters += 1;
+
+            let filter_drift = FilterDrift {
+                filter_id,
+                public_consumed: public_filter.consumed()?,
+                private_consumed,
+            };
+            if filter_drift.drift() != 0.0 {
+                self.pds.core.observer.on_public_filter_drift(
+                    &filter_drift.filter_id,
+                    &filter_drift.drift(),
+                );
+                drifted_filters.push(filter_drift);
+            }
+        }
+        drifted_filters.sort_by(|a, b| b.drift().total_cmp(&a.drift()));
+
+        if tighten {
+            for filter_drift in &drifted_filters {
+                if filter_drift.drift() > 0.0 {
+                    self.public_filters.refund(
+                        &filter_drift.filter_id,
+                        &filter_drift.drift(),
+                    )?;
+                }
+            }
         }
 
-        let unallocated_requests =
-            self.try_allocate(batched_requests, false)?;
-        Ok(unallocated_requests)
+        let positive_drifts = drifted_filters
+            .iter()
+            .map(FilterDrift::drift)
+            .filter(|drift| *drift > 0.0);
+        let report = DriftReport {
+            n_filters,
+            total_drift: positive_drifts.clone().sum(),
+            max_drift: positive_drifts
+                .fold(PureDPBudget::zero(), |max, drift| {
+                    max.max_budget(&drift)
+                }),
+            drifted_filters,
+            tightened: tighten,
+        };
+        debug!(
+            "Public filters drift by {} in total over {} filters",
+            report.total_drift, report.n_filters
+        );
+        Ok(report)
     }
 
-    /// Browse `new_requests` one by one, try to allocate under
-    /// regular quotas. Stores allocated requests for delayed response. Returns
-    /// a list of unallocated requests.
-    fn online_phase(
+    /// Invokes the phase callback, if any. Requests are moved out of the
+    /// queues while `schedule_batch` runs, so the caller passes their counts.
+    fn end_phase(
         &mut self,
-        new_requests: Vec<BatchedRequest<Q>>,
-    ) -> Result<Vec<BatchedRequest<Q>>, ERR> {
-        let unallocated_requests = self.try_allocate(new_requests, false)?;
-        Ok(unallocated_requests)
+        phase: BatchPhase,
+        n_pending_requests: usize,
+        n_batched_requests: usize,
+    ) -> Result<(), ERR> {
+        let Some(mut callback) = self.phase_callback.take() else {
+            return Ok(());
+        };
+        let stats = self.stats().map(|stats| BatchSchedulerStats {
+            n_pending_requests,
+            n_batched_requests,
+            ..stats
+        });
+        if let Ok(stats) = &stats {
+            callback(phase, stats);
+        }
+        self.phase_callback = Some(callback);
+        stats.map(|_| ())
     }
 
-    /// Disable the imp quotas, sort the requests, and try to allocate them.
-    /// Stores allocated requests for delayed response. Returns a list of
-    /// unallocated requests.
-    fn batch_phase(
+    /// Registers a request for the next scheduling interval. Real-time
+    /// requests, with 0 scheduling attempts, bypass batching and get their
+    /// reports right away, one per querier. Batched requests whose
+    /// attribution window is still open are parked until the window closes,
+    /// based on the current epoch of the base PDS.
+    pub fn register_report_request(
         &mut self,
-        batched_requests: Vec<BatchedRequest<Q>>,
-    ) -> Result<Vec<BatchedRequest<Q>>, ERR> {
-        let epoch_ids =
-            self.sources_per_epoch.keys().copied().collect::<Vec<_>>();
-        for epoch_id in epoch_ids {
-            // Turn the impression-site quotas off by setting their capacity
-            // to infinity.
-            self.set_imp_quota_capacity(epoch_id, f64::INFINITY)?;
-        }
-
-        // Repeatedly sort and try to allocate. Re-sort each time a request is
-        // allocated. Exit the loop when no more request can be
-        // allocated.
-        let sorted_batched_requests = self.sort_batch(batched_requests)?;
-        let mut k = sorted_batched_requests.len();
-        let (mut unallocated_requests, mut allocated_index) =
-            self.try_allocate_one(sorted_batched_requests, true)?;
-        debug!("Tried allocating one request.");
-
-        while let Some(i) = allocated_index {
-            if i == k - 1 {
-                // We allocated the last request. No need to sort again.
-                debug!("Allocated all the requests we could.");
-                break;
-            }
+        mut request: BatchedRequest<Q>,
+    ) -> Result<Vec<BatchedReport<Q>>, ERR> {
+        request.registered_at = self.clock.now();
+
+        // Duration windows are resolved once, so that parked and pending
+        // requests are allocated on fixed epochs.
+        if let Cow::Owned(resolved) =
+            self.pds.resolve_epoch_window(&request.request)?
+        {
+            request.request = resolved;
+        }
+
+        // Requests with too little noise for their sensitivity would only
+        // fail once they are allocated, failing the whole batch.
+        self.pds.validate_noise_floor(&request.request)?;
 
+        // Same for requests whose queriers can't share a report.
+        Self::check_queriers(&self.pds.core, &request.request)?;
+
+        if request.n_remaining_scheduling_attempts == 0 {
+            // Real-time requests can't wait for their window to close.
+            self.pds.validate_epoch_window(&request.request)?;
+            self.track_epochs(&request.request);
+            return self.real_time_phase(request);
+        }
+
+        if !self.pds.is_window_closed(&request.request) {
             debug!(
-                "Allocated request {i} from the unallocated ones. Sorting the remaining requests and trying to allocate again."
+                "Parking request {} until its window closes",
+                request.request_id
             );
-            let sorted_batched_requests =
-                self.sort_batch(unallocated_requests)?;
-            k = sorted_batched_requests.len();
-            (unallocated_requests, allocated_index) =
-                self.try_allocate_one(sorted_batched_requests, true)?;
+            let index = self.requests.insert(request);
+            self.parked_requests.push(index);
+            return Ok(vec![]);
         }
 
-        Ok(unallocated_requests)
+        // Back-pressure: reject the request before tracking its epochs, so
+        // that the caller can retry later as if it never came.
+        if let Some(max_pending_requests) = self.max_pending_requests {
+            if self.new_pending_requests.len() >= max_pending_requests {
+                return Err(PdsError::QueueFull(format!(
+                    "{max_pending_requests} requests are already pending, retry after the next scheduling interval"
+                ))
+                .into());
+            }
+        }
+
+        self.track_epochs(&request.request);
+        let index = self.requests.insert(request);
+        self.new_pending_requests.push(index);
+        Ok(vec![])
     }
 
-    /// Just mimics `deduct_budget` but with non-IDP filters.
-    /// And also does it across all epochs.
-    fn deduct_budget(
-        &mut self,
+    /// Checks that the request has queriers to deliver its reports to, and
+    /// that they can share a report, see `check_beneficiaries`.
+    fn check_queriers(
+        core: &PrivateDataServiceCore<Q, FS, ERR>,
         request: &Q,
-        dry_run: bool,
-    ) -> Result<PdsFilterStatus<FilterIdQ<Q>>, ERR> {
-        let uris = request.report_uris();
+    ) -> Result<(), PdsError> {
+        if request.report_uris().querier_uris.is_empty() {
+            return Err(PdsError::InvalidRequest(
+                "the request has no querier to deliver reports to".into(),
+            ));
+        }
+        core.check_beneficiaries(request)
+    }
 
-        // Case 3 from Cookie Monster only.
-        let NoiseScale::Laplace(noise_scale) = request.noise_scale();
-        let loss = request.report_global_sensitivity() / noise_scale;
+    /// Cancels a registered request. Requests that are not allocated yet are
+    /// removed, whether they are parked, pending or batched. Requests that are
+    /// already allocated have their delayed report dropped instead.
+    ///
+    /// NOTE: the epochs of a withdrawn request stay tracked, since they were
+    /// already requested publicly.
+    pub fn cancel_report_request(
+        &mut self,
+        request_id: u64,
+    ) -> CancellationAck {
+        let mut withdrawn = vec![];
+        for queue in [
+            &mut self.parked_requests,
+            &mut self.new_pending_requests,
+            &mut self.batched_requests,
+        ] {
+            queue.retain(|index| {
+                let keep = self.requests[*index].request_id != request_id;
+                if !keep {
+                    withdrawn.push(*index);
+                }
+                keep
+            });
+        }
+        for index in &withdrawn {
+            self.requests.remove(*index);
+        }
+        let withdrawn = !withdrawn.is_empty();
+
+        let mut suppressed = false;
+        for reports in self.delayed_reports.values_mut() {
+            let n_reports = reports.len();
+            reports.retain(|report| report.request_id != request_id);
+            suppressed |= reports.len() < n_reports;
+        }
+        self.delayed_reports
+            .retain(|_, reports| !reports.is_empty());
+
+        let ack = match (withdrawn, suppressed) {
+            (true, _) => CancellationAck::Withdrawn,
+            (false, true) => CancellationAck::ReportSuppressed,
+            (false, false) => CancellationAck::NotFound,
+        };
+        debug!("Canceled request {request_id}: {ack:?}");
+        ack
+    }
+
+    /// Registers a recurring request. Its budget is reserved from the next
+    /// epoch that starts, so subscriptions need an epoch schedule.
+    pub fn subscribe(
+        &mut self,
+        subscription: Subscription<Q>,
+    ) -> Result<(), ERR> {
+        let Some(epoch_schedule) = &self.epoch_schedule else {
+            return Err(PdsError::InvalidConfig(
+                "subscriptions need an epoch schedule".into(),
+            )
+            .into());
+        };
+        let subscription_id = subscription.subscription_id();
+        if self
+            .subscriptions
+            .iter()
+            .any(|other| other.subscription_id() == subscription_id)
+        {
+            return Err(PdsError::InvalidRequest(format!(
+                "subscription {subscription_id} already exists"
+            ))
+            .into());
+        }
 
+        // Reject invalid descriptors now rather than when an epoch starts.
+        let epoch_id =
+            epoch_schedule.epoch_at(self.current_scheduling_interval);
+        Self::check_queriers(
+            &self.pds.core,
+            &subscription.request_for(&epoch_id),
+        )?;
+
+        debug!("Registering subscription {subscription_id}");
+        self.subscriptions.push(subscription);
+        Ok(())
+    }
+
+    /// Removes a subscription, and refunds the budget reserved for its
+    /// reports that were not answered yet. Returns false if there is no such
+    /// subscription.
+    pub fn unsubscribe(
+        &mut self,
+        subscription_id: SubscriptionId,
+    ) -> Result<bool, ERR> {
+        let n_subscriptions = self.subscriptions.len();
+        self.subscriptions.retain(|subscription| {
+            subscription.subscription_id() != subscription_id
+        });
+
+        let (canceled, kept): (Vec<_>, Vec<_>) =
+            take(&mut self.subscription_reservations)
+                .into_iter()
+                .partition(|reservation| {
+                    reservation.subscription_id == subscription_id
+                });
+        self.subscription_reservations = kept;
+        for reservation in &canceled {
+            if !reservation.oob_filters.is_empty() {
+                continue;
+            }
+            let loss = Self::public_loss(&reservation.request);
+            for filter_id in self.public_filter_ids(&reservation.request) {
+                self.public_filters.refund(&filter_id, &loss)?;
+            }
+        }
+
+        debug!(
+            "Unsubscribed {subscription_id}, refunded {} reservations",
+            canceled.len()
+        );
+        Ok(self.subscriptions.len() < n_subscriptions || !canceled.is_empty())
+    }
+
+    /// Answers a pull subscription for the given epoch, with the budget
+    /// reserved when the epoch started, with one report per querier. Fails if
+    /// nothing is reserved for that epoch, or if the epoch is not over yet.
+    pub fn fetch_subscription_reports(
+        &mut self,
+        subscription_id: SubscriptionId,
+        epoch_id: Q::EpochId,
+    ) -> Result<Vec<SubscriptionReport<Q>>, ERR> {
+        let Some(position) =
+            self.subscription_reservations
+                .iter()
+                .position(|reservation| {
+                    reservation.subscription_id == subscription_id
+                        && reservation.epoch_id == epoch_id
+                        && reservation.delivery == SubscriptionDelivery::Pull
+                })
+        else {
+            return Err(PdsError::InvalidRequest(format!(
+                "no reservation of pull subscription {subscription_id} for epoch {epoch_id:?}"
+            ))
+            .into());
+        };
+        if !self
+            .pds
+            .is_window_closed(&self.subscription_reservations[position].request)
+        {
+            return Err(PdsError::InvalidEpochWindow(format!(
+                "epoch {epoch_id:?} of subscription {subscription_id} is not over yet"
+            ))
+            .into());
+        }
+
+        let reservation = self.subscription_reservations.remove(position);
+        self.answer_subscription(reservation)
+    }
+
+    /// Takes the reports of push subscriptions answered so far.
+    pub fn take_subscription_reports(&mut self) -> Vec<SubscriptionReport<Q>> {
+        take(&mut self.subscription_reports)
+    }
+
+    /// Update the sources that have been publicly requested for each active
+    /// epoch.
+    fn track_epochs(&mut self, request: &Q) {
+        let sources = &request.report_uris().source_uris;
+        for epoch in request.epoch_ids() {
+            if self.is_retired(&epoch) {
+                continue;
+            }
+            self.epoch_first_intervals
+                .entry(epoch)
+                .or_insert(self.current_scheduling_interval);
+            for source in sources {
+                self.sources_per_epoch
+                    .entry(epoch)
+                    .or_default()
+                    .insert(source.clone());
+            }
+        }
+    }
+
+    /// Moves the parked requests whose window closed to the pending requests
+    /// of this interval.
+    fn unpark_requests(&mut self) {
+        let (closed, parked): (Vec<_>, Vec<_>) =
+            take(&mut self.parked_requests)
+                .into_iter()
+                .partition(|index| {
+                    self.pds.is_window_closed(&self.requests[*index].request)
+                });
+        self.parked_requests = parked;
+        for index in closed {
+            self.with_request(index, |this, request| {
+                debug!("Unparking request {}", request.request_id);
+                this.track_epochs(&request.request);
+            });
+            self.new_pending_requests.push(index);
+        }
+    }
+
+    pub fn schedule_batch(&mut self) -> Result<Vec<BatchedReport<Q>>, ERR> {
+        let _scope = CorrelationScope::enter("schedule_batch");
+        let _span = timed_span!(
+            "schedule_batch",
+            correlation_id = _scope.id(),
+            scheduling_interval = self.current_scheduling_interval
+        );
+        debug!(
+            "Scheduling batch for interval {}",
+            self.current_scheduling_interval
+        );
+
+        let new_epoch = self.advance_epoch()?;
+        self.unpark_requests();
+        self.retire_epochs()?;
+
+        let previous_batch = take(&mut self.batched_requests);
+        let new_requests = take(&mut self.new_pending_requests);
+
+        // We are starting a scheduling attempt. Decrement the number
+        // of remaining attempts for all requests in the system.
+        for index in previous_batch.iter().chain(&new_requests) {
+            self.requests[*index].n_remaining_scheduling_attempts -= 1;
+        }
+
+        if let Err(error) = self.run_phases(
+            previous_batch.clone(),
+            new_requests.clone(),
+            new_epoch,
+        ) {
+            self.requeue(previous_batch, new_requests);
+            return Err(error);
+        }
+
+        // Take all the reports that are ready to be released.
+        let reports = self
+            .delayed_reports
+            .remove(&self.current_scheduling_interval)
+            .unwrap_or_default();
+
+        self.current_scheduling_interval += 1;
+
+        self.pds
+            .core
+            .observer
+            .on_batch_scheduled(self.batched_requests.len(), reports.len());
+
+        Ok(reports)
+    }
+
+    /// Runs the phases of a scheduling attempt on the previous batch and the
+    /// new requests, and stores the requests that are still unallocated for
+    /// the next scheduling interval.
+    fn run_phases(
+        &mut self,
+        previous_batch: Vec<RequestIndex>,
+        new_requests: Vec<RequestIndex>,
+        new_epoch: Option<Q::EpochId>,
+    ) -> Result<(), ERR> {
+        // Previous batch gets the first shot.
+        let unallocated_from_previous_batch =
+            self.initialization_phase(previous_batch, new_epoch)?;
+        self.end_phase(
+            BatchPhase::Initialization,
+            new_requests.len(),
+            unallocated_from_previous_batch.len(),
+        )?;
+
+        // New online queries try next.
+        let unallocated_new_requests = self.online_phase(new_requests)?;
+        self.end_phase(
+            BatchPhase::Online,
+            0,
+            unallocated_from_previous_batch.len()
+                + unallocated_new_requests.len(),
+        )?;
+
+        // Put all the unallocated requests into the batch.
+        let mut batched_requests = vec![];
+        batched_requests.extend(unallocated_from_previous_batch);
+        batched_requests.extend(unallocated_new_requests);
+
+        // Requests with 0 remaining attempts will be answered and removed from
+        // the batch.
+        let unallocated_requests = self.batch_phase(batched_requests)?;
+
+        // Store the batch for next scheduling interval.
+        self.batched_requests = unallocated_requests;
+        self.end_phase(BatchPhase::Batch, 0, self.batched_requests.len())
+    }
+
+    /// Puts the requests of a failed scheduling attempt back in their queues,
+    /// with the attempt they didn't get. Requests that were allocated before
+    /// the failure already left the arena, and their reports are released
+    /// as planned. The scheduling interval doesn't advance, so that the next
+    /// call to `schedule_batch` retries it.
+    fn requeue(
+        &mut self,
+        previous_batch: Vec<RequestIndex>,
+        new_requests: Vec<RequestIndex>,
+    ) {
+        let mut requeue = |indices: Vec<RequestIndex>| {
+            let mut queued = vec![];
+            for index in indices {
+                if self.requests.get(index).is_some() {
+                    self.requests[index].n_remaining_scheduling_attempts += 1;
+                    queued.push(index);
+                }
+            }
+            queued
+        };
+        let previous_batch = requeue(previous_batch);
+        let new_requests = requeue(new_requests);
+        warn!(
+            "Scheduling interval {} failed, requeued {} requests",
+            self.current_scheduling_interval,
+            previous_batch.len() + new_requests.len()
+        );
+        self.batched_requests = previous_batch;
+        self.new_pending_requests = new_requests;
+    }
+
+    /// Moves the base PDS to the epoch of the current scheduling interval, if
+    /// there is an epoch schedule. A new epoch becomes active right away,
+    /// with its public Global filter, so that its budget starts being
+    /// released before it is requested. Returns the epoch that started, if
+    /// any.
+    fn advance_epoch(&mut self) -> Result<Option<Q::EpochId>, ERR> {
+        let Some(epoch_schedule) = &self.epoch_schedule else {
+            return Ok(None);
+        };
+        if !epoch_schedule.starts_epoch(self.current_scheduling_interval) {
+            return Ok(None);
+        }
+
+        let epoch_id =
+            epoch_schedule.epoch_at(self.current_scheduling_interval);
+        debug!(
+            "Starting epoch {epoch_id:?} at interval {}",
+            self.current_scheduling_interval
+        );
+        self.pds.set_current_epoch(epoch_id);
+        if self.is_retired(&epoch_id) {
+            return Ok(None);
+        }
+
+        self.epoch_first_intervals
+            .entry(epoch_id)
+            .or_insert(self.current_scheduling_interval);
+        self.sources_per_epoch.entry(epoch_id).or_default();
+        self.initialize_filters([&FilterId::Global(epoch_id)].into_iter())?;
+        Ok(Some(epoch_id))
+    }
+
+    /// Whether the Global filter of the given epoch still gets releases.
+    fn is_within_release_horizon(&self, epoch_id: &Q::EpochId) -> bool {
+        self.epoch_first_intervals
+            .get(epoch_id)
+            .is_some_and(|first_interval| {
+                self.current_scheduling_interval - first_interval
+                    < self.n_releases as u64
+            })
+    }
+
+    /// Advice for a request that was out of budget on the given public
+    /// filters. Global filters that still get releases will have more budget
+    /// at the next scheduling interval.
+    fn retry_advice(
+        &self,
+        oob_filters: &[FilterIdQ<Q>],
+    ) -> Option<RetryAdvice> {
+        oob_filters
+            .iter()
+            .map(|filter_id| match filter_id {
+                FilterId::Global(epoch_id)
+                    if self.is_within_release_horizon(epoch_id) =>
+                {
+                    RetryAdvice::ReleasePending
+                }
+                _ => RetryAdvice::for_filter_kind(filter_id.kind()),
+            })
+            .max()
+    }
+
+    /// Reserves the budget of each subscription for the epoch that just
+    /// started, on the public filters, before other requests can be
+    /// allocated. Subscriptions that are out of budget reserve nothing, and
+    /// are answered with a null report.
+    fn reserve_subscriptions(
+        &mut self,
+        epoch_id: Q::EpochId,
+    ) -> Result<(), ERR> {
+        // A retried interval doesn't reserve twice for the same epoch.
+        let requests = self
+            .subscriptions
+            .iter()
+            .filter(|subscription| {
+                !self.subscription_reservations.iter().any(|reservation| {
+                    reservation.subscription_id
+                        == subscription.subscription_id()
+                        && reservation.epoch_id == epoch_id
+                })
+            })
+            .map(|subscription| {
+                (
+                    subscription.subscription_id(),
+                    subscription.delivery(),
+                    subscription.request_for(&epoch_id),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        for (subscription_id, delivery, request) in requests {
+            self.track_epochs(&request);
+            let oob_filters = match self.deduct_budget(&request, true)? {
+                PdsFilterStatus::Continue => {
+                    self.commit_public_deduction(&request)?;
+                    vec![]
+                }
+                PdsFilterStatus::OutOfBudget(oob_filters) => oob_filters,
+            };
+            debug!(
+                "Reserved budget of subscription {subscription_id} for epoch {epoch_id:?}, out of budget filters: {oob_filters:?}"
+            );
+            self.subscription_reservations
+                .push(SubscriptionReservation {
+                    subscription_id,
+                    delivery,
+                    epoch_id,
+                    request,
+                    reserved_at: self.clock.now(),
+                    oob_filters,
+                });
+        }
+        Ok(())
+    }
+
+    /// Answers the push subscriptions whose epoch is over.
+    fn answer_push_subscriptions(&mut self) -> Result<(), ERR> {
+        let (closed, open): (Vec<_>, Vec<_>) =
+            take(&mut self.subscription_reservations)
+                .into_iter()
+                .partition(|reservation| {
+                    reservation.delivery == SubscriptionDelivery::Push
+                        && self.pds.is_window_closed(&reservation.request)
+                });
+        self.subscription_reservations = open;
+        for reservation in closed {
+            let reports = self.answer_subscription(reservation)?;
+            self.subscription_reports.extend(reports);
+        }
+        Ok(())
+    }
+
+    /// Computes the report of a subscription, for each of its queriers. Its
+    /// public budget was already deducted by the reservation.
+    fn answer_subscription(
+        &mut self,
+        reservation: SubscriptionReservation<Q>,
+    ) -> Result<Vec<SubscriptionReport<Q>>, ERR> {
+        let report = if reservation.oob_filters.is_empty() {
+            self.initialize_filters_for_request(&reservation.request)?;
+            self.pds.compute_report(&reservation.request)?
+        } else {
+            PdsReport {
+                retry_advice: self.retry_advice(&reservation.oob_filters),
+                oob_filters: reservation.oob_filters,
+                ..PdsReport::null(NullReason::OutOfBudget)
+            }
+        };
+        debug!(
+            "Subscription {} got report {report:?} for epoch {:?}",
+            reservation.subscription_id, reservation.epoch_id
+        );
+        let computed_at = self.clock.now();
+        Ok(per_querier(&reservation.request, report)
+            .map(|(querier_uri, report)| SubscriptionReport {
+                subscription_id: reservation.subscription_id,
+                querier_uri,
+                epoch_id: reservation.epoch_id,
+                reserved_at: reservation.reserved_at,
+                computed_at,
+                report,
+            })
+            .collect())
+    }
+
+    /// Retire the epochs that reached their lifetime, along with all the
+    /// older epochs. Their budget is not released anymore, their quotas are
+    /// not toggled anymore, and their filters are pruned.
+    fn retire_epochs(&mut self) -> Result<(), ERR> {
+        let Some(epoch_lifetime) = self.epoch_lifetime else {
+            return Ok(());
+        };
+
+        let expired_epochs = self
+            .epoch_first_intervals
+            .iter()
+            .filter(|(_, first_interval)| {
+                self.current_scheduling_interval - **first_interval
+                    >= epoch_lifetime
+            })
+            .map(|(epoch_id, _)| *epoch_id);
+        let Some(retired_through) =
+            expired_epochs.chain(self.retired_through).max()
+        else {
+            return Ok(());
+        };
+        if self.retired_through != Some(retired_through) {
+            self.prune_retired_epochs(retired_through)?;
+        }
+        self.retired_through = Some(retired_through);
+
+        self.epoch_first_intervals
+            .retain(|epoch_id, _| *epoch_id > retired_through);
+        self.sources_per_epoch
+            .retain(|epoch_id, _| *epoch_id > retired_through);
+        self.last_releases
+            .retain(|epoch_id, _| *epoch_id > retired_through);
+        debug!("Retired epochs up to {retired_through:?}");
+        Ok(())
+    }
+
+    /// Prunes the private and public filters of the retired epochs. The epoch
+    /// schedule tells which epoch follows the last retired one. Without a
+    /// schedule, the last retired epoch is only pruned at the next
+    /// retirement.
+    fn prune_retired_epochs(
+        &mut self,
+        retired_through: Q::EpochId,
+    ) -> Result<(), ERR> {
+        let older_than_epoch = match &self.epoch_schedule {
+            Some(epoch_schedule) => {
+                (epoch_schedule.next_epoch)(&retired_through)
+            }
+            None => retired_through,
+        };
+        let n_private = self.pds.prune_epochs(older_than_epoch)?;
+        let core = &self.pds.core;
+        let n_public = self
+            .public_filters
+            .prune(&|filter_id| core.is_filter_pruned(filter_id))?;
+        debug!(
+            "Pruned {n_private} private and {n_public} public filters older than epoch {older_than_epoch:?}"
+        );
+        Ok(())
+    }
+
+    /// Unlock fresh eps_c, enable imp quota with fresh capacity, answer the
+    /// push subscriptions whose epoch closed, reserve budget for the
+    /// subscriptions of the epoch that started, if any, and try to allocate
+    /// requests from the previous batch.
+    fn initialization_phase(
+        &mut self,
+        mut batched_requests: Vec<RequestIndex>,
+        new_epoch: Option<Q::EpochId>,
+    ) -> Result<Vec<RequestIndex>, ERR> {
+        let _span = timed_span!(
+            "initialization_phase",
+            n_requests = batched_requests.len()
+        );
+        let imp_capacity =
+            self.pds.core.filter_storage.capacities().source_quota;
+
+        // Retired epochs are not in `sources_per_epoch` anymore, so the work
+        // per interval is bounded by the number of active epochs.
+        let epoch_ids =
+            self.sources_per_epoch.keys().copied().collect::<Vec<_>>();
+        for epoch_id in epoch_ids {
+            // Release (unlock) a fraction of this epoch's global budget, until
+            // the end of its release horizon.
+            if self.is_within_release_horizon(&epoch_id) {
+                self.release_budget(epoch_id)?;
+            }
+
+            // Turn on the impression-site quota
+            self.set_imp_quota_capacity(epoch_id, imp_capacity)?;
+        }
+
+        self.answer_push_subscriptions()?;
+        if let Some(epoch_id) = new_epoch {
+            self.reserve_subscriptions(epoch_id)?;
+        }
+
+        self.requests.sort_by_rank(&mut batched_requests);
+        let unallocated_requests =
+            self.try_allocate(batched_requests, false)?;
+        Ok(unallocated_requests)
+    }
+
+    /// Browse `new_requests` one by one, try to allocate under
+    /// regular quotas. Stores allocated requests for delayed response. Returns
+    /// a list of unallocated requests.
+    fn online_phase(
+        &mut self,
+        mut new_requests: Vec<RequestIndex>,
+    ) -> Result<Vec<RequestIndex>, ERR> {
+        let _span =
+            timed_span!("online_phase", n_requests = new_requests.len());
+        self.requests.sort_by_rank(&mut new_requests);
+        let unallocated_requests = self.try_allocate(new_requests, false)?;
+        Ok(unallocated_requests)
+    }
+
+    /// Try to allocate a real-time request right away, under regular quotas
+    /// and the budget released so far. If the request can't be allocated, it
+    /// is answered with a null report listing the public filters that were
+    /// out of budget.
+    fn real_time_phase(
+        &mut self,
+        request: BatchedRequest<Q>,
+    ) -> Result<Vec<BatchedReport<Q>>, ERR> {
+        let _span =
+            timed_span!("real_time_phase", request_id = request.request_id);
+        let imp_capacity =
+            self.pds.core.filter_storage.capacities().source_quota;
+        let epoch_ids = unique_epochs(request.request.epoch_ids());
+        for epoch_id in &epoch_ids {
+            self.set_imp_quota_capacity(*epoch_id, imp_capacity)?;
+        }
+
+        let report = match self.deduct_budget(&request.request, true)? {
+            PdsFilterStatus::Continue => self.allocate(&request, false)?,
+            PdsFilterStatus::OutOfBudget(oob_filters) => PdsReport {
+                retry_advice: self.retry_advice(&oob_filters),
+                oob_filters,
+                ..PdsReport::null(NullReason::OutOfBudget)
+            },
+        };
+
+        // Quotas stay off between two scheduling intervals, as after the
+        // batch phase.
+        for epoch_id in epoch_ids {
+            self.set_imp_quota_capacity(epoch_id, PureDPBudget::infinity())?;
+        }
+
+        debug!(
+            "Real-time request {} got report {report:?}",
+            request.request_id
+        );
+        Ok(self.batched_reports(&request, report))
+    }
+
+    /// Disable the imp quotas, sort the requests, and try to allocate them.
+    /// Stores allocated requests for delayed response. Returns a list of
+    /// unallocated requests.
+    fn batch_phase(
+        &mut self,
+        batched_requests: Vec<RequestIndex>,
+    ) -> Result<Vec<RequestIndex>, ERR> {
+        let _span =
+            timed_span!("batch_phase", n_requests = batched_requests.len());
+        let epoch_ids =
+            self.sources_per_epoch.keys().copied().collect::<Vec<_>>();
+        for epoch_id in epoch_ids {
+            // Turn the impression-site quotas off by setting their capacity
+            // to infinity.
+            self.set_imp_quota_capacity(epoch_id, PureDPBudget::infinity())?;
+        }
+
+        // Try the requests by increasing key, see `sort_batch`. Allocations
+        // only consume budget, so keys can only increase and a request that
+        // can't be allocated now can't be allocated later in this phase.
+        // Instead of re-sorting the batch after each allocation, keys are
+        // refreshed lazily: a request whose key increased since it was pushed
+        // is pushed back with its current key. The epochs of the batch are
+        // fixed at the start of the phase.
+        let (epochs, mut budget_per_source) =
+            self.budget_per_source(&batched_requests)?;
+        let mut queue = BinaryHeap::new();
+        for (position, index) in batched_requests.into_iter().enumerate() {
+            let key = self.batch_key(index, position, &budget_per_source)?;
+            queue.push(Reverse((key, index)));
+        }
+
+        let mut unallocated_requests = vec![];
+        while let Some(Reverse((key, index))) = queue.pop() {
+            let current_key =
+                self.batch_key(index, key.position, &budget_per_source)?;
+            if current_key > key {
+                queue.push(Reverse((current_key, index)));
+                continue;
+            }
+
+            let sources = self.requests[index]
+                .request
+                .report_uris()
+                .source_uris
+                .clone();
+            if self.try_allocate(vec![index], true)?.is_empty() {
+                debug!("Allocated request at position {}", key.position);
+
+                // Only the sources of the allocated request consumed budget.
+                for source in sources {
+                    let budget = self.source_budget(&source, &epochs)?;
+                    budget_per_source.insert(source, budget);
+                }
+            } else {
+                unallocated_requests.push(index);
+            }
+        }
+
+        // Keep the batch sorted for the next scheduling interval.
+        self.sort_batch(unallocated_requests)
+    }
+
+    /// Public filters that a request deducts from, across all its epochs.
+    fn public_filter_ids(&self, request: &Q) -> Vec<FilterIdQ<Q>> {
+        let uris = request.report_uris();
+
+        // Same quota exemptions as the base PDS.
+        let exemptions = &self.pds.core.quota_exemptions;
+        let is_trigger_exempt = exemptions.is_exempt(&uris.trigger_uri, uris);
+        let source_trigger_quotas = self
+            .pds
+            .core
+            .filter_storage
+            .capacities()
+            .source_trigger_quota
+            .is_some();
         let mut filter_ids = vec![];
-        for epoch_id in request.epoch_ids() {
+        for epoch_id in unique_epochs(request.epoch_ids()) {
+            // Pruned epochs don't consume budget anymore, so their filters
+            // are not recreated.
+            if self.pds.core.is_request_epoch_pruned(uris, epoch_id) {
+                continue;
+            }
+
             // Build the filter IDs for PerQuerier, Global and TriggerQuota.
-            // SourceQuota has the same loss here.
+            // SourceQuota and SourceTriggerQuota have the same loss here.
+            // Queriers of a group share their PerQuerier filter, which the
+            // base PDS only charges once.
             for query_uri in &uris.querier_uris {
-                filter_ids
-                    .push(FilterId::PerQuerier(epoch_id, query_uri.clone()));
+                let filter_id = self
+                    .pds
+                    .core
+                    .querier_groups
+                    .per_querier_filter(epoch_id, query_uri);
+                if !filter_ids.contains(&filter_id) {
+                    filter_ids.push(filter_id);
+                }
+            }
+            if !is_trigger_exempt {
+                filter_ids.push(FilterId::TriggerQuota(
+                    epoch_id,
+                    uris.trigger_uri.clone(),
+                ));
             }
-            filter_ids.push(FilterId::TriggerQuota(
-                epoch_id,
-                uris.trigger_uri.clone(),
-            ));
             filter_ids.push(FilterId::Global(epoch_id));
 
             for source in &uris.source_uris {
+                if exemptions.is_exempt(source, uris) {
+                    continue;
+                }
                 filter_ids
                     .push(FilterId::SourceQuota(epoch_id, source.clone()));
+                if source_trigger_quotas && !is_trigger_exempt {
+                    filter_ids.push(FilterId::SourceTriggerQuota(
+                        epoch_id,
+                        source.clone(),
+                        uris.trigger_uri.clone(),
+                    ));
+                }
             }
         }
+        filter_ids
+    }
 
+    /// Privacy loss of a request on the public filters. Case 3 from Cookie
+    /// Monster only.
+    fn public_loss(request: &Q) -> FS::Budget {
+        let NoiseScale::Laplace(noise_scale) = request.noise_scale();
+        request.report_global_sensitivity() / noise_scale
+    }
+
+    /// Just mimics `deduct_budget` but with non-IDP filters.
+    /// And also does it across all epochs.
+    fn deduct_budget(
+        &mut self,
+        request: &Q,
+        dry_run: bool,
+    ) -> Result<PdsFilterStatus<FilterIdQ<Q>>, ERR> {
+        let loss = Self::public_loss(request);
+        let filter_ids = self.public_filter_ids(request);
         self.initialize_filters(filter_ids.iter())?;
 
         // Try to consume the privacy loss from the filters
@@ -383,13 +1712,65 @@ where
         Ok(PdsFilterStatus::Continue)
     }
 
-    /// After sending a request for allocation by calling `compute_report`, keep
-    /// track of public information that was in the request. We don't peek
-    /// into the result of the report itself or the state of the filters. Maybe
-    /// the request was not allocated after all.
-    fn update_allocation_statistics(&mut self, request: &Q) -> Result<(), ERR> {
-        self.deduct_budget(request, false)?;
-        Ok(())
+    /// Largest shortfall of a request across its public filters, i.e. how
+    /// much budget is missing for it to be allocated right now. Only used to
+    /// sort the batch, never returned to queriers.
+    fn request_shortfall(&mut self, request: &Q) -> Result<FS::Budget, ERR> {
+        let loss = Self::public_loss(request);
+        let filter_ids = self.public_filter_ids(request);
+        self.initialize_filters(filter_ids.iter())?;
+
+        let mut shortfall = FS::Budget::zero();
+        for fid in filter_ids {
+            let filter = self.public_filters.get_filter_or_new(&fid)?;
+            shortfall = shortfall.max_budget(&filter.shortfall(&loss)?);
+        }
+        Ok(shortfall)
+    }
+
+    /// Deducts the public loss of a request from its public filters, keeping
+    /// track of public information only: we don't peek into the report or
+    /// the private filters, and maybe the request won't be allocated after
+    /// all. Filters without enough budget are skipped. All or nothing: if a
+    /// filter fails, e.g. on a storage error, the filters already charged
+    /// are refunded and the error is returned. Returns the charged filters.
+    fn commit_public_deduction(
+        &mut self,
+        request: &Q,
+    ) -> Result<Vec<FilterIdQ<Q>>, ERR> {
+        let loss = Self::public_loss(request);
+        let filter_ids = self.public_filter_ids(request);
+        self.initialize_filters(filter_ids.iter())?;
+
+        let mut charged_filters = vec![];
+        for filter_id in filter_ids {
+            match self.public_filters.try_consume(&filter_id, &loss) {
+                Ok(FilterStatus::Continue) => charged_filters.push(filter_id),
+                Ok(FilterStatus::OutOfBudget) => {}
+                Err(error) => {
+                    self.roll_back_public_deduction(request, charged_filters);
+                    return Err(error.into());
+                }
+            }
+        }
+        Ok(charged_filters)
+    }
+
+    /// Refunds the public loss of a request that failed to the filters it
+    /// was charged to. Refunds are best effort: budget that can't be
+    /// refunded stays consumed, so the public filters still bound the
+    /// private consumption.
+    fn roll_back_public_deduction(
+        &mut self,
+        request: &Q,
+        charged_filters: Vec<FilterIdQ<Q>>,
+    ) {
+        let loss = Self::public_loss(request);
+        for filter_id in charged_filters {
+            if self.public_filters.refund(&filter_id, &loss).is_err() {
+                warn!("Couldn't refund {loss} to public filter {filter_id:?}");
+            }
+        }
     }
 
     fn can_probably_allocate(&mut self, request: &Q) -> Result<bool, ERR> {
@@ -413,10 +1794,7 @@ where
         debug!("Request {} got report {:?}", request.request_id, report);
 
         // Keep the result for when the time is right.
-        let batched_report = BatchedReport {
-            request_id: request.request_id,
-            report,
-        };
+        let batched_reports = self.batched_reports(request, report);
 
         // If n_remaining_scheduling_attempts is 0, we will release the
         // report right away, at the end of the current call to
@@ -428,87 +1806,155 @@ where
         self.delayed_reports
             .entry(target_scheduling_interval)
             .or_default()
-            .push(batched_report);
+            .extend(batched_reports);
+    }
+
+    /// Reports of a request, one per querier.
+    fn batched_reports(
+        &self,
+        request: &BatchedRequest<Q>,
+        report: PdsReport<Q>,
+    ) -> Vec<BatchedReport<Q>> {
+        let computed_at = self.clock.now();
+        per_querier(&request.request, report)
+            .map(|(querier_uri, report)| BatchedReport {
+                request_id: request.request_id,
+                querier_uri,
+                registered_at: request.registered_at,
+                computed_at,
+                report,
+            })
+            .collect()
     }
 
     /// Stores allocated requests for delayed response. Returns a list of
     /// unallocated requests.
     fn try_allocate(
         &mut self,
-        requests: Vec<BatchedRequest<Q>>,
+        requests: Vec<RequestIndex>,
         allocate_final_attempts: bool,
-    ) -> Result<Vec<BatchedRequest<Q>>, ERR> {
+    ) -> Result<Vec<RequestIndex>, ERR> {
+        let passes_snapshot = self.dry_run_on_snapshot(&requests)?;
+
         // Go through requests one by one and try to allocate them.
         let mut unallocated_requests = vec![];
-        for request in requests {
-            if (allocate_final_attempts
-                && request.n_remaining_scheduling_attempts == 0)
-                || self.can_probably_allocate(&request.request)?
+        for (index, passes_snapshot) in
+            requests.into_iter().zip(passes_snapshot)
+        {
+            let is_final_attempt =
+                self.requests[index].n_remaining_scheduling_attempts == 0;
+            if (allocate_final_attempts && is_final_attempt)
+                || (passes_snapshot
+                    && self.with_request(index, |this, request| {
+                        this.can_probably_allocate(&request.request)
+                    })?)
             {
-                debug!(
-                    "Request {} can probably be allocated: {request:?}",
-                    request.request_id
-                );
-
-                // pre-initialize filters to unlock non-C filters
-                self.initialize_filters_for_request(&request.request)?;
-
-                // Compute the actual report. It might be null though.
-                let report = self.pds.compute_report(&request.request)?;
-
-                if !report.oob_filters.is_empty() {
-                    for filter_id in report.oob_filters.iter() {
-                        if let FilterId::SourceQuota(_, _) = filter_id {
-                            // SourceQuota should never block a request if we
-                            // have perfect upper
-                            // bounds for the public filters.
-                            panic!(
-                                "Request {} was not allocated: {:?}. Final attempt? {}",
-                                request.request_id, report.oob_filters, allocate_final_attempts
-                            );
-                        }
-                    }
-                }
-
-                self.update_allocation_statistics(&request.request)?;
+                // Allocated requests leave the arena with their report. A
+                // request that fails to be allocated stays in the arena, to
+                // be requeued.
+                let report = self.with_request(index, |this, request| {
+                    debug!(
+                        "Request {} can probably be allocated: {request:?}",
+                        request.request_id
+                    );
+                    this.allocate(request, allocate_final_attempts)
+                })?;
+                let request = self.requests.remove(index);
 
                 // Keep the result for when the time is right.
                 self.send_report_for_release(&request, report);
             } else {
-                unallocated_requests.push(request);
+                unallocated_requests.push(index);
             }
         }
 
         Ok(unallocated_requests)
     }
 
-    /// Browse the requests one by one, try to allocate them. If we allocate a
-    /// request, stop trying allocating and return the index of the allocated
-    /// request. Otherwise, return None. Either way, also return all the
-    /// unallocated requests.
-    fn try_allocate_one(
+    /// Dry-runs the requests over a snapshot of their public filters, in
+    /// parallel with the `parallel` feature. Public filters only get consumed
+    /// during a phase, so a request that is out of budget on the snapshot
+    /// stays out of budget for the rest of the phase, and only the other
+    /// requests need to be checked again before they are allocated. Errors
+    /// are left to that second check.
+    fn dry_run_on_snapshot(
         &mut self,
-        mut requests: Vec<BatchedRequest<Q>>,
-        allocate_final_attempts: bool,
-    ) -> Result<(Vec<BatchedRequest<Q>>, Option<usize>), ERR> {
-        let mut i = 0;
-        let mut unallocated_requests = vec![];
+        requests: &[RequestIndex],
+    ) -> Result<Vec<bool>, ERR> {
+        let mut dry_runs = vec![];
+        let mut snapshot = HashMap::new();
+        for index in requests {
+            let request = &self.requests[*index].request;
+            let loss = Self::public_loss(request);
+            let filter_ids = self.public_filter_ids(request);
+
+            // Filters are unlocked before the snapshot, so that allocations
+            // can't unlock them later in the phase.
+            self.initialize_filters(filter_ids.iter())?;
+            for filter_id in &filter_ids {
+                if !snapshot.contains_key(filter_id) {
+                    let filter =
+                        self.public_filters.get_filter_or_new(filter_id)?;
+                    snapshot.insert(filter_id.clone(), filter);
+                }
+            }
+            dry_runs.push((loss, filter_ids));
+        }
 
-        while !requests.is_empty() {
-            let request = requests.remove(0);
-            let unallocated_request =
-                self.try_allocate(vec![request], allocate_final_attempts)?;
-            if unallocated_request.is_empty() {
-                // We successfully allocated the request.
-                // Keep all the other requests as unallocated.
-                unallocated_requests.extend(requests);
-                return Ok((unallocated_requests, Some(i)));
+        Ok(par_map(&dry_runs, |(loss, filter_ids)| {
+            filter_ids.iter().all(|filter_id| {
+                !matches!(
+                    snapshot[filter_id].can_consume(loss),
+                    Ok(FilterStatus::OutOfBudget)
+                )
+            })
+        }))
+    }
+
+    /// Computes the report of a request that can be allocated, and deducts
+    /// its budget from the public filters. If the report fails, e.g. on a
+    /// storage error, neither the public nor the private filters are charged.
+    fn allocate(
+        &mut self,
+        request: &BatchedRequest<Q>,
+        allocate_final_attempts: bool,
+    ) -> Result<PdsReport<Q>, ERR> {
+        // pre-initialize filters to unlock non-C filters
+        self.initialize_filters_for_request(&request.request)?;
+
+        // Charge the public filters first, whatever the report. They only
+        // depend on public information, and are refunded if the report
+        // fails, so that they always bound the private consumption.
+        let charged_filters = self.commit_public_deduction(&request.request)?;
+
+        // Compute the actual report. It might be null though.
+        let report = match self.pds.compute_report(&request.request) {
+            Ok(report) => report,
+            Err(error) => {
+                self.roll_back_public_deduction(
+                    &request.request,
+                    charged_filters,
+                );
+                return Err(error);
             }
-            unallocated_requests.extend(unallocated_request);
-            i += 1;
+        };
+
+        // SourceQuota should never block a request if we have perfect upper
+        // bounds for the public filters. The bounds are broken, but the budget
+        // is consumed all the same, so the report is still delivered: the
+        // base PDS already dropped the epochs that were out of budget.
+        if report
+            .oob_filters
+            .iter()
+            .any(|filter_id| matches!(filter_id, FilterId::SourceQuota(..)))
+        {
+            warn!(
+                "Request {} was out of budget on a private SourceQuota filter: {:?}. Final attempt? {}",
+                request.request_id, report.oob_filters, allocate_final_attempts
+            );
         }
 
-        Ok((unallocated_requests, None))
+        Ok(report)
     }
 
     /// Modify the capacity of the impression-site quota for the given epoch.
@@ -543,9 +1989,16 @@ where
     }
 
     /// Release budget for the given epoch. This is a no-op when the epoch's
-    /// unlocked budget has reached the capacity.
+    /// unlocked budget has reached the capacity, or when the budget was
+    /// already released at this scheduling interval, e.g. before a failure.
     fn release_budget(&mut self, epoch: Q::EpochId) -> Result<(), ERR> {
+        if self.last_releases.get(&epoch)
+            == Some(&self.current_scheduling_interval)
+        {
+            return Ok(());
+        }
         let filter_id = FilterId::Global(epoch);
+        self.initialize_filters([&filter_id].into_iter())?;
 
         self.pds
             .core
@@ -554,6 +2007,10 @@ where
                 f.release(&self.eps_c_per_release)
             })?;
 
+        // If the public release fails, the public filter misses this release
+        // for good, which is safe: it still bounds the private consumption.
+        self.last_releases
+            .insert(epoch, self.current_scheduling_interval);
         self.public_filters.edit_filter_or_new(&filter_id, |f| {
             f.release(&self.eps_c_per_release)
         })?;
@@ -561,107 +2018,116 @@ where
         Ok(())
     }
 
-    /// Sort the requests. Start with the request that has the smallest
-    /// beneficiary, break ties by request budget.
+    /// Sort the requests. Requests that can be allocated right now come
+    /// first, starting with the highest scheduling rank, then the one that
+    /// has the smallest beneficiary and breaking ties by request budget. The
+    /// others follow by increasing shortfall, so that requests that barely
+    /// miss are tried before hopeless ones. See `BatchKey`.
     ///
     /// NOTE: this is just one possible heuristic.
     fn sort_batch(
         &mut self,
-        requests: Vec<BatchedRequest<Q>>,
-    ) -> Result<Vec<BatchedRequest<Q>>, ERR> {
-        let mut all_sources = HashSet::new();
-        for request in &requests {
-            let source_uris = &request.request.report_uris().source_uris;
-            all_sources.extend(source_uris);
+        requests: Vec<RequestIndex>,
+    ) -> Result<Vec<RequestIndex>, ERR> {
+        let (_, budget_per_source) = self.budget_per_source(&requests)?;
+
+        let mut weighted_requests = vec![];
+        for (position, index) in requests.into_iter().enumerate() {
+            let key = self.batch_key(index, position, &budget_per_source)?;
+            weighted_requests.push((key, index));
         }
-        debug!("Sources across all requests: {all_sources:?}");
+        weighted_requests.sort();
+
+        debug!(
+            "Requests and keys after sorting: {:?}",
+            weighted_requests
+                .iter()
+                .map(|(key, index)| (self.requests[*index].request_id, key))
+                .collect::<Vec<_>>()
+        );
+
+        Ok(weighted_requests
+            .into_iter()
+            .map(|(_, index)| index)
+            .collect())
+    }
 
+    /// Budget consumed by each source of the requests, as the maximum over
+    /// the epochs of the requests of its public SourceQuota filters. Also
+    /// returns these epochs.
+    #[allow(clippy::type_complexity)]
+    fn budget_per_source(
+        &mut self,
+        requests: &[RequestIndex],
+    ) -> Result<(HashSet<Q::EpochId>, HashMap<Q::Uri, FS::Budget>), ERR> {
+        let mut all_sources = HashSet::new();
         let mut all_epochs = HashSet::new();
-        for request in &requests {
-            let epoch_ids = request.request.epoch_ids();
-            all_epochs.extend(epoch_ids);
+        for index in requests {
+            let request = &self.requests[*index].request;
+            all_sources.extend(request.report_uris().source_uris.iter());
+            all_epochs.extend(request.epoch_ids());
         }
+        debug!("Sources across all requests: {all_sources:?}");
         debug!("Epochs across all requests: {all_epochs:?}");
 
-        let mut budget_per_source: HashMap<Q::Uri, FS::Budget> = HashMap::new();
-        for source in &all_sources {
-            let source = (*source).clone();
-            let mut source_total_budget: f64 = 0.0;
-            for epoch in &all_epochs {
-                let filter_id = FilterId::SourceQuota(*epoch, source.clone());
-
-                let filter =
-                    self.public_filters.get_filter_or_new(&filter_id)?;
-                let consumed_budget =
-                    filter.get_capacity()? - filter.remaining_budget()?;
-
-                source_total_budget = source_total_budget.max(consumed_budget);
-            }
-            budget_per_source.insert(source, source_total_budget);
+        let all_sources = all_sources.into_iter().cloned().collect::<Vec<_>>();
+        let mut budget_per_source = HashMap::new();
+        for source in all_sources {
+            let budget = self.source_budget(&source, &all_epochs)?;
+            budget_per_source.insert(source, budget);
         }
         debug!("Budget per source: {budget_per_source:?}");
+        Ok((all_epochs, budget_per_source))
+    }
 
-        let mut weighted_requests: Vec<(BatchedRequest<Q>, f64, f64)> = vec![];
-
-        // For each request, find the minimum source budget across all sources.
-        // So it r appears in both q1's list of requests and q2's list, since
-        // we'll go through q1's list first we don't need to even remember about
-        // q2.
-        for request in requests {
-            let mut min_source_budget = f64::MAX;
-            let source_uris = &request.request.report_uris().source_uris;
-
-            let NoiseScale::Laplace(noise_scale) =
-                request.request.noise_scale();
-            let requested_budget =
-                request.request.report_global_sensitivity() / noise_scale;
-
-            for source in source_uris.iter() {
-                let source_budget = *budget_per_source.get(source).unwrap();
-                if source_budget < min_source_budget {
-                    min_source_budget = source_budget;
-                }
-            }
-
-            weighted_requests.push((
-                request,
-                min_source_budget,
-                requested_budget,
-            ));
-        }
-
-        // Sort by weight.
-        weighted_requests.sort_by(|a, b| {
-            let (a_min_source_budget, a_request_budget) = (a.1, a.2);
-            let (b_min_source_budget, b_request_budget) = (b.1, b.2);
-
-            if a_min_source_budget < b_min_source_budget {
-                Less
-            } else if a_min_source_budget > b_min_source_budget {
-                Greater
-            } else {
-                // If the minimum source budget is the same, sort by request
-                // budget
-                if a_request_budget <= b_request_budget {
-                    Less
-                } else {
-                    Greater
-                }
-            }
-        });
-
-        debug!(
-            "Requests and budgets after sorting: {:?}",
-            weighted_requests
-                .iter()
-                .map(|(r, b, c)| (r.request_id, b, c))
-                .collect::<Vec<_>>()
-        );
+    /// Maximum budget consumed by the public SourceQuota filters of `source`
+    /// over `epochs`.
+    fn source_budget(
+        &mut self,
+        source: &Q::Uri,
+        epochs: &HashSet<Q::EpochId>,
+    ) -> Result<FS::Budget, ERR> {
+        let mut source_total_budget = FS::Budget::zero();
+        for epoch in epochs {
+            let filter_id = FilterId::SourceQuota(*epoch, source.clone());
+            let filter = self.public_filters.get_filter_or_new(&filter_id)?;
+            source_total_budget =
+                source_total_budget.max_budget(&filter.consumed()?);
+        }
+        Ok(source_total_budget)
+    }
 
-        let sorted_requests =
-            weighted_requests.into_iter().map(|(r, _, _)| r).collect();
+    /// Current sort key of the request at `index`, that was at `position` in
+    /// the batch.
+    fn batch_key(
+        &mut self,
+        index: RequestIndex,
+        position: usize,
+        budget_per_source: &HashMap<Q::Uri, FS::Budget>,
+    ) -> Result<BatchKey, ERR> {
+        let request = &self.requests[index];
+        let rank = request.scheduling_rank();
+
+        // The smallest beneficiary is the source with the least consumed
+        // budget among the sources of the request.
+        let mut min_source_budget = FS::Budget::infinity();
+        for source in &request.request.report_uris().source_uris {
+            min_source_budget =
+                min_source_budget.min_budget(&budget_per_source[source]);
+        }
+        let requested_budget = Self::public_loss(&request.request);
+
+        let shortfall = self.with_request(index, |this, request| {
+            this.request_shortfall(&request.request)
+        })?;
 
-        Ok(sorted_requests)
+        Ok(BatchKey {
+            shortfall,
+            rank,
+            min_source_budget,
+            requested_budget,
+            position,
+        })
     }
 
     /// Given a request, calculate the list of filters that will be deducted
@@ -676,6 +2142,9 @@ where
         // We (mis-)use PDS's filters_to_consume() method to get a list of
         // filters that will be deducted for this request.
         for epoch_id in request.epoch_ids() {
+            if self.pds.core.is_request_epoch_pruned(uris, epoch_id) {
+                continue;
+            }
             let mut source_losses = HashMap::new();
             for source in &uris.source_uris {
                 source_losses.insert(source.clone(), 0.0);
@@ -693,7 +2162,8 @@ where
     }
 
     /// Given a list of filter IDs, initialize them in the filter storage,
-    /// such that non-global filters are unlocked and act as regular filters.
+    /// such that non-global filters are unlocked and act as regular filters,
+    /// and new global filters are locked until their budget is released.
     fn initialize_filters<'f, FID>(
         &mut self,
         filters: impl Iterator<Item = FID>,
@@ -714,18 +2184,19 @@ where
             // As such, they act as regular non-release filters.
             let should_unlock = !matches!(filter_id, FilterId::Global(_));
 
-            // if we don't need to unlock, we don't need to do anything
-            if !should_unlock {
-                continue;
-            }
-
             for filter_storage in
                 [&mut self.pds.core.filter_storage, &mut self.public_filters]
             {
-                filter_storage.edit_filter_or_new(filter_id, |f| {
-                    // unlock the filter so it acts as a regular filter
-                    f.release(&f64::INFINITY)
-                })?;
+                if should_unlock {
+                    filter_storage.edit_filter_or_new(filter_id, |f| {
+                        // unlock the filter so it acts as a regular filter
+                        f.release(&PureDPBudget::infinity())
+                    })?;
+                } else if filter_storage.get_filter(filter_id)?.is_none() {
+                    // Some filters start fully unlocked, e.g. `Unlockable`.
+                    filter_storage
+                        .edit_filter_or_new(filter_id, |f| f.lock())?;
+                }
             }
         }
 
@@ -733,149 +2204,1053 @@ where
     }
 }
 
+/// Copies of a report for each querier of its request. The base PDS charges
+/// all the queriers of a request at once, so they all get the same report.
+fn per_querier<'a, Q: EpochReportRequest>(
+    request: &'a Q,
+    report: PdsReport<Q>,
+) -> impl Iterator<Item = (Q::Uri, PdsReport<Q>)> + 'a {
+    request
+        .report_uris()
+        .querier_uris
+        .iter()
+        .map(move |querier_uri| (querier_uri.clone(), report.clone()))
+}
+
 #[cfg(test)]
 mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
     use log::info;
 
     use super::*;
     use crate::{
         budget::{
             hashmap_filter_storage::HashMapFilterStorage,
-            release_filter::PureDPBudgetReleaseFilter,
+            release_filter::{
+                PureDPBudgetReleaseFilter, UnlockablePureDPBudgetFilter,
+            },
         },
         events::{
             hashmap_event_storage::HashMapEventStorage,
             ppa_event::PpaEvent,
             traits::{Event, EventUris},
         },
+        pds::private_data_service::ReportStatus,
         queries::{
+            builder::PpaHistogramRequestBuilder,
             ppa_histogram::{
-                PpaHistogramConfig, PpaHistogramRequest,
+                FilterDataPredicate, PpaHistogramConfig, PpaHistogramRequest,
                 PpaRelevantEventSelector, RequestedBuckets,
             },
             traits::ReportRequestUris,
         },
-        util::tests::init_default_logging,
+        util::{clock::MockClock, tests::init_default_logging},
     };
 
-    fn collect_request_ids(
-        requests: &[BatchedRequest<PpaHistogramRequest>],
-    ) -> Vec<u64> {
-        requests.iter().map(|r| r.request_id).collect::<Vec<_>>()
-    }
+    fn collect_report_ids(
+        reports: &[BatchedReport<PpaHistogramRequest>],
+    ) -> Vec<u64> {
+        reports.iter().map(|r| r.request_id).collect::<Vec<_>>()
+    }
+
+    fn event_storage_with_events<E: Event>(
+        events: Vec<E>,
+    ) -> HashMapEventStorage<E> {
+        let mut event_storage = HashMapEventStorage::new();
+        for event in events {
+            event_storage.add_event(event).unwrap();
+        }
+        event_storage
+    }
+
+    #[test]
+    fn schedule_one_batch() -> Result<()> {
+        init_default_logging();
+
+        let capacities = StaticCapacities::new(10.0, 5.0, 10.0, 4.0);
+
+        let event1 = PpaEvent {
+            id: 1,
+            timestamp: 0,
+            epoch_number: 1,
+            histogram_index: 0,
+            uris: EventUris::mock(),
+            filter_data: 1,
+            priority: 0,
+        };
+        let event_storage = event_storage_with_events(vec![event1]);
+
+        let filter_storage: HashMapFilterStorage<PureDPBudgetReleaseFilter, _> =
+            HashMapFilterStorage::new(capacities)?;
+        let pds: PrivateDataService<_, _, _, anyhow::Error> =
+            PrivateDataService::new(filter_storage, event_storage);
+        let mut batch_pds = BatchPrivateDataService::new(pds, 2)?;
+
+        let report_uris = ReportRequestUris::mock();
+
+        let always_relevant_request = |requested_epsilon| {
+            PpaHistogramRequestBuilder::new()
+                .epoch_range(1, 1)?
+                .value(1.0, 1.0, requested_epsilon)?
+                .uris(report_uris.clone())?
+                .selector(
+                    FilterDataPredicate::Any,
+                    5,
+                    RequestedBuckets::AllBuckets,
+                )
+                .map(|builder| builder.build())
+        };
+
+        // Request that will be answered in the first scheduling attempt.
+        batch_pds.register_report_request(BatchedRequest::new(
+            1,
+            1,
+            always_relevant_request(1.1)?,
+        ))?;
+
+        // Another request with one scheduling attempt. But it doesn't go
+        // through the online phase because of SourceQuota, instead it has to
+        // wait until the batch phase for the quotas to be disabled.
+        batch_pds.register_report_request(BatchedRequest::new(
+            2,
+            1,
+            always_relevant_request(1.2)?,
+        ))?;
+
+        // A request that will try two scheduling attempts. It requests too much
+        // so should wait for more budget to be released.
+        batch_pds.register_report_request(BatchedRequest::new(
+            3,
+            2,
+            always_relevant_request(1.3)?,
+        ))?;
+
+        let reports = batch_pds.schedule_batch()?;
+        assert_eq!(reports.len(), 2);
+
+        debug!("Reports: {reports:?}");
+
+        for report in reports {
+            assert!(
+                report.report.oob_filters.is_empty(),
+                "Report should not have any OOB filters. Got: {:?}",
+                report.report.oob_filters
+            );
+        }
+
+        let reports = batch_pds.schedule_batch()?;
+        assert_eq!(reports.len(), 1);
+        debug!("Reports again: {reports:?}");
+
+        assert!(
+            reports[0].report.oob_filters.is_empty(),
+            "Report should not have any OOB filters. Got: {:?}",
+            reports[0].report.oob_filters
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn reports_are_timestamped() -> Result<()> {
+        let capacities = StaticCapacities::new(10.0, 5.0, 10.0, 4.0);
+        let filter_storage: HashMapFilterStorage<PureDPBudgetReleaseFilter, _> =
+            HashMapFilterStorage::new(capacities)?;
+        let pds: PrivateDataService<_, _, _, anyhow::Error> =
+            PrivateDataService::new(filter_storage, HashMapEventStorage::new());
+
+        let clock = MockClock::new(100);
+        let mut batch_pds =
+            BatchPrivateDataService::new(pds, 2)?.with_clock(clock.clone());
+
+        let request = PpaHistogramRequestBuilder::new()
+            .epoch_range(1, 1)?
+            .value(1.0, 1.0, 1.0)?
+            .uris(ReportRequestUris::mock())?
+            .selector(
+                FilterDataPredicate::Any,
+                5,
+                RequestedBuckets::AllBuckets,
+            )?
+            .build();
+        batch_pds
+            .register_report_request(BatchedRequest::new(1, 1, request))?;
+
+        clock.advance(60);
+        let reports = batch_pds.schedule_batch()?;
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].registered_at, 100);
+        assert_eq!(reports[0].computed_at, 160);
+
+        Ok(())
+    }
+
+    #[test]
+    fn real_time_requests() -> Result<()> {
+        let capacities = StaticCapacities::new(10.0, 5.0, 10.0, 4.0);
+        let event1 = PpaEvent {
+            id: 1,
+            timestamp: 0,
+            epoch_number: 1,
+            histogram_index: 0,
+            uris: EventUris::mock(),
+            filter_data: 1,
+            priority: 0,
+        };
+        let event_storage = event_storage_with_events(vec![event1]);
+        let filter_storage: HashMapFilterStorage<PureDPBudgetReleaseFilter, _> =
+            HashMapFilterStorage::new(capacities)?;
+        let pds: PrivateDataService<_, _, _, anyhow::Error> =
+            PrivateDataService::new(filter_storage, event_storage);
+        let mut batch_pds = BatchPrivateDataService::new(pds, 2)?;
+
+        let request = |requested_epsilon| {
+            PpaHistogramRequestBuilder::new()
+                .epoch_range(1, 1)?
+                .value(1.0, 1.0, requested_epsilon)?
+                .uris(ReportRequestUris::mock())?
+                .selector(
+                    FilterDataPredicate::Any,
+                    5,
+                    RequestedBuckets::AllBuckets,
+                )
+                .map(|builder| builder.build())
+        };
+
+        // No Global budget has been released yet.
+        let report = batch_pds
+            .register_report_request(BatchedRequest::new(1, 0, request(1.0)?))?
+            .remove(0);
+        assert_eq!(report.request_id, 1);
+        assert!(report.report.oob_filters.contains(&FilterId::Global(1)));
+        assert_eq!(report.report.filtered_report.bin_values, HashMap::new());
+        assert_eq!(
+            report.report.retry_advice,
+            Some(RetryAdvice::ReleasePending)
+        );
+
+        // Release half of the Global budget.
+        assert!(batch_pds.schedule_batch()?.is_empty());
+
+        // Real-time requests are answered right away and are never batched.
+        let report = batch_pds
+            .register_report_request(BatchedRequest::new(2, 0, request(1.0)?))?
+            .remove(0);
+        assert!(report.report.oob_filters.is_empty());
+        assert_eq!(report.report.retry_advice, None);
+        assert_eq!(report.report.filtered_report.bin_values[&0], 1.0);
+        assert!(batch_pds.new_pending_requests.is_empty());
+
+        // Real-time requests are still subject to the SourceQuota, which is
+        // only disabled in batch phases.
+        let report = batch_pds
+            .register_report_request(BatchedRequest::new(3, 0, request(3.5)?))?
+            .remove(0);
+        assert!(report.report.oob_filters.contains(&FilterId::SourceQuota(
+            1,
+            ReportRequestUris::mock().source_uris[0].clone()
+        )));
+        assert_eq!(report.report.filtered_report.bin_values, HashMap::new());
+
+        // Batched requests are not answered right away.
+        let report = batch_pds.register_report_request(BatchedRequest::new(
+            4,
+            1,
+            request(1.0)?,
+        ))?;
+        assert!(report.is_empty());
+        assert_eq!(collect_report_ids(&batch_pds.schedule_batch()?), vec![4]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn unlockable_filters() -> Result<()> {
+        let capacities = StaticCapacities::new(10.0, 5.0, 10.0, 4.0);
+        let event = PpaEvent {
+            id: 1,
+            timestamp: 0,
+            epoch_number: 1,
+            histogram_index: 0,
+            uris: EventUris::mock(),
+            filter_data: 1,
+            priority: 0,
+        };
+        let request = || {
+            PpaHistogramRequestBuilder::new()
+                .epoch_range(1, 1)?
+                .value(1.0, 1.0, 1.0)?
+                .uris(ReportRequestUris::mock())?
+                .selector(
+                    FilterDataPredicate::Any,
+                    5,
+                    RequestedBuckets::AllBuckets,
+                )
+                .map(|builder| builder.build())
+        };
+
+        // A regular PDS can use the filters as is, they start unlocked.
+        let filter_storage: HashMapFilterStorage<
+            UnlockablePureDPBudgetFilter,
+            _,
+        > = HashMapFilterStorage::new(capacities.clone())?;
+        let mut pds: PrivateDataService<_, _, _, anyhow::Error> =
+            PrivateDataService::new(
+                filter_storage,
+                event_storage_with_events(vec![event.clone()]),
+            );
+        let report = pds.compute_report(&request()?)?;
+        assert_eq!(report.filtered_report.bin_values[&0], 1.0);
+
+        // The batch scheduler locks the Global filters of the same filter
+        // type until their budget is released.
+        let filter_storage: HashMapFilterStorage<
+            UnlockablePureDPBudgetFilter,
+            _,
+        > = HashMapFilterStorage::new(capacities)?;
+        let pds: PrivateDataService<_, _, _, anyhow::Error> =
+            PrivateDataService::new(
+                filter_storage,
+                event_storage_with_events(vec![event]),
+            );
+        let mut batch_pds = BatchPrivateDataService::new(pds, 2)?;
+        let report = batch_pds
+            .register_report_request(BatchedRequest::new(1, 0, request()?))?
+            .remove(0);
+        assert!(report.report.oob_filters.contains(&FilterId::Global(1)));
+
+        // Release half of the Global budget.
+        assert!(batch_pds.schedule_batch()?.is_empty());
+        let report = batch_pds
+            .register_report_request(BatchedRequest::new(2, 0, request()?))?
+            .remove(0);
+        assert!(report.report.oob_filters.is_empty());
+        assert_eq!(report.report.filtered_report.bin_values[&0], 1.0);
+        let global_filter = batch_pds
+            .pds
+            .core
+            .filter_storage
+            .get_filter(&FilterId::Global(1))?
+            .unwrap();
+        assert_eq!(global_filter.unlocked_remaining()?, 1.5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn multi_querier_requests() -> Result<()> {
+        let capacities = StaticCapacities::new(10.0, 5.0, 10.0, 4.0);
+        let event = PpaEvent {
+            id: 1,
+            timestamp: 0,
+            epoch_number: 1,
+            histogram_index: 0,
+            uris: EventUris::mock(),
+            filter_data: 1,
+            priority: 0,
+        };
+        let filter_storage: HashMapFilterStorage<PureDPBudgetReleaseFilter, _> =
+            HashMapFilterStorage::new(capacities)?;
+        let pds: PrivateDataService<_, _, _, anyhow::Error> =
+            PrivateDataService::new(
+                filter_storage,
+                event_storage_with_events(vec![event]),
+            );
+        let mut batch_pds = BatchPrivateDataService::new(pds, 1)?;
+
+        let querier_uris = vec!["adtech.com".to_string(), "shoes.com".into()];
+        let request = |request_id, querier_uris| {
+            let request = PpaHistogramRequestBuilder::new()
+                .epoch_range(1, 1)?
+                .value(1.0, 1.0, 1.0)?
+                .uris(ReportRequestUris {
+                    querier_uris,
+                    ..ReportRequestUris::mock()
+                })?
+                .selector(
+                    FilterDataPredicate::Any,
+                    5,
+                    RequestedBuckets::AllBuckets,
+                )?
+                .build();
+            Ok::<_, anyhow::Error>(BatchedRequest::new(request_id, 1, request))
+        };
+
+        // Requests without queriers would never deliver their report. The
+        // builder rejects them, and so does the PDS for requests built from a
+        // config.
+        assert!(request(1, vec![]).is_err());
+        let no_querier = PpaHistogramRequest::from_config(
+            &PpaHistogramConfig {
+                start_epoch: 1,
+                end_epoch: 1,
+                epochs: None,
+                value_policy: None,
+                lookback: None,
+                epsilon_grid: None,
+                attributable_value: 1.0,
+                max_attributable_value: 1.0,
+                requested_epsilon: 1.0,
+                histogram_size: 5,
+            },
+            PpaRelevantEventSelector {
+                report_request_uris: ReportRequestUris {
+                    querier_uris: vec![],
+                    ..ReportRequestUris::mock()
+                },
+                is_matching_event: FilterDataPredicate::Any,
+                requested_buckets: RequestedBuckets::AllBuckets,
+                lookback: None,
+            },
+        )?;
+        assert!(batch_pds
+            .register_report_request(BatchedRequest::new(1, 1, no_querier))
+            .is_err());
+
+        // Each querier gets its own copy of the report.
+        assert!(batch_pds
+            .register_report_request(request(2, querier_uris.clone())?)?
+            .is_empty());
+        let mut reports = batch_pds.schedule_batch()?;
+        reports.sort_by(|a, b| a.querier_uri.cmp(&b.querier_uri));
+        assert_eq!(collect_report_ids(&reports), [2, 2]);
+        assert_eq!(
+            reports
+                .iter()
+                .map(|report| report.querier_uri.clone())
+                .collect::<Vec<_>>(),
+            querier_uris
+        );
+        assert!(!reports[0].report.filtered_report.bin_values.is_empty());
+        assert_eq!(reports[0].report, reports[1].report);
+
+        // The public PerQuerier filter of each querier is charged.
+        for querier_uri in querier_uris {
+            let filter = batch_pds
+                .public_filters
+                .get_filter_or_new(&FilterId::PerQuerier(1, querier_uri))?;
+            assert_eq!(filter.consumed()?, 1.0);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn request_priorities() -> Result<()> {
+        let capacities = StaticCapacities::new(10.0, 4.0, 10.0, 10.0);
+        let event = PpaEvent {
+            id: 1,
+            timestamp: 0,
+            epoch_number: 1,
+            histogram_index: 0,
+            uris: EventUris::mock(),
+            filter_data: 1,
+            priority: 0,
+        };
+        let filter_storage: HashMapFilterStorage<PureDPBudgetReleaseFilter, _> =
+            HashMapFilterStorage::new(capacities)?;
+        let pds: PrivateDataService<_, _, _, anyhow::Error> =
+            PrivateDataService::new(
+                filter_storage,
+                event_storage_with_events(vec![event]),
+            );
+
+        // Each interval releases enough Global budget for one request.
+        let mut batch_pds = BatchPrivateDataService::new(pds, 4)?;
+        let request = |request_id, n_scheduling_attempts, priority| {
+            let request = PpaHistogramRequestBuilder::new()
+                .epoch_range(1, 1)?
+                .value(1.0, 1.0, 1.0)?
+                .uris(ReportRequestUris::mock())?
+                .selector(
+                    FilterDataPredicate::Any,
+                    5,
+                    RequestedBuckets::AllBuckets,
+                )?
+                .build();
+            Ok::<_, anyhow::Error>(
+                BatchedRequest::new(request_id, n_scheduling_attempts, request)
+                    .with_priority(priority),
+            )
+        };
+
+        // High-priority requests go first, even if they arrived later.
+        batch_pds.register_report_request(request(
+            1,
+            2,
+            RequestPriority::Low,
+        )?)?;
+        batch_pds.register_report_request(request(
+            2,
+            5,
+            RequestPriority::High,
+        )?)?;
+        batch_pds.register_report_request(request(
+            3,
+            5,
+            RequestPriority::High,
+        )?)?;
+        assert!(batch_pds.schedule_batch()?.is_empty());
+        assert_eq!(
+            batch_pds.requests.request_ids(&batch_pds.batched_requests),
+            [3, 1]
+        );
+
+        // The low-priority request is only inverted until its final attempt,
+        // where it goes before the remaining high-priority request.
+        let reports = batch_pds.schedule_batch()?;
+        assert_eq!(collect_report_ids(&reports), [1]);
+        assert!(reports[0].report.oob_filters.is_empty());
+        assert!(!reports[0].report.filtered_report.bin_values.is_empty());
+        assert_eq!(
+            batch_pds.requests.request_ids(&batch_pds.batched_requests),
+            [3]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn canceled_requests() -> Result<()> {
+        let capacities = StaticCapacities::new(10.0, 5.0, 10.0, 4.0);
+        let filter_storage: HashMapFilterStorage<PureDPBudgetReleaseFilter, _> =
+            HashMapFilterStorage::new(capacities)?;
+        let pds: PrivateDataService<_, _, _, anyhow::Error> =
+            PrivateDataService::new(filter_storage, HashMapEventStorage::new());
+        let mut batch_pds = BatchPrivateDataService::new(pds, 2)?;
+
+        let request = |request_id, requested_epsilon| -> Result<_> {
+            Ok(BatchedRequest::new(
+                request_id,
+                2,
+                PpaHistogramRequestBuilder::new()
+                    .epoch_range(1, 1)?
+                    .value(1.0, 1.0, requested_epsilon)?
+                    .uris(ReportRequestUris::mock())?
+                    .selector(
+                        FilterDataPredicate::Any,
+                        5,
+                        RequestedBuckets::AllBuckets,
+                    )?
+                    .build(),
+            ))
+        };
+
+        batch_pds.register_report_request(request(1, 1.0)?)?;
+        batch_pds.register_report_request(request(2, 1.0)?)?;
+        batch_pds.register_report_request(request(3, 3.0)?)?;
+
+        // Pending requests are withdrawn before consuming any budget.
+        assert_eq!(
+            batch_pds.cancel_report_request(2),
+            CancellationAck::Withdrawn
+        );
+        assert_eq!(
+            batch_pds
+                .requests
+                .request_ids(&batch_pds.new_pending_requests),
+            [1, 3]
+        );
+
+        // Request 1 is allocated but its report is delayed, and request 3
+        // waits for more Global budget to be released.
+        assert!(batch_pds.schedule_batch()?.is_empty());
+        assert_eq!(
+            batch_pds.requests.request_ids(&batch_pds.batched_requests),
+            [3]
+        );
+        let global_filter = FilterId::Global(1);
+        let global_consumed = batch_pds
+            .public_filters
+            .get_filter_or_new(&global_filter)?
+            .consumed()?;
+        assert_eq!(global_consumed, 1.0);
+
+        // Batched requests can be withdrawn too.
+        assert_eq!(
+            batch_pds.cancel_report_request(3),
+            CancellationAck::Withdrawn
+        );
+        assert!(batch_pds.batched_requests.is_empty());
+
+        // The budget of allocated requests is spent, but their report is
+        // never released.
+        assert_eq!(
+            batch_pds.cancel_report_request(1),
+            CancellationAck::ReportSuppressed
+        );
+        assert!(batch_pds.schedule_batch()?.is_empty());
+
+        assert_eq!(
+            batch_pds.cancel_report_request(1),
+            CancellationAck::NotFound
+        );
 
-    fn collect_report_ids(
-        reports: &[BatchedReport<PpaHistogramRequest>],
-    ) -> Vec<u64> {
-        reports.iter().map(|r| r.request_id).collect::<Vec<_>>()
+        Ok(())
     }
 
-    fn event_storage_with_events<E: Event>(
-        events: Vec<E>,
-    ) -> HashMapEventStorage<E> {
-        let mut event_storage = HashMapEventStorage::new();
-        for event in events {
-            event_storage.add_event(event).unwrap();
-        }
-        event_storage
+    #[test]
+    fn scheduler_stats() -> Result<()> {
+        let capacities = StaticCapacities::new(10.0, 5.0, 10.0, 4.0);
+        let filter_storage: HashMapFilterStorage<PureDPBudgetReleaseFilter, _> =
+            HashMapFilterStorage::new(capacities)?;
+        let pds: PrivateDataService<_, _, _, anyhow::Error> =
+            PrivateDataService::new(filter_storage, HashMapEventStorage::new());
+
+        let phases = Rc::new(RefCell::new(vec![]));
+        let recorded = phases.clone();
+        let mut batch_pds = BatchPrivateDataService::new(pds, 2)?
+            .with_phase_callback(move |phase, stats| {
+                recorded.borrow_mut().push((phase, stats.clone()))
+            });
+
+        let request = |request_id, requested_epsilon| -> Result<_> {
+            Ok(BatchedRequest::new(
+                request_id,
+                2,
+                PpaHistogramRequestBuilder::new()
+                    .epoch_range(1, 1)?
+                    .value(1.0, 1.0, requested_epsilon)?
+                    .uris(ReportRequestUris::mock())?
+                    .selector(
+                        FilterDataPredicate::Any,
+                        5,
+                        RequestedBuckets::AllBuckets,
+                    )?
+                    .build(),
+            ))
+        };
+        batch_pds.register_report_request(request(1, 1.0)?)?;
+        batch_pds.register_report_request(request(2, 3.0)?)?;
+
+        let stats = batch_pds.stats()?;
+        assert_eq!(stats.n_pending_requests, 2);
+        assert_eq!(stats.epochs[&1].global_unlocked, 0.0);
+
+        // Request 1 is allocated in the online phase, request 2 waits for
+        // more Global budget.
+        batch_pds.schedule_batch()?;
+        let phases = RefCell::borrow(&phases);
+        assert_eq!(
+            phases.iter().map(|(phase, _)| *phase).collect::<Vec<_>>(),
+            [
+                BatchPhase::Initialization,
+                BatchPhase::Online,
+                BatchPhase::Batch
+            ]
+        );
+
+        let (_, initialization) = &phases[0];
+        assert_eq!(initialization.scheduling_interval, 0);
+        assert_eq!(initialization.n_pending_requests, 2);
+        assert_eq!(initialization.n_delayed_reports, 0);
+        assert_eq!(initialization.epochs[&1].global_unlocked, 2.5);
+        assert_eq!(initialization.epochs[&1].global_consumed, 0.0);
+
+        let (_, online) = &phases[1];
+        assert_eq!(online.n_pending_requests, 0);
+        assert_eq!(online.n_batched_requests, 1);
+        assert_eq!(online.n_delayed_reports, 1);
+        assert_eq!(online.epochs[&1].global_consumed, 1.0);
+        let source = &ReportRequestUris::mock().source_uris[0];
+        assert_eq!(online.epochs[&1].source_consumed[source], 1.0);
+
+        let (_, batch) = &phases[2];
+        assert_eq!(batch.n_batched_requests, 1);
+        assert_eq!(batch_pds.stats()?.n_batched_requests, 1);
+
+        // Snapshots can be exported along with the experiment results.
+        let json = serde_json::to_value(batch)?;
+        assert_eq!(json["epochs"]["1"]["global_unlocked"], 2.5);
+
+        Ok(())
     }
 
     #[test]
-    fn schedule_one_batch() -> Result<()> {
-        init_default_logging();
-
+    fn parked_requests() -> Result<()> {
         let capacities = StaticCapacities::new(10.0, 5.0, 10.0, 4.0);
+        let filter_storage: HashMapFilterStorage<PureDPBudgetReleaseFilter, _> =
+            HashMapFilterStorage::new(capacities)?;
+        let mut pds: PrivateDataService<_, _, _, anyhow::Error> =
+            PrivateDataService::new(filter_storage, HashMapEventStorage::new());
+        pds.set_current_epoch(1);
+        let mut batch_pds = BatchPrivateDataService::new(pds, 2)?;
 
-        let event1 = PpaEvent {
+        // The conversion is registered during epoch 1, but its window
+        // extends to epoch 2.
+        let request = |n_scheduling_attempts| -> Result<_> {
+            Ok(BatchedRequest::new(
+                1,
+                n_scheduling_attempts,
+                PpaHistogramRequestBuilder::new()
+                    .epoch_range(1, 2)?
+                    .value(1.0, 1.0, 1.0)?
+                    .uris(ReportRequestUris::mock())?
+                    .selector(
+                        FilterDataPredicate::Any,
+                        5,
+                        RequestedBuckets::AllBuckets,
+                    )?
+                    .build(),
+            ))
+        };
+
+        // Real-time requests can't be parked.
+        assert!(batch_pds.register_report_request(request(0)?).is_err());
+
+        assert!(batch_pds.register_report_request(request(1)?)?.is_empty());
+        assert_eq!(batch_pds.parked_requests.len(), 1);
+
+        // The window is still open, so the request doesn't use its attempt
+        // and its epochs are not tracked yet.
+        batch_pds.pds.set_current_epoch(2);
+        assert!(batch_pds.schedule_batch()?.is_empty());
+        assert_eq!(batch_pds.parked_requests.len(), 1);
+        assert!(batch_pds.sources_per_epoch.is_empty());
+
+        // An event arrives in the future epoch of the window.
+        batch_pds.pds.register_event(PpaEvent {
             id: 1,
             timestamp: 0,
-            epoch_number: 1,
-            histogram_index: 0,
+            epoch_number: 2,
+            histogram_index: 3,
             uris: EventUris::mock(),
             filter_data: 1,
-        };
-        let event_storage = event_storage_with_events(vec![event1]);
+            priority: 0,
+        })?;
+
+        // Once the window closes, the request goes through its batch.
+        batch_pds.pds.set_current_epoch(3);
+        let reports = batch_pds.schedule_batch()?;
+        assert!(batch_pds.parked_requests.is_empty());
+        assert_eq!(collect_report_ids(&reports), vec![1]);
+        assert!(reports[0].report.oob_filters.is_empty());
+        assert_eq!(reports[0].report.filtered_report.bin_values[&3], 1.0);
+
+        Ok(())
+    }
 
+    #[test]
+    fn retired_epochs() -> Result<()> {
+        let capacities = StaticCapacities::new(10.0, 4.0, 10.0, 4.0);
         let filter_storage: HashMapFilterStorage<PureDPBudgetReleaseFilter, _> =
             HashMapFilterStorage::new(capacities)?;
         let pds: PrivateDataService<_, _, _, anyhow::Error> =
-            PrivateDataService::new(filter_storage, event_storage);
-        let mut batch_pds = BatchPrivateDataService::new(pds, 2)?;
-
-        let mut request_config = PpaHistogramConfig {
-            start_epoch: 1,
-            end_epoch: 1,
-            attributable_value: 1.0,
-            max_attributable_value: 1.0,
-            requested_epsilon: 1.1,
-            histogram_size: 5,
+            PrivateDataService::new(filter_storage, HashMapEventStorage::new());
+        let mut batch_pds =
+            BatchPrivateDataService::new(pds, 4)?.with_epoch_lifetime(2);
+
+        let request = |epoch| {
+            PpaHistogramRequestBuilder::new()
+                .epoch_range(epoch, epoch)?
+                .value(1.0, 1.0, 0.1)?
+                .uris(ReportRequestUris::mock())?
+                .selector(
+                    FilterDataPredicate::Any,
+                    5,
+                    RequestedBuckets::AllBuckets,
+                )
+                .map(|builder| builder.build())
         };
 
-        let report_uris = ReportRequestUris::mock();
+        // A new epoch is requested at each interval, but only the last two
+        // are still active. Without an epoch schedule, the filters of the
+        // retired epochs are pruned, except for the last one.
+        for epoch in 1..=6 {
+            batch_pds.register_report_request(BatchedRequest::new(
+                epoch,
+                1,
+                request(epoch)?,
+            ))?;
+            batch_pds.schedule_batch()?;
+            assert!(batch_pds.sources_per_epoch.len() <= 2);
+            assert!(batch_pds.epoch_first_intervals.len() <= 2);
+        }
+        assert_eq!(batch_pds.retired_through, Some(4));
+        assert_eq!(batch_pds.pds.core.pruned_before, Some(4));
 
-        let always_relevant_selector = || PpaRelevantEventSelector {
-            report_request_uris: report_uris.clone(),
-            is_matching_event: Box::new(|_: u64| true),
-            requested_buckets: RequestedBuckets::AllBuckets,
-        };
+        // Retired epochs got two releases.
+        let filter = batch_pds
+            .pds
+            .core
+            .filter_storage
+            .get_filter(&FilterId::Global(4))?
+            .unwrap();
+        assert_eq!(filter.unlocked, 2.0);
 
-        // Request that will be answered in the first scheduling attempt.
+        // Retired epochs are not tracked anymore, even if they are requested
+        // again, and the filters of pruned epochs are not recreated.
         batch_pds.register_report_request(BatchedRequest::new(
+            7,
             1,
-            1,
-            PpaHistogramRequest::new(
-                &request_config,
-                always_relevant_selector(),
-            )?,
+            request(1)?,
         ))?;
+        batch_pds.schedule_batch()?;
+        assert!(!batch_pds.sources_per_epoch.contains_key(&1));
+        assert_eq!(batch_pds.pds.core.pruned_before, Some(5));
+        for epoch in 1..=6 {
+            for filters in [
+                &mut batch_pds.pds.core.filter_storage,
+                &mut batch_pds.public_filters,
+            ] {
+                let filter = filters.get_filter(&FilterId::Global(epoch))?;
+                assert_eq!(filter.is_some(), epoch > 4, "epoch {epoch}");
+            }
+        }
 
-        // Another request with one scheduling attempt. But it doesn't go
-        // through the online phase because of SourceQuota, instead it has to
-        // wait until the batch phase for the quotas to be disabled.
-        request_config.requested_epsilon = 1.2;
-        batch_pds.register_report_request(BatchedRequest::new(
-            2,
-            1,
-            PpaHistogramRequest::new(
-                &request_config,
-                always_relevant_selector(),
-            )?,
-        ))?;
+        Ok(())
+    }
 
-        // A request that will try two scheduling attempts. It requests too much
-        // so should wait for more budget to be released.
-        request_config.requested_epsilon = 1.3;
-        batch_pds.register_report_request(BatchedRequest::new(
-            3,
-            2,
-            PpaHistogramRequest::new(
-                &request_config,
-                always_relevant_selector(),
-            )?,
-        ))?;
+    #[test]
+    fn dry_run_on_snapshot() -> Result<()> {
+        let capacities = StaticCapacities::new(10.0, 1.5, 10.0, 10.0);
+        let filter_storage: HashMapFilterStorage<PureDPBudgetReleaseFilter, _> =
+            HashMapFilterStorage::new(capacities)?;
+        let pds: PrivateDataService<_, _, _, anyhow::Error> =
+            PrivateDataService::new(filter_storage, HashMapEventStorage::new());
+        let mut batch_pds = BatchPrivateDataService::new(pds, 1)?;
+
+        for (request_id, requested_epsilon) in [(1, 1.0), (2, 1.0), (3, 2.0)] {
+            let request = PpaHistogramRequestBuilder::new()
+                .epoch_range(1, 1)?
+                .value(1.0, 1.0, requested_epsilon)?
+                .uris(ReportRequestUris::mock())?
+                .selector(
+                    FilterDataPredicate::Any,
+                    5,
+                    RequestedBuckets::AllBuckets,
+                )?
+                .build();
+            batch_pds.register_report_request(BatchedRequest::new(
+                request_id, 2, request,
+            ))?;
+        }
+
+        // Requests 1 and 2 both fit on the snapshot, request 3 never fits.
+        batch_pds.release_budget(1)?;
+        let pending = batch_pds.new_pending_requests.clone();
+        assert_eq!(
+            batch_pds.dry_run_on_snapshot(&pending)?,
+            [true, true, false]
+        );
+
+        // Only request 1 is allocated, request 2 is checked again once
+        // request 1 consumed the budget.
+        batch_pds.schedule_batch()?;
+        let mut batched_ids =
+            batch_pds.requests.request_ids(&batch_pds.batched_requests);
+        batched_ids.sort();
+        assert_eq!(batched_ids, [2, 3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reconcile_public_filters() -> Result<()> {
+        let capacities = StaticCapacities::new(10.0, 4.0, 10.0, 4.0);
+        let filter_storage: HashMapFilterStorage<PureDPBudgetReleaseFilter, _> =
+            HashMapFilterStorage::new(capacities)?;
+        let pds: PrivateDataService<_, _, _, anyhow::Error> =
+            PrivateDataService::new(filter_storage, HashMapEventStorage::new());
+        let mut batch_pds = BatchPrivateDataService::new(pds, 1)?;
+
+        // No relevant event, so IDP charges nothing while the public filters
+        // are charged the upper bound.
+        let request = PpaHistogramRequestBuilder::new()
+            .epoch_range(1, 1)?
+            .value(1.0, 1.0, 1.0)?
+            .uris(ReportRequestUris::mock())?
+            .selector(
+                FilterDataPredicate::Any,
+                5,
+                RequestedBuckets::AllBuckets,
+            )?
+            .build();
+        batch_pds
+            .register_report_request(BatchedRequest::new(1, 1, request))?;
+        batch_pds.schedule_batch()?;
+
+        let report = batch_pds.reconcile_public_filters(false)?;
+        let source = ReportRequestUris::mock().source_uris[0].clone();
+        assert_eq!(report.n_filters, 2);
+        assert_eq!(report.total_drift, 2.0);
+        assert_eq!(report.max_drift, 1.0);
+        assert_eq!(
+            report
+                .drifted_filters
+                .iter()
+                .map(|filter_drift| &filter_drift.filter_id)
+                .collect::<HashSet<_>>(),
+            HashSet::from_iter([
+                &FilterId::Global(1),
+                &FilterId::SourceQuota(1, source)
+            ])
+        );
+        assert_eq!(batch_pds.stats()?.epochs[&1].global_consumed, 1.0);
+
+        // Tightening gives the drift back to the scheduler.
+        assert!(batch_pds.reconcile_public_filters(true)?.tightened);
+        assert_eq!(batch_pds.stats()?.epochs[&1].global_consumed, 0.0);
+        let report = batch_pds.reconcile_public_filters(false)?;
+        assert!(report.drifted_filters.is_empty());
+        assert_eq!(report.max_drift, 0.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn epoch_schedule() -> Result<()> {
+        let capacities = StaticCapacities::new(10.0, 4.0, 10.0, 4.0);
+        let filter_storage: HashMapFilterStorage<PureDPBudgetReleaseFilter, _> =
+            HashMapFilterStorage::new(capacities)?;
+        let pds: PrivateDataService<_, _, _, anyhow::Error> =
+            PrivateDataService::new(filter_storage, HashMapEventStorage::new());
+        let mut batch_pds = BatchPrivateDataService::new(pds, 2)?
+            .with_epoch_schedule(EpochSchedule::new(2, 1)?);
+        assert!(EpochSchedule::new(0, 1).is_err());
+
+        let global_unlocked = |filters: &mut HashMapFilterStorage<
+            PureDPBudgetReleaseFilter,
+            _,
+        >,
+                               epoch| {
+            filters
+                .get_filter_or_new(&FilterId::Global(epoch))
+                .map(|filter| filter.unlocked)
+        };
 
+        // Epoch 1 starts with the first interval, and its budget is released
+        // before it is requested.
+        batch_pds.schedule_batch()?;
+        assert_eq!(batch_pds.pds.current_epoch, Some(1));
+        assert_eq!(global_unlocked(&mut batch_pds.public_filters, 1)?, 2.0);
+
+        // Requests for epoch 1 wait for the epoch to be over.
+        let request = PpaHistogramRequestBuilder::new()
+            .epoch_range(1, 1)?
+            .value(1.0, 1.0, 1.0)?
+            .uris(ReportRequestUris::mock())?
+            .selector(
+                FilterDataPredicate::Any,
+                5,
+                RequestedBuckets::AllBuckets,
+            )?
+            .build();
+        batch_pds
+            .register_report_request(BatchedRequest::new(1, 1, request))?;
+        assert_eq!(batch_pds.parked_requests.len(), 1);
+
+        batch_pds.schedule_batch()?;
+        assert_eq!(batch_pds.pds.current_epoch, Some(1));
+        assert_eq!(global_unlocked(&mut batch_pds.public_filters, 1)?, 4.0);
+        assert_eq!(batch_pds.parked_requests.len(), 1);
+
+        // Epoch 2 starts, which closes the window of the request. Epoch 1 is
+        // past its release horizon.
         let reports = batch_pds.schedule_batch()?;
-        assert_eq!(reports.len(), 2);
+        assert_eq!(batch_pds.pds.current_epoch, Some(2));
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].request_id, 1);
+        assert!(!batch_pds.is_within_release_horizon(&1));
+        assert!(batch_pds.is_within_release_horizon(&2));
+        assert_eq!(global_unlocked(&mut batch_pds.public_filters, 2)?, 2.0);
 
-        debug!("Reports: {reports:?}");
+        Ok(())
+    }
 
-        for report in reports {
-            assert!(
-                report.report.oob_filters.is_empty(),
-                "Report should not have any OOB filters. Got: {:?}",
-                report.report.oob_filters
+    #[test]
+    fn subscriptions() -> Result<()> {
+        let capacities = StaticCapacities::new(10.0, 4.0, 10.0, 4.0);
+        let events = [1, 2].map(|epoch_number| PpaEvent {
+            id: epoch_number,
+            timestamp: 0,
+            epoch_number,
+            histogram_index: 0,
+            uris: EventUris::mock(),
+            filter_data: 1,
+            priority: 0,
+        });
+        let filter_storage: HashMapFilterStorage<PureDPBudgetReleaseFilter, _> =
+            HashMapFilterStorage::new(capacities)?;
+        let pds: PrivateDataService<_, _, _, anyhow::Error> =
+            PrivateDataService::new(
+                filter_storage,
+                event_storage_with_events(events.to_vec()),
             );
-        }
+        let mut batch_pds = BatchPrivateDataService::new(pds, 2)?;
 
-        let reports = batch_pds.schedule_batch()?;
+        let request = |epoch_id: &u64| {
+            PpaHistogramRequestBuilder::new()
+                .epoch_range(*epoch_id, *epoch_id)
+                .unwrap()
+                .value(1.0, 1.0, 1.0)
+                .unwrap()
+                .uris(ReportRequestUris::mock())
+                .unwrap()
+                .selector(
+                    FilterDataPredicate::Any,
+                    5,
+                    RequestedBuckets::AllBuckets,
+                )
+                .unwrap()
+                .build()
+        };
+        let push = || Subscription::new(1, SubscriptionDelivery::Push, request);
+        let pull = || Subscription::new(2, SubscriptionDelivery::Pull, request);
+
+        // Reservations are made when epochs start.
+        assert!(batch_pds.subscribe(push()).is_err());
+        batch_pds = batch_pds.with_epoch_schedule(EpochSchedule::new(2, 1)?);
+        batch_pds.subscribe(push())?;
+        batch_pds.subscribe(pull())?;
+        assert!(batch_pds.subscribe(push()).is_err());
+
+        // Epoch 1 starts, and the subscriptions reserve all the Global budget
+        // released so far, before any other request.
+        batch_pds.schedule_batch()?;
+        assert_eq!(batch_pds.subscription_reservations.len(), 2);
+        let report = batch_pds
+            .register_report_request(BatchedRequest::new(3, 0, request(&1)))?
+            .remove(0);
+        assert!(report.report.oob_filters.contains(&FilterId::Global(1)));
+
+        // Reports wait for the epoch to be over.
+        assert!(batch_pds.fetch_subscription_reports(2, 1).is_err());
+        batch_pds.schedule_batch()?;
+        assert!(batch_pds.take_subscription_reports().is_empty());
+
+        // Epoch 2 starts: the push subscription is answered for epoch 1, and
+        // the pull subscription can be fetched.
+        batch_pds.schedule_batch()?;
+        let reports = batch_pds.take_subscription_reports();
         assert_eq!(reports.len(), 1);
-        debug!("Reports again: {reports:?}");
+        assert_eq!((reports[0].subscription_id, reports[0].epoch_id), (1, 1));
+        assert_eq!(reports[0].report.filtered_report.bin_values[&0], 1.0);
 
-        assert!(
-            reports[0].report.oob_filters.is_empty(),
-            "Report should not have any OOB filters. Got: {:?}",
-            reports[0].report.oob_filters
-        );
+        let reports = batch_pds.fetch_subscription_reports(2, 1)?;
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].report.filtered_report.bin_values[&0], 1.0);
+        assert!(batch_pds.fetch_subscription_reports(2, 1).is_err());
+        assert!(batch_pds.fetch_subscription_reports(1, 2).is_err());
+
+        // Unsubscribing refunds the budget reserved for epoch 2.
+        let global_consumed = |batch_pds: &mut BatchPrivateDataService<
+            _,
+            HashMapFilterStorage<PureDPBudgetReleaseFilter, _>,
+            _,
+            _,
+        >| {
+            batch_pds
+                .public_filters
+                .get_filter_or_new(&FilterId::Global(2))
+                .and_then(|filter| filter.consumed())
+        };
+        assert_eq!(global_consumed(&mut batch_pds)?, 2.0);
+        assert!(batch_pds.unsubscribe(1)?);
+        assert!(!batch_pds.unsubscribe(1)?);
+        assert_eq!(global_consumed(&mut batch_pds)?, 1.0);
+        assert_eq!(batch_pds.subscription_reservations.len(), 1);
 
         Ok(())
     }
@@ -905,6 +3280,7 @@ mod tests {
                 querier_uris: trigger_uris.clone(),
             },
             filter_data: 1,
+            priority: 0,
         };
         let event2 = PpaEvent {
             id: 1,
@@ -917,6 +3293,7 @@ mod tests {
                 querier_uris: vec!["hats-1.ex".to_string()],
             },
             filter_data: 1,
+            priority: 0,
         };
 
         let event_storage = event_storage_with_events(vec![event1, event2]);
@@ -928,67 +3305,62 @@ mod tests {
             PrivateDataService::new(filter_storage, event_storage);
         let mut batch_pds = BatchPrivateDataService::new(pds, 1)?;
 
-        let mut request_config = PpaHistogramConfig {
-            start_epoch: 1,
-            end_epoch: 1,
-            attributable_value: 1.0,
-            max_attributable_value: 1.0,
-            requested_epsilon: 99.9, // will be set per request
-            histogram_size: 5,
-        };
-
-        let always_valid_selector =
-            |uris: ReportRequestUris<String>| PpaRelevantEventSelector {
-                report_request_uris: uris,
-                is_matching_event: Box::new(|_: u64| true),
-                requested_buckets: RequestedBuckets::AllBuckets,
+        let always_valid_request =
+            |requested_epsilon, uris: ReportRequestUris<String>| {
+                PpaHistogramRequestBuilder::new()
+                    .epoch_range(1, 1)?
+                    .value(1.0, 1.0, requested_epsilon)?
+                    .uris(uris)?
+                    .selector(
+                        FilterDataPredicate::Any,
+                        5,
+                        RequestedBuckets::AllBuckets,
+                    )
+                    .map(|builder| builder.build())
             };
 
         // Every single conversion sites gets a conversion.
         for i in 1..=9 {
-            request_config.requested_epsilon = 0.9 + 0.01 * i as f64;
             batch_pds.register_report_request(BatchedRequest::new(
                 i,
                 1,
-                PpaHistogramRequest::new(
-                    &request_config,
-                    always_valid_selector(ReportRequestUris {
+                always_valid_request(
+                    0.9 + 0.01 * i as f64,
+                    ReportRequestUris {
                         trigger_uri: format!("shoes-{i}.ex"),
                         source_uris: vec!["news.ex".to_string()],
                         querier_uris: vec![format!("shoes-{i}.ex")],
-                    }),
+                        campaign_id: None,
+                    },
                 )?,
             ))?;
         }
 
-        request_config.requested_epsilon = 0.96;
         batch_pds.register_report_request(BatchedRequest::new(
             6,
             1,
-            PpaHistogramRequest::new(
-                &request_config,
-                always_valid_selector(ReportRequestUris {
+            always_valid_request(
+                0.96,
+                ReportRequestUris {
                     trigger_uri: "hats-1.ex".to_string(),
                     source_uris: vec!["blog.ex".to_string()],
                     querier_uris: vec!["hats-1.ex".to_string()],
-                }),
+                    campaign_id: None,
+                },
             )?,
         ))?;
 
         // We open up `schedule_batch` to check step by step.
-        let mut previous_batch = take(&mut batch_pds.batched_requests);
-        let mut new_requests = take(&mut batch_pds.new_pending_requests);
-        for request in &mut previous_batch {
-            request.n_remaining_scheduling_attempts -= 1;
-        }
-        for request in &mut new_requests {
-            request.n_remaining_scheduling_attempts -= 1;
+        let previous_batch = take(&mut batch_pds.batched_requests);
+        let new_requests = take(&mut batch_pds.new_pending_requests);
+        for index in previous_batch.iter().chain(&new_requests) {
+            batch_pds.requests[*index].n_remaining_scheduling_attempts -= 1;
         }
 
         assert!(previous_batch.is_empty());
 
         let unallocated_from_previous_batch =
-            batch_pds.initialization_phase(previous_batch)?;
+            batch_pds.initialization_phase(previous_batch, None)?;
 
         assert!(unallocated_from_previous_batch.is_empty());
         assert_eq!(new_requests.len(), 10);
@@ -998,7 +3370,7 @@ mod tests {
         // Because of SourceQuota, news.ex can't accept all the queries. Also
         // tried to allocate in order.
         assert_eq!(
-            collect_request_ids(&unallocated_new_requests),
+            batch_pds.requests.request_ids(&unallocated_new_requests),
             vec![6, 7, 8, 9]
         );
 
@@ -1071,6 +3443,7 @@ mod tests {
                 querier_uris: trigger_uris.clone(),
             },
             filter_data: 1,
+            priority: 0,
         };
 
         // Site with a lot of requests, but not as many as news.ex.
@@ -1089,6 +3462,7 @@ mod tests {
                 querier_uris: trigger_uris.clone(),
             },
             filter_data: 1,
+            priority: 0,
         };
 
         let event_storage = event_storage_with_events(vec![event1, event2]);
@@ -1100,13 +3474,17 @@ mod tests {
             PrivateDataService::new(filter_storage, event_storage);
         let mut batch_pds = BatchPrivateDataService::new(pds, 2)?;
 
-        let mut request_config = PpaHistogramConfig {
-            start_epoch: 1,
-            end_epoch: 1,
-            attributable_value: 1.0,
-            max_attributable_value: 1.0,
-            requested_epsilon: 99.9, // will be set per request
-            histogram_size: 5,
+        let request = |requested_epsilon, uris: ReportRequestUris<String>| {
+            PpaHistogramRequestBuilder::new()
+                .epoch_range(1, 1)?
+                .value(1.0, 1.0, requested_epsilon)?
+                .uris(uris)?
+                .selector(
+                    FilterDataPredicate::Any,
+                    5,
+                    RequestedBuckets::AllBuckets,
+                )
+                .map(|builder| builder.build())
         };
 
         // Every single conversion sites gets a conversion. But news.ex comes
@@ -1115,7 +3493,7 @@ mod tests {
         for i in 1..=10 {
             let shoes_conv = format!("shoes-{i}.ex");
 
-            request_config.requested_epsilon = if i == 3 {
+            let requested_epsilon = if i == 3 {
                 0.99 // We want this request to be smaller than the others in
                      // the tests.
             } else {
@@ -1125,16 +3503,13 @@ mod tests {
             batch_pds.register_report_request(BatchedRequest::new(
                 i,
                 2, // Space for one more time. Easier to check the batch.
-                PpaHistogramRequest::new(
-                    &request_config,
-                    PpaRelevantEventSelector {
-                        report_request_uris: ReportRequestUris {
-                            trigger_uri: shoes_conv.clone(),
-                            source_uris: vec!["news.ex".to_string()],
-                            querier_uris: vec![shoes_conv.clone()],
-                        },
-                        is_matching_event: Box::new(|_: u64| true),
-                        requested_buckets: RequestedBuckets::AllBuckets,
+                request(
+                    requested_epsilon,
+                    ReportRequestUris {
+                        trigger_uri: shoes_conv.clone(),
+                        source_uris: vec!["news.ex".to_string()],
+                        querier_uris: vec![shoes_conv.clone()],
+                        campaign_id: None,
                     },
                 )?,
             ))?;
@@ -1143,21 +3518,18 @@ mod tests {
         for i in 1..=10 {
             let hats_conv = format!("hats-{i}.ex");
 
-            request_config.requested_epsilon = 0.99 + 0.0001 * i as f64;
+            let requested_epsilon = 0.99 + 0.0001 * i as f64;
 
             batch_pds.register_report_request(BatchedRequest::new(
                 10 + i,
                 2,
-                PpaHistogramRequest::new(
-                    &request_config,
-                    PpaRelevantEventSelector {
-                        report_request_uris: ReportRequestUris {
-                            trigger_uri: hats_conv.clone(),
-                            source_uris: vec!["blog.ex".to_string()],
-                            querier_uris: vec![hats_conv.clone()],
-                        },
-                        is_matching_event: Box::new(|_: u64| true),
-                        requested_buckets: RequestedBuckets::AllBuckets,
+                request(
+                    requested_epsilon,
+                    ReportRequestUris {
+                        trigger_uri: hats_conv.clone(),
+                        source_uris: vec!["blog.ex".to_string()],
+                        querier_uris: vec![hats_conv.clone()],
+                        campaign_id: None,
                     },
                 )?,
             ))?;
@@ -1175,7 +3547,7 @@ mod tests {
 
         info!(
             "Batched requests after first scheduling: {:?}",
-            collect_request_ids(&batch_pds.batched_requests)
+            batch_pds.requests.request_ids(&batch_pds.batched_requests)
         );
 
         // Only 5 reports should have been allocated from the released global
@@ -1214,4 +3586,302 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn sort_batch_by_shortfall() -> Result<()> {
+        let capacities = StaticCapacities::new(10.0, 5.0, 10.0, 4.0);
+        let filter_storage: HashMapFilterStorage<PureDPBudgetReleaseFilter, _> =
+            HashMapFilterStorage::new(capacities)?;
+        let pds: PrivateDataService<_, _, _, anyhow::Error> =
+            PrivateDataService::new(filter_storage, HashMapEventStorage::new());
+        let mut batch_pds = BatchPrivateDataService::new(pds, 2)?;
+
+        // Half of the global budget is unlocked, and source A already
+        // consumed most of its quota.
+        batch_pds.release_budget(1)?;
+        let source_a = FilterId::SourceQuota(1, "a.com".to_string());
+        batch_pds.initialize_filters([&source_a].into_iter())?;
+        batch_pds.public_filters.try_consume(&source_a, &3.0)?;
+
+        let request = |source: &str, requested_epsilon| {
+            PpaHistogramRequestBuilder::new()
+                .epoch_range(1, 1)?
+                .value(1.0, 1.0, requested_epsilon)?
+                .uris(ReportRequestUris {
+                    source_uris: vec![source.to_string()],
+                    ..ReportRequestUris::mock()
+                })?
+                .selector(
+                    FilterDataPredicate::Any,
+                    5,
+                    RequestedBuckets::AllBuckets,
+                )
+                .map(|builder| builder.build())
+        };
+
+        // Request 1 has the smallest beneficiary but misses 0.5 of global
+        // budget, request 2 misses 4.5 and request 3 can be allocated.
+        let requests = vec![
+            batch_pds.requests.insert(BatchedRequest::new(
+                1,
+                1,
+                request("b.com", 3.0)?,
+            )),
+            batch_pds.requests.insert(BatchedRequest::new(
+                2,
+                1,
+                request("b.com", 7.0)?,
+            )),
+            batch_pds.requests.insert(BatchedRequest::new(
+                3,
+                1,
+                request("a.com", 0.5)?,
+            )),
+        ];
+        let shortfall = batch_pds
+            .with_request(requests[0], |this, request| {
+                this.request_shortfall(&request.request)
+            })?;
+        assert_eq!(shortfall, 0.5);
+
+        let sorted = batch_pds.sort_batch(requests)?;
+        assert_eq!(batch_pds.requests.request_ids(&sorted), vec![3, 1, 2]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn pending_request_limit() -> Result<()> {
+        let capacities = StaticCapacities::new(10.0, 5.0, 10.0, 4.0);
+        let filter_storage: HashMapFilterStorage<PureDPBudgetReleaseFilter, _> =
+            HashMapFilterStorage::new(capacities)?;
+        let pds: PrivateDataService<_, _, _, anyhow::Error> =
+            PrivateDataService::new(filter_storage, HashMapEventStorage::new());
+        let mut batch_pds =
+            BatchPrivateDataService::new(pds, 2)?.with_max_pending_requests(2);
+
+        let request = |request_id| -> Result<_> {
+            Ok(BatchedRequest::new(
+                request_id,
+                2,
+                PpaHistogramRequestBuilder::new()
+                    .epoch_range(1, 1)?
+                    .value(1.0, 1.0, 1.0)?
+                    .uris(ReportRequestUris::mock())?
+                    .selector(
+                        FilterDataPredicate::Any,
+                        5,
+                        RequestedBuckets::AllBuckets,
+                    )?
+                    .build(),
+            ))
+        };
+
+        batch_pds.register_report_request(request(1)?)?;
+        batch_pds.register_report_request(request(2)?)?;
+        let result = batch_pds.register_report_request(request(3)?);
+        assert!(matches!(
+            result.map_err(|err| err.downcast::<PdsError>()),
+            Err(Ok(PdsError::QueueFull(_)))
+        ));
+        assert_eq!(
+            batch_pds
+                .requests
+                .request_ids(&batch_pds.new_pending_requests),
+            [1, 2]
+        );
+
+        // Scheduling empties the pending queue, and allocated requests leave
+        // the arena, so their slots are reused.
+        batch_pds.schedule_batch()?;
+        assert!(batch_pds.new_pending_requests.is_empty());
+        assert_eq!(batch_pds.requests.len(), batch_pds.batched_requests.len());
+        batch_pds.register_report_request(request(3)?)?;
+        assert_eq!(
+            batch_pds.requests.len(),
+            batch_pds.batched_requests.len() + 1
+        );
+        assert!(batch_pds.new_pending_requests[0] < 2);
+
+        // Canceled requests leave the arena too.
+        assert_eq!(
+            batch_pds.cancel_report_request(3),
+            CancellationAck::Withdrawn
+        );
+        assert_eq!(batch_pds.requests.len(), batch_pds.batched_requests.len());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn failed_allocation_consumes_nothing() -> Result<()> {
+        use crate::testing::faults::FaultyFilterStorage;
+
+        let event = PpaEvent {
+            id: 1,
+            timestamp: 0,
+            epoch_number: 1,
+            histogram_index: 0,
+            uris: EventUris::mock(),
+            filter_data: 1,
+            priority: 0,
+        };
+        let uris = ReportRequestUris::mock();
+        let filter_ids = [
+            FilterId::PerQuerier(1, uris.querier_uris[0].clone()),
+            FilterId::Global(1),
+            FilterId::TriggerQuota(1, uris.trigger_uri.clone()),
+            FilterId::SourceQuota(1, uris.source_uris[0].clone()),
+        ];
+
+        // Fail each operation on the private filters in turn. Either the
+        // request is answered, or it fails to be allocated, neither the
+        // private nor the public filters are charged, and the request is
+        // answered by the next scheduling interval.
+        let mut n = 0;
+        loop {
+            let filter_storage: FaultyFilterStorage<
+                HashMapFilterStorage<PureDPBudgetReleaseFilter, _>,
+            > = FaultyFilterStorage::new(StaticCapacities::new(
+                10.0, 5.0, 10.0, 4.0,
+            ))?;
+            let pds: PrivateDataService<_, _, _, anyhow::Error> =
+                PrivateDataService::new(
+                    filter_storage,
+                    event_storage_with_events(vec![event.clone()]),
+                );
+            let mut batch_pds = BatchPrivateDataService::new(pds, 2)?;
+            batch_pds.register_report_request(BatchedRequest::new(
+                1,
+                1,
+                PpaHistogramRequestBuilder::new()
+                    .epoch_range(1, 1)?
+                    .value(1.0, 1.0, 1.0)?
+                    .uris(uris.clone())?
+                    .selector(
+                        FilterDataPredicate::Any,
+                        5,
+                        RequestedBuckets::AllBuckets,
+                    )?
+                    .build(),
+            ))?;
+
+            batch_pds.pds.core.filter_storage.fail_operation(n);
+            if let Ok(reports) = batch_pds.schedule_batch() {
+                assert_eq!(collect_report_ids(&reports), [1]);
+                break;
+            }
+            n += 1;
+            batch_pds.pds.core.filter_storage.clear_faults();
+            assert_eq!(batch_pds.current_scheduling_interval, 0);
+
+            // Later steps can fail once the request is allocated, its report
+            // is then still waiting for release.
+            if batch_pds.delayed_reports.is_empty() {
+                for filter_id in &filter_ids {
+                    for filters in [
+                        &mut batch_pds.pds.core.filter_storage,
+                        &mut batch_pds.public_filters,
+                    ] {
+                        if let Some(filter) = filters.get_filter(filter_id)? {
+                            assert_eq!(filter.consumed, 0.0, "{filter_id:?}");
+                        }
+                    }
+                }
+
+                // The request is still queued, with the attempt it didn't
+                // get.
+                let index = batch_pds.new_pending_requests[0];
+                assert_eq!(batch_pds.requests.len(), 1);
+                assert_eq!(batch_pds.requests[index].request_id, 1);
+                assert_eq!(
+                    batch_pds.requests[index].n_remaining_scheduling_attempts,
+                    1
+                );
+            }
+
+            let reports = batch_pds.schedule_batch()?;
+            assert_eq!(collect_report_ids(&reports), [1]);
+            assert!(batch_pds.requests.is_empty());
+
+            // The retried interval released the Global filter only once.
+            let global_filter = batch_pds
+                .pds
+                .core
+                .filter_storage
+                .get_filter(&FilterId::Global(1))?
+                .unwrap();
+            assert_eq!(global_filter.unlocked, 2.5);
+        }
+        assert!(n > 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn private_source_quota_shortfall_delivers_report() -> Result<()> {
+        let event = PpaEvent {
+            id: 1,
+            timestamp: 0,
+            epoch_number: 1,
+            histogram_index: 0,
+            uris: EventUris::mock(),
+            filter_data: 1,
+            priority: 0,
+        };
+        let filter_storage: HashMapFilterStorage<PureDPBudgetReleaseFilter, _> =
+            HashMapFilterStorage::new(StaticCapacities::new(
+                10.0, 5.0, 10.0, 4.0,
+            ))?;
+        let pds: PrivateDataService<_, _, _, anyhow::Error> =
+            PrivateDataService::new(
+                filter_storage,
+                event_storage_with_events(vec![event]),
+            );
+        let mut batch_pds = BatchPrivateDataService::new(pds, 1)?;
+        let uris = ReportRequestUris::mock();
+        let request = PpaHistogramRequestBuilder::new()
+            .epoch_range(1, 1)?
+            .value(1.0, 1.0, 1.0)?
+            .uris(uris.clone())?
+            .selector(
+                FilterDataPredicate::Any,
+                5,
+                RequestedBuckets::AllBuckets,
+            )?
+            .build();
+        batch_pds
+            .register_report_request(BatchedRequest::new(1, 1, request))?;
+
+        // The private SourceQuota filter is used up, but not its public
+        // bound, e.g. after a bug in the public accounting.
+        let source_quota =
+            FilterId::SourceQuota(1, uris.source_uris[0].clone());
+        batch_pds.pds.core.filter_storage.edit_filter_or_new(
+            &source_quota,
+            |f| {
+                f.release(&PureDPBudget::infinity())?;
+                f.try_consume(&4.0)
+            },
+        )?;
+
+        // The public budget is consumed and the request still gets its
+        // report, null since its only epoch was out of budget.
+        let reports = batch_pds.schedule_batch()?;
+        assert_eq!(collect_report_ids(&reports), [1]);
+        assert_eq!(reports[0].report.oob_filters, [source_quota]);
+        assert_eq!(
+            reports[0].report.status,
+            ReportStatus::Null {
+                reason: NullReason::OutOfBudget
+            }
+        );
+        let per_querier = FilterId::PerQuerier(1, uris.querier_uris[0].clone());
+        let filter = batch_pds.public_filters.get_filter(&per_querier)?;
+        assert_eq!(filter.unwrap().consumed, 1.0);
+        assert!(batch_pds.requests.is_empty());
+
+        Ok(())
+    }
 }
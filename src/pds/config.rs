@@ -0,0 +1,430 @@
+//! TOML configuration of a PDS, so that deployments can change capacities
+//! and policies without recompiling, e.g.
+//! ```toml
+//! [capacities]
+//! per_querier = 1.0
+//! global = 20.0
+//! trigger_quota = 1.5
+//! source_quota = 4.0
+//! rate_limit = { burst = 10, refill_period = 60 }
+//!
+//! [quotas]
+//! exempt_uris = ["shoes.com"]
+//! querier_groups = { "adtech.com" = ["adtech.com", "adtech.net"] }
+//!
+//! [epochs]
+//! max_attribution_window = 30
+//! borrow_limit = 0.5
+//! querier_granularity = { "adtech.com" = 7 }
+//! base_epoch_duration = 86400
+//!
+//! [batch]
+//! n_releases = 4
+//! epoch_lifetime = 30
+//! max_pending_requests = 10000
+//! intervals_per_epoch = 24
+//! ```
+//! Only `capacities` is required. Other sections default to no exemptions,
+//! no querier groups, base epochs for all queriers, no attribution window limit, no borrowing
+//! and, for batch PDS, a single release, unbounded pending requests and epochs
+//! left to the caller.
+
+use std::{collections::BTreeMap, fs, path::Path};
+
+use serde::Deserialize;
+
+#[cfg(feature = "experimental")]
+use crate::{
+    budget::traits::ReleaseFilter,
+    events::traits::EventStorage,
+    pds::{
+        batch_pds::{BatchPrivateDataService, EpochSchedule},
+        private_data_service::PrivateDataService,
+    },
+    queries::traits::EpochReportRequest,
+    util::parallel::ThreadSafe,
+};
+use crate::{
+    budget::{pure_dp_filter::PureDPBudget, traits::FilterStorage},
+    error::PdsError,
+    pds::{
+        aliases::{PpaEventStorage, PpaFilterStorage, PpaPds},
+        epoch_policy::QuerierEpochGranularity,
+        quotas::{
+            BorrowingPolicy, FilterId, QuerierGroups, QuotaExemptions,
+            StaticCapacities,
+        },
+    },
+};
+
+/// First-party measurements exempt from their quotas, and queriers sharing
+/// their PerQuerier filters.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuotaConfig {
+    #[serde(default)]
+    pub exempt_uris: Vec<String>,
+
+    /// Querier URIs of each group, by group ID, see `QuerierGroups`.
+    #[serde(default)]
+    pub querier_groups: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EpochConfig {
+    /// Maximum number of base epochs that a request can span. Unlimited if
+    /// None.
+    pub max_attribution_window: Option<usize>,
+
+    /// Largest budget that PerQuerier filters can borrow from the next
+    /// epoch, see `BorrowingPolicy`. Borrowing is disabled if None.
+    pub borrow_limit: Option<PureDPBudget>,
+
+    /// Number of base epochs in each epoch of the listed queriers. Other
+    /// queriers use base epochs.
+    #[serde(default)]
+    pub querier_granularity: BTreeMap<String, u64>,
+
+    /// Duration of base epochs in seconds, to resolve the duration windows
+    /// of requests into epochs. Requests with duration windows are rejected
+    /// if None.
+    pub base_epoch_duration: Option<u64>,
+}
+
+/// Parameters of `BatchPrivateDataService`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchConfig {
+    /// Number of scheduling intervals over which the Global filter is
+    /// released.
+    #[serde(default = "default_n_releases")]
+    pub n_releases: usize,
+
+    /// Scheduling intervals after which epochs are retired. Never if None.
+    pub epoch_lifetime: Option<u64>,
+
+    /// Maximum number of requests pending for the next scheduling interval.
+    /// Unbounded if None.
+    pub max_pending_requests: Option<usize>,
+
+    /// Number of scheduling intervals per epoch, to move to the next epoch
+    /// from the scheduler. Epochs are left to the caller if None.
+    pub intervals_per_epoch: Option<u64>,
+
+    /// Epoch of the first scheduling interval, if `intervals_per_epoch` is
+    /// set.
+    #[serde(default)]
+    pub first_epoch: u64,
+}
+
+fn default_n_releases() -> usize {
+    1
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PdsConfig {
+    pub capacities: StaticCapacities<FilterId, PureDPBudget>,
+
+    #[serde(default)]
+    pub quotas: QuotaConfig,
+
+    #[serde(default)]
+    pub epochs: EpochConfig,
+
+    /// Parameters of the batch scheduler, if it is used.
+    pub batch: Option<BatchConfig>,
+}
+
+impl PdsConfig {
+    /// Reads and validates a TOML config file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, PdsError> {
+        let path = path.as_ref();
+        let config = fs::read_to_string(path).map_err(|err| {
+            PdsError::InvalidConfig(format!("can't read {path:?}: {err}"))
+        })?;
+        Self::from_toml(&config)
+    }
+
+    /// Parses and validates a TOML config.
+    pub fn from_toml(config: &str) -> Result<Self, PdsError> {
+        let config: Self = toml::from_str(config)
+            .map_err(|err| PdsError::InvalidConfig(err.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks that capacities are non-negative, that counts are positive and
+    /// that querier groups don't overlap.
+    pub fn validate(&self) -> Result<(), PdsError> {
+        let capacities = &self.capacities;
+        let named_capacities = [
+            ("per_querier", Some(capacities.per_querier)),
+            ("global", Some(capacities.global)),
+            ("trigger_quota", Some(capacities.trigger_quota)),
+            ("source_quota", Some(capacities.source_quota)),
+            ("campaign_quota", capacities.campaign_quota),
+            ("source_trigger_quota", capacities.source_trigger_quota),
+            ("ldp", capacities.ldp),
+            ("introspection", capacities.introspection),
+            ("borrow_limit", self.epochs.borrow_limit),
+        ];
+        for (name, capacity) in named_capacities {
+            if let Some(capacity) = capacity {
+                if capacity.is_nan() || capacity < 0.0 {
+                    return Err(PdsError::InvalidConfig(format!(
+                        "{name} must be >= 0, got {capacity}"
+                    )));
+                }
+            }
+        }
+        if capacities.rate_limit.is_some_and(|limit| limit.burst == 0) {
+            return Err(PdsError::InvalidConfig(
+                "rate limit burst must be > 0".into(),
+            ));
+        }
+        if self.epochs.max_attribution_window == Some(0) {
+            return Err(PdsError::InvalidConfig(
+                "max_attribution_window must be > 0".into(),
+            ));
+        }
+        if let Some((querier_uri, _)) = self
+            .epochs
+            .querier_granularity
+            .iter()
+            .find(|(_, n_base_epochs)| **n_base_epochs == 0)
+        {
+            return Err(PdsError::InvalidConfig(format!(
+                "granularity of {querier_uri:?} must be > 0"
+            )));
+        }
+        if self.epochs.base_epoch_duration == Some(0) {
+            return Err(PdsError::InvalidConfig(
+                "base_epoch_duration must be > 0".into(),
+            ));
+        }
+        self.querier_groups()?;
+        if self
+            .batch
+            .as_ref()
+            .is_some_and(|batch| batch.n_releases == 0)
+        {
+            return Err(PdsError::InvalidConfig(
+                "n_releases must be > 0".into(),
+            ));
+        }
+        if self
+            .batch
+            .as_ref()
+            .is_some_and(|batch| batch.intervals_per_epoch == Some(0))
+        {
+            return Err(PdsError::InvalidConfig(
+                "intervals_per_epoch must be > 0".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Quota exemptions of the listed URIs.
+    pub fn quota_exemptions(&self) -> QuotaExemptions<String> {
+        self.quotas
+            .exempt_uris
+            .iter()
+            .fold(QuotaExemptions::new(), |exemptions, uri| {
+                exemptions.with_uri(uri.clone())
+            })
+    }
+
+    /// Querier groups, rejecting queriers that are in several groups.
+    pub fn querier_groups(&self) -> Result<QuerierGroups<String>, PdsError> {
+        self.quotas.querier_groups.iter().try_fold(
+            QuerierGroups::new(),
+            |groups, (group_id, members)| {
+                groups.with_group(group_id.clone(), members.iter().cloned())
+            },
+        )
+    }
+
+    /// Epoch policy with the granularity of each listed querier, and the
+    /// duration of base epochs if set.
+    pub fn epoch_policy(
+        &self,
+    ) -> Result<QuerierEpochGranularity<String>, PdsError> {
+        let mut epoch_policy = QuerierEpochGranularity::new();
+        for (querier_uri, n_base_epochs) in &self.epochs.querier_granularity {
+            epoch_policy
+                .set_granularity(querier_uri.clone(), *n_base_epochs)?;
+        }
+        if let Some(base_epoch_duration) = self.epochs.base_epoch_duration {
+            epoch_policy.set_base_epoch_duration(base_epoch_duration)?;
+        }
+        Ok(epoch_policy)
+    }
+
+    /// Builds a PPA PDS with in-memory storages.
+    pub fn ppa_pds(&self) -> Result<PpaPds, PdsError> {
+        let filters = PpaFilterStorage::new(self.capacities.clone())?;
+        let mut pds = PpaPds::new(filters, PpaEventStorage::new())
+            .with_quota_exemptions(self.quota_exemptions())
+            .with_querier_groups(self.querier_groups()?)
+            .with_epoch_policy(self.epoch_policy()?);
+        if let Some(max_attribution_window) = self.epochs.max_attribution_window
+        {
+            pds = pds.with_max_attribution_window(max_attribution_window);
+        }
+        if let Some(borrow_limit) = self.epochs.borrow_limit {
+            pds = pds.with_borrowing_policy(BorrowingPolicy::new(borrow_limit));
+        }
+        Ok(pds)
+    }
+}
+
+#[cfg(feature = "experimental")]
+impl BatchConfig {
+    /// Wraps `pds` in a batch PDS with these parameters.
+    pub fn batch_pds<Q, FS, ES, ERR>(
+        &self,
+        pds: PrivateDataService<Q, FS, ES, ERR>,
+    ) -> Result<BatchPrivateDataService<Q, FS, ES, ERR>, ERR>
+    where
+        Q: EpochReportRequest<EpochId = u64>,
+        FS: FilterStorage<
+            Budget = PureDPBudget,
+            FilterId = FilterId<Q::EpochId, Q::Uri>,
+            Capacities = StaticCapacities<
+                FilterId<Q::EpochId, Q::Uri>,
+                PureDPBudget,
+            >,
+        >,
+        FS::Filter: ReleaseFilter<FS::Budget, Error = FS::Error> + ThreadSafe,
+        ES: EventStorage<Event = Q::Event>,
+        ERR: From<FS::Error> + From<ES::Error> + From<PdsError>,
+    {
+        let mut batch_pds = BatchPrivateDataService::new(pds, self.n_releases)?;
+        batch_pds.epoch_lifetime = self.epoch_lifetime;
+        batch_pds.max_pending_requests = self.max_pending_requests;
+        if let Some(intervals_per_epoch) = self.intervals_per_epoch {
+            batch_pds.epoch_schedule = Some(EpochSchedule::new(
+                intervals_per_epoch,
+                self.first_epoch,
+            )?);
+        }
+        Ok(batch_pds)
+    }
+}
+
+impl PpaPds {
+    /// Builds a PPA PDS with in-memory storages from a TOML config file, see
+    /// `PdsConfig`.
+    pub fn from_config(path: impl AsRef<Path>) -> Result<Self, PdsError> {
+        PdsConfig::from_file(path)?.ppa_pds()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        budget::traits::FilterCapacities, queries::traits::ReportRequestUris,
+    };
+
+    const CONFIG: &str = r#"
+        [capacities]
+        per_querier = 1.0
+        global = 20.0
+        trigger_quota = 1.5
+        source_quota = 4.0
+        ldp = 2.0
+        rate_limit = { burst = 10, refill_period = 60 }
+
+        [quotas]
+        exempt_uris = ["shoes.com"]
+        querier_groups = { "adtech.com" = ["adtech.com", "adtech.net"] }
+
+        [epochs]
+        max_attribution_window = 30
+        borrow_limit = 0.5
+        querier_granularity = { "adtech.com" = 7 }
+        base_epoch_duration = 86400
+
+        [batch]
+        epoch_lifetime = 30
+        max_pending_requests = 100
+        intervals_per_epoch = 24
+    "#;
+
+    #[test]
+    fn test_pds_config() -> Result<(), PdsError> {
+        let config = PdsConfig::from_toml(CONFIG)?;
+        let capacities = &config.capacities;
+        assert_eq!(capacities.capacity(&FilterId::Ldp(1))?, 2.0);
+        assert_eq!(capacities.capacity(&FilterId::Introspection(1))?, 0.0);
+        assert_eq!(capacities.rate_limit.map(|limit| limit.burst), Some(10));
+        assert_eq!(
+            config.batch.as_ref().map(|batch| batch.n_releases),
+            Some(1)
+        );
+        assert_eq!(
+            config
+                .batch
+                .as_ref()
+                .and_then(|batch| batch.max_pending_requests),
+            Some(100)
+        );
+        assert_eq!(
+            config.batch.as_ref().map(|batch| batch.first_epoch),
+            Some(0)
+        );
+
+        let pds = config.ppa_pds()?;
+        assert_eq!(pds.max_attribution_window, Some(30));
+        assert!(pds.core.borrowing_policy.is_some());
+        assert_eq!(
+            pds.core.base_epochs(&ReportRequestUris::mock(), 1),
+            (7..14).collect::<Vec<_>>()
+        );
+        let querier_uri = "adtech.com".to_string();
+        assert_eq!(
+            pds.core.epoch_policy.epochs_in_window(
+                &querier_uri,
+                7 * 86400,
+                14 * 86400
+            )?,
+            vec![2, 1]
+        );
+        assert_eq!(pds.core.filter_storage.capacities().global, 20.0);
+        assert_eq!(
+            pds.core.querier_groups.group_of(&"adtech.net".to_string()),
+            "adtech.com"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        // Missing capacities, typos and invalid values are rejected.
+        assert!(PdsConfig::from_toml("[quotas]").is_err());
+        let typo = CONFIG.replace("trigger_quota", "triger_quota");
+        assert!(PdsConfig::from_toml(&typo).is_err());
+        let negative = CONFIG.replace("global = 20.0", "global = -1.0");
+        assert!(PdsConfig::from_toml(&negative).is_err());
+        let granularity = CONFIG.replace("= 7", "= 0");
+        assert!(PdsConfig::from_toml(&granularity).is_err());
+        let overlapping_groups = CONFIG.replace(
+            r#""adtech.com" = ["#,
+            r#""other" = ["adtech.net"], "adtech.com" = ["#,
+        );
+        assert!(PdsConfig::from_toml(&overlapping_groups).is_err());
+        let no_intervals = CONFIG.replace("= 24", "= 0");
+        assert!(PdsConfig::from_toml(&no_intervals).is_err());
+        let no_duration = CONFIG.replace("= 86400", "= 0");
+        assert!(PdsConfig::from_toml(&no_duration).is_err());
+
+        assert!(matches!(
+            PpaPds::from_config("missing.toml"),
+            Err(PdsError::InvalidConfig(_))
+        ));
+    }
+}
@@ -0,0 +1,52 @@
+//! Budget top-ups granted by the user. Some products let users explicitly
+//! grant more measurement budget, e.g. by opting into a study. Each grant
+//! comes with a consent token, checked by a `ConsentVerifier` supplied by the
+//! embedder, and is recorded so that capacity increases can be audited.
+
+use serde::Serialize;
+
+use crate::{
+    budget::pure_dp_filter::PureDPBudget,
+    error::PdsError,
+    events::traits::{EpochId, Uri},
+    pds::quotas::FilterId,
+};
+
+/// Checks that budget grants were consented to by the user.
+pub trait ConsentVerifier<E: EpochId, U: Uri> {
+    /// Whether `consent_token` proves that the user granted `amount` to the
+    /// filter `filter_id`. Tokens should be bound to the grant, so that a
+    /// token can't be replayed for another filter or a larger amount.
+    fn verify(
+        &self,
+        filter_id: &FilterId<E, U>,
+        amount: PureDPBudget,
+        consent_token: &str,
+    ) -> Result<bool, PdsError>;
+}
+
+/// Rejects all the grants, i.e. capacities can't be raised.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoConsent;
+
+impl<E: EpochId, U: Uri> ConsentVerifier<E, U> for NoConsent {
+    fn verify(
+        &self,
+        _filter_id: &FilterId<E, U>,
+        _amount: PureDPBudget,
+        _consent_token: &str,
+    ) -> Result<bool, PdsError> {
+        Ok(false)
+    }
+}
+
+/// Record of an accepted grant.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BudgetGrant<E: EpochId, U: Uri> {
+    pub filter_id: FilterId<E, U>,
+    pub amount: PureDPBudget,
+    pub consent_token: String,
+
+    /// Time of the grant, in seconds.
+    pub granted_at: u64,
+}
@@ -1,20 +1,50 @@
-use std::{cell::Cell, marker::PhantomData, vec};
+use std::{cell::Cell, marker::PhantomData, mem::take, time::Instant, vec};
 
-use log::debug;
+#[cfg(feature = "experimental")]
+use std::collections::BTreeMap;
+
+use log::warn;
 
 use super::{
-    accounting::{compute_epoch_loss, compute_epoch_source_losses},
-    private_data_service::PdsReport,
-    quotas::{FilterId, PdsFilterStatus},
+    accounting::{compute_losses_per_epoch, EpochLosses},
+    accounting_stats::AccountingStats,
+    epoch_policy::{is_filter_pruned, BaseEpochs, EpochPolicy},
+    observer::{NoopObserver, PdsObserver},
+    preflight::{Headroom, PreflightResult, MANY_REQUESTS},
+    private_data_service::{PdsReport, ReportStatus},
+    quotas::{
+        BorrowingPolicy, FilterId, PdsFilterStatus, QuerierGroups,
+        QuotaExemptions,
+    },
 };
+#[cfg(feature = "experimental")]
+use super::{
+    attribution_trace::{AttributionTrace, CandidateEvent, RejectionReason},
+    cross_report::BeneficiaryCap,
+    quotas::CarryoverPolicy,
+};
+#[cfg(feature = "experimental")]
+use crate::budget::traits::EpochFilterId;
 use crate::{
     budget::{
         pure_dp_filter::PureDPBudget,
-        traits::{FilterStatus, FilterStorage},
+        traits::{
+            Filter, FilterCapacities, FilterStatus, FilterStorage, Scale,
+        },
+    },
+    error::PdsError,
+    events::{relevant_events::RelevantEvents, traits::Event},
+    mechanisms::ldp::LdpFallback,
+    queries::{
+        epoch_selection::unique_epochs,
+        traits::{EpochReportRequest, Report, ReportRequestUris},
+    },
+    util::{
+        correlation::debug,
+        hashmap::HashMap,
+        rng::{new_rng, PdsRng},
+        spans::timed_span,
     },
-    events::relevant_events::RelevantEvents,
-    queries::traits::{EpochReportRequest, Report, ReportRequestUris},
-    util::hashmap::HashMap,
 };
 
 pub struct PrivateDataServiceCore<Q, FS, ERR>
@@ -24,11 +54,61 @@ where
         FilterId = FilterId<Q::EpochId, Q::Uri>,
         Budget = PureDPBudget,
     >,
-    ERR: From<FS::Error>,
+    ERR: From<FS::Error> + From<PdsError>,
 {
     /// Filter storage interface.
     pub filter_storage: FS,
 
+    /// Epochs strictly older than this one have been pruned from the filter
+    /// storage. They are treated as if they had no relevant events, and never
+    /// consume budget again. Cached from the filter storage, which persists
+    /// it across restarts.
+    pub pruned_before: Option<Q::EpochId>,
+
+    /// Hooks called on report computations and budget deductions.
+    pub observer: Box<dyn PdsObserver<FilterId<Q::EpochId, Q::Uri>>>,
+
+    /// Budget consumed so far, by filter kind and by epoch.
+    pub accounting_stats: AccountingStats<Q::EpochId, Q::Uri>,
+
+    /// Local-DP fallback for out-of-budget requests. Disabled if None, in
+    /// which case out-of-budget requests get a null report.
+    pub ldp_fallback: Option<LdpFallback<Q>>,
+
+    /// Epoch scheme of each querier. Requests are in the epochs of their
+    /// querier, and the per-querier filters too, but the other filters are in
+    /// base epochs.
+    pub epoch_policy: Box<dyn EpochPolicy<Q::EpochId, Q::Uri>>,
+
+    /// First-party measurements that skip the trigger and source quotas.
+    pub quota_exemptions: QuotaExemptions<Q::Uri>,
+
+    /// Queriers that share their PerQuerier filters.
+    pub querier_groups: QuerierGroups<Q::Uri>,
+
+    /// Borrowing of PerQuerier budget against the next epoch. Disabled if
+    /// None.
+    pub borrowing_policy: Option<BorrowingPolicy<Q::EpochId>>,
+
+    /// [Experimental] Carryover of unused budget when epochs are pruned.
+    /// Disabled if None.
+    #[cfg(feature = "experimental")]
+    pub carryover_policy: Option<CarryoverPolicy<Q::EpochId>>,
+
+    /// [Experimental] Cap on the beneficiaries of each `AttributionObject`
+    /// measured from now on. Unbounded if None.
+    #[cfg(feature = "experimental")]
+    pub beneficiary_cap: Option<BeneficiaryCap>,
+
+    /// Whether reports carry an `AttributionTrace`.
+    #[cfg(feature = "experimental")]
+    pub attribution_trace: bool,
+
+    /// RNG sampling the relevant events of requests with an `EventSampling`.
+    /// Seeded from OS entropy, so that queriers can't know which epochs are
+    /// sampled.
+    pub sampling_rng: PdsRng,
+
     /// This PhantomData serves two purposes:
     /// 1. It Defines the Q and ERR generics on the struct instead of on each
     ///    individual function, reducing boilerplate
@@ -45,126 +125,705 @@ where
         FilterId = FilterId<Q::EpochId, Q::Uri>,
         Budget = PureDPBudget,
     >,
-    ERR: From<FS::Error>,
+    ERR: From<FS::Error> + From<PdsError>,
 {
     pub fn new(filter_storage: FS) -> Self {
         Self {
+            pruned_before: filter_storage.pruned_before(),
             filter_storage,
+            observer: Box::new(NoopObserver),
+            accounting_stats: AccountingStats::new(),
+            ldp_fallback: None,
+            epoch_policy: Box::new(BaseEpochs),
+            quota_exemptions: QuotaExemptions::new(),
+            querier_groups: QuerierGroups::new(),
+            borrowing_policy: None,
+            #[cfg(feature = "experimental")]
+            carryover_policy: None,
+            #[cfg(feature = "experimental")]
+            beneficiary_cap: None,
+            #[cfg(feature = "experimental")]
+            attribution_trace: false,
+            sampling_rng: new_rng(None),
             _phantom: PhantomData,
         }
     }
 
+    /// Replaces the observer, e.g. to export metrics.
+    pub fn set_observer(
+        &mut self,
+        observer: impl PdsObserver<FilterId<Q::EpochId, Q::Uri>> + 'static,
+    ) {
+        self.observer = Box::new(observer);
+    }
+
+    /// Enables the local-DP fallback for out-of-budget requests.
+    pub fn set_ldp_fallback(&mut self, ldp_fallback: LdpFallback<Q>) {
+        self.ldp_fallback = Some(ldp_fallback);
+    }
+
+    /// Replaces the epoch policy, e.g. to give coarser epochs to some
+    /// queriers.
+    pub fn set_epoch_policy(
+        &mut self,
+        epoch_policy: impl EpochPolicy<Q::EpochId, Q::Uri> + 'static,
+    ) {
+        self.epoch_policy = Box::new(epoch_policy);
+    }
+
+    /// Replaces the quota exemptions for first-party measurements.
+    pub fn set_quota_exemptions(
+        &mut self,
+        quota_exemptions: QuotaExemptions<Q::Uri>,
+    ) {
+        self.quota_exemptions = quota_exemptions;
+    }
+
+    /// Replaces the groups of queriers that share their PerQuerier filters.
+    pub fn set_querier_groups(
+        &mut self,
+        querier_groups: QuerierGroups<Q::Uri>,
+    ) {
+        self.querier_groups = querier_groups;
+    }
+
+    /// Lets PerQuerier filters borrow budget from the next epoch.
+    pub fn set_borrowing_policy(
+        &mut self,
+        borrowing_policy: BorrowingPolicy<Q::EpochId>,
+    ) {
+        self.borrowing_policy = Some(borrowing_policy);
+    }
+
+    /// Rolls unused budget over to the next epoch when epochs are pruned.
+    #[cfg(feature = "experimental")]
+    pub fn set_carryover_policy(
+        &mut self,
+        carryover_policy: CarryoverPolicy<Q::EpochId>,
+    ) {
+        self.carryover_policy = Some(carryover_policy);
+    }
+
+    /// Caps the beneficiaries of the conversions measured from now on, see
+    /// `BeneficiaryCap`.
+    #[cfg(feature = "experimental")]
+    pub fn set_beneficiary_cap(&mut self, beneficiary_cap: BeneficiaryCap) {
+        self.beneficiary_cap = Some(beneficiary_cap);
+    }
+
+    /// Attaches an `AttributionTrace` to the reports, or stops doing so.
+    #[cfg(feature = "experimental")]
+    pub fn set_attribution_trace(&mut self, enabled: bool) {
+        self.attribution_trace = enabled;
+    }
+
+    /// Seeds the sampling of the relevant events, for simulations only.
+    pub fn set_sampling_seed(&mut self, seed: u64) {
+        self.sampling_rng = new_rng(Some(seed));
+    }
+
+    /// Base epochs covered by a requested epoch, which is in the epoch scheme
+    /// of the querier.
+    pub fn base_epochs(
+        &self,
+        uris: &ReportRequestUris<Q::Uri>,
+        epoch_id: Q::EpochId,
+    ) -> Vec<Q::EpochId> {
+        match uris.querier_uris.first() {
+            Some(querier_uri) => {
+                self.epoch_policy.base_epochs(querier_uri, &epoch_id)
+            }
+            None => vec![epoch_id],
+        }
+    }
+
+    /// Whether some filters of a requested epoch have been pruned, i.e. the
+    /// filters of any of its base epochs.
+    pub fn is_request_epoch_pruned(
+        &self,
+        uris: &ReportRequestUris<Q::Uri>,
+        epoch_id: Q::EpochId,
+    ) -> bool {
+        self.base_epochs(uris, epoch_id)
+            .iter()
+            .any(|base_epoch| self.is_pruned(base_epoch))
+    }
+
+    /// Prunes the filters for all epochs strictly older than
+    /// `older_than_epoch`, and stops accounting for these epochs in future
+    /// requests. Returns the number of pruned filters.
+    pub fn prune_epochs(
+        &mut self,
+        older_than_epoch: Q::EpochId,
+    ) -> Result<usize, ERR> {
+        // The pruning watermark can only move forward.
+        let older_than_epoch = match self.pruned_before {
+            Some(pruned_before) => pruned_before.max(older_than_epoch),
+            None => older_than_epoch,
+        };
+        #[cfg(feature = "experimental")]
+        self.carry_over(older_than_epoch)?;
+        self.filter_storage.set_pruned_before(older_than_epoch)?;
+        self.pruned_before = Some(older_than_epoch);
+
+        let epoch_policy = &*self.epoch_policy;
+        let querier_groups = &self.querier_groups;
+        let is_pruned = |filter_id: &FilterId<Q::EpochId, Q::Uri>| {
+            is_filter_pruned(
+                epoch_policy,
+                querier_groups,
+                filter_id,
+                &older_than_epoch,
+            )
+        };
+
+        let n_pruned = self.filter_storage.prune(&is_pruned)?;
+        debug!(
+            "Pruned {n_pruned} filters older than epoch {older_than_epoch:?}"
+        );
+        Ok(n_pruned)
+    }
+
+    /// Whether the filters for the given epoch have been pruned.
+    pub fn is_pruned(&self, epoch_id: &Q::EpochId) -> bool {
+        self.pruned_before
+            .is_some_and(|pruned_before| *epoch_id < pruned_before)
+    }
+
+    /// Whether the given filter has been pruned, see `is_filter_pruned`.
+    pub fn is_filter_pruned(
+        &self,
+        filter_id: &FilterId<Q::EpochId, Q::Uri>,
+    ) -> bool {
+        self.pruned_before.is_some_and(|pruned_before| {
+            is_filter_pruned(
+                &*self.epoch_policy,
+                &self.querier_groups,
+                filter_id,
+                &pruned_before,
+            )
+        })
+    }
+
+    /// Whether all the base epochs covered by the epoch `epoch_id` of
+    /// `querier_uri` have been pruned.
+    pub fn is_querier_epoch_pruned(
+        &self,
+        querier_uri: &Q::Uri,
+        epoch_id: &Q::EpochId,
+    ) -> bool {
+        self.epoch_policy
+            .base_epochs(querier_uri, epoch_id)
+            .iter()
+            .all(|base_epoch| self.is_pruned(base_epoch))
+    }
+
     /// Computes a report for the given report request.
     /// This function follows `compute_attribution_report` from the Cookie
     /// Monster Algorithm (https://arxiv.org/pdf/2405.16719, Code Listing 1)
+    ///
+    /// Multi-beneficiary requests charge the per-querier filters of each of
+    /// their queriers, and the other filters once. An epoch is dropped if any
+    /// of these filters is out of budget, so the report is the same for all
+    /// the queriers, and can be delivered to each of them.
     pub fn compute_report(
+        &mut self,
+        request: &Q,
+        relevant_events: RelevantEvents<Q::Event>,
+    ) -> Result<PdsReport<Q>, ERR> {
+        let (report, _) =
+            self.compute_report_with_deductions(request, relevant_events)?;
+        Ok(report)
+    }
+
+    /// Same as `compute_report`, but also returns the budget deducted from
+    /// each filter, e.g. to refund it if the report is never released.
+    #[allow(clippy::type_complexity)]
+    pub fn compute_report_with_deductions(
         &mut self,
         request: &Q,
         // mutable, as we will drop out-of-budget epochs from it
         mut relevant_events: RelevantEvents<Q::Event>,
-    ) -> Result<PdsReport<Q>, ERR> {
+    ) -> Result<
+        (
+            PdsReport<Q>,
+            Vec<(FilterId<Q::EpochId, Q::Uri>, PureDPBudget)>,
+        ),
+        ERR,
+    > {
         debug!("Computing report for request {request:?}");
+        let start = Instant::now();
 
-        let uris = request.report_uris();
+        self.check_beneficiaries(request)?;
+        Self::drop_unreadable_events(request, &mut relevant_events);
 
-        // Check if this is a multi-beneficiary query, which we don't support
-        // yet
-        if uris.querier_uris.len() > 1 {
-            unimplemented!("Multi-beneficiary queries");
-        }
+        let epochs = unique_epochs(request.epoch_ids());
 
-        let epochs = request.epoch_ids();
-        let num_epochs = epochs.len();
+        // Keep the events of all the epochs to explain the report, before
+        // dropping any.
+        #[cfg(feature = "experimental")]
+        let traced_events =
+            self.attribution_trace.then(|| relevant_events.clone());
+
+        // Filters for pruned epochs are gone, so we can't account for them
+        // anymore. Drop their events without any filter consumption.
+        for epoch_id in &epochs {
+            if self.is_request_epoch_pruned(request.report_uris(), *epoch_id) {
+                relevant_events.drop_epoch(epoch_id);
+            }
+        }
 
         // Compute the raw report, useful for debugging and accounting.
         let unfiltered_report = request.compute_report(&relevant_events);
 
-        // Browse epochs in the attribution window
-        let mut oob_filters = vec![];
-        for epoch_id in epochs {
-            // Step 1. Get relevant events for the current epoch `epoch_id`.
-            let epoch_relevant_events = relevant_events.for_epoch(&epoch_id);
-
-            // Step 2. Compute individual loss for current epoch.
-            let individual_privacy_loss = compute_epoch_loss(
-                request,
-                epoch_relevant_events,
-                &unfiltered_report,
-                num_epochs,
-            );
+        // Steps 1 to 3. Compute the individual and device-epoch-source losses
+        // of each epoch in the attribution window. Pruned epochs have no
+        // events left, they are skipped so we don't recreate their filters.
+        let epoch_losses = self.epoch_losses(
+            request,
+            &relevant_events,
+            &unfiltered_report,
+            epochs,
+        );
+        let epochs_with_events = epoch_losses
+            .iter()
+            .filter(|(_, losses)| losses.has_events)
+            .map(|(epoch_id, _)| *epoch_id)
+            .collect::<Vec<_>>();
 
-            // Step 3. Compute device-epoch-source losses.
-            let source_losses = compute_epoch_source_losses(
-                request,
-                relevant_events.sources_for_epoch(&epoch_id),
-                &unfiltered_report,
-                num_epochs,
-            );
+        // The losses are discounted for the sampling, so the filtered report
+        // must only see the sampled events.
+        if let Some(sampling) = request.event_sampling() {
+            sampling.sample(&mut relevant_events, &mut self.sampling_rng);
+        }
 
-            // Step 4. Try to consume budget from current epoch, drop events if
-            // OOB. Two phase commit.
+        // Step 4. Try to consume budget from each epoch, drop events if OOB.
+        // Two phase commit.
+        let mut oob_filters = vec![];
+        let mut oob_epochs = vec![];
+        let mut in_budget_epochs = vec![];
+        let request_epochs = epoch_losses
+            .iter()
+            .map(|(epoch_id, _)| *epoch_id)
+            .collect::<Vec<_>>();
+        for (epoch_id, losses) in &epoch_losses {
+            let _span = timed_span!("epoch_accounting", epoch = ?epoch_id);
             let filters_to_consume = self.filters_to_consume(
-                epoch_id,
-                &individual_privacy_loss,
-                &source_losses,
+                *epoch_id,
+                &losses.loss,
+                &losses.source_losses,
                 request.report_uris(),
             );
+            for filter_id in filters_to_consume.keys() {
+                self.settle_repayment(filter_id)?;
+            }
 
-            // Phase 1: dry run.
-            let check_status = self.deduct_budget(
+            // Phase 1: dry run. Epochs have disjoint filters, so they can be
+            // checked independently before consuming anything.
+            let mut check_status = self.deduct_budget(
                 &filters_to_consume,
                 true, // dry run
             )?;
-
-            match check_status {
-                PdsFilterStatus::Continue => {
-                    // Phase 2: Consume the budget
-                    let consume_status = self.deduct_budget(
+            if let PdsFilterStatus::OutOfBudget(filters) = &check_status {
+                if self.try_borrow(
+                    &filters_to_consume,
+                    filters,
+                    &request_epochs,
+                )? {
+                    check_status = self.deduct_budget(
                         &filters_to_consume,
-                        false, // actually consume
+                        true, // dry run
                     )?;
+                }
+            }
 
-                    if consume_status != PdsFilterStatus::Continue {
-                        panic!("ERR: Phase 2 failed unexpectedly wtih status {consume_status:?} after Phase 1 succeeded");
-                    }
+            match check_status {
+                PdsFilterStatus::Continue => {
+                    in_budget_epochs.push(filters_to_consume);
                 }
 
                 PdsFilterStatus::OutOfBudget(mut filters) => {
                     // Not enough budget, drop events without any filter
                     // consumption
-                    relevant_events.drop_epoch(&epoch_id);
+                    relevant_events.drop_epoch(epoch_id);
 
                     // Keep track of why we dropped this epoch
                     oob_filters.append(&mut filters);
+                    oob_epochs.push(*epoch_id);
                 }
             }
         }
 
+        // Phase 2: consume the budget of all the epochs in budget, in a single
+        // pass. Rolled back if any filter fails.
+        let mut deductions = vec![];
+        for filters_to_consume in in_budget_epochs {
+            self.commit_deductions(&filters_to_consume, &mut deductions)?;
+        }
+
         debug!(
             "Relevant events after filtering OOB epochs: {relevant_events:?}"
         );
 
         // Now that we've dropped OOB epochs, we can compute the final report.
-        let filtered_report = request.compute_report(&relevant_events);
+        let mut filtered_report = request.compute_report(&relevant_events);
         debug!("Filtered report: {filtered_report:?}");
+        let mut status = ReportStatus::from_dropped_epochs(
+            oob_epochs.clone(),
+            &epochs_with_events,
+        );
+
+        // If every epoch with events was OOB, the report would be null. Fall
+        // back to a local-DP report if enabled, since it only depends on the
+        // OOB epochs.
+        if !oob_epochs.is_empty()
+            && oob_epochs.len() == epochs_with_events.len()
+        {
+            if let Some(ldp_report) = self.compute_ldp_report(
+                request,
+                &unfiltered_report,
+                &oob_epochs,
+                &mut oob_filters,
+                &mut deductions,
+            )? {
+                filtered_report = ldp_report;
+                status = ReportStatus::PartiallyFiltered {
+                    dropped_epochs: oob_epochs.clone(),
+                };
+            }
+        }
 
+        self.observer
+            .on_report_computed(&oob_filters, start.elapsed());
         #[cfg(feature = "experimental")]
         let report_with_metadata = PdsReport {
             filtered_report,
             unfiltered_report,
             oob_filters,
+            status,
+            retry_advice: None,
+            attribution_trace: traced_events.map(|traced_events| {
+                self.build_attribution_trace(
+                    request,
+                    &traced_events,
+                    &oob_epochs,
+                )
+            }),
         };
         #[cfg(not(feature = "experimental"))]
         let report_with_metadata = PdsReport {
             filtered_report,
+            status,
             ..Default::default()
         };
 
-        Ok(report_with_metadata)
+        Ok((report_with_metadata, deductions))
+    }
+
+    /// Computes the losses of each epoch that is not pruned, in parallel with
+    /// the `parallel` feature. Accounting against the filters stays
+    /// sequential.
+    fn epoch_losses(
+        &self,
+        request: &Q,
+        relevant_events: &RelevantEvents<Q::Event>,
+        unfiltered_report: &Q::Report,
+        epochs: Vec<Q::EpochId>,
+    ) -> Vec<(Q::EpochId, EpochLosses<Q::Uri>)> {
+        let _span = timed_span!("epoch_losses", n_epochs = epochs.len());
+        let num_epochs = epochs.len();
+        let epochs = epochs
+            .into_iter()
+            .filter(|epoch_id| {
+                !self.is_request_epoch_pruned(request.report_uris(), *epoch_id)
+            })
+            .collect::<Vec<_>>();
+
+        let losses = compute_losses_per_epoch(
+            request,
+            relevant_events,
+            unfiltered_report,
+            &epochs,
+            num_epochs,
+        );
+        epochs.into_iter().zip(losses).collect()
+    }
+
+    /// Checks that the queriers of a multi-beneficiary request share the
+    /// epoch scheme of the first querier, in which the Global filters and
+    /// quotas are charged.
+    pub fn check_beneficiaries(&self, request: &Q) -> Result<(), PdsError> {
+        let querier_uris = &request.report_uris().querier_uris;
+        let Some((first_querier, other_queriers)) = querier_uris.split_first()
+        else {
+            return Ok(());
+        };
+        for epoch_id in unique_epochs(request.epoch_ids()) {
+            let base_epochs =
+                self.epoch_policy.base_epochs(first_querier, &epoch_id);
+            for querier_uri in other_queriers {
+                if self.epoch_policy.base_epochs(querier_uri, &epoch_id)
+                    != base_epochs
+                {
+                    return Err(PdsError::InvalidRequest(format!(
+                        "querier {querier_uri:?} doesn't share the epochs of querier {first_querier:?}"
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the events that some querier of a multi-beneficiary request
+    /// can't read, so that the report delivered to a querier never depends
+    /// on the events of another one, see
+    /// https://github.com/columbia/pdslib/issues/71.
+    fn drop_unreadable_events(
+        request: &Q,
+        relevant_events: &mut RelevantEvents<Q::Event>,
+    ) {
+        let querier_uris = &request.report_uris().querier_uris;
+        if querier_uris.len() > 1 {
+            relevant_events.retain(|event| {
+                querier_uris.iter().all(|querier_uri| {
+                    event.event_uris().querier_uris.contains(querier_uri)
+                })
+            });
+        }
+    }
+
+    /// Dry-runs the accounting of `compute_report`, without consuming any
+    /// budget or computing a report. Filter headroom is coarse, so that the
+    /// result doesn't leak exact remaining budgets.
+    #[allow(clippy::type_complexity)]
+    pub fn preflight(
+        &mut self,
+        request: &Q,
+        mut relevant_events: RelevantEvents<Q::Event>,
+    ) -> Result<PreflightResult<FilterId<Q::EpochId, Q::Uri>>, ERR> {
+        self.check_beneficiaries(request)?;
+        Self::drop_unreadable_events(request, &mut relevant_events);
+
+        let epochs = unique_epochs(request.epoch_ids());
+        let unfiltered_report = request.compute_report(&relevant_events);
+
+        let mut headroom = vec![];
+        let epoch_losses = self.epoch_losses(
+            request,
+            &relevant_events,
+            &unfiltered_report,
+            epochs,
+        );
+        for (epoch_id, losses) in &epoch_losses {
+            let filters_to_consume = self.filters_to_consume(
+                *epoch_id,
+                &losses.loss,
+                &losses.source_losses,
+                request.report_uris(),
+            );
+
+            for (filter_id, loss) in filters_to_consume {
+                let filter_headroom = self.headroom(&filter_id, loss)?;
+                headroom.push((filter_id, filter_headroom));
+            }
+        }
+
+        headroom.sort_by_key(|(_, headroom)| *headroom);
+        let answerable = headroom
+            .iter()
+            .all(|(_, headroom)| *headroom != Headroom::Exhausted);
+        Ok(PreflightResult {
+            answerable,
+            headroom,
+        })
+    }
+
+    /// Runs the attribution and the accounting of `compute_report` without
+    /// consuming any budget or notifying the observer, e.g. to debug the
+    /// utility of a request. The filtered report is the one
+    /// `compute_report` would currently release, ignoring the local-DP
+    /// fallback.
+    #[cfg(feature = "experimental")]
+    pub fn compute_report_dry_run(
+        &mut self,
+        request: &Q,
+        mut relevant_events: RelevantEvents<Q::Event>,
+    ) -> Result<PdsReport<Q>, ERR> {
+        self.check_beneficiaries(request)?;
+        Self::drop_unreadable_events(request, &mut relevant_events);
+
+        let epochs = unique_epochs(request.epoch_ids());
+        let traced_events =
+            self.attribution_trace.then(|| relevant_events.clone());
+        for epoch_id in &epochs {
+            if self.is_request_epoch_pruned(request.report_uris(), *epoch_id) {
+                relevant_events.drop_epoch(epoch_id);
+            }
+        }
+        let unfiltered_report = request.compute_report(&relevant_events);
+
+        let mut oob_filters = vec![];
+        let mut oob_epochs = vec![];
+        let epoch_losses = self.epoch_losses(
+            request,
+            &relevant_events,
+            &unfiltered_report,
+            epochs,
+        );
+        if let Some(sampling) = request.event_sampling() {
+            sampling.sample(&mut relevant_events, &mut self.sampling_rng);
+        }
+        for (epoch_id, losses) in &epoch_losses {
+            let filters_to_consume = self.filters_to_consume(
+                *epoch_id,
+                &losses.loss,
+                &losses.source_losses,
+                request.report_uris(),
+            );
+
+            // Epochs have disjoint filters, so they can be checked
+            // independently.
+            let mut epoch_oob_filters = vec![];
+            for (filter_id, loss) in filters_to_consume {
+                if self.filter_storage.can_consume(&filter_id, loss)?
+                    == FilterStatus::OutOfBudget
+                {
+                    epoch_oob_filters.push(filter_id);
+                }
+            }
+            if !epoch_oob_filters.is_empty() {
+                relevant_events.drop_epoch(epoch_id);
+                oob_filters.append(&mut epoch_oob_filters);
+                oob_epochs.push(*epoch_id);
+            }
+        }
+
+        let epochs_with_events = epoch_losses
+            .iter()
+            .filter(|(_, losses)| losses.has_events)
+            .map(|(epoch_id, _)| *epoch_id)
+            .collect::<Vec<_>>();
+        let filtered_report = request.compute_report(&relevant_events);
+        Ok(PdsReport {
+            filtered_report,
+            unfiltered_report,
+            retry_advice: None,
+            oob_filters,
+            status: ReportStatus::from_dropped_epochs(
+                oob_epochs.clone(),
+                &epochs_with_events,
+            ),
+            attribution_trace: traced_events.map(|traced_events| {
+                self.build_attribution_trace(
+                    request,
+                    &traced_events,
+                    &oob_epochs,
+                )
+            }),
+        })
+    }
+
+    /// Explains the report computed from `relevant_events`, the events of
+    /// the requested epochs before any was dropped. Events rejected by the
+    /// selector are never seen by the core, the PDS adds them.
+    #[cfg(feature = "experimental")]
+    fn build_attribution_trace(
+        &self,
+        request: &Q,
+        relevant_events: &RelevantEvents<Q::Event>,
+        oob_epochs: &[Q::EpochId],
+    ) -> AttributionTrace<Q::Event> {
+        let mut trace = AttributionTrace::default();
+        for epoch_id in unique_epochs(request.epoch_ids()) {
+            let is_pruned =
+                self.is_request_epoch_pruned(request.report_uris(), epoch_id);
+            let is_oob = oob_epochs.contains(&epoch_id);
+            for event in relevant_events.for_epoch(&epoch_id) {
+                let rejection = if is_pruned {
+                    Some(RejectionReason::EpochPruned)
+                } else if !request.is_event_in_range(event) {
+                    Some(RejectionReason::BucketOutOfRange)
+                } else if is_oob {
+                    Some(RejectionReason::EpochOutOfBudget)
+                } else {
+                    None
+                };
+                trace.candidates.push(CandidateEvent {
+                    event: event.clone(),
+                    rejection,
+                });
+            }
+        }
+        trace
+    }
+
+    /// Number of times `loss` could be consumed from the filter, bucketed.
+    fn headroom(
+        &mut self,
+        filter_id: &FilterId<Q::EpochId, Q::Uri>,
+        loss: &PureDPBudget,
+    ) -> Result<Headroom, ERR> {
+        let thresholds = [
+            (MANY_REQUESTS, Headroom::Many),
+            (2, Headroom::Few),
+            (1, Headroom::Single),
+        ];
+        for (n_requests, headroom) in thresholds {
+            let budget = loss.scale(f64::from(n_requests));
+            if self.filter_storage.can_consume(filter_id, &budget)?
+                == FilterStatus::Continue
+            {
+                return Ok(headroom);
+            }
+        }
+        Ok(Headroom::Exhausted)
+    }
+
+    /// Randomizes the unfiltered report with the local-DP fallback, if
+    /// enabled and if the `Ldp` filters of all the OOB epochs have enough
+    /// budget. Otherwise, returns None and records the OOB `Ldp` filters.
+    #[allow(clippy::type_complexity)]
+    fn compute_ldp_report(
+        &mut self,
+        request: &Q,
+        unfiltered_report: &R,
+        oob_epochs: &[Q::EpochId],
+        oob_filters: &mut Vec<FilterId<Q::EpochId, Q::Uri>>,
+        deductions: &mut Vec<(FilterId<Q::EpochId, Q::Uri>, PureDPBudget)>,
+    ) -> Result<Option<R>, ERR> {
+        let Some(epsilon) = self.ldp_fallback.as_ref().map(|ldp| ldp.epsilon)
+        else {
+            return Ok(None);
+        };
+
+        // The randomized report depends on the events of all the OOB epochs,
+        // so each of them pays for it. Two phase commit.
+        let filters_to_consume = oob_epochs
+            .iter()
+            .flat_map(|epoch_id| {
+                self.base_epochs(request.report_uris(), *epoch_id)
+            })
+            .map(|base_epoch| (FilterId::Ldp(base_epoch), &epsilon))
+            .collect::<HashMap<_, _>>();
+        match self.deduct_budget(&filters_to_consume, true)? {
+            PdsFilterStatus::Continue => {
+                self.commit_deductions(&filters_to_consume, deductions)?;
+            }
+            PdsFilterStatus::OutOfBudget(mut filters) => {
+                oob_filters.append(&mut filters);
+                return Ok(None);
+            }
+        }
+
+        let ldp_report = self.ldp_fallback.as_mut().map(|ldp| {
+            ldp.mechanism.randomize(request, unfiltered_report, epsilon)
+        });
+        debug!("Local-DP fallback report: {ldp_report:?}");
+        Ok(ldp_report)
     }
 
     /// Calculate how much privacy to deduct from which filters,
-    /// for the given epoch and losses.
+    /// for the given epoch, in the epoch scheme of the querier, and losses.
     pub fn filters_to_consume<'a>(
         &self,
         epoch_id: Q::EpochId,
@@ -172,33 +831,279 @@ where
         source_losses: &'a HashMap<Q::Uri, FS::Budget>,
         uris: &ReportRequestUris<Q::Uri>,
     ) -> HashMap<FilterId<Q::EpochId, Q::Uri>, &'a PureDPBudget> {
-        // Build the filter IDs for PerQuerier, Global and TriggerQuota
+        // Build the filter IDs for PerQuerier and CampaignQuota, in the epoch
+        // scheme of the querier. Queriers of a group share their PerQuerier
+        // filter.
         let mut device_epoch_filter_ids = Vec::new();
         for query_uri in &uris.querier_uris {
-            device_epoch_filter_ids
-                .push(FilterId::PerQuerier(epoch_id, query_uri.clone()));
+            device_epoch_filter_ids.push(
+                self.querier_groups.per_querier_filter(epoch_id, query_uri),
+            );
+            if let Some(campaign_id) = uris.campaign_id {
+                device_epoch_filter_ids.push(FilterId::CampaignQuota(
+                    epoch_id,
+                    query_uri.clone(),
+                    campaign_id,
+                ));
+            }
         }
-        device_epoch_filter_ids
-            .push(FilterId::TriggerQuota(epoch_id, uris.trigger_uri.clone()));
-        device_epoch_filter_ids.push(FilterId::Global(epoch_id));
 
-        // PerQuerier, Global and TriggerQuota all have the same device-epoch
-        // level loss
+        // Build the filter IDs for Global and TriggerQuota, for each base
+        // epoch covered by the requested epoch. Exemptions only ever skip
+        // quotas.
+        let base_epochs = self.base_epochs(uris, epoch_id);
+        let is_trigger_exempt =
+            self.quota_exemptions.is_exempt(&uris.trigger_uri, uris);
+        for base_epoch in &base_epochs {
+            if !is_trigger_exempt {
+                device_epoch_filter_ids.push(FilterId::TriggerQuota(
+                    *base_epoch,
+                    uris.trigger_uri.clone(),
+                ));
+            }
+            device_epoch_filter_ids.push(FilterId::Global(*base_epoch));
+        }
+
+        // PerQuerier, CampaignQuota, Global and TriggerQuota all have the same
+        // device-epoch level loss
         let mut filters_to_consume = HashMap::new();
         for filter_id in device_epoch_filter_ids {
             filters_to_consume.insert(filter_id, loss);
         }
 
         // Add the SourceQuota filters with their own device-epoch-source level
-        // loss
-        for (source, loss) in source_losses {
-            let fid = FilterId::SourceQuota(epoch_id, source.clone());
-            filters_to_consume.insert(fid, loss);
+        // loss, and the SourceTriggerQuota filters with the same loss if
+        // enabled. Pairs are exempt if any of their parties is.
+        let source_trigger_quotas =
+            self.filter_storage.capacities().source_trigger_quotas();
+        for base_epoch in base_epochs {
+            for (source, loss) in source_losses {
+                if self.quota_exemptions.is_exempt(source, uris) {
+                    continue;
+                }
+                let fid = FilterId::SourceQuota(base_epoch, source.clone());
+                filters_to_consume.insert(fid, loss);
+                if source_trigger_quotas && !is_trigger_exempt {
+                    let fid = FilterId::SourceTriggerQuota(
+                        base_epoch,
+                        source.clone(),
+                        uris.trigger_uri.clone(),
+                    );
+                    filters_to_consume.insert(fid, loss);
+                }
+            }
         }
 
         filters_to_consume
     }
 
+    /// Deducts the budget borrowed from a PerQuerier filter from its
+    /// capacity, if it was not initialized yet when it lent it.
+    pub fn settle_repayment(
+        &mut self,
+        filter_id: &FilterId<Q::EpochId, Q::Uri>,
+    ) -> Result<(), ERR> {
+        let Some(repayment) = self.filter_storage.take_repayment(filter_id)?
+        else {
+            return Ok(());
+        };
+        let capacity = self.filter_storage.capacities().capacity(filter_id)?;
+        let capacity = (capacity - repayment).max(0.0);
+        debug!("Repaying {repayment} on {filter_id:?}, capacity {capacity}");
+        self.filter_storage
+            .edit_filter_or_new(filter_id, |filter| {
+                filter.tighten_capacity(&capacity)
+            })?;
+        Ok(())
+    }
+
+    /// Rolls the unused budget of the filters of the epochs that are about
+    /// to be pruned over to the next epoch, see `CarryoverPolicy`. Older
+    /// epochs go first, so that budget carried into an epoch that is pruned
+    /// too can be carried further, within the cap.
+    #[cfg(feature = "experimental")]
+    fn carry_over(&mut self, older_than_epoch: Q::EpochId) -> Result<(), ERR> {
+        let Some(policy) = self.carryover_policy else {
+            return Ok(());
+        };
+
+        let is_pruned = |core: &Self, filter_id: &FilterId<_, _>| {
+            is_filter_pruned(
+                &*core.epoch_policy,
+                &core.querier_groups,
+                filter_id,
+                &older_than_epoch,
+            )
+        };
+        let mut pending = BTreeMap::<_, Vec<_>>::new();
+        for filter_id in self.accounting_stats.filter_ids() {
+            if is_pruned(self, filter_id) && !self.is_filter_pruned(filter_id) {
+                let epoch_id = *filter_id.epoch_id();
+                pending.entry(epoch_id).or_default().push(filter_id.clone());
+            }
+        }
+
+        while let Some((epoch_id, filter_ids)) = pending.pop_first() {
+            let next_epoch = (policy.next_epoch)(&epoch_id);
+            for filter_id in filter_ids {
+                let Some(filter) =
+                    self.filter_storage.get_filter(&filter_id)?
+                else {
+                    continue;
+                };
+                let unused = filter.remaining_budget()?;
+                if unused.is_infinite() {
+                    continue;
+                }
+
+                let next = filter_id.class().filter_id(next_epoch);
+                let carried_so_far =
+                    self.filter_storage.get_carryover(&next)?.unwrap_or(0.0);
+                let carried = (policy.fraction * unused.max(0.0))
+                    .min(policy.max_carryover - carried_so_far);
+                if carried <= 0.0 {
+                    continue;
+                }
+
+                debug!(
+                    "Carrying {carried} over from {filter_id:?} to {next:?}"
+                );
+                self.settle_repayment(&next)?;
+                self.filter_storage.edit_filter_or_new(&next, |filter| {
+                    filter.raise_capacity(&carried)
+                })?;
+                self.filter_storage
+                    .set_carryover(&next, carried_so_far + carried)?;
+                if is_pruned(self, &next) {
+                    let next_filter_ids =
+                        pending.entry(next_epoch).or_default();
+                    if !next_filter_ids.contains(&next) {
+                        next_filter_ids.push(next);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Borrows the loss of out-of-budget PerQuerier filters from the next
+    /// epoch, see `BorrowingPolicy`. Borrows nothing and returns false unless
+    /// every out-of-budget filter can borrow enough. Epochs of the request
+    /// can't lend, since they might already have passed their dry run.
+    #[allow(clippy::type_complexity)]
+    fn try_borrow(
+        &mut self,
+        filters_to_consume: &HashMap<
+            FilterId<Q::EpochId, Q::Uri>,
+            &PureDPBudget,
+        >,
+        oob_filters: &[FilterId<Q::EpochId, Q::Uri>],
+        request_epochs: &[Q::EpochId],
+    ) -> Result<bool, ERR> {
+        let Some(policy) = self.borrowing_policy else {
+            return Ok(false);
+        };
+
+        let mut loans = vec![];
+        for filter_id in oob_filters {
+            let FilterId::PerQuerier(epoch_id, querier_uri) = filter_id else {
+                return Ok(false);
+            };
+            let next_epoch = (policy.next_epoch)(epoch_id);
+            if request_epochs.contains(&next_epoch) {
+                return Ok(false);
+            }
+            let lender = FilterId::PerQuerier(next_epoch, querier_uri.clone());
+            if self.filter_storage.get_filter(&lender)?.is_some() {
+                return Ok(false);
+            }
+
+            let loss = *filters_to_consume[filter_id];
+            let borrowed =
+                self.filter_storage.get_repayment(&lender)?.unwrap_or(0.0);
+            if borrowed + loss > policy.max_borrow {
+                return Ok(false);
+            }
+            let mut filter =
+                self.filter_storage.get_filter_or_new(filter_id)?;
+            filter.raise_capacity(&loss)?;
+            if filter.can_consume(&loss)? != FilterStatus::Continue {
+                return Ok(false);
+            }
+            loans.push((filter_id, filter, lender, loss));
+        }
+
+        for (filter_id, filter, lender, loss) in loans {
+            debug!("{filter_id:?} borrows {loss} from {lender:?}");
+            self.filter_storage.set_filter(filter_id, filter)?;
+            let borrowed =
+                self.filter_storage.get_repayment(&lender)?.unwrap_or(0.0);
+            self.filter_storage
+                .set_repayment(&lender, borrowed + loss)?;
+        }
+        Ok(true)
+    }
+
+    /// Phase 2 of a two-phase commit: consumes the losses that passed phase
+    /// 1, and appends them to `deductions`, the deductions already made for
+    /// the same request. Phase 2 can still fail, e.g. on a storage error or
+    /// if a filter changed since phase 1. In that case, all the `deductions`
+    /// are rolled back and the error is returned, so that a failed request
+    /// leaves the filters as they were.
+    #[allow(clippy::type_complexity)]
+    pub fn commit_deductions(
+        &mut self,
+        filters_to_consume: &HashMap<
+            FilterId<Q::EpochId, Q::Uri>,
+            &PureDPBudget,
+        >,
+        deductions: &mut Vec<(FilterId<Q::EpochId, Q::Uri>, PureDPBudget)>,
+    ) -> Result<(), ERR> {
+        for (filter_id, loss) in filters_to_consume {
+            let error: ERR = match self
+                .filter_storage
+                .try_consume(filter_id, loss)
+            {
+                Ok(FilterStatus::Continue) => {
+                    self.observer.on_budget_deducted(filter_id, loss);
+                    self.accounting_stats.record_deduction(filter_id, **loss);
+                    deductions.push((filter_id.clone(), **loss));
+                    continue;
+                }
+                Ok(FilterStatus::OutOfBudget) => {
+                    self.observer.on_filter_oob(filter_id);
+                    PdsError::CapacityExceeded(format!(
+                        "Phase 2 failed for filter {filter_id:?} after Phase 1 succeeded"
+                    ))
+                    .into()
+                }
+                Err(error) => error.into(),
+            };
+            self.roll_back(take(deductions));
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    /// Refunds the deductions of a request that failed before its report
+    /// was released, which is sound since the budget never influenced any
+    /// output. Refunds are best effort: budget that can't be refunded stays
+    /// consumed, which wastes it but never overspends.
+    #[allow(clippy::type_complexity)]
+    pub fn roll_back(
+        &mut self,
+        deductions: Vec<(FilterId<Q::EpochId, Q::Uri>, PureDPBudget)>,
+    ) {
+        for (filter_id, loss) in deductions {
+            match self.filter_storage.refund(&filter_id, &loss) {
+                Ok(()) => self.accounting_stats.record_refund(&filter_id, loss),
+                Err(_) => {
+                    warn!("Couldn't refund {loss} to {filter_id:?}, it stays consumed")
+                }
+            }
+        }
+    }
+
     /// Deduct the privacy loss from the various filters.
     #[allow(clippy::type_complexity)]
     pub fn deduct_budget(
@@ -209,6 +1114,11 @@ where
         >,
         dry_run: bool,
     ) -> Result<PdsFilterStatus<FilterId<Q::EpochId, Q::Uri>>, ERR> {
+        let _span = timed_span!(
+            "deduct_budget",
+            dry_run,
+            n_filters = filters_to_consume.len()
+        );
         // Try to consume the privacy loss from the filters
         let mut oob_filters = vec![];
         for (fid, loss) in filters_to_consume {
@@ -218,7 +1128,11 @@ where
             };
 
             if filter_status == FilterStatus::OutOfBudget {
+                self.observer.on_filter_oob(fid);
                 oob_filters.push(fid.clone());
+            } else if !dry_run {
+                self.observer.on_budget_deducted(fid, loss);
+                self.accounting_stats.record_deduction(fid, **loss);
             }
         }
 
@@ -1,33 +1,54 @@
 use std::vec;
 
 use log::{debug, warn};
+use serde::{
+    ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer,
+};
 
 use super::{
-    accounting::compute_epoch_loss,
-    private_data_service::PdsReport,
-    quotas::{FilterId, PdsFilterStatus},
+    accounting::compute_epoch_loss_with_noise_scale,
+    private_data_service::{NullReason, PdsReport, ReportStatus},
+    quotas::{FilterId, PdsFilterStatus, QuerierGroups},
 };
 use crate::{
     budget::{
         pure_dp_filter::PureDPBudget,
         traits::{FilterStatus, FilterStorage},
     },
+    error::PdsError,
     events::{
         ppa_event::PpaEvent, relevant_events::RelevantEvents, traits::Uri,
     },
     mechanisms::NoiseScale,
     pds::core::PrivateDataServiceCore,
     queries::{
+        epoch_selection::unique_epochs,
         histogram::HistogramRequest,
         ppa_histogram::{
-            PpaEpochId, PpaHistogramRequest, PpaRelevantEventSelector,
-            RequestedBuckets,
+            PpaBucketKey, PpaEpochId, PpaHistogramRequest,
+            PpaRelevantEventSelector, RequestedBuckets,
         },
         traits::EpochReportRequest,
     },
     util::hashmap::{HashMap, HashSet},
 };
 
+/// Bound on the reports that the beneficiaries of one attribution object can
+/// collectively extract. `measure_conversion` only charges the shared loss
+/// once, and each beneficiary then pays its per-querier loss on its own
+/// filters, so without a cap the information released about the conversion
+/// grows with the number of beneficiaries.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BeneficiaryCap {
+    /// Largest number of beneficiaries that can get a report.
+    pub max_beneficiaries: usize,
+
+    /// Largest sum of the per-querier losses of the beneficiaries, in each
+    /// epoch. Epochs that would exceed it are dropped from the report, as if
+    /// they were out of budget.
+    pub max_total_loss: PureDPBudget,
+}
+
 /// The attribution object that can be used to compute distinct
 /// reports for distinct queriers/beneficiaries, while sharing
 /// global privacy loss.
@@ -46,6 +67,21 @@ pub struct AttributionObject<Q: HistogramRequest> {
     /// again, a null report will be generated instead.
     /// If AllBuckets, all buckets have already been requested.
     pub already_requested_buckets: RequestedBuckets<Q::BucketKey>,
+
+    /// Queriers that already received a report. Each querier can only get
+    /// one report per attribution object.
+    pub issued_queriers: HashSet<Q::Uri>,
+
+    /// Groups of the PDS when the conversion was measured, so that the
+    /// queriers of a group share their per-querier filters.
+    pub querier_groups: QuerierGroups<Q::Uri>,
+
+    /// Cap of the PDS when the conversion was measured. Unbounded if None.
+    pub beneficiary_cap: Option<BeneficiaryCap>,
+
+    /// Per-querier losses charged to the beneficiaries so far, summed in
+    /// each epoch, to enforce `BeneficiaryCap::max_total_loss`.
+    pub beneficiary_losses: HashMap<Q::EpochId, PureDPBudget>,
 }
 
 impl<U, FS, ERR> PrivateDataServiceCore<PpaHistogramRequest<U>, FS, ERR>
@@ -55,7 +91,7 @@ where
         FilterId = FilterId<PpaEpochId, U>,
         Budget = PureDPBudget,
     >,
-    ERR: From<FS::Error>,
+    ERR: From<FS::Error> + From<PdsError>,
 {
     /// Attributes conversion value to events and deduct privacy loss from
     /// global filter and quotas. Creates an `AttributionObject` that can
@@ -70,7 +106,7 @@ where
         mut relevant_events: RelevantEvents<PpaEvent<U>>,
     ) -> Result<AttributionObject<PpaHistogramRequest<U>>, ERR> {
         let uris = request.report_uris();
-        let epochs = request.epoch_ids();
+        let epochs = unique_epochs(request.epoch_ids());
 
         // TODO(later): optimize privacy loss accounting
         if epochs.len() <= 1 {
@@ -78,7 +114,15 @@ where
         }
 
         let mut oob_filters = vec![];
+        let mut deductions = vec![];
         for epoch_id in epochs {
+            // Filters for pruned epochs are gone, drop their events without
+            // any filter consumption.
+            if self.is_pruned(&epoch_id) {
+                relevant_events.drop_epoch(&epoch_id);
+                continue;
+            }
+
             // 2 * a^max / lambda
             let NoiseScale::Laplace(noise_scale) = request.noise_scale();
             let individual_privacy_loss = request
@@ -100,13 +144,14 @@ where
                 uris,
             );
 
-            // Do not consume per-querier, that is done in get_report().
-            for querier_uri in &uris.querier_uris {
-                filters_to_consume.remove(&FilterId::PerQuerier(
-                    epoch_id,
-                    querier_uri.clone(),
-                ));
-            }
+            // Do not consume per-querier and campaign quotas, that is done in
+            // get_report().
+            filters_to_consume.retain(|filter_id, _| {
+                !matches!(
+                    filter_id,
+                    FilterId::PerQuerier(..) | FilterId::CampaignQuota(..)
+                )
+            });
 
             // Phase 1: dry run.
             let check_status = self.deduct_budget(
@@ -116,15 +161,12 @@ where
 
             match check_status {
                 PdsFilterStatus::Continue => {
-                    // Phase 2: Consume the budget
-                    let consume_status = self.deduct_budget(
+                    // Phase 2: Consume the budget. If it fails, the epochs
+                    // consumed so far are rolled back too.
+                    self.commit_deductions(
                         &filters_to_consume,
-                        false, // actually consume
+                        &mut deductions,
                     )?;
-
-                    if consume_status != PdsFilterStatus::Continue {
-                        panic!("ERR: Phase 2 failed unexpectedly wtih status {consume_status:?} after Phase 1 succeeded");
-                    }
                 }
 
                 PdsFilterStatus::OutOfBudget(mut filters) => {
@@ -151,6 +193,10 @@ where
             already_requested_buckets: RequestedBuckets::SpecificBuckets(
                 HashSet::new(),
             ),
+            issued_queriers: HashSet::new(),
+            querier_groups: self.querier_groups.clone(),
+            beneficiary_cap: self.beneficiary_cap,
+            beneficiary_losses: HashMap::new(),
         };
 
         Ok(attribution_object)
@@ -158,7 +204,8 @@ where
 }
 
 impl<U: Uri> AttributionObject<PpaHistogramRequest<U>> {
-    /// Get the report for a specific querier/beneficiary URI.
+    /// Get the report for a specific querier/beneficiary URI, noised with the
+    /// noise scale of the request.
     pub fn get_report<FS>(
         &mut self,
         beneficiary_uri: &U,
@@ -170,17 +217,94 @@ impl<U: Uri> AttributionObject<PpaHistogramRequest<U>> {
             FilterId = FilterId<PpaEpochId, U>,
             Budget = PureDPBudget,
         >,
+        FS::Error: From<PdsError>,
     {
-        let epochs = self.request.epoch_ids();
+        let noise_scale = self.request.noise_scale();
+        self.get_report_with_noise_scale(
+            beneficiary_uri,
+            relevant_event_selector,
+            &noise_scale,
+            filter_storage,
+        )
+    }
+
+    /// Get the report for a specific querier/beneficiary URI, that the querier
+    /// will noise with its own `noise_scale`. The per-querier filter is
+    /// charged for that noise scale. Since the global filter and quotas were
+    /// only charged for the noise scale of the request in
+    /// `measure_conversion`, queriers can't request less noise than that.
+    pub fn get_report_with_noise_scale<FS>(
+        &mut self,
+        beneficiary_uri: &U,
+        relevant_event_selector: &PpaRelevantEventSelector<U>,
+        noise_scale: &NoiseScale,
+        filter_storage: &mut FS,
+    ) -> Result<PdsReport<PpaHistogramRequest<U>>, FS::Error>
+    where
+        FS: FilterStorage<
+            FilterId = FilterId<PpaEpochId, U>,
+            Budget = PureDPBudget,
+        >,
+        FS::Error: From<PdsError>,
+    {
+        let NoiseScale::Laplace(querier_noise_scale) = *noise_scale;
+        let NoiseScale::Laplace(request_noise_scale) =
+            self.request.noise_scale();
+        if querier_noise_scale.is_nan()
+            || querier_noise_scale < request_noise_scale
+        {
+            return Err(PdsError::InvalidRequest(format!(
+                "noise scale {querier_noise_scale} is lower than the noise scale {request_noise_scale} of the measured conversion"
+            ))
+            .into());
+        }
+
+        // The selector must be for the request that was measured, and for one
+        // of its queriers.
+        let request_uris = self.request.report_uris();
+        if relevant_event_selector.report_request_uris != *request_uris {
+            return Err(PdsError::InvalidRequest(format!(
+                "selector URIs {:?} don't match the measured request URIs {request_uris:?}",
+                relevant_event_selector.report_request_uris
+            ))
+            .into());
+        }
+        if !request_uris.querier_uris.contains(beneficiary_uri) {
+            return Err(PdsError::InvalidRequest(format!(
+                "{beneficiary_uri:?} is not a querier of the measured request"
+            ))
+            .into());
+        }
+        if self.issued_queriers.contains(beneficiary_uri) {
+            return Err(PdsError::ReportAlreadyIssued(format!(
+                "{beneficiary_uri:?} already received a report"
+            ))
+            .into());
+        }
+        if let Some(cap) = self.beneficiary_cap {
+            if self.issued_queriers.len() >= cap.max_beneficiaries {
+                return Err(PdsError::ReportAlreadyIssued(format!(
+                    "the attribution object already issued reports to {} beneficiaries, rejecting {beneficiary_uri:?}",
+                    cap.max_beneficiaries
+                ))
+                .into());
+            }
+        }
+        self.issued_queriers.insert(beneficiary_uri.clone());
+
+        let epochs = unique_epochs(self.request.epoch_ids());
         let num_epochs = epochs.len();
 
+        // Restored if the report fails, so that the beneficiary can ask again.
+        let previous_buckets = self.already_requested_buckets.clone();
+
         // if already_requested_buckets is None, all buckets have already
         // been requested
         let RequestedBuckets::SpecificBuckets(already_requested_buckets) =
             &mut self.already_requested_buckets
         else {
             debug!("All buckets have already been requested, returning null report");
-            return Ok(PdsReport::default());
+            return Ok(PdsReport::null(NullReason::BucketsAlreadyRequested));
         };
 
         match &relevant_event_selector.requested_buckets {
@@ -193,27 +317,48 @@ impl<U: Uri> AttributionObject<PpaHistogramRequest<U>> {
                     > 0
                 {
                     debug!("Some requested buckets have already been requested, returning null report");
-                    return Ok(PdsReport::default());
+                    return Ok(PdsReport::null(
+                        NullReason::BucketsAlreadyRequested,
+                    ));
                 }
 
                 // Add the requested buckets to the already requested set
                 already_requested_buckets.extend(requested_buckets);
             }
             RequestedBuckets::AllBuckets => {
+                // Some buckets were already given to other queriers, so they
+                // can't all be requested anymore.
+                if !already_requested_buckets.is_empty() {
+                    debug!("Some buckets have already been requested, returning null report");
+                    return Ok(PdsReport::null(
+                        NullReason::BucketsAlreadyRequested,
+                    ));
+                }
+
                 // Mark all buckets as requested
                 self.already_requested_buckets = RequestedBuckets::AllBuckets;
             }
         }
 
+        // The beneficiary only sees the events that list it as a querier.
+        // Other events can still win the attribution, but their value is
+        // dropped from this report.
+        let is_authorized = |event: &PpaEvent<U>| {
+            event.uris.querier_uris.contains(beneficiary_uri)
+        };
+
         // get the attributed values for the requested events
         // only keep the buckets that are requested
         let mut event_values = HashMap::new();
         for epoch in &epochs {
             for event in self.events.for_epoch(epoch) {
-                if relevant_event_selector
-                    .requested_buckets
-                    .contains(&event.histogram_index)
-                {
+                let is_requested =
+                    self.request.bucket_key(event).is_some_and(|bucket| {
+                        relevant_event_selector
+                            .requested_buckets
+                            .contains(&bucket)
+                    });
+                if is_requested && is_authorized(event) {
                     if let Some(value) = self.event_values.get(event) {
                         event_values.insert(event.clone(), *value);
                     }
@@ -228,34 +373,123 @@ impl<U: Uri> AttributionObject<PpaHistogramRequest<U>> {
             self.request.map_events_to_buckets(&event_values);
 
         let mut oob_filters = vec![];
+        let mut oob_epochs = vec![];
+        let mut epochs_with_events = vec![];
+        let mut charged_filters = vec![];
+        let mut beneficiary_losses = vec![];
         for epoch_id in epochs {
-            let epoch_relevant_events = self.events.for_epoch(&epoch_id);
+            let epoch_relevant_events = self
+                .events
+                .for_epoch(&epoch_id)
+                .iter()
+                .filter(|event| is_authorized(event))
+                .cloned()
+                .collect::<Vec<_>>();
 
-            // Compute per-querier individual loss for current epoch.
-            let individual_privacy_loss = compute_epoch_loss(
+            // Compute per-querier individual loss for current epoch, with
+            // the querier's own noise scale.
+            let individual_privacy_loss = compute_epoch_loss_with_noise_scale(
                 &self.request,
-                epoch_relevant_events,
+                &epoch_relevant_events,
                 &unfiltered_report,
                 num_epochs,
+                noise_scale,
             );
 
-            let filter_id =
-                FilterId::PerQuerier(epoch_id, beneficiary_uri.clone());
-            let filter_status = filter_storage
-                .try_consume(&filter_id, &individual_privacy_loss)?;
+            if !epoch_relevant_events.is_empty() {
+                epochs_with_events.push(epoch_id);
+            }
 
-            if filter_status == FilterStatus::OutOfBudget {
+            let mut filter_ids = vec![self
+                .querier_groups
+                .per_querier_filter(epoch_id, beneficiary_uri)];
+            if let Some(campaign_id) = self.request.report_uris().campaign_id {
+                filter_ids.push(FilterId::CampaignQuota(
+                    epoch_id,
+                    beneficiary_uri.clone(),
+                    campaign_id,
+                ));
+            }
+
+            // Two phase commit, so the per-querier filter and the campaign
+            // quota are consumed together.
+            let mut epoch_oob_filters = vec![];
+            for filter_id in &filter_ids {
+                let filter_status = match filter_storage
+                    .can_consume(filter_id, &individual_privacy_loss)
+                {
+                    Ok(filter_status) => filter_status,
+                    Err(error) => {
+                        self.roll_back_report(
+                            beneficiary_uri,
+                            previous_buckets,
+                            charged_filters,
+                            filter_storage,
+                        );
+                        return Err(error);
+                    }
+                };
+                if filter_status == FilterStatus::OutOfBudget {
+                    epoch_oob_filters.push(filter_id.clone());
+                }
+            }
+            let total_loss = self
+                .beneficiary_losses
+                .get(&epoch_id)
+                .copied()
+                .unwrap_or(0.0)
+                + individual_privacy_loss;
+            let is_capped = self
+                .beneficiary_cap
+                .is_some_and(|cap| total_loss > cap.max_total_loss);
+            if is_capped {
+                debug!("Epoch {epoch_id} exceeds the loss cap of the beneficiaries");
+            }
+
+            if epoch_oob_filters.is_empty() && !is_capped {
+                for filter_id in &filter_ids {
+                    let error = match filter_storage
+                        .try_consume(filter_id, &individual_privacy_loss)
+                    {
+                        Ok(FilterStatus::Continue) => {
+                            charged_filters.push((
+                                filter_id.clone(),
+                                individual_privacy_loss,
+                            ));
+                            continue;
+                        }
+                        Ok(FilterStatus::OutOfBudget) => {
+                            PdsError::CapacityExceeded(format!(
+                                "Phase 2 failed for filter {filter_id:?} after Phase 1 succeeded"
+                            ))
+                            .into()
+                        }
+                        Err(error) => error,
+                    };
+                    self.roll_back_report(
+                        beneficiary_uri,
+                        previous_buckets,
+                        charged_filters,
+                        filter_storage,
+                    );
+                    return Err(error);
+                }
+                beneficiary_losses.push((epoch_id, total_loss));
+            } else {
                 // Not enough budget, drop events without any filter
                 // consumption
-                for event in epoch_relevant_events {
+                for event in &epoch_relevant_events {
                     event_values.remove(event);
                 }
 
                 // Keep track of why we dropped this epoch
-                oob_filters.push(filter_id);
+                oob_filters.append(&mut epoch_oob_filters);
+                oob_epochs.push(epoch_id);
             }
         }
 
+        self.beneficiary_losses.extend(beneficiary_losses);
+
         // Now that we've dropped OOB epochs, we can compute the final report,
         // using the attributed event values precomputed by
         // `measure_conversion`.
@@ -264,10 +498,240 @@ impl<U: Uri> AttributionObject<PpaHistogramRequest<U>> {
         let report = PdsReport {
             filtered_report,
             unfiltered_report,
+            retry_advice: None,
             oob_filters,
+            status: ReportStatus::from_dropped_epochs(
+                oob_epochs,
+                &epochs_with_events,
+            ),
+            attribution_trace: None,
         };
         Ok(report)
     }
+
+    /// Undoes a report that failed, e.g. on a storage error: refunds the
+    /// filters charged so far and gives the beneficiary and its buckets
+    /// back. Refunds are best effort, budget that can't be refunded stays
+    /// consumed.
+    fn roll_back_report<FS>(
+        &mut self,
+        beneficiary_uri: &U,
+        previous_buckets: RequestedBuckets<PpaBucketKey>,
+        charged_filters: Vec<(FilterId<PpaEpochId, U>, PureDPBudget)>,
+        filter_storage: &mut FS,
+    ) where
+        FS: FilterStorage<
+            FilterId = FilterId<PpaEpochId, U>,
+            Budget = PureDPBudget,
+        >,
+    {
+        for (filter_id, loss) in charged_filters {
+            if filter_storage.refund(&filter_id, &loss).is_err() {
+                warn!("Couldn't refund {loss} to {filter_id:?}, it stays consumed");
+            }
+        }
+        self.already_requested_buckets = previous_buckets;
+        self.issued_queriers.remove(beneficiary_uri);
+    }
+}
+
+/// Serialized with its events and the state of the reports issued so far, so
+/// that reports can still be issued after a restart. Fails to serialize if
+/// the request has a `FilterDataPredicate::Custom` predicate.
+impl<U: Uri> Serialize for AttributionObject<PpaHistogramRequest<U>> {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("AttributionObject", 8)?;
+        state.serialize_field("request", &self.request)?;
+        // Events are keys of the attributed values, which can't be map keys
+        // in most formats, so maps are serialized as lists of pairs.
+        state.serialize_field(
+            "events",
+            &self.events.events_per_epoch.iter().collect::<Vec<_>>(),
+        )?;
+        state.serialize_field(
+            "event_values",
+            &self.event_values.iter().collect::<Vec<_>>(),
+        )?;
+        state.serialize_field(
+            "already_requested_buckets",
+            &self.already_requested_buckets,
+        )?;
+        state.serialize_field("issued_queriers", &self.issued_queriers)?;
+        state.serialize_field("querier_groups", &self.querier_groups)?;
+        state.serialize_field("beneficiary_cap", &self.beneficiary_cap)?;
+        state.serialize_field(
+            "beneficiary_losses",
+            &self.beneficiary_losses.iter().collect::<Vec<_>>(),
+        )?;
+        state.end()
+    }
+}
+
+/// Owned counterpart of the serialized `AttributionObject`.
+#[derive(Deserialize)]
+#[serde(rename = "AttributionObject")]
+struct AttributionObjectRecord<U: Uri> {
+    request: PpaHistogramRequest<U>,
+    events: Vec<(PpaEpochId, Vec<PpaEvent<U>>)>,
+    event_values: Vec<(PpaEvent<U>, f64)>,
+    already_requested_buckets: RequestedBuckets<PpaBucketKey>,
+    issued_queriers: HashSet<U>,
+    querier_groups: QuerierGroups<U>,
+
+    // Missing from objects persisted before the cap existed.
+    #[serde(default)]
+    beneficiary_cap: Option<BeneficiaryCap>,
+    #[serde(default)]
+    beneficiary_losses: Vec<(PpaEpochId, PureDPBudget)>,
+}
+
+impl<'de, U> Deserialize<'de> for AttributionObject<PpaHistogramRequest<U>>
+where
+    U: Uri + Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        let record = AttributionObjectRecord::deserialize(deserializer)?;
+        Ok(Self {
+            request: record.request,
+            events: RelevantEvents::from_mapping(
+                record.events.into_iter().collect(),
+            ),
+            event_values: record.event_values.into_iter().collect(),
+            already_requested_buckets: record.already_requested_buckets,
+            issued_queriers: record.issued_queriers,
+            querier_groups: record.querier_groups,
+            beneficiary_cap: record.beneficiary_cap,
+            beneficiary_losses: record.beneficiary_losses.into_iter().collect(),
+        })
+    }
+}
+
+/// Attribution objects of recent conversions, keyed by a conversion ID
+/// chosen by the embedder, with their expiry time in seconds. The shared
+/// loss is paid by `measure_conversion`, so embedders should persist the
+/// store after measuring a conversion and after each report, so that the
+/// remaining queriers can still get their reports after a restart, and
+/// queriers that already got one can't get another. It serializes to a list
+/// of entries.
+pub struct AttributionObjectStore<U: Uri> {
+    objects: HashMap<String, (u64, AttributionObject<PpaHistogramRequest<U>>)>,
+}
+
+/// Attribution object with its conversion ID and expiry, as persisted.
+#[derive(Serialize)]
+struct AttributionObjectEntryRef<'a, U: Uri> {
+    conversion_id: &'a str,
+    expires_at: u64,
+    object: &'a AttributionObject<PpaHistogramRequest<U>>,
+}
+
+/// Owned counterpart of `AttributionObjectEntryRef`.
+#[derive(Deserialize)]
+struct AttributionObjectEntry<U: Uri> {
+    conversion_id: String,
+    expires_at: u64,
+    object: AttributionObject<PpaHistogramRequest<U>>,
+}
+
+impl<U: Uri> Default for AttributionObjectStore<U> {
+    fn default() -> Self {
+        Self {
+            objects: HashMap::new(),
+        }
+    }
+}
+
+impl<U: Uri> AttributionObjectStore<U> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    /// Stores the attribution object of a conversion until `expires_at`,
+    /// replacing any previous object for the same conversion ID.
+    pub fn insert(
+        &mut self,
+        conversion_id: impl Into<String>,
+        object: AttributionObject<PpaHistogramRequest<U>>,
+        expires_at: u64,
+    ) {
+        self.objects
+            .insert(conversion_id.into(), (expires_at, object));
+    }
+
+    /// Attribution object of the conversion, to get the report of a querier,
+    /// if it was stored and has not expired at `now`.
+    pub fn get_mut(
+        &mut self,
+        conversion_id: &str,
+        now: u64,
+    ) -> Option<&mut AttributionObject<PpaHistogramRequest<U>>> {
+        self.objects
+            .get_mut(conversion_id)
+            .filter(|(expires_at, _)| *expires_at > now)
+            .map(|(_, object)| object)
+    }
+
+    /// Removes the attribution object of the conversion, e.g. once all its
+    /// queriers got their reports.
+    pub fn remove(
+        &mut self,
+        conversion_id: &str,
+    ) -> Option<AttributionObject<PpaHistogramRequest<U>>> {
+        self.objects.remove(conversion_id).map(|(_, object)| object)
+    }
+
+    /// Drops the attribution objects that expired at `now`.
+    pub fn remove_expired(&mut self, now: u64) {
+        self.objects.retain(|_, (expires_at, _)| *expires_at > now);
+    }
+}
+
+impl<U: Uri> Serialize for AttributionObjectStore<U> {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.objects.iter().map(
+            |(conversion_id, (expires_at, object))| AttributionObjectEntryRef {
+                conversion_id,
+                expires_at: *expires_at,
+                object,
+            },
+        ))
+    }
+}
+
+impl<'de, U> Deserialize<'de> for AttributionObjectStore<U>
+where
+    U: Uri + Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        let entries =
+            Vec::<AttributionObjectEntry<U>>::deserialize(deserializer)?;
+        Ok(Self {
+            objects: entries
+                .into_iter()
+                .map(|entry| {
+                    (entry.conversion_id, (entry.expires_at, entry.object))
+                })
+                .collect(),
+        })
+    }
 }
 
 #[cfg(test)]
@@ -275,14 +739,18 @@ mod tests {
 
     use super::*;
     use crate::{
-        events::{ppa_event::PpaEvent, traits::EventUris},
+        events::{
+            ppa_event::PpaEvent,
+            traits::{EventUris, RelevantEventSelector},
+        },
         pds::{
             aliases::{PpaFilterStorage, PpaPdsCore},
             quotas::StaticCapacities,
         },
         queries::{
+            builder::PpaHistogramRequestBuilder,
             ppa_histogram::{
-                PpaHistogramConfig, PpaHistogramRequest,
+                FilterDataPredicate, PpaHistogramRequest,
                 PpaRelevantEventSelector,
             },
             traits::ReportRequestUris,
@@ -317,6 +785,7 @@ mod tests {
             trigger_uri: trigger_uri.clone(),
             source_uris: vec![source_uri.clone()],
             querier_uris: querier_uris.clone(),
+            campaign_id: None,
         };
 
         // Register an early event with bucket 1 - this should be overridden by
@@ -328,6 +797,7 @@ mod tests {
             histogram_index: 1, // r1.ex bucket
             uris: event_uris.clone(),
             filter_data: 1,
+            priority: 0,
         };
 
         // The event that should be attributed (latest timestamp in epoch 1)
@@ -340,35 +810,27 @@ mod tests {
             histogram_index: 2, // A bucket that will be kept and read by r2.ex
             uris: event_uris.clone(),
             filter_data: 1,
+            priority: 0,
         };
 
         let events = HashMap::from([(1, vec![early_event, main_event])]);
         let relevant_events = RelevantEvents::from_mapping(events);
 
-        let config = PpaHistogramConfig {
-            start_epoch: 1,
-            end_epoch: 2,
-            attributable_value: 100.0,
-            max_attributable_value: 200.0,
-            requested_epsilon: 1.0,
-            histogram_size: 3,
-        };
+        let attributable_value = 100.0;
 
         let relevant_event_selector = |bucket: u64| PpaRelevantEventSelector {
             report_request_uris: report_request_uris.clone(),
-            is_matching_event: Box::new(|_: u64| true),
+            is_matching_event: FilterDataPredicate::Any,
             requested_buckets: vec![bucket].into(),
+            lookback: None,
         };
 
-        let request = PpaHistogramRequest::new(
-            &config,
-            PpaRelevantEventSelector {
-                report_request_uris: report_request_uris.clone(),
-                is_matching_event: Box::new(|_| true),
-                requested_buckets: vec![1].into(),
-            },
-        )
-        .expect("Failed to create request");
+        let request = PpaHistogramRequestBuilder::new()
+            .epoch_range(1, 2)?
+            .value(attributable_value, 200.0, 1.0)?
+            .uris(report_request_uris.clone())?
+            .selector(FilterDataPredicate::Any, 3, vec![1].into())?
+            .build();
 
         let NoiseScale::Laplace(noise_scale) = request.noise_scale();
 
@@ -406,7 +868,7 @@ mod tests {
         // Intermediary r2 receives the value from the main event
         assert_eq!(
             r2_bins.get(&2),
-            Some(&config.attributable_value),
+            Some(&attributable_value),
             "Incorrect value for r2.ex bucket 3"
         );
 
@@ -443,7 +905,7 @@ mod tests {
 
         // Calculate what would be deducted with vs. without
         // optimization
-        let expected_deduction = 2.0 * config.attributable_value / noise_scale;
+        let expected_deduction = 2.0 * attributable_value / noise_scale;
 
         // Verify deduction is close to single event (cross-report
         // optimization working)
@@ -468,6 +930,7 @@ mod tests {
             histogram_index: 1,
             uris: EventUris::mock(),
             filter_data: 1,
+            priority: 0,
         };
         let event2 = PpaEvent {
             id: 2,
@@ -476,6 +939,7 @@ mod tests {
             histogram_index: 1, // Same bucket as event1
             uris: EventUris::mock(),
             filter_data: 1,
+            priority: 0,
         };
 
         // set epoch 2 PerQuerier filter to be OOB
@@ -486,22 +950,12 @@ mod tests {
             .try_consume(&filter_id, &filter_capacity)?;
 
         // get attribution object
-        let request = PpaHistogramRequest::new(
-            &PpaHistogramConfig {
-                start_epoch: 1,
-                end_epoch: 2,
-                attributable_value: 100.0,
-                max_attributable_value: 200.0,
-                requested_epsilon: 1.0,
-                histogram_size: 3,
-            },
-            PpaRelevantEventSelector {
-                report_request_uris: ReportRequestUris::mock(),
-                is_matching_event: Box::new(|_| true),
-                requested_buckets: vec![1].into(),
-            },
-        )
-        .unwrap();
+        let request = PpaHistogramRequestBuilder::new()
+            .epoch_range(1, 2)?
+            .value(100.0, 200.0, 1.0)?
+            .uris(ReportRequestUris::mock())?
+            .selector(FilterDataPredicate::Any, 3, vec![1].into())?
+            .build();
         let relevant_events = RelevantEvents::from_vec(vec![event1, event2]);
         let mut attr_object =
             pds.measure_conversion(request, relevant_events)?;
@@ -510,8 +964,9 @@ mod tests {
             &querier_uri,
             &PpaRelevantEventSelector {
                 report_request_uris: ReportRequestUris::mock(),
-                is_matching_event: Box::new(|_| true),
+                is_matching_event: FilterDataPredicate::Any,
                 requested_buckets: RequestedBuckets::AllBuckets,
+                lookback: None,
             },
             &mut pds.filter_storage,
         )?;
@@ -526,4 +981,477 @@ mod tests {
 
         Ok(())
     }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_failed_phase_2_restores_state() -> Result<(), anyhow::Error> {
+        use crate::testing::faults::FaultyFilterStorage;
+
+        let capacities = StaticCapacities::mock();
+        let filters =
+            FaultyFilterStorage::<PpaFilterStorage>::new(capacities.clone())?;
+        let mut pds = PpaPdsCore::<_>::new(filters);
+
+        let events = || {
+            let event = |id: u64, epoch_number| PpaEvent {
+                id,
+                timestamp: 100 * id,
+                epoch_number,
+                histogram_index: 1,
+                uris: EventUris::mock(),
+                filter_data: 1,
+                priority: 0,
+            };
+            RelevantEvents::from_vec(vec![event(1, 1), event(2, 2)])
+        };
+        let selector = PpaRelevantEventSelector {
+            report_request_uris: ReportRequestUris::mock(),
+            is_matching_event: FilterDataPredicate::Any,
+            requested_buckets: RequestedBuckets::AllBuckets,
+            lookback: None,
+        };
+        let request = PpaHistogramRequestBuilder::new()
+            .epoch_range(1, 2)?
+            .value(100.0, 200.0, 1.0)?
+            .uris(ReportRequestUris::mock())?
+            .selector(
+                FilterDataPredicate::Any,
+                3,
+                RequestedBuckets::AllBuckets,
+            )?
+            .build();
+
+        // The shared loss is charged to all the epochs or to none of them.
+        pds.filter_storage.fail_writes_to(FilterId::Global(1));
+        assert!(pds.measure_conversion(request.clone(), events()).is_err());
+        pds.filter_storage.clear_faults();
+        for epoch_id in [1, 2] {
+            let filter_id = FilterId::Global(epoch_id);
+            assert_eq!(
+                pds.filter_storage.remaining_budget(&filter_id)?,
+                capacities.global
+            );
+        }
+        let mut attr_object = pds.measure_conversion(request, events())?;
+
+        // Same for the per-querier loss, and the querier can ask again.
+        let querier_uri = ReportRequestUris::mock().querier_uris[0].clone();
+        pds.filter_storage
+            .fail_writes_to(FilterId::PerQuerier(1, querier_uri.clone()));
+        assert!(attr_object
+            .get_report(&querier_uri, &selector, &mut pds.filter_storage)
+            .is_err());
+        pds.filter_storage.clear_faults();
+        for epoch_id in [1, 2] {
+            let filter_id = FilterId::PerQuerier(epoch_id, querier_uri.clone());
+            assert_eq!(
+                pds.filter_storage.remaining_budget(&filter_id)?,
+                capacities.per_querier
+            );
+        }
+        let report = attr_object.get_report(
+            &querier_uri,
+            &selector,
+            &mut pds.filter_storage,
+        )?;
+        assert!(!report.filtered_report.bin_values.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_per_querier_noise_scale() -> Result<(), anyhow::Error> {
+        let capacities = StaticCapacities::mock();
+        let filters = PpaFilterStorage::new(capacities.clone())?;
+        let mut pds = PpaPdsCore::<_>::new(filters);
+
+        let querier_uris = EventUris::mock().querier_uris;
+        let report_request_uris = ReportRequestUris {
+            querier_uris: querier_uris.clone(),
+            ..ReportRequestUris::mock()
+        };
+        let selector = PpaRelevantEventSelector {
+            report_request_uris: report_request_uris.clone(),
+            is_matching_event: FilterDataPredicate::Any,
+            requested_buckets: vec![1].into(),
+            lookback: None,
+        };
+
+        // Noise scale of 1.0 / 0.5 = 2.0 for the measured conversion.
+        let request = PpaHistogramRequestBuilder::new()
+            .epoch_range(1, 1)?
+            .value(1.0, 1.0, 0.5)?
+            .uris(report_request_uris)?
+            .selector(FilterDataPredicate::Any, 3, vec![1].into())?
+            .build();
+        let event = PpaEvent {
+            id: 1,
+            timestamp: 100,
+            epoch_number: 1,
+            histogram_index: 1,
+            uris: EventUris::mock(),
+            filter_data: 1,
+            priority: 0,
+        };
+        let relevant_events = RelevantEvents::from_vec(vec![event]);
+        let mut attr_object =
+            pds.measure_conversion(request, relevant_events)?;
+
+        // Queriers can't ask for less noise than the measured conversion.
+        let result = attr_object.get_report_with_noise_scale(
+            &querier_uris[1],
+            &selector,
+            &NoiseScale::Laplace(1.0),
+            &mut pds.filter_storage,
+        );
+        assert!(matches!(result, Err(PdsError::InvalidRequest(_))));
+        let filter_id = FilterId::PerQuerier(1, querier_uris[1].clone());
+        assert!(pds.filter_storage.get_filter(&filter_id)?.is_none());
+
+        // More noise costs less per-querier budget.
+        let report = attr_object.get_report_with_noise_scale(
+            &querier_uris[0],
+            &selector,
+            &NoiseScale::Laplace(4.0),
+            &mut pds.filter_storage,
+        )?;
+        assert_eq!(report.filtered_report.bin_values.get(&1), Some(&1.0));
+        let filter_id = FilterId::PerQuerier(1, querier_uris[0].clone());
+        let remaining = pds.filter_storage.remaining_budget(&filter_id)?;
+        assert_eq!(capacities.per_querier - remaining, 1.0 / 4.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_beneficiary_authorization() -> Result<(), anyhow::Error> {
+        let filters = PpaFilterStorage::new(StaticCapacities::mock())?;
+        let mut pds = PpaPdsCore::<_>::new(filters);
+
+        let querier_uris = vec!["r1.ex".to_string(), "r2.ex".to_string()];
+        let report_request_uris = ReportRequestUris {
+            querier_uris: querier_uris.clone(),
+            ..ReportRequestUris::mock()
+        };
+        let selector = |requested_buckets: Vec<u64>| PpaRelevantEventSelector {
+            report_request_uris: report_request_uris.clone(),
+            is_matching_event: FilterDataPredicate::Any,
+            requested_buckets: requested_buckets.into(),
+            lookback: None,
+        };
+
+        // The last-touch event can only be read by r1.ex.
+        let event = |id, epoch_number, querier_uris| PpaEvent {
+            id,
+            timestamp: id * 100,
+            epoch_number,
+            histogram_index: id,
+            uris: EventUris {
+                querier_uris,
+                ..EventUris::mock()
+            },
+            filter_data: 1,
+            priority: 0,
+        };
+        let events = || {
+            vec![
+                event(1, 1, querier_uris.clone()),
+                event(2, 2, vec![querier_uris[0].clone()]),
+            ]
+        };
+        assert!(events()
+            .iter()
+            .all(|event| selector(vec![]).is_relevant_event(event)));
+
+        let mut measure_conversion = || -> Result<_, anyhow::Error> {
+            let request = PpaHistogramRequestBuilder::new()
+                .epoch_range(1, 2)?
+                .value(1.0, 1.0, 1.0)?
+                .uris(report_request_uris.clone())?
+                .selector(FilterDataPredicate::Any, 3, vec![2].into())?
+                .build();
+            let events = RelevantEvents::from_vec(events());
+            Ok(pds.measure_conversion(request, events)?)
+        };
+        let mut r1_attr_object = measure_conversion()?;
+        let mut r2_attr_object = measure_conversion()?;
+
+        // r2.ex doesn't see the event, and doesn't pay for its epoch.
+        let report = r2_attr_object.get_report(
+            &querier_uris[1],
+            &selector(vec![2]),
+            &mut pds.filter_storage,
+        )?;
+        assert!(report.filtered_report.bin_values.is_empty());
+        assert!(report.unfiltered_report.bin_values.is_empty());
+        let filter_id = FilterId::PerQuerier(2, querier_uris[1].clone());
+        let remaining = pds.filter_storage.remaining_budget(&filter_id)?;
+        assert_eq!(remaining, 1.0);
+
+        // r1.ex sees it.
+        let report = r1_attr_object.get_report(
+            &querier_uris[0],
+            &selector(vec![2]),
+            &mut pds.filter_storage,
+        )?;
+        assert_eq!(report.filtered_report.bin_values.get(&2), Some(&1.0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_report_validation() -> Result<(), anyhow::Error> {
+        let filters = PpaFilterStorage::new(StaticCapacities::mock())?;
+        let mut pds = PpaPdsCore::<_>::new(filters);
+
+        let querier_uris = EventUris::mock().querier_uris;
+        let report_request_uris = ReportRequestUris {
+            querier_uris: querier_uris.clone(),
+            ..ReportRequestUris::mock()
+        };
+        let selector =
+            |report_request_uris, requested_buckets| PpaRelevantEventSelector {
+                report_request_uris,
+                is_matching_event: FilterDataPredicate::Any,
+                requested_buckets,
+                lookback: None,
+            };
+
+        let request = PpaHistogramRequestBuilder::new()
+            .epoch_range(1, 2)?
+            .value(1.0, 1.0, 1.0)?
+            .uris(report_request_uris.clone())?
+            .selector(FilterDataPredicate::Any, 3, vec![1].into())?
+            .build();
+        let event = PpaEvent {
+            id: 1,
+            timestamp: 100,
+            epoch_number: 1,
+            histogram_index: 1,
+            uris: EventUris::mock(),
+            filter_data: 1,
+            priority: 0,
+        };
+        let mut attr_object = pds.measure_conversion(
+            request,
+            RelevantEvents::from_vec(vec![event]),
+        )?;
+
+        // Selectors must match the measured request.
+        let result = attr_object.get_report(
+            &querier_uris[0],
+            &selector(ReportRequestUris::mock(), vec![1].into()),
+            &mut pds.filter_storage,
+        );
+        assert!(matches!(result, Err(PdsError::InvalidRequest(_))));
+        let result = attr_object.get_report(
+            &"other.com".to_string(),
+            &selector(report_request_uris.clone(), vec![1].into()),
+            &mut pds.filter_storage,
+        );
+        assert!(matches!(result, Err(PdsError::InvalidRequest(_))));
+
+        // Each querier gets a single report.
+        let report = attr_object.get_report(
+            &querier_uris[0],
+            &selector(report_request_uris.clone(), vec![1].into()),
+            &mut pds.filter_storage,
+        )?;
+        assert_eq!(report.filtered_report.bin_values.get(&1), Some(&1.0));
+        let result = attr_object.get_report(
+            &querier_uris[0],
+            &selector(report_request_uris.clone(), vec![2].into()),
+            &mut pds.filter_storage,
+        );
+        assert!(matches!(result, Err(PdsError::ReportAlreadyIssued(_))));
+
+        // All buckets can't be requested once some were given out.
+        let report = attr_object.get_report(
+            &querier_uris[1],
+            &selector(report_request_uris, RequestedBuckets::AllBuckets),
+            &mut pds.filter_storage,
+        )?;
+        assert!(report.filtered_report.bin_values.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_beneficiary_cap() -> Result<(), anyhow::Error> {
+        let filters = PpaFilterStorage::new(StaticCapacities::mock())?;
+        let mut pds = PpaPdsCore::<_>::new(filters);
+        pds.set_beneficiary_cap(BeneficiaryCap {
+            max_beneficiaries: 2,
+            max_total_loss: 1.5,
+        });
+
+        let querier_uris = vec![
+            "r1.ex".to_string(),
+            "r2.ex".to_string(),
+            "r3.ex".to_string(),
+        ];
+        let report_request_uris = ReportRequestUris {
+            querier_uris: querier_uris.clone(),
+            ..ReportRequestUris::mock()
+        };
+        let selector = |requested_buckets: Vec<u64>| PpaRelevantEventSelector {
+            report_request_uris: report_request_uris.clone(),
+            is_matching_event: FilterDataPredicate::Any,
+            requested_buckets: requested_buckets.into(),
+            lookback: None,
+        };
+
+        // Each querier reads its own bucket. The request has two epochs, so
+        // each querier pays the global sensitivity for epoch 1, i.e. 1, even
+        // if its buckets are empty.
+        let events = querier_uris
+            .iter()
+            .enumerate()
+            .map(|(i, _)| PpaEvent {
+                id: i as u64,
+                timestamp: i as u64,
+                epoch_number: 1,
+                histogram_index: i as u64,
+                uris: EventUris {
+                    querier_uris: querier_uris.clone(),
+                    ..EventUris::mock()
+                },
+                filter_data: 1,
+                priority: 0,
+            })
+            .collect();
+        let request = PpaHistogramRequestBuilder::new()
+            .epoch_range(1, 2)?
+            .value(1.0, 1.0, 1.0)?
+            .uris(report_request_uris.clone())?
+            .selector(FilterDataPredicate::Any, 3, vec![].into())?
+            .build();
+        let mut attr_object =
+            pds.measure_conversion(request, RelevantEvents::from_vec(events))?;
+        assert_eq!(attr_object.beneficiary_cap.unwrap().max_beneficiaries, 2);
+
+        let report = attr_object.get_report(
+            &querier_uris[2],
+            &selector(vec![2]),
+            &mut pds.filter_storage,
+        )?;
+        assert_eq!(report.filtered_report.bin_values.get(&2), Some(&1.0));
+        assert_eq!(attr_object.beneficiary_losses.get(&1), Some(&1.0));
+
+        // The second beneficiary would bring the total loss to 2, above the
+        // cap, so its epoch is dropped without charging its filter.
+        let report = attr_object.get_report(
+            &querier_uris[0],
+            &selector(vec![0]),
+            &mut pds.filter_storage,
+        )?;
+        assert!(report.filtered_report.bin_values.is_empty());
+        assert_eq!(
+            report.status,
+            ReportStatus::Null {
+                reason: NullReason::OutOfBudget
+            }
+        );
+        let filter_id = FilterId::PerQuerier(1, querier_uris[0].clone());
+        assert!(pds.filter_storage.get_filter(&filter_id)?.is_none());
+        assert_eq!(attr_object.beneficiary_losses.get(&1), Some(&1.0));
+
+        // Both beneficiaries got a report, the third one is rejected.
+        let result = attr_object.get_report(
+            &querier_uris[1],
+            &selector(vec![1]),
+            &mut pds.filter_storage,
+        );
+        assert!(matches!(result, Err(PdsError::ReportAlreadyIssued(_))));
+
+        // The cap and the losses survive a restart.
+        let json = serde_json::to_string(&attr_object)?;
+        let restored: AttributionObject<PpaHistogramRequest> =
+            serde_json::from_str(&json)?;
+        assert_eq!(restored.beneficiary_cap, attr_object.beneficiary_cap);
+        assert_eq!(restored.beneficiary_losses, attr_object.beneficiary_losses);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_attribution_object_store() -> Result<(), anyhow::Error> {
+        let filters = PpaFilterStorage::new(StaticCapacities::mock())?;
+        let mut pds = PpaPdsCore::<_>::new(filters);
+
+        let querier_uris = EventUris::mock().querier_uris;
+        let report_request_uris = ReportRequestUris {
+            querier_uris: querier_uris.clone(),
+            ..ReportRequestUris::mock()
+        };
+        let selector = |requested_buckets: Vec<u64>| PpaRelevantEventSelector {
+            report_request_uris: report_request_uris.clone(),
+            is_matching_event: FilterDataPredicate::Any,
+            requested_buckets: requested_buckets.into(),
+            lookback: None,
+        };
+
+        let request = PpaHistogramRequestBuilder::new()
+            .epoch_range(1, 2)?
+            .value(1.0, 1.0, 1.0)?
+            .uris(report_request_uris.clone())?
+            .selector(FilterDataPredicate::Any, 3, vec![1, 2].into())?
+            .build();
+        let event = |id, histogram_index| PpaEvent {
+            id,
+            timestamp: 100 + id,
+            epoch_number: id,
+            histogram_index,
+            uris: EventUris::mock(),
+            filter_data: 1,
+            priority: 0,
+        };
+        let attr_object = pds.measure_conversion(
+            request,
+            RelevantEvents::from_vec(vec![event(1, 1), event(2, 2)]),
+        )?;
+        let global =
+            pds.filter_storage.remaining_budget(&FilterId::Global(1))?;
+
+        let mut store = AttributionObjectStore::new();
+        store.insert("order-1", attr_object, 100);
+        let first = store.get_mut("order-1", 10).unwrap().get_report(
+            &querier_uris[0],
+            &selector(vec![1]),
+            &mut pds.filter_storage,
+        )?;
+        assert!(first.filtered_report.bin_values.is_empty());
+
+        // After a restart, the remaining queriers still get their reports,
+        // without paying for the shared loss again.
+        let json = serde_json::to_string(&store)?;
+        let mut store: AttributionObjectStore<String> =
+            serde_json::from_str(&json)?;
+        assert_eq!(store.len(), 1);
+        let attr_object = store.get_mut("order-1", 20).unwrap();
+        let second = attr_object.get_report(
+            &querier_uris[1],
+            &selector(vec![2]),
+            &mut pds.filter_storage,
+        )?;
+        assert_eq!(second.filtered_report.bin_values.get(&2), Some(&1.0));
+        assert_eq!(
+            pds.filter_storage.remaining_budget(&FilterId::Global(1))?,
+            global
+        );
+
+        // Queriers that got a report before the restart can't get another.
+        let result = attr_object.get_report(
+            &querier_uris[0],
+            &selector(vec![2]),
+            &mut pds.filter_storage,
+        );
+        assert!(matches!(result, Err(PdsError::ReportAlreadyIssued(_))));
+
+        // Expired objects can't be used anymore.
+        assert!(store.get_mut("order-1", 100).is_none());
+        store.remove_expired(100);
+        assert!(store.is_empty());
+        Ok(())
+    }
 }
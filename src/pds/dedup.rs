@@ -0,0 +1,149 @@
+//! Deduplication of triggers. The same conversion can fire several report
+//! requests, e.g. when the conversion page is reloaded. Requests can carry a
+//! deduplication key with a window, and duplicates within the window get the
+//! report of the first request, or a null report, without consuming budget
+//! again.
+//!
+//! Keys must survive restarts, otherwise a reload after a restart would be
+//! charged again, so they are kept in a `DedupStorage` that embedders can
+//! serialize along with the filters. Reports are only kept in memory, so
+//! duplicates of requests made before a restart get a null report.
+
+use alloc::{string::String, vec::Vec};
+use core::hash::Hash;
+
+use serde::{Deserialize, Serialize};
+
+use crate::util::hashmap::HashMap;
+
+/// Deduplication key and window carried by a request.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TriggerDedup {
+    /// Key chosen by the trigger site, e.g. an order ID.
+    pub key: String,
+
+    /// Time during which requests with the same key are duplicates, in
+    /// seconds.
+    pub window: u64,
+}
+
+/// Key of a deduplicated trigger. Keys are scoped to the trigger site and the
+/// queriers, so that requests of other queriers are never mistaken for
+/// duplicates.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DedupKey<U> {
+    pub trigger_uri: U,
+    pub querier_uris: Vec<U>,
+    pub key: String,
+}
+
+/// Deduplication key with its expiry, as persisted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DedupEntry<U> {
+    pub key: DedupKey<U>,
+    pub expires_at: u64,
+}
+
+/// Recent deduplication keys, with their expiry time in seconds. It
+/// serializes to a list of entries, so embedders can persist it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(
+    into = "Vec<DedupEntry<U>>",
+    from = "Vec<DedupEntry<U>>",
+    bound(
+        serialize = "U: Serialize + Clone + Eq + Hash",
+        deserialize = "U: Deserialize<'de> + Eq + Hash"
+    )
+)]
+pub struct DedupStorage<U> {
+    keys: HashMap<DedupKey<U>, u64>,
+}
+
+impl<U> Default for DedupStorage<U> {
+    fn default() -> Self {
+        Self {
+            keys: HashMap::default(),
+        }
+    }
+}
+
+impl<U> DedupStorage<U> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+impl<U: Eq + Hash> From<Vec<DedupEntry<U>>> for DedupStorage<U> {
+    fn from(entries: Vec<DedupEntry<U>>) -> Self {
+        Self {
+            keys: entries
+                .into_iter()
+                .map(|entry| (entry.key, entry.expires_at))
+                .collect(),
+        }
+    }
+}
+
+impl<U> From<DedupStorage<U>> for Vec<DedupEntry<U>> {
+    fn from(storage: DedupStorage<U>) -> Self {
+        storage
+            .keys
+            .into_iter()
+            .map(|(key, expires_at)| DedupEntry { key, expires_at })
+            .collect()
+    }
+}
+
+impl<U: Eq + Hash> DedupStorage<U> {
+    /// Whether the key was stored and has not expired at `now`.
+    pub fn contains(&self, key: &DedupKey<U>, now: u64) -> bool {
+        self.keys
+            .get(key)
+            .is_some_and(|expires_at| *expires_at > now)
+    }
+
+    /// Stores the key until `expires_at`, replacing any previous expiry.
+    pub fn insert(&mut self, key: DedupKey<U>, expires_at: u64) {
+        self.keys.insert(key, expires_at);
+    }
+
+    /// Drops the keys that expired at `now`.
+    pub fn remove_expired(&mut self, now: u64) {
+        self.keys.retain(|_, expires_at| *expires_at > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dedup_storage() {
+        let key = DedupKey {
+            trigger_uri: "shoes.com".to_string(),
+            querier_uris: vec!["adtech.com".to_string()],
+            key: "order-1".to_string(),
+        };
+        let mut storage = DedupStorage::new();
+        storage.insert(key.clone(), 10);
+        assert!(storage.contains(&key, 9));
+        assert!(!storage.contains(&key, 10));
+
+        // Keys survive a serialization round trip.
+        let json = serde_json::to_string(&storage).unwrap();
+        let mut storage: DedupStorage<String> =
+            serde_json::from_str(&json).unwrap();
+        assert!(storage.contains(&key, 9));
+
+        storage.remove_expired(10);
+        assert!(storage.is_empty());
+    }
+}
@@ -0,0 +1,122 @@
+//! Guards against events landing in closed epochs. If the device clock jumps
+//! backwards, events can be registered for epochs whose budget was already
+//! consumed by requests, so they could be attributed without being accounted
+//! for. The current epoch of the PDS never moves backwards, and events for
+//! older epochs go through an `EpochGuard`.
+
+use log::debug;
+
+use crate::{error::PdsError, events::traits::EpochId};
+
+/// What to do with an event for a given epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventAdmission {
+    /// Store the event.
+    Accept,
+
+    /// Drop the event without storing it.
+    Drop,
+}
+
+/// Decides whether late events are stored.
+pub trait EpochGuard<E: EpochId> {
+    /// Admission of an event for `event_epoch`, registered during
+    /// `current_epoch`. Returns an error if the event should be reported to
+    /// the caller, e.g. because the device clock is way off.
+    fn admit(
+        &self,
+        event_epoch: &E,
+        current_epoch: &E,
+    ) -> Result<EventAdmission, PdsError>;
+}
+
+/// Accepts all the events, whatever their epoch.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoEpochGuard;
+
+impl<E: EpochId> EpochGuard<E> for NoEpochGuard {
+    fn admit(
+        &self,
+        _event_epoch: &E,
+        _current_epoch: &E,
+    ) -> Result<EventAdmission, PdsError> {
+        Ok(EventAdmission::Accept)
+    }
+}
+
+/// Tolerates late events for the `grace_epochs` epochs before the current
+/// one, e.g. to absorb small clock skews. Older events are dropped, and events
+/// older than `retention_epochs` epochs are rejected with an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LateEventGuard {
+    pub grace_epochs: u64,
+
+    /// Events are rejected with an error beyond this many epochs before the
+    /// current one. Never if None.
+    pub retention_epochs: Option<u64>,
+}
+
+impl LateEventGuard {
+    pub fn new(grace_epochs: u64) -> Self {
+        Self {
+            grace_epochs,
+            retention_epochs: None,
+        }
+    }
+
+    /// Rejects events older than `retention_epochs` epochs, which can't be
+    /// shorter than the grace period.
+    pub fn with_retention_epochs(
+        mut self,
+        retention_epochs: u64,
+    ) -> Result<Self, PdsError> {
+        if retention_epochs < self.grace_epochs {
+            return Err(PdsError::InvalidRequest(format!(
+                "retention window of {retention_epochs} epochs is shorter than the grace period of {} epochs",
+                self.grace_epochs
+            )));
+        }
+        self.retention_epochs = Some(retention_epochs);
+        Ok(self)
+    }
+}
+
+impl EpochGuard<u64> for LateEventGuard {
+    fn admit(
+        &self,
+        event_epoch: &u64,
+        current_epoch: &u64,
+    ) -> Result<EventAdmission, PdsError> {
+        let lateness = current_epoch.saturating_sub(*event_epoch);
+        if lateness <= self.grace_epochs {
+            return Ok(EventAdmission::Accept);
+        }
+        if self
+            .retention_epochs
+            .is_some_and(|retention_epochs| lateness > retention_epochs)
+        {
+            return Err(PdsError::StaleEvent(format!(
+                "epoch {event_epoch} is older than the retention window of current epoch {current_epoch}"
+            )));
+        }
+        debug!("Dropping late event for closed epoch {event_epoch}");
+        Ok(EventAdmission::Drop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_late_event_guard() -> Result<(), PdsError> {
+        let guard = LateEventGuard::new(1).with_retention_epochs(3)?;
+        assert_eq!(guard.admit(&12, &10)?, EventAdmission::Accept);
+        assert_eq!(guard.admit(&9, &10)?, EventAdmission::Accept);
+        assert_eq!(guard.admit(&7, &10)?, EventAdmission::Drop);
+        assert!(matches!(guard.admit(&6, &10), Err(PdsError::StaleEvent(_))));
+
+        assert!(LateEventGuard::new(2).with_retention_epochs(1).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,225 @@
+//! Epoch schemes of the queriers. Events and device-level filters use base
+//! epochs, e.g. days, but some queriers can be entitled to coarser epochs,
+//! e.g. weeks. The per-querier filters of such queriers are kept in their own
+//! epochs, while the other filters are still charged for each base epoch.
+//! Policies that know how long epochs last can also resolve requests whose
+//! window is a duration before the trigger, see `EpochSelection::Duration`.
+
+use crate::{
+    budget::traits::EpochFilterId,
+    error::PdsError,
+    events::traits::{EpochId, Uri},
+    pds::quotas::{FilterId, QuerierGroups},
+    util::hashmap::HashMap,
+};
+
+/// Maps the epochs of a querier to the base epochs they cover.
+pub trait EpochPolicy<E: EpochId, U: Uri> {
+    /// Base epochs covered by the epoch `epoch_id` of `querier_uri`. Two
+    /// epochs of the same querier must never cover the same base epoch.
+    fn base_epochs(&self, querier_uri: &U, epoch_id: &E) -> Vec<E>;
+
+    /// Epochs of `querier_uri` overlapping the time window `start..=end`, in
+    /// seconds, most recent first. Errors if the policy doesn't know when
+    /// epochs start.
+    fn epochs_in_window(
+        &self,
+        _querier_uri: &U,
+        _start: u64,
+        _end: u64,
+    ) -> Result<Vec<E>, PdsError> {
+        Err(PdsError::InvalidConfig(
+            "the epoch policy can't resolve time windows into epochs".into(),
+        ))
+    }
+}
+
+/// All the queriers use the base epochs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BaseEpochs;
+
+impl<E: EpochId, U: Uri> EpochPolicy<E, U> for BaseEpochs {
+    fn base_epochs(&self, _querier_uri: &U, epoch_id: &E) -> Vec<E> {
+        vec![*epoch_id]
+    }
+}
+
+/// Queriers can have epochs made of a fixed number of consecutive base
+/// epochs. Epoch `e` of a querier with `n` base epochs per epoch covers the
+/// base epochs `e * n` to `(e + 1) * n - 1`. Other queriers use base epochs.
+/// Time windows are resolved once the duration of base epochs is set, with
+/// base epoch `b` covering the seconds `b * d` to `(b + 1) * d - 1`.
+#[derive(Debug, Clone, Default)]
+pub struct QuerierEpochGranularity<U: Uri> {
+    n_base_epochs: HashMap<U, u64>,
+    base_epoch_duration: Option<u64>,
+}
+
+impl<U: Uri> QuerierEpochGranularity<U> {
+    pub fn new() -> Self {
+        Self {
+            n_base_epochs: HashMap::new(),
+            base_epoch_duration: None,
+        }
+    }
+
+    /// Sets the duration of base epochs, in seconds, to resolve time windows
+    /// into epochs. Must match the epochs of the events, e.g. 86400 for
+    /// daily epochs starting at the Unix epoch.
+    pub fn set_base_epoch_duration(
+        &mut self,
+        seconds: u64,
+    ) -> Result<(), PdsError> {
+        if seconds == 0 {
+            return Err(PdsError::InvalidConfig(
+                "base epochs must last at least one second".into(),
+            ));
+        }
+        self.base_epoch_duration = Some(seconds);
+        Ok(())
+    }
+
+    /// Sets the number of base epochs in each epoch of the querier. The
+    /// granularity of a querier can't change once set, otherwise its existing
+    /// filters would cover other base epochs and events could be counted
+    /// twice.
+    pub fn set_granularity(
+        &mut self,
+        querier_uri: U,
+        n_base_epochs: u64,
+    ) -> Result<(), PdsError> {
+        if n_base_epochs == 0 {
+            return Err(PdsError::InvalidRequest(
+                "epochs must cover at least one base epoch".into(),
+            ));
+        }
+        match self.n_base_epochs.get(&querier_uri) {
+            Some(current) if *current != n_base_epochs => {
+                Err(PdsError::InvalidRequest(format!(
+                    "querier {querier_uri:?} already has {current} base epochs per epoch"
+                )))
+            }
+            _ => {
+                self.n_base_epochs.insert(querier_uri, n_base_epochs);
+                Ok(())
+            }
+        }
+    }
+
+    /// Number of base epochs in each epoch of the querier.
+    pub fn granularity(&self, querier_uri: &U) -> u64 {
+        self.n_base_epochs.get(querier_uri).copied().unwrap_or(1)
+    }
+
+    /// Epoch of the querier containing the given base epoch, e.g. to map an
+    /// event to the epoch scheme of a querier.
+    pub fn querier_epoch(&self, querier_uri: &U, base_epoch: u64) -> u64 {
+        base_epoch / self.granularity(querier_uri)
+    }
+}
+
+impl<U: Uri> EpochPolicy<u64, U> for QuerierEpochGranularity<U> {
+    fn base_epochs(&self, querier_uri: &U, epoch_id: &u64) -> Vec<u64> {
+        let n_base_epochs = self.granularity(querier_uri);
+        (epoch_id * n_base_epochs..(epoch_id + 1) * n_base_epochs).collect()
+    }
+
+    fn epochs_in_window(
+        &self,
+        querier_uri: &U,
+        start: u64,
+        end: u64,
+    ) -> Result<Vec<u64>, PdsError> {
+        let Some(base_epoch_duration) = self.base_epoch_duration else {
+            return Err(PdsError::InvalidConfig(
+                "no base epoch duration to resolve time windows".into(),
+            ));
+        };
+        let epoch_duration =
+            base_epoch_duration.saturating_mul(self.granularity(querier_uri));
+        Ok((start / epoch_duration..=end / epoch_duration)
+            .rev()
+            .collect())
+    }
+}
+
+/// Whether `filter_id` only covers base epochs strictly older than
+/// `older_than_epoch`, i.e. whether it can be pruned. `PerQuerier` and
+/// `CampaignQuota` filters are in the epochs of their querier, so they are
+/// only pruned once all the base epochs of their epoch are. Shared
+/// `PerQuerier` filters wait for the epoch of every member of the group.
+pub fn is_filter_pruned<E: EpochId, U: Uri>(
+    epoch_policy: &dyn EpochPolicy<E, U>,
+    querier_groups: &QuerierGroups<U>,
+    filter_id: &FilterId<E, U>,
+    older_than_epoch: &E,
+) -> bool {
+    let is_querier_epoch_pruned = |querier_uri: &U, epoch_id: &E| {
+        epoch_policy
+            .base_epochs(querier_uri, epoch_id)
+            .iter()
+            .all(|base_epoch| base_epoch < older_than_epoch)
+    };
+    match filter_id {
+        FilterId::PerQuerier(epoch_id, group_id) => querier_groups
+            .members_of(group_id)
+            .all(|querier_uri| is_querier_epoch_pruned(querier_uri, epoch_id)),
+        FilterId::CampaignQuota(epoch_id, querier_uri, _) => {
+            is_querier_epoch_pruned(querier_uri, epoch_id)
+        }
+        _ => filter_id.epoch_id() < older_than_epoch,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_querier_epoch_granularity() -> Result<(), PdsError> {
+        let mut policy = QuerierEpochGranularity::new();
+        policy.set_granularity("weekly.com".to_string(), 7)?;
+
+        let weekly = "weekly.com".to_string();
+        let daily = "daily.com".to_string();
+        assert_eq!(
+            policy.base_epochs(&weekly, &2),
+            (14..21).collect::<Vec<_>>()
+        );
+        assert_eq!(policy.base_epochs(&daily, &2), vec![2]);
+        assert_eq!(policy.querier_epoch(&weekly, 20), 2);
+        assert_eq!(policy.querier_epoch(&daily, 20), 20);
+
+        // Granularities can't change once set.
+        assert!(policy.set_granularity(weekly.clone(), 7).is_ok());
+        assert!(policy.set_granularity(weekly.clone(), 1).is_err());
+        assert!(policy.set_granularity(daily.clone(), 0).is_err());
+
+        // Windows are resolved into the epochs of each querier, once the
+        // duration of base epochs is known.
+        let day = 24 * 60 * 60;
+        assert!(policy.epochs_in_window(&daily, 0, day).is_err());
+        assert!(policy.set_base_epoch_duration(0).is_err());
+        policy.set_base_epoch_duration(day)?;
+        let (start, end) = (13 * day + 1, 21 * day + 1);
+        assert_eq!(
+            policy.epochs_in_window(&daily, start, end)?,
+            (13..=21).rev().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            policy.epochs_in_window(&weekly, start, end)?,
+            vec![3, 2, 1]
+        );
+        assert!(matches!(
+            EpochPolicy::<u64, _>::epochs_in_window(
+                &BaseEpochs,
+                &daily,
+                start,
+                end
+            ),
+            Err(PdsError::InvalidConfig(_))
+        ));
+
+        Ok(())
+    }
+}
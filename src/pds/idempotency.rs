@@ -0,0 +1,145 @@
+//! Idempotency of report requests. A querier that retries a request, e.g.
+//! after a transport failure, should not be charged twice for the same
+//! report. Requests can carry an idempotency key, and the report of the first
+//! request with a given key is returned again to the retries while it is
+//! cached.
+
+use std::{
+    fmt::Debug,
+    hash::{DefaultHasher, Hash, Hasher},
+};
+
+use crate::{error::PdsError, util::hashmap::HashMap};
+
+/// Default time-to-live of cached reports, in seconds.
+pub const DEFAULT_IDEMPOTENCY_TTL: u64 = 60 * 60;
+
+#[derive(Debug, Clone)]
+struct CachedReport<R> {
+    /// Hash of the request that produced the report, to detect keys reused
+    /// for different requests.
+    fingerprint: u64,
+    report: R,
+
+    /// Time after which the report is evicted, in seconds.
+    expires_at: u64,
+}
+
+/// Reports of the requests with an idempotency key, by querier URIs and key.
+/// Keys are scoped to the queriers, so a querier can't get the report of
+/// another querier by guessing its key.
+#[derive(Debug, Clone)]
+pub struct IdempotencyCache<U, R> {
+    reports: HashMap<(Vec<U>, String), CachedReport<R>>,
+}
+
+impl<U, R> Default for IdempotencyCache<U, R> {
+    fn default() -> Self {
+        Self {
+            reports: HashMap::new(),
+        }
+    }
+}
+
+/// Fingerprint of a request, from its debug representation which covers all
+/// of its fields.
+pub fn fingerprint(request: &impl Debug) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    format!("{request:?}").hash(&mut hasher);
+    hasher.finish()
+}
+
+impl<U, R> IdempotencyCache<U, R>
+where
+    U: Clone + Eq + Hash + Debug,
+    R: Clone,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the cached report for `key`, if any. Fails if the key was
+    /// used for a request with a different fingerprint.
+    pub fn get(
+        &mut self,
+        querier_uris: &[U],
+        key: &str,
+        fingerprint: u64,
+        now: u64,
+    ) -> Result<Option<R>, PdsError> {
+        self.evict_expired(now);
+        let Some(cached) =
+            self.reports.get(&(querier_uris.to_vec(), key.to_string()))
+        else {
+            return Ok(None);
+        };
+        if cached.fingerprint != fingerprint {
+            return Err(PdsError::InvalidRequest(format!(
+                "idempotency key {key:?} was already used by {querier_uris:?} for a different request"
+            )));
+        }
+        Ok(Some(cached.report.clone()))
+    }
+
+    /// Caches `report` for `key` until `now + ttl`.
+    pub fn insert(
+        &mut self,
+        querier_uris: &[U],
+        key: &str,
+        fingerprint: u64,
+        report: R,
+        now: u64,
+        ttl: u64,
+    ) {
+        self.reports.insert(
+            (querier_uris.to_vec(), key.to_string()),
+            CachedReport {
+                fingerprint,
+                report,
+                expires_at: now.saturating_add(ttl),
+            },
+        );
+    }
+
+    /// Drops the reports that expired at `now`.
+    pub fn evict_expired(&mut self, now: u64) {
+        self.reports.retain(|_, cached| cached.expires_at > now);
+    }
+
+    pub fn len(&self) -> usize {
+        self.reports.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reports.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idempotency_cache() -> Result<(), PdsError> {
+        let mut cache = IdempotencyCache::new();
+        let queriers = vec!["adtech.com".to_string()];
+        cache.insert(&queriers, "retry-1", 7, 1.0, 0, 10);
+
+        assert_eq!(cache.get(&queriers, "retry-1", 7, 9)?, Some(1.0));
+        assert_eq!(cache.get(&queriers, "retry-2", 7, 9)?, None);
+
+        // Keys are scoped to the queriers.
+        let others = vec!["other.com".to_string()];
+        assert_eq!(cache.get(&others, "retry-1", 7, 9)?, None);
+
+        // Reusing a key for another request is an error.
+        assert!(matches!(
+            cache.get(&queriers, "retry-1", 8, 9),
+            Err(PdsError::InvalidRequest(_))
+        ));
+
+        assert_eq!(cache.get(&queriers, "retry-1", 7, 10)?, None);
+        assert!(cache.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+//! Budget introspection for queriers. Exact remaining budgets depend on the
+//! device's events, so queriers only get a coarse, noised indicator of their
+//! headroom, and each indicator is charged on a separate `Introspection`
+//! filter of the epoch.
+//!
+//! Queriers can also get the list of epochs where their own per-querier
+//! filter is exhausted, to skip requests that can only return null reports.
+
+use log::debug;
+use rand::Rng;
+use serde::Serialize;
+
+use super::{core::PrivateDataServiceCore, quotas::FilterId};
+use crate::{
+    budget::{
+        pure_dp_filter::PureDPBudget,
+        traits::{Filter, FilterCapacities, FilterStatus, FilterStorage},
+    },
+    error::PdsError,
+    mechanisms::NoiseScale,
+    queries::traits::{EpochReportRequest, Report},
+};
+
+/// Coarse level of the remaining per-querier budget, as a fraction of the
+/// capacity, after noising.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum HeadroomLevel {
+    /// Less than a third of the capacity is left.
+    Low,
+
+    /// Between a third and two thirds of the capacity are left.
+    Medium,
+
+    /// More than two thirds of the capacity are left.
+    High,
+}
+
+/// Headroom indicator of a querier for one epoch.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HeadroomIndicator<E> {
+    pub epoch_id: E,
+
+    /// None if the epoch was pruned or if its introspection budget is
+    /// exhausted.
+    pub level: Option<HeadroomLevel>,
+}
+
+/// Smallest budget that counts as a nonzero request. Smaller budgets can get
+/// lost in the rounding of the consumed budget.
+const MIN_NONZERO_BUDGET: PureDPBudget = 1e-9;
+
+/// Epochs where a querier is out of budget, in a form that queriers can
+/// parse to skip doomed requests.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QuotaReport<E> {
+    /// Epochs where the per-querier filter can't pay for any nonzero
+    /// request, in the order they were asked for.
+    pub exhausted_epochs: Vec<E>,
+}
+
+impl<R, Q, FS, ERR> PrivateDataServiceCore<Q, FS, ERR>
+where
+    R: Report + Clone,
+    Q: EpochReportRequest<Report = R>,
+    FS: FilterStorage<
+        FilterId = FilterId<Q::EpochId, Q::Uri>,
+        Budget = PureDPBudget,
+    >,
+    ERR: From<FS::Error> + From<PdsError>,
+{
+    /// Returns an `epsilon`-DP indicator of the per-querier headroom of
+    /// `querier_uri` for each epoch, and charges `epsilon` on the
+    /// `Introspection` filter of each epoch.
+    pub fn querier_headroom(
+        &mut self,
+        querier_uri: &Q::Uri,
+        epoch_ids: &[Q::EpochId],
+        epsilon: PureDPBudget,
+        rng: &mut impl Rng,
+    ) -> Result<Vec<HeadroomIndicator<Q::EpochId>>, ERR> {
+        if epsilon <= 0.0 {
+            return Err(PdsError::InvalidRequest(
+                "introspection epsilon must be > 0".into(),
+            )
+            .into());
+        }
+
+        let mut indicators = vec![];
+        for epoch_id in epoch_ids {
+            let level =
+                self.epoch_headroom(querier_uri, *epoch_id, epsilon, rng)?;
+            indicators.push(HeadroomIndicator {
+                epoch_id: *epoch_id,
+                level,
+            });
+        }
+        Ok(indicators)
+    }
+
+    /// Returns the epochs of `epoch_ids` where the per-querier filter of
+    /// `querier_uri` has no budget left at all. Only that filter is read, so
+    /// the other queriers and the device-wide filters don't influence the
+    /// report, and no budget is charged.
+    ///
+    /// WARNING: unlike `querier_headroom`, the report is not noised. It only
+    /// reveals that the querier already spent its whole budget on the epoch,
+    /// which depends on the events that its past reports were computed on.
+    pub fn quota_report(
+        &mut self,
+        querier_uri: &Q::Uri,
+        epoch_ids: &[Q::EpochId],
+    ) -> Result<QuotaReport<Q::EpochId>, ERR> {
+        let mut exhausted_epochs = vec![];
+        for epoch_id in epoch_ids {
+            // Pruned epochs don't charge any filter.
+            if self.is_pruned(epoch_id) {
+                continue;
+            }
+            let filter_id = self
+                .querier_groups
+                .per_querier_filter(*epoch_id, querier_uri);
+            let Some(filter) = self.filter_storage.get_filter(&filter_id)?
+            else {
+                continue;
+            };
+            if filter.can_consume(&MIN_NONZERO_BUDGET)?
+                == FilterStatus::OutOfBudget
+            {
+                exhausted_epochs.push(*epoch_id);
+            }
+        }
+        debug!("Exhausted epochs of {querier_uri:?}: {exhausted_epochs:?}");
+        Ok(QuotaReport { exhausted_epochs })
+    }
+
+    fn epoch_headroom(
+        &mut self,
+        querier_uri: &Q::Uri,
+        epoch_id: Q::EpochId,
+        epsilon: PureDPBudget,
+        rng: &mut impl Rng,
+    ) -> Result<Option<HeadroomLevel>, ERR> {
+        // Don't recreate the filters of pruned epochs.
+        if self.is_pruned(&epoch_id) {
+            return Ok(None);
+        }
+
+        let filter_id = self
+            .querier_groups
+            .per_querier_filter(epoch_id, querier_uri);
+        let capacity = self.filter_storage.capacities().capacity(&filter_id)?;
+
+        // The remaining budget of infinite filters doesn't depend on the
+        // events, so it can be released for free.
+        if capacity.is_infinite() {
+            return Ok(Some(HeadroomLevel::High));
+        }
+
+        let introspection_filter_id = FilterId::Introspection(epoch_id);
+        if self
+            .filter_storage
+            .try_consume(&introspection_filter_id, &epsilon)?
+            == FilterStatus::OutOfBudget
+        {
+            self.observer.on_filter_oob(&introspection_filter_id);
+            return Ok(None);
+        }
+        self.observer
+            .on_budget_deducted(&introspection_filter_id, &epsilon);
+        self.accounting_stats
+            .record_deduction(&introspection_filter_id, epsilon);
+
+        // The remaining budget is in [0, capacity], so its sensitivity is the
+        // capacity. Instead of reading it, compare it to each noised
+        // threshold with `can_consume`, which is equivalent to bucketizing
+        // the noised remaining budget.
+        let noise = NoiseScale::Laplace(capacity / epsilon).sample_noise(rng);
+        let thresholds = [
+            (2.0 / 3.0, HeadroomLevel::High),
+            (1.0 / 3.0, HeadroomLevel::Medium),
+        ];
+        for (fraction, level) in thresholds {
+            let budget = (fraction * capacity - noise).max(0.0);
+            if self.filter_storage.can_consume(&filter_id, &budget)?
+                == FilterStatus::Continue
+            {
+                debug!("Headroom of {filter_id:?}: {level:?}");
+                return Ok(Some(level));
+            }
+        }
+        debug!("Headroom of {filter_id:?}: Low");
+        Ok(Some(HeadroomLevel::Low))
+    }
+}
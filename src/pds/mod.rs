@@ -1,13 +1,40 @@
 pub mod accounting;
+#[cfg(feature = "std")]
+pub mod accounting_stats;
+#[cfg(feature = "std")]
 pub mod aliases;
+#[cfg(feature = "std")]
+pub mod consent;
+#[cfg(feature = "std")]
 pub mod core;
+pub mod dedup;
+#[cfg(feature = "std")]
+pub mod epoch_guard;
+#[cfg(feature = "std")]
+pub mod epoch_policy;
+#[cfg(feature = "std")]
+pub mod idempotency;
+#[cfg(feature = "std")]
+pub mod introspection;
+#[cfg(feature = "std")]
+pub mod observer;
+#[cfg(feature = "std")]
+pub mod preflight;
+#[cfg(feature = "std")]
 pub mod private_data_service;
 pub mod quotas;
+pub mod rate_limit;
 
+#[cfg(feature = "experimental")]
+pub mod attribution_trace;
 #[cfg(feature = "experimental")]
 pub mod batch_pds;
+#[cfg(feature = "config")]
+pub mod config;
 #[cfg(feature = "experimental")]
 pub mod cross_report;
+#[cfg(feature = "experimental")]
+pub mod subscription;
 
 #[cfg(test)]
 mod tests;
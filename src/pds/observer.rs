@@ -0,0 +1,215 @@
+use std::time::Duration;
+
+use crate::budget::pure_dp_filter::PureDPBudget;
+#[cfg(feature = "metrics")]
+use crate::{
+    events::traits::{EpochId, Uri},
+    pds::quotas::FilterId,
+};
+
+/// Hooks to observe the PDS, e.g. to collect metrics on out-of-budget rates,
+/// latencies or batch sizes. All the methods default to no-ops.
+///
+/// WARNING: observers see private filter state. Whatever they record must stay
+/// on the device, like `remaining_budget`.
+pub trait PdsObserver<FID> {
+    /// Called after each report computation, with the out-of-budget filters
+    /// that caused epochs to be dropped.
+    fn on_report_computed(&self, _oob_filters: &[FID], _latency: Duration) {}
+
+    /// Called when a filter is out of budget for a deduction.
+    fn on_filter_oob(&self, _filter_id: &FID) {}
+
+    /// Called when budget is actually deducted from a filter.
+    fn on_budget_deducted(&self, _filter_id: &FID, _budget: &PureDPBudget) {}
+
+    /// Called at the end of each `schedule_batch` with the number of requests
+    /// left in the batch and the number of reports released.
+    fn on_batch_scheduled(
+        &self,
+        _n_batched_requests: usize,
+        _n_released_reports: usize,
+    ) {
+    }
+
+    /// Called when reconciling the public filters of a batch PDS, for each
+    /// public filter whose consumption differs from the private one.
+    fn on_public_filter_drift(&self, _filter_id: &FID, _drift: &PureDPBudget) {}
+}
+
+/// Observer that ignores everything.
+#[derive(Debug, Default)]
+pub struct NoopObserver;
+
+impl<FID> PdsObserver<FID> for NoopObserver {}
+
+/// Name of the filter type, to label metrics without leaking URIs.
+#[cfg(feature = "metrics")]
+fn filter_kind<E: EpochId, U: Uri>(filter_id: &FilterId<E, U>) -> &'static str {
+    match filter_id {
+        FilterId::PerQuerier(..) => "per_querier",
+        FilterId::Global(..) => "global",
+        FilterId::TriggerQuota(..) => "trigger_quota",
+        FilterId::SourceQuota(..) => "source_quota",
+        FilterId::CampaignQuota(..) => "campaign_quota",
+        FilterId::SourceTriggerQuota(..) => "source_trigger_quota",
+        FilterId::Ldp(..) => "ldp",
+        FilterId::Introspection(..) => "introspection",
+    }
+}
+
+/// Observer reporting to the `metrics` facade. Filters are labeled by type
+/// only.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Default)]
+pub struct MetricsObserver;
+
+#[cfg(feature = "metrics")]
+impl<E: EpochId, U: Uri> PdsObserver<FilterId<E, U>> for MetricsObserver {
+    fn on_report_computed(
+        &self,
+        oob_filters: &[FilterId<E, U>],
+        latency: Duration,
+    ) {
+        metrics::counter!("pdslib_reports_computed").increment(1);
+        if !oob_filters.is_empty() {
+            metrics::counter!("pdslib_reports_with_oob").increment(1);
+        }
+        metrics::histogram!("pdslib_report_latency_seconds")
+            .record(latency.as_secs_f64());
+    }
+
+    fn on_filter_oob(&self, filter_id: &FilterId<E, U>) {
+        metrics::counter!("pdslib_filter_oob", "filter" => filter_kind(filter_id))
+            .increment(1);
+    }
+
+    fn on_budget_deducted(
+        &self,
+        filter_id: &FilterId<E, U>,
+        budget: &PureDPBudget,
+    ) {
+        metrics::histogram!(
+            "pdslib_budget_deducted",
+            "filter" => filter_kind(filter_id)
+        )
+        .record(*budget);
+    }
+
+    fn on_batch_scheduled(
+        &self,
+        n_batched_requests: usize,
+        n_released_reports: usize,
+    ) {
+        metrics::histogram!("pdslib_batch_size")
+            .record(n_batched_requests as f64);
+        metrics::counter!("pdslib_reports_released")
+            .increment(n_released_reports as u64);
+    }
+
+    fn on_public_filter_drift(
+        &self,
+        filter_id: &FilterId<E, U>,
+        drift: &PureDPBudget,
+    ) {
+        metrics::histogram!(
+            "pdslib_public_filter_drift",
+            "filter" => filter_kind(filter_id)
+        )
+        .record(*drift);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{
+        budget::traits::FilterStorage,
+        pds::{
+            aliases::{PpaEventStorage, PpaFilterStorage, PpaPds},
+            quotas::{FilterId, StaticCapacities},
+        },
+        queries::{
+            builder::PpaHistogramRequestBuilder,
+            ppa_histogram::{FilterDataPredicate, RequestedBuckets},
+            traits::ReportRequestUris,
+        },
+        util::hashmap::HashMap,
+    };
+
+    #[derive(Debug, Default)]
+    struct Recorded {
+        n_reports: usize,
+        oob: Vec<FilterId>,
+        deducted: Vec<(FilterId, PureDPBudget)>,
+    }
+
+    #[derive(Clone, Default)]
+    struct RecordingObserver(Rc<RefCell<Recorded>>);
+
+    impl PdsObserver<FilterId> for RecordingObserver {
+        fn on_report_computed(&self, _: &[FilterId], _: Duration) {
+            self.0.borrow_mut().n_reports += 1;
+        }
+
+        fn on_filter_oob(&self, filter_id: &FilterId) {
+            self.0.borrow_mut().oob.push(filter_id.clone());
+        }
+
+        fn on_budget_deducted(&self, filter_id: &FilterId, budget: &f64) {
+            self.0
+                .borrow_mut()
+                .deducted
+                .push((filter_id.clone(), *budget));
+        }
+    }
+
+    #[test]
+    fn test_observer_callbacks() -> Result<(), anyhow::Error> {
+        let capacities = StaticCapacities::new(1.0, 20.0, 10.0, 10.0);
+        let filters = PpaFilterStorage::new(capacities)?;
+        let mut pds = PpaPds::<_>::new(filters, PpaEventStorage::new());
+
+        let observer = RecordingObserver::default();
+        pds.core.set_observer(observer.clone());
+
+        let uris = ReportRequestUris::mock();
+        let source_losses = HashMap::new();
+        let loss = 0.6;
+        let filters_to_consume =
+            pds.core.filters_to_consume(1, &loss, &source_losses, &uris);
+
+        // Dry runs don't deduct anything.
+        pds.core.deduct_budget(&filters_to_consume, true)?;
+        assert!(observer.0.borrow().deducted.is_empty());
+
+        pds.core.deduct_budget(&filters_to_consume, false)?;
+        assert_eq!(observer.0.borrow().deducted.len(), 3);
+        assert!(observer.0.borrow().oob.is_empty());
+
+        // The per-querier filter can't fit a second loss.
+        pds.core.deduct_budget(&filters_to_consume, true)?;
+        let querier_uri = uris.querier_uris[0].clone();
+        assert_eq!(
+            observer.0.borrow().oob,
+            vec![FilterId::PerQuerier(1, querier_uri)]
+        );
+
+        let request = PpaHistogramRequestBuilder::new()
+            .epoch_range(1, 1)?
+            .value(1.0, 1.0, 1.0)?
+            .uris(uris)?
+            .selector(
+                FilterDataPredicate::Any,
+                4,
+                RequestedBuckets::AllBuckets,
+            )?
+            .build();
+        pds.compute_report(&request)?;
+        assert_eq!(observer.0.borrow().n_reports, 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,54 @@
+use serde::Serialize;
+
+/// Number of requests like the checked one that a filter must fit to have
+/// `Headroom::Many`.
+pub const MANY_REQUESTS: u32 = 5;
+
+/// Coarse budget headroom of a filter, in number of requests like the checked
+/// one that it could still fit. Exact remaining budgets are never exposed.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize,
+)]
+pub enum Headroom {
+    /// Not enough budget for the request.
+    Exhausted,
+
+    /// Enough budget for the request, but not for a second one.
+    Single,
+
+    /// Enough budget for at least two requests, but fewer than
+    /// `MANY_REQUESTS`.
+    Few,
+
+    /// Enough budget for at least `MANY_REQUESTS` requests.
+    Many,
+}
+
+/// Outcome of a dry-run of the accounting for a request.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PreflightResult<FID> {
+    /// Whether the request would currently be answered without dropping any
+    /// epoch.
+    pub answerable: bool,
+
+    /// Headroom of each filter the request would consume from, from the
+    /// least to the most headroom.
+    pub headroom: Vec<(FID, Headroom)>,
+}
+
+impl<FID: PartialEq> PreflightResult<FID> {
+    /// One of the filters with the least headroom, i.e. the first ones that
+    /// will run out of budget. None if the request doesn't consume from any
+    /// filter.
+    pub fn bottleneck(&self) -> Option<&(FID, Headroom)> {
+        self.headroom.first()
+    }
+
+    /// Headroom of the given filter, if the request consumes from it.
+    pub fn headroom_of(&self, filter_id: &FID) -> Option<Headroom> {
+        self.headroom
+            .iter()
+            .find(|(fid, _)| fid == filter_id)
+            .map(|(_, headroom)| *headroom)
+    }
+}
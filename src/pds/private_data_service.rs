@@ -1,20 +1,66 @@
+use std::borrow::Cow;
 #[cfg(feature = "experimental")]
 use std::fmt::Debug;
+use std::fmt::{self, Display};
 
-use log::debug;
+use serde::{Deserialize, Serialize};
 
-use super::{core::PrivateDataServiceCore, quotas::FilterId};
+use super::{
+    accounting_stats::AccountingSummary,
+    consent::{BudgetGrant, ConsentVerifier, NoConsent},
+    core::PrivateDataServiceCore,
+    dedup::{DedupKey, DedupStorage},
+    epoch_guard::{EpochGuard, EventAdmission, NoEpochGuard},
+    epoch_policy::EpochPolicy,
+    idempotency::{self, IdempotencyCache, DEFAULT_IDEMPOTENCY_TTL},
+    preflight::PreflightResult,
+    quotas::{
+        BorrowingPolicy, FilterClass, FilterId, FilterKind, QuerierGroups,
+        QuotaExemptions,
+    },
+    rate_limit::RateLimiter,
+};
 use crate::{
-    budget::{pure_dp_filter::PureDPBudget, traits::FilterStorage},
-    events::{relevant_events::RelevantEvents, traits::EventStorage},
+    budget::{
+        pure_dp_filter::PureDPBudget,
+        reservation::{Reservation, ReservationToken},
+        traits::{Filter, FilterCapacities, FilterStorage},
+    },
+    error::PdsError,
+    events::{
+        relevant_events::RelevantEvents,
+        traits::{Event, EventStorage},
+    },
+    mechanisms::{ldp::LdpFallback, NoiseScale},
     queries::traits::EpochReportRequest,
+    util::{
+        clock::{Clock, SystemClock},
+        correlation::{debug, CorrelationScope},
+        hashmap::HashMap,
+        spans::timed_span,
+    },
 };
 #[cfg(feature = "experimental")]
 use crate::{
-    pds::quotas::PdsFilterStatus, queries::traits::PassivePrivacyLossRequest,
-    util::hashmap::HashMap,
+    events::traits::RelevantEventSelector,
+    pds::{
+        attribution_trace::{
+            AttributionTrace, CandidateEvent, RejectionReason,
+        },
+        quotas::{CarryoverPolicy, PdsFilterStatus},
+    },
+    queries::{
+        epoch_selection::unique_epochs, traits::PassivePrivacyLossRequest,
+    },
 };
 
+/// Default time-to-live of budget reservations, in seconds.
+pub const DEFAULT_RESERVATION_TTL: u64 = 24 * 60 * 60;
+
+/// Number of times events are read before giving up, if the event storage
+/// keeps changing during the reads.
+const MAX_SNAPSHOT_ATTEMPTS: usize = 3;
+
 /// Epoch-based private data service, using generic filter
 /// storage and event storage interfaces.
 pub struct PrivateDataService<
@@ -24,15 +70,68 @@ pub struct PrivateDataService<
         FilterId = FilterId<Q::EpochId, Q::Uri>,
     >,
     ES: EventStorage<Event = Q::Event>,
-    ERR: From<FS::Error> + From<ES::Error>,
+    ERR: From<FS::Error> + From<ES::Error> + From<PdsError>,
 > {
     pub core: PrivateDataServiceCore<Q, FS, ERR>,
 
     /// Event storage interface.
     pub event_storage: ES,
+
+    /// Maximum number of epochs a request can span. Unlimited if None.
+    pub max_attribution_window: Option<usize>,
+
+    /// Largest epsilon of a report, i.e. global sensitivity over noise scale,
+    /// so that queriers can't ask for almost no noise. Unchecked if None.
+    pub max_report_epsilon: Option<f64>,
+
+    /// Latest epoch that requests can attribute to. Set it to the current
+    /// epoch to reject windows extending into the future. Unchecked if None.
+    pub current_epoch: Option<Q::EpochId>,
+
+    /// Decides whether events for epochs before the current one are stored.
+    /// Accepts all the events by default.
+    pub epoch_guard: Box<dyn EpochGuard<Q::EpochId>>,
+
+    /// Source of time for reservation expiry and rate limiting.
+    pub clock: Box<dyn Clock>,
+
+    /// Time after which reservations are released, in seconds.
+    pub reservation_ttl: u64,
+
+    /// Reports waiting for their reservation to be committed.
+    reserved_reports: HashMap<ReservationToken, PdsReport<Q>>,
+
+    /// Token for the next reservation.
+    next_reservation_token: ReservationToken,
+
+    /// Request rate of each querier, limited by the rate limit of the
+    /// capacities.
+    rate_limiter: RateLimiter<Q::EpochId, Q::Uri>,
+
+    /// Time during which reports of requests with an idempotency key are
+    /// returned again to retries, in seconds.
+    pub idempotency_ttl: u64,
+
+    /// Reports of the recent requests with an idempotency key.
+    idempotency_cache: IdempotencyCache<Q::Uri, PdsReport<Q>>,
+
+    /// Checks the consent tokens of budget grants. Rejects all the grants by
+    /// default.
+    pub consent_verifier: Box<dyn ConsentVerifier<Q::EpochId, Q::Uri>>,
+
+    /// Grants accepted so far, in order.
+    budget_grants: Vec<BudgetGrant<Q::EpochId, Q::Uri>>,
+
+    /// Deduplication keys of the recent triggers. Persist it along with the
+    /// filters, so that duplicates are still recognized after a restart.
+    pub dedup_storage: DedupStorage<Q::Uri>,
+
+    /// Reports of the recent deduplicated triggers, with their expiry time.
+    dedup_reports: HashMap<DedupKey<Q::Uri>, (u64, PdsReport<Q>)>,
 }
 
-/// Report returned by Pds, potentially augmented with debugging information
+/// Report returned by Pds, potentially augmented with debugging information.
+/// Serialized as a `PdsReportWire`, with the filtered report only.
 #[derive(Debug)]
 pub struct PdsReport<Q: EpochReportRequest> {
     pub filtered_report: Q::Report,
@@ -41,6 +140,53 @@ pub struct PdsReport<Q: EpochReportRequest> {
     /// Store a list of the filter IDs that were out-of-budget in the atomic
     /// check for any epoch in the attribution window.
     pub oob_filters: Vec<FilterId<Q::EpochId, Q::Uri>>,
+
+    /// Whether the filtered report is complete, partially filtered or null.
+    /// Populated even without the debugging fields.
+    /// WARNING: like `oob_filters`, the status depends on the remaining
+    /// budget, it should not be shared outside the device.
+    pub status: ReportStatus<Q::EpochId>,
+
+    /// When to retry if the request was out of budget on public filters, None
+    /// otherwise. Only set by the batch PDS, whose public filters depend on
+    /// the requests alone, so it can be returned to the querier. The device
+    /// filters depend on the device's data, so like `oob_filters` they never
+    /// drive the advice.
+    pub retry_advice: Option<RetryAdvice>,
+
+    /// Events of the requested epochs and why they were not attributed, if
+    /// enabled with `with_attribution_trace`.
+    /// WARNING: the trace exposes raw events, it is for local debugging only.
+    #[cfg(feature = "experimental")]
+    pub attribution_trace: Option<AttributionTrace<Q::Event>>,
+}
+
+impl<Q: EpochReportRequest> Clone for PdsReport<Q> {
+    fn clone(&self) -> Self {
+        Self {
+            filtered_report: self.filtered_report.clone(),
+            unfiltered_report: self.unfiltered_report.clone(),
+            oob_filters: self.oob_filters.clone(),
+            status: self.status.clone(),
+            retry_advice: self.retry_advice,
+            #[cfg(feature = "experimental")]
+            attribution_trace: self.attribution_trace.clone(),
+        }
+    }
+}
+
+impl<Q: EpochReportRequest> PartialEq for PdsReport<Q> {
+    fn eq(&self, other: &Self) -> bool {
+        #[cfg(feature = "experimental")]
+        if self.attribution_trace != other.attribution_trace {
+            return false;
+        }
+        self.filtered_report == other.filtered_report
+            && self.unfiltered_report == other.unfiltered_report
+            && self.oob_filters == other.oob_filters
+            && self.status == other.status
+            && self.retry_advice == other.retry_advice
+    }
 }
 
 /// Default implementation for a null report
@@ -50,45 +196,985 @@ impl<Q: EpochReportRequest> Default for PdsReport<Q> {
             filtered_report: Q::Report::default(),
             unfiltered_report: Q::Report::default(),
             oob_filters: Vec::new(),
+            status: ReportStatus::Null {
+                reason: NullReason::NoRelevantEvents,
+            },
+            retry_advice: None,
+            #[cfg(feature = "experimental")]
+            attribution_trace: None,
         }
     }
 }
 
+impl<Q: EpochReportRequest> PdsReport<Q> {
+    /// Empty report, with the reason why it is null.
+    pub fn null(reason: NullReason) -> Self {
+        Self {
+            status: ReportStatus::Null { reason },
+            ..Default::default()
+        }
+    }
+}
+
+/// Status of a report, so that consumers don't have to infer null reports
+/// from `oob_filters` or from empty bins.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)
+)]
+pub enum ReportStatus<E> {
+    /// All the epochs with relevant events were attributed.
+    Full,
+
+    /// Some epochs with relevant events were out of budget, and their events
+    /// were not attributed, except through the local-DP fallback if enabled.
+    PartiallyFiltered { dropped_epochs: Vec<E> },
+
+    /// The report doesn't attribute any value.
+    Null { reason: NullReason },
+}
+
+/// Why a report is null.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)
+)]
+pub enum NullReason {
+    /// No relevant events in the requested epochs, e.g. because their
+    /// filters were pruned.
+    NoRelevantEvents,
+
+    /// All the epochs with relevant events were out of budget.
+    OutOfBudget,
+
+    /// The buckets were already requested by another querier of the same
+    /// cross-report attribution.
+    BucketsAlreadyRequested,
+
+    /// The trigger duplicates a recent trigger whose report was not kept.
+    Duplicate,
+}
+
+impl<E: PartialEq> ReportStatus<E> {
+    /// Status of a report that dropped `dropped_epochs`, out of the epochs
+    /// that had relevant events.
+    pub(crate) fn from_dropped_epochs(
+        dropped_epochs: Vec<E>,
+        epochs_with_events: &[E],
+    ) -> Self {
+        if epochs_with_events.is_empty() {
+            return Self::Null {
+                reason: NullReason::NoRelevantEvents,
+            };
+        }
+        if epochs_with_events
+            .iter()
+            .all(|epoch_id| dropped_epochs.contains(epoch_id))
+        {
+            return Self::Null {
+                reason: NullReason::OutOfBudget,
+            };
+        }
+        match dropped_epochs.is_empty() {
+            true => Self::Full,
+            false => Self::PartiallyFiltered { dropped_epochs },
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Self::Null { .. })
+    }
+
+    /// Whether some relevant events were dropped because of out-of-budget
+    /// filters.
+    pub fn is_out_of_budget(&self) -> bool {
+        matches!(
+            self,
+            Self::PartiallyFiltered { .. }
+                | Self::Null {
+                    reason: NullReason::OutOfBudget
+                }
+        )
+    }
+}
+
+impl<E: Display> Display for ReportStatus<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full => write!(f, "full"),
+            Self::PartiallyFiltered { dropped_epochs } => {
+                write!(f, "partially filtered, dropped epochs [")?;
+                for (i, epoch_id) in dropped_epochs.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{epoch_id}")?;
+                }
+                write!(f, "]")
+            }
+            Self::Null { reason } => write!(f, "null, {reason}"),
+        }
+    }
+}
+
+impl Display for NullReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            Self::NoRelevantEvents => "no relevant events",
+            Self::OutOfBudget => "out of budget",
+            Self::BucketsAlreadyRequested => "buckets already requested",
+            Self::Duplicate => "duplicate trigger",
+        };
+        f.write_str(reason)
+    }
+}
+
+/// Dominant reason why a request was out of budget, telling the querier when
+/// a retry can succeed without revealing how much budget is left. Variants
+/// are ordered by increasing wait, and a report gets the advice of its most
+/// limiting filter.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+)]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)
+)]
+pub enum RetryAdvice {
+    /// A quota shared with other sites is out of budget, e.g. the trigger or
+    /// source quota. The querier's own budget might be left, e.g. for other
+    /// sites, or in the batch phase where impression-site quotas are lifted.
+    QuotaThrottled,
+
+    /// The Global budget of the epoch is not fully released yet. Retry after
+    /// the next release.
+    ReleasePending,
+
+    /// The Global or PerQuerier budget of the epoch is exhausted for good.
+    /// Retry with later epochs.
+    EpochExhausted,
+}
+
+impl RetryAdvice {
+    /// Advice for an out-of-budget public filter of the given kind.
+    pub fn for_filter_kind(kind: FilterKind) -> Self {
+        match kind {
+            FilterKind::PerQuerier
+            | FilterKind::Global
+            | FilterKind::Ldp
+            | FilterKind::Introspection => Self::EpochExhausted,
+            FilterKind::TriggerQuota
+            | FilterKind::SourceQuota
+            | FilterKind::CampaignQuota
+            | FilterKind::SourceTriggerQuota => Self::QuotaThrottled,
+        }
+    }
+}
+
+impl Display for RetryAdvice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let advice = match self {
+            Self::QuotaThrottled => "quota throttled",
+            Self::ReleasePending => "release pending",
+            Self::EpochExhausted => "epoch exhausted",
+        };
+        f.write_str(advice)
+    }
+}
+
 /// API for the epoch-based PDS.
 impl<Q, FS, ES, ERR> PrivateDataService<Q, FS, ES, ERR>
 where
-    Q: EpochReportRequest<Report: Clone>,
+    Q: EpochReportRequest,
     FS: FilterStorage<
         Budget = PureDPBudget,
         FilterId = FilterId<Q::EpochId, Q::Uri>,
     >,
     ES: EventStorage<Event = Q::Event>,
-    ERR: From<FS::Error> + From<ES::Error>,
+    ERR: From<FS::Error> + From<ES::Error> + From<PdsError>,
 {
     pub fn new(filter_storage: FS, event_storage: ES) -> Self {
         Self {
             core: PrivateDataServiceCore::new(filter_storage),
             event_storage,
+            max_attribution_window: None,
+            max_report_epsilon: None,
+            current_epoch: None,
+            epoch_guard: Box::new(NoEpochGuard),
+            clock: Box::new(SystemClock),
+            reservation_ttl: DEFAULT_RESERVATION_TTL,
+            reserved_reports: HashMap::new(),
+            next_reservation_token: 0,
+            rate_limiter: RateLimiter::new(),
+            idempotency_ttl: DEFAULT_IDEMPOTENCY_TTL,
+            idempotency_cache: IdempotencyCache::new(),
+            consent_verifier: Box::new(NoConsent),
+            budget_grants: Vec::new(),
+            dedup_storage: DedupStorage::new(),
+            dedup_reports: HashMap::new(),
+        }
+    }
+
+    /// Rejects requests spanning more than `max_attribution_window` epochs.
+    pub fn with_max_attribution_window(
+        mut self,
+        max_attribution_window: usize,
+    ) -> Self {
+        self.max_attribution_window = Some(max_attribution_window);
+        self
+    }
+
+    /// Rejects requests whose reports would have an epsilon above
+    /// `max_report_epsilon`, i.e. too little noise for their sensitivity.
+    pub fn with_max_report_epsilon(mut self, max_report_epsilon: f64) -> Self {
+        self.max_report_epsilon = Some(max_report_epsilon);
+        self
+    }
+
+    /// Lets the given first-party measurements skip the trigger and source
+    /// quotas.
+    pub fn with_quota_exemptions(
+        mut self,
+        quota_exemptions: QuotaExemptions<Q::Uri>,
+    ) -> Self {
+        self.core.set_quota_exemptions(quota_exemptions);
+        self
+    }
+
+    /// Lets the given groups of queriers share their PerQuerier filters.
+    pub fn with_querier_groups(
+        mut self,
+        querier_groups: QuerierGroups<Q::Uri>,
+    ) -> Self {
+        self.core.set_querier_groups(querier_groups);
+        self
+    }
+
+    /// Sets the current epoch, after which requests can't attribute. The
+    /// current epoch never moves backwards, e.g. if the device clock
+    /// regresses.
+    pub fn set_current_epoch(&mut self, current_epoch: Q::EpochId) {
+        if self
+            .current_epoch
+            .is_some_and(|epoch| current_epoch < epoch)
+        {
+            debug!(
+                "Ignoring clock regression to epoch {current_epoch:?}, current epoch is {:?}",
+                self.current_epoch
+            );
+            return;
         }
+        self.current_epoch = Some(current_epoch);
+    }
+
+    /// Uses the given guard for events registered for past epochs, see
+    /// `EpochGuard`. Only applies once the current epoch is set.
+    pub fn with_epoch_guard(
+        mut self,
+        epoch_guard: impl EpochGuard<Q::EpochId> + 'static,
+    ) -> Self {
+        self.epoch_guard = Box::new(epoch_guard);
+        self
+    }
+
+    /// Uses the given clock for reservation expiry and rate limiting.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
     }
 
-    /// Registers a new event.
+    /// Answers out-of-budget requests with a local-DP report instead of a
+    /// null report.
+    pub fn with_ldp_fallback(mut self, ldp_fallback: LdpFallback<Q>) -> Self {
+        self.core.set_ldp_fallback(ldp_fallback);
+        self
+    }
+
+    /// Uses the given epoch scheme for each querier, see `EpochPolicy`.
+    pub fn with_epoch_policy(
+        mut self,
+        epoch_policy: impl EpochPolicy<Q::EpochId, Q::Uri> + 'static,
+    ) -> Self {
+        self.core.set_epoch_policy(epoch_policy);
+        self
+    }
+
+    /// Lets PerQuerier filters borrow budget from the next epoch, see
+    /// `BorrowingPolicy`.
+    pub fn with_borrowing_policy(
+        mut self,
+        borrowing_policy: BorrowingPolicy<Q::EpochId>,
+    ) -> Self {
+        self.core.set_borrowing_policy(borrowing_policy);
+        self
+    }
+
+    /// [Experimental] Rolls part of the unused budget over to the next epoch
+    /// when epochs are pruned, see `CarryoverPolicy`.
+    #[cfg(feature = "experimental")]
+    pub fn with_carryover_policy(
+        mut self,
+        carryover_policy: CarryoverPolicy<Q::EpochId>,
+    ) -> Self {
+        self.core.set_carryover_policy(carryover_policy);
+        self
+    }
+
+    /// Attaches an `AttributionTrace` to the reports, to debug empty
+    /// reports.
+    /// WARNING: traces expose raw events, they should not be shared outside
+    /// the device.
+    #[cfg(feature = "experimental")]
+    pub fn with_attribution_trace(mut self) -> Self {
+        self.core.set_attribution_trace(true);
+        self
+    }
+
+    /// Seeds the sampling of the relevant events, see `EventSampling`, so
+    /// that tests and simulations are reproducible. Must never be used on
+    /// real devices.
+    pub fn with_sampling_seed(mut self, seed: u64) -> Self {
+        self.core.set_sampling_seed(seed);
+        self
+    }
+
+    /// Sets the time-to-live of reservations, in seconds.
+    pub fn with_reservation_ttl(mut self, reservation_ttl: u64) -> Self {
+        self.reservation_ttl = reservation_ttl;
+        self
+    }
+
+    /// Sets the time during which reports of requests with an idempotency
+    /// key are returned again to retries, in seconds.
+    pub fn with_idempotency_ttl(mut self, idempotency_ttl: u64) -> Self {
+        self.idempotency_ttl = idempotency_ttl;
+        self
+    }
+
+    /// Uses the given verifier for the consent tokens of budget grants.
+    pub fn with_consent_verifier(
+        mut self,
+        consent_verifier: impl ConsentVerifier<Q::EpochId, Q::Uri> + 'static,
+    ) -> Self {
+        self.consent_verifier = Box::new(consent_verifier);
+        self
+    }
+
+    /// Restores the deduplication keys persisted by a previous instance.
+    pub fn with_dedup_storage(
+        mut self,
+        dedup_storage: DedupStorage<Q::Uri>,
+    ) -> Self {
+        self.dedup_storage = dedup_storage;
+        self
+    }
+
+    /// Registers a new event. Events for past epochs go through the epoch
+    /// guard, and can be dropped or rejected.
     pub fn register_event(&mut self, event: Q::Event) -> Result<(), ERR> {
         debug!("Registering event {event:?}");
+        if let Some(current_epoch) = self.current_epoch {
+            let admission =
+                self.epoch_guard.admit(&event.epoch_id(), &current_epoch)?;
+            if admission == EventAdmission::Drop {
+                return Ok(());
+            }
+        }
         self.event_storage.add_event(event)?;
         Ok(())
     }
 
-    /// Computes a report for the given report request.
-    pub fn compute_report(&mut self, request: &Q) -> Result<PdsReport<Q>, ERR> {
-        let relevant_event_selector = request.relevant_event_selector();
-        let relevant_events = RelevantEvents::from_event_storage(
-            &mut self.event_storage,
-            &request.epoch_ids(),
-            relevant_event_selector,
+    /// Updates the capacities of the filters. New capacities always apply to
+    /// filters created from now on. If `tighten_existing` is true, existing
+    /// filters are also tightened, but never loosened, so consumed budget is
+    /// never refunded. The policy version of `capacities` is recorded in the
+    /// filter state for auditability.
+    pub fn set_capacities(
+        &mut self,
+        capacities: FS::Capacities,
+        tighten_existing: bool,
+    ) -> Result<(), ERR> {
+        debug!("Setting capacities, tighten existing: {tighten_existing}");
+        self.core
+            .filter_storage
+            .set_capacities(capacities, tighten_existing)?;
+        Ok(())
+    }
+
+    /// Raises the capacity of the filter of class `filter_class` for
+    /// `epoch_id` by `amount`, if the user consented to it. The grant is
+    /// recorded and can be listed with `budget_grants`. Each consent token
+    /// can only be used once.
+    ///
+    /// Note: capacities normally never increase, so every grant must be
+    /// accounted for as additional privacy loss the user agreed to. Grants
+    /// are lost if the filter is later tightened by `set_capacities` or
+    /// pruned.
+    pub fn grant_budget(
+        &mut self,
+        epoch_id: Q::EpochId,
+        filter_class: FilterClass<Q::Uri>,
+        amount: PureDPBudget,
+        consent_token: &str,
+    ) -> Result<(), ERR> {
+        if !amount.is_finite() || amount <= 0.0 {
+            return Err(PdsError::InvalidRequest(format!(
+                "granted budget {amount} must be finite and > 0"
+            ))
+            .into());
+        }
+        // A pruned filter would be recreated, with full capacity.
+        if self.core.is_pruned(&epoch_id) {
+            return Err(PdsError::InvalidEpochWindow(format!(
+                "epoch {epoch_id:?} was pruned"
+            ))
+            .into());
+        }
+
+        let filter_id = filter_class.filter_id(epoch_id);
+        if self
+            .budget_grants
+            .iter()
+            .any(|grant| grant.consent_token == consent_token)
+        {
+            return Err(PdsError::ConsentRejected(format!(
+                "consent token for {filter_id:?} was already used"
+            ))
+            .into());
+        }
+        if !self
+            .consent_verifier
+            .verify(&filter_id, amount, consent_token)?
+        {
+            return Err(PdsError::ConsentRejected(format!(
+                "invalid consent token for granting {amount} to {filter_id:?}"
+            ))
+            .into());
+        }
+
+        debug!("Granting {amount} to {filter_id:?}");
+        self.core.settle_repayment(&filter_id)?;
+        self.core
+            .filter_storage
+            .edit_filter_or_new(&filter_id, |filter| {
+                filter.raise_capacity(&amount)
+            })?;
+        self.budget_grants.push(BudgetGrant {
+            filter_id,
+            amount,
+            consent_token: consent_token.to_string(),
+            granted_at: self.clock.now(),
+        });
+        Ok(())
+    }
+
+    /// Budget grants accepted so far, in order, for auditing.
+    pub fn budget_grants(&self) -> &[BudgetGrant<Q::EpochId, Q::Uri>] {
+        &self.budget_grants
+    }
+
+    /// Budget consumed so far by each kind of filter, in total and per
+    /// epoch, with the `top_k` filters that consumed the most.
+    /// WARNING: the summary reflects private filter state, it must stay on
+    /// the device.
+    pub fn accounting_summary(
+        &self,
+        top_k: usize,
+    ) -> AccountingSummary<Q::EpochId, Q::Uri> {
+        self.core.accounting_stats.summary(top_k)
+    }
+
+    /// Prunes the filters for all epochs strictly older than
+    /// `older_than_epoch`, e.g. once they are outside of any possible
+    /// attribution window. Requests for pruned epochs will not see their
+    /// events. Returns the number of pruned filters.
+    pub fn prune_epochs(
+        &mut self,
+        older_than_epoch: Q::EpochId,
+    ) -> Result<usize, ERR> {
+        let n_pruned = self.core.prune_epochs(older_than_epoch)?;
+        let core = &self.core;
+        self.rate_limiter.prune(|epoch_id, querier_uri| {
+            core.is_querier_epoch_pruned(querier_uri, epoch_id)
+        });
+        Ok(n_pruned)
+    }
+
+    /// Resolves the duration window of the request, if any, into the epochs
+    /// of its querier with the epoch policy, see `EpochSelection::Duration`.
+    /// Requests that already list their epochs are returned as is.
+    pub fn resolve_epoch_window<'a>(
+        &self,
+        request: &'a Q,
+    ) -> Result<Cow<'a, Q>, PdsError> {
+        let Some(window) = request.duration_window() else {
+            return Ok(Cow::Borrowed(request));
+        };
+        let Some(querier_uri) = request.report_uris().querier_uris.first()
+        else {
+            return Err(PdsError::InvalidRequest(
+                "duration windows are resolved in the epochs of a querier"
+                    .into(),
+            ));
+        };
+        let start = window.trigger_time.saturating_sub(window.duration);
+        let epoch_ids = self.core.epoch_policy.epochs_in_window(
+            querier_uri,
+            start,
+            window.trigger_time,
         )?;
+        debug!("Resolved window {window:?} into epochs {epoch_ids:?}");
+        Ok(Cow::Owned(request.with_epoch_ids(epoch_ids)))
+    }
+
+    /// Checks that the epochs requested are in order, within the maximum
+    /// attribution window and not in the future.
+    pub fn validate_epoch_window(&self, request: &Q) -> Result<(), PdsError> {
+        let (start_epoch, end_epoch) = request.epoch_range();
+        if start_epoch > end_epoch {
+            return Err(PdsError::InvalidEpochWindow(format!(
+                "start epoch {start_epoch:?} is after end epoch {end_epoch:?}"
+            )));
+        }
 
-        self.core.compute_report(request, relevant_events)
+        // Windows are checked in base epochs, since queriers can have coarser
+        // epochs.
+        let uris = request.report_uris();
+        if let Some(max_attribution_window) = self.max_attribution_window {
+            let n_epochs = request
+                .epoch_ids()
+                .into_iter()
+                .map(|epoch_id| self.core.base_epochs(uris, epoch_id).len())
+                .sum::<usize>();
+            if n_epochs > max_attribution_window {
+                return Err(PdsError::InvalidEpochWindow(format!(
+                    "{n_epochs} epochs requested, at most {max_attribution_window} allowed"
+                )));
+            }
+        }
+
+        if let Some(current_epoch) = self.current_epoch {
+            let end_epoch = self.last_base_epoch(request);
+            if end_epoch > current_epoch {
+                return Err(PdsError::InvalidEpochWindow(format!(
+                    "end epoch {end_epoch:?} is after current epoch {current_epoch:?}"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the noise of the request is above the noise floor of the
+    /// device: the epsilon of its reports must be at most
+    /// `max_report_epsilon`.
+    pub fn validate_noise_floor(&self, request: &Q) -> Result<(), PdsError> {
+        let Some(max_report_epsilon) = self.max_report_epsilon else {
+            return Ok(());
+        };
+        let NoiseScale::Laplace(noise_scale) = request.noise_scale();
+        let sensitivity = request.report_global_sensitivity();
+        let report_epsilon = sensitivity / noise_scale;
+
+        // NaN epsilons, e.g. without noise nor sensitivity, are rejected too.
+        if report_epsilon.is_nan() || report_epsilon > max_report_epsilon {
+            return Err(PdsError::NoiseBelowFloor(format!(
+                "report epsilon {report_epsilon} is above {max_report_epsilon}, the noise scale must be at least {}",
+                sensitivity / max_report_epsilon
+            )));
+        }
+        Ok(())
+    }
+
+    /// Whether the attribution window of the request is over, i.e. its last
+    /// base epoch ended before the current epoch, so no new event can fall in
+    /// the window anymore. Windows are always closed if the current epoch is
+    /// unchecked.
+    pub fn is_window_closed(&self, request: &Q) -> bool {
+        self.current_epoch.is_none_or(|current_epoch| {
+            self.last_base_epoch(request) < current_epoch
+        })
+    }
+
+    /// Takes a token from the rate limiter for each requested epoch and
+    /// querier, if the capacities set a rate limit. Rejected requests never
+    /// reach the accounting.
+    fn check_rate_limit(&mut self, request: &Q) -> Result<(), PdsError> {
+        let Some(rate_limit) =
+            self.core.filter_storage.capacities().rate_limit()
+        else {
+            return Ok(());
+        };
+        let keys = request
+            .epoch_ids()
+            .into_iter()
+            .flat_map(|epoch_id| {
+                request
+                    .report_uris()
+                    .querier_uris
+                    .iter()
+                    .map(move |querier_uri| (epoch_id, querier_uri.clone()))
+            })
+            .collect::<Vec<_>>();
+        self.rate_limiter
+            .try_acquire(&rate_limit, &keys, self.clock.now())
+    }
+
+    /// Latest base epoch covered by the request.
+    fn last_base_epoch(&self, request: &Q) -> Q::EpochId {
+        let (_, end_epoch) = request.epoch_range();
+        self.core
+            .base_epochs(request.report_uris(), end_epoch)
+            .into_iter()
+            .max()
+            .unwrap_or(end_epoch)
+    }
+
+    /// Fetches the relevant events of the request, grouped by requested epoch,
+    /// from a consistent state of the event storage. The report is computed
+    /// on this snapshot only, so events arriving from other threads in the
+    /// meantime are not half-counted. Reads are retried if the storage
+    /// changed while they ran.
+    fn relevant_events(
+        &self,
+        request: &Q,
+    ) -> Result<RelevantEvents<Q::Event>, ERR> {
+        let _span = timed_span!(
+            "relevant_events",
+            n_epochs = request.epoch_ids().len()
+        );
+        for _ in 0..MAX_SNAPSHOT_ATTEMPTS {
+            let generation = self.event_storage.generation();
+            let relevant_events = self.read_relevant_events(request)?;
+            if self.event_storage.generation() == generation {
+                return Ok(relevant_events);
+            }
+            debug!("Events changed while reading them, retrying");
+        }
+        Err(PdsError::ConcurrentModification(format!(
+            "events kept changing over {MAX_SNAPSHOT_ATTEMPTS} attempts"
+        ))
+        .into())
+    }
+
+    /// Each requested epoch gets the events of the base epochs it covers.
+    fn read_relevant_events(
+        &self,
+        request: &Q,
+    ) -> Result<RelevantEvents<Q::Event>, ERR> {
+        let mut events_per_epoch = HashMap::new();
+        for epoch_id in request.epoch_ids() {
+            let base_epochs =
+                self.core.base_epochs(request.report_uris(), epoch_id);
+            let mut base_events = RelevantEvents::from_event_storage(
+                &self.event_storage,
+                &base_epochs,
+                request.relevant_event_selector(),
+            )?;
+            let events = base_epochs
+                .iter()
+                .filter_map(|base_epoch| {
+                    base_events.events_per_epoch.remove(base_epoch)
+                })
+                .flatten()
+                .collect::<Vec<_>>();
+            events_per_epoch.insert(epoch_id, events);
+        }
+        Ok(RelevantEvents::from_mapping(events_per_epoch))
+    }
+
+    /// Computes a report for the given report request. If the request has
+    /// an idempotency key that was used by the same queriers less than
+    /// `idempotency_ttl` seconds ago, the cached report is returned without
+    /// consuming budget again. Likewise, duplicates of a trigger within its
+    /// deduplication window get the report of the first request, or a null
+    /// report if it is not in memory anymore.
+    pub fn compute_report(&mut self, request: &Q) -> Result<PdsReport<Q>, ERR> {
+        let _scope = CorrelationScope::enter("compute_report");
+        let _span = timed_span!("compute_report", correlation_id = _scope.id());
+        let request = self.resolve_epoch_window(request)?;
+        let request = request.as_ref();
+        self.validate_epoch_window(request)?;
+        self.validate_noise_floor(request)?;
+
+        let querier_uris = &request.report_uris().querier_uris;
+        let idempotency_key = request
+            .idempotency_key()
+            .map(|key| (key, idempotency::fingerprint(request)));
+        if let Some((key, fingerprint)) = idempotency_key {
+            if let Some(report) = self.idempotency_cache.get(
+                querier_uris,
+                key,
+                fingerprint,
+                self.clock.now(),
+            )? {
+                debug!("Returning cached report for idempotency key {key:?}");
+                return Ok(report);
+            }
+        }
+
+        let dedup_key = request.trigger_dedup().map(|dedup| DedupKey {
+            trigger_uri: request.report_uris().trigger_uri.clone(),
+            querier_uris: querier_uris.clone(),
+            key: dedup.key.clone(),
+        });
+        if let Some(dedup_key) = &dedup_key {
+            if let Some(report) = self.duplicate_report(dedup_key) {
+                return Ok(report);
+            }
+        }
+
+        self.check_rate_limit(request)?;
+
+        let relevant_events = self.relevant_events(request)?;
+        let report = self.core.compute_report(request, relevant_events)?;
+        #[cfg(feature = "experimental")]
+        let report = self.trace_selector_mismatches(request, report)?;
+
+        if let Some((key, fingerprint)) = idempotency_key {
+            self.idempotency_cache.insert(
+                querier_uris,
+                key,
+                fingerprint,
+                report.clone(),
+                self.clock.now(),
+                self.idempotency_ttl,
+            );
+        }
+        if let (Some(dedup_key), Some(dedup)) =
+            (dedup_key, request.trigger_dedup())
+        {
+            let expires_at = self.clock.now().saturating_add(dedup.window);
+            self.dedup_storage.insert(dedup_key.clone(), expires_at);
+            self.dedup_reports
+                .insert(dedup_key, (expires_at, report.clone()));
+        }
+        Ok(report)
+    }
+
+    /// Report for a duplicate of a recent trigger, or None if the trigger
+    /// was not seen within its window.
+    fn duplicate_report(
+        &mut self,
+        dedup_key: &DedupKey<Q::Uri>,
+    ) -> Option<PdsReport<Q>> {
+        let now = self.clock.now();
+        self.dedup_storage.remove_expired(now);
+        self.dedup_reports
+            .retain(|_, (expires_at, _)| *expires_at > now);
+        if !self.dedup_storage.contains(dedup_key, now) {
+            return None;
+        }
+
+        debug!("Deduplicating trigger {dedup_key:?}");
+        let report = match self.dedup_reports.get(dedup_key) {
+            Some((_, report)) => report.clone(),
+            None => PdsReport::null(NullReason::Duplicate),
+        };
+        Some(report)
+    }
+
+    /// Checks whether the given report request could currently be answered,
+    /// without consuming any budget. See `PreflightResult` for the coarse
+    /// per-filter headroom that is returned.
+    #[allow(clippy::type_complexity)]
+    pub fn preflight(
+        &mut self,
+        request: &Q,
+    ) -> Result<PreflightResult<FilterId<Q::EpochId, Q::Uri>>, ERR> {
+        let request = self.resolve_epoch_window(request)?;
+        let request = request.as_ref();
+        self.validate_epoch_window(request)?;
+        self.validate_noise_floor(request)?;
+
+        let relevant_events = self.relevant_events(request)?;
+        self.core.preflight(request, relevant_events)
+    }
+
+    /// Computes the report for the given report request without consuming
+    /// any budget, and returns the unfiltered report along with the filters
+    /// that would be out of budget.
+    /// WARNING: this method is for local debugging only. The unfiltered
+    /// report should not be shared outside the device.
+    #[cfg(feature = "experimental")]
+    pub fn compute_report_dry_run(
+        &mut self,
+        request: &Q,
+    ) -> Result<PdsReport<Q>, ERR> {
+        let request = self.resolve_epoch_window(request)?;
+        let request = request.as_ref();
+        self.validate_epoch_window(request)?;
+        self.validate_noise_floor(request)?;
+
+        let relevant_events = self.relevant_events(request)?;
+        let report =
+            self.core.compute_report_dry_run(request, relevant_events)?;
+        self.trace_selector_mismatches(request, report)
+    }
+
+    /// Adds the stored events of the requested epochs that the selector
+    /// rejected to the attribution trace of the report, if any.
+    #[cfg(feature = "experimental")]
+    fn trace_selector_mismatches(
+        &self,
+        request: &Q,
+        mut report: PdsReport<Q>,
+    ) -> Result<PdsReport<Q>, ERR> {
+        let Some(trace) = &mut report.attribution_trace else {
+            return Ok(report);
+        };
+        let selector = request.relevant_event_selector();
+        for epoch_id in unique_epochs(request.epoch_ids()) {
+            for base_epoch in
+                self.core.base_epochs(request.report_uris(), epoch_id)
+            {
+                if self.event_storage.is_retired(&base_epoch) {
+                    continue;
+                }
+                let mismatches = self
+                    .event_storage
+                    .events_for_epoch(&base_epoch)?
+                    .filter(|event| !selector.is_relevant_event(event))
+                    .map(|event| CandidateEvent {
+                        event: event.clone(),
+                        rejection: Some(RejectionReason::SelectorMismatch),
+                    });
+                trace.candidates.extend(mismatches);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Deducts the budget for the given report request like
+    /// `compute_report`, but holds the report until the reservation is
+    /// committed. The reservation is persisted in the filter storage, and
+    /// released automatically after `reservation_ttl` seconds.
+    pub fn reserve_budget(
+        &mut self,
+        request: &Q,
+    ) -> Result<ReservationToken, ERR> {
+        let request = self.resolve_epoch_window(request)?;
+        let request = request.as_ref();
+        self.validate_epoch_window(request)?;
+        self.validate_noise_floor(request)?;
+        self.check_rate_limit(request)?;
+        self.release_expired_reservations()?;
+
+        let relevant_events = self.relevant_events(request)?;
+        let (report, deductions) = self
+            .core
+            .compute_report_with_deductions(request, relevant_events)?;
+
+        // Don't reuse tokens of reservations persisted by a previous
+        // instance.
+        let token = self
+            .core
+            .filter_storage
+            .reservations()?
+            .iter()
+            .map(|(token, _)| token + 1)
+            .fold(self.next_reservation_token, ReservationToken::max);
+        self.next_reservation_token = token + 1;
+
+        let reservation = Reservation {
+            deductions: deductions.clone(),
+            expires_at: self.clock.now() + self.reservation_ttl,
+        };
+        debug!("Reserving budget with token {token}: {reservation:?}");
+        // Budget that isn't recorded in a reservation could never be
+        // released, so the deductions are rolled back instead.
+        if let Err(error) =
+            self.core.filter_storage.set_reservation(token, reservation)
+        {
+            self.core.roll_back(deductions);
+            return Err(error.into());
+        }
+        self.reserved_reports.insert(token, report);
+        Ok(token)
+    }
+
+    /// Makes the deduction of a reservation final, and returns its report.
+    /// Fails if the reservation is unknown or expired. If the report was lost,
+    /// e.g. after a restart, the budget stays deducted.
+    pub fn commit(
+        &mut self,
+        token: ReservationToken,
+    ) -> Result<PdsReport<Q>, ERR> {
+        self.release_expired_reservations()?;
+
+        let reservation = self.core.filter_storage.take_reservation(token)?;
+        let report = self.reserved_reports.remove(&token);
+        match (reservation, report) {
+            (Some(_), Some(report)) => Ok(report),
+            _ => Err(PdsError::InvalidRequest(format!(
+                "unknown or expired reservation {token}"
+            ))
+            .into()),
+        }
+    }
+
+    /// Drops the report of a reservation and refunds its budget to the
+    /// filters. Fails if the reservation is unknown or expired.
+    pub fn release(&mut self, token: ReservationToken) -> Result<(), ERR> {
+        self.release_expired_reservations()?;
+
+        if !self.release_reservation(token)? {
+            return Err(PdsError::InvalidRequest(format!(
+                "unknown or expired reservation {token}"
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Releases all the reservations that reached their time-to-live.
+    /// Returns the number of released reservations.
+    pub fn release_expired_reservations(&mut self) -> Result<usize, ERR> {
+        let now = self.clock.now();
+        let expired_tokens = self
+            .core
+            .filter_storage
+            .reservations()?
+            .into_iter()
+            .filter(|(_, reservation)| reservation.expires_at <= now)
+            .map(|(token, _)| token)
+            .collect::<Vec<_>>();
+
+        for token in &expired_tokens {
+            debug!("Reservation {token} expired");
+            self.release_reservation(*token)?;
+        }
+        Ok(expired_tokens.len())
+    }
+
+    /// Refunds the budget of a reservation. Returns false if there is no
+    /// such reservation.
+    fn release_reservation(
+        &mut self,
+        token: ReservationToken,
+    ) -> Result<bool, ERR> {
+        self.reserved_reports.remove(&token);
+        let Some(reservation) =
+            self.core.filter_storage.take_reservation(token)?
+        else {
+            return Ok(false);
+        };
+
+        for (filter_id, budget) in &reservation.deductions {
+            self.core.filter_storage.refund(filter_id, budget)?;
+            self.core.accounting_stats.record_refund(filter_id, *budget);
+        }
+        Ok(true)
     }
 
     /// [Experimental] Accounts for passive privacy loss. Can fail if the
@@ -106,7 +1192,13 @@ where
         let source_losses = HashMap::new(); // Dummy.
 
         // For each epoch, try to consume the privacy budget.
+        let mut deductions = vec![];
         for epoch_id in request.epoch_ids {
+            // Pruned epochs can't be charged anymore.
+            if self.core.is_request_epoch_pruned(&request.uris, epoch_id) {
+                continue;
+            }
+
             let filters_to_consume = self.core.filters_to_consume(
                 epoch_id,
                 &request.privacy_budget,
@@ -123,16 +1215,10 @@ where
                 return Ok(check_status);
             }
 
-            // Phase 2: Consume the budget
-            let consume_status = self.core.deduct_budget(
-                &filters_to_consume,
-                false, // actually consume
-            )?;
-
-            assert_eq!(
-                consume_status, PdsFilterStatus::Continue,
-                "ERR: Phase 2 failed unexpectedly with status {consume_status:?} after Phase 1 succeeded",
-            );
+            // Phase 2: Consume the budget. If it fails, the epochs consumed
+            // so far are rolled back too.
+            self.core
+                .commit_deductions(&filters_to_consume, &mut deductions)?;
 
             // Semantics are still unclear, for now we ignore the request if
             // it would exhaust the filter.
@@ -140,3 +1226,65 @@ where
         Ok(PdsFilterStatus::Continue)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        events::{simple_event::SimpleEvent, traits::EventUris},
+        pds::{
+            aliases::{SimpleEventStorage, SimpleFilterStorage, SimplePds},
+            quotas::StaticCapacities,
+        },
+        queries::{
+            simple_last_touch_histogram::{
+                SimpleLastTouchHistogramRequest, SimpleRelevantEventSelector,
+            },
+            traits::ReportRequestUris,
+        },
+    };
+
+    #[test]
+    fn test_noise_floor() -> Result<(), PdsError> {
+        let filters = SimpleFilterStorage::new(StaticCapacities::mock())?;
+        let mut pds = SimplePds::new(filters, SimpleEventStorage::new())
+            .with_max_report_epsilon(1.0);
+        pds.register_event(SimpleEvent {
+            id: 1,
+            epoch_number: 1,
+            event_key: 1,
+            uris: EventUris::mock(),
+        })?;
+
+        // Report epsilon of 0.5 / (1.0 / requested_epsilon).
+        let request = |requested_epsilon| SimpleLastTouchHistogramRequest {
+            epoch_start: 1,
+            epoch_end: 1,
+            report_global_sensitivity: 0.5,
+            query_global_sensitivity: 1.0,
+            requested_epsilon,
+            is_relevant_event: SimpleRelevantEventSelector { lambda: |_| true },
+            report_uris: ReportRequestUris::mock(),
+        };
+
+        pds.compute_report(&request(2.0))?;
+        assert!(matches!(
+            pds.compute_report(&request(4.0)),
+            Err(PdsError::NoiseBelowFloor(_))
+        ));
+        assert!(matches!(
+            pds.reserve_budget(&request(f64::INFINITY)),
+            Err(PdsError::NoiseBelowFloor(_))
+        ));
+
+        // Rejected requests never reach the filters.
+        let consumed = pds
+            .core
+            .filter_storage
+            .get_filter(&FilterId::Global(1))?
+            .map(|filter| filter.consumed);
+        assert_eq!(consumed, Some(1.0));
+
+        Ok(())
+    }
+}
@@ -1,18 +1,32 @@
-use core::fmt;
-use std::{
+use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
+use core::{
+    fmt,
     fmt::{Debug, Display},
     hash::Hash,
-    vec,
 };
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    budget::traits::{Budget, FilterCapacities},
+    budget::{
+        pure_dp_filter::PureDPBudget,
+        traits::{BudgetOps, EpochFilterId, FilterCapacities},
+    },
+    error::PdsError,
     events::traits::{EpochId, Uri},
+    pds::rate_limit::RateLimit,
+    queries::traits::ReportRequestUris,
+    util::hashmap::{HashMap, HashSet},
 };
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+/// Identifier of an advertising campaign, chosen by the querier.
+pub type CampaignId = u64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)
+)]
 pub enum FilterId<E: EpochId = u64, U: Uri = String> {
     /// Non-collusion per-querier filter
     PerQuerier(E, U /* querier URI */),
@@ -25,6 +39,19 @@ pub enum FilterId<E: EpochId = u64, U: Uri = String> {
 
     /// Quota filter regulating Global filter consumption per source_uri
     SourceQuota(E, U /* source URI */),
+
+    /// Quota filter regulating PerQuerier filter consumption per campaign
+    CampaignQuota(E, U /* querier URI */, CampaignId),
+
+    /// Quota filter regulating Global filter consumption per pair of
+    /// source_uri and trigger_uri
+    SourceTriggerQuota(E, U /* source URI */, U /* trigger URI */),
+
+    /// Filter for the local-DP fallback reports, separate from the others
+    Ldp(E),
+
+    /// Metadata filter for budget introspection queries
+    Introspection(E),
 }
 
 impl<E: EpochId + Display, U: Uri + Display> fmt::Display for FilterId<E, U> {
@@ -42,20 +69,186 @@ impl<E: EpochId + Display, U: Uri + Display> fmt::Display for FilterId<E, U> {
             FilterId::SourceQuota(epoch_id, source_uri) => {
                 write!(f, "SourceQuota({epoch_id}, {source_uri})")
             }
+            FilterId::CampaignQuota(epoch_id, querier_uri, campaign_id) => {
+                write!(
+                    f,
+                    "CampaignQuota({epoch_id}, {querier_uri}, {campaign_id})"
+                )
+            }
+            FilterId::SourceTriggerQuota(epoch_id, source_uri, trigger_uri) => {
+                write!(
+                    f,
+                    "SourceTriggerQuota({epoch_id}, {source_uri}, {trigger_uri})"
+                )
+            }
+            FilterId::Ldp(epoch_id) => write!(f, "Ldp({epoch_id})"),
+            FilterId::Introspection(epoch_id) => {
+                write!(f, "Introspection({epoch_id})")
+            }
+        }
+    }
+}
+
+impl<E: EpochId, U: Uri> EpochFilterId for FilterId<E, U> {
+    type EpochId = E;
+
+    fn epoch_id(&self) -> &E {
+        match self {
+            FilterId::PerQuerier(epoch_id, _)
+            | FilterId::Global(epoch_id)
+            | FilterId::TriggerQuota(epoch_id, _)
+            | FilterId::SourceQuota(epoch_id, _)
+            | FilterId::CampaignQuota(epoch_id, _, _)
+            | FilterId::SourceTriggerQuota(epoch_id, _, _)
+            | FilterId::Ldp(epoch_id)
+            | FilterId::Introspection(epoch_id) => epoch_id,
+        }
+    }
+}
+
+impl<E: EpochId, U: Uri> FilterId<E, U> {
+    /// Type of the filter, without its epoch and URIs.
+    pub fn kind(&self) -> FilterKind {
+        match self {
+            FilterId::PerQuerier(..) => FilterKind::PerQuerier,
+            FilterId::Global(..) => FilterKind::Global,
+            FilterId::TriggerQuota(..) => FilterKind::TriggerQuota,
+            FilterId::SourceQuota(..) => FilterKind::SourceQuota,
+            FilterId::CampaignQuota(..) => FilterKind::CampaignQuota,
+            FilterId::SourceTriggerQuota(..) => FilterKind::SourceTriggerQuota,
+            FilterId::Ldp(..) => FilterKind::Ldp,
+            FilterId::Introspection(..) => FilterKind::Introspection,
+        }
+    }
+
+    /// Class of the filter, i.e. its ID without the epoch.
+    pub fn class(&self) -> FilterClass<U> {
+        match self {
+            FilterId::PerQuerier(_, querier_uri) => {
+                FilterClass::PerQuerier(querier_uri.clone())
+            }
+            FilterId::Global(_) => FilterClass::Global,
+            FilterId::TriggerQuota(_, trigger_uri) => {
+                FilterClass::TriggerQuota(trigger_uri.clone())
+            }
+            FilterId::SourceQuota(_, source_uri) => {
+                FilterClass::SourceQuota(source_uri.clone())
+            }
+            FilterId::CampaignQuota(_, querier_uri, campaign_id) => {
+                FilterClass::CampaignQuota(querier_uri.clone(), *campaign_id)
+            }
+            FilterId::SourceTriggerQuota(_, source_uri, trigger_uri) => {
+                FilterClass::SourceTriggerQuota(
+                    source_uri.clone(),
+                    trigger_uri.clone(),
+                )
+            }
+            FilterId::Ldp(_) => FilterClass::Ldp,
+            FilterId::Introspection(_) => FilterClass::Introspection,
+        }
+    }
+}
+
+/// Type of a filter, without any URI, e.g. to aggregate statistics without
+/// leaking sites.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize,
+)]
+pub enum FilterKind {
+    PerQuerier,
+    Global,
+    TriggerQuota,
+    SourceQuota,
+    CampaignQuota,
+    SourceTriggerQuota,
+    Ldp,
+    Introspection,
+}
+
+/// Type of a filter, with the URIs that identify it within an epoch. Combined
+/// with an epoch, it gives a `FilterId`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum FilterClass<U: Uri = String> {
+    PerQuerier(U /* querier URI */),
+    Global,
+    TriggerQuota(U /* trigger URI */),
+    SourceQuota(U /* source URI */),
+    CampaignQuota(U /* querier URI */, CampaignId),
+    SourceTriggerQuota(U /* source URI */, U /* trigger URI */),
+    Ldp,
+    Introspection,
+}
+
+impl<U: Uri> FilterClass<U> {
+    /// ID of the filter of this class for the given epoch.
+    pub fn filter_id<E: EpochId>(self, epoch_id: E) -> FilterId<E, U> {
+        match self {
+            FilterClass::PerQuerier(querier_uri) => {
+                FilterId::PerQuerier(epoch_id, querier_uri)
+            }
+            FilterClass::Global => FilterId::Global(epoch_id),
+            FilterClass::TriggerQuota(trigger_uri) => {
+                FilterId::TriggerQuota(epoch_id, trigger_uri)
+            }
+            FilterClass::SourceQuota(source_uri) => {
+                FilterId::SourceQuota(epoch_id, source_uri)
+            }
+            FilterClass::CampaignQuota(querier_uri, campaign_id) => {
+                FilterId::CampaignQuota(epoch_id, querier_uri, campaign_id)
+            }
+            FilterClass::SourceTriggerQuota(source_uri, trigger_uri) => {
+                FilterId::SourceTriggerQuota(epoch_id, source_uri, trigger_uri)
+            }
+            FilterClass::Ldp => FilterId::Ldp(epoch_id),
+            FilterClass::Introspection => FilterId::Introspection(epoch_id),
         }
     }
 }
 
 /// Struct containing the default capacity for each type of filter.
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(
+    deny_unknown_fields,
+    bound(serialize = "B: Serialize", deserialize = "B: Deserialize<'de>")
+)]
 pub struct StaticCapacities<FID, B> {
     pub per_querier: B,
     pub global: B,
     pub trigger_quota: B,
     pub source_quota: B,
 
-    #[serde(skip_serializing)]
-    _phantom: std::marker::PhantomData<FID>,
+    /// Capacity of the campaign quotas. Defaults to the per-querier capacity,
+    /// i.e. a single campaign can use the whole per-querier budget.
+    #[serde(default)]
+    pub campaign_quota: Option<B>,
+
+    /// Capacity of the quotas of each pair of source and trigger URIs.
+    /// Disabled if None, in which case only the source and trigger quotas
+    /// are charged.
+    #[serde(default)]
+    pub source_trigger_quota: Option<B>,
+
+    /// Capacity of the local-DP fallback filters. Defaults to the global
+    /// capacity.
+    #[serde(default)]
+    pub ldp: Option<B>,
+
+    /// Capacity of the introspection filters. Defaults to zero, i.e.
+    /// introspection is disabled.
+    #[serde(default)]
+    pub introspection: Option<B>,
+
+    /// Version of this capacity policy, bumped by deployments when they
+    /// update capacities.
+    #[serde(default)]
+    pub policy_version: u64,
+
+    /// Limit on the request rate of each querier. Unlimited if None.
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+
+    #[serde(skip)]
+    _phantom: core::marker::PhantomData<FID>,
 }
 
 impl<FID, B> StaticCapacities<FID, B> {
@@ -70,17 +263,63 @@ impl<FID, B> StaticCapacities<FID, B> {
             global,
             trigger_quota,
             source_quota,
-            _phantom: std::marker::PhantomData,
+            campaign_quota: None,
+            source_trigger_quota: None,
+            ldp: None,
+            introspection: None,
+            policy_version: 0,
+            rate_limit: None,
+            _phantom: core::marker::PhantomData,
         }
     }
+
+    /// Sets the capacity of the campaign quotas.
+    pub fn with_campaign_quota(mut self, campaign_quota: B) -> Self {
+        self.campaign_quota = Some(campaign_quota);
+        self
+    }
+
+    /// Enables the quotas of each pair of source and trigger URIs, with the
+    /// given capacity.
+    pub fn with_source_trigger_quota(
+        mut self,
+        source_trigger_quota: B,
+    ) -> Self {
+        self.source_trigger_quota = Some(source_trigger_quota);
+        self
+    }
+
+    /// Sets the capacity of the local-DP fallback filters.
+    pub fn with_ldp_capacity(mut self, ldp: B) -> Self {
+        self.ldp = Some(ldp);
+        self
+    }
+
+    /// Sets the capacity of the introspection filters.
+    pub fn with_introspection_capacity(mut self, introspection: B) -> Self {
+        self.introspection = Some(introspection);
+        self
+    }
+
+    /// Sets the policy version of these capacities.
+    pub fn with_policy_version(mut self, policy_version: u64) -> Self {
+        self.policy_version = policy_version;
+        self
+    }
+
+    /// Limits the request rate of each querier.
+    pub fn with_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
 }
 
-impl<B: Budget, E: EpochId, U: Uri> FilterCapacities
+impl<B: BudgetOps, E: EpochId, U: Uri> FilterCapacities
     for StaticCapacities<FilterId<E, U>, B>
 {
     type FilterId = FilterId<E, U>;
     type Budget = B;
-    type Error = anyhow::Error;
+    type Error = PdsError;
 
     fn capacity(
         &self,
@@ -91,11 +330,111 @@ impl<B: Budget, E: EpochId, U: Uri> FilterCapacities
             FilterId::Global(..) => Ok(self.global.clone()),
             FilterId::TriggerQuota(..) => Ok(self.trigger_quota.clone()),
             FilterId::SourceQuota(..) => Ok(self.source_quota.clone()),
+            FilterId::CampaignQuota(..) => Ok(self
+                .campaign_quota
+                .clone()
+                .unwrap_or_else(|| self.per_querier.clone())),
+            FilterId::SourceTriggerQuota(..) => Ok(self
+                .source_trigger_quota
+                .clone()
+                .unwrap_or_else(B::infinity)),
+            FilterId::Ldp(..) => {
+                Ok(self.ldp.clone().unwrap_or_else(|| self.global.clone()))
+            }
+            FilterId::Introspection(..) => {
+                Ok(self.introspection.clone().unwrap_or_else(B::zero))
+            }
+        }
+    }
+
+    fn policy_version(&self) -> u64 {
+        self.policy_version
+    }
+
+    fn rate_limit(&self) -> Option<RateLimit> {
+        self.rate_limit
+    }
+
+    fn source_trigger_quotas(&self) -> bool {
+        self.source_trigger_quota.is_some()
+    }
+}
+
+/// Opt-in borrowing of PerQuerier budget against the next epoch, to smooth
+/// the budget of bursty queriers across epochs. When a request is only out of
+/// budget on PerQuerier filters, each of them borrows the loss of the request
+/// from the PerQuerier filter of the same querier in the next epoch. Borrowing
+/// is only possible while that filter is not initialized yet: the repayment
+/// is recorded in the filter storage, and deducted from its capacity when it
+/// is initialized. The total borrowed by a filter can't exceed `max_borrow`.
+#[derive(Debug, Clone, Copy)]
+pub struct BorrowingPolicy<E> {
+    /// Largest budget that a PerQuerier filter can borrow from the next
+    /// epoch.
+    pub max_borrow: PureDPBudget,
+
+    /// Epoch following the given epoch, in the epoch scheme of the querier.
+    pub next_epoch: fn(&E) -> E,
+}
+
+impl BorrowingPolicy<u64> {
+    /// Borrowing from the next integer epoch.
+    pub fn new(max_borrow: PureDPBudget) -> Self {
+        Self {
+            max_borrow,
+            next_epoch: |epoch_id| epoch_id + 1,
+        }
+    }
+}
+
+/// [Experimental] Opt-in carryover of unused budget across epochs. When
+/// epochs are pruned, each filter that was charged in these epochs rolls
+/// `fraction` of its unused budget into the filter of the same class in the
+/// next epoch, whose capacity is raised accordingly. Filters that were never
+/// charged don't carry anything over.
+///
+/// Accounting: carryover raises capacities, so the budget spent on an epoch
+/// can exceed its capacity. A filter can receive at most `max_carryover` in
+/// total, including budget that was itself carried over from older epochs,
+/// so the budget of any filter is bounded by its capacity plus
+/// `max_carryover`. The PDS records the budget carried into each filter.
+#[derive(Debug, Clone, Copy)]
+pub struct CarryoverPolicy<E> {
+    /// Fraction of the unused budget that is carried over, in `[0, 1]`.
+    pub fraction: f64,
+
+    /// Largest total budget that a filter can receive from carryover.
+    pub max_carryover: PureDPBudget,
+
+    /// Epoch following the given epoch, in the epoch scheme of the filter.
+    pub next_epoch: fn(&E) -> E,
+}
+
+impl CarryoverPolicy<u64> {
+    /// Carryover into the next integer epoch.
+    pub fn new(
+        fraction: f64,
+        max_carryover: PureDPBudget,
+    ) -> Result<Self, PdsError> {
+        if !(0.0..=1.0).contains(&fraction) {
+            return Err(PdsError::InvalidConfig(format!(
+                "carryover fraction must be in [0, 1], got {fraction}"
+            )));
         }
+        if max_carryover.is_nan() || max_carryover < 0.0 {
+            return Err(PdsError::InvalidConfig(format!(
+                "max carryover must be >= 0, got {max_carryover}"
+            )));
+        }
+        Ok(Self {
+            fraction,
+            max_carryover,
+            next_epoch: |epoch_id| epoch_id + 1,
+        })
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PdsFilterStatus<FID> {
     /// No filter was out budget, the atomic check passed for this epoch
     Continue,
@@ -112,3 +451,165 @@ impl<FID> Default for PdsFilterStatus<FID> {
         Self::OutOfBudget(vec![])
     }
 }
+
+impl<FID: Display> fmt::Display for PdsFilterStatus<FID> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PdsFilterStatus::Continue => write!(f, "continue"),
+            PdsFilterStatus::OutOfBudget(filters) => {
+                write!(f, "out of budget [")?;
+                for (i, filter_id) in filters.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{filter_id}")?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+/// Predicate deciding whether a party, i.e. a trigger or source URI, is
+/// exempt from its quota for a request.
+pub type ExemptionPredicate<U> = Box<dyn Fn(&U, &ReportRequestUris<U>) -> bool>;
+
+/// Parties whose first-party measurements skip their `TriggerQuota` or
+/// `SourceQuota`, e.g. a publisher measuring conversions on its own pages.
+/// Quotas only bound how much of the Global filter a single site can use, so
+/// the `PerQuerier`, `CampaignQuota` and `Global` filters are always charged.
+/// A party is only exempt when it is also one of the queriers of the request.
+pub struct QuotaExemptions<U: Uri> {
+    uris: HashSet<U>,
+    predicate: Option<ExemptionPredicate<U>>,
+}
+
+impl<U: Uri> QuotaExemptions<U> {
+    /// No exemptions.
+    pub fn new() -> Self {
+        Self {
+            uris: HashSet::new(),
+            predicate: None,
+        }
+    }
+
+    /// Exempts the first-party measurements of the given URI.
+    pub fn with_uri(mut self, uri: U) -> Self {
+        self.uris.insert(uri);
+        self
+    }
+
+    /// Exempts the first-party measurements accepted by the predicate, in
+    /// addition to the listed URIs.
+    pub fn with_predicate(
+        mut self,
+        predicate: impl Fn(&U, &ReportRequestUris<U>) -> bool + 'static,
+    ) -> Self {
+        self.predicate = Some(Box::new(predicate));
+        self
+    }
+
+    /// Whether the quota of `party` is skipped for a request with `uris`.
+    pub fn is_exempt(&self, party: &U, uris: &ReportRequestUris<U>) -> bool {
+        if !uris.querier_uris.contains(party) {
+            return false;
+        }
+        self.uris.contains(party)
+            || self
+                .predicate
+                .as_ref()
+                .is_some_and(|predicate| predicate(party, uris))
+    }
+}
+
+impl<U: Uri> Default for QuotaExemptions<U> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<U: Uri> Debug for QuotaExemptions<U> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QuotaExemptions")
+            .field("uris", &self.uris)
+            .field("has_predicate", &self.predicate.is_some())
+            .finish()
+    }
+}
+
+/// Groups of querier URIs that share their `PerQuerier` filters, e.g. the
+/// domains of a single ad-tech company, so that registering more domains
+/// doesn't multiply its budget. The `PerQuerier` filters of the members are
+/// keyed by the ID of their group, which is a URI too: use one of the member
+/// URIs, or an ID that can't collide with a querier URI. Queriers that are
+/// not in a group keep their own filters. Other filters, e.g.
+/// `CampaignQuota`, are still keyed by querier URI.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuerierGroups<U: Uri> {
+    groups: HashMap<U, U>,
+}
+
+impl<U: Uri> QuerierGroups<U> {
+    /// No groups.
+    pub fn new() -> Self {
+        Self {
+            groups: HashMap::new(),
+        }
+    }
+
+    /// Adds `members` to the group `group_id`. Fails if a member is already
+    /// in another group, since it can only have one `PerQuerier` filter.
+    pub fn with_group(
+        mut self,
+        group_id: U,
+        members: impl IntoIterator<Item = U>,
+    ) -> Result<Self, PdsError> {
+        for member in members {
+            if let Some(other_group) = self.groups.get(&member) {
+                if *other_group != group_id {
+                    return Err(PdsError::InvalidConfig(format!(
+                        "querier {member:?} is already in group {other_group:?}"
+                    )));
+                }
+            }
+            self.groups.insert(member, group_id.clone());
+        }
+        Ok(self)
+    }
+
+    /// ID that the `PerQuerier` filters of `querier_uri` are keyed by, i.e.
+    /// its group ID, or the URI itself if it is not in a group.
+    pub fn group_of<'a>(&'a self, querier_uri: &'a U) -> &'a U {
+        self.groups.get(querier_uri).unwrap_or(querier_uri)
+    }
+
+    /// Queriers whose `PerQuerier` filters are keyed by `group_id`, i.e. the
+    /// members of the group, and `group_id` itself in case it is a querier
+    /// URI too.
+    pub fn members_of<'a>(
+        &'a self,
+        group_id: &'a U,
+    ) -> impl Iterator<Item = &'a U> {
+        let members = self
+            .groups
+            .iter()
+            .filter(move |(_, group)| *group == group_id)
+            .map(|(member, _)| member);
+        core::iter::once(group_id).chain(members)
+    }
+
+    /// `PerQuerier` filter of `querier_uri` for the given epoch.
+    pub fn per_querier_filter<E: EpochId>(
+        &self,
+        epoch_id: E,
+        querier_uri: &U,
+    ) -> FilterId<E, U> {
+        FilterId::PerQuerier(epoch_id, self.group_of(querier_uri).clone())
+    }
+}
+
+impl<U: Uri> Default for QuerierGroups<U> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,150 @@
+//! Rate limiting of the report requests of each querier, independently of
+//! the privacy budget. Budget only bounds what queriers learn, so a querier
+//! with budget left could still flood the device with requests, e.g. requests
+//! with no relevant events that cost no budget but still need to be
+//! processed.
+
+use alloc::format;
+use core::hash::Hash;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{error::PdsError, util::hashmap::HashMap};
+
+/// Token bucket parameters, for each querier and each requested epoch. A
+/// bucket holds up to `burst` tokens, starts full, and gets a new token every
+/// `refill_period` seconds. Each request takes one token from the bucket of
+/// each of its epochs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub refill_period: u64,
+}
+
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    tokens: u32,
+
+    /// Time of the last refill, in seconds.
+    last_refill: u64,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, rate_limit: &RateLimit, now: u64) {
+        if self.tokens >= rate_limit.burst || rate_limit.refill_period == 0 {
+            self.tokens = rate_limit.burst;
+            self.last_refill = now;
+            return;
+        }
+        let elapsed = now.saturating_sub(self.last_refill);
+        let new_tokens = elapsed / rate_limit.refill_period;
+        self.tokens = rate_limit
+            .burst
+            .min(self.tokens.saturating_add(new_tokens as u32));
+        self.last_refill += new_tokens * rate_limit.refill_period;
+    }
+}
+
+/// Token buckets of each (epoch, querier) pair that made requests.
+#[derive(Debug, Clone)]
+pub struct RateLimiter<E, U> {
+    buckets: HashMap<(E, U), TokenBucket>,
+}
+
+impl<E, U> Default for RateLimiter<E, U> {
+    fn default() -> Self {
+        Self {
+            buckets: HashMap::new(),
+        }
+    }
+}
+
+impl<E, U> RateLimiter<E, U>
+where
+    E: Clone + Eq + Hash + Ord + core::fmt::Debug,
+    U: Clone + Eq + Hash + core::fmt::Debug,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes a token from the bucket of each (epoch, querier) pair, or from
+    /// none of them if any bucket is empty.
+    pub fn try_acquire(
+        &mut self,
+        rate_limit: &RateLimit,
+        keys: &[(E, U)],
+        now: u64,
+    ) -> Result<(), PdsError> {
+        for key in keys {
+            let bucket =
+                self.buckets.entry(key.clone()).or_insert(TokenBucket {
+                    tokens: rate_limit.burst,
+                    last_refill: now,
+                });
+            bucket.refill(rate_limit, now);
+            if bucket.tokens == 0 {
+                let (epoch_id, querier_uri) = key;
+                return Err(PdsError::RateLimited(format!(
+                    "querier {querier_uri:?} exceeded its rate limit for epoch {epoch_id:?}"
+                )));
+            }
+        }
+        for key in keys {
+            if let Some(bucket) = self.buckets.get_mut(key) {
+                bucket.tokens -= 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the buckets of the (epoch, querier) pairs for which `is_pruned`
+    /// returns true. Epochs are in the epoch scheme of their querier.
+    pub fn prune(&mut self, is_pruned: impl Fn(&E, &U) -> bool) {
+        self.buckets.retain(|(epoch_id, querier_uri), _| {
+            !is_pruned(epoch_id, querier_uri)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let rate_limit = RateLimit {
+            burst: 2,
+            refill_period: 10,
+        };
+        let mut limiter = RateLimiter::new();
+        let key = |epoch: u64| (epoch, "adtech.com".to_string());
+
+        // The bucket starts full.
+        assert!(limiter.try_acquire(&rate_limit, &[key(1)], 0).is_ok());
+        assert!(limiter.try_acquire(&rate_limit, &[key(1)], 5).is_ok());
+        assert!(matches!(
+            limiter.try_acquire(&rate_limit, &[key(1)], 9),
+            Err(PdsError::RateLimited(_))
+        ));
+
+        // Rejected requests take no token, even from the other buckets.
+        assert!(limiter
+            .try_acquire(&rate_limit, &[key(2), key(1)], 9)
+            .is_err());
+        assert!(limiter.try_acquire(&rate_limit, &[key(2)], 9).is_ok());
+        assert!(limiter.try_acquire(&rate_limit, &[key(2)], 9).is_ok());
+
+        // One token every 10 seconds, up to the burst.
+        assert!(limiter.try_acquire(&rate_limit, &[key(1)], 10).is_ok());
+        assert!(limiter.try_acquire(&rate_limit, &[key(1)], 19).is_err());
+        for _ in 0..2 {
+            assert!(limiter.try_acquire(&rate_limit, &[key(1)], 100).is_ok());
+        }
+        assert!(limiter.try_acquire(&rate_limit, &[key(1)], 100).is_err());
+
+        // Pruned buckets start full again.
+        limiter.prune(|epoch_id, _| *epoch_id < 2);
+        assert!(limiter.try_acquire(&rate_limit, &[key(1)], 100).is_ok());
+    }
+}
@@ -0,0 +1,105 @@
+//! [Experimental] Subscriptions for recurring report requests. A querier
+//! registers a request descriptor once, and the batch PDS reserves the budget
+//! of the request on its public filters every time an epoch starts, before
+//! the other requests of that interval are allocated. Once the epoch is over,
+//! the report is either pushed with the reports of the next scheduling
+//! interval or held until the querier fetches it.
+
+use crate::{
+    pds::{private_data_service::PdsReport, quotas::FilterId},
+    queries::traits::EpochReportRequest,
+};
+
+/// Identifier of a subscription, chosen by the querier.
+pub type SubscriptionId = u64;
+
+/// How the reports of a subscription are delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscriptionDelivery {
+    /// Answered when the epoch closes, and returned by
+    /// `BatchPrivateDataService::take_subscription_reports`.
+    Push,
+
+    /// The reservation is held after the epoch closes, until the report is
+    /// fetched with `BatchPrivateDataService::fetch_subscription_report`.
+    Pull,
+}
+
+/// Builds the request of a subscription for the given epoch.
+pub type RequestDescriptor<E, Q> = Box<dyn Fn(&E) -> Q>;
+
+/// Recurring request, instantiated for each epoch by its descriptor.
+pub struct Subscription<Q: EpochReportRequest> {
+    subscription_id: SubscriptionId,
+    delivery: SubscriptionDelivery,
+
+    /// Request to run for the given epoch. The request must not attribute
+    /// to later epochs, or it is answered only once they are over too.
+    descriptor: RequestDescriptor<Q::EpochId, Q>,
+}
+
+impl<Q: EpochReportRequest> Subscription<Q> {
+    pub fn new(
+        subscription_id: SubscriptionId,
+        delivery: SubscriptionDelivery,
+        descriptor: impl Fn(&Q::EpochId) -> Q + 'static,
+    ) -> Self {
+        Self {
+            subscription_id,
+            delivery,
+            descriptor: Box::new(descriptor),
+        }
+    }
+
+    pub fn subscription_id(&self) -> SubscriptionId {
+        self.subscription_id
+    }
+
+    pub fn delivery(&self) -> SubscriptionDelivery {
+        self.delivery
+    }
+
+    /// Instance of the recurring request for the given epoch.
+    pub fn request_for(&self, epoch_id: &Q::EpochId) -> Q {
+        (self.descriptor)(epoch_id)
+    }
+}
+
+/// Budget reserved for one epoch of a subscription.
+#[derive(Debug)]
+pub struct SubscriptionReservation<Q: EpochReportRequest> {
+    pub subscription_id: SubscriptionId,
+    pub delivery: SubscriptionDelivery,
+
+    /// Epoch that started when the budget was reserved.
+    pub epoch_id: Q::EpochId,
+
+    pub request: Q,
+
+    /// Time at which the budget was reserved, from the batch PDS clock.
+    pub reserved_at: u64,
+
+    /// Public filters that were out of budget when the epoch started. If
+    /// any, nothing was reserved and the subscription gets a null report for
+    /// this epoch.
+    pub oob_filters: Vec<FilterId<Q::EpochId, Q::Uri>>,
+}
+
+/// Report for one epoch of a subscription.
+#[derive(Debug)]
+pub struct SubscriptionReport<Q: EpochReportRequest> {
+    pub subscription_id: SubscriptionId,
+
+    /// Querier receiving this report, see `BatchedReport::querier_uri`.
+    pub querier_uri: Q::Uri,
+
+    pub epoch_id: Q::EpochId,
+
+    /// Time at which the budget was reserved.
+    pub reserved_at: u64,
+
+    /// Time at which the report was computed.
+    pub computed_at: u64,
+
+    pub report: PdsReport<Q>,
+}
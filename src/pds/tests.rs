@@ -1,14 +1,32 @@
 #[cfg(feature = "experimental")]
 use crate::{
     budget::{pure_dp_filter::PureDPBudget, traits::FilterStorage},
-    pds::quotas::{FilterId, PdsFilterStatus, StaticCapacities},
+    error::PdsError,
+    events::{
+        simple_event::SimpleEvent,
+        traits::{EventStorage, EventUris},
+    },
+    mechanisms::ldp::{LdpFallback, LdpMechanism},
+    pds::epoch_guard::LateEventGuard,
+    pds::preflight::Headroom,
+    pds::quotas::{
+        FilterId, FilterKind, PdsFilterStatus, QuerierGroups, QuotaExemptions,
+        StaticCapacities,
+    },
     pds::{
         aliases::{SimpleEventStorage, SimpleFilterStorage, SimplePds},
+        private_data_service::{
+            NullReason, PrivateDataService, ReportStatus, RetryAdvice,
+        },
         quotas::FilterId::*,
     },
+    queries::simple_last_touch_histogram::{
+        SimpleLastTouchHistogramReport, SimpleLastTouchHistogramRequest,
+        SimpleRelevantEventSelector,
+    },
     queries::traits::PassivePrivacyLossRequest,
     queries::traits::ReportRequestUris,
-    util::hashmap::HashMap,
+    util::{clock::MockClock, hashmap::HashMap},
 };
 
 #[test]
@@ -227,3 +245,1704 @@ fn test_budget_rollback_on_depletion() -> Result<(), anyhow::Error> {
 
     Ok(())
 }
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_pruned_epochs_are_not_charged() -> Result<(), anyhow::Error> {
+    let capacities: StaticCapacities<FilterId, PureDPBudget> =
+        StaticCapacities::mock();
+    let filters = SimpleFilterStorage::new(capacities)?;
+    let events = SimpleEventStorage::new();
+    let mut pds = SimplePds::new(filters, events);
+
+    let uris = ReportRequestUris::mock();
+    for epoch_number in 1..=2 {
+        pds.register_event(SimpleEvent {
+            id: epoch_number,
+            epoch_number,
+            event_key: epoch_number,
+            uris: EventUris::mock(),
+        })?;
+    }
+
+    let request = |epoch_start, epoch_end| SimpleLastTouchHistogramRequest {
+        epoch_start,
+        epoch_end,
+        report_global_sensitivity: 0.5,
+        query_global_sensitivity: 1.0,
+        requested_epsilon: 1.0,
+        is_relevant_event: SimpleRelevantEventSelector { lambda: |_| true },
+        report_uris: uris.clone(),
+    };
+
+    // Charge epoch 1, then prune it.
+    let report = pds.compute_report(&request(1, 1))?;
+    assert_eq!(report.filtered_report.bin_value, Some((1, 0.5)));
+    assert!(pds.prune_epochs(2)? > 0);
+    assert!(pds.core.filter_storage.get_filter(&Global(1))?.is_none());
+
+    // Epoch 1 is now ignored, and its filters are not recreated.
+    let report = pds.compute_report(&request(1, 1))?;
+    assert_eq!(report.filtered_report.bin_value, None);
+    assert!(pds.core.filter_storage.get_filter(&Global(1))?.is_none());
+
+    // Epoch 2 is still usable, and the watermark never goes back.
+    assert_eq!(pds.prune_epochs(1)?, 0);
+    let report = pds.compute_report(&request(1, 2))?;
+    assert_eq!(report.filtered_report.bin_value, Some((2, 0.5)));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_pruning_watermark_survives_restart() -> Result<(), anyhow::Error> {
+    let capacities: StaticCapacities<FilterId, PureDPBudget> =
+        StaticCapacities::mock();
+    let filters = SimpleFilterStorage::new(capacities)?;
+    let mut pds = SimplePds::new(filters, SimpleEventStorage::new());
+    pds.register_event(SimpleEvent {
+        id: 1,
+        epoch_number: 1,
+        event_key: 1,
+        uris: EventUris::mock(),
+    })?;
+    pds.prune_epochs(2)?;
+
+    // Restart from the same storages: the pruned epoch stays pruned, instead
+    // of being charged again with fresh filters.
+    let mut pds = SimplePds::new(pds.core.filter_storage, pds.event_storage);
+    assert_eq!(pds.core.pruned_before, Some(2));
+    assert!(pds.core.is_pruned(&1));
+
+    let report = pds.compute_report(&SimpleLastTouchHistogramRequest {
+        epoch_start: 1,
+        epoch_end: 1,
+        report_global_sensitivity: 0.5,
+        query_global_sensitivity: 1.0,
+        requested_epsilon: 1.0,
+        is_relevant_event: SimpleRelevantEventSelector { lambda: |_| true },
+        report_uris: ReportRequestUris::mock(),
+    })?;
+    assert_eq!(report.filtered_report.bin_value, None);
+    assert!(pds.core.filter_storage.get_filter(&Global(1))?.is_none());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_multi_beneficiary_requests() -> Result<(), anyhow::Error> {
+    use crate::pds::epoch_policy::QuerierEpochGranularity;
+
+    let capacities = StaticCapacities::mock();
+    let filters = SimpleFilterStorage::new(capacities)?;
+    let mut pds = SimplePds::new(filters, SimpleEventStorage::new());
+
+    // Only adtech.com can read the most recent event.
+    pds.register_event(SimpleEvent {
+        id: 1,
+        epoch_number: 1,
+        event_key: 1,
+        uris: EventUris::mock(),
+    })?;
+    pds.register_event(SimpleEvent {
+        id: 2,
+        epoch_number: 2,
+        event_key: 2,
+        uris: EventUris {
+            querier_uris: vec!["adtech.com".to_string()],
+            ..EventUris::mock()
+        },
+    })?;
+
+    let querier_uris = vec!["shoes.com".to_string(), "adtech.com".to_string()];
+    let request = SimpleLastTouchHistogramRequest {
+        epoch_start: 1,
+        epoch_end: 2,
+        report_global_sensitivity: 0.5,
+        query_global_sensitivity: 1.0,
+        requested_epsilon: 1.0,
+        is_relevant_event: SimpleRelevantEventSelector { lambda: |_| true },
+        report_uris: ReportRequestUris {
+            querier_uris: querier_uris.clone(),
+            ..ReportRequestUris::mock()
+        },
+    };
+
+    // The shared report only attributes the events that every querier can
+    // read. Each querier pays on its own filter, and the Global filter is
+    // charged once.
+    let report = pds.compute_report(&request)?;
+    assert_eq!(report.filtered_report.bin_value, Some((1, 0.5)));
+    for querier_uri in &querier_uris {
+        let filter_id = PerQuerier(1, querier_uri.clone());
+        assert_eq!(pds.core.filter_storage.remaining_budget(&filter_id)?, 0.5);
+    }
+    assert_eq!(pds.core.filter_storage.remaining_budget(&Global(1))?, 19.5);
+
+    // Queriers must share their epochs.
+    let mut epoch_policy = QuerierEpochGranularity::new();
+    epoch_policy.set_granularity("adtech.com".to_string(), 7)?;
+    let filters = SimpleFilterStorage::new(StaticCapacities::mock())?;
+    let mut pds = SimplePds::new(filters, SimpleEventStorage::new())
+        .with_epoch_policy(epoch_policy);
+    let result = pds.compute_report(&request);
+    assert!(matches!(result, Err(PdsError::InvalidRequest(_))));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_budget_reservations() -> Result<(), anyhow::Error> {
+    let capacities: StaticCapacities<FilterId, PureDPBudget> =
+        StaticCapacities::mock();
+    let filters = SimpleFilterStorage::new(capacities)?;
+    let clock = MockClock::new(0);
+    let mut pds = SimplePds::new(filters, SimpleEventStorage::new())
+        .with_clock(clock.clone())
+        .with_reservation_ttl(10);
+
+    pds.register_event(SimpleEvent {
+        id: 1,
+        epoch_number: 1,
+        event_key: 3,
+        uris: EventUris::mock(),
+    })?;
+
+    let uris = ReportRequestUris::mock();
+    let request = SimpleLastTouchHistogramRequest {
+        epoch_start: 1,
+        epoch_end: 1,
+        report_global_sensitivity: 1.0,
+        query_global_sensitivity: 1.0,
+        requested_epsilon: 1.0,
+        is_relevant_event: SimpleRelevantEventSelector { lambda: |_| true },
+        report_uris: uris.clone(),
+    };
+    let querier_filter = PerQuerier(1, uris.querier_uris[0].clone());
+    let consumed = |pds: &mut SimplePds| -> Result<f64, anyhow::Error> {
+        let filter = pds.core.filter_storage.get_filter(&querier_filter)?;
+        Ok(filter.map(|f| f.consumed).unwrap_or_default())
+    };
+
+    // Reserved budget is deducted, and the reservation is persisted.
+    let token = pds.reserve_budget(&request)?;
+    assert_eq!(consumed(&mut pds)?, 1.0);
+    assert_eq!(pds.core.filter_storage.reservations()?.len(), 1);
+
+    // Releasing refunds the budget, and the token can't be used anymore.
+    pds.release(token)?;
+    assert_eq!(consumed(&mut pds)?, 0.0);
+    assert!(matches!(
+        pds.commit(token),
+        Err(PdsError::InvalidRequest(_))
+    ));
+
+    // Committing returns the report and keeps the budget deducted.
+    let token = pds.reserve_budget(&request)?;
+    let report = pds.commit(token)?;
+    assert_eq!(report.filtered_report.bin_value, Some((3, 1.0)));
+    assert_eq!(consumed(&mut pds)?, 1.0);
+    assert!(pds.core.filter_storage.reservations()?.is_empty());
+
+    // Expired reservations are refunded.
+    let filters = SimpleFilterStorage::new(StaticCapacities::mock())?;
+    pds.core.filter_storage = filters;
+    let token = pds.reserve_budget(&request)?;
+    clock.advance(10);
+    assert_eq!(pds.release_expired_reservations()?, 1);
+    assert_eq!(consumed(&mut pds)?, 0.0);
+    assert!(pds.commit(token).is_err());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_epoch_window_validation() -> Result<(), anyhow::Error> {
+    let filters = SimpleFilterStorage::new(StaticCapacities::mock())?;
+    let mut pds = SimplePds::new(filters, SimpleEventStorage::new())
+        .with_max_attribution_window(3);
+    pds.set_current_epoch(10);
+
+    let request = |epoch_start, epoch_end| SimpleLastTouchHistogramRequest {
+        epoch_start,
+        epoch_end,
+        report_global_sensitivity: 0.5,
+        query_global_sensitivity: 1.0,
+        requested_epsilon: 1.0,
+        is_relevant_event: SimpleRelevantEventSelector { lambda: |_| true },
+        report_uris: ReportRequestUris::mock(),
+    };
+    let is_invalid_window = |result: Result<_, PdsError>| {
+        matches!(result, Err(PdsError::InvalidEpochWindow(_)))
+    };
+
+    pds.compute_report(&request(8, 10))?;
+    assert!(is_invalid_window(pds.compute_report(&request(3, 2))));
+    assert!(is_invalid_window(pds.compute_report(&request(7, 10))));
+    assert!(is_invalid_window(pds.compute_report(&request(10, 11))));
+    assert!(matches!(
+        pds.reserve_budget(&request(10, 11)),
+        Err(PdsError::InvalidEpochWindow(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_clock_regression() -> Result<(), anyhow::Error> {
+    let filters = SimpleFilterStorage::new(StaticCapacities::mock())?;
+    let guard = LateEventGuard::new(1).with_retention_epochs(3)?;
+    let mut pds = SimplePds::new(filters, SimpleEventStorage::new())
+        .with_epoch_guard(guard);
+    let event = |id, epoch_number| SimpleEvent {
+        id,
+        epoch_number,
+        event_key: 1,
+        uris: EventUris::mock(),
+    };
+    let n_events = |pds: &SimplePds, epoch_number| -> Result<_, PdsError> {
+        Ok(pds.event_storage.events_for_epoch(&epoch_number)?.count())
+    };
+
+    // Without a current epoch, events are never late.
+    pds.register_event(event(1, 1))?;
+    assert_eq!(n_events(&pds, 1)?, 1);
+
+    // The clock jumps back to epoch 8, but the current epoch stays at 10.
+    pds.set_current_epoch(10);
+    pds.set_current_epoch(8);
+    assert_eq!(pds.current_epoch, Some(10));
+
+    // Events within the grace period are stored, older ones are dropped,
+    // and events beyond the retention window are rejected.
+    pds.register_event(event(2, 9))?;
+    pds.register_event(event(3, 8))?;
+    assert_eq!(n_events(&pds, 9)?, 1);
+    assert_eq!(n_events(&pds, 8)?, 0);
+    assert!(matches!(
+        pds.register_event(event(4, 6)),
+        Err(PdsError::StaleEvent(_))
+    ));
+    assert_eq!(n_events(&pds, 6)?, 0);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_campaign_quotas() -> Result<(), anyhow::Error> {
+    let capacities = StaticCapacities::mock().with_campaign_quota(0.5);
+    let filters = SimpleFilterStorage::new(capacities)?;
+    let mut pds = SimplePds::new(filters, SimpleEventStorage::new());
+
+    pds.register_event(SimpleEvent {
+        id: 1,
+        epoch_number: 1,
+        event_key: 3,
+        uris: EventUris::mock(),
+    })?;
+
+    // Each report costs 0.5, so a campaign can only get one.
+    let request = |campaign_id| SimpleLastTouchHistogramRequest {
+        epoch_start: 1,
+        epoch_end: 1,
+        report_global_sensitivity: 0.5,
+        query_global_sensitivity: 1.0,
+        requested_epsilon: 1.0,
+        is_relevant_event: SimpleRelevantEventSelector { lambda: |_| true },
+        report_uris: ReportRequestUris {
+            campaign_id: Some(campaign_id),
+            ..ReportRequestUris::mock()
+        },
+    };
+
+    let report = pds.compute_report(&request(1))?;
+    assert_eq!(report.filtered_report.bin_value, Some((3, 0.5)));
+
+    let report = pds.compute_report(&request(1))?;
+    assert_eq!(report.filtered_report.bin_value, None);
+    let querier_uri = ReportRequestUris::mock().querier_uris[0].clone();
+    assert_eq!(report.oob_filters, vec![CampaignQuota(1, querier_uri, 1)]);
+
+    // Other campaigns still have budget, within the per-querier filter.
+    let report = pds.compute_report(&request(2))?;
+    assert_eq!(report.filtered_report.bin_value, Some((3, 0.5)));
+    let report = pds.compute_report(&request(3))?;
+    assert_eq!(report.filtered_report.bin_value, None);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_querier_groups() -> Result<(), anyhow::Error> {
+    let filters = SimpleFilterStorage::new(StaticCapacities::mock())?;
+    let groups = QuerierGroups::new().with_group(
+        "adtech".to_string(),
+        ["adtech.com", "shoes.com"].map(String::from),
+    )?;
+    let mut pds = SimplePds::new(filters, SimpleEventStorage::new())
+        .with_querier_groups(groups);
+    pds.register_event(SimpleEvent {
+        id: 1,
+        epoch_number: 1,
+        event_key: 3,
+        uris: EventUris::mock(),
+    })?;
+
+    // Each report costs 0.5, and both queriers draw from the PerQuerier
+    // filter of their group.
+    let request = |querier_uri: &str| SimpleLastTouchHistogramRequest {
+        epoch_start: 1,
+        epoch_end: 1,
+        report_global_sensitivity: 0.5,
+        query_global_sensitivity: 1.0,
+        requested_epsilon: 1.0,
+        is_relevant_event: SimpleRelevantEventSelector { lambda: |_| true },
+        report_uris: ReportRequestUris {
+            querier_uris: vec![querier_uri.to_string()],
+            ..ReportRequestUris::mock()
+        },
+    };
+    for querier_uri in ["adtech.com", "shoes.com"] {
+        let report = pds.compute_report(&request(querier_uri))?;
+        assert_eq!(report.filtered_report.bin_value, Some((3, 0.5)));
+    }
+    let report = pds.compute_report(&request("shoes.com"))?;
+    assert_eq!(report.filtered_report.bin_value, None);
+    assert_eq!(report.oob_filters, vec![PerQuerier(1, "adtech".into())]);
+
+    // Queriers can't be in two groups.
+    assert!(QuerierGroups::new()
+        .with_group("a".to_string(), ["adtech.com".to_string()])?
+        .with_group("b".to_string(), ["adtech.com".to_string()])
+        .is_err());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_accounting_summary() -> Result<(), anyhow::Error> {
+    let filters = SimpleFilterStorage::new(StaticCapacities::mock())?;
+    let mut pds = SimplePds::new(filters, SimpleEventStorage::new());
+    pds.register_event(SimpleEvent {
+        id: 1,
+        epoch_number: 1,
+        event_key: 3,
+        uris: EventUris::mock(),
+    })?;
+
+    // Each report costs 0.5 on every filter of epoch 1.
+    let request = SimpleLastTouchHistogramRequest {
+        epoch_start: 1,
+        epoch_end: 1,
+        report_global_sensitivity: 0.5,
+        query_global_sensitivity: 1.0,
+        requested_epsilon: 1.0,
+        is_relevant_event: SimpleRelevantEventSelector { lambda: |_| true },
+        report_uris: ReportRequestUris::mock(),
+    };
+    pds.compute_report(&request)?;
+
+    // Released reservations are refunded in the statistics too.
+    let token = pds.reserve_budget(&request)?;
+    pds.release(token)?;
+
+    let summary = pds.accounting_summary(1);
+    assert_eq!(summary.totals.get(&FilterKind::PerQuerier), Some(&0.5));
+    assert_eq!(summary.totals.get(&FilterKind::Global), Some(&0.5));
+    assert_eq!(summary.epochs.keys().copied().collect::<Vec<_>>(), [1]);
+    assert_eq!(summary.top_consumers.len(), 1);
+    assert_eq!(summary.top_consumers[0].1, 0.5);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_report_status() -> Result<(), anyhow::Error> {
+    let filters = SimpleFilterStorage::new(StaticCapacities::mock())?;
+    let mut pds = SimplePds::new(filters, SimpleEventStorage::new());
+    for epoch_number in [1, 2] {
+        pds.register_event(SimpleEvent {
+            id: epoch_number,
+            epoch_number,
+            event_key: 3,
+            uris: EventUris::mock(),
+        })?;
+    }
+
+    // Each report costs 0.5 per epoch, so the per-querier filter of an epoch
+    // is exhausted after two single-epoch reports.
+    let request = |epoch_start, epoch_end| SimpleLastTouchHistogramRequest {
+        epoch_start,
+        epoch_end,
+        report_global_sensitivity: 0.5,
+        query_global_sensitivity: 1.0,
+        requested_epsilon: 1.0,
+        is_relevant_event: SimpleRelevantEventSelector { lambda: |_| true },
+        report_uris: ReportRequestUris::mock(),
+    };
+    for _ in 0..2 {
+        let report = pds.compute_report(&request(1, 1))?;
+        assert_eq!(report.status, ReportStatus::Full);
+        assert_eq!(report.retry_advice, None);
+    }
+    let report = pds.compute_report(&request(1, 1))?;
+    assert_eq!(
+        report.status,
+        ReportStatus::Null {
+            reason: NullReason::OutOfBudget
+        }
+    );
+    assert!(report.status.is_out_of_budget());
+    // The device filters depend on its data, so they never drive the advice.
+    assert_eq!(report.retry_advice, None);
+
+    // Epoch 2 still has budget.
+    let report = pds.compute_report(&request(1, 2))?;
+    assert_eq!(
+        report.status,
+        ReportStatus::PartiallyFiltered {
+            dropped_epochs: vec![1]
+        }
+    );
+    assert_eq!(report.filtered_report.bin_value, Some((3, 0.5)));
+
+    let report = pds.compute_report(&request(3, 4))?;
+    assert_eq!(
+        report.status,
+        ReportStatus::Null {
+            reason: NullReason::NoRelevantEvents
+        }
+    );
+    assert!(!report.status.is_out_of_budget());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_source_trigger_quotas() -> Result<(), anyhow::Error> {
+    let capacities = StaticCapacities::new(10.0, 20.0, 10.0, 10.0)
+        .with_source_trigger_quota(1.0);
+    let filters = SimpleFilterStorage::new(capacities)?;
+    let mut pds = SimplePds::new(filters, SimpleEventStorage::new());
+
+    pds.register_event(SimpleEvent {
+        id: 1,
+        epoch_number: 1,
+        event_key: 3,
+        uris: EventUris::mock(),
+    })?;
+
+    // Each report costs 0.5, so a source-trigger pair can only get two.
+    let request = |trigger_uri: &str| SimpleLastTouchHistogramRequest {
+        epoch_start: 1,
+        epoch_end: 1,
+        report_global_sensitivity: 0.5,
+        query_global_sensitivity: 1.0,
+        requested_epsilon: 1.0,
+        is_relevant_event: SimpleRelevantEventSelector { lambda: |_| true },
+        report_uris: ReportRequestUris {
+            trigger_uri: trigger_uri.to_string(),
+            ..ReportRequestUris::mock()
+        },
+    };
+    for _ in 0..2 {
+        let report = pds.compute_report(&request("shoes.com"))?;
+        assert_eq!(report.filtered_report.bin_value, Some((3, 0.5)));
+    }
+    let report = pds.compute_report(&request("shoes.com"))?;
+    assert_eq!(report.filtered_report.bin_value, None);
+    let source_uri = ReportRequestUris::mock().source_uris[0].clone();
+    assert_eq!(
+        report.oob_filters,
+        vec![SourceTriggerQuota(
+            1,
+            source_uri.clone(),
+            "shoes.com".into()
+        )]
+    );
+    assert_eq!(report.retry_advice, None);
+
+    // On public filters, an exhausted epoch dominates throttled quotas.
+    assert!(
+        RetryAdvice::for_filter_kind(FilterKind::Global)
+            > RetryAdvice::for_filter_kind(FilterKind::SourceTriggerQuota)
+    );
+
+    // The same source still has budget with other triggers.
+    let report = pds.compute_report(&request("hats.com"))?;
+    assert_eq!(report.filtered_report.bin_value, Some((3, 0.5)));
+
+    // Pair quotas are disabled by default.
+    let filters = SimpleFilterStorage::new(StaticCapacities::mock())?;
+    let mut pds = SimplePds::new(filters, SimpleEventStorage::new());
+    pds.compute_report(&request("shoes.com"))?;
+    let pair_quota = SourceTriggerQuota(1, source_uri, "shoes.com".into());
+    assert!(pds.core.filter_storage.get_filter(&pair_quota)?.is_none());
+
+    Ok(())
+}
+
+/// Mechanism moving the attributed bucket, to recognize fallback reports.
+#[cfg(feature = "experimental")]
+struct ShiftBucket;
+
+#[cfg(feature = "experimental")]
+impl LdpMechanism<SimpleLastTouchHistogramRequest> for ShiftBucket {
+    fn randomize(
+        &mut self,
+        _request: &SimpleLastTouchHistogramRequest,
+        report: &SimpleLastTouchHistogramReport,
+        _epsilon: PureDPBudget,
+    ) -> SimpleLastTouchHistogramReport {
+        SimpleLastTouchHistogramReport {
+            bin_value: report.bin_value.map(|(key, value)| (key + 100, value)),
+        }
+    }
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_quota_exemptions() -> Result<(), anyhow::Error> {
+    let capacities = StaticCapacities::new(10.0, 1.5, 0.5, 10.0);
+    let filters = SimpleFilterStorage::new(capacities)?;
+    let exemptions = QuotaExemptions::new().with_uri("shoes.com".to_string());
+    let mut pds = SimplePds::new(filters, SimpleEventStorage::new())
+        .with_quota_exemptions(exemptions);
+
+    for epoch_number in [1, 2] {
+        pds.register_event(SimpleEvent {
+            id: epoch_number,
+            epoch_number,
+            event_key: 3,
+            uris: EventUris::mock(),
+        })?;
+    }
+
+    // Each report costs 0.5.
+    let request = |epoch, querier_uri: &str| SimpleLastTouchHistogramRequest {
+        epoch_start: epoch,
+        epoch_end: epoch,
+        report_global_sensitivity: 0.5,
+        query_global_sensitivity: 1.0,
+        requested_epsilon: 1.0,
+        is_relevant_event: SimpleRelevantEventSelector { lambda: |_| true },
+        report_uris: ReportRequestUris {
+            querier_uris: vec![querier_uri.to_string()],
+            ..ReportRequestUris::mock()
+        },
+    };
+
+    // The advertiser measuring its own conversions skips its TriggerQuota,
+    // but is still bounded by the Global filter.
+    for _ in 0..3 {
+        let report = pds.compute_report(&request(1, "shoes.com"))?;
+        assert_eq!(report.filtered_report.bin_value, Some((3, 0.5)));
+    }
+    let report = pds.compute_report(&request(1, "shoes.com"))?;
+    assert_eq!(report.filtered_report.bin_value, None);
+    assert_eq!(report.oob_filters, vec![Global(1)]);
+    assert_remaining_budgets(
+        &mut pds.core.filter_storage,
+        &[
+            (Global(1), 0.0),
+            (TriggerQuota(1, "shoes.com".to_string()), 0.5),
+            (PerQuerier(1, "shoes.com".to_string()), 8.5),
+        ],
+    )?;
+
+    // Third-party queriers are still charged on the quota.
+    let report = pds.compute_report(&request(2, "adtech.com"))?;
+    assert_eq!(report.filtered_report.bin_value, Some((3, 0.5)));
+    let report = pds.compute_report(&request(2, "adtech.com"))?;
+    assert_eq!(
+        report.oob_filters,
+        vec![TriggerQuota(2, "shoes.com".to_string())]
+    );
+
+    // Predicates only apply to first-party measurements too.
+    let exemptions = QuotaExemptions::new()
+        .with_predicate(|party: &String, _| party.ends_with("blog.com"));
+    let uris = ReportRequestUris::mock();
+    assert!(!exemptions.is_exempt(&"blog.com".to_string(), &uris));
+    let uris = ReportRequestUris {
+        querier_uris: vec!["blog.com".to_string()],
+        ..uris
+    };
+    assert!(exemptions.is_exempt(&"blog.com".to_string(), &uris));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_ldp_fallback() -> Result<(), anyhow::Error> {
+    let capacities = StaticCapacities::mock().with_ldp_capacity(1.0);
+    let filters = SimpleFilterStorage::new(capacities)?;
+    let mut pds = SimplePds::new(filters, SimpleEventStorage::new())
+        .with_ldp_fallback(LdpFallback::new(0.4, ShiftBucket));
+
+    pds.register_event(SimpleEvent {
+        id: 1,
+        epoch_number: 1,
+        event_key: 3,
+        uris: EventUris::mock(),
+    })?;
+
+    // Each report costs 0.5, so the per-querier filter allows two reports.
+    let request = SimpleLastTouchHistogramRequest {
+        epoch_start: 1,
+        epoch_end: 1,
+        report_global_sensitivity: 0.5,
+        query_global_sensitivity: 1.0,
+        requested_epsilon: 1.0,
+        is_relevant_event: SimpleRelevantEventSelector { lambda: |_| true },
+        report_uris: ReportRequestUris::mock(),
+    };
+    for _ in 0..2 {
+        let report = pds.compute_report(&request)?;
+        assert_eq!(report.filtered_report.bin_value, Some((3, 0.5)));
+    }
+
+    // Then the fallback kicks in, twice within the LDP capacity.
+    for _ in 0..2 {
+        let report = pds.compute_report(&request)?;
+        assert_eq!(report.filtered_report.bin_value, Some((103, 0.5)));
+    }
+    let remaining = pds.core.filter_storage.remaining_budget(&Ldp(1))?;
+    assert!((remaining - 0.2).abs() < 1e-9);
+
+    let report = pds.compute_report(&request)?;
+    assert_eq!(report.filtered_report.bin_value, None);
+    assert!(report.oob_filters.contains(&Ldp(1)));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_preflight() -> Result<(), anyhow::Error> {
+    let capacities = StaticCapacities::mock();
+    let filters = SimpleFilterStorage::new(capacities)?;
+    let mut pds = SimplePds::new(filters, SimpleEventStorage::new());
+
+    pds.register_event(SimpleEvent {
+        id: 1,
+        epoch_number: 1,
+        event_key: 3,
+        uris: EventUris::mock(),
+    })?;
+
+    // Each report costs 0.5, out of 1.0 for the per-querier filter.
+    let request = SimpleLastTouchHistogramRequest {
+        epoch_start: 1,
+        epoch_end: 1,
+        report_global_sensitivity: 0.5,
+        query_global_sensitivity: 1.0,
+        requested_epsilon: 1.0,
+        is_relevant_event: SimpleRelevantEventSelector { lambda: |_| true },
+        report_uris: ReportRequestUris::mock(),
+    };
+    let per_querier =
+        PerQuerier(1, ReportRequestUris::mock().querier_uris[0].clone());
+
+    // Preflight doesn't consume any budget.
+    let result = pds.preflight(&request)?;
+    assert!(result.answerable);
+    assert_eq!(result.headroom.len(), 4);
+    assert_eq!(result.headroom_of(&Global(1)), Some(Headroom::Many));
+    assert_eq!(result.headroom_of(&per_querier), Some(Headroom::Few));
+    assert_eq!(result.bottleneck().unwrap().1, Headroom::Few);
+    let result = pds.preflight(&request)?;
+    assert_eq!(result.headroom_of(&per_querier), Some(Headroom::Few));
+
+    pds.compute_report(&request)?;
+    let result = pds.preflight(&request)?;
+    assert!(result.answerable);
+    assert_eq!(result.headroom_of(&per_querier), Some(Headroom::Single));
+
+    pds.compute_report(&request)?;
+    let result = pds.preflight(&request)?;
+    assert!(!result.answerable);
+    assert_eq!(
+        result.bottleneck(),
+        Some(&(per_querier, Headroom::Exhausted))
+    );
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_compute_report_dry_run() -> Result<(), anyhow::Error> {
+    let capacities = StaticCapacities::mock();
+    let filters = SimpleFilterStorage::new(capacities)?;
+    let mut pds = SimplePds::new(filters, SimpleEventStorage::new());
+
+    pds.register_event(SimpleEvent {
+        id: 1,
+        epoch_number: 1,
+        event_key: 3,
+        uris: EventUris::mock(),
+    })?;
+
+    // Each report costs 0.6, out of 1.0 for the per-querier filter.
+    let request = SimpleLastTouchHistogramRequest {
+        epoch_start: 1,
+        epoch_end: 1,
+        report_global_sensitivity: 0.6,
+        query_global_sensitivity: 1.0,
+        requested_epsilon: 1.0,
+        is_relevant_event: SimpleRelevantEventSelector { lambda: |_| true },
+        report_uris: ReportRequestUris::mock(),
+    };
+    let per_querier =
+        PerQuerier(1, ReportRequestUris::mock().querier_uris[0].clone());
+
+    // Dry runs never consume budget.
+    for _ in 0..3 {
+        let report = pds.compute_report_dry_run(&request)?;
+        assert!(report.oob_filters.is_empty());
+        assert_eq!(
+            report.filtered_report.bin_value,
+            report.unfiltered_report.bin_value
+        );
+        assert!(report.unfiltered_report.bin_value.is_some());
+    }
+    let remaining = pds.core.filter_storage.remaining_budget(&per_querier)?;
+    assert_eq!(remaining, 1.0);
+
+    // Once the filter is OOB, the dry run reports it but still returns the
+    // unfiltered report.
+    pds.compute_report(&request)?;
+    let report = pds.compute_report_dry_run(&request)?;
+    assert_eq!(report.oob_filters, vec![per_querier.clone()]);
+    assert_eq!(report.filtered_report.bin_value, None);
+    assert!(report.unfiltered_report.bin_value.is_some());
+    let remaining = pds.core.filter_storage.remaining_budget(&per_querier)?;
+    assert!((remaining - 0.4).abs() < 1e-9);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_rate_limit() -> Result<(), anyhow::Error> {
+    use crate::pds::rate_limit::RateLimit;
+
+    let capacities = StaticCapacities::mock().with_rate_limit(RateLimit {
+        burst: 2,
+        refill_period: 60,
+    });
+    let filters = SimpleFilterStorage::new(capacities)?;
+    let clock = MockClock::new(0);
+    let mut pds = SimplePds::new(filters, SimpleEventStorage::new())
+        .with_clock(clock.clone());
+
+    pds.register_event(SimpleEvent {
+        id: 1,
+        epoch_number: 1,
+        event_key: 3,
+        uris: EventUris::mock(),
+    })?;
+    let request = SimpleLastTouchHistogramRequest {
+        epoch_start: 1,
+        epoch_end: 1,
+        report_global_sensitivity: 0.1,
+        query_global_sensitivity: 1.0,
+        requested_epsilon: 1.0,
+        is_relevant_event: SimpleRelevantEventSelector { lambda: |_| true },
+        report_uris: ReportRequestUris::mock(),
+    };
+    let per_querier =
+        PerQuerier(1, ReportRequestUris::mock().querier_uris[0].clone());
+
+    for _ in 0..2 {
+        pds.compute_report(&request)?;
+    }
+    let remaining = pds.core.filter_storage.remaining_budget(&per_querier)?;
+
+    // Budget is left, but the querier has to wait for a new token. Rejected
+    // requests cost no budget.
+    let result = pds.compute_report(&request);
+    assert!(matches!(result, Err(PdsError::RateLimited(_))));
+    assert!(pds.reserve_budget(&request).is_err());
+    assert_eq!(
+        pds.core.filter_storage.remaining_budget(&per_querier)?,
+        remaining
+    );
+
+    clock.advance(60);
+    let report = pds.compute_report(&request)?;
+    assert!(report.filtered_report.bin_value.is_some());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_querier_headroom() -> Result<(), anyhow::Error> {
+    use crate::{pds::introspection::HeadroomLevel, util::rng::new_rng};
+
+    let mut rng = new_rng(Some(0));
+    let querier_uri = ReportRequestUris::mock().querier_uris[0].clone();
+
+    // Introspection is disabled by default.
+    let filters = SimpleFilterStorage::new(StaticCapacities::mock())?;
+    let mut pds = SimplePds::new(filters, SimpleEventStorage::new());
+    let indicators =
+        pds.core
+            .querier_headroom(&querier_uri, &[1], 0.5, &mut rng)?;
+    assert_eq!(indicators[0].level, None);
+
+    let capacities = StaticCapacities::mock().with_introspection_capacity(1.0);
+    let filters = SimpleFilterStorage::new(capacities)?;
+    let mut pds = SimplePds::new(filters, SimpleEventStorage::new());
+
+    // Each indicator is charged on the introspection filter of its epoch.
+    let indicators =
+        pds.core
+            .querier_headroom(&querier_uri, &[1, 2], 0.5, &mut rng)?;
+    assert!(indicators.iter().all(|i| i.level.is_some()));
+    let indicators =
+        pds.core
+            .querier_headroom(&querier_uri, &[1], 0.5, &mut rng)?;
+    assert!(indicators[0].level.is_some());
+    assert_remaining_budgets(
+        &mut pds.core.filter_storage,
+        &[(Introspection(1), 0.0), (Introspection(2), 0.5)],
+    )?;
+
+    let indicators =
+        pds.core
+            .querier_headroom(&querier_uri, &[1], 0.5, &mut rng)?;
+    assert_eq!(indicators[0].level, None);
+
+    // Epsilon must be positive.
+    let result = pds.core.querier_headroom(&querier_uri, &[2], 0.0, &mut rng);
+    assert!(matches!(result, Err(PdsError::InvalidRequest(_))));
+
+    // A fully used per-querier filter can't look like it has high headroom
+    // for large epsilons.
+    pds.core
+        .filter_storage
+        .try_consume(&PerQuerier(2, querier_uri.clone()), &1.0)?;
+    let indicators =
+        pds.core
+            .querier_headroom(&querier_uri, &[2], 100.0, &mut rng)?;
+    assert_ne!(indicators[0].level, Some(HeadroomLevel::High));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_quota_report() -> Result<(), anyhow::Error> {
+    let filters = SimpleFilterStorage::new(StaticCapacities::mock())?;
+    let mut pds = SimplePds::new(filters, SimpleEventStorage::new());
+    let querier_uri = ReportRequestUris::mock().querier_uris[0].clone();
+    let other_querier_uri = "other-adtech.com".to_string();
+
+    let filters = &mut pds.core.filter_storage;
+    filters.try_consume(&PerQuerier(0, querier_uri.clone()), &1.0)?;
+    filters.try_consume(&PerQuerier(1, querier_uri.clone()), &1.0)?;
+    filters.try_consume(&PerQuerier(2, querier_uri.clone()), &0.5)?;
+
+    // Other filters don't show up in the report of the querier.
+    filters.try_consume(&Global(3), &20.0)?;
+    filters.try_consume(&PerQuerier(3, other_querier_uri.clone()), &1.0)?;
+
+    let report = pds.core.quota_report(&querier_uri, &[3, 2, 1, 0])?;
+    assert_eq!(report.exhausted_epochs, vec![1, 0]);
+    let report = pds.core.quota_report(&other_querier_uri, &[3, 2, 1, 0])?;
+    assert_eq!(report.exhausted_epochs, vec![3]);
+
+    // Pruned epochs are skipped.
+    pds.prune_epochs(1)?;
+    let report = pds.core.quota_report(&querier_uri, &[3, 2, 1, 0])?;
+    assert_eq!(report.exhausted_epochs, vec![1]);
+    assert_eq!(
+        serde_json::to_string(&report)?,
+        r#"{"exhausted_epochs":[1]}"#
+    );
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_querier_epoch_granularity() -> Result<(), anyhow::Error> {
+    use crate::pds::epoch_policy::QuerierEpochGranularity;
+
+    // adtech.com has weekly epochs, over daily base epochs.
+    let querier_uri = ReportRequestUris::mock().querier_uris[0].clone();
+    let mut epoch_policy = QuerierEpochGranularity::new();
+    epoch_policy.set_granularity(querier_uri.clone(), 7)?;
+
+    let filters = SimpleFilterStorage::new(StaticCapacities::mock())?;
+    let mut pds = SimplePds::new(filters, SimpleEventStorage::new())
+        .with_epoch_policy(epoch_policy);
+    for (id, epoch_number) in [(1, 14), (2, 16), (3, 21)] {
+        pds.register_event(SimpleEvent {
+            id,
+            epoch_number,
+            event_key: id,
+            uris: EventUris::mock(),
+        })?;
+    }
+
+    // Week 2 covers the days 14 to 20.
+    let request = SimpleLastTouchHistogramRequest {
+        epoch_start: 2,
+        epoch_end: 2,
+        report_global_sensitivity: 0.5,
+        query_global_sensitivity: 1.0,
+        requested_epsilon: 1.0,
+        is_relevant_event: SimpleRelevantEventSelector { lambda: |_| true },
+        report_uris: ReportRequestUris::mock(),
+    };
+    let report = pds.compute_report(&request)?;
+    assert_eq!(report.filtered_report.bin_value, Some((2, 0.5)));
+
+    // The per-querier filter is in weeks, the others in days.
+    assert_remaining_budgets(
+        &mut pds.core.filter_storage,
+        &[
+            (PerQuerier(2, querier_uri.clone()), 0.5),
+            (PerQuerier(14, querier_uri.clone()), 1.0),
+            (Global(2), 20.0),
+            (Global(14), 19.5),
+            (Global(20), 19.5),
+            (Global(21), 20.0),
+        ],
+    )?;
+
+    // The current epoch is in days too.
+    pds.set_current_epoch(15);
+    assert!(matches!(
+        pds.compute_report(&request),
+        Err(PdsError::InvalidEpochWindow(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_prune_querier_epochs() -> Result<(), anyhow::Error> {
+    use crate::pds::epoch_policy::QuerierEpochGranularity;
+
+    // adtech.com has weekly epochs, over daily base epochs.
+    let querier_uri = ReportRequestUris::mock().querier_uris[0].clone();
+    let mut epoch_policy = QuerierEpochGranularity::new();
+    epoch_policy.set_granularity(querier_uri.clone(), 7)?;
+
+    let filters = SimpleFilterStorage::new(StaticCapacities::mock())?;
+    let mut pds = SimplePds::new(filters, SimpleEventStorage::new())
+        .with_epoch_policy(epoch_policy);
+    pds.register_event(SimpleEvent {
+        id: 1,
+        epoch_number: 22,
+        event_key: 1,
+        uris: EventUris::mock(),
+    })?;
+
+    // Week 3 covers the days 21 to 27.
+    let request = SimpleLastTouchHistogramRequest {
+        epoch_start: 3,
+        epoch_end: 3,
+        report_global_sensitivity: 0.5,
+        query_global_sensitivity: 1.0,
+        requested_epsilon: 1.0,
+        is_relevant_event: SimpleRelevantEventSelector { lambda: |_| true },
+        report_uris: ReportRequestUris::mock(),
+    };
+    pds.compute_report(&request)?;
+
+    // Pruning day 15 keeps week 3, even though 3 < 15, so its PerQuerier
+    // filter isn't recreated with full capacity.
+    pds.prune_epochs(15)?;
+    let week_3 = PerQuerier(3, querier_uri.clone());
+    assert!(!pds.core.is_filter_pruned(&week_3));
+    assert_remaining_budgets(
+        &mut pds.core.filter_storage,
+        &[(week_3.clone(), 0.5)],
+    )?;
+    pds.compute_report(&request)?;
+    let report = pds.compute_report(&request)?;
+    assert_eq!(report.filtered_report.bin_value, None);
+    assert_eq!(report.oob_filters, vec![week_3.clone()]);
+
+    // Week 3 is pruned once all of its days are.
+    pds.prune_epochs(27)?;
+    assert!(pds.core.filter_storage.get_filter(&week_3)?.is_some());
+    pds.prune_epochs(28)?;
+    assert!(pds.core.is_filter_pruned(&week_3));
+    assert!(pds.core.filter_storage.get_filter(&week_3)?.is_none());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_duration_windows() -> Result<(), anyhow::Error> {
+    use crate::{
+        events::ppa_event::PpaEvent,
+        pds::{
+            aliases::{PpaEventStorage, PpaFilterStorage, PpaPds},
+            epoch_policy::QuerierEpochGranularity,
+        },
+        queries::{
+            builder::PpaHistogramRequestBuilder,
+            epoch_selection::EpochSelection,
+            ppa_histogram::{
+                AttributionLogic, FilterDataPredicate, RequestedBuckets,
+            },
+        },
+    };
+
+    const DAY: u64 = 24 * 60 * 60;
+    let new_pds = |epoch_duration: Option<u64>| -> Result<PpaPds, PdsError> {
+        let mut epoch_policy = QuerierEpochGranularity::new();
+        if let Some(epoch_duration) = epoch_duration {
+            epoch_policy.set_base_epoch_duration(epoch_duration)?;
+        }
+        let filters = PpaFilterStorage::new(StaticCapacities::mock())?;
+        let mut pds = PpaPds::<_>::new(filters, PpaEventStorage::new())
+            .with_epoch_policy(epoch_policy);
+        // The first impression has a higher priority, but happened before
+        // the window, in its first epoch.
+        for (id, timestamp, histogram_index, priority) in
+            [(1, 7 * DAY + DAY / 4, 1, 1), (2, 9 * DAY, 2, 0)]
+        {
+            pds.register_event(PpaEvent {
+                id,
+                timestamp,
+                epoch_number: timestamp / epoch_duration.unwrap_or(DAY),
+                histogram_index,
+                uris: EventUris::mock(),
+                filter_data: 0,
+                priority,
+            })?;
+        }
+        Ok(pds)
+    };
+
+    // The last 3 days before a conversion at 10.5 days.
+    let request = PpaHistogramRequestBuilder::new()
+        .epochs(EpochSelection::Duration {
+            trigger_time: 10 * DAY + DAY / 2,
+            duration: 3 * DAY,
+        })?
+        .value(1.0, 1.0, 1.0)?
+        .uris(ReportRequestUris::mock())?
+        .selector(FilterDataPredicate::Any, 8, RequestedBuckets::AllBuckets)?
+        .attribution_logic(AttributionLogic::PriorityThenLastTouch)
+        .build();
+
+    // Devices with daily and 12-hour epochs give the same report, and only
+    // charge the epoch of the attributed impression.
+    let querier_uri = ReportRequestUris::mock().querier_uris[0].clone();
+    for (epoch_duration, first_epoch, second_epoch) in
+        [(DAY, 7, 9), (DAY / 2, 14, 18)]
+    {
+        let mut pds = new_pds(Some(epoch_duration))?;
+        let report = pds.compute_report(&request)?;
+        assert_eq!(
+            report.filtered_report.bin_values,
+            HashMap::from_iter([(2, 1.0)])
+        );
+        assert_remaining_budgets(
+            &mut pds.core.filter_storage,
+            &[
+                (PerQuerier(first_epoch, querier_uri.clone()), 1.0),
+                (PerQuerier(second_epoch, querier_uri.clone()), 0.0),
+            ],
+        )?;
+    }
+
+    // Devices that don't know the duration of their epochs reject them.
+    let mut pds = new_pds(None)?;
+    assert!(matches!(
+        pds.compute_report(&request),
+        Err(PdsError::InvalidConfig(_))
+    ));
+
+    Ok(())
+}
+
+/// Event storage shared with an ingestion thread, simulated by bumping the
+/// generation on the next reads.
+#[cfg(feature = "experimental")]
+struct ConcurrentEventStorage {
+    events: SimpleEventStorage,
+    generation: std::cell::Cell<u64>,
+    n_concurrent_writes: std::cell::Cell<u64>,
+}
+
+#[cfg(feature = "experimental")]
+impl EventStorage for ConcurrentEventStorage {
+    type Event = SimpleEvent;
+    type Error = PdsError;
+
+    fn add_event(&mut self, event: SimpleEvent) -> Result<(), PdsError> {
+        self.events.add_event(event)
+    }
+
+    fn events_for_epoch(
+        &self,
+        epoch_id: &u64,
+    ) -> Result<impl Iterator<Item = &SimpleEvent>, PdsError> {
+        self.events.events_for_epoch(epoch_id)
+    }
+
+    fn generation(&self) -> u64 {
+        let n_concurrent_writes = self.n_concurrent_writes.get();
+        if n_concurrent_writes > 0 {
+            self.n_concurrent_writes.set(n_concurrent_writes - 1);
+            self.generation.set(self.generation.get() + 1);
+        }
+        self.generation.get()
+    }
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_snapshot_consistent_reads() -> Result<(), anyhow::Error> {
+    let filters = SimpleFilterStorage::new(StaticCapacities::mock())?;
+    let events = ConcurrentEventStorage {
+        events: SimpleEventStorage::new(),
+        generation: Default::default(),
+        n_concurrent_writes: Default::default(),
+    };
+    let mut pds: PrivateDataService<_, _, _, PdsError> =
+        PrivateDataService::new(filters, events);
+    pds.register_event(SimpleEvent {
+        id: 1,
+        epoch_number: 1,
+        event_key: 3,
+        uris: EventUris::mock(),
+    })?;
+    let request = SimpleLastTouchHistogramRequest {
+        epoch_start: 1,
+        epoch_end: 1,
+        report_global_sensitivity: 0.5,
+        query_global_sensitivity: 1.0,
+        requested_epsilon: 1.0,
+        is_relevant_event: SimpleRelevantEventSelector { lambda: |_| true },
+        report_uris: ReportRequestUris::mock(),
+    };
+
+    // A write during the first read is detected, and the events are read
+    // again.
+    pds.event_storage.n_concurrent_writes.set(2);
+    let report = pds.compute_report(&request)?;
+    assert_eq!(report.filtered_report.bin_value, Some((3, 0.5)));
+
+    // The PDS gives up if events keep arriving, without charging anything,
+    // so only the first report was paid for out of the capacity of 1.
+    pds.event_storage.n_concurrent_writes.set(100);
+    assert!(matches!(
+        pds.compute_report(&request),
+        Err(PdsError::ConcurrentModification(_))
+    ));
+    let querier_uri = ReportRequestUris::mock().querier_uris[0].clone();
+    assert_eq!(
+        pds.core
+            .filter_storage
+            .remaining_budget(&PerQuerier(1, querier_uri))?,
+        0.5
+    );
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_idempotent_retries() -> Result<(), anyhow::Error> {
+    use crate::{
+        events::ppa_event::PpaEvent,
+        pds::aliases::{PpaEventStorage, PpaFilterStorage, PpaPds},
+        queries::{
+            builder::PpaHistogramRequestBuilder,
+            ppa_histogram::FilterDataPredicate,
+        },
+    };
+
+    let filters = PpaFilterStorage::new(StaticCapacities::mock())?;
+    let clock = MockClock::new(0);
+    let mut pds = PpaPds::<_>::new(filters, PpaEventStorage::new())
+        .with_clock(clock.clone())
+        .with_idempotency_ttl(60);
+    pds.register_event(PpaEvent {
+        id: 1,
+        timestamp: 0,
+        epoch_number: 1,
+        histogram_index: 3,
+        uris: EventUris::mock(),
+        filter_data: 1,
+        priority: 0,
+    })?;
+
+    let request = |key: &str, value: f64| {
+        PpaHistogramRequestBuilder::new()
+            .epoch_range(1, 1)?
+            .value(value, 1.0, 0.5)?
+            .uris(ReportRequestUris::mock())?
+            .selector(FilterDataPredicate::Any, 8, vec![3].into())
+            .map(|builder| builder.idempotency_key(key).build())
+    };
+    let per_querier =
+        PerQuerier(1, ReportRequestUris::mock().querier_uris[0].clone());
+
+    let report = pds.compute_report(&request("retry", 1.0)?)?;
+    assert_eq!(report.filtered_report.bin_values.get(&3), Some(&1.0));
+    let remaining = pds.core.filter_storage.remaining_budget(&per_querier)?;
+
+    // Retries get the same report without consuming budget.
+    let retry = pds.compute_report(&request("retry", 1.0)?)?;
+    assert_eq!(
+        retry.filtered_report.bin_values,
+        report.filtered_report.bin_values
+    );
+    assert_eq!(
+        pds.core.filter_storage.remaining_budget(&per_querier)?,
+        remaining
+    );
+
+    // The key can't be reused for a different request.
+    let result = pds.compute_report(&request("retry", 0.5)?);
+    assert!(matches!(result, Err(PdsError::InvalidRequest(_))));
+
+    // Once the cached report expired, the request is charged again.
+    clock.advance(60);
+    pds.compute_report(&request("retry", 1.0)?)?;
+    assert!(
+        pds.core.filter_storage.remaining_budget(&per_querier)? < remaining
+    );
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_budget_grants() -> Result<(), anyhow::Error> {
+    use crate::pds::{consent::ConsentVerifier, quotas::FilterClass};
+
+    /// Accepts tokens of the form "<amount>:<nonce>".
+    struct AmountInToken;
+
+    impl ConsentVerifier<u64, String> for AmountInToken {
+        fn verify(
+            &self,
+            _filter_id: &FilterId,
+            amount: PureDPBudget,
+            consent_token: &str,
+        ) -> Result<bool, PdsError> {
+            Ok(consent_token.starts_with(&format!("{amount}:")))
+        }
+    }
+
+    let filters = SimpleFilterStorage::new(StaticCapacities::mock())?;
+    let clock = MockClock::new(42);
+    let mut pds = SimplePds::new(filters, SimpleEventStorage::new())
+        .with_clock(clock.clone());
+    let querier_uri = ReportRequestUris::mock().querier_uris[0].clone();
+    let querier = FilterClass::PerQuerier(querier_uri.clone());
+
+    // Grants are rejected without a verifier.
+    let result = pds.grant_budget(1, querier.clone(), 0.5, "0.5:a");
+    assert!(matches!(result, Err(PdsError::ConsentRejected(_))));
+
+    let mut pds = pds.with_consent_verifier(AmountInToken);
+    pds.grant_budget(1, querier.clone(), 0.5, "0.5:a")?;
+    let per_querier = PerQuerier(1, querier_uri);
+    assert_eq!(pds.core.filter_storage.remaining_budget(&per_querier)?, 1.5);
+
+    // Tokens must match the grant and can't be replayed.
+    let result = pds.grant_budget(1, querier.clone(), 2.0, "0.5:b");
+    assert!(matches!(result, Err(PdsError::ConsentRejected(_))));
+    let result = pds.grant_budget(2, querier.clone(), 0.5, "0.5:a");
+    assert!(matches!(result, Err(PdsError::ConsentRejected(_))));
+    assert!(pds.grant_budget(1, querier, -1.0, "-1:c").is_err());
+
+    assert_eq!(pds.budget_grants().len(), 1);
+    assert_eq!(pds.budget_grants()[0].filter_id, per_querier);
+    assert_eq!(pds.budget_grants()[0].granted_at, 42);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_trigger_dedup() -> Result<(), anyhow::Error> {
+    use crate::{
+        events::ppa_event::PpaEvent,
+        pds::{
+            aliases::{PpaEventStorage, PpaFilterStorage, PpaPds},
+            dedup::DedupStorage,
+        },
+        queries::{
+            builder::PpaHistogramRequestBuilder,
+            ppa_histogram::FilterDataPredicate,
+        },
+    };
+
+    let clock = MockClock::new(0);
+    let new_pds = |dedup_storage| -> Result<_, anyhow::Error> {
+        let filters = PpaFilterStorage::new(StaticCapacities::mock())?;
+        let mut pds = PpaPds::<_>::new(filters, PpaEventStorage::new())
+            .with_clock(clock.clone())
+            .with_dedup_storage(dedup_storage);
+        pds.register_event(PpaEvent {
+            id: 1,
+            timestamp: 0,
+            epoch_number: 1,
+            histogram_index: 3,
+            uris: EventUris::mock(),
+            filter_data: 1,
+            priority: 0,
+        })?;
+        Ok(pds)
+    };
+    let request = || {
+        PpaHistogramRequestBuilder::new()
+            .epoch_range(1, 1)?
+            .value(1.0, 1.0, 0.5)?
+            .uris(ReportRequestUris::mock())?
+            .selector(FilterDataPredicate::Any, 8, vec![3].into())
+            .map(|builder| builder.trigger_dedup("order-1", 60).build())
+    };
+    let per_querier =
+        PerQuerier(1, ReportRequestUris::mock().querier_uris[0].clone());
+
+    let mut pds = new_pds(DedupStorage::new())?;
+    let report = pds.compute_report(&request()?)?;
+    assert_eq!(report.filtered_report.bin_values.get(&3), Some(&1.0));
+    let remaining = pds.core.filter_storage.remaining_budget(&per_querier)?;
+
+    // Page reloads get the original report for free.
+    clock.advance(30);
+    let duplicate = pds.compute_report(&request()?)?;
+    assert_eq!(
+        duplicate.filtered_report.bin_values,
+        report.filtered_report.bin_values
+    );
+    assert_eq!(
+        pds.core.filter_storage.remaining_budget(&per_querier)?,
+        remaining
+    );
+
+    // After a restart, keys are restored but reports are not, so duplicates
+    // get a null report.
+    let json = serde_json::to_string(&pds.dedup_storage)?;
+    let mut pds = new_pds(serde_json::from_str(&json)?)?;
+    let duplicate = pds.compute_report(&request()?)?;
+    assert!(duplicate.filtered_report.bin_values.is_empty());
+    assert_eq!(pds.core.filter_storage.remaining_budget(&per_querier)?, 1.0);
+
+    // Requests after the window are not duplicates.
+    clock.advance(30);
+    let report = pds.compute_report(&request()?)?;
+    assert_eq!(report.filtered_report.bin_values.get(&3), Some(&1.0));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_borrowing_policy() -> Result<(), anyhow::Error> {
+    use crate::pds::quotas::BorrowingPolicy;
+
+    let capacities = StaticCapacities::new(1.0, 20.0, 10.0, 4.0);
+    let filters = SimpleFilterStorage::new(capacities)?;
+    let mut pds = SimplePds::new(filters, SimpleEventStorage::new())
+        .with_borrowing_policy(BorrowingPolicy::new(0.5));
+    let querier_uri = ReportRequestUris::mock().querier_uris[0].clone();
+    for epoch_number in [1, 2] {
+        pds.register_event(SimpleEvent {
+            id: epoch_number,
+            epoch_number,
+            event_key: epoch_number,
+            uris: EventUris::mock(),
+        })?;
+    }
+
+    // Each request costs 0.5 to the per-querier filter.
+    let request = |epoch_id| SimpleLastTouchHistogramRequest {
+        epoch_start: epoch_id,
+        epoch_end: epoch_id,
+        report_global_sensitivity: 0.5,
+        query_global_sensitivity: 1.0,
+        requested_epsilon: 1.0,
+        is_relevant_event: SimpleRelevantEventSelector { lambda: |_| true },
+        report_uris: ReportRequestUris::mock(),
+    };
+    for _ in 0..2 {
+        let report = pds.compute_report(&request(1))?;
+        assert_eq!(report.filtered_report.bin_value, Some((1, 0.5)));
+    }
+
+    // The third request borrows from epoch 2, but not the fourth one.
+    let report = pds.compute_report(&request(1))?;
+    assert_eq!(report.filtered_report.bin_value, Some((1, 0.5)));
+    let report = pds.compute_report(&request(1))?;
+    assert_eq!(report.filtered_report.bin_value, None);
+    let lender = PerQuerier(2, querier_uri.clone());
+    assert_eq!(pds.core.filter_storage.get_repayment(&lender)?, Some(0.5));
+
+    // The repayment is persisted with the filters, so it survives a restart.
+    let mut pds = SimplePds::new(pds.core.filter_storage, pds.event_storage)
+        .with_borrowing_policy(BorrowingPolicy::new(0.5));
+
+    // Epoch 2 repays when it is initialized.
+    let report = pds.compute_report(&request(2))?;
+    assert_eq!(report.filtered_report.bin_value, Some((2, 0.5)));
+    assert_eq!(pds.core.filter_storage.get_repayment(&lender)?, None);
+    assert_remaining_budgets(
+        &mut pds.core.filter_storage,
+        &[(PerQuerier(1, querier_uri.clone()), 0.0), (lender, 0.0)],
+    )?;
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_carryover_policy() -> Result<(), anyhow::Error> {
+    use crate::pds::quotas::CarryoverPolicy;
+
+    let filters = SimpleFilterStorage::new(StaticCapacities::mock())?;
+    let mut pds = SimplePds::new(filters, SimpleEventStorage::new())
+        .with_carryover_policy(CarryoverPolicy::new(0.5, 0.4)?);
+    let querier_uri = ReportRequestUris::mock().querier_uris[0].clone();
+    for epoch_number in [1, 3] {
+        pds.register_event(SimpleEvent {
+            id: epoch_number,
+            epoch_number,
+            event_key: epoch_number,
+            uris: EventUris::mock(),
+        })?;
+    }
+
+    // Each request costs 0.5 to the filters of its epoch.
+    let request = |epoch_id| SimpleLastTouchHistogramRequest {
+        epoch_start: epoch_id,
+        epoch_end: epoch_id,
+        report_global_sensitivity: 0.5,
+        query_global_sensitivity: 1.0,
+        requested_epsilon: 1.0,
+        is_relevant_event: SimpleRelevantEventSelector { lambda: |_| true },
+        report_uris: ReportRequestUris::mock(),
+    };
+    pds.compute_report(&request(1))?;
+
+    // Epoch 1 carries half of its unused budget into epoch 2, which is
+    // pruned too and carries its budget further. Epoch 2 could carry 0.625
+    // on the PerQuerier filter, but filters receive at most 0.4 in total.
+    pds.prune_epochs(3)?;
+    let filters = &mut pds.core.filter_storage;
+    assert_eq!(
+        filters.get_carryover(&PerQuerier(2, querier_uri.clone()))?,
+        None
+    );
+    assert_eq!(
+        filters.get_carryover(&PerQuerier(3, querier_uri.clone()))?,
+        Some(0.4)
+    );
+    assert_remaining_budgets(
+        &mut pds.core.filter_storage,
+        &[(PerQuerier(3, querier_uri.clone()), 1.4), (Global(3), 20.4)],
+    )?;
+
+    // Epoch 3 can spend its capacity plus the carried budget, but no more.
+    for _ in 0..2 {
+        let report = pds.compute_report(&request(3))?;
+        assert_eq!(report.filtered_report.bin_value, Some((3, 0.5)));
+    }
+    let report = pds.compute_report(&request(3))?;
+    assert_eq!(report.filtered_report.bin_value, None);
+
+    // Carryover can't exceed the unused budget.
+    assert!(CarryoverPolicy::new(1.5, 0.4).is_err());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_attribution_trace() -> Result<(), anyhow::Error> {
+    use crate::pds::attribution_trace::RejectionReason;
+
+    let capacities = StaticCapacities::mock();
+    let filters = SimpleFilterStorage::new(capacities)?;
+    let mut pds = SimplePds::new(filters, SimpleEventStorage::new())
+        .with_attribution_trace();
+
+    // Event 2 is not relevant, because of its key.
+    for (id, epoch_number, event_key) in
+        [(1, 1, 1), (2, 1, 99), (3, 2, 3), (4, 3, 4)]
+    {
+        pds.register_event(SimpleEvent {
+            id,
+            epoch_number,
+            event_key,
+            uris: EventUris::mock(),
+        })?;
+    }
+
+    // Each report costs 0.6 per epoch, out of 1.0 for the per-querier
+    // filter.
+    let request = |epoch_start, epoch_end| SimpleLastTouchHistogramRequest {
+        epoch_start,
+        epoch_end,
+        report_global_sensitivity: 0.6,
+        query_global_sensitivity: 1.0,
+        requested_epsilon: 1.0,
+        is_relevant_event: SimpleRelevantEventSelector {
+            lambda: |event| event.event_key < 10,
+        },
+        report_uris: ReportRequestUris::mock(),
+    };
+    pds.compute_report(&request(2, 2))?;
+    pds.prune_epochs(2)?;
+
+    // Epoch 1 is pruned and epoch 2 is out of budget, so only event 4 is
+    // attributed.
+    let report = pds.compute_report(&request(1, 3))?;
+    assert_eq!(report.filtered_report.bin_value, Some((4, 0.6)));
+    let trace = report.attribution_trace.expect("trace is enabled");
+    let ids = |events: Vec<&SimpleEvent>| {
+        events.iter().map(|event| event.id).collect::<Vec<_>>()
+    };
+    assert_eq!(ids(trace.considered().collect()), vec![4]);
+    let rejected = |reason| ids(trace.rejected(reason).collect());
+    assert_eq!(rejected(RejectionReason::EpochPruned), vec![1]);
+    assert_eq!(rejected(RejectionReason::EpochOutOfBudget), vec![3]);
+    assert_eq!(rejected(RejectionReason::SelectorMismatch), vec![2]);
+    assert!(rejected(RejectionReason::BucketOutOfRange).is_empty());
+
+    // Traces are opt-in.
+    pds.core.set_attribution_trace(false);
+    let report = pds.compute_report(&request(3, 3))?;
+    assert!(report.attribution_trace.is_none());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "experimental")]
+fn test_event_sampling() -> Result<(), anyhow::Error> {
+    use crate::{
+        events::{ppa_event::PpaEvent, relevant_events::RelevantEvents},
+        pds::{
+            accounting::EventSampling,
+            aliases::{PpaEventStorage, PpaFilterStorage, PpaPds},
+        },
+        queries::{
+            builder::PpaHistogramRequestBuilder,
+            ppa_histogram::FilterDataPredicate,
+        },
+        util::rng::new_rng,
+    };
+
+    let new_pds = |num_events: u64| -> Result<_, anyhow::Error> {
+        let filters = PpaFilterStorage::new(StaticCapacities::mock())?;
+        let mut pds = PpaPds::<_>::new(filters, PpaEventStorage::new());
+        for id in 1..=num_events {
+            pds.register_event(PpaEvent {
+                id,
+                timestamp: id,
+                epoch_number: 1,
+                histogram_index: id,
+                uris: EventUris::mock(),
+                filter_data: 1,
+                priority: 0,
+            })?;
+        }
+        Ok(pds)
+    };
+    let request = |sampling: EventSampling| {
+        PpaHistogramRequestBuilder::new()
+            .epoch_range(1, 1)?
+            .value(1.0, 1.0, 0.5)?
+            .uris(ReportRequestUris::mock())?
+            .selector(
+                FilterDataPredicate::Any,
+                16,
+                (1..=8).collect::<Vec<_>>().into(),
+            )
+            .map(|builder| builder.event_sampling(sampling).build())
+    };
+    let per_querier =
+        PerQuerier(1, ReportRequestUris::mock().querier_uris[0].clone());
+
+    // Sampling everything gives the same report and loss as no sampling.
+    let mut pds = new_pds(8)?;
+    let report = pds.compute_report(&request(EventSampling::new(1.0)?)?)?;
+    assert_eq!(report.filtered_report.bin_values.get(&8), Some(&1.0));
+    assert_eq!(pds.core.filter_storage.remaining_budget(&per_querier)?, 0.5);
+
+    // The loss is discounted to ln(1 + q(e^loss - 1)).
+    let sampling = EventSampling::new(0.5)?;
+    assert!(
+        (sampling.amplify(0.5) - (1.0 + 0.5 * 0.5f64.exp_m1()).ln()).abs()
+            < 1e-12
+    );
+    assert_eq!(sampling.amplify(f64::INFINITY), f64::INFINITY);
+    assert!(EventSampling::new(0.0).is_err());
+    assert!(EventSampling::new(1.5).is_err());
+
+    // The discount only depends on the sampling rate, not on how many events
+    // the device has in the epoch.
+    let sampling = EventSampling::new(0.1)?;
+    let loss = sampling.amplify(0.5);
+    assert!(loss < 0.5);
+    for num_events in [1, 8] {
+        let mut pds = new_pds(num_events)?;
+        pds.compute_report(&request(sampling.clone())?)?;
+        let remaining =
+            pds.core.filter_storage.remaining_budget(&per_querier)?;
+        assert!((remaining - (1.0 - loss)).abs() < 1e-12);
+    }
+
+    // Epochs are sampled as a whole.
+    let events = (1..=64)
+        .flat_map(|epoch_number| {
+            (1..=8).map(move |id| SimpleEvent {
+                id,
+                epoch_number,
+                event_key: id,
+                uris: EventUris::mock(),
+            })
+        })
+        .collect();
+    let mut relevant_events = RelevantEvents::from_vec(events);
+    EventSampling::new(0.5)?
+        .sample(&mut relevant_events, &mut new_rng(Some(7)));
+    let sizes = (1..=64)
+        .map(|epoch_number| relevant_events.for_epoch(&epoch_number).len())
+        .collect::<Vec<_>>();
+    assert!(sizes.contains(&0) && sizes.contains(&8));
+    assert!(sizes.iter().all(|size| *size == 0 || *size == 8));
+
+    // Samplings seeded by the device are reproducible.
+    let mut pds = new_pds(8)?.with_sampling_seed(7);
+    let report = pds.compute_report(&request(sampling.clone())?)?;
+    let mut pds = new_pds(8)?.with_sampling_seed(7);
+    let replay = pds.compute_report(&request(sampling)?)?;
+    assert_eq!(
+        replay.filtered_report.bin_values,
+        report.filtered_report.bin_values
+    );
+    Ok(())
+}
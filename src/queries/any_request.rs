@@ -0,0 +1,384 @@
+use serde::Serialize;
+
+use crate::{
+    budget::pure_dp_filter::PureDPBudget,
+    events::{
+        any_event::AnyEvent,
+        relevant_events::RelevantEvents,
+        traits::{Event, RelevantEventSelector},
+    },
+    mechanisms::{NoiseScale, NormType},
+    pds::{accounting::EventSampling, dedup::TriggerDedup},
+    queries::{
+        histogram::HistogramReport,
+        ppa_histogram::{Lookback, PpaBucketKey, PpaHistogramRequest},
+        simple_last_touch_histogram::{
+            SimpleLastTouchHistogramReport, SimpleLastTouchHistogramRequest,
+        },
+        traits::{EpochReportRequest, Report, ReportRequestUris},
+    },
+};
+
+/// Request of any of the supported types. A single `PrivateDataService` over
+/// `AnyEpochReportRequest` answers heterogeneous requests against the same
+/// filters, using an event storage of `AnyEvent`s. Each request only sees
+/// the events of its own type.
+#[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
+pub enum AnyEpochReportRequest {
+    SimpleLastTouch(SimpleLastTouchHistogramRequest),
+    Ppa(PpaHistogramRequest),
+}
+
+/// Report for an `AnyEpochReportRequest`, of the same type as the request.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub enum AnyReport {
+    SimpleLastTouch(SimpleLastTouchHistogramReport),
+    Ppa(HistogramReport<PpaBucketKey>),
+
+    /// Null report, e.g. when the PDS fails before knowing the request type.
+    #[default]
+    Null,
+}
+
+impl Report for AnyReport {}
+
+impl From<SimpleLastTouchHistogramRequest> for AnyEpochReportRequest {
+    fn from(request: SimpleLastTouchHistogramRequest) -> Self {
+        AnyEpochReportRequest::SimpleLastTouch(request)
+    }
+}
+
+impl From<PpaHistogramRequest> for AnyEpochReportRequest {
+    fn from(request: PpaHistogramRequest) -> Self {
+        AnyEpochReportRequest::Ppa(request)
+    }
+}
+
+/// The request is its own selector: events of another type are never
+/// relevant.
+impl RelevantEventSelector for AnyEpochReportRequest {
+    type Event = AnyEvent;
+
+    fn is_relevant_event(&self, event: &Self::Event) -> bool {
+        match (self, event) {
+            (
+                AnyEpochReportRequest::SimpleLastTouch(request),
+                AnyEvent::Simple(event),
+            ) => request.relevant_event_selector().is_relevant_event(event),
+            (AnyEpochReportRequest::Ppa(request), AnyEvent::Ppa(event)) => {
+                request.relevant_event_selector().is_relevant_event(event)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Keeps the events of a single type, to compute the report of the inner
+/// request.
+fn filter_events<E: Event<EpochId = u64>>(
+    relevant_events: &RelevantEvents<AnyEvent>,
+    f: impl Fn(&AnyEvent) -> Option<E>,
+) -> RelevantEvents<E> {
+    let events_per_epoch = relevant_events
+        .events_per_epoch
+        .iter()
+        .map(|(epoch_id, events)| {
+            (*epoch_id, events.iter().filter_map(&f).collect())
+        })
+        .collect();
+    RelevantEvents::from_mapping(events_per_epoch)
+}
+
+impl EpochReportRequest for AnyEpochReportRequest {
+    type EpochId = u64;
+    type Event = AnyEvent;
+    type PrivacyBudget = PureDPBudget;
+    type RelevantEventSelector = Self;
+    type Report = AnyReport;
+    type Uri = String;
+
+    fn report_uris(&self) -> &ReportRequestUris<String> {
+        match self {
+            AnyEpochReportRequest::SimpleLastTouch(request) => {
+                request.report_uris()
+            }
+            AnyEpochReportRequest::Ppa(request) => request.report_uris(),
+        }
+    }
+
+    fn idempotency_key(&self) -> Option<&str> {
+        match self {
+            AnyEpochReportRequest::SimpleLastTouch(request) => {
+                request.idempotency_key()
+            }
+            AnyEpochReportRequest::Ppa(request) => request.idempotency_key(),
+        }
+    }
+
+    fn trigger_dedup(&self) -> Option<&TriggerDedup> {
+        match self {
+            AnyEpochReportRequest::SimpleLastTouch(request) => {
+                request.trigger_dedup()
+            }
+            AnyEpochReportRequest::Ppa(request) => request.trigger_dedup(),
+        }
+    }
+
+    fn event_sampling(&self) -> Option<&EventSampling> {
+        match self {
+            AnyEpochReportRequest::SimpleLastTouch(request) => {
+                request.event_sampling()
+            }
+            AnyEpochReportRequest::Ppa(request) => request.event_sampling(),
+        }
+    }
+
+    fn epoch_ids(&self) -> Vec<Self::EpochId> {
+        match self {
+            AnyEpochReportRequest::SimpleLastTouch(request) => {
+                request.epoch_ids()
+            }
+            AnyEpochReportRequest::Ppa(request) => request.epoch_ids(),
+        }
+    }
+
+    fn epoch_range(&self) -> (Self::EpochId, Self::EpochId) {
+        match self {
+            AnyEpochReportRequest::SimpleLastTouch(request) => {
+                request.epoch_range()
+            }
+            AnyEpochReportRequest::Ppa(request) => request.epoch_range(),
+        }
+    }
+
+    fn duration_window(&self) -> Option<Lookback> {
+        match self {
+            AnyEpochReportRequest::SimpleLastTouch(request) => {
+                request.duration_window()
+            }
+            AnyEpochReportRequest::Ppa(request) => request.duration_window(),
+        }
+    }
+
+    fn with_epoch_ids(&self, epoch_ids: Vec<Self::EpochId>) -> Self {
+        match self {
+            AnyEpochReportRequest::SimpleLastTouch(request) => {
+                request.with_epoch_ids(epoch_ids).into()
+            }
+            AnyEpochReportRequest::Ppa(request) => {
+                request.with_epoch_ids(epoch_ids).into()
+            }
+        }
+    }
+
+    fn relevant_event_selector(&self) -> &Self::RelevantEventSelector {
+        self
+    }
+
+    fn compute_report(
+        &self,
+        relevant_events: &RelevantEvents<Self::Event>,
+    ) -> Self::Report {
+        match self {
+            AnyEpochReportRequest::SimpleLastTouch(request) => {
+                let events =
+                    filter_events(relevant_events, |event| match event {
+                        AnyEvent::Simple(event) => Some(event.clone()),
+                        _ => None,
+                    });
+                AnyReport::SimpleLastTouch(request.compute_report(&events))
+            }
+            AnyEpochReportRequest::Ppa(request) => {
+                let events =
+                    filter_events(relevant_events, |event| match event {
+                        AnyEvent::Ppa(event) => Some(event.clone()),
+                        _ => None,
+                    });
+                AnyReport::Ppa(request.compute_report(&events))
+            }
+        }
+    }
+
+    fn is_event_in_range(&self, event: &Self::Event) -> bool {
+        match (self, event) {
+            (
+                AnyEpochReportRequest::SimpleLastTouch(request),
+                AnyEvent::Simple(event),
+            ) => request.is_event_in_range(event),
+            (AnyEpochReportRequest::Ppa(request), AnyEvent::Ppa(event)) => {
+                request.is_event_in_range(event)
+            }
+            // Mismatched events are rejected by the selector.
+            _ => true,
+        }
+    }
+
+    fn single_epoch_individual_sensitivity(
+        &self,
+        report: &Self::Report,
+        norm_type: NormType,
+    ) -> f64 {
+        match (self, report) {
+            (
+                AnyEpochReportRequest::SimpleLastTouch(request),
+                AnyReport::SimpleLastTouch(report),
+            ) => request.single_epoch_individual_sensitivity(report, norm_type),
+            (AnyEpochReportRequest::Ppa(request), AnyReport::Ppa(report)) => {
+                request.single_epoch_individual_sensitivity(report, norm_type)
+            }
+            // Null or mismatched reports have no attribution.
+            _ => 0.0,
+        }
+    }
+
+    fn single_epoch_source_individual_sensitivity(
+        &self,
+        report: &Self::Report,
+        norm_type: NormType,
+    ) -> f64 {
+        match (self, report) {
+            (
+                AnyEpochReportRequest::SimpleLastTouch(request),
+                AnyReport::SimpleLastTouch(report),
+            ) => request
+                .single_epoch_source_individual_sensitivity(report, norm_type),
+            (AnyEpochReportRequest::Ppa(request), AnyReport::Ppa(report)) => {
+                request.single_epoch_source_individual_sensitivity(
+                    report, norm_type,
+                )
+            }
+            _ => 0.0,
+        }
+    }
+
+    fn multi_epoch_individual_sensitivity(
+        &self,
+        epoch_relevant_events: &[Self::Event],
+        norm_type: NormType,
+    ) -> Option<f64> {
+        match self {
+            AnyEpochReportRequest::SimpleLastTouch(request) => {
+                let events = epoch_relevant_events
+                    .iter()
+                    .filter_map(|event| match event {
+                        AnyEvent::Simple(event) => Some(event.clone()),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                request.multi_epoch_individual_sensitivity(&events, norm_type)
+            }
+            AnyEpochReportRequest::Ppa(request) => {
+                let events = epoch_relevant_events
+                    .iter()
+                    .filter_map(|event| match event {
+                        AnyEvent::Ppa(event) => Some(event.clone()),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                request.multi_epoch_individual_sensitivity(&events, norm_type)
+            }
+        }
+    }
+
+    fn report_global_sensitivity(&self) -> f64 {
+        match self {
+            AnyEpochReportRequest::SimpleLastTouch(request) => {
+                request.report_global_sensitivity()
+            }
+            AnyEpochReportRequest::Ppa(request) => {
+                request.report_global_sensitivity()
+            }
+        }
+    }
+
+    fn noise_scale(&self) -> NoiseScale {
+        match self {
+            AnyEpochReportRequest::SimpleLastTouch(request) => {
+                request.noise_scale()
+            }
+            AnyEpochReportRequest::Ppa(request) => request.noise_scale(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        budget::traits::FilterStorage,
+        events::{
+            ppa_event::PpaEvent, simple_event::SimpleEvent, traits::EventUris,
+        },
+        pds::{
+            aliases::{AnyEventStorage, AnyPds, SimpleFilterStorage},
+            quotas::StaticCapacities,
+        },
+        queries::{
+            builder::PpaHistogramRequestBuilder,
+            ppa_histogram::{FilterDataPredicate, RequestedBuckets},
+            simple_last_touch_histogram::SimpleRelevantEventSelector,
+        },
+    };
+
+    #[test]
+    fn test_heterogeneous_requests_share_filters() -> Result<(), anyhow::Error>
+    {
+        let filters = SimpleFilterStorage::new(StaticCapacities::mock())?;
+        let mut pds = AnyPds::<_>::new(filters, AnyEventStorage::new());
+
+        pds.register_event(AnyEvent::Simple(SimpleEvent {
+            id: 1,
+            epoch_number: 1,
+            event_key: 3,
+            uris: EventUris::mock(),
+        }))?;
+        pds.register_event(AnyEvent::Ppa(PpaEvent {
+            id: 2,
+            timestamp: 1,
+            epoch_number: 1,
+            histogram_index: 5,
+            uris: EventUris::mock(),
+            filter_data: 0,
+            priority: 0,
+        }))?;
+
+        // The PPA request only sees the PPA event, and consumes half of the
+        // per-querier budget.
+        let ppa_request = PpaHistogramRequestBuilder::new()
+            .epoch_range(1, 1)?
+            .value(1.0, 1.0, 0.5)?
+            .uris(ReportRequestUris::mock())?
+            .selector(
+                FilterDataPredicate::Any,
+                8,
+                RequestedBuckets::AllBuckets,
+            )?
+            .build();
+        let report = pds.compute_report(&ppa_request.into())?;
+        let AnyReport::Ppa(report) = report.filtered_report else {
+            panic!("Expected a PPA report");
+        };
+        assert_eq!(report.bin_values.len(), 1);
+        assert_eq!(report.bin_values.get(&5), Some(&1.0));
+
+        // The simple request needs the full per-querier budget, which is now
+        // shared with the PPA request.
+        let simple_request = SimpleLastTouchHistogramRequest {
+            epoch_start: 1,
+            epoch_end: 1,
+            report_global_sensitivity: 1.0,
+            query_global_sensitivity: 1.0,
+            requested_epsilon: 1.0,
+            is_relevant_event: SimpleRelevantEventSelector { lambda: |_| true },
+            report_uris: ReportRequestUris::mock(),
+        };
+        let report = pds.compute_report(&simple_request.into())?;
+        let AnyReport::SimpleLastTouch(report) = report.filtered_report else {
+            panic!("Expected a simple last-touch report");
+        };
+        assert_eq!(report.bin_value, None);
+
+        Ok(())
+    }
+}
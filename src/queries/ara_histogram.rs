@@ -0,0 +1,324 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    budget::pure_dp_filter::PureDPBudget,
+    error::PdsError,
+    events::{
+        ara_event::{AraEvent, AraFilterData},
+        relevant_events::RelevantEvents,
+        traits::{RelevantEventSelector, Uri},
+    },
+    mechanisms::{NoiseScale, NormType},
+    queries::{
+        epoch_selection::{unique_epochs, EpochSelection},
+        histogram::HistogramReport,
+        traits::{EpochReportRequest, ReportRequestUris},
+    },
+    util::hashmap::HashMap,
+};
+
+/// ARA aggregation keys are 128 bits.
+pub type AraBucketKey = u128;
+pub type AraEpochId = u64;
+
+/// Trigger-side key piece, ORed into the keys of the listed source keys.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AraTriggerData {
+    pub key_piece: AraBucketKey,
+    pub source_keys: Vec<String>,
+}
+
+/// Matches a source filter data against trigger filters, as in ARA: for each
+/// filter that the source also has, the source must have one of the values
+/// of the trigger, or no values at all if the trigger lists none. Filters
+/// that only one side has are ignored.
+pub fn filters_match(
+    filter_data: &AraFilterData,
+    filters: &AraFilterData,
+) -> bool {
+    filters
+        .iter()
+        .all(|(name, trigger_values)| match filter_data.get(name) {
+            None => true,
+            Some(source_values) if trigger_values.is_empty() => {
+                source_values.is_empty()
+            }
+            Some(source_values) => trigger_values
+                .iter()
+                .any(|value| source_values.contains(value)),
+        })
+}
+
+#[derive(Clone)]
+pub struct AraRelevantEventSelector<U: Uri = String> {
+    /// source/trigger/querier URIs for this request
+    pub report_request_uris: ReportRequestUris<U>,
+
+    /// Filters of the trigger, matched against the filter data of the
+    /// sources.
+    pub filters: AraFilterData,
+
+    /// Time of the trigger, in seconds. Sources that expired before it are
+    /// not relevant.
+    pub trigger_time: u64,
+}
+
+impl<U: Uri> std::fmt::Debug for AraRelevantEventSelector<U> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AraRelevantEventSelector")
+            .field("report_request_uris", &self.report_request_uris)
+            .field("filters", &self.filters)
+            .field("trigger_time", &self.trigger_time)
+            .finish()
+    }
+}
+
+impl<U: Uri> RelevantEventSelector for AraRelevantEventSelector<U> {
+    type Event = AraEvent<U>;
+
+    fn is_relevant_event(&self, event: &Self::Event) -> bool {
+        let uris = &self.report_request_uris;
+        let source_match = uris.source_uris.contains(&event.uris.source_uri);
+        let querier_match = uris
+            .querier_uris
+            .iter()
+            .any(|uri| event.uris.querier_uris.contains(uri));
+        let trigger_match = event.uris.trigger_uris.contains(&uris.trigger_uri);
+
+        source_match
+            && querier_match
+            && trigger_match
+            && !event.is_expired_at(self.trigger_time)
+            && filters_match(&event.filter_data, &self.filters)
+    }
+}
+
+/// Parameters of an ARA trigger registration, with the budget of the
+/// aggregatable report.
+#[derive(Debug, Clone)]
+pub struct AraHistogramConfig {
+    pub epochs: EpochSelection,
+
+    /// Trigger-side key pieces.
+    pub aggregatable_trigger_data: Vec<AraTriggerData>,
+
+    /// Value contributed to the bucket of each source key. The sum of the
+    /// values is the attributable value of the report.
+    pub aggregatable_values: BTreeMap<String, f64>,
+
+    /// Maximum attributable value across the reports of the batch, a.k.a.
+    /// the L1 contribution budget in ARA.
+    pub max_attributable_value: f64,
+
+    pub requested_epsilon: f64,
+}
+
+/// Aggregatable report request for an ARA trigger. The source with the
+/// highest priority, and then the most recent one, gets the attribution.
+/// Each aggregation key of that source with an aggregatable value contributes
+/// that value to the bucket made of the source key piece ORed with the
+/// matching trigger key pieces.
+#[derive(Debug, Clone)]
+pub struct AraHistogramRequest<U: Uri = String> {
+    epochs: EpochSelection,
+    aggregatable_trigger_data: Vec<AraTriggerData>,
+    aggregatable_values: BTreeMap<String, f64>,
+    laplace_noise_scale: f64,
+    relevant_event_selector: AraRelevantEventSelector<U>,
+}
+
+impl<U: Uri> AraHistogramRequest<U> {
+    pub fn new(
+        config: AraHistogramConfig,
+        relevant_event_selector: AraRelevantEventSelector<U>,
+    ) -> Result<Self, PdsError> {
+        if !config.requested_epsilon.is_finite()
+            || config.requested_epsilon <= 0.0
+        {
+            return Err(PdsError::InvalidRequest(
+                "epsilon scale must be > 0".into(),
+            ));
+        }
+        if let Some((key, value)) = config
+            .aggregatable_values
+            .iter()
+            .find(|(_, value)| !value.is_finite() || **value < 0.0)
+        {
+            return Err(PdsError::InvalidRequest(format!(
+                "aggregatable value {value} of key {key:?} must be finite and >= 0"
+            )));
+        }
+        let attributable_value: f64 = config.aggregatable_values.values().sum();
+        if !config.max_attributable_value.is_finite()
+            || config.max_attributable_value < attributable_value
+        {
+            return Err(PdsError::InvalidRequest(format!(
+                "max attributable value {} must be finite and >= the sum of the aggregatable values {attributable_value}",
+                config.max_attributable_value
+            )));
+        }
+        config.epochs.validate()?;
+
+        // Same sensitivity as PPA histograms, see `PpaHistogramRequest::from_config`.
+        let query_global_sensitivity = if config.epochs.epoch_ids().len() == 1 {
+            config.max_attributable_value
+        } else {
+            2.0 * config.max_attributable_value
+        };
+
+        Ok(Self {
+            epochs: config.epochs,
+            aggregatable_trigger_data: config.aggregatable_trigger_data,
+            aggregatable_values: config.aggregatable_values,
+            laplace_noise_scale: query_global_sensitivity
+                / config.requested_epsilon,
+            relevant_event_selector,
+        })
+    }
+
+    /// Sum of the aggregatable values, i.e. the value of a report with an
+    /// attributed source that has all the keys.
+    pub fn attributable_value(&self) -> f64 {
+        self.aggregatable_values.values().sum()
+    }
+
+    /// Source that gets the attribution: highest priority, then most recent,
+    /// across all the requested epochs.
+    pub fn attributed_event<'a>(
+        &self,
+        relevant_events: &'a RelevantEvents<AraEvent<U>>,
+    ) -> Option<&'a AraEvent<U>> {
+        unique_epochs(self.epoch_ids())
+            .iter()
+            .flat_map(|epoch_id| relevant_events.for_epoch(epoch_id))
+            .max_by_key(|event| (event.priority, event.timestamp))
+    }
+
+    /// Bucket of the given source key piece, with the trigger key pieces
+    /// that apply to `source_key`.
+    fn bucket_key(
+        &self,
+        source_key: &str,
+        source_key_piece: AraBucketKey,
+    ) -> AraBucketKey {
+        self.aggregatable_trigger_data
+            .iter()
+            .filter(|data| data.source_keys.iter().any(|key| key == source_key))
+            .fold(source_key_piece, |bucket, data| bucket | data.key_piece)
+    }
+}
+
+impl<U: Uri> EpochReportRequest for AraHistogramRequest<U> {
+    type Uri = U;
+    type EpochId = AraEpochId;
+    type Event = AraEvent<U>;
+    type RelevantEventSelector = AraRelevantEventSelector<U>;
+    type PrivacyBudget = PureDPBudget;
+    type Report = HistogramReport<AraBucketKey>;
+
+    fn epoch_ids(&self) -> Vec<Self::EpochId> {
+        self.epochs.epoch_ids()
+    }
+
+    fn epoch_range(&self) -> (Self::EpochId, Self::EpochId) {
+        self.epochs.epoch_range()
+    }
+
+    fn report_global_sensitivity(&self) -> f64 {
+        if self.epochs.epoch_ids().len() == 1 {
+            self.attributable_value()
+        } else {
+            2.0 * self.attributable_value()
+        }
+    }
+
+    fn relevant_event_selector(&self) -> &Self::RelevantEventSelector {
+        &self.relevant_event_selector
+    }
+
+    fn report_uris(&self) -> &ReportRequestUris<Self::Uri> {
+        &self.relevant_event_selector.report_request_uris
+    }
+
+    fn compute_report(
+        &self,
+        relevant_events: &RelevantEvents<Self::Event>,
+    ) -> Self::Report {
+        let Some(event) = self.attributed_event(relevant_events) else {
+            return HistogramReport::default();
+        };
+
+        // Keys without an aggregatable value don't contribute.
+        let mut bin_values = HashMap::new();
+        for (source_key, source_key_piece) in &event.aggregation_keys {
+            if let Some(value) = self.aggregatable_values.get(source_key) {
+                *bin_values
+                    .entry(self.bucket_key(source_key, *source_key_piece))
+                    .or_default() += value;
+            }
+        }
+        HistogramReport { bin_values }
+    }
+
+    fn single_epoch_individual_sensitivity(
+        &self,
+        report: &Self::Report,
+        norm_type: NormType,
+    ) -> f64 {
+        match norm_type {
+            NormType::L1 => report.bin_values.values().sum(),
+            NormType::L2 => {
+                let sum_squares: f64 =
+                    report.bin_values.values().map(|x| x * x).sum();
+                sum_squares.sqrt()
+            }
+        }
+    }
+
+    fn single_epoch_source_individual_sensitivity(
+        &self,
+        report: &Self::Report,
+        norm_type: NormType,
+    ) -> f64 {
+        self.single_epoch_individual_sensitivity(report, norm_type)
+    }
+
+    fn noise_scale(&self) -> NoiseScale {
+        NoiseScale::Laplace(self.laplace_noise_scale)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter_data(entries: &[(&str, &[&str])]) -> AraFilterData {
+        entries
+            .iter()
+            .map(|(name, values)| {
+                (
+                    name.to_string(),
+                    values.iter().map(|value| value.to_string()).collect(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_filters_match() {
+        let source = filter_data(&[("product", &["shoes", "socks"])]);
+        assert!(filters_match(&source, &filter_data(&[])));
+        assert!(filters_match(
+            &source,
+            &filter_data(&[("product", &["socks"])])
+        ));
+        assert!(!filters_match(
+            &source,
+            &filter_data(&[("product", &["hats"])])
+        ));
+        assert!(!filters_match(&source, &filter_data(&[("product", &[])])));
+
+        // Filters that the source doesn't have are ignored.
+        assert!(filters_match(&source, &filter_data(&[("geo", &["us"])])));
+    }
+}
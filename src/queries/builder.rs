@@ -0,0 +1,522 @@
+//! Staged builders for report requests. Parameters are set in a fixed order,
+//! epochs, then value and noise, then URIs, then the event selector, and each
+//! stage is validated as soon as it is set, so misconfigured requests fail
+//! with an error about the faulty stage. The type of each stage only exposes
+//! the next one, so a request can't be built with missing parameters.
+
+use crate::{
+    error::PdsError,
+    events::traits::Uri,
+    pds::accounting::EventSampling,
+    queries::{
+        epoch_selection::EpochSelection,
+        histogram::BucketPolicy,
+        ppa_histogram::{
+            AttributionLogic, EpsilonGrid, FilterDataPredicate, Lookback,
+            PpaBucketKey, PpaEpochId, PpaHistogramConfig, PpaHistogramRequest,
+            PpaRelevantEventSelector, RequestedBuckets, ValuePolicy,
+        },
+        simple_last_touch_histogram::{
+            SimpleLastTouchHistogramRequest, SimpleRelevantEventSelector,
+        },
+        traits::ReportRequestUris,
+    },
+};
+
+/// Builder for `PpaHistogramRequest`, in stage `S`. Start with
+/// `PpaHistogramRequestBuilder::new()`.
+#[derive(Debug)]
+pub struct PpaHistogramRequestBuilder<S = PpaEpochStage> {
+    stage: S,
+}
+
+/// Waiting for the epochs.
+#[derive(Debug)]
+pub struct PpaEpochStage;
+
+/// Waiting for the conversion value and the epsilon.
+#[derive(Debug)]
+pub struct PpaValueStage {
+    epochs: EpochSelection,
+}
+
+/// Waiting for the URIs. The value policy and the epsilon grid can still be
+/// set.
+#[derive(Debug)]
+pub struct PpaUriStage {
+    epochs: EpochSelection,
+    attributable_value: f64,
+    max_attributable_value: f64,
+    requested_epsilon: f64,
+    value_policy: Option<ValuePolicy>,
+    epsilon_grid: Option<EpsilonGrid>,
+    lookback: Option<Lookback>,
+}
+
+/// Waiting for the event selector and the histogram domain.
+#[derive(Debug)]
+pub struct PpaSelectorStage<U: Uri> {
+    values: PpaUriStage,
+    report_request_uris: ReportRequestUris<U>,
+}
+
+/// Fully validated request. Optional settings can still be changed.
+#[derive(Debug)]
+pub struct PpaReadyStage<U: Uri> {
+    request: PpaHistogramRequest<U>,
+}
+
+impl PpaHistogramRequestBuilder {
+    pub fn new() -> Self {
+        Self {
+            stage: PpaEpochStage,
+        }
+    }
+}
+
+impl Default for PpaHistogramRequestBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PpaHistogramRequestBuilder<PpaEpochStage> {
+    /// Attributes to the given epochs.
+    pub fn epochs(
+        self,
+        epochs: EpochSelection,
+    ) -> Result<PpaHistogramRequestBuilder<PpaValueStage>, PdsError> {
+        epochs.validate()?;
+        Ok(PpaHistogramRequestBuilder {
+            stage: PpaValueStage { epochs },
+        })
+    }
+
+    /// Attributes to the epochs from `start` to `end`, inclusive.
+    pub fn epoch_range(
+        self,
+        start: PpaEpochId,
+        end: PpaEpochId,
+    ) -> Result<PpaHistogramRequestBuilder<PpaValueStage>, PdsError> {
+        self.epochs(EpochSelection::Range { start, end })
+    }
+}
+
+impl PpaHistogramRequestBuilder<PpaValueStage> {
+    /// Sets the conversion value, the maximum value across the reports of the
+    /// batch, and the epsilon spent on the batch for that maximum value.
+    pub fn value(
+        self,
+        attributable_value: f64,
+        max_attributable_value: f64,
+        requested_epsilon: f64,
+    ) -> Result<PpaHistogramRequestBuilder<PpaUriStage>, PdsError> {
+        if !attributable_value.is_finite() || attributable_value < 0.0 {
+            return Err(PdsError::InvalidRequest(format!(
+                "attributable value {attributable_value} must be finite and >= 0"
+            )));
+        }
+        if !max_attributable_value.is_finite()
+            || max_attributable_value < attributable_value
+        {
+            return Err(PdsError::InvalidRequest(format!(
+                "max attributable value {max_attributable_value} must be finite and >= the attributable value {attributable_value}"
+            )));
+        }
+        if !requested_epsilon.is_finite() || requested_epsilon <= 0.0 {
+            return Err(PdsError::InvalidRequest(format!(
+                "requested epsilon {requested_epsilon} must be finite and > 0"
+            )));
+        }
+        Ok(PpaHistogramRequestBuilder {
+            stage: PpaUriStage {
+                epochs: self.stage.epochs,
+                attributable_value,
+                max_attributable_value,
+                requested_epsilon,
+                value_policy: None,
+                epsilon_grid: None,
+                lookback: None,
+            },
+        })
+    }
+}
+
+impl PpaHistogramRequestBuilder<PpaUriStage> {
+    /// Clamps and scales the conversion values, see `ValuePolicy`.
+    pub fn value_policy(
+        mut self,
+        value_policy: ValuePolicy,
+    ) -> Result<Self, PdsError> {
+        value_policy.validate()?;
+        self.stage.value_policy = Some(value_policy);
+        Ok(self)
+    }
+
+    /// Rounds the requested epsilon down to the grid, see `EpsilonGrid`.
+    pub fn epsilon_grid(
+        mut self,
+        epsilon_grid: EpsilonGrid,
+    ) -> Result<Self, PdsError> {
+        epsilon_grid.validate()?;
+        epsilon_grid.quantize(self.stage.requested_epsilon)?;
+        self.stage.epsilon_grid = Some(epsilon_grid);
+        Ok(self)
+    }
+
+    /// Only attributes to the events in the window, see `Lookback`.
+    pub fn lookback(mut self, lookback: Lookback) -> Self {
+        self.stage.lookback = Some(lookback);
+        self
+    }
+
+    /// Sets the trigger, source and querier URIs of the request.
+    pub fn uris<U: Uri>(
+        self,
+        report_request_uris: ReportRequestUris<U>,
+    ) -> Result<PpaHistogramRequestBuilder<PpaSelectorStage<U>>, PdsError> {
+        validate_uris(&report_request_uris)?;
+        Ok(PpaHistogramRequestBuilder {
+            stage: PpaSelectorStage {
+                values: self.stage,
+                report_request_uris,
+            },
+        })
+    }
+}
+
+impl<U: Uri> PpaHistogramRequestBuilder<PpaSelectorStage<U>> {
+    /// Sets the events to attribute to, the histogram domain and the buckets
+    /// to report. Requested buckets must be in the domain.
+    pub fn selector(
+        self,
+        is_matching_event: FilterDataPredicate,
+        histogram_size: u64,
+        requested_buckets: RequestedBuckets<PpaBucketKey>,
+    ) -> Result<PpaHistogramRequestBuilder<PpaReadyStage<U>>, PdsError> {
+        if let RequestedBuckets::SpecificBuckets(buckets) = &requested_buckets {
+            if let Some(bucket) =
+                buckets.iter().find(|bucket| **bucket >= histogram_size)
+            {
+                return Err(PdsError::InvalidRequest(format!(
+                    "requested bucket {bucket} is out of the histogram domain of size {histogram_size}"
+                )));
+            }
+        }
+
+        let PpaSelectorStage {
+            values,
+            report_request_uris,
+        } = self.stage;
+        let config = PpaHistogramConfig {
+            start_epoch: 0,
+            end_epoch: 0,
+            epochs: Some(values.epochs),
+            attributable_value: values.attributable_value,
+            max_attributable_value: values.max_attributable_value,
+            requested_epsilon: values.requested_epsilon,
+            histogram_size,
+            value_policy: values.value_policy,
+            lookback: values.lookback,
+            epsilon_grid: values.epsilon_grid,
+        };
+        let request = PpaHistogramRequest::from_config(
+            &config,
+            PpaRelevantEventSelector {
+                report_request_uris,
+                is_matching_event,
+                requested_buckets,
+                lookback: None,
+            },
+        )?;
+        Ok(PpaHistogramRequestBuilder {
+            stage: PpaReadyStage { request },
+        })
+    }
+}
+
+impl<U: Uri> PpaHistogramRequestBuilder<PpaReadyStage<U>> {
+    /// Sets the handling of out-of-range histogram indices, `Reject` by
+    /// default.
+    pub fn bucket_policy(mut self, policy: BucketPolicy) -> Self {
+        self.stage.request = self.stage.request.with_bucket_policy(policy);
+        self
+    }
+
+    /// Sets the attribution logic, `LastTouch` by default.
+    pub fn attribution_logic(mut self, logic: AttributionLogic) -> Self {
+        self.stage.request = self.stage.request.with_attribution_logic(logic);
+        self
+    }
+
+    /// Sets the idempotency key, see `EpochReportRequest::idempotency_key`.
+    pub fn idempotency_key(mut self, key: impl Into<String>) -> Self {
+        self.stage.request = self.stage.request.with_idempotency_key(key);
+        self
+    }
+
+    /// Sets the deduplication key and window of the trigger, see
+    /// `EpochReportRequest::trigger_dedup`.
+    pub fn trigger_dedup(
+        mut self,
+        key: impl Into<String>,
+        window: u64,
+    ) -> Self {
+        self.stage.request = self.stage.request.with_trigger_dedup(key, window);
+        self
+    }
+
+    /// Sets the sampling of the relevant events, see
+    /// `EpochReportRequest::event_sampling`.
+    pub fn event_sampling(mut self, sampling: EventSampling) -> Self {
+        self.stage.request = self.stage.request.with_event_sampling(sampling);
+        self
+    }
+
+    pub fn build(self) -> PpaHistogramRequest<U> {
+        self.stage.request
+    }
+}
+
+/// Builder for `SimpleLastTouchHistogramRequest`, in stage `S`. Start with
+/// `SimpleLastTouchHistogramRequestBuilder::new()`.
+#[derive(Debug)]
+pub struct SimpleLastTouchHistogramRequestBuilder<S = SimpleEpochStage> {
+    stage: S,
+}
+
+/// Waiting for the epochs.
+#[derive(Debug)]
+pub struct SimpleEpochStage;
+
+/// Waiting for the sensitivities and the epsilon.
+#[derive(Debug)]
+pub struct SimpleNoiseStage {
+    epoch_start: u64,
+    epoch_end: u64,
+}
+
+/// Waiting for the URIs.
+#[derive(Debug)]
+pub struct SimpleUriStage {
+    epochs: SimpleNoiseStage,
+    report_global_sensitivity: f64,
+    query_global_sensitivity: f64,
+    requested_epsilon: f64,
+}
+
+/// Waiting for the event selector.
+#[derive(Debug)]
+pub struct SimpleSelectorStage {
+    noise: SimpleUriStage,
+    report_uris: ReportRequestUris<String>,
+}
+
+impl SimpleLastTouchHistogramRequestBuilder {
+    pub fn new() -> Self {
+        Self {
+            stage: SimpleEpochStage,
+        }
+    }
+}
+
+impl Default for SimpleLastTouchHistogramRequestBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SimpleLastTouchHistogramRequestBuilder<SimpleEpochStage> {
+    /// Attributes to the epochs from `epoch_start` to `epoch_end`, inclusive.
+    pub fn epoch_range(
+        self,
+        epoch_start: u64,
+        epoch_end: u64,
+    ) -> Result<
+        SimpleLastTouchHistogramRequestBuilder<SimpleNoiseStage>,
+        PdsError,
+    > {
+        if epoch_start > epoch_end {
+            return Err(PdsError::InvalidEpochWindow(format!(
+                "start epoch {epoch_start} is after end epoch {epoch_end}"
+            )));
+        }
+        Ok(SimpleLastTouchHistogramRequestBuilder {
+            stage: SimpleNoiseStage {
+                epoch_start,
+                epoch_end,
+            },
+        })
+    }
+}
+
+impl SimpleLastTouchHistogramRequestBuilder<SimpleNoiseStage> {
+    /// Sets the sensitivity of this report, the sensitivity of the query it
+    /// is part of, and the epsilon spent on the query. The report can't be
+    /// more sensitive than the query.
+    pub fn noise(
+        self,
+        report_global_sensitivity: f64,
+        query_global_sensitivity: f64,
+        requested_epsilon: f64,
+    ) -> Result<SimpleLastTouchHistogramRequestBuilder<SimpleUriStage>, PdsError>
+    {
+        if !query_global_sensitivity.is_finite()
+            || query_global_sensitivity < 0.0
+        {
+            return Err(PdsError::InvalidRequest(format!(
+                "query global sensitivity {query_global_sensitivity} must be finite and >= 0"
+            )));
+        }
+        if !(0.0..=query_global_sensitivity)
+            .contains(&report_global_sensitivity)
+        {
+            return Err(PdsError::InvalidRequest(format!(
+                "report global sensitivity {report_global_sensitivity} must be between 0 and the query global sensitivity {query_global_sensitivity}"
+            )));
+        }
+        if !requested_epsilon.is_finite() || requested_epsilon <= 0.0 {
+            return Err(PdsError::InvalidRequest(format!(
+                "requested epsilon {requested_epsilon} must be finite and > 0"
+            )));
+        }
+        Ok(SimpleLastTouchHistogramRequestBuilder {
+            stage: SimpleUriStage {
+                epochs: self.stage,
+                report_global_sensitivity,
+                query_global_sensitivity,
+                requested_epsilon,
+            },
+        })
+    }
+}
+
+impl SimpleLastTouchHistogramRequestBuilder<SimpleUriStage> {
+    /// Sets the trigger, source and querier URIs of the request.
+    pub fn uris(
+        self,
+        report_uris: ReportRequestUris<String>,
+    ) -> Result<
+        SimpleLastTouchHistogramRequestBuilder<SimpleSelectorStage>,
+        PdsError,
+    > {
+        validate_uris(&report_uris)?;
+        Ok(SimpleLastTouchHistogramRequestBuilder {
+            stage: SimpleSelectorStage {
+                noise: self.stage,
+                report_uris,
+            },
+        })
+    }
+}
+
+impl SimpleLastTouchHistogramRequestBuilder<SimpleSelectorStage> {
+    /// Sets the events to attribute to, and builds the request.
+    pub fn selector(
+        self,
+        is_relevant_event: SimpleRelevantEventSelector,
+    ) -> SimpleLastTouchHistogramRequest {
+        let SimpleSelectorStage { noise, report_uris } = self.stage;
+        SimpleLastTouchHistogramRequest {
+            epoch_start: noise.epochs.epoch_start,
+            epoch_end: noise.epochs.epoch_end,
+            report_global_sensitivity: noise.report_global_sensitivity,
+            query_global_sensitivity: noise.query_global_sensitivity,
+            requested_epsilon: noise.requested_epsilon,
+            is_relevant_event,
+            report_uris,
+        }
+    }
+}
+
+/// Requests need at least one source to attribute to and one querier to
+/// receive the report.
+fn validate_uris<U: Uri>(uris: &ReportRequestUris<U>) -> Result<(), PdsError> {
+    if uris.source_uris.is_empty() {
+        return Err(PdsError::InvalidRequest(format!(
+            "request for trigger {:?} has no source URIs",
+            uris.trigger_uri
+        )));
+    }
+    if uris.querier_uris.is_empty() {
+        return Err(PdsError::InvalidRequest(format!(
+            "request for trigger {:?} has no querier URIs",
+            uris.trigger_uri
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queries::traits::EpochReportRequest;
+
+    #[test]
+    fn test_ppa_histogram_request_builder() -> Result<(), PdsError> {
+        let request = PpaHistogramRequestBuilder::new()
+            .epoch_range(1, 3)?
+            .value(10.0, 20.0, 1.0)?
+            .uris(ReportRequestUris::mock())?
+            .selector(FilterDataPredicate::Any, 8, vec![1, 7].into())?
+            .bucket_policy(BucketPolicy::Modulo)
+            .build();
+        assert_eq!(request.epoch_ids(), vec![3, 2, 1]);
+        assert_eq!(request.histogram_size(), 8);
+        assert_eq!(request.report_global_sensitivity(), 20.0);
+
+        // Each stage is validated on its own.
+        let builder = PpaHistogramRequestBuilder::new;
+        assert!(matches!(
+            builder().epoch_range(3, 1),
+            Err(PdsError::InvalidEpochWindow(_))
+        ));
+        let value = |attributable_value, max_value, epsilon| {
+            builder().epoch_range(1, 1)?.value(
+                attributable_value,
+                max_value,
+                epsilon,
+            )
+        };
+        assert!(value(10.0, 20.0, 0.0).is_err());
+        assert!(value(30.0, 20.0, 1.0).is_err());
+        assert!(value(-1.0, 20.0, 1.0).is_err());
+        assert!(value(10.0, f64::INFINITY, 1.0).is_err());
+
+        let uris = value(10.0, 20.0, 1.0)?.uris(ReportRequestUris {
+            source_uris: vec![],
+            ..ReportRequestUris::mock()
+        });
+        assert!(uris.is_err());
+
+        let selector = value(10.0, 20.0, 1.0)?
+            .uris(ReportRequestUris::mock())?
+            .selector(FilterDataPredicate::Any, 8, vec![8].into());
+        assert!(selector.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_simple_request_builder() -> Result<(), PdsError> {
+        let selector = SimpleRelevantEventSelector { lambda: |_| true };
+        let request = SimpleLastTouchHistogramRequestBuilder::new()
+            .epoch_range(1, 2)?
+            .noise(3.0, 5.0, 5.0)?
+            .uris(ReportRequestUris::mock())?
+            .selector(selector);
+        assert_eq!(request.epoch_ids(), vec![2, 1]);
+        assert_eq!(request.report_global_sensitivity(), 3.0);
+
+        let noise = |report_sensitivity, query_sensitivity, epsilon| {
+            SimpleLastTouchHistogramRequestBuilder::new()
+                .epoch_range(1, 1)?
+                .noise(report_sensitivity, query_sensitivity, epsilon)
+        };
+        assert!(noise(6.0, 5.0, 1.0).is_err());
+        assert!(noise(1.0, 5.0, -1.0).is_err());
+        assert!(noise(f64::NAN, 5.0, 1.0).is_err());
+
+        Ok(())
+    }
+}
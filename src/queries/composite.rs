@@ -0,0 +1,386 @@
+//! Requests made of several sub-requests on the same trigger, e.g. an
+//! event-level and an aggregate measurement for the same conversion. The
+//! losses of the sub-requests are composed and charged once per filter, in a
+//! single atomic deduction: either all the sub-reports go through for an
+//! epoch, or none does.
+
+use serde::Serialize;
+
+use crate::{
+    error::PdsError,
+    events::{relevant_events::RelevantEvents, traits::RelevantEventSelector},
+    mechanisms::{NoiseScale, NormType},
+    queries::{
+        ppa_histogram::Lookback,
+        traits::{EpochReportRequest, Report, ReportRequestUris},
+    },
+    util::hashmap::HashMap,
+};
+
+/// Sub-requests sharing the same epochs and URIs. Each sub-request keeps its
+/// own relevant events, attribution and noise scale.
+///
+/// By sequential composition, the loss of an epoch is the sum of the losses
+/// of the sub-requests. Sensitivities are normalized by the noise scale of
+/// each sub-request, and the composite request reports a unit noise scale,
+/// so that the usual `sensitivity / noise_scale` accounting charges the
+/// composed loss. Each sub-report is still noised with its own noise scale.
+#[derive(Debug, Clone)]
+pub struct CompositeReportRequest<Q: EpochReportRequest> {
+    requests: Vec<Q>,
+}
+
+/// Reports of the sub-requests, in the order of the sub-requests. Empty for
+/// null reports.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CompositeReport<R> {
+    pub reports: Vec<R>,
+}
+
+impl<R> Default for CompositeReport<R> {
+    fn default() -> Self {
+        Self { reports: vec![] }
+    }
+}
+
+impl<R: Report> Report for CompositeReport<R> {}
+
+impl<Q: EpochReportRequest> CompositeReportRequest<Q> {
+    /// Rejects empty compositions, and sub-requests that don't share the
+    /// epochs and URIs of the first one, since they would be charged on
+    /// different filters.
+    pub fn new(requests: Vec<Q>) -> Result<Self, PdsError> {
+        let Some(first) = requests.first() else {
+            return Err(PdsError::InvalidRequest(
+                "composite requests need at least one sub-request".into(),
+            ));
+        };
+        for request in &requests[1..] {
+            if request.epoch_ids() != first.epoch_ids()
+                || request.duration_window() != first.duration_window()
+                || request.report_uris() != first.report_uris()
+            {
+                return Err(PdsError::InvalidRequest(format!(
+                    "sub-request {request:?} doesn't share the epochs and URIs of {first:?}"
+                )));
+            }
+        }
+        Ok(Self { requests })
+    }
+
+    pub fn requests(&self) -> &[Q] {
+        &self.requests
+    }
+
+    /// Relevant events of each sub-request.
+    fn sub_request_events(
+        &self,
+        request: &Q,
+        relevant_events: &RelevantEvents<Q::Event>,
+    ) -> RelevantEvents<Q::Event> {
+        let selector = request.relevant_event_selector();
+        let events_per_epoch = relevant_events
+            .events_per_epoch
+            .iter()
+            .map(|(epoch_id, events)| {
+                let events = events
+                    .iter()
+                    .filter(|event| selector.is_relevant_event(event))
+                    .cloned()
+                    .collect();
+                (*epoch_id, events)
+            })
+            .collect::<HashMap<_, _>>();
+        RelevantEvents::from_mapping(events_per_epoch)
+    }
+
+    /// Sums the sensitivities of the sub-requests, each normalized by its
+    /// noise scale.
+    fn composed_sensitivity(
+        &self,
+        sensitivity: impl Fn(usize, &Q) -> f64,
+    ) -> f64 {
+        self.requests
+            .iter()
+            .enumerate()
+            .map(|(i, request)| {
+                let NoiseScale::Laplace(noise_scale) = request.noise_scale();
+                // Non-private sub-requests make the whole request
+                // non-private.
+                if noise_scale.abs() < f64::EPSILON {
+                    return f64::INFINITY;
+                }
+                sensitivity(i, request) / noise_scale
+            })
+            .sum()
+    }
+
+    /// Sensitivity of the i-th sub-report. Sub-reports missing from null
+    /// reports have no sensitivity.
+    fn sub_report_sensitivity(
+        report: &CompositeReport<Q::Report>,
+        i: usize,
+        sensitivity: impl Fn(&Q::Report) -> f64,
+    ) -> f64 {
+        report.reports.get(i).map_or(0.0, sensitivity)
+    }
+}
+
+/// The composite request is its own selector: an event is relevant if it is
+/// relevant for any sub-request.
+impl<Q: EpochReportRequest> RelevantEventSelector
+    for CompositeReportRequest<Q>
+{
+    type Event = Q::Event;
+
+    fn is_relevant_event(&self, event: &Self::Event) -> bool {
+        self.requests.iter().any(|request| {
+            request.relevant_event_selector().is_relevant_event(event)
+        })
+    }
+}
+
+impl<Q: EpochReportRequest> EpochReportRequest for CompositeReportRequest<Q> {
+    type Uri = Q::Uri;
+    type EpochId = Q::EpochId;
+    type Event = Q::Event;
+    type RelevantEventSelector = Self;
+    type PrivacyBudget = Q::PrivacyBudget;
+    type Report = CompositeReport<Q::Report>;
+
+    fn report_uris(&self) -> &ReportRequestUris<Self::Uri> {
+        self.requests[0].report_uris()
+    }
+
+    fn epoch_ids(&self) -> Vec<Self::EpochId> {
+        self.requests[0].epoch_ids()
+    }
+
+    fn epoch_range(&self) -> (Self::EpochId, Self::EpochId) {
+        self.requests[0].epoch_range()
+    }
+
+    fn duration_window(&self) -> Option<Lookback> {
+        self.requests[0].duration_window()
+    }
+
+    fn with_epoch_ids(&self, epoch_ids: Vec<Self::EpochId>) -> Self {
+        let requests = self
+            .requests
+            .iter()
+            .map(|request| request.with_epoch_ids(epoch_ids.clone()))
+            .collect();
+        Self { requests }
+    }
+
+    fn relevant_event_selector(&self) -> &Self::RelevantEventSelector {
+        self
+    }
+
+    fn compute_report(
+        &self,
+        relevant_events: &RelevantEvents<Self::Event>,
+    ) -> Self::Report {
+        let reports = self
+            .requests
+            .iter()
+            .map(|request| {
+                request.compute_report(
+                    &self.sub_request_events(request, relevant_events),
+                )
+            })
+            .collect();
+        CompositeReport { reports }
+    }
+
+    fn is_event_in_range(&self, event: &Self::Event) -> bool {
+        self.requests
+            .iter()
+            .any(|request| request.is_event_in_range(event))
+    }
+
+    fn single_epoch_individual_sensitivity(
+        &self,
+        report: &Self::Report,
+        norm_type: NormType,
+    ) -> f64 {
+        self.composed_sensitivity(|i, request| {
+            Self::sub_report_sensitivity(report, i, |sub_report| {
+                request
+                    .single_epoch_individual_sensitivity(sub_report, norm_type)
+            })
+        })
+    }
+
+    fn single_epoch_source_individual_sensitivity(
+        &self,
+        report: &Self::Report,
+        norm_type: NormType,
+    ) -> f64 {
+        self.composed_sensitivity(|i, request| {
+            Self::sub_report_sensitivity(report, i, |sub_report| {
+                request.single_epoch_source_individual_sensitivity(
+                    sub_report, norm_type,
+                )
+            })
+        })
+    }
+
+    fn single_epoch_split_source_sensitivity(
+        &self,
+        report: &Self::Report,
+        source: &Self::Uri,
+        norm_type: NormType,
+    ) -> Option<f64> {
+        // Only if every sub-report is split by source.
+        let mut sensitivities = vec![];
+        for (i, request) in self.requests.iter().enumerate() {
+            let sensitivity = match report.reports.get(i) {
+                Some(sub_report) => request
+                    .single_epoch_split_source_sensitivity(
+                        sub_report, source, norm_type,
+                    )?,
+                None => 0.0,
+            };
+            sensitivities.push(sensitivity);
+        }
+        Some(self.composed_sensitivity(|i, _| sensitivities[i]))
+    }
+
+    fn multi_epoch_individual_sensitivity(
+        &self,
+        epoch_relevant_events: &[Self::Event],
+        norm_type: NormType,
+    ) -> Option<f64> {
+        // Sub-requests without relevant events in the epoch are not affected
+        // by it. The others use their own bound, or their global sensitivity.
+        let sensitivity = self.composed_sensitivity(|_, request| {
+            let selector = request.relevant_event_selector();
+            let events = epoch_relevant_events
+                .iter()
+                .filter(|event| selector.is_relevant_event(event))
+                .cloned()
+                .collect::<Vec<_>>();
+            if events.is_empty() {
+                return 0.0;
+            }
+            request
+                .multi_epoch_individual_sensitivity(&events, norm_type)
+                .unwrap_or_else(|| request.report_global_sensitivity())
+        });
+        Some(sensitivity)
+    }
+
+    fn report_global_sensitivity(&self) -> f64 {
+        self.composed_sensitivity(|_, request| {
+            request.report_global_sensitivity()
+        })
+    }
+
+    /// Unit scale, since sensitivities are already normalized.
+    fn noise_scale(&self) -> NoiseScale {
+        NoiseScale::Laplace(1.0)
+    }
+}
+
+#[cfg(all(test, feature = "experimental"))]
+mod tests {
+    use super::*;
+    use crate::{
+        budget::traits::FilterStorage,
+        events::{ppa_event::PpaEvent, traits::EventUris},
+        pds::{
+            aliases::{PpaEventStorage, PpaFilterStorage},
+            private_data_service::PrivateDataService,
+            quotas::{FilterId, StaticCapacities},
+        },
+        queries::{
+            builder::PpaHistogramRequestBuilder,
+            histogram::BucketPolicy,
+            ppa_histogram::{
+                FilterDataPredicate, PpaHistogramRequest, RequestedBuckets,
+            },
+        },
+    };
+
+    fn request(
+        epoch: u64,
+        histogram_size: u64,
+        requested_epsilon: f64,
+        is_matching_event: FilterDataPredicate,
+    ) -> Result<PpaHistogramRequest, PdsError> {
+        PpaHistogramRequestBuilder::new()
+            .epoch_range(epoch, epoch)?
+            .value(1.0, 1.0, requested_epsilon)?
+            .uris(ReportRequestUris::mock())?
+            .selector(
+                is_matching_event,
+                histogram_size,
+                RequestedBuckets::AllBuckets,
+            )
+            .map(|builder| builder.build())
+    }
+
+    #[test]
+    fn test_composite_request() -> Result<(), anyhow::Error> {
+        let capacities = StaticCapacities::new(2.0, 20.0, 10.0, 10.0);
+        let filters = PpaFilterStorage::new(capacities)?;
+        let mut pds: PrivateDataService<_, _, _, PdsError> =
+            PrivateDataService::new(filters, PpaEventStorage::new());
+        pds.register_event(PpaEvent {
+            id: 1,
+            timestamp: 0,
+            epoch_number: 1,
+            histogram_index: 3,
+            uris: EventUris::mock(),
+            filter_data: 1,
+            priority: 0,
+        })?;
+
+        // An event-level measurement with a single bucket, and an aggregate
+        // one that only matches other events.
+        let composite = || -> Result<_, PdsError> {
+            CompositeReportRequest::new(vec![
+                request(1, 1, 0.5, FilterDataPredicate::Any)?
+                    .with_bucket_policy(BucketPolicy::Modulo),
+                request(1, 5, 1.0, FilterDataPredicate::Equals(2))?,
+                request(1, 5, 0.25, FilterDataPredicate::Any)?,
+            ])
+        };
+
+        // Losses add up: 0.5 + 0 (no relevant event) + 0.25.
+        let report = pds.compute_report(&composite()?)?;
+        let reports = &report.filtered_report.reports;
+        assert_eq!(reports.len(), 3);
+        assert_eq!(reports[0].bin_values, HashMap::from_iter([(0, 1.0)]));
+        assert!(reports[1].bin_values.is_empty());
+        assert_eq!(reports[2].bin_values, HashMap::from_iter([(3, 1.0)]));
+        let querier_uri = ReportRequestUris::mock().querier_uris[0].clone();
+        let filter_id = FilterId::PerQuerier(1, querier_uri);
+        let remaining = pds.core.filter_storage.remaining_budget(&filter_id)?;
+        assert!((remaining - 1.25).abs() < 1e-9);
+
+        // The composed loss is charged atomically: once it doesn't fit, all
+        // the sub-reports are null.
+        pds.compute_report(&composite()?)?;
+        let report = pds.compute_report(&composite()?)?;
+        assert!(report
+            .filtered_report
+            .reports
+            .iter()
+            .all(|r| r.bin_values.is_empty()));
+        assert!(!report.oob_filters.is_empty());
+
+        // Sub-requests must share epochs and URIs.
+        assert!(CompositeReportRequest::new(vec![
+            request(1, 5, 1.0, FilterDataPredicate::Any)?,
+            request(2, 5, 1.0, FilterDataPredicate::Any)?,
+        ])
+        .is_err());
+        assert!(
+            CompositeReportRequest::<PpaHistogramRequest>::new(vec![]).is_err()
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+use alloc::{format, vec::Vec};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{error::PdsError, events::traits::EpochId, util::hashmap::HashSet};
+
+/// Epochs requested by a query. Windows don't have to be contiguous, e.g. to
+/// skip a blackout epoch or to only look at the same weekday.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EpochSelection {
+    /// All the epochs in `start..=end`.
+    Range { start: u64, end: u64 },
+
+    /// Arbitrary set of epochs. Duplicates are ignored.
+    Set(Vec<u64>),
+
+    /// The `count` epochs `end`, `end - stride`, `end - 2 * stride`, etc.
+    /// E.g. the 4 most recent Mondays with daily epochs and a stride of 7.
+    Lookback { end: u64, count: u64, stride: u64 },
+
+    /// The epochs overlapping the `duration` seconds before `trigger_time`,
+    /// known as impression-time windows. The PDS resolves them with its
+    /// `EpochPolicy`, so that the same request works on devices with other
+    /// epoch durations. Only events within the window are attributed to.
+    Duration { trigger_time: u64, duration: u64 },
+}
+
+impl EpochSelection {
+    /// Rejects selections that are empty or go below epoch 0.
+    pub fn validate(&self) -> Result<(), PdsError> {
+        match self {
+            EpochSelection::Range { start, end } if start > end => {
+                Err(PdsError::InvalidEpochWindow(format!(
+                    "start epoch {start} is after end epoch {end}"
+                )))
+            }
+            EpochSelection::Set(epochs) if epochs.is_empty() => Err(
+                PdsError::InvalidEpochWindow("epoch set is empty".into()),
+            ),
+            EpochSelection::Lookback { count, stride, .. }
+                if *count == 0 || *stride == 0 =>
+            {
+                Err(PdsError::InvalidEpochWindow(
+                    "lookback count and stride must be > 0".into(),
+                ))
+            }
+            EpochSelection::Lookback { end, count, stride }
+                if (count - 1).saturating_mul(*stride) > *end =>
+            {
+                Err(PdsError::InvalidEpochWindow(format!(
+                    "lookback of {count} epochs every {stride} epochs goes before epoch 0 from epoch {end}"
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Selected epochs, most recent first, without duplicates. Empty for
+    /// durations, until the PDS resolves them.
+    pub fn epoch_ids(&self) -> Vec<u64> {
+        match self {
+            EpochSelection::Range { start, end } => {
+                (*start..=*end).rev().collect()
+            }
+            EpochSelection::Set(epochs) => {
+                let mut epochs = unique_epochs(epochs.clone());
+                epochs.sort_by(|a, b| b.cmp(a));
+                epochs
+            }
+            EpochSelection::Lookback { end, count, stride } => (0..*count)
+                .map_while(|i| end.checked_sub(i.checked_mul(*stride)?))
+                .collect(),
+            EpochSelection::Duration { .. } => Vec::new(),
+        }
+    }
+
+    /// First and last selected epochs. For ranges, these are the bounds as
+    /// given, even if they are inverted.
+    pub fn epoch_range(&self) -> (u64, u64) {
+        match self {
+            EpochSelection::Range { start, end } => (*start, *end),
+            _ => {
+                let epochs = self.epoch_ids();
+                let first = epochs.last().copied().unwrap_or_default();
+                let last = epochs.first().copied().unwrap_or_default();
+                (first, last)
+            }
+        }
+    }
+}
+
+/// Removes duplicate epochs, keeping the first occurrence of each epoch so
+/// that the attribution order is preserved. Epochs must only be accounted
+/// for once per request.
+pub fn unique_epochs<E: EpochId>(epochs: Vec<E>) -> Vec<E> {
+    let mut seen = HashSet::new();
+    epochs
+        .into_iter()
+        .filter(|epoch| seen.insert(*epoch))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_epoch_selection() {
+        let range = EpochSelection::Range { start: 2, end: 4 };
+        assert_eq!(range.epoch_ids(), vec![4, 3, 2]);
+        assert_eq!(range.epoch_range(), (2, 4));
+
+        let set = EpochSelection::Set(vec![3, 9, 1, 9]);
+        assert_eq!(set.epoch_ids(), vec![9, 3, 1]);
+        assert_eq!(set.epoch_range(), (1, 9));
+
+        let mondays = EpochSelection::Lookback {
+            end: 22,
+            count: 4,
+            stride: 7,
+        };
+        assert_eq!(mondays.epoch_ids(), vec![22, 15, 8, 1]);
+        assert_eq!(mondays.epoch_range(), (1, 22));
+        assert!(mondays.validate().is_ok());
+
+        let week = EpochSelection::Duration {
+            trigger_time: 1_000_000,
+            duration: 7 * 24 * 60 * 60,
+        };
+        assert!(week.validate().is_ok());
+        assert!(week.epoch_ids().is_empty());
+
+        let invalid = [
+            EpochSelection::Range { start: 3, end: 2 },
+            EpochSelection::Set(vec![]),
+            EpochSelection::Lookback {
+                end: 20,
+                count: 4,
+                stride: 7,
+            },
+            EpochSelection::Lookback {
+                end: 20,
+                count: 0,
+                stride: 7,
+            },
+        ];
+        for selection in invalid {
+            assert!(matches!(
+                selection.validate(),
+                Err(PdsError::InvalidEpochWindow(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_unique_epochs() {
+        assert_eq!(unique_epochs(vec![2, 5, 2, 1, 5]), vec![2, 5, 1]);
+    }
+}
@@ -0,0 +1,269 @@
+use alloc::{string::String, vec::Vec};
+
+use serde::Serialize;
+
+use crate::{
+    budget::pure_dp_filter::PureDPBudget,
+    error::PdsError,
+    events::{
+        ppa_event::PpaEvent, relevant_events::RelevantEvents, traits::Uri,
+    },
+    mechanisms::{NoiseScale, NormType},
+    queries::{
+        histogram::HistogramReport,
+        ppa_histogram::{
+            Lookback, PpaBucketKey, PpaEpochId, PpaHistogramRequest,
+            PpaRelevantEventSelector,
+        },
+        traits::{EpochReportRequest, Report, ReportRequestUris},
+    },
+    util::{hashmap::HashMap, math},
+};
+
+/// Report of a hierarchical histogram, with one histogram per level of the
+/// tree. Level 0 is the root, with a single bucket, and the last level has
+/// the buckets of the underlying PPA histogram. Bucket `b` of a level is the
+/// parent of buckets `b * branching_factor..(b + 1) * branching_factor` of
+/// the next level.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct HierarchicalHistogramReport {
+    /// Empty for null reports.
+    pub levels: Vec<HistogramReport<PpaBucketKey>>,
+}
+
+impl Report for HierarchicalHistogramReport {}
+
+/// Tree-structured (prefix) histogram over the buckets of a PPA histogram
+/// request, e.g. to answer range queries on conversion values with less
+/// noise than summing leaf buckets. The attributed value is counted once per
+/// level, so sensitivities add up across levels.
+#[derive(Debug, Clone)]
+pub struct HierarchicalHistogramRequest<U: Uri = String> {
+    request: PpaHistogramRequest<U>,
+    branching_factor: u64,
+    n_levels: u32,
+}
+
+impl<U: Uri> HierarchicalHistogramRequest<U> {
+    /// Builds a tree with the given branching factor over the buckets of
+    /// `request`, with as many levels as needed to have one root bucket.
+    pub fn new(
+        request: PpaHistogramRequest<U>,
+        branching_factor: u64,
+    ) -> Result<Self, PdsError> {
+        if branching_factor < 2 {
+            return Err(PdsError::InvalidRequest(
+                "branching_factor must be at least 2".into(),
+            ));
+        }
+
+        let mut n_levels = 1;
+        let mut n_buckets = request.histogram_size();
+        while n_buckets > 1 {
+            n_buckets = n_buckets.div_ceil(branching_factor);
+            n_levels += 1;
+        }
+
+        Ok(Self {
+            request,
+            branching_factor,
+            n_levels,
+        })
+    }
+
+    /// Number of levels of the tree, including the root and the leaves.
+    pub fn n_levels(&self) -> u32 {
+        self.n_levels
+    }
+
+    /// Maps a leaf bucket to its ancestor at the given level.
+    pub fn bucket_at_level(
+        &self,
+        leaf: PpaBucketKey,
+        level: u32,
+    ) -> PpaBucketKey {
+        let depth = self.n_levels - 1 - level;
+        match self.branching_factor.checked_pow(depth) {
+            Some(divisor) => leaf / divisor,
+            None => 0,
+        }
+    }
+}
+
+impl<U: Uri> EpochReportRequest for HierarchicalHistogramRequest<U> {
+    type Uri = U;
+    type EpochId = PpaEpochId;
+    type Event = PpaEvent<U>;
+    type RelevantEventSelector = PpaRelevantEventSelector<U>;
+    type PrivacyBudget = PureDPBudget;
+    type Report = HierarchicalHistogramReport;
+
+    fn epoch_ids(&self) -> Vec<Self::EpochId> {
+        self.request.epoch_ids()
+    }
+
+    fn epoch_range(&self) -> (Self::EpochId, Self::EpochId) {
+        self.request.epoch_range()
+    }
+
+    fn duration_window(&self) -> Option<Lookback> {
+        self.request.duration_window()
+    }
+
+    fn with_epoch_ids(&self, epoch_ids: Vec<Self::EpochId>) -> Self {
+        Self {
+            request: self.request.with_epoch_ids(epoch_ids),
+            ..self.clone()
+        }
+    }
+
+    fn relevant_event_selector(&self) -> &Self::RelevantEventSelector {
+        self.request.relevant_event_selector()
+    }
+
+    fn report_uris(&self) -> &ReportRequestUris<Self::Uri> {
+        self.request.report_uris()
+    }
+
+    fn compute_report(
+        &self,
+        relevant_events: &RelevantEvents<Self::Event>,
+    ) -> Self::Report {
+        let leaves = self.request.compute_report(relevant_events);
+        if leaves.bin_values.is_empty() {
+            return HierarchicalHistogramReport::default();
+        }
+
+        let levels = (0..self.n_levels)
+            .map(|level| {
+                let mut bin_values = HashMap::new();
+                for (leaf, value) in &leaves.bin_values {
+                    *bin_values
+                        .entry(self.bucket_at_level(*leaf, level))
+                        .or_default() += value;
+                }
+                HistogramReport { bin_values }
+            })
+            .collect();
+        HierarchicalHistogramReport { levels }
+    }
+
+    fn is_event_in_range(&self, event: &Self::Event) -> bool {
+        self.request.is_event_in_range(event)
+    }
+
+    fn single_epoch_individual_sensitivity(
+        &self,
+        report: &Self::Report,
+        norm_type: NormType,
+    ) -> f64 {
+        // Levels are disjoint sets of buckets of a single vector.
+        let values = report
+            .levels
+            .iter()
+            .flat_map(|level| level.bin_values.values());
+        match norm_type {
+            NormType::L1 => values.map(|value| value.abs()).sum(),
+            NormType::L2 => math::sqrt(values.map(|value| value * value).sum()),
+        }
+    }
+
+    fn single_epoch_source_individual_sensitivity(
+        &self,
+        report: &Self::Report,
+        norm_type: NormType,
+    ) -> f64 {
+        self.single_epoch_individual_sensitivity(report, norm_type)
+    }
+
+    fn report_global_sensitivity(&self) -> f64 {
+        f64::from(self.n_levels) * self.request.report_global_sensitivity()
+    }
+
+    fn noise_scale(&self) -> NoiseScale {
+        self.request.noise_scale()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        events::traits::EventUris,
+        queries::{
+            builder::PpaHistogramRequestBuilder,
+            ppa_histogram::{FilterDataPredicate, RequestedBuckets},
+        },
+    };
+
+    fn hierarchical_request(
+        start_epoch: u64,
+        histogram_size: u64,
+    ) -> Result<HierarchicalHistogramRequest, PdsError> {
+        let request = PpaHistogramRequestBuilder::new()
+            .epoch_range(start_epoch, 2)?
+            .value(10.0, 10.0, 1.0)?
+            .uris(ReportRequestUris::mock())?
+            .selector(
+                FilterDataPredicate::Any,
+                histogram_size,
+                RequestedBuckets::AllBuckets,
+            )?
+            .build();
+        HierarchicalHistogramRequest::new(request, 2)
+    }
+
+    #[test]
+    fn test_hierarchical_report() -> Result<(), PdsError> {
+        let request = hierarchical_request(2, 6)?;
+        assert_eq!(request.n_levels(), 4);
+
+        let event = PpaEvent {
+            id: 1,
+            timestamp: 1,
+            epoch_number: 2,
+            histogram_index: 5,
+            uris: EventUris::mock(),
+            filter_data: 0,
+            priority: 0,
+        };
+        let relevant_events = RelevantEvents::from_vec(vec![event]);
+        let report = request.compute_report(&relevant_events);
+
+        // Bucket 5 is 101 in binary, its ancestors are its prefixes.
+        let buckets = report
+            .levels
+            .iter()
+            .map(|level| level.bin_values.clone())
+            .collect::<Vec<_>>();
+        let expected =
+            [0, 1, 2, 5].map(|bucket| HashMap::from_iter([(bucket, 10.0)]));
+        assert_eq!(buckets, expected);
+
+        // The attributed value counts once per level.
+        let sensitivity =
+            request.single_epoch_individual_sensitivity(&report, NormType::L1);
+        assert_eq!(sensitivity, 40.0);
+        assert_eq!(request.report_global_sensitivity(), 40.0);
+        assert_eq!(
+            hierarchical_request(1, 6)?.report_global_sensitivity(),
+            80.0
+        );
+
+        // Null reports have no levels.
+        let report = request.compute_report(&RelevantEvents::from_vec(vec![]));
+        assert!(report.levels.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_branching_factor() -> Result<(), PdsError> {
+        let request = hierarchical_request(2, 6)?.request;
+        assert!(matches!(
+            HierarchicalHistogramRequest::new(request, 1),
+            Err(PdsError::InvalidRequest(_))
+        ));
+        Ok(())
+    }
+}
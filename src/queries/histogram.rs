@@ -1,23 +1,130 @@
-use std::{fmt::Debug, hash::Hash};
+use alloc::vec::Vec;
+use core::{fmt::Debug, hash::Hash, num::NonZeroU64};
+
+use serde::{Deserialize, Serialize};
 
 use crate::{
+    error::PdsError,
     events::relevant_events::RelevantEvents,
     mechanisms::NormType,
     queries::traits::{EpochReportRequest, Report, ReportRequestUris},
-    util::hashmap::HashMap,
+    util::{hashmap::HashMap, math},
 };
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "BucketKey: Serialize + Hash + Eq",
+    deserialize = "BucketKey: Deserialize<'de> + Hash + Eq"
+))]
 pub struct HistogramReport<BucketKey> {
     pub bin_values: HashMap<BucketKey, f64>,
 }
 
 /// Trait for bucket keys.
-pub trait BucketKey: Debug + Hash + Eq + Clone {}
+pub trait BucketKey: Debug + Hash + Eq + Clone + Serialize {}
 
 /// Default type for bucket keys.
 impl BucketKey for u64 {}
 
+/// 128-bit keys, e.g. for ARA aggregation keys.
+impl BucketKey for u128 {}
+
+/// What to do with bucket indices outside of the histogram domain
+/// `0..histogram_size`, or how to merge a larger key domain into it.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize,
+)]
+pub enum BucketPolicy {
+    /// Drop the event, as if it was not relevant.
+    #[default]
+    Reject,
+
+    /// Wrap the index around the domain.
+    Modulo,
+
+    /// Hash the index into the domain, so that structured indices, e.g.
+    /// sharing their low bits, don't all land in the same buckets.
+    Hash,
+
+    /// Merge contiguous ranges of the key domain `0..key_domain` into the
+    /// buckets, e.g. keys 0 to 3 into bucket 0 and keys 4 to 7 into bucket 1
+    /// for 8 keys and 2 buckets. Unlike the other policies, in-range indices
+    /// are remapped too, and indices outside the key domain are dropped.
+    ///
+    /// Each event still lands in a single bucket, so merging never increases
+    /// the global sensitivity, and individual sensitivities are computed on
+    /// the merged buckets.
+    Coarsen { key_domain: NonZeroU64 },
+}
+
+/// Validates bucket indices against the domain of a histogram, and remaps or
+/// rejects out-of-range ones according to its `BucketPolicy`. In-range
+/// indices are never remapped, unless the policy coarsens the keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BucketMapper {
+    histogram_size: u64,
+    policy: BucketPolicy,
+}
+
+impl BucketMapper {
+    pub fn new(
+        histogram_size: u64,
+        policy: BucketPolicy,
+    ) -> Result<Self, PdsError> {
+        if histogram_size == 0 {
+            return Err(PdsError::InvalidRequest(
+                "histogram_size must be greater than 0".into(),
+            ));
+        }
+        Ok(Self {
+            histogram_size,
+            policy,
+        })
+    }
+
+    /// Same domain with another policy.
+    pub fn with_policy(mut self, policy: BucketPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Number of buckets in the domain.
+    pub fn histogram_size(&self) -> u64 {
+        self.histogram_size
+    }
+
+    pub fn policy(&self) -> BucketPolicy {
+        self.policy
+    }
+
+    /// Bucket for the given index, or None if the index is out of range and
+    /// rejected.
+    pub fn map(&self, index: u64) -> Option<u64> {
+        match self.policy {
+            BucketPolicy::Coarsen { key_domain } => {
+                let key_domain = key_domain.get();
+                // Fits in u64 since index < key_domain.
+                (index < key_domain).then(|| {
+                    (index as u128 * self.histogram_size as u128
+                        / key_domain as u128) as u64
+                })
+            }
+            _ if index < self.histogram_size => Some(index),
+            BucketPolicy::Reject => None,
+            BucketPolicy::Modulo => Some(index % self.histogram_size),
+            BucketPolicy::Hash => Some(mix64(index) % self.histogram_size),
+        }
+    }
+}
+
+/// SplitMix64 finalizer. Deterministic across platforms and releases, unlike
+/// the standard library hashers, so reports don't depend on the build.
+fn mix64(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
 /// Default histogram has no bins (null report).
 impl<BK> Default for HistogramReport<BK> {
     fn default() -> Self {
@@ -27,6 +134,12 @@ impl<BK> Default for HistogramReport<BK> {
     }
 }
 
+impl<BK: BucketKey> PartialEq for HistogramReport<BK> {
+    fn eq(&self, other: &Self) -> bool {
+        self.bin_values == other.bin_values
+    }
+}
+
 impl<BK: BucketKey> Report for HistogramReport<BK> {}
 
 /// Trait for generic histogram requests. Any type satisfying
@@ -43,8 +156,10 @@ where
     /// for this particular conversion. a.k.a. A^max.
     fn attributable_value(&self) -> f64;
 
-    /// Returns the histogram bucket key (bin) for a given event.
-    fn bucket_key(&self, event: &Self::Event) -> Self::BucketKey;
+    /// Returns the histogram bucket key (bin) for a given event, or None if
+    /// the event is outside of the histogram domain and rejected. Rejected
+    /// events are not attributed any value.
+    fn bucket_key(&self, event: &Self::Event) -> Option<Self::BucketKey>;
 
     /// Attributes a value to each event in `relevant_events_per_epoch`, which
     /// will be obtained by retrieving *relevant* events from the event
@@ -90,6 +205,9 @@ where
         let mut early_stop = false;
 
         for (event, value) in event_values {
+            let Some(bin) = self.bucket_key(event) else {
+                continue;
+            };
             total_value += value;
             if total_value > self.attributable_value() {
                 // Return partial attribution to stay within the cap.
@@ -99,7 +217,6 @@ where
                 };
                 break;
             }
-            let bin = self.bucket_key(event);
             *bin_values.entry(bin).or_default() += value;
         }
 
@@ -121,7 +238,7 @@ where
             NormType::L2 => {
                 let sum_squares: f64 =
                     report.bin_values.values().map(|x| x * x).sum();
-                sum_squares.sqrt()
+                math::sqrt(sum_squares)
             }
         }
     }
@@ -153,3 +270,46 @@ where
         self.attributable_value()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_mapper() -> Result<(), PdsError> {
+        assert!(BucketMapper::new(0, BucketPolicy::Reject).is_err());
+
+        let mapper = BucketMapper::new(4, BucketPolicy::Reject)?;
+        assert_eq!(mapper.map(3), Some(3));
+        assert_eq!(mapper.map(4), None);
+
+        let mapper = mapper.with_policy(BucketPolicy::Modulo);
+        assert_eq!(mapper.map(3), Some(3));
+        assert_eq!(mapper.map(9), Some(1));
+
+        // Hashed indices stay in the domain and are stable.
+        let mapper = mapper.with_policy(BucketPolicy::Hash);
+        assert_eq!(mapper.map(3), Some(3));
+        let buckets = (4..100).filter_map(|i| mapper.map(i));
+        assert!(buckets.clone().all(|bucket| bucket < 4));
+        assert_eq!(buckets.clone().count(), 96);
+        assert_eq!(mapper.map(42), mapper.map(42));
+
+        // Coarsened keys are merged by contiguous ranges.
+        let key_domain = NonZeroU64::new(16).unwrap();
+        let mapper = mapper.with_policy(BucketPolicy::Coarsen { key_domain });
+        assert_eq!(mapper.map(0), Some(0));
+        assert_eq!(mapper.map(3), Some(0));
+        assert_eq!(mapper.map(4), Some(1));
+        assert_eq!(mapper.map(15), Some(3));
+        assert_eq!(mapper.map(16), None);
+        assert_eq!(mapper.map(u64::MAX), None);
+
+        // Smaller key domains are spread over the buckets.
+        let key_domain = NonZeroU64::new(2).unwrap();
+        let mapper = mapper.with_policy(BucketPolicy::Coarsen { key_domain });
+        assert_eq!((mapper.map(0), mapper.map(1)), (Some(0), Some(2)));
+
+        Ok(())
+    }
+}
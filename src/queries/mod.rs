@@ -1,4 +1,19 @@
+#[cfg(feature = "std")]
+pub mod any_request;
+#[cfg(feature = "std")]
+pub mod ara_histogram;
+#[cfg(feature = "std")]
+pub mod builder;
+#[cfg(feature = "std")]
+pub mod composite;
+pub mod epoch_selection;
+pub mod hierarchical_histogram;
 pub mod histogram;
 pub mod ppa_histogram;
+#[cfg(feature = "std")]
 pub mod simple_last_touch_histogram;
+pub mod source_keyed_histogram;
+#[cfg(feature = "std")]
+pub mod spec;
+pub mod threshold;
 pub mod traits;
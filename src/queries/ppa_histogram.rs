@@ -1,47 +1,187 @@
-use std::vec;
+use alloc::{format, string::String, sync::Arc, vec, vec::Vec};
 
-use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
 
 use crate::{
     budget::pure_dp_filter::PureDPBudget,
+    error::PdsError,
     events::{
         ppa_event::PpaEvent,
         relevant_events::RelevantEvents,
         traits::{RelevantEventSelector, Uri},
+        trigger_event::TriggerEvent,
     },
     mechanisms::{NoiseScale, NormType},
+    pds::{accounting::EventSampling, dedup::TriggerDedup},
     queries::{
-        histogram::{BucketKey, HistogramReport, HistogramRequest},
+        epoch_selection::{unique_epochs, EpochSelection},
+        histogram::{
+            BucketKey, BucketMapper, BucketPolicy, HistogramReport,
+            HistogramRequest,
+        },
         traits::{EpochReportRequest, ReportRequestUris},
     },
-    util::hashmap::{HashMap, HashSet},
+    util::{
+        hashmap::{HashMap, HashSet},
+        math,
+    },
 };
 
 pub type PpaBucketKey = u64;
 pub type PpaEpochId = u64;
 pub type PpaFilterData = u64;
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct PpaRelevantEventSelector<U: Uri = String> {
     /// source/trigger/querier URIs for this request
     pub report_request_uris: ReportRequestUris<U>,
 
-    /// Function to determine if an event is relevant based on its filter_data
-    pub is_matching_event: Box<dyn Fn(PpaFilterData) -> bool>,
+    /// Predicate to determine if an event is relevant based on its
+    /// filter_data
+    pub is_matching_event: FilterDataPredicate,
 
     /// List of requested histogram buckets. All other buckets are ignored.
     /// If None, all buckets are requested.
     pub requested_buckets: RequestedBuckets<PpaBucketKey>,
+
+    /// Only events registered within the window are relevant. Set from
+    /// `PpaHistogramConfig::lookback` when the request is constructed.
+    pub lookback: Option<Lookback>,
+}
+
+/// Lookback window evaluated against event timestamps, e.g. the last 7 days
+/// before the conversion. Finer than the requested epochs, which it doesn't
+/// replace: the window is only applied to the events of these epochs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lookback {
+    /// Time of the conversion, in seconds.
+    pub trigger_time: u64,
+
+    /// Length of the window before `trigger_time`, in seconds.
+    pub duration: u64,
 }
 
-impl<U: Uri> std::fmt::Debug for PpaRelevantEventSelector<U> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl Lookback {
+    /// Whether an event with the given timestamp is in the window.
+    pub fn contains(&self, timestamp: u64) -> bool {
+        let start = self.trigger_time.saturating_sub(self.duration);
+        (start..=self.trigger_time).contains(&timestamp)
+    }
+}
+
+impl<U: Uri> core::fmt::Debug for PpaRelevantEventSelector<U> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("PpaRelevantEventSelector")
             .field("report_request_uris", &self.report_request_uris)
+            .field("is_matching_event", &self.is_matching_event)
+            .field("lookback", &self.lookback)
             .finish_non_exhaustive()
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// Declarative predicate on the `filter_data` of an event, known as `filters`
+/// in the PPA spec. Unlike an arbitrary closure, it can be serialized,
+/// validated, and inspected by storage implementations for bulk filtering.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum FilterDataPredicate {
+    /// Matches all events.
+    Any,
+
+    /// Matches events whose filter_data is equal to the given value.
+    Equals(PpaFilterData),
+
+    /// Matches events whose filter_data is in the given set.
+    InSet(HashSet<PpaFilterData>),
+
+    /// Matches events such that `filter_data & mask == value`.
+    BitmaskMatch {
+        mask: PpaFilterData,
+        value: PpaFilterData,
+    },
+
+    /// Matches events whose filter_data is in `start..=end`.
+    Range {
+        start: PpaFilterData,
+        end: PpaFilterData,
+    },
+
+    /// Arbitrary closure, for local use only. Can't be serialized. Must be
+    /// `Send + Sync` so that requests can be shared across threads. Clones
+    /// of the predicate share the closure.
+    #[serde(skip)]
+    Custom(Arc<dyn Fn(PpaFilterData) -> bool + Send + Sync>),
+}
+
+impl FilterDataPredicate {
+    /// Predicate from an arbitrary closure, see `Custom`.
+    pub fn custom(
+        f: impl Fn(PpaFilterData) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        Self::Custom(Arc::new(f))
+    }
+
+    /// Checks whether the given filter_data satisfies the predicate.
+    pub fn matches(&self, filter_data: PpaFilterData) -> bool {
+        match self {
+            FilterDataPredicate::Any => true,
+            FilterDataPredicate::Equals(value) => filter_data == *value,
+            FilterDataPredicate::InSet(values) => values.contains(&filter_data),
+            FilterDataPredicate::BitmaskMatch { mask, value } => {
+                filter_data & mask == *value
+            }
+            FilterDataPredicate::Range { start, end } => {
+                (*start..=*end).contains(&filter_data)
+            }
+            FilterDataPredicate::Custom(f) => f(filter_data),
+        }
+    }
+
+    /// Rejects predicates that can never match any event, which are most
+    /// likely misconfigured.
+    pub fn validate(&self) -> Result<(), PdsError> {
+        match self {
+            FilterDataPredicate::InSet(values) if values.is_empty() => {
+                Err(PdsError::InvalidRequest("filter_data set must not be empty".into()))
+            }
+            FilterDataPredicate::BitmaskMatch { mask, value }
+                if value & !mask != 0 =>
+            {
+                Err(PdsError::InvalidRequest(format!("bitmask value {value:#x} has bits outside of mask {mask:#x}")))
+            }
+            FilterDataPredicate::Range { start, end } if start > end => {
+                Err(PdsError::InvalidRequest(format!("filter_data range start {start} is after end {end}")))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl core::fmt::Debug for FilterDataPredicate {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Any => write!(f, "Any"),
+            Self::Equals(value) => {
+                f.debug_tuple("Equals").field(value).finish()
+            }
+            Self::InSet(values) => {
+                f.debug_tuple("InSet").field(values).finish()
+            }
+            Self::BitmaskMatch { mask, value } => f
+                .debug_struct("BitmaskMatch")
+                .field("mask", mask)
+                .field("value", value)
+                .finish(),
+            Self::Range { start, end } => f
+                .debug_struct("Range")
+                .field("start", start)
+                .field("end", end)
+                .finish(),
+            Self::Custom(_) => f.debug_tuple("Custom").finish_non_exhaustive(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum RequestedBuckets<BK: BucketKey> {
     AllBuckets,
     SpecificBuckets(HashSet<BK>),
@@ -66,11 +206,16 @@ impl<BK: BucketKey> From<Vec<BK>> for RequestedBuckets<BK> {
 
 /// For compatibility with PPA spec that uses two parameters (epsilon, query
 /// global sensitivity) instead of directly Laplace noise scale.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PpaHistogramConfig {
     pub start_epoch: PpaEpochId,
     pub end_epoch: PpaEpochId,
 
+    /// Epochs to attribute to, for non-contiguous windows. Replaces
+    /// `start_epoch..=end_epoch` if set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub epochs: Option<EpochSelection>,
+
     /// Conversion value that is spread across events for this conversion.
     pub attributable_value: f64,
 
@@ -80,6 +225,112 @@ pub struct PpaHistogramConfig {
     /// Budget spent on the batch, considering the max_attributable_value.
     pub requested_epsilon: f64,
     pub histogram_size: u64,
+
+    /// Clamping and scaling of the conversion values, applied to both the
+    /// attributable value and the maximum attributable value.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value_policy: Option<ValuePolicy>,
+
+    /// Lookback window within the requested epochs, see `Lookback`. Epochs
+    /// are still accounted for as a whole, so the window doesn't change the
+    /// sensitivity.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lookback: Option<Lookback>,
+
+    /// Allowed values of the requested epsilon, see `EpsilonGrid`. Any
+    /// epsilon is allowed if None.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub epsilon_grid: Option<EpsilonGrid>,
+}
+
+/// Quantization of the requested epsilon to a fixed set of allowed values,
+/// so that queriers can only pick among a few noise scales. This limits the
+/// number of distinguishable query classes, e.g. to analyze or cache them
+/// per class. Requested epsilons are rounded down to the nearest allowed
+/// value, so a request never spends more than it asked for, and requests
+/// below the smallest allowed value are rejected.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EpsilonGrid {
+    pub allowed_epsilons: Vec<f64>,
+}
+
+impl EpsilonGrid {
+    /// Rejects empty grids and epsilons that are not finite and positive.
+    pub fn validate(&self) -> Result<(), PdsError> {
+        let is_valid = !self.allowed_epsilons.is_empty()
+            && self
+                .allowed_epsilons
+                .iter()
+                .all(|epsilon| epsilon.is_finite() && *epsilon > 0.0);
+        match is_valid {
+            true => Ok(()),
+            false => Err(PdsError::InvalidRequest(format!(
+                "invalid epsilon grid {self:?}"
+            ))),
+        }
+    }
+
+    /// Rounds `epsilon` down to the largest allowed value below it.
+    pub fn quantize(&self, epsilon: f64) -> Result<f64, PdsError> {
+        self.allowed_epsilons
+            .iter()
+            .copied()
+            .filter(|allowed| *allowed <= epsilon)
+            .max_by(f64::total_cmp)
+            .ok_or_else(|| {
+                PdsError::InvalidRequest(format!(
+                    "requested epsilon {epsilon} is below the smallest allowed epsilon in {:?}",
+                    self.allowed_epsilons
+                ))
+            })
+    }
+}
+
+/// Rounding of scaled conversion values to the integer domain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RoundingMode {
+    Nearest,
+    Floor,
+    Ceil,
+}
+
+/// Maps raw conversion values, e.g. revenue, to the value domain of the
+/// histogram: values are clamped to `[min_value, max_value]`, multiplied by
+/// `scaling_factor` and rounded to an integer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ValuePolicy {
+    pub min_value: f64,
+    pub max_value: f64,
+    pub scaling_factor: f64,
+    pub rounding: RoundingMode,
+}
+
+impl ValuePolicy {
+    /// Rejects policies that can produce negative or non-finite values.
+    pub fn validate(&self) -> Result<(), PdsError> {
+        let is_valid = self.min_value >= 0.0
+            && self.min_value <= self.max_value
+            && self.max_value.is_finite()
+            && self.scaling_factor > 0.0
+            && self.scaling_factor.is_finite();
+        match is_valid {
+            true => Ok(()),
+            false => Err(PdsError::InvalidRequest(format!(
+                "invalid value policy {self:?}"
+            ))),
+        }
+    }
+
+    /// Clamps, scales and rounds a raw conversion value.
+    pub fn apply(&self, value: f64) -> f64 {
+        let scaled =
+            value.clamp(self.min_value, self.max_value) * self.scaling_factor;
+        match self.rounding {
+            RoundingMode::Nearest => math::round(scaled),
+            RoundingMode::Floor => math::floor(scaled),
+            RoundingMode::Ceil => math::ceil(scaled),
+        }
+    }
 }
 
 /// Alternative configuration that directly provides Laplace noise scale.
@@ -95,9 +346,66 @@ pub struct DirectPpaHistogramConfig {
     pub histogram_size: u64,
 }
 
-#[derive(Debug, Clone)]
+/// Serializable specification of a `PpaHistogramRequest`, so that embedders
+/// can construct requests from JSON coming over IPC/FFI.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PpaHistogramRequestSpec<U: Uri = String> {
+    pub config: PpaHistogramConfig,
+
+    /// source/trigger/querier URIs for this request
+    pub report_request_uris: ReportRequestUris<U>,
+
+    /// Known as `filters` in the PPA spec. Matches all events if omitted.
+    #[serde(default = "default_filter_data_predicate")]
+    pub filters: FilterDataPredicate,
+
+    /// Matches all buckets if omitted.
+    #[serde(default = "default_requested_buckets")]
+    pub requested_buckets: RequestedBuckets<PpaBucketKey>,
+
+    /// Handling of out-of-range histogram indices. Rejects them if omitted.
+    #[serde(default)]
+    pub bucket_policy: BucketPolicy,
+}
+
+fn default_filter_data_predicate() -> FilterDataPredicate {
+    FilterDataPredicate::Any
+}
+
+fn default_requested_buckets() -> RequestedBuckets<PpaBucketKey> {
+    RequestedBuckets::AllBuckets
+}
+
+impl<U: Uri> TryFrom<PpaHistogramRequestSpec<U>> for PpaHistogramRequest<U> {
+    type Error = PdsError;
+
+    fn try_from(spec: PpaHistogramRequestSpec<U>) -> Result<Self, PdsError> {
+        let relevant_event_selector = PpaRelevantEventSelector {
+            report_request_uris: spec.report_request_uris,
+            is_matching_event: spec.filters,
+            requested_buckets: spec.requested_buckets,
+            lookback: None,
+        };
+        let request = Self::from_config(&spec.config, relevant_event_selector)?;
+        Ok(request.with_bucket_policy(spec.bucket_policy))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum AttributionLogic {
+    /// Attribute all the value to the most recent relevant event, across all
+    /// epochs.
     LastTouch,
+
+    /// Split the value evenly across the requested epochs, and attribute the
+    /// share of each epoch to its most recent relevant event. Each epoch only
+    /// pays for its own share in multi-epoch requests.
+    EpochLastTouch,
+
+    /// Attribute all the value to the relevant event with the highest
+    /// priority across all epochs, as ARA does, and break ties by recency
+    /// like `LastTouch`. Sensitivity is the same as `LastTouch`.
+    PriorityThenLastTouch,
 }
 
 impl<U: Uri> RelevantEventSelector for PpaRelevantEventSelector<U> {
@@ -111,15 +419,16 @@ impl<U: Uri> RelevantEventSelector for PpaRelevantEventSelector<U> {
             .source_uris
             .contains(&event.uris.source_uri);
 
-        // Condition 2: Every querier URI from the report must be in the event’s
-        // querier URIs.
-        // TODO(https://github.com/columbia/pdslib/issues/71): modify this for cross-report
-        // loss optimization, where one querier is authorized but not others?
+        // Condition 2: At least one querier URI from the report must be in the
+        // event’s querier URIs. For requests with several queriers, the PDS
+        // core and the cross-report API drop the events that a querier can't
+        // read from its report, see
+        // https://github.com/columbia/pdslib/issues/71.
         let querier_match = self
             .report_request_uris
             .querier_uris
             .iter()
-            .all(|uri| event.uris.querier_uris.contains(uri));
+            .any(|uri| event.uris.querier_uris.contains(uri));
 
         // Condition 3: The report’s trigger URI should be allowed by the event
         // trigger URIs.
@@ -128,162 +437,401 @@ impl<U: Uri> RelevantEventSelector for PpaRelevantEventSelector<U> {
             .trigger_uris
             .contains(&self.report_request_uris.trigger_uri);
 
+        // Condition 4: The event is in the lookback window, if any.
+        let lookback_match = self
+            .lookback
+            .is_none_or(|lookback| lookback.contains(event.timestamp));
+
         source_match
             && querier_match
             && trigger_match
-            && (self.is_matching_event)(event.filter_data)
+            && lookback_match
+            && self.is_matching_event.matches(event.filter_data)
     }
 }
 
-#[derive(Debug)]
+/// Serializable, e.g. to persist cross-report attribution objects, unless
+/// its selector has a `FilterDataPredicate::Custom` predicate.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PpaHistogramRequest<U: Uri = String> {
-    start_epoch: PpaEpochId,
-    end_epoch: PpaEpochId,
+    epochs: EpochSelection,
     /// Conversion value that is spread across events
     attributable_value: f64,
+    value_policy: Option<ValuePolicy>,
     laplace_noise_scale: f64,
-    histogram_size: u64,
+    bucket_mapper: BucketMapper,
     relevant_event_selector: PpaRelevantEventSelector<U>,
     logic: AttributionLogic,
+    idempotency_key: Option<String>,
+    trigger_dedup: Option<TriggerDedup>,
+    event_sampling: Option<EventSampling>,
 }
 
 impl<U: Uri> PpaHistogramRequest<U> {
     /// Constructs a new `PpaHistogramRequest` with PPA-style parameters.
+    /// Deprecated: a full `PpaHistogramConfig` is easy to get wrong, e.g. by
+    /// leaving `start_epoch` and `end_epoch` next to `epochs`. Use
+    /// `PpaHistogramRequestBuilder`, which sets the same parameters stage by
+    /// stage with more descriptive errors, or `PpaHistogramRequestSpec` for
+    /// requests coming from JSON.
+    #[deprecated(note = "use `PpaHistogramRequestBuilder` instead")]
+    pub fn new(
+        config: &PpaHistogramConfig,
+        relevant_event_selector: PpaRelevantEventSelector<U>,
+    ) -> Result<Self, PdsError> {
+        Self::from_config(config, relevant_event_selector)
+    }
+
+    /// Constructs a request from PPA-style parameters.
     /// `relevant_event_selector` are known as `filters` in the PPA spec, but
     /// this is an overloaded term.
     /// Takes sensitivity as an input to reverse-engineer the attributable
     /// value.
-    pub fn new(
+    pub(crate) fn from_config(
         config: &PpaHistogramConfig,
-        relevant_event_selector: PpaRelevantEventSelector<U>,
-    ) -> Result<Self> {
+        mut relevant_event_selector: PpaRelevantEventSelector<U>,
+    ) -> Result<Self, PdsError> {
         if config.requested_epsilon <= 0.0 {
-            bail!("epsilon scale must be > 0");
+            return Err(PdsError::InvalidRequest(
+                "epsilon scale must be > 0".into(),
+            ));
         }
         if config.attributable_value < 0.0
             || config.max_attributable_value < 0.0
         {
-            bail!("sensitivity values must be >= 0");
+            return Err(PdsError::InvalidRequest(
+                "sensitivity values must be >= 0".into(),
+            ));
         }
-        if config.histogram_size == 0 {
-            bail!("histogram_size must be greater than 0");
+        let bucket_mapper =
+            BucketMapper::new(config.histogram_size, BucketPolicy::Reject)?;
+        relevant_event_selector.is_matching_event.validate()?;
+        if let Some(value_policy) = &config.value_policy {
+            value_policy.validate()?;
         }
+        if config.lookback.is_some() {
+            relevant_event_selector.lookback = config.lookback;
+        }
+        // The noise scale is computed from the quantized epsilon, so that
+        // the budget deducted for the request matches its noise.
+        let requested_epsilon = match &config.epsilon_grid {
+            Some(epsilon_grid) => {
+                epsilon_grid.validate()?;
+                epsilon_grid.quantize(config.requested_epsilon)?
+            }
+            None => config.requested_epsilon,
+        };
 
         // Sensitivity for a histogram query with multiple bins, where all
         // reports have the same attributable value and a device-epoch
         // participates in at most one report. Reverse of
         // `report_global_sensitivity`
-        let query_global_sensitivity = if config.end_epoch == config.start_epoch
+        let epochs = match &config.epochs {
+            Some(epochs) => {
+                epochs.validate()?;
+                epochs.clone()
+            }
+            None => EpochSelection::Range {
+                start: config.start_epoch,
+                end: config.end_epoch,
+            },
+        };
+        // Duration windows also select the events by timestamp. Their number
+        // of epochs is only known on the device, so they are accounted for
+        // as multi-epoch windows below.
+        if let EpochSelection::Duration {
+            trigger_time,
+            duration,
+        } = epochs
         {
-            config.max_attributable_value
+            relevant_event_selector.lookback.get_or_insert(Lookback {
+                trigger_time,
+                duration,
+            });
+        }
+        // The clamped maximum value bounds the value of any report.
+        let max_attributable_value = match &config.value_policy {
+            Some(value_policy) => {
+                value_policy.apply(config.max_attributable_value)
+            }
+            None => config.max_attributable_value,
+        };
+        let query_global_sensitivity = if epochs.epoch_ids().len() == 1 {
+            max_attributable_value
         } else {
-            2.0 * config.max_attributable_value
+            2.0 * max_attributable_value
         };
-        let laplace_noise_scale =
-            query_global_sensitivity / config.requested_epsilon;
+        let laplace_noise_scale = query_global_sensitivity / requested_epsilon;
 
         Ok(Self {
-            start_epoch: config.start_epoch,
-            end_epoch: config.end_epoch,
+            epochs,
             attributable_value: config.attributable_value,
+            value_policy: config.value_policy.clone(),
             laplace_noise_scale,
-            histogram_size: config.histogram_size,
+            bucket_mapper,
             relevant_event_selector,
             logic: AttributionLogic::LastTouch,
+            idempotency_key: None,
+            trigger_dedup: None,
+            event_sampling: None,
         })
     }
 
+    /// Constructs a request attributing stored conversions, possibly long
+    /// after they happened. The report attributes the sum of the values of
+    /// `triggers`, each going through the value policy of `config` on its
+    /// own, and `config.attributable_value` is ignored. The maximum
+    /// attributable value of `config` bounds each trigger, so the noise scale
+    /// and the loss are those of a conversion worth the sum of the maximum
+    /// values, and aggregating triggers in one report is accounted for
+    /// correctly.
+    ///
+    /// Triggers must come from the trigger URI of the request, accept all its
+    /// queriers, and not precede the requested epochs.
+    pub fn for_triggers(
+        config: &PpaHistogramConfig,
+        relevant_event_selector: PpaRelevantEventSelector<U>,
+        triggers: &[TriggerEvent<U>],
+    ) -> Result<Self, PdsError> {
+        if triggers.is_empty() {
+            return Err(PdsError::InvalidRequest(
+                "deferred attribution needs at least one trigger".into(),
+            ));
+        }
+        let uris = &relevant_event_selector.report_request_uris;
+        let last_epoch = match &config.epochs {
+            Some(epochs) => epochs.epoch_ids().into_iter().max(),
+            None => Some(config.end_epoch),
+        };
+        for trigger in triggers {
+            if trigger.trigger_uri != uris.trigger_uri
+                || !uris.querier_uris.iter().all(|querier_uri| {
+                    trigger.querier_uris.contains(querier_uri)
+                })
+            {
+                return Err(PdsError::InvalidRequest(format!(
+                    "trigger {} doesn't match the URIs of the request",
+                    trigger.id
+                )));
+            }
+            if last_epoch.is_some_and(|epoch| epoch > trigger.epoch_number) {
+                return Err(PdsError::InvalidEpochWindow(format!(
+                    "trigger {} happened in epoch {}, before the requested epochs",
+                    trigger.id, trigger.epoch_number
+                )));
+            }
+        }
+
+        // Values are clamped per trigger, before they are summed.
+        if let Some(value_policy) = &config.value_policy {
+            value_policy.validate()?;
+        }
+        let apply_policy = |value| match &config.value_policy {
+            Some(value_policy) => value_policy.apply(value),
+            None => value,
+        };
+        let aggregate_config = PpaHistogramConfig {
+            attributable_value: triggers
+                .iter()
+                .map(|trigger| apply_policy(trigger.value))
+                .sum(),
+            max_attributable_value: triggers.len() as f64
+                * apply_policy(config.max_attributable_value),
+            value_policy: None,
+            ..config.clone()
+        };
+        Self::from_config(&aggregate_config, relevant_event_selector)
+    }
+
     /// Constructs a new `PpaHistogramRequest` with direct Laplace noise scale.
     pub fn new_direct(
         config: DirectPpaHistogramConfig,
         relevant_event_selector: PpaRelevantEventSelector<U>,
-    ) -> Result<Self> {
+    ) -> Result<Self, PdsError> {
         if config.attributable_value <= 0.0 {
-            bail!("attributable_value must be > 0");
+            return Err(PdsError::InvalidRequest(
+                "attributable_value must be > 0".into(),
+            ));
         }
         if config.laplace_noise_scale <= 0.0 {
-            bail!("laplace_noise_scale must be > 0");
-        }
-        if config.histogram_size == 0 {
-            bail!("histogram_size must be greater than 0");
+            return Err(PdsError::InvalidRequest(
+                "laplace_noise_scale must be > 0".into(),
+            ));
         }
+        let bucket_mapper =
+            BucketMapper::new(config.histogram_size, BucketPolicy::Reject)?;
+        relevant_event_selector.is_matching_event.validate()?;
         Ok(Self {
-            start_epoch: config.start_epoch,
-            end_epoch: config.end_epoch,
+            epochs: EpochSelection::Range {
+                start: config.start_epoch,
+                end: config.end_epoch,
+            },
             attributable_value: config.attributable_value,
+            value_policy: None,
             laplace_noise_scale: config.laplace_noise_scale,
-            histogram_size: config.histogram_size,
+            bucket_mapper,
             relevant_event_selector,
             logic: AttributionLogic::LastTouch,
+            idempotency_key: None,
+            trigger_dedup: None,
+            event_sampling: None,
         })
     }
+
+    /// Number of buckets in the histogram.
+    pub fn histogram_size(&self) -> u64 {
+        self.bucket_mapper.histogram_size()
+    }
+
+    /// Sets the handling of out-of-range histogram indices, `Reject` by
+    /// default, or coarsens a larger key domain into the histogram.
+    pub fn with_bucket_policy(mut self, policy: BucketPolicy) -> Self {
+        self.bucket_mapper = self.bucket_mapper.with_policy(policy);
+        self
+    }
+
+    /// Sets the attribution logic, `LastTouch` by default.
+    pub fn with_attribution_logic(mut self, logic: AttributionLogic) -> Self {
+        self.logic = logic;
+        self
+    }
+
+    /// Sets the idempotency key, so that retries of this request get the same
+    /// report without being charged again.
+    pub fn with_idempotency_key(mut self, key: impl Into<String>) -> Self {
+        self.idempotency_key = Some(key.into());
+        self
+    }
+
+    /// Sets the deduplication key of the trigger, so that duplicates within
+    /// `window` seconds are not charged again.
+    pub fn with_trigger_dedup(
+        mut self,
+        key: impl Into<String>,
+        window: u64,
+    ) -> Self {
+        self.trigger_dedup = Some(TriggerDedup {
+            key: key.into(),
+            window,
+        });
+        self
+    }
+
+    /// Samples the relevant events with `sampling` before computing the
+    /// report, to discount the loss charged to the filters.
+    pub fn with_event_sampling(mut self, sampling: EventSampling) -> Self {
+        self.event_sampling = Some(sampling);
+        self
+    }
+
+    /// Most recent relevant event with a bucket in the domain in the epoch.
+    fn last_touch_in_epoch<'a>(
+        &self,
+        relevant_events_in_epoch: &'a [PpaEvent<U>],
+    ) -> Option<&'a PpaEvent<U>> {
+        // TODO(later): pre-sort the events by timestamp in storage
+        let mut relevant_events_in_epoch: Vec<&_> =
+            relevant_events_in_epoch.iter().collect();
+        relevant_events_in_epoch.sort_by_key(|e| e.timestamp);
+
+        // Start from the most recent event in the epoch and go backwards,
+        // skipping the events rejected by the bucket policy.
+        relevant_events_in_epoch
+            .into_iter()
+            .rev()
+            .find(|event| self.bucket_key(event).is_some())
+    }
 }
 
 impl<U: Uri> HistogramRequest for PpaHistogramRequest<U> {
     type BucketKey = PpaBucketKey;
 
-    fn bucket_key(&self, event: &Self::Event) -> Self::BucketKey {
-        // Bucket key validation.
-        if event.histogram_index >= self.histogram_size {
+    fn bucket_key(&self, event: &Self::Event) -> Option<Self::BucketKey> {
+        let bucket_key = self.bucket_mapper.map(event.histogram_index);
+        if bucket_key.is_none() {
             log::warn!(
-                "Invalid bucket key {}: exceeds histogram size {}. Event id: {}",
+                "Dropping event with id {} due to invalid bucket key {}: out of the domain of {:?} over {} buckets",
+                event.id,
                 event.histogram_index,
-                self.histogram_size,
-                event.id
+                self.bucket_mapper.policy(),
+                self.histogram_size()
             );
         }
-
-        event.histogram_index
+        bucket_key
     }
 
     fn event_values<'a>(
         &self,
         relevant_events: &'a RelevantEvents<PpaEvent<U>>,
     ) -> Vec<(&'a PpaEvent<U>, f64)> {
-        // Supporting only one attribution logic for now.
+        // Browse epochs in the order given by `epoch_ids`, most recent first.
+        let epoch_ids = unique_epochs(self.epoch_ids());
         match self.logic {
             // Attribute all the value to the most recent relevant event, across
             // all epochs
             AttributionLogic::LastTouch => {
-                // Browse epochs in the order given by `epoch_ids`, most recent
-                // first.
-                let epoch_ids = self.epoch_ids();
                 for epoch_id in epoch_ids {
                     let relevant_events_in_epoch =
                         relevant_events.for_epoch(&epoch_id);
+                    if let Some(event) =
+                        self.last_touch_in_epoch(relevant_events_in_epoch)
+                    {
+                        return vec![(event, self.attributable_value())];
+                    }
+                }
 
-                    // TODO(later): pre-sort the events by timestamp in storage
-                    let mut relevant_events_in_epoch: Vec<&_> =
-                        relevant_events_in_epoch.iter().collect();
-                    relevant_events_in_epoch.sort_by_key(|e| e.timestamp);
-
-                    // Start from the most recent event in the epoch and go
-                    // backwards.
-                    for event in relevant_events_in_epoch.iter().rev() {
-                        if event.histogram_index < self.histogram_size {
-                            // Found a relevant event with a valid bucket
-                            // key, we're done.
-                            return vec![(event, self.attributable_value)];
-                        } else {
-                            // Log error for dropped events, and keep
-                            // searching.
-                            log::error!(
-                                "Dropping event with id {} due to invalid bucket key {}",
-                                event.id,
-                                event.histogram_index
-                            );
+                // If no valid event was found, return an empty vector.
+                vec![]
+            }
+
+            // Highest priority first. Epochs are browsed from the most recent,
+            // so on ties the first event found wins.
+            AttributionLogic::PriorityThenLastTouch => {
+                let mut attributed: Option<&PpaEvent<U>> = None;
+                for epoch_id in epoch_ids {
+                    let relevant_events_in_epoch =
+                        relevant_events.for_epoch(&epoch_id);
+                    let mut candidates: Vec<&_> = relevant_events_in_epoch
+                        .iter()
+                        .filter(|event| self.bucket_key(event).is_some())
+                        .collect();
+                    candidates.sort_by_key(|e| (e.priority, e.timestamp));
+                    if let Some(event) = candidates.pop() {
+                        if attributed
+                            .is_none_or(|best| event.priority > best.priority)
+                        {
+                            attributed = Some(event);
                         }
                     }
                 }
+                attributed
+                    .map(|event| vec![(event, self.attributable_value())])
+                    .unwrap_or_default()
             }
-        }
 
-        // If no valid event was found, return an empty vector.
-        vec![]
+            // Attribute an equal share of the value to the most recent
+            // relevant event of each epoch
+            AttributionLogic::EpochLastTouch => {
+                let share = self.attributable_value() / epoch_ids.len() as f64;
+                epoch_ids
+                    .iter()
+                    .filter_map(|epoch_id| {
+                        self.last_touch_in_epoch(
+                            relevant_events.for_epoch(epoch_id),
+                        )
+                    })
+                    .map(|event| (event, share))
+                    .collect()
+            }
+        }
     }
 
+    /// Attributable value after the value policy, if any.
     fn attributable_value(&self) -> f64 {
-        self.attributable_value
+        match &self.value_policy {
+            Some(value_policy) => value_policy.apply(self.attributable_value),
+            None => self.attributable_value,
+        }
     }
 
     fn histogram_report_uris(&self) -> ReportRequestUris<Self::Uri> {
@@ -300,11 +848,35 @@ impl<U: Uri> EpochReportRequest for PpaHistogramRequest<U> {
     type Report = HistogramReport<PpaBucketKey>;
 
     fn epoch_ids(&self) -> Vec<Self::EpochId> {
-        (self.start_epoch..=self.end_epoch).rev().collect()
+        self.epochs.epoch_ids()
+    }
+
+    fn epoch_range(&self) -> (Self::EpochId, Self::EpochId) {
+        self.epochs.epoch_range()
+    }
+
+    fn duration_window(&self) -> Option<Lookback> {
+        match self.epochs {
+            EpochSelection::Duration {
+                trigger_time,
+                duration,
+            } => Some(Lookback {
+                trigger_time,
+                duration,
+            }),
+            _ => None,
+        }
+    }
+
+    fn with_epoch_ids(&self, epoch_ids: Vec<Self::EpochId>) -> Self {
+        Self {
+            epochs: EpochSelection::Set(epoch_ids),
+            ..self.clone()
+        }
     }
 
     fn report_global_sensitivity(&self) -> f64 {
-        if self.start_epoch == self.end_epoch {
+        if self.epochs.epoch_ids().len() == 1 {
             self.histogram_single_epoch_report_global_sensitivity()
         } else {
             self.histogram_multi_epoch_report_global_sensitivity()
@@ -319,6 +891,18 @@ impl<U: Uri> EpochReportRequest for PpaHistogramRequest<U> {
         &self.relevant_event_selector.report_request_uris
     }
 
+    fn idempotency_key(&self) -> Option<&str> {
+        self.idempotency_key.as_deref()
+    }
+
+    fn trigger_dedup(&self) -> Option<&TriggerDedup> {
+        self.trigger_dedup.as_ref()
+    }
+
+    fn event_sampling(&self) -> Option<&EventSampling> {
+        self.event_sampling.as_ref()
+    }
+
     fn compute_report(
         &self,
         relevant_events: &RelevantEvents<Self::Event>,
@@ -331,6 +915,10 @@ impl<U: Uri> EpochReportRequest for PpaHistogramRequest<U> {
         self.map_events_to_buckets(&event_values)
     }
 
+    fn is_event_in_range(&self, event: &Self::Event) -> bool {
+        self.bucket_mapper.map(event.histogram_index).is_some()
+    }
+
     fn single_epoch_individual_sensitivity(
         &self,
         report: &Self::Report,
@@ -349,6 +937,28 @@ impl<U: Uri> EpochReportRequest for PpaHistogramRequest<U> {
         )
     }
 
+    fn multi_epoch_individual_sensitivity(
+        &self,
+        epoch_relevant_events: &[Self::Event],
+        norm_type: NormType,
+    ) -> Option<f64> {
+        match self.logic {
+            AttributionLogic::LastTouch
+            | AttributionLogic::PriorityThenLastTouch => None,
+
+            // The contribution of an epoch only depends on its own events.
+            AttributionLogic::EpochLastTouch => {
+                let epoch_report = self.compute_report(
+                    &RelevantEvents::from_vec(epoch_relevant_events.to_vec()),
+                );
+                Some(self.histogram_single_epoch_individual_sensitivity(
+                    &epoch_report,
+                    norm_type,
+                ))
+            }
+        }
+    }
+
     fn noise_scale(&self) -> NoiseScale {
         NoiseScale::Laplace(self.laplace_noise_scale)
     }
@@ -370,3 +980,637 @@ pub fn filter_histogram_for_intermediary<BK: BucketKey>(
         })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use core::num::NonZeroU64;
+
+    use anyhow::Result;
+
+    use super::*;
+    use crate::{
+        events::traits::EventUris, pds::accounting::compute_epoch_loss,
+        queries::builder::PpaHistogramRequestBuilder,
+    };
+
+    #[test]
+    fn test_filter_data_predicate() -> Result<()> {
+        assert!(FilterDataPredicate::Any.matches(42));
+        assert!(FilterDataPredicate::Equals(1).matches(1));
+        assert!(!FilterDataPredicate::Equals(1).matches(2));
+
+        let in_set = FilterDataPredicate::InSet(HashSet::from_iter([1, 3]));
+        assert!(in_set.matches(3) && !in_set.matches(2));
+
+        let bitmask = FilterDataPredicate::BitmaskMatch {
+            mask: 0xff00,
+            value: 0x1200,
+        };
+        assert!(bitmask.matches(0x12ab) && !bitmask.matches(0x13ab));
+
+        let range = FilterDataPredicate::Range { start: 10, end: 20 };
+        assert!(range.matches(10) && range.matches(20) && !range.matches(21));
+
+        // Misconfigured predicates are rejected.
+        assert!(FilterDataPredicate::InSet(HashSet::new())
+            .validate()
+            .is_err());
+        assert!(FilterDataPredicate::BitmaskMatch {
+            mask: 0xf0,
+            value: 0x1
+        }
+        .validate()
+        .is_err());
+        assert!(FilterDataPredicate::Range { start: 2, end: 1 }
+            .validate()
+            .is_err());
+        bitmask.validate()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_value_policy() -> Result<()> {
+        let json = r#"{
+            "config": {
+                "start_epoch": 1,
+                "end_epoch": 1,
+                "attributable_value": 123.456,
+                "max_attributable_value": 1000.0,
+                "requested_epsilon": 1.0,
+                "histogram_size": 5,
+                "value_policy": {
+                    "min_value": 1.0,
+                    "max_value": 100.0,
+                    "scaling_factor": 0.5,
+                    "rounding": "Floor"
+                }
+            },
+            "report_request_uris": {
+                "trigger_uri": "shoes.com",
+                "source_uris": ["blog.com"],
+                "querier_uris": ["adtech.com"]
+            }
+        }"#;
+        let spec: PpaHistogramRequestSpec = serde_json::from_str(json)?;
+        let request = PpaHistogramRequest::try_from(spec)?;
+
+        // Values are clamped to 100 then scaled, and so is the sensitivity.
+        assert_eq!(request.attributable_value(), 50.0);
+        assert_eq!(request.report_global_sensitivity(), 50.0);
+        assert_eq!(request.noise_scale(), NoiseScale::Laplace(50.0));
+
+        let event = PpaEvent {
+            id: 1,
+            timestamp: 1,
+            epoch_number: 1,
+            histogram_index: 3,
+            uris: EventUris {
+                source_uri: "blog.com".to_string(),
+                trigger_uris: vec!["shoes.com".to_string()],
+                querier_uris: vec!["adtech.com".to_string()],
+            },
+            filter_data: 0,
+            priority: 0,
+        };
+        let relevant_events = RelevantEvents::from_vec(vec![event]);
+        let report = request.compute_report(&relevant_events);
+        assert_eq!(report.bin_values, HashMap::from_iter([(3, 50.0)]));
+
+        let policy = ValuePolicy {
+            min_value: 0.0,
+            max_value: 10.0,
+            scaling_factor: 3.0,
+            rounding: RoundingMode::Nearest,
+        };
+        assert_eq!(policy.apply(0.45), 1.0);
+        assert_eq!(policy.apply(-5.0), 0.0);
+        assert!(ValuePolicy {
+            min_value: 5.0,
+            ..policy.clone()
+        }
+        .validate()
+        .is_ok());
+        assert!(ValuePolicy {
+            min_value: 20.0,
+            ..policy.clone()
+        }
+        .validate()
+        .is_err());
+        assert!(ValuePolicy {
+            scaling_factor: 0.0,
+            ..policy
+        }
+        .validate()
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_non_contiguous_epochs() -> Result<()> {
+        let json = r#"{
+            "config": {
+                "start_epoch": 0,
+                "end_epoch": 0,
+                "epochs": { "Lookback": { "end": 15, "count": 3, "stride": 7 } },
+                "attributable_value": 10.0,
+                "max_attributable_value": 20.0,
+                "requested_epsilon": 1.0,
+                "histogram_size": 5
+            },
+            "report_request_uris": {
+                "trigger_uri": "shoes.com",
+                "source_uris": ["blog.com"],
+                "querier_uris": ["adtech.com"]
+            }
+        }"#;
+        let spec: PpaHistogramRequestSpec = serde_json::from_str(json)?;
+        let request = PpaHistogramRequest::try_from(spec)?;
+
+        // The selection replaces the start and end epochs, and spans
+        // multiple epochs.
+        assert_eq!(request.epoch_ids(), vec![15, 8, 1]);
+        assert_eq!(request.epoch_range(), (1, 15));
+        assert_eq!(request.noise_scale(), NoiseScale::Laplace(40.0));
+
+        // Invalid selections are rejected.
+        assert!(matches!(
+            PpaHistogramRequestBuilder::new()
+                .epochs(EpochSelection::Set(vec![])),
+            Err(PdsError::InvalidEpochWindow(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_deferred_attribution() -> Result<()> {
+        let config = PpaHistogramConfig {
+            start_epoch: 1,
+            end_epoch: 1,
+            epochs: None,
+            attributable_value: 0.0,
+            max_attributable_value: 100.0,
+            requested_epsilon: 1.0,
+            histogram_size: 5,
+            value_policy: Some(ValuePolicy {
+                min_value: 0.0,
+                max_value: 50.0,
+                scaling_factor: 1.0,
+                rounding: RoundingMode::Nearest,
+            }),
+            lookback: None,
+            epsilon_grid: None,
+        };
+        let selector = || PpaRelevantEventSelector {
+            report_request_uris: ReportRequestUris::mock(),
+            is_matching_event: FilterDataPredicate::Any,
+            requested_buckets: RequestedBuckets::AllBuckets,
+            lookback: None,
+        };
+        let uris = ReportRequestUris::mock();
+        let trigger = |id, epoch_number, value| TriggerEvent {
+            id,
+            timestamp: id,
+            epoch_number,
+            trigger_uri: uris.trigger_uri.clone(),
+            querier_uris: uris.querier_uris.clone(),
+            value,
+        };
+
+        // Each trigger is clamped to 50 before the values are summed, and
+        // the request is accounted for two conversions worth 50.
+        let triggers = [trigger(1, 1, 10.0), trigger(2, 2, 80.0)];
+        let request =
+            PpaHistogramRequest::for_triggers(&config, selector(), &triggers)?;
+        assert_eq!(request.attributable_value(), 60.0);
+        assert_eq!(request.report_global_sensitivity(), 60.0);
+        assert_eq!(request.noise_scale(), NoiseScale::Laplace(100.0));
+
+        let event = PpaEvent {
+            id: 1,
+            timestamp: 1,
+            epoch_number: 1,
+            histogram_index: 3,
+            uris: EventUris::mock(),
+            filter_data: 0,
+            priority: 0,
+        };
+        let relevant_events = RelevantEvents::from_vec(vec![event]);
+        let report = request.compute_report(&relevant_events);
+        assert_eq!(report.bin_values, HashMap::from_iter([(3, 60.0)]));
+
+        // Triggers must match the request.
+        assert!(PpaHistogramRequest::for_triggers(&config, selector(), &[])
+            .is_err());
+        let other_site = TriggerEvent {
+            trigger_uri: "hats.com".to_string(),
+            ..trigger(3, 1, 10.0)
+        };
+        assert!(PpaHistogramRequest::for_triggers(
+            &config,
+            selector(),
+            &[other_site]
+        )
+        .is_err());
+        let early = PpaHistogramConfig {
+            end_epoch: 3,
+            ..config.clone()
+        };
+        assert!(matches!(
+            PpaHistogramRequest::for_triggers(&early, selector(), &triggers),
+            Err(PdsError::InvalidEpochWindow(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_epoch_last_touch() -> Result<()> {
+        let builder = || {
+            PpaHistogramRequestBuilder::new()
+                .epoch_range(1, 2)?
+                .value(10.0, 10.0, 1.0)?
+                .uris(ReportRequestUris::mock())?
+                .selector(
+                    FilterDataPredicate::Any,
+                    5,
+                    RequestedBuckets::AllBuckets,
+                )
+        };
+        let event = |epoch_number, timestamp, histogram_index| PpaEvent {
+            id: timestamp,
+            timestamp,
+            epoch_number,
+            histogram_index,
+            uris: EventUris::mock(),
+            filter_data: 1,
+            priority: 0,
+        };
+        let epoch_2_events = vec![event(2, 3, 0), event(2, 4, 1)];
+        let mut events = epoch_2_events.clone();
+        events.push(event(1, 1, 2));
+        let relevant_events = RelevantEvents::from_vec(events);
+
+        // Last touch gives all the value to the last event, and epochs pay
+        // for the global sensitivity.
+        let request = builder()?.build();
+        let report = request.compute_report(&relevant_events);
+        assert_eq!(report.bin_values, HashMap::from_iter([(1, 10.0)]));
+        assert_eq!(
+            request.multi_epoch_individual_sensitivity(
+                &epoch_2_events,
+                NormType::L1
+            ),
+            None
+        );
+
+        // Each epoch gets half of the value, and only pays for it.
+        let request = builder()?
+            .attribution_logic(AttributionLogic::EpochLastTouch)
+            .build();
+        let report = request.compute_report(&relevant_events);
+        assert_eq!(report.bin_values, HashMap::from_iter([(1, 5.0), (2, 5.0)]));
+        assert_eq!(
+            request.multi_epoch_individual_sensitivity(
+                &epoch_2_events,
+                NormType::L1
+            ),
+            Some(5.0)
+        );
+        let epoch_loss =
+            compute_epoch_loss(&request, &epoch_2_events, &report, 2);
+        assert_eq!(epoch_loss, 5.0 / 20.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_priority_then_last_touch() -> Result<()> {
+        let event =
+            |epoch_number, timestamp, histogram_index, priority| PpaEvent {
+                id: timestamp,
+                timestamp,
+                epoch_number,
+                histogram_index,
+                uris: EventUris::mock(),
+                filter_data: 1,
+                priority,
+            };
+        let request = PpaHistogramRequestBuilder::new()
+            .epoch_range(1, 2)?
+            .value(10.0, 10.0, 1.0)?
+            .uris(ReportRequestUris::mock())?
+            .selector(
+                FilterDataPredicate::Any,
+                5,
+                RequestedBuckets::AllBuckets,
+            )?
+            .build()
+            .with_attribution_logic(AttributionLogic::PriorityThenLastTouch);
+        let report = |events| {
+            request
+                .compute_report(&RelevantEvents::from_vec(events))
+                .bin_values
+        };
+
+        // An older impression with a higher priority wins, even from an
+        // older epoch.
+        assert_eq!(
+            report(vec![event(2, 4, 0, 1), event(1, 1, 2, 5)]),
+            HashMap::from_iter([(2, 10.0)])
+        );
+
+        // Same priority: last touch.
+        assert_eq!(
+            report(vec![
+                event(2, 3, 0, 1),
+                event(2, 4, 1, 1),
+                event(1, 1, 2, 1)
+            ]),
+            HashMap::from_iter([(1, 10.0)])
+        );
+
+        // Events rejected by the bucket policy are skipped.
+        assert_eq!(
+            report(vec![event(2, 4, 0, 1), event(1, 1, 7, 5)]),
+            HashMap::from_iter([(0, 10.0)])
+        );
+
+        // Same sensitivity as last touch.
+        assert_eq!(
+            request.multi_epoch_individual_sensitivity(
+                &[event(2, 4, 0, 1)],
+                NormType::L1
+            ),
+            None
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_clone_request_with_custom_predicate() -> Result<()> {
+        let request = PpaHistogramRequestBuilder::new()
+            .epoch_range(1, 1)?
+            .value(1.0, 1.0, 1.0)?
+            .uris(ReportRequestUris::mock())?
+            .selector(
+                FilterDataPredicate::custom(|data| data != 2),
+                5,
+                RequestedBuckets::AllBuckets,
+            )?
+            .build();
+        let event = |id, filter_data| PpaEvent {
+            id,
+            timestamp: id,
+            epoch_number: 1,
+            histogram_index: id,
+            uris: EventUris::mock(),
+            filter_data,
+            priority: 0,
+        };
+
+        // Clones share the closure, and compute the same reports.
+        let clone = request.clone();
+        let selector = clone.relevant_event_selector();
+        assert!(selector.is_relevant_event(&event(1, 1)));
+        assert!(!selector.is_relevant_event(&event(2, 2)));
+
+        let relevant_events = RelevantEvents::from_vec(vec![event(1, 1)]);
+        let report = request.compute_report(&relevant_events);
+        assert_eq!(report, clone.compute_report(&relevant_events));
+        assert_eq!(report.clone(), report);
+        assert_ne!(report, HistogramReport::default());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bucket_policy() -> Result<()> {
+        let builder = || {
+            PpaHistogramRequestBuilder::new()
+                .epoch_range(1, 1)?
+                .value(1.0, 1.0, 1.0)?
+                .uris(ReportRequestUris::mock())?
+                .selector(
+                    FilterDataPredicate::Any,
+                    5,
+                    RequestedBuckets::AllBuckets,
+                )
+        };
+        let event = |timestamp, histogram_index| PpaEvent {
+            id: timestamp,
+            timestamp,
+            epoch_number: 1,
+            histogram_index,
+            uris: EventUris::mock(),
+            filter_data: 1,
+            priority: 0,
+        };
+        let relevant_events =
+            RelevantEvents::from_vec(vec![event(1, 3), event(2, 7)]);
+
+        // Out-of-range events are skipped by default, so an older event gets
+        // the value.
+        let request = builder()?.build();
+        let report = request.compute_report(&relevant_events);
+        assert_eq!(report.bin_values, HashMap::from_iter([(3, 1.0)]));
+
+        let request = builder()?.bucket_policy(BucketPolicy::Modulo).build();
+        let report = request.compute_report(&relevant_events);
+        assert_eq!(report.bin_values, HashMap::from_iter([(2, 1.0)]));
+
+        // Coarsened keys are merged into the histogram, and keys outside the
+        // key domain are skipped.
+        let coarsen = |key_domain| BucketPolicy::Coarsen {
+            key_domain: NonZeroU64::new(key_domain).unwrap(),
+        };
+        let request = builder()?.bucket_policy(coarsen(10)).build();
+        let report = request.compute_report(&relevant_events);
+        assert_eq!(report.bin_values, HashMap::from_iter([(3, 1.0)]));
+        assert!(request.is_event_in_range(&event(1, 9)));
+
+        let request = builder()?.bucket_policy(coarsen(5)).build();
+        let report = request.compute_report(&relevant_events);
+        assert_eq!(report.bin_values, HashMap::from_iter([(3, 1.0)]));
+        assert!(!request.is_event_in_range(&event(2, 7)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lookback_window() -> Result<()> {
+        let request = |lookback: Option<Lookback>| {
+            let mut builder = PpaHistogramRequestBuilder::new()
+                .epoch_range(1, 2)?
+                .value(1.0, 1.0, 1.0)?;
+            if let Some(lookback) = lookback {
+                builder = builder.lookback(lookback);
+            }
+            builder
+                .uris(ReportRequestUris::mock())?
+                .selector(
+                    FilterDataPredicate::Any,
+                    5,
+                    RequestedBuckets::AllBuckets,
+                )
+                .map(|builder| builder.build())
+        };
+        let event = |timestamp, epoch_number, histogram_index| PpaEvent {
+            id: timestamp,
+            timestamp,
+            epoch_number,
+            histogram_index,
+            uris: EventUris::mock(),
+            filter_data: 1,
+            priority: 0,
+        };
+
+        // The window covers the end of epoch 1 and all of epoch 2.
+        let lookback = Lookback {
+            trigger_time: 200,
+            duration: 150,
+        };
+        let windowed = request(Some(lookback))?;
+        let unbounded = request(None)?;
+        let selector = windowed.relevant_event_selector();
+        assert!(!selector.is_relevant_event(&event(40, 1, 1)));
+        assert!(selector.is_relevant_event(&event(50, 1, 1)));
+        assert!(selector.is_relevant_event(&event(200, 2, 1)));
+        assert!(!selector.is_relevant_event(&event(201, 2, 1)));
+
+        // Events filtered out by the window are not attributed.
+        let relevant_events = RelevantEvents::from_vec(
+            [event(40, 1, 1), event(60, 1, 2)]
+                .into_iter()
+                .filter(|event| selector.is_relevant_event(event))
+                .collect(),
+        );
+        let report = windowed.compute_report(&relevant_events);
+        assert_eq!(report.bin_values, HashMap::from_iter([(2, 1.0)]));
+
+        // Partial-epoch windows don't change sensitivity accounting.
+        assert_eq!(
+            windowed.report_global_sensitivity(),
+            unbounded.report_global_sensitivity()
+        );
+        assert_eq!(windowed.noise_scale(), unbounded.noise_scale());
+        assert_eq!(windowed.epoch_ids(), unbounded.epoch_ids());
+        assert_eq!(
+            windowed.single_epoch_individual_sensitivity(&report, NormType::L1),
+            unbounded
+                .single_epoch_individual_sensitivity(&report, NormType::L1)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_epsilon_grid() -> Result<()> {
+        let request = |requested_epsilon, epsilon_grid: Option<EpsilonGrid>| {
+            let mut builder = PpaHistogramRequestBuilder::new()
+                .epoch_range(1, 1)?
+                .value(1.0, 2.0, requested_epsilon)?;
+            if let Some(epsilon_grid) = epsilon_grid {
+                builder = builder.epsilon_grid(epsilon_grid)?;
+            }
+            builder
+                .uris(ReportRequestUris::mock())?
+                .selector(
+                    FilterDataPredicate::Any,
+                    5,
+                    RequestedBuckets::AllBuckets,
+                )
+                .map(|builder| builder.build())
+        };
+        let grid = EpsilonGrid {
+            allowed_epsilons: vec![1.0, 0.25, 0.5],
+        };
+
+        // Rounded down to the nearest allowed epsilon, and the noise scale is
+        // the same as for a request with that epsilon.
+        let quantized = request(0.9, Some(grid.clone()))?;
+        let expected = request(0.5, None)?;
+        assert_eq!(quantized.noise_scale(), expected.noise_scale());
+        assert_eq!(quantized.noise_scale(), NoiseScale::Laplace(4.0));
+
+        // Allowed epsilons and epsilons above the grid.
+        assert_eq!(grid.quantize(0.25)?, 0.25);
+        assert_eq!(grid.quantize(3.0)?, 1.0);
+
+        // Epsilons below the grid and invalid grids are rejected.
+        assert!(request(0.1, Some(grid.clone())).is_err());
+        let empty_grid = EpsilonGrid {
+            allowed_epsilons: vec![],
+        };
+        assert!(request(1.0, Some(empty_grid)).is_err());
+        assert!(EpsilonGrid {
+            allowed_epsilons: vec![0.5, f64::INFINITY],
+        }
+        .validate()
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_request_spec_from_json() -> Result<()> {
+        let json = r#"{
+            "config": {
+                "start_epoch": 1,
+                "end_epoch": 2,
+                "attributable_value": 10.0,
+                "max_attributable_value": 20.0,
+                "requested_epsilon": 1.0,
+                "histogram_size": 5
+            },
+            "report_request_uris": {
+                "trigger_uri": "shoes.com",
+                "source_uris": ["blog.com"],
+                "querier_uris": ["adtech.com"]
+            },
+            "filters": { "InSet": [1, 2] },
+            "requested_buckets": { "SpecificBuckets": [3] },
+            "bucket_policy": "Hash"
+        }"#;
+        let spec: PpaHistogramRequestSpec = serde_json::from_str(json)?;
+        let request = PpaHistogramRequest::try_from(spec)?;
+
+        assert_eq!(request.epoch_ids(), vec![2, 1]);
+        assert_eq!(request.attributable_value(), 10.0);
+        assert_eq!(request.noise_scale(), NoiseScale::Laplace(40.0));
+        let selector = request.relevant_event_selector();
+        assert!(selector.is_matching_event.matches(2));
+        assert!(!selector.is_matching_event.matches(3));
+        assert_eq!(selector.requested_buckets, vec![3].into());
+        assert_eq!(request.bucket_mapper.policy(), BucketPolicy::Hash);
+
+        // Filters and buckets are optional, invalid configs are rejected.
+        let json = r#"{
+            "config": {
+                "start_epoch": 1,
+                "end_epoch": 1,
+                "attributable_value": 10.0,
+                "max_attributable_value": 20.0,
+                "requested_epsilon": 0.0,
+                "histogram_size": 5
+            },
+            "report_request_uris": {
+                "trigger_uri": "shoes.com",
+                "source_uris": [],
+                "querier_uris": []
+            }
+        }"#;
+        let spec: PpaHistogramRequestSpec = serde_json::from_str(json)?;
+        assert!(matches!(spec.filters, FilterDataPredicate::Any));
+        assert_eq!(spec.bucket_policy, BucketPolicy::Reject);
+        assert!(matches!(
+            PpaHistogramRequest::try_from(spec),
+            Err(PdsError::InvalidRequest(_))
+        ));
+
+        Ok(())
+    }
+}
@@ -10,7 +10,9 @@ use crate::{
     queries::traits::{EpochReportRequest, Report, ReportRequestUris},
 };
 
-#[derive(Debug)]
+/// Prefer `SimpleLastTouchHistogramRequestBuilder` over constructing the
+/// struct directly, since the builder validates the parameters.
+#[derive(Debug, Clone)]
 pub struct SimpleLastTouchHistogramRequest {
     pub epoch_start: u64,
     pub epoch_end: u64,
@@ -41,7 +43,7 @@ impl std::fmt::Debug for SimpleRelevantEventSelector {
     }
 }
 
-#[derive(Debug, Clone, Default, Serialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
 pub struct SimpleLastTouchHistogramReport {
     // Value attributed to one bin or None if no attribution
     pub bin_value: Option<(
@@ -69,6 +71,10 @@ impl EpochReportRequest for SimpleLastTouchHistogramRequest {
         range.rev().collect()
     }
 
+    fn epoch_range(&self) -> (Self::EpochId, Self::EpochId) {
+        (self.epoch_start, self.epoch_end)
+    }
+
     fn relevant_event_selector(&self) -> &Self::RelevantEventSelector {
         &self.is_relevant_event
     }
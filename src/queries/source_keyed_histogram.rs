@@ -0,0 +1,237 @@
+use alloc::{string::String, vec::Vec};
+
+use crate::{
+    budget::pure_dp_filter::PureDPBudget,
+    events::{
+        ppa_event::PpaEvent, relevant_events::RelevantEvents, traits::Uri,
+    },
+    mechanisms::{NoiseScale, NormType},
+    queries::{
+        histogram::{BucketKey, HistogramReport, HistogramRequest},
+        ppa_histogram::{
+            Lookback, PpaBucketKey, PpaEpochId, PpaHistogramRequest,
+            PpaRelevantEventSelector,
+        },
+        traits::{EpochReportRequest, ReportRequestUris},
+    },
+    util::hashmap::HashMap,
+};
+
+/// Bucket key of a histogram split by source: (source URI, PPA bucket).
+pub type SourceKeyedBucketKey<U> = (U, PpaBucketKey);
+
+impl<U: Uri> BucketKey for SourceKeyedBucketKey<U> {}
+
+/// PPA histogram request whose buckets are split by source URI, to give
+/// queriers a per-publisher breakdown. Attribution is the same as the wrapped
+/// `PpaHistogramRequest`, so the value of a report is still capped by the
+/// attributable value, but each source is only charged on its `SourceQuota`
+/// filter for the value attributed to its own buckets.
+#[derive(Debug, Clone)]
+pub struct SourceKeyedHistogramRequest<U: Uri = String> {
+    request: PpaHistogramRequest<U>,
+}
+
+impl<U: Uri> SourceKeyedHistogramRequest<U> {
+    pub fn new(request: PpaHistogramRequest<U>) -> Self {
+        Self { request }
+    }
+}
+
+impl<U: Uri> HistogramRequest for SourceKeyedHistogramRequest<U> {
+    type BucketKey = SourceKeyedBucketKey<U>;
+
+    fn bucket_key(&self, event: &Self::Event) -> Option<Self::BucketKey> {
+        let bucket_key = self.request.bucket_key(event)?;
+        Some((event.uris.source_uri.clone(), bucket_key))
+    }
+
+    fn event_values<'a>(
+        &self,
+        relevant_events: &'a RelevantEvents<PpaEvent<U>>,
+    ) -> Vec<(&'a PpaEvent<U>, f64)> {
+        self.request.event_values(relevant_events)
+    }
+
+    fn attributable_value(&self) -> f64 {
+        self.request.attributable_value()
+    }
+
+    fn histogram_report_uris(&self) -> ReportRequestUris<Self::Uri> {
+        self.request.histogram_report_uris()
+    }
+}
+
+impl<U: Uri> EpochReportRequest for SourceKeyedHistogramRequest<U> {
+    type Uri = U;
+    type EpochId = PpaEpochId;
+    type Event = PpaEvent<U>;
+    type RelevantEventSelector = PpaRelevantEventSelector<U>;
+    type PrivacyBudget = PureDPBudget;
+    type Report = HistogramReport<SourceKeyedBucketKey<U>>;
+
+    fn epoch_ids(&self) -> Vec<Self::EpochId> {
+        self.request.epoch_ids()
+    }
+
+    fn epoch_range(&self) -> (Self::EpochId, Self::EpochId) {
+        self.request.epoch_range()
+    }
+
+    fn duration_window(&self) -> Option<Lookback> {
+        self.request.duration_window()
+    }
+
+    fn with_epoch_ids(&self, epoch_ids: Vec<Self::EpochId>) -> Self {
+        Self {
+            request: self.request.with_epoch_ids(epoch_ids),
+        }
+    }
+
+    fn report_global_sensitivity(&self) -> f64 {
+        self.request.report_global_sensitivity()
+    }
+
+    fn relevant_event_selector(&self) -> &Self::RelevantEventSelector {
+        self.request.relevant_event_selector()
+    }
+
+    fn report_uris(&self) -> &ReportRequestUris<Self::Uri> {
+        self.request.report_uris()
+    }
+
+    fn compute_report(
+        &self,
+        relevant_events: &RelevantEvents<Self::Event>,
+    ) -> Self::Report {
+        let event_values = self.event_values(relevant_events);
+        let event_values: HashMap<_, _> = event_values
+            .into_iter()
+            .map(|(e, v)| (e.clone(), v))
+            .collect();
+        self.map_events_to_buckets(&event_values)
+    }
+
+    fn single_epoch_individual_sensitivity(
+        &self,
+        report: &Self::Report,
+        norm_type: NormType,
+    ) -> f64 {
+        self.histogram_single_epoch_individual_sensitivity(report, norm_type)
+    }
+
+    fn single_epoch_source_individual_sensitivity(
+        &self,
+        report: &Self::Report,
+        norm_type: NormType,
+    ) -> f64 {
+        self.histogram_single_epoch_source_individual_sensitivity(
+            report, norm_type,
+        )
+    }
+
+    /// Only the buckets of `source` hold value attributed to its events, so
+    /// its `SourceQuota` is only charged for them. Device-epoch filters are
+    /// still charged for the whole report.
+    fn single_epoch_split_source_sensitivity(
+        &self,
+        report: &Self::Report,
+        source: &Self::Uri,
+        norm_type: NormType,
+    ) -> Option<f64> {
+        let source_report = HistogramReport {
+            bin_values: report
+                .bin_values
+                .iter()
+                .filter(|((bin_source, _), _)| bin_source == source)
+                .map(|(bin, value)| (bin.clone(), *value))
+                .collect(),
+        };
+        Some(self.histogram_single_epoch_individual_sensitivity(
+            &source_report,
+            norm_type,
+        ))
+    }
+
+    fn noise_scale(&self) -> NoiseScale {
+        self.request.noise_scale()
+    }
+}
+
+#[cfg(all(test, feature = "experimental"))]
+mod tests {
+    use super::*;
+    use crate::{
+        budget::traits::FilterStorage,
+        events::traits::EventUris,
+        pds::{
+            aliases::{PpaEventStorage, PpaFilterStorage, SourceKeyedPds},
+            quotas::{FilterId, StaticCapacities},
+        },
+        queries::{
+            builder::PpaHistogramRequestBuilder,
+            ppa_histogram::{FilterDataPredicate, RequestedBuckets},
+        },
+    };
+
+    #[test]
+    fn test_source_keyed_report_and_quotas() -> Result<(), anyhow::Error> {
+        let capacities = StaticCapacities::mock();
+        let filters = PpaFilterStorage::new(capacities)?;
+        let mut pds: SourceKeyedPds =
+            SourceKeyedPds::new(filters, PpaEventStorage::new());
+
+        let uris = ReportRequestUris::mock();
+        for (id, source_uri) in [(1, "blog.com"), (2, "news.com")] {
+            pds.register_event(PpaEvent {
+                id,
+                timestamp: id * 100,
+                epoch_number: 1,
+                histogram_index: id,
+                uris: EventUris {
+                    source_uri: source_uri.to_string(),
+                    trigger_uris: vec![uris.trigger_uri.clone()],
+                    querier_uris: uris.querier_uris.clone(),
+                },
+                filter_data: 0,
+                priority: 0,
+            })?;
+        }
+
+        // Single epoch with a loss of 10 / 10 for the attributed source.
+        let request = SourceKeyedHistogramRequest::new(
+            PpaHistogramRequestBuilder::new()
+                .epoch_range(1, 1)?
+                .value(10.0, 10.0, 1.0)?
+                .uris(ReportRequestUris {
+                    source_uris: vec!["blog.com".into(), "news.com".into()],
+                    ..uris
+                })?
+                .selector(
+                    FilterDataPredicate::Any,
+                    5,
+                    RequestedBuckets::AllBuckets,
+                )?
+                .build(),
+        );
+
+        // The most recent event wins, and its bucket is keyed by its source.
+        let report = pds.compute_report(&request)?;
+        assert_eq!(
+            report.filtered_report.bin_values,
+            HashMap::from_iter([(("news.com".to_string(), 2), 10.0)])
+        );
+
+        // Only the attributed source is charged on its quota, instead of
+        // the global sensitivity for each source.
+        let filters = &mut pds.core.filter_storage;
+        let remaining = |filters: &mut PpaFilterStorage, source: &str| {
+            filters.remaining_budget(&FilterId::SourceQuota(1, source.into()))
+        };
+        assert_eq!(remaining(filters, "news.com")?, 3.0);
+        assert_eq!(remaining(filters, "blog.com")?, 4.0);
+        assert_eq!(filters.remaining_budget(&FilterId::Global(1))?, 19.0);
+
+        Ok(())
+    }
+}
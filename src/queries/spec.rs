@@ -0,0 +1,362 @@
+//! Declarative report specs. A `ReportSpec` describes a request as plain
+//! data, epochs, buckets, attribution logic, value caps and noise, so that
+//! browsers and apps can send requests as JSON instead of calling the Rust
+//! builders. `ReportSpec::compile` validates the spec with the same checks
+//! as `PpaHistogramRequestBuilder`, and errors are prefixed with the faulty
+//! field of the spec, e.g. `buckets: requested bucket 8 is out of the
+//! histogram domain of size 8`.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::PdsError,
+    events::traits::Uri,
+    pds::{accounting::EventSampling, dedup::TriggerDedup},
+    queries::{
+        builder::PpaHistogramRequestBuilder,
+        epoch_selection::EpochSelection,
+        hierarchical_histogram::HierarchicalHistogramRequest,
+        histogram::BucketPolicy,
+        ppa_histogram::{
+            AttributionLogic, EpsilonGrid, FilterDataPredicate, Lookback,
+            PpaBucketKey, PpaHistogramRequest, RequestedBuckets, ValuePolicy,
+        },
+        source_keyed_histogram::SourceKeyedHistogramRequest,
+        threshold::ThresholdRequest,
+        traits::ReportRequestUris,
+    },
+};
+
+/// Declarative description of a report request, compiled into a request with
+/// `compile`. Optional fields can be omitted from the JSON.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReportSpec<U: Uri = String> {
+    /// Epochs to attribute to.
+    pub epochs: EpochSelection,
+
+    /// Only attributes to the events in the window, within the epochs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lookback: Option<Lookback>,
+
+    pub uris: ReportRequestUris<U>,
+
+    /// Known as `filters` in the PPA spec. Matches all events if omitted.
+    #[serde(default = "default_filters")]
+    pub filters: FilterDataPredicate,
+
+    pub buckets: BucketSpec,
+
+    /// `LastTouch` if omitted.
+    #[serde(default = "default_attribution")]
+    pub attribution: AttributionLogic,
+
+    pub value: ValueSpec,
+    pub noise: NoiseSpec,
+
+    /// Kind of report, a PPA histogram if omitted.
+    #[serde(default)]
+    pub report: ReportType,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trigger_dedup: Option<TriggerDedup>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_sampling: Option<EventSampling>,
+}
+
+/// Histogram domain, and mapping of the histogram indices of the events to
+/// buckets.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BucketSpec {
+    pub histogram_size: u64,
+
+    /// Buckets to report, all of them if omitted.
+    #[serde(default = "default_requested_buckets")]
+    pub requested: RequestedBuckets<PpaBucketKey>,
+
+    /// Handling of out-of-range histogram indices, or coarsening of a larger
+    /// key domain. Rejects out-of-range indices if omitted.
+    #[serde(default)]
+    pub policy: BucketPolicy,
+}
+
+/// Conversion value and its caps.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ValueSpec {
+    pub attributable_value: f64,
+
+    /// Maximum value across the reports of the batch, which sets the noise.
+    pub max_attributable_value: f64,
+
+    /// Clamping and scaling of the values, see `ValuePolicy`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy: Option<ValuePolicy>,
+}
+
+/// Noise of the report, as the epsilon spent on the batch.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NoiseSpec {
+    pub requested_epsilon: f64,
+
+    /// Rounds the epsilon down to the grid, see `EpsilonGrid`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub epsilon_grid: Option<EpsilonGrid>,
+}
+
+/// Kind of request the spec compiles to. All of them wrap the PPA histogram
+/// request described by the rest of the spec.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ReportType {
+    /// `PpaHistogramRequest`.
+    #[default]
+    Histogram,
+
+    /// `SourceKeyedHistogramRequest`.
+    SourceKeyedHistogram,
+
+    /// `HierarchicalHistogramRequest`.
+    HierarchicalHistogram { branching_factor: u64 },
+
+    /// `ThresholdRequest`.
+    Threshold { thresholds: Vec<u64> },
+}
+
+/// Request compiled from a `ReportSpec`, of the type given by
+/// `ReportSpec::report`.
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum CompiledRequest<U: Uri = String> {
+    Histogram(PpaHistogramRequest<U>),
+    SourceKeyedHistogram(SourceKeyedHistogramRequest<U>),
+    HierarchicalHistogram(HierarchicalHistogramRequest<U>),
+    Threshold(ThresholdRequest<U>),
+}
+
+fn default_filters() -> FilterDataPredicate {
+    FilterDataPredicate::Any
+}
+
+fn default_requested_buckets() -> RequestedBuckets<PpaBucketKey> {
+    RequestedBuckets::AllBuckets
+}
+
+fn default_attribution() -> AttributionLogic {
+    AttributionLogic::LastTouch
+}
+
+/// Prefixes validation errors with the field of the spec they come from.
+fn at<T>(field: &str, result: Result<T, PdsError>) -> Result<T, PdsError> {
+    result.map_err(|error| match error {
+        PdsError::InvalidRequest(reason) => {
+            PdsError::InvalidRequest(format!("{field}: {reason}"))
+        }
+        PdsError::InvalidEpochWindow(reason) => {
+            PdsError::InvalidEpochWindow(format!("{field}: {reason}"))
+        }
+        error => error,
+    })
+}
+
+impl<U: Uri> ReportSpec<U> {
+    /// Validates the spec and builds the request it describes.
+    pub fn compile(self) -> Result<CompiledRequest<U>, PdsError> {
+        let builder = at("epochs", {
+            PpaHistogramRequestBuilder::new().epochs(self.epochs)
+        })?;
+        let epsilon = self.noise.requested_epsilon;
+        if !epsilon.is_finite() || epsilon <= 0.0 {
+            return Err(PdsError::InvalidRequest(format!(
+                "noise: requested epsilon {epsilon} must be finite and > 0"
+            )));
+        }
+        let mut builder = at("value", {
+            builder.value(
+                self.value.attributable_value,
+                self.value.max_attributable_value,
+                self.noise.requested_epsilon,
+            )
+        })?;
+        if let Some(value_policy) = self.value.policy {
+            builder = at("value.policy", builder.value_policy(value_policy))?;
+        }
+        if let Some(epsilon_grid) = self.noise.epsilon_grid {
+            builder =
+                at("noise.epsilon_grid", builder.epsilon_grid(epsilon_grid))?;
+        }
+        if let Some(lookback) = self.lookback {
+            builder = builder.lookback(lookback);
+        }
+        let builder = at("uris", builder.uris(self.uris))?;
+        at("filters", self.filters.validate())?;
+        let mut builder = at("buckets", {
+            builder.selector(
+                self.filters,
+                self.buckets.histogram_size,
+                self.buckets.requested,
+            )
+        })?
+        .bucket_policy(self.buckets.policy)
+        .attribution_logic(self.attribution);
+        if let Some(key) = self.idempotency_key {
+            builder = builder.idempotency_key(key);
+        }
+        if let Some(dedup) = self.trigger_dedup {
+            builder = builder.trigger_dedup(dedup.key, dedup.window);
+        }
+        if let Some(sampling) = self.event_sampling {
+            at("event_sampling", EventSampling::new(sampling.rate))?;
+            builder = builder.event_sampling(sampling);
+        }
+        let request = builder.build();
+
+        match self.report {
+            ReportType::Histogram => Ok(CompiledRequest::Histogram(request)),
+            ReportType::SourceKeyedHistogram => {
+                Ok(CompiledRequest::SourceKeyedHistogram(
+                    SourceKeyedHistogramRequest::new(request),
+                ))
+            }
+            ReportType::HierarchicalHistogram { branching_factor } => {
+                let request = at("report", {
+                    HierarchicalHistogramRequest::new(request, branching_factor)
+                })?;
+                Ok(CompiledRequest::HierarchicalHistogram(request))
+            }
+            ReportType::Threshold { thresholds } => {
+                let request =
+                    at("report", ThresholdRequest::new(request, thresholds))?;
+                Ok(CompiledRequest::Threshold(request))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queries::traits::EpochReportRequest;
+
+    const SPEC: &str = r#"{
+        "epochs": { "Range": { "start": 1, "end": 3 } },
+        "uris": {
+            "trigger_uri": "shoes.com",
+            "source_uris": ["blog.com"],
+            "querier_uris": ["adtech.com"]
+        },
+        "buckets": { "histogram_size": 8, "policy": "Modulo" },
+        "value": { "attributable_value": 10.0, "max_attributable_value": 20.0 },
+        "noise": { "requested_epsilon": 1.0 }
+    }"#;
+
+    fn spec() -> Result<ReportSpec, anyhow::Error> {
+        Ok(serde_json::from_str(SPEC)?)
+    }
+
+    #[test]
+    fn test_compile_histogram_spec() -> Result<(), anyhow::Error> {
+        let CompiledRequest::Histogram(request) = spec()?.compile()? else {
+            panic!("expected a histogram request");
+        };
+        assert_eq!(request.epoch_ids(), vec![3, 2, 1]);
+        assert_eq!(request.histogram_size(), 8);
+        assert_eq!(request.report_global_sensitivity(), 20.0);
+
+        let threshold = ReportSpec {
+            report: ReportType::Threshold {
+                thresholds: vec![1, 3],
+            },
+            ..spec()?
+        };
+        let CompiledRequest::Threshold(request) = threshold.compile()? else {
+            panic!("expected a threshold request");
+        };
+        assert_eq!(request.thresholds(), &[1, 3]);
+
+        // Specs round-trip through JSON.
+        let json = serde_json::to_string(&spec()?)?;
+        let spec: ReportSpec = serde_json::from_str(&json)?;
+        assert!(matches!(spec.compile()?, CompiledRequest::Histogram(_)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_spec_validation_errors() -> Result<(), anyhow::Error> {
+        let error = |spec: ReportSpec| match spec.compile() {
+            Ok(_) => panic!("expected an invalid spec"),
+            Err(error) => error.to_string(),
+        };
+
+        let spec_with_value = |attributable_value| {
+            Ok::<_, anyhow::Error>(ReportSpec {
+                value: ValueSpec {
+                    attributable_value,
+                    max_attributable_value: 20.0,
+                    policy: None,
+                },
+                ..spec()?
+            })
+        };
+        assert!(error(spec_with_value(30.0)?).contains("value: "));
+
+        let spec_with_buckets = ReportSpec {
+            buckets: BucketSpec {
+                histogram_size: 8,
+                requested: vec![8].into(),
+                policy: BucketPolicy::Reject,
+            },
+            ..spec()?
+        };
+        assert!(error(spec_with_buckets).contains("buckets: "));
+
+        let spec_with_filters = ReportSpec {
+            filters: FilterDataPredicate::Range { start: 2, end: 1 },
+            ..spec()?
+        };
+        assert!(error(spec_with_filters).contains("filters: "));
+
+        let spec_with_report = ReportSpec {
+            report: ReportType::HierarchicalHistogram {
+                branching_factor: 1,
+            },
+            ..spec()?
+        };
+        assert!(error(spec_with_report).contains("report: "));
+
+        let spec_with_epochs = ReportSpec {
+            epochs: EpochSelection::Range { start: 3, end: 1 },
+            ..spec()?
+        };
+        assert!(matches!(
+            spec_with_epochs.compile(),
+            Err(PdsError::InvalidEpochWindow(reason)) if reason.starts_with("epochs: ")
+        ));
+
+        // Typos in optional fields are not silently ignored.
+        let typo = SPEC.replace(
+            "\"noise\"",
+            "\"attribution_logic\": \"LastTouch\", \"noise\"",
+        );
+        assert!(serde_json::from_str::<ReportSpec>(&typo).is_err());
+
+        // The sampling is seeded by the device, never by the querier.
+        let sampling = |sampling: &str| {
+            let json = SPEC.replace(
+                "\"noise\"",
+                &format!("\"event_sampling\": {sampling}, \"noise\""),
+            );
+            serde_json::from_str::<ReportSpec>(&json)
+        };
+        assert!(sampling(r#"{ "rate": 0.5 }"#)?.compile().is_ok());
+        assert!(sampling(r#"{ "rate": 0.5, "seed": 7 }"#).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,282 @@
+use alloc::{string::String, vec, vec::Vec};
+
+use crate::{
+    budget::pure_dp_filter::PureDPBudget,
+    error::PdsError,
+    events::{
+        ppa_event::PpaEvent, relevant_events::RelevantEvents, traits::Uri,
+    },
+    mechanisms::{NoiseScale, NormType},
+    queries::{
+        epoch_selection::unique_epochs,
+        histogram::{HistogramReport, HistogramRequest},
+        ppa_histogram::{
+            Lookback, PpaBucketKey, PpaEpochId, PpaHistogramRequest,
+            PpaRelevantEventSelector,
+        },
+        traits::{EpochReportRequest, ReportRequestUris},
+    },
+    util::hashmap::HashMap,
+};
+
+/// Frequency request, e.g. "was this user exposed at least k times", over the
+/// relevant events of a PPA histogram request. The device counts its relevant
+/// events in the requested epochs and compares the count with increasing
+/// thresholds `k_0 < k_1 < ...`. Bucket `i` of the report holds the
+/// attributable value if `k_i` is the highest threshold reached, and the
+/// report is null if the count is below `k_0`. With a single threshold, the
+/// report is a single bit.
+///
+/// Histogram indices of the events are ignored, only the selector, epochs,
+/// attributable value and noise of the wrapped request are used. Reports can
+/// be randomized on the device with `PpaRandomizedResponse`.
+#[derive(Debug, Clone)]
+pub struct ThresholdRequest<U: Uri = String> {
+    request: PpaHistogramRequest<U>,
+    thresholds: Vec<u64>,
+}
+
+impl<U: Uri> ThresholdRequest<U> {
+    /// Compares the number of relevant events of `request` with
+    /// `thresholds`, which must be positive and strictly increasing.
+    pub fn new(
+        request: PpaHistogramRequest<U>,
+        thresholds: Vec<u64>,
+    ) -> Result<Self, PdsError> {
+        if thresholds.is_empty() {
+            return Err(PdsError::InvalidRequest(
+                "thresholds must not be empty".into(),
+            ));
+        }
+        if thresholds[0] == 0 {
+            return Err(PdsError::InvalidRequest(
+                "thresholds must be greater than 0".into(),
+            ));
+        }
+        if thresholds.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(PdsError::InvalidRequest(
+                "thresholds must be strictly increasing".into(),
+            ));
+        }
+        Ok(Self {
+            request,
+            thresholds,
+        })
+    }
+
+    /// Single-bit request: whether there are at least `threshold` relevant
+    /// events.
+    pub fn at_least(
+        request: PpaHistogramRequest<U>,
+        threshold: u64,
+    ) -> Result<Self, PdsError> {
+        Self::new(request, vec![threshold])
+    }
+
+    pub fn thresholds(&self) -> &[u64] {
+        &self.thresholds
+    }
+
+    /// Number of buckets of the report, one per threshold.
+    pub fn n_buckets(&self) -> u64 {
+        self.thresholds.len() as u64
+    }
+
+    /// Value held by the bucket of the highest threshold reached.
+    pub fn attributable_value(&self) -> f64 {
+        self.request.attributable_value()
+    }
+
+    /// Number of relevant events in the requested epochs, counting each
+    /// epoch once.
+    pub fn count_events(
+        &self,
+        relevant_events: &RelevantEvents<PpaEvent<U>>,
+    ) -> u64 {
+        unique_epochs(self.epoch_ids())
+            .iter()
+            .map(|epoch_id| relevant_events.for_epoch(epoch_id).len() as u64)
+            .sum()
+    }
+
+    /// Bucket of the highest threshold reached by `count`, if any.
+    pub fn bucket_for_count(&self, count: u64) -> Option<PpaBucketKey> {
+        let n_reached = self
+            .thresholds
+            .iter()
+            .take_while(|&&threshold| threshold <= count)
+            .count();
+        (n_reached as u64).checked_sub(1)
+    }
+}
+
+impl<U: Uri> EpochReportRequest for ThresholdRequest<U> {
+    type Uri = U;
+    type EpochId = PpaEpochId;
+    type Event = PpaEvent<U>;
+    type RelevantEventSelector = PpaRelevantEventSelector<U>;
+    type PrivacyBudget = PureDPBudget;
+    type Report = HistogramReport<PpaBucketKey>;
+
+    fn epoch_ids(&self) -> Vec<Self::EpochId> {
+        self.request.epoch_ids()
+    }
+
+    fn epoch_range(&self) -> (Self::EpochId, Self::EpochId) {
+        self.request.epoch_range()
+    }
+
+    fn duration_window(&self) -> Option<Lookback> {
+        self.request.duration_window()
+    }
+
+    fn with_epoch_ids(&self, epoch_ids: Vec<Self::EpochId>) -> Self {
+        Self {
+            request: self.request.with_epoch_ids(epoch_ids),
+            ..self.clone()
+        }
+    }
+
+    fn relevant_event_selector(&self) -> &Self::RelevantEventSelector {
+        self.request.relevant_event_selector()
+    }
+
+    fn report_uris(&self) -> &ReportRequestUris<Self::Uri> {
+        self.request.report_uris()
+    }
+
+    fn compute_report(
+        &self,
+        relevant_events: &RelevantEvents<Self::Event>,
+    ) -> Self::Report {
+        let count = self.count_events(relevant_events);
+        let mut bin_values = HashMap::new();
+        if let Some(bucket) = self.bucket_for_count(count) {
+            bin_values.insert(bucket, self.attributable_value());
+        }
+        HistogramReport { bin_values }
+    }
+
+    fn single_epoch_individual_sensitivity(
+        &self,
+        report: &Self::Report,
+        norm_type: NormType,
+    ) -> f64 {
+        // Without the epoch's events the count is 0, so the report is null.
+        self.request
+            .histogram_single_epoch_individual_sensitivity(report, norm_type)
+    }
+
+    fn single_epoch_source_individual_sensitivity(
+        &self,
+        report: &Self::Report,
+        norm_type: NormType,
+    ) -> f64 {
+        self.single_epoch_individual_sensitivity(report, norm_type)
+    }
+
+    fn report_global_sensitivity(&self) -> f64 {
+        // Changing the events can move the value from one bucket to another,
+        // except with a single bucket, where it can only appear or vanish.
+        match self.thresholds.len() {
+            1 => self.attributable_value(),
+            _ => 2.0 * self.attributable_value(),
+        }
+    }
+
+    fn noise_scale(&self) -> NoiseScale {
+        self.request.noise_scale()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        events::traits::EventUris,
+        queries::{
+            builder::PpaHistogramRequestBuilder,
+            ppa_histogram::{FilterDataPredicate, RequestedBuckets},
+        },
+    };
+
+    fn ppa_request(start_epoch: u64) -> Result<PpaHistogramRequest, PdsError> {
+        PpaHistogramRequestBuilder::new()
+            .epoch_range(start_epoch, 2)?
+            .value(1.0, 1.0, 1.0)?
+            .uris(ReportRequestUris::mock())?
+            .selector(FilterDataPredicate::Any, 1, RequestedBuckets::AllBuckets)
+            .map(|builder| builder.build())
+    }
+
+    fn events(epochs: &[u64]) -> RelevantEvents<PpaEvent> {
+        let events = epochs
+            .iter()
+            .enumerate()
+            .map(|(i, &epoch_number)| PpaEvent {
+                id: i as u64,
+                timestamp: i as u64,
+                epoch_number,
+                // Out of the histogram domain, but still counted.
+                histogram_index: 7,
+                uris: EventUris::mock(),
+                filter_data: 0,
+                priority: 0,
+            })
+            .collect();
+        RelevantEvents::from_vec(events)
+    }
+
+    #[test]
+    fn test_threshold_report() -> Result<(), PdsError> {
+        let request = ThresholdRequest::new(ppa_request(1)?, vec![2, 4])?;
+        let report =
+            |epochs: &[u64]| request.compute_report(&events(epochs)).bin_values;
+
+        assert!(report(&[]).is_empty());
+        assert!(report(&[1]).is_empty());
+        assert_eq!(report(&[1, 2]), HashMap::from_iter([(0, 1.0)]));
+        assert_eq!(report(&[1, 2, 2]), HashMap::from_iter([(0, 1.0)]));
+        assert_eq!(report(&[1, 1, 2, 2, 2]), HashMap::from_iter([(1, 1.0)]));
+
+        // Events outside of the requested epochs are not counted.
+        assert!(report(&[2, 3]).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_threshold_sensitivity() -> Result<(), PdsError> {
+        let bit = ThresholdRequest::at_least(ppa_request(2)?, 3)?;
+        let report = bit.compute_report(&events(&[2, 2, 2]));
+        assert_eq!(report.bin_values, HashMap::from_iter([(0, 1.0)]));
+        assert_eq!(
+            bit.single_epoch_individual_sensitivity(&report, NormType::L1),
+            1.0
+        );
+        assert_eq!(bit.report_global_sensitivity(), 1.0);
+
+        // Below the threshold, the report is null and costs nothing.
+        let report = bit.compute_report(&events(&[2, 2]));
+        assert_eq!(
+            bit.single_epoch_individual_sensitivity(&report, NormType::L1),
+            0.0
+        );
+
+        let histogram = ThresholdRequest::new(ppa_request(1)?, vec![1, 3])?;
+        assert_eq!(histogram.report_global_sensitivity(), 2.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_thresholds() -> Result<(), PdsError> {
+        for thresholds in [vec![], vec![0, 1], vec![2, 2], vec![3, 1]] {
+            assert!(matches!(
+                ThresholdRequest::new(ppa_request(1)?, thresholds),
+                Err(PdsError::InvalidRequest(_))
+            ));
+        }
+        Ok(())
+    }
+}
@@ -1,4 +1,7 @@
-use std::fmt::Debug;
+use alloc::vec::Vec;
+use core::fmt::Debug;
+
+use serde::{Deserialize, Serialize};
 
 use crate::{
     events::{
@@ -6,9 +9,12 @@ use crate::{
         traits::{EpochId, Event, RelevantEventSelector, Uri},
     },
     mechanisms::{NoiseScale, NormType},
+    pds::{accounting::EventSampling, dedup::TriggerDedup, quotas::CampaignId},
+    queries::ppa_histogram::Lookback,
+    util::parallel::ThreadSafe,
 };
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ReportRequestUris<U> {
     /// URI that triggered the report
     pub trigger_uri: U,
@@ -18,30 +24,95 @@ pub struct ReportRequestUris<U> {
 
     /// Queriers that will receive a report
     pub querier_uris: Vec<U>,
+
+    /// Campaign the report is for, if any. Each querier's campaigns are
+    /// bounded by their own `CampaignQuota` filter.
+    #[serde(default)]
+    pub campaign_id: Option<CampaignId>,
+}
+
+impl<U> ReportRequestUris<U> {
+    /// Converts each URI, e.g. to intern them with `InternedUri::from`.
+    pub fn map_uris<V>(
+        self,
+        mut f: impl FnMut(U) -> V,
+    ) -> ReportRequestUris<V> {
+        ReportRequestUris {
+            trigger_uri: f(self.trigger_uri),
+            source_uris: self.source_uris.into_iter().map(&mut f).collect(),
+            querier_uris: self.querier_uris.into_iter().map(f).collect(),
+            campaign_id: self.campaign_id,
+        }
+    }
 }
 
 /// Trait for report types returned by a device (in plaintext). Must implement a
 /// default variant for null reports, so devices with errors or no budget
 /// left are still sending something (and are thus indistinguishable from other
 /// devices once reports are encrypted). Aggregation methods can be defined by
-/// callers.
-pub trait Report: Debug + Default {}
+/// callers. Reports can be cloned, compared and serialized, e.g. to cache,
+/// test or deliver them.
+pub trait Report: Debug + Default + Clone + PartialEq + Serialize {}
 
 /// Trait for an epoch-based query.
-pub trait EpochReportRequest: Debug {
+///
+/// With the `parallel` feature, requests, their events and their reports are
+/// shared across threads to account for epochs in parallel, so they must be
+/// `Send + Sync`. Requests can be cloned, e.g. to retry or batch them, so
+/// closures in their selectors must be shared rather than owned.
+pub trait EpochReportRequest: Debug + Clone + ThreadSafe {
     type Uri: Uri;
     type EpochId: EpochId;
-    type Event: Event<Uri = Self::Uri, EpochId = Self::EpochId>;
+    type Event: Event<Uri = Self::Uri, EpochId = Self::EpochId> + ThreadSafe;
     type RelevantEventSelector: RelevantEventSelector<Event = Self::Event>;
     type PrivacyBudget;
-    type Report: Report;
+    type Report: Report + ThreadSafe;
 
     fn report_uris(&self) -> &ReportRequestUris<Self::Uri>;
 
+    /// Key chosen by the querier to identify retries of the same request.
+    /// Requests with the same key from the same queriers get the report of
+    /// the first one while it is cached, without consuming budget again.
+    fn idempotency_key(&self) -> Option<&str> {
+        None
+    }
+
+    /// Deduplication key and window of the trigger. Requests with the same
+    /// key for the same trigger site and queriers within the window are
+    /// duplicates, e.g. from page reloads, and don't consume budget again.
+    fn trigger_dedup(&self) -> Option<&TriggerDedup> {
+        None
+    }
+
+    /// Sampling of the relevant events before computing the report, in
+    /// exchange for a discounted loss. See `EventSampling`.
+    fn event_sampling(&self) -> Option<&EventSampling> {
+        None
+    }
+
     /// Returns the list of requested epoch IDs, in the order the attribution
-    /// should run.
+    /// should run. Epochs don't have to be contiguous, see `EpochSelection`.
+    /// Duplicates are ignored by the accounting.
     fn epoch_ids(&self) -> Vec<Self::EpochId>;
 
+    /// Returns the first and last requested epochs, as given by the querier.
+    /// The range is inverted if the first epoch is after the last one.
+    fn epoch_range(&self) -> (Self::EpochId, Self::EpochId);
+
+    /// Time window to attribute to, for requests selecting their epochs with
+    /// a duration before the trigger, see `EpochSelection::Duration`. The PDS
+    /// resolves the window into epochs with its `EpochPolicy`, and runs
+    /// `with_epoch_ids` instead.
+    fn duration_window(&self) -> Option<Lookback> {
+        None
+    }
+
+    /// Same request over the given epochs, most recent first, once its
+    /// duration window has been resolved.
+    fn with_epoch_ids(&self, _epoch_ids: Vec<Self::EpochId>) -> Self {
+        self.clone()
+    }
+
     /// Returns the selector for relevant events for the query. The selector
     /// can be passed to the event storage to retrieve only the relevant events.
     fn relevant_event_selector(&self) -> &Self::RelevantEventSelector;
@@ -52,6 +123,13 @@ pub trait EpochReportRequest: Debug {
         relevant_events: &RelevantEvents<Self::Event>,
     ) -> Self::Report;
 
+    /// Whether a relevant event can contribute to the report, e.g. whether
+    /// its histogram index maps to a bucket. Only used to explain reports,
+    /// see `AttributionTrace`.
+    fn is_event_in_range(&self, _event: &Self::Event) -> bool {
+        true
+    }
+
     /// Computes the individual sensitivity for the query when the report is
     /// computed over a single epoch.
     fn single_epoch_individual_sensitivity(
@@ -68,6 +146,32 @@ pub trait EpochReportRequest: Debug {
         norm_type: NormType,
     ) -> f64;
 
+    /// Computes the individual sensitivity of the events from `source` when
+    /// the report is computed over a single epoch, for reports that are split
+    /// by source. Returns None for other reports, whose epoch-source
+    /// sensitivity is only known for single-source requests.
+    fn single_epoch_split_source_sensitivity(
+        &self,
+        _report: &Self::Report,
+        _source: &Self::Uri,
+        _norm_type: NormType,
+    ) -> Option<f64> {
+        None
+    }
+
+    /// Computes the individual sensitivity of the events of a single epoch
+    /// when the report is computed over multiple epochs, for attribution
+    /// functions that add up independent contributions from each epoch.
+    /// Returns None for other attribution functions, such as last touch
+    /// across epochs, where an epoch can take value away from another one.
+    fn multi_epoch_individual_sensitivity(
+        &self,
+        _epoch_relevant_events: &[Self::Event],
+        _norm_type: NormType,
+    ) -> Option<f64> {
+        None
+    }
+
     /// Computes the global sensitivity for the query.
     fn report_global_sensitivity(&self) -> f64;
 
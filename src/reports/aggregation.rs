@@ -0,0 +1,170 @@
+//! Post-processing of the reports on the aggregation side: summing device
+//! reports, adding noise when devices don't, and estimating the error of
+//! the noisy aggregates.
+
+use rand::Rng;
+
+use crate::{
+    error::PdsError,
+    mechanisms::NoiseScale,
+    queries::histogram::{BucketKey, HistogramReport},
+    util::hashmap::HashMap,
+};
+
+/// Sums histogram reports bucket by bucket.
+pub fn sum_histograms<'a, BK: BucketKey + 'a>(
+    reports: impl IntoIterator<Item = &'a HistogramReport<BK>>,
+) -> HashMap<BK, f64> {
+    let mut bin_values = HashMap::new();
+    for report in reports {
+        for (bucket, value) in &report.bin_values {
+            *bin_values.entry(bucket.clone()).or_default() += value;
+        }
+    }
+    bin_values
+}
+
+/// Adds one draw of noise to each bucket of `domain`, for reports that were
+/// not noised on the device. Buckets missing from the aggregate are noised
+/// too, otherwise the set of non-empty buckets would leak.
+pub fn add_noise<BK: BucketKey, R: Rng + ?Sized>(
+    aggregate: &HashMap<BK, f64>,
+    domain: impl IntoIterator<Item = BK>,
+    noise_scale: &NoiseScale,
+    rng: &mut R,
+) -> HashMap<BK, f64> {
+    domain
+        .into_iter()
+        .map(|bucket| {
+            let value = aggregate.get(&bucket).copied().unwrap_or_default();
+            (bucket, value + noise_scale.sample_noise(rng))
+        })
+        .collect()
+}
+
+/// Variance of the sum of `n_draws` independent noise draws, e.g. 1 for
+/// noise added by the aggregation service, or the number of reports for
+/// noise added on each device.
+pub fn noise_variance(noise_scale: &NoiseScale, n_draws: usize) -> f64 {
+    match noise_scale {
+        NoiseScale::Laplace(b) => 2.0 * b * b * n_draws as f64,
+    }
+}
+
+/// Bound on the absolute value of a single noise draw that holds with
+/// probability `confidence`.
+pub fn noise_error_bound(
+    noise_scale: &NoiseScale,
+    confidence: f64,
+) -> Result<f64, PdsError> {
+    check_confidence(confidence)?;
+    match noise_scale {
+        // P(|Lap(b)| > t) = exp(-t / b)
+        NoiseScale::Laplace(b) => Ok(-b * (1.0 - confidence).ln()),
+    }
+}
+
+/// Unbiased estimate of an aggregate, since the noise has zero mean.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Estimate {
+    pub value: f64,
+
+    /// Variance of the noise in `value`.
+    pub variance: f64,
+}
+
+impl Estimate {
+    /// Interval containing the noiseless aggregate with probability at least
+    /// `confidence`. Uses Chebyshev's inequality, so it holds for sums of any
+    /// number of noise draws, at the cost of being conservative.
+    pub fn confidence_interval(
+        &self,
+        confidence: f64,
+    ) -> Result<(f64, f64), PdsError> {
+        check_confidence(confidence)?;
+        let half_width = (self.variance / (1.0 - confidence)).sqrt();
+        Ok((self.value - half_width, self.value + half_width))
+    }
+}
+
+/// Estimates the sum of the given buckets of a noisy aggregate, where each
+/// bucket has noise of variance `bucket_noise_variance`.
+pub fn estimate_sum<BK: BucketKey>(
+    noisy_aggregate: &HashMap<BK, f64>,
+    buckets: &[BK],
+    bucket_noise_variance: f64,
+) -> Estimate {
+    let value = buckets
+        .iter()
+        .map(|bucket| noisy_aggregate.get(bucket).copied().unwrap_or_default())
+        .sum();
+    Estimate {
+        value,
+        variance: bucket_noise_variance * buckets.len() as f64,
+    }
+}
+
+fn check_confidence(confidence: f64) -> Result<(), PdsError> {
+    if !(0.0..1.0).contains(&confidence) {
+        return Err(PdsError::InvalidRequest(
+            "confidence must be in [0, 1)".into(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::rng::new_rng;
+
+    #[test]
+    fn test_aggregation() -> Result<(), PdsError> {
+        let reports = [
+            HistogramReport {
+                bin_values: HashMap::from_iter([(1_u64, 2.0), (2, 1.0)]),
+            },
+            HistogramReport {
+                bin_values: HashMap::from_iter([(1, 3.0)]),
+            },
+        ];
+        let aggregate = sum_histograms(&reports);
+        assert_eq!(aggregate, HashMap::from_iter([(1, 5.0), (2, 1.0)]));
+
+        // Every bucket of the domain gets noise, even empty ones.
+        let noise_scale = NoiseScale::Laplace(0.5);
+        let mut rng = new_rng(Some(0));
+        let noisy_aggregate =
+            add_noise(&aggregate, 0..4, &noise_scale, &mut rng);
+        assert_eq!(noisy_aggregate.len(), 4);
+        assert_ne!(noisy_aggregate[&0], 0.0);
+
+        let estimate = estimate_sum(
+            &noisy_aggregate,
+            &[1, 2],
+            noise_variance(&noise_scale, 1),
+        );
+        assert_eq!(estimate.variance, 1.0);
+        let (low, high) = estimate.confidence_interval(0.75)?;
+        assert!((high - low - 4.0).abs() < 1e-9);
+
+        // Half of the Laplace draws are within b * ln(2).
+        assert_eq!(noise_error_bound(&noise_scale, 0.5)?, 0.5 * 2f64.ln());
+        assert!(noise_error_bound(&noise_scale, 1.0).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_bound_coverage() -> Result<(), PdsError> {
+        let noise_scale = NoiseScale::Laplace(2.0);
+        let bound = noise_error_bound(&noise_scale, 0.9)?;
+        let mut rng = new_rng(Some(1));
+        let n_covered = (0..10_000)
+            .filter(|_| noise_scale.sample_noise(&mut rng).abs() <= bound)
+            .count();
+        assert!((8_800..=9_200).contains(&n_covered));
+
+        Ok(())
+    }
+}
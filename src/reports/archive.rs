@@ -0,0 +1,327 @@
+//! Zero-copy serialization of reports with `rkyv`, for high-volume
+//! simulations where JSON is too slow. Reports are converted to plain records
+//! first, so that the archived layout doesn't depend on the hash map used by
+//! the PDS, and archived records can be read in place without deserializing.
+//!
+//! Schema evolution: archived layouts are not self-describing, so a buffer
+//! can only be read with the exact record types that wrote it. Every change
+//! to a record, including reordering fields, must bump `ARCHIVE_VERSION`.
+//! Records carry the version they were written with, and readers should
+//! check `version` before using the other fields, e.g. to re-run older
+//! simulations with the matching release. Attribution traces contain raw
+//! events and are never archived.
+
+use rkyv::{
+    api::high::{HighSerializer, HighValidator},
+    bytecheck::CheckBytes,
+    rancor,
+    ser::allocator::ArenaHandle,
+    util::AlignedVec,
+    Archive, Deserialize, Portable, Serialize,
+};
+
+#[cfg(feature = "experimental")]
+use crate::pds::batch_pds::BatchedReport;
+use crate::{
+    error::PdsError,
+    events::traits::{EpochId, Uri},
+    pds::{
+        private_data_service::{PdsReport, ReportStatus, RetryAdvice},
+        quotas::FilterId,
+    },
+    queries::{
+        histogram::{BucketKey, HistogramReport},
+        traits::EpochReportRequest,
+    },
+};
+
+/// Version of the layout of the records, see the module documentation.
+pub const ARCHIVE_VERSION: u32 = 3;
+
+/// Histogram report, with its buckets sorted by key so that equal reports
+/// are archived identically.
+#[derive(Debug, Clone, PartialEq, Archive, Serialize, Deserialize)]
+pub struct HistogramRecord<BK> {
+    pub bin_values: Vec<(BK, f64)>,
+}
+
+impl<BK: BucketKey + Ord> From<&HistogramReport<BK>> for HistogramRecord<BK> {
+    fn from(report: &HistogramReport<BK>) -> Self {
+        let mut bin_values = report
+            .bin_values
+            .iter()
+            .map(|(bucket, value)| (bucket.clone(), *value))
+            .collect::<Vec<_>>();
+        bin_values.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Self { bin_values }
+    }
+}
+
+impl<BK: BucketKey> From<HistogramRecord<BK>> for HistogramReport<BK> {
+    fn from(record: HistogramRecord<BK>) -> Self {
+        Self {
+            bin_values: record.bin_values.into_iter().collect(),
+        }
+    }
+}
+
+/// Report returned by the PDS, without its attribution trace.
+#[derive(Debug, Clone, PartialEq, Archive, Serialize, Deserialize)]
+pub struct PdsReportRecord<R, E: EpochId = u64, U: Uri = String> {
+    pub version: u32,
+    pub filtered_report: R,
+    pub unfiltered_report: R,
+    pub oob_filters: Vec<FilterId<E, U>>,
+    pub status: ReportStatus<E>,
+    pub retry_advice: Option<RetryAdvice>,
+}
+
+impl<R, E: EpochId, U: Uri> PdsReportRecord<R, E, U> {
+    /// Converts a report, with `to_record` converting the reports of the
+    /// request.
+    pub fn from_report<Q>(
+        report: &PdsReport<Q>,
+        to_record: impl Fn(&Q::Report) -> R,
+    ) -> Self
+    where
+        Q: EpochReportRequest<EpochId = E, Uri = U>,
+    {
+        Self {
+            version: ARCHIVE_VERSION,
+            filtered_report: to_record(&report.filtered_report),
+            unfiltered_report: to_record(&report.unfiltered_report),
+            oob_filters: report.oob_filters.clone(),
+            status: report.status.clone(),
+            retry_advice: report.retry_advice,
+        }
+    }
+
+    /// Converts back to a report, with `from_record` converting the reports
+    /// of the request.
+    pub fn into_report<Q>(
+        self,
+        from_record: impl Fn(R) -> Q::Report,
+    ) -> PdsReport<Q>
+    where
+        Q: EpochReportRequest<EpochId = E, Uri = U>,
+    {
+        PdsReport {
+            filtered_report: from_record(self.filtered_report),
+            unfiltered_report: from_record(self.unfiltered_report),
+            oob_filters: self.oob_filters,
+            status: self.status,
+            retry_advice: self.retry_advice,
+            #[cfg(feature = "experimental")]
+            attribution_trace: None,
+        }
+    }
+}
+
+impl<Q, BK> From<&PdsReport<Q>>
+    for PdsReportRecord<HistogramRecord<BK>, Q::EpochId, Q::Uri>
+where
+    Q: EpochReportRequest<Report = HistogramReport<BK>>,
+    BK: BucketKey + Ord,
+{
+    fn from(report: &PdsReport<Q>) -> Self {
+        Self::from_report(report, |report| HistogramRecord::from(report))
+    }
+}
+
+/// [Experimental] Report of a batched request.
+#[cfg(feature = "experimental")]
+#[derive(Debug, Clone, PartialEq, Archive, Serialize, Deserialize)]
+pub struct BatchedReportRecord<R, E: EpochId = u64, U: Uri = String> {
+    pub request_id: u64,
+    pub querier_uri: U,
+    pub registered_at: u64,
+    pub computed_at: u64,
+    pub report: PdsReportRecord<R, E, U>,
+}
+
+#[cfg(feature = "experimental")]
+impl<R, E: EpochId, U: Uri> BatchedReportRecord<R, E, U> {
+    /// Converts a batched report, with `to_record` converting the reports of
+    /// the request.
+    pub fn from_report<Q>(
+        report: &BatchedReport<Q>,
+        to_record: impl Fn(&Q::Report) -> R,
+    ) -> Self
+    where
+        Q: EpochReportRequest<EpochId = E, Uri = U>,
+    {
+        Self {
+            request_id: report.request_id,
+            querier_uri: report.querier_uri.clone(),
+            registered_at: report.registered_at,
+            computed_at: report.computed_at,
+            report: PdsReportRecord::from_report(&report.report, to_record),
+        }
+    }
+
+    /// Converts back to a batched report, with `from_record` converting the
+    /// reports of the request.
+    pub fn into_report<Q>(
+        self,
+        from_record: impl Fn(R) -> Q::Report,
+    ) -> BatchedReport<Q>
+    where
+        Q: EpochReportRequest<EpochId = E, Uri = U>,
+    {
+        BatchedReport {
+            request_id: self.request_id,
+            querier_uri: self.querier_uri,
+            registered_at: self.registered_at,
+            computed_at: self.computed_at,
+            report: self.report.into_report(from_record),
+        }
+    }
+}
+
+#[cfg(feature = "experimental")]
+impl<Q, BK> From<&BatchedReport<Q>>
+    for BatchedReportRecord<HistogramRecord<BK>, Q::EpochId, Q::Uri>
+where
+    Q: EpochReportRequest<Report = HistogramReport<BK>>,
+    BK: BucketKey + Ord,
+{
+    fn from(report: &BatchedReport<Q>) -> Self {
+        Self::from_report(report, |report| HistogramRecord::from(report))
+    }
+}
+
+/// Archives a record.
+pub fn to_bytes<T>(record: &T) -> Result<AlignedVec, PdsError>
+where
+    T: for<'a> Serialize<
+        HighSerializer<AlignedVec, ArenaHandle<'a>, rancor::Error>,
+    >,
+{
+    rkyv::to_bytes::<rancor::Error>(record)
+        .map_err(|err| PdsError::Internal(format!("can't archive: {err}")))
+}
+
+/// Reads an archived record in place, after validating it. `bytes` must be
+/// aligned, e.g. an `AlignedVec`.
+pub fn access<T>(bytes: &[u8]) -> Result<&T::Archived, PdsError>
+where
+    T: Archive,
+    T::Archived:
+        Portable + for<'a> CheckBytes<HighValidator<'a, rancor::Error>>,
+{
+    rkyv::access::<T::Archived, rancor::Error>(bytes).map_err(|err| {
+        PdsError::InvalidRequest(format!("invalid archive: {err}"))
+    })
+}
+
+/// Reads and deserializes an archived record.
+pub fn from_bytes<T>(bytes: &[u8]) -> Result<T, PdsError>
+where
+    T: Archive,
+    T::Archived: Portable
+        + for<'a> CheckBytes<HighValidator<'a, rancor::Error>>
+        + Deserialize<T, rancor::Strategy<rkyv::de::Pool, rancor::Error>>,
+{
+    let archived = access::<T>(bytes)?;
+    rkyv::deserialize::<T, rancor::Error>(archived).map_err(|err| {
+        PdsError::Internal(format!("can't deserialize archive: {err}"))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        queries::ppa_histogram::PpaHistogramRequest, util::hashmap::HashMap,
+    };
+
+    fn pds_report() -> PdsReport<PpaHistogramRequest> {
+        PdsReport {
+            filtered_report: HistogramReport {
+                bin_values: HashMap::from_iter([(3, 1.5), (1, 0.5)]),
+            },
+            unfiltered_report: HistogramReport {
+                bin_values: HashMap::from_iter([(3, 1.5), (1, 0.5), (2, 4.0)]),
+            },
+            oob_filters: vec![FilterId::SourceTriggerQuota(
+                2,
+                "blog.com".to_string(),
+                "shoes.com".to_string(),
+            )],
+            status: ReportStatus::PartiallyFiltered {
+                dropped_epochs: vec![2],
+            },
+            retry_advice: Some(RetryAdvice::QuotaThrottled),
+            #[cfg(feature = "experimental")]
+            attribution_trace: None,
+        }
+    }
+
+    #[test]
+    fn test_pds_report_round_trip() -> Result<(), PdsError> {
+        let record = PdsReportRecord::from(&pds_report());
+        let bytes = to_bytes(&record)?;
+
+        // Archived records are read in place.
+        let archived = access::<PdsReportRecord<HistogramRecord<u64>>>(&bytes)?;
+        assert_eq!(archived.version, ARCHIVE_VERSION);
+        let buckets = archived
+            .filtered_report
+            .bin_values
+            .iter()
+            .map(|bin| bin.0.to_native())
+            .collect::<Vec<_>>();
+        assert_eq!(buckets, [1, 3]);
+
+        let record =
+            from_bytes::<PdsReportRecord<HistogramRecord<u64>>>(&bytes)?;
+        assert_eq!(record.version, ARCHIVE_VERSION);
+        let report: PdsReport<PpaHistogramRequest> =
+            record.into_report(HistogramReport::from);
+        assert_eq!(
+            report.filtered_report.bin_values,
+            pds_report().filtered_report.bin_values
+        );
+        assert_eq!(report.unfiltered_report.bin_values.len(), 3);
+        assert_eq!(report.oob_filters, pds_report().oob_filters);
+        assert_eq!(report.status, pds_report().status);
+        assert_eq!(report.retry_advice, pds_report().retry_advice);
+
+        // Equal reports are archived identically.
+        assert_eq!(
+            to_bytes(&PdsReportRecord::from(&pds_report()))?.as_slice(),
+            bytes.as_slice()
+        );
+
+        // Corrupted buffers are rejected.
+        assert!(access::<PdsReportRecord<HistogramRecord<u64>>>(&bytes[1..])
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "experimental")]
+    fn test_batched_report_round_trip() -> Result<(), PdsError> {
+        let batched_report = BatchedReport {
+            request_id: 7,
+            querier_uri: "adtech.com".to_string(),
+            registered_at: 10,
+            computed_at: 20,
+            report: pds_report(),
+        };
+        let record = BatchedReportRecord::from(&batched_report);
+        let bytes = to_bytes(&record)?;
+
+        let archived =
+            access::<BatchedReportRecord<HistogramRecord<u64>>>(&bytes)?;
+        assert_eq!(archived.request_id, 7);
+        assert_eq!(archived.report.oob_filters.len(), 1);
+
+        let round_trip = from_bytes::<BatchedReportRecord<_>>(&bytes)?;
+        assert_eq!(round_trip, record);
+        let report: BatchedReport<PpaHistogramRequest> =
+            round_trip.into_report(HistogramReport::from);
+        assert_eq!((report.request_id, report.computed_at), (7, 20));
+        Ok(())
+    }
+}
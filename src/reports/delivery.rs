@@ -0,0 +1,544 @@
+//! Report submission pipeline, following the PPA/ARA report-delivery model:
+//! reports are held on the device for a random delay, so that their arrival
+//! time doesn't leak when the triggering conversion happened, and then
+//! submitted in batches to a pluggable `ReportSink`.
+
+use std::{
+    fs::OpenOptions,
+    io::{Read, Write},
+    net::TcpStream,
+    path::PathBuf,
+    thread,
+    time::Duration,
+};
+
+use log::{debug, warn};
+use rand::Rng;
+use serde::Serialize;
+
+use crate::{
+    error::PdsError,
+    util::{
+        clock::{Clock, SystemClock},
+        rng::{new_rng, PdsRng},
+    },
+};
+
+/// Destination for reports, e.g. an aggregation service.
+pub trait ReportSink<R> {
+    /// Submits a batch of reports. On error, none of the reports should be
+    /// considered delivered, so the caller can submit them again.
+    fn submit(&mut self, reports: &[R]) -> Result<(), PdsError>;
+}
+
+/// Sink keeping the reports in memory, for tests.
+#[derive(Debug)]
+pub struct InMemorySink<R> {
+    /// Batches submitted so far, in order.
+    pub batches: Vec<Vec<R>>,
+}
+
+impl<R> Default for InMemorySink<R> {
+    fn default() -> Self {
+        Self { batches: vec![] }
+    }
+}
+
+impl<R> InMemorySink<R> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<R: Clone> ReportSink<R> for InMemorySink<R> {
+    fn submit(&mut self, reports: &[R]) -> Result<(), PdsError> {
+        self.batches.push(reports.to_vec());
+        Ok(())
+    }
+}
+
+/// Sink appending reports to a spool file, one JSON array per batch and per
+/// line, so that another process can upload them.
+#[derive(Debug, Clone)]
+pub struct FileSpoolSink {
+    pub path: PathBuf,
+}
+
+impl FileSpoolSink {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl<R: Serialize> ReportSink<R> for FileSpoolSink {
+    fn submit(&mut self, reports: &[R]) -> Result<(), PdsError> {
+        let mut line = serde_json::to_string(reports)
+            .map_err(|e| PdsError::DeliveryError(e.to_string()))?;
+        line.push('\n');
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| PdsError::DeliveryError(e.to_string()))?;
+        file.write_all(line.as_bytes())
+            .map_err(|e| PdsError::DeliveryError(e.to_string()))
+    }
+}
+
+/// Transport for `HttpSink`, so that embedders can use their own HTTP stack
+/// (e.g. with TLS).
+pub trait HttpTransport {
+    /// Sends a POST request with a JSON body, and returns the HTTP status
+    /// code.
+    fn post(&mut self, url: &str, body: &[u8]) -> Result<u16, PdsError>;
+}
+
+/// Minimal HTTP/1.1 transport over plain TCP, for `http://` URLs only.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpHttpTransport;
+
+impl HttpTransport for TcpHttpTransport {
+    fn post(&mut self, url: &str, body: &[u8]) -> Result<u16, PdsError> {
+        let io_error =
+            |e: std::io::Error| PdsError::DeliveryError(e.to_string());
+
+        let Some(rest) = url.strip_prefix("http://") else {
+            return Err(PdsError::DeliveryError(format!(
+                "unsupported URL {url}, only http:// is supported"
+            )));
+        };
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let address = match host.contains(':') {
+            true => host.to_string(),
+            false => format!("{host}:80"),
+        };
+
+        let mut stream = TcpStream::connect(address).map_err(io_error)?;
+        let header = format!(
+            "POST {path} HTTP/1.1\r\nHost: {host}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        stream.write_all(header.as_bytes()).map_err(io_error)?;
+        stream.write_all(body).map_err(io_error)?;
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).map_err(io_error)?;
+        response
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| {
+                PdsError::DeliveryError(format!(
+                    "invalid HTTP response from {url}"
+                ))
+            })
+    }
+}
+
+/// Sink POSTing each batch as a JSON array, retrying with exponential backoff
+/// on transport errors and server errors (5xx).
+#[derive(Debug, Clone)]
+pub struct HttpSink<T: HttpTransport = TcpHttpTransport> {
+    pub url: String,
+    pub transport: T,
+
+    /// Number of retries after the first attempt.
+    pub max_retries: u32,
+
+    /// Delay before the first retry, doubled after each retry.
+    pub initial_backoff: Duration,
+}
+
+impl HttpSink {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self::with_transport(url, TcpHttpTransport)
+    }
+}
+
+impl<T: HttpTransport> HttpSink<T> {
+    pub fn with_transport(url: impl Into<String>, transport: T) -> Self {
+        Self {
+            url: url.into(),
+            transport,
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+impl<R: Serialize, T: HttpTransport> ReportSink<R> for HttpSink<T> {
+    fn submit(&mut self, reports: &[R]) -> Result<(), PdsError> {
+        let body = serde_json::to_vec(reports)
+            .map_err(|e| PdsError::DeliveryError(e.to_string()))?;
+
+        let mut backoff = self.initial_backoff;
+        let mut attempt = 0;
+        loop {
+            let error = match self.transport.post(&self.url, &body) {
+                Ok(status) if (200..300).contains(&status) => return Ok(()),
+                // Client errors won't go away by retrying.
+                Ok(status) if (400..500).contains(&status) => {
+                    return Err(PdsError::DeliveryError(format!(
+                        "{} rejected the reports with status {status}",
+                        self.url
+                    )));
+                }
+                Ok(status) => PdsError::DeliveryError(format!(
+                    "{} answered with status {status}",
+                    self.url
+                )),
+                Err(e) => e,
+            };
+
+            if attempt >= self.max_retries {
+                return Err(error);
+            }
+            warn!("Report submission failed, retrying in {backoff:?}: {error}");
+            thread::sleep(backoff);
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+}
+
+/// Distribution of the delivery delays, in seconds. Delays are drawn
+/// independently of the reports, so null reports are delayed like the other
+/// ones and their send time doesn't leak whether attribution happened. Null
+/// reports must go through the same scheduler for this to hold.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DelayPolicy {
+    /// Reports are sent at the next flush, e.g. when they are already
+    /// released on a fixed schedule by the batch PDS.
+    NoDelay,
+
+    /// Uniform between `min` and `max`, included.
+    Uniform { min: u64, max: u64 },
+
+    /// Exponential with the given mean, truncated to `max` so that reports
+    /// are never held forever. Most reports are sent early, unlike with a
+    /// uniform delay of the same maximum.
+    Exponential { mean: f64, max: u64 },
+}
+
+impl DelayPolicy {
+    /// Checks that the distribution is well-defined.
+    pub fn validate(&self) -> Result<(), PdsError> {
+        match *self {
+            DelayPolicy::NoDelay => Ok(()),
+            DelayPolicy::Uniform { min, max } if min > max => {
+                Err(PdsError::InvalidRequest(format!(
+                    "minimum delay {min} is larger than maximum delay {max}"
+                )))
+            }
+            DelayPolicy::Uniform { .. } => Ok(()),
+            DelayPolicy::Exponential { mean, .. }
+                if !mean.is_finite() || mean <= 0.0 =>
+            {
+                Err(PdsError::InvalidRequest(format!(
+                    "mean delay must be positive and finite, got {mean}"
+                )))
+            }
+            DelayPolicy::Exponential { .. } => Ok(()),
+        }
+    }
+
+    /// Longest possible delay.
+    pub fn max_delay(&self) -> u64 {
+        match *self {
+            DelayPolicy::NoDelay => 0,
+            DelayPolicy::Uniform { max, .. }
+            | DelayPolicy::Exponential { max, .. } => max,
+        }
+    }
+
+    /// Draws a delay.
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> u64 {
+        match *self {
+            DelayPolicy::NoDelay => 0,
+            DelayPolicy::Uniform { min, max } => rng.gen_range(min..=max),
+            DelayPolicy::Exponential { mean, max } => {
+                // Inverse transform sampling, with 1 - u in (0, 1].
+                let u: f64 = rng.gen();
+                let delay = -mean * (1.0 - u).ln();
+                (delay as u64).min(max)
+            }
+        }
+    }
+}
+
+/// Report waiting for its delivery time.
+#[derive(Debug)]
+struct PendingReport<R> {
+    deliver_at: u64,
+    report: R,
+}
+
+/// Holds reports for a random delay, drawn from a `DelayPolicy`, then submits
+/// the due ones to the sink in batches. Reports that fail to be submitted
+/// stay pending until the next flush.
+pub struct DeliveryScheduler<R, S: ReportSink<R>> {
+    pub sink: S,
+    pub clock: Box<dyn Clock>,
+
+    /// Distribution of the delays.
+    pub delay_policy: DelayPolicy,
+
+    /// Maximum number of reports per submission.
+    pub batch_size: usize,
+
+    rng: PdsRng,
+    pending: Vec<PendingReport<R>>,
+}
+
+impl<R, S: ReportSink<R>> DeliveryScheduler<R, S> {
+    /// Delays reports uniformly between 0 and `max_delay` seconds.
+    pub fn new(sink: S, max_delay: u64, batch_size: usize) -> Self {
+        Self {
+            sink,
+            clock: Box::new(SystemClock),
+            delay_policy: DelayPolicy::Uniform {
+                min: 0,
+                max: max_delay,
+            },
+            batch_size: batch_size.max(1),
+            rng: new_rng(None),
+            pending: vec![],
+        }
+    }
+
+    /// Uses the given clock to compute delivery times.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Draws the delays from the given distribution instead.
+    pub fn with_delay_policy(
+        mut self,
+        delay_policy: DelayPolicy,
+    ) -> Result<Self, PdsError> {
+        delay_policy.validate()?;
+        self.delay_policy = delay_policy;
+        Ok(self)
+    }
+
+    /// Seeds the delays, for simulations only.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = new_rng(Some(seed));
+        self
+    }
+
+    /// Number of reports waiting to be delivered.
+    pub fn n_pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Holds the report until its random delivery time. Returns that time,
+    /// which never depends on the report.
+    pub fn schedule(&mut self, report: R) -> u64 {
+        let deliver_at =
+            self.clock.now() + self.delay_policy.sample(&mut self.rng);
+        self.pending.push(PendingReport { deliver_at, report });
+        deliver_at
+    }
+
+    /// Submits all the reports that are due, and returns the number of
+    /// delivered reports. Stops at the first failed batch, which stays
+    /// pending along with the remaining reports.
+    pub fn flush_due(&mut self) -> Result<usize, PdsError> {
+        let now = self.clock.now();
+        let (mut due, not_due): (Vec<_>, Vec<_>) =
+            std::mem::take(&mut self.pending)
+                .into_iter()
+                .partition(|pending| pending.deliver_at <= now);
+        self.pending = not_due;
+
+        // Deliver in the order reports became due.
+        due.sort_by_key(|pending| pending.deliver_at);
+        let mut due = due.into_iter().map(|p| p.report).collect::<Vec<_>>();
+
+        let mut n_delivered = 0;
+        while !due.is_empty() {
+            let batch_len = self.batch_size.min(due.len());
+            if let Err(e) = self.sink.submit(&due[..batch_len]) {
+                // Put the undelivered reports back, due right away.
+                self.pending.extend(due.into_iter().map(|report| {
+                    PendingReport {
+                        deliver_at: now,
+                        report,
+                    }
+                }));
+                return Err(e);
+            }
+            due.drain(..batch_len);
+            n_delivered += batch_len;
+        }
+
+        debug!("Delivered {n_delivered} reports");
+        Ok(n_delivered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::clock::MockClock;
+
+    /// Transport answering with the given statuses, in order.
+    struct MockTransport {
+        statuses: Vec<Result<u16, PdsError>>,
+        bodies: Vec<Vec<u8>>,
+    }
+
+    impl HttpTransport for MockTransport {
+        fn post(&mut self, _url: &str, body: &[u8]) -> Result<u16, PdsError> {
+            self.bodies.push(body.to_vec());
+            self.statuses.remove(0)
+        }
+    }
+
+    fn http_sink(
+        statuses: Vec<Result<u16, PdsError>>,
+    ) -> HttpSink<MockTransport> {
+        let transport = MockTransport {
+            statuses,
+            bodies: vec![],
+        };
+        HttpSink {
+            max_retries: 2,
+            initial_backoff: Duration::ZERO,
+            ..HttpSink::with_transport("http://aggregator.test", transport)
+        }
+    }
+
+    #[test]
+    fn test_scheduler_delays_and_batches() -> Result<(), PdsError> {
+        let clock = MockClock::new(1000);
+        let mut scheduler = DeliveryScheduler::new(InMemorySink::new(), 60, 2)
+            .with_clock(clock.clone())
+            .with_seed(42);
+
+        for report in 0..5u64 {
+            let deliver_at = scheduler.schedule(report);
+            assert!((1000..=1060).contains(&deliver_at));
+        }
+
+        // Nothing is due before the minimum delay, everything is due after
+        // the maximum delay.
+        clock.set(999);
+        assert_eq!(scheduler.flush_due()?, 0);
+        clock.set(1060);
+        assert_eq!(scheduler.flush_due()?, 5);
+        assert_eq!(scheduler.n_pending(), 0);
+
+        let batch_sizes = scheduler
+            .sink
+            .batches
+            .iter()
+            .map(|batch| batch.len())
+            .collect::<Vec<_>>();
+        assert_eq!(batch_sizes, vec![2, 2, 1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delay_policies() -> Result<(), PdsError> {
+        let policy = DelayPolicy::Exponential {
+            mean: 10.0,
+            max: 60,
+        };
+        let clock = MockClock::new(0);
+        let scheduler = |sink| {
+            DeliveryScheduler::new(sink, 0, 10)
+                .with_clock(clock.clone())
+                .with_seed(7)
+                .with_delay_policy(policy)
+        };
+
+        // Null reports get the same send times as attributed reports.
+        let mut null_reports = scheduler(InMemorySink::new())?;
+        let mut reports = scheduler(InMemorySink::new())?;
+        let null_times = (0..100)
+            .map(|_| null_reports.schedule(None))
+            .collect::<Vec<_>>();
+        let times = (0..100)
+            .map(|value| reports.schedule(Some(value)))
+            .collect::<Vec<_>>();
+        assert_eq!(null_times, times);
+
+        // Exponential delays are truncated, and mostly short.
+        assert!(times.iter().all(|time| *time <= policy.max_delay()));
+        let n_early = times.iter().filter(|time| **time <= 10).count();
+        assert!(n_early > 50);
+
+        // Invalid distributions are rejected.
+        let uniform = DelayPolicy::Uniform { min: 10, max: 5 };
+        let scheduler =
+            DeliveryScheduler::new(InMemorySink::<u64>::new(), 0, 1);
+        assert!(scheduler.with_delay_policy(uniform).is_err());
+        let exponential = DelayPolicy::Exponential { mean: 0.0, max: 5 };
+        assert!(exponential.validate().is_err());
+
+        let mut rng = new_rng(Some(1));
+        assert_eq!(DelayPolicy::NoDelay.sample(&mut rng), 0);
+        let uniform = DelayPolicy::Uniform { min: 5, max: 5 };
+        assert_eq!(uniform.sample(&mut rng), 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_http_sink_retries() {
+        // Server errors and transport errors are retried.
+        let error = Err(PdsError::DeliveryError("connection reset".into()));
+        let mut sink = http_sink(vec![Ok(503), error, Ok(200)]);
+        assert!(sink.submit(&[1u64, 2]).is_ok());
+        assert_eq!(sink.transport.bodies.len(), 3);
+        assert_eq!(sink.transport.bodies[0], b"[1,2]");
+
+        // Retries are bounded.
+        let mut sink = http_sink(vec![Ok(500), Ok(500), Ok(500)]);
+        assert!(sink.submit(&[1u64]).is_err());
+        assert_eq!(sink.transport.bodies.len(), 3);
+
+        // Client errors are not retried.
+        let mut sink = http_sink(vec![Ok(400)]);
+        assert!(sink.submit(&[1u64]).is_err());
+        assert_eq!(sink.transport.bodies.len(), 1);
+    }
+
+    #[test]
+    fn test_failed_batches_stay_pending() {
+        let clock = MockClock::new(0);
+        let sink = http_sink(vec![Ok(200), Ok(500), Ok(500), Ok(500)]);
+        let mut scheduler =
+            DeliveryScheduler::new(sink, 0, 1).with_clock(clock.clone());
+
+        scheduler.schedule(1u64);
+        scheduler.schedule(2u64);
+        assert!(scheduler.flush_due().is_err());
+        assert_eq!(scheduler.n_pending(), 1);
+    }
+
+    #[test]
+    fn test_file_spool_sink() -> Result<(), PdsError> {
+        let path = std::env::temp_dir()
+            .join(format!("pdslib-spool-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut sink = FileSpoolSink::new(&path);
+        sink.submit(&[1u64, 2])?;
+        sink.submit(&[3u64])?;
+
+        let spooled = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(spooled, "[1,2]\n[3]\n");
+        std::fs::remove_file(&path).unwrap();
+
+        Ok(())
+    }
+}
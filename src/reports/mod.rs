@@ -0,0 +1,8 @@
+//! Delivery of the reports produced by the PDS to an aggregation service,
+//! and post-processing of the aggregates.
+
+pub mod aggregation;
+#[cfg(feature = "rkyv")]
+pub mod archive;
+pub mod delivery;
+pub mod wire;
@@ -0,0 +1,186 @@
+//! Serde schema of the reports shipped across process boundaries, e.g. from
+//! the PDS process to the process submitting the reports. `PdsReport` is
+//! serialized as a `PdsReportWire`, which only carries the filtered report,
+//! and the retry advice derived from public filters. The unfiltered report, the
+//! out-of-budget filters and the report status depend on the device's budget,
+//! so they never leave the device. Readers deserialize a `PdsReportWire`, since
+//! a `PdsReport` can't be rebuilt from it.
+//!
+//! Schema evolution: fields can be added with a serde default without
+//! breaking older readers, but any other change, e.g. renaming or removing a
+//! field, must bump `WIRE_VERSION`. Readers reject reports written with
+//! another version.
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    pds::private_data_service::{PdsReport, RetryAdvice},
+    queries::traits::EpochReportRequest,
+};
+
+/// Version of the schema, see the module documentation.
+pub const WIRE_VERSION: u32 = 2;
+
+/// Report returned by the PDS, as seen outside of the device.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PdsReportWire<R> {
+    pub version: u32,
+    pub filtered_report: R,
+
+    /// Missing from reports written before retry advice existed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_advice: Option<RetryAdvice>,
+}
+
+impl<R> PdsReportWire<R> {
+    pub fn from_report<Q>(report: &PdsReport<Q>) -> Self
+    where
+        Q: EpochReportRequest<Report = R>,
+        R: Clone,
+    {
+        Self {
+            version: WIRE_VERSION,
+            filtered_report: report.filtered_report.clone(),
+            retry_advice: report.retry_advice,
+        }
+    }
+}
+
+/// Fields of `PdsReportWire`, deserialized before checking the version.
+#[derive(Deserialize)]
+struct UncheckedWire<R> {
+    version: u32,
+    filtered_report: R,
+    #[serde(default)]
+    retry_advice: Option<RetryAdvice>,
+}
+
+impl<'de, R: Deserialize<'de>> Deserialize<'de> for PdsReportWire<R> {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        let wire = UncheckedWire::deserialize(deserializer)?;
+        if wire.version != WIRE_VERSION {
+            return Err(de::Error::custom(format!(
+                "unsupported report version {}, expected {WIRE_VERSION}",
+                wire.version
+            )));
+        }
+        Ok(Self {
+            version: wire.version,
+            filtered_report: wire.filtered_report,
+            retry_advice: wire.retry_advice,
+        })
+    }
+}
+
+impl<Q: EpochReportRequest> Serialize for PdsReport<Q> {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        PdsReportWire::from_report(self).serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        pds::{
+            private_data_service::{NullReason, ReportStatus},
+            quotas::{FilterId, PdsFilterStatus},
+        },
+        queries::{
+            histogram::HistogramReport,
+            ppa_histogram::{PpaBucketKey, PpaHistogramRequest},
+        },
+        util::hashmap::HashMap,
+    };
+
+    type Wire = PdsReportWire<HistogramReport<PpaBucketKey>>;
+
+    #[test]
+    fn test_pds_report_round_trip() -> Result<(), anyhow::Error> {
+        let report = PdsReport::<PpaHistogramRequest> {
+            filtered_report: HistogramReport {
+                bin_values: HashMap::from_iter([(3, 1.5)]),
+            },
+            unfiltered_report: HistogramReport {
+                bin_values: HashMap::from_iter([(3, 1.5), (2, 4.0)]),
+            },
+            oob_filters: vec![FilterId::Global(2)],
+            status: ReportStatus::PartiallyFiltered {
+                dropped_epochs: vec![2],
+            },
+            retry_advice: Some(RetryAdvice::EpochExhausted),
+            #[cfg(feature = "experimental")]
+            attribution_trace: None,
+        };
+
+        let json = serde_json::to_string(&report)?;
+        let value: serde_json::Value = serde_json::from_str(&json)?;
+        assert_eq!(value["version"], WIRE_VERSION);
+
+        // Nothing that depends on the device's budget leaves the device.
+        for key in [
+            "unfiltered_report",
+            "oob_filters",
+            "status",
+            "attribution_trace",
+        ] {
+            assert!(value.get(key).is_none(), "{key} is serialized");
+        }
+
+        let round_trip: Wire = serde_json::from_str(&json)?;
+        assert_eq!(
+            round_trip.filtered_report.bin_values,
+            report.filtered_report.bin_values
+        );
+        assert_eq!(round_trip.retry_advice, report.retry_advice);
+
+        // Reports written before retry advice existed are still read.
+        let mut value = value;
+        value.as_object_mut().unwrap().remove("retry_advice");
+        let round_trip: Wire = serde_json::from_value(value.clone())?;
+        assert_eq!(round_trip.retry_advice, None);
+
+        // Reports from other versions of the schema are rejected.
+        value["version"] = (WIRE_VERSION - 1).into();
+        assert!(serde_json::from_value::<Wire>(value).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_statuses() -> Result<(), anyhow::Error> {
+        let status = PdsFilterStatus::OutOfBudget(vec![
+            FilterId::<u64, String>::PerQuerier(1, "adtech.com".to_string()),
+            FilterId::Global(1),
+        ]);
+        let json = serde_json::to_string(&status)?;
+        assert_eq!(serde_json::from_str::<PdsFilterStatus<_>>(&json)?, status);
+        assert_eq!(
+            status.to_string(),
+            "out of budget [PerQuerier(1, adtech.com), Global(1)]"
+        );
+        assert_eq!(
+            PdsFilterStatus::<FilterId>::Continue.to_string(),
+            "continue"
+        );
+
+        let status = ReportStatus::<u64>::Null {
+            reason: NullReason::OutOfBudget,
+        };
+        let json = serde_json::to_string(&status)?;
+        assert_eq!(serde_json::from_str::<ReportStatus<u64>>(&json)?, status);
+        assert_eq!(status.to_string(), "null, out of budget");
+        assert_eq!(
+            ReportStatus::PartiallyFiltered {
+                dropped_epochs: vec![1, 3]
+            }
+            .to_string(),
+            "partially filtered, dropped epochs [1, 3]"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,398 @@
+//! [Experimental] Trace-driven simulator, to evaluate quota and scheduling
+//! policies on impression/conversion traces. Replays a trace on a
+//! `PrivateDataService` or a `BatchPrivateDataService`, and collects the
+//! allocation result of each query and the budget utilization of each filter.
+
+pub mod scenario;
+pub mod trace;
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::{
+    budget::{
+        hashmap_filter_storage::HashMapFilterStorage,
+        pure_dp_filter::PureDPBudget,
+        release_filter::PureDPBudgetReleaseFilter,
+        traits::{FilterCapacities, FilterStorage, ReleaseFilter},
+    },
+    error::PdsError,
+    pds::{
+        aliases::PpaEventStorage,
+        batch_pds::{BatchPrivateDataService, BatchedReport, BatchedRequest},
+        private_data_service::{PdsReport, PrivateDataService},
+        quotas::{FilterId, StaticCapacities},
+    },
+    queries::ppa_histogram::{PpaBucketKey, PpaHistogramRequest},
+    simulator::trace::{TraceQuery, TraceRecord},
+    util::hashmap::{HashMap, HashSet},
+};
+
+/// Batch PDS for PPA requests, using release filters.
+pub type BatchPpaPds = BatchPrivateDataService<
+    PpaHistogramRequest,
+    HashMapFilterStorage<
+        PureDPBudgetReleaseFilter,
+        StaticCapacities<FilterId, PureDPBudget>,
+    >,
+    PpaEventStorage,
+    PdsError,
+>;
+
+/// Outcome of a single query from the trace, for one of its queriers.
+#[derive(Debug, Clone, Serialize)]
+pub struct QueryResult {
+    pub query_id: u64,
+    pub querier_uri: String,
+
+    /// Whether the query was answered without dropping any epoch.
+    pub allocated: bool,
+
+    /// Filters that caused epochs to be dropped.
+    pub oob_filters: Vec<FilterId>,
+
+    /// Filtered (pre-noise) histogram.
+    pub bin_values: HashMap<PpaBucketKey, f64>,
+}
+
+/// Budget consumed by a filter at the end of the simulation.
+#[derive(Debug, Clone, Serialize)]
+pub struct FilterUtilization {
+    pub filter_id: FilterId,
+    pub capacity: PureDPBudget,
+    pub consumed: PureDPBudget,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SimulationResults {
+    /// Query results, in the order the reports were produced.
+    pub queries: Vec<QueryResult>,
+
+    /// Utilization of every filter that queries could deduct from.
+    pub filters: Vec<FilterUtilization>,
+}
+
+impl SimulationResults {
+    /// Fraction of queries that were allocated.
+    pub fn allocation_rate(&self) -> f64 {
+        if self.queries.is_empty() {
+            return 0.0;
+        }
+        let n_allocated = self.queries.iter().filter(|q| q.allocated).count();
+        n_allocated as f64 / self.queries.len() as f64
+    }
+
+    /// Number of times each type of filter caused an epoch to be dropped,
+    /// e.g. `PerQuerier`.
+    pub fn oob_filter_counts(&self) -> BTreeMap<&'static str, usize> {
+        let mut counts = BTreeMap::new();
+        for filter_id in self.queries.iter().flat_map(|q| &q.oob_filters) {
+            *counts.entry(filter_kind(filter_id)).or_default() += 1;
+        }
+        counts
+    }
+
+    /// Average fraction of the capacity consumed by Global filters with
+    /// finite capacity.
+    pub fn global_utilization(&self) -> f64 {
+        let utilizations = self
+            .filters
+            .iter()
+            .filter(|f| matches!(f.filter_id, FilterId::Global(_)))
+            .filter(|f| f.capacity.is_finite() && f.capacity > 0.0)
+            .map(|f| f.consumed / f.capacity)
+            .collect::<Vec<_>>();
+        if utilizations.is_empty() {
+            return 0.0;
+        }
+        utilizations.iter().sum::<f64>() / utilizations.len() as f64
+    }
+}
+
+/// Replays the trace on a regular PDS, answering queries as they arrive.
+pub fn simulate<FS>(
+    trace: &[TraceRecord],
+    pds: &mut PrivateDataService<
+        PpaHistogramRequest,
+        FS,
+        PpaEventStorage,
+        PdsError,
+    >,
+) -> Result<SimulationResults>
+where
+    FS: FilterStorage<
+        FilterId = FilterId,
+        Budget = PureDPBudget,
+        Error = PdsError,
+    >,
+{
+    let mut results = SimulationResults::default();
+    let mut filter_ids = HashSet::new();
+
+    for record in trace {
+        match record {
+            TraceRecord::Event(event) => pds.register_event(event.clone())?,
+            TraceRecord::Query(query) => {
+                filter_ids.extend(query_filter_ids(query));
+                // Every querier of the query gets the same report.
+                let report = pds.compute_report(&query.to_request()?)?;
+                for querier_uri in &query.uris.querier_uris {
+                    results.queries.push(query_result(
+                        query.id,
+                        querier_uri.clone(),
+                        report.clone(),
+                    ));
+                }
+            }
+        }
+    }
+
+    results.filters =
+        filter_utilization(&mut pds.core.filter_storage, filter_ids)?;
+    Ok(results)
+}
+
+/// Replays the trace on a batch PDS. Queries are grouped in scheduling
+/// intervals of `interval_duration` based on their timestamp, and each one
+/// goes through `n_scheduling_attempts` batches, or is answered in real time
+/// if that is 0.
+pub fn simulate_batch(
+    trace: &[TraceRecord],
+    batch_pds: &mut BatchPpaPds,
+    interval_duration: u64,
+    n_scheduling_attempts: u64,
+) -> Result<SimulationResults> {
+    let mut results = SimulationResults::default();
+    let mut filter_ids = HashSet::new();
+
+    for record in trace {
+        match record {
+            TraceRecord::Event(event) => {
+                batch_pds.pds.register_event(event.clone())?
+            }
+            TraceRecord::Query(query) => {
+                // Run the batches for all the intervals that ended before
+                // this query arrived.
+                let interval = query.timestamp / interval_duration;
+                while batch_pds.current_scheduling_interval < interval {
+                    collect_batch(batch_pds, &mut results)?;
+                }
+
+                filter_ids.extend(query_filter_ids(query));
+                let real_time_reports =
+                    batch_pds.register_report_request(BatchedRequest::new(
+                        query.id,
+                        n_scheduling_attempts,
+                        query.to_request()?,
+                    ))?;
+                results.queries.extend(
+                    real_time_reports.into_iter().map(batched_query_result),
+                );
+            }
+        }
+    }
+
+    // Flush the remaining requests.
+    for _ in 0..n_scheduling_attempts {
+        collect_batch(batch_pds, &mut results)?;
+    }
+
+    results.filters = release_filter_utilization(
+        &mut batch_pds.pds.core.filter_storage,
+        filter_ids,
+    )?;
+    Ok(results)
+}
+
+fn collect_batch(
+    batch_pds: &mut BatchPpaPds,
+    results: &mut SimulationResults,
+) -> Result<()> {
+    results.queries.extend(
+        batch_pds
+            .schedule_batch()?
+            .into_iter()
+            .map(batched_query_result),
+    );
+    Ok(())
+}
+
+fn batched_query_result(
+    batched_report: BatchedReport<PpaHistogramRequest>,
+) -> QueryResult {
+    query_result(
+        batched_report.request_id,
+        batched_report.querier_uri,
+        batched_report.report,
+    )
+}
+
+fn query_result(
+    query_id: u64,
+    querier_uri: String,
+    report: PdsReport<PpaHistogramRequest>,
+) -> QueryResult {
+    QueryResult {
+        query_id,
+        querier_uri,
+        allocated: !report.status.is_out_of_budget(),
+        oob_filters: report.oob_filters,
+        bin_values: report.filtered_report.bin_values,
+    }
+}
+
+/// Name of the type of a filter.
+fn filter_kind(filter_id: &FilterId) -> &'static str {
+    match filter_id {
+        FilterId::PerQuerier(..) => "PerQuerier",
+        FilterId::Global(..) => "Global",
+        FilterId::TriggerQuota(..) => "TriggerQuota",
+        FilterId::SourceQuota(..) => "SourceQuota",
+        FilterId::CampaignQuota(..) => "CampaignQuota",
+        FilterId::SourceTriggerQuota(..) => "SourceTriggerQuota",
+        FilterId::Ldp(..) => "Ldp",
+        FilterId::Introspection(..) => "Introspection",
+    }
+}
+
+/// All the filters a query can deduct from.
+fn query_filter_ids(query: &TraceQuery) -> Vec<FilterId> {
+    let uris = &query.uris;
+    let mut filter_ids = vec![];
+    for epoch_id in query.config.start_epoch..=query.config.end_epoch {
+        filter_ids.push(FilterId::Global(epoch_id));
+        filter_ids
+            .push(FilterId::TriggerQuota(epoch_id, uris.trigger_uri.clone()));
+        for querier_uri in &uris.querier_uris {
+            filter_ids
+                .push(FilterId::PerQuerier(epoch_id, querier_uri.clone()));
+            if let Some(campaign_id) = uris.campaign_id {
+                filter_ids.push(FilterId::CampaignQuota(
+                    epoch_id,
+                    querier_uri.clone(),
+                    campaign_id,
+                ));
+            }
+        }
+        for source_uri in &uris.source_uris {
+            filter_ids
+                .push(FilterId::SourceQuota(epoch_id, source_uri.clone()));
+        }
+    }
+    filter_ids
+}
+
+fn filter_utilization<FS>(
+    filter_storage: &mut FS,
+    filter_ids: HashSet<FilterId>,
+) -> Result<Vec<FilterUtilization>>
+where
+    FS: FilterStorage<
+        FilterId = FilterId,
+        Budget = PureDPBudget,
+        Error = PdsError,
+    >,
+{
+    let mut utilization = vec![];
+    for filter_id in filter_ids {
+        let capacity = filter_storage.capacities().capacity(&filter_id)?;
+        let remaining = filter_storage.remaining_budget(&filter_id)?;
+        utilization.push(FilterUtilization {
+            filter_id,
+            capacity,
+            consumed: capacity - remaining,
+        });
+    }
+    Ok(utilization)
+}
+
+/// Same as `filter_utilization`, for release filters. Their remaining budget
+/// only covers the unlocked budget, so the consumed budget is read directly.
+fn release_filter_utilization<FS>(
+    filter_storage: &mut FS,
+    filter_ids: HashSet<FilterId>,
+) -> Result<Vec<FilterUtilization>>
+where
+    FS: FilterStorage<
+        FilterId = FilterId,
+        Budget = PureDPBudget,
+        Error = PdsError,
+    >,
+    FS::Filter: ReleaseFilter<PureDPBudget>,
+{
+    let mut utilization = vec![];
+    for filter_id in filter_ids {
+        let filter = filter_storage.get_filter_or_new(&filter_id)?;
+        utilization.push(FilterUtilization {
+            filter_id,
+            capacity: filter.get_capacity()?,
+            consumed: filter.consumed()?,
+        });
+    }
+    Ok(utilization)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        pds::aliases::{PpaFilterStorage, PpaPds},
+        simulator::trace::parse_trace,
+    };
+
+    const TRACE: &str = "
+        # Two impressions on blog.com, then three conversions on shoes.com.
+        event,1,10,1,blog.com,shoes.com,adtech.com,1,0
+        event,2,20,1,blog.com,shoes.com,adtech.com,2,0
+        query,1,30,1,1,shoes.com,blog.com,adtech.com,1,1,1,4
+        query,2,40,1,1,shoes.com,blog.com,adtech.com,1,1,1,4
+        query,3,50,1,1,shoes.com,blog.com,adtech.com,1,1,1,4
+    ";
+
+    #[test]
+    fn test_simulate() -> Result<()> {
+        let trace = parse_trace(TRACE.as_bytes())?;
+        assert_eq!(trace.len(), 5);
+
+        // Per-querier capacity only fits two queries.
+        let capacities = StaticCapacities::new(2.0, 20.0, 10.0, 10.0);
+        let filters = PpaFilterStorage::new(capacities)?;
+        let mut pds = PpaPds::<_>::new(filters, PpaEventStorage::new());
+
+        let results = simulate(&trace, &mut pds)?;
+        let allocated = results
+            .queries
+            .iter()
+            .map(|q| q.allocated)
+            .collect::<Vec<_>>();
+        assert_eq!(allocated, vec![true, true, false]);
+        assert_eq!(results.queries[0].bin_values.get(&2), Some(&1.0));
+        assert_eq!(results.global_utilization(), 2.0 / 20.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_simulate_batch() -> Result<()> {
+        let trace = parse_trace(TRACE.as_bytes())?;
+
+        let capacities = StaticCapacities::new(2.0, 20.0, 10.0, 10.0);
+        let filters = HashMapFilterStorage::new(capacities)?;
+        let pds = PrivateDataService::new(filters, PpaEventStorage::new());
+        let mut batch_pds = BatchPpaPds::new(pds, 1)?;
+
+        let results = simulate_batch(&trace, &mut batch_pds, 100, 1)?;
+        assert_eq!(results.queries.len(), 3);
+        assert!((results.allocation_rate() - 2.0 / 3.0).abs() < 1e-9);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_trace() {
+        assert!(parse_trace("event,1,2".as_bytes()).is_err());
+        assert!(parse_trace("query,a,1,1,1,t,s,q,1,1,1,4".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,204 @@
+//! JSON scenarios for the `pdslib-sim` binary: capacities, a trace, and
+//! optionally the parameters of the batch scheduler, e.g.
+//! ```json
+//! {
+//!     "capacities": {
+//!         "per_querier": 2.0,
+//!         "global": 20.0,
+//!         "trigger_quota": 10.0,
+//!         "source_quota": 10.0
+//!     },
+//!     "trace": [
+//!         "event,1,10,1,blog.com,shoes.com,adtech.com,1,0",
+//!         "query,1,30,1,1,shoes.com,blog.com,adtech.com,1,1,1,4"
+//!     ],
+//!     "batch": {
+//!         "n_releases": 1,
+//!         "interval_duration": 100,
+//!         "n_scheduling_attempts": 1
+//!     }
+//! }
+//! ```
+//! Trace lines use the format of `trace`, and can also be read from
+//! `trace_file`, relative to the scenario. Without `batch`, queries are
+//! answered as they arrive by a regular PDS.
+
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use super::{
+    simulate, simulate_batch,
+    trace::{parse_trace, TraceRecord},
+    BatchPpaPds, SimulationResults,
+};
+use crate::{
+    budget::{
+        hashmap_filter_storage::HashMapFilterStorage,
+        pure_dp_filter::PureDPBudget, traits::FilterStorage,
+    },
+    pds::{
+        aliases::{PpaEventStorage, PpaFilterStorage, PpaPds},
+        private_data_service::PrivateDataService,
+        quotas::{FilterId, StaticCapacities},
+    },
+};
+
+/// Capacity of each type of filter.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScenarioCapacities {
+    pub per_querier: f64,
+    pub global: f64,
+    pub trigger_quota: f64,
+    pub source_quota: f64,
+    pub campaign_quota: Option<f64>,
+}
+
+/// Parameters of the batch scheduler, see `simulate_batch`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchScenario {
+    /// Number of scheduling intervals over which the Global filter is
+    /// released.
+    pub n_releases: usize,
+    pub interval_duration: u64,
+    pub n_scheduling_attempts: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    pub capacities: ScenarioCapacities,
+
+    /// Trace lines, replayed before the ones of `trace_file`.
+    #[serde(default)]
+    pub trace: Vec<String>,
+
+    pub trace_file: Option<PathBuf>,
+
+    /// Runs the batch scheduler if set, a regular PDS otherwise.
+    pub batch: Option<BatchScenario>,
+}
+
+impl Scenario {
+    /// Reads a scenario from a JSON file. A relative `trace_file` is resolved
+    /// against the directory of the scenario.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Can't open scenario {path:?}"))?;
+        let mut scenario: Scenario =
+            serde_json::from_reader(BufReader::new(file))
+                .with_context(|| format!("Invalid scenario {path:?}"))?;
+        if let (Some(trace_file), Some(dir)) =
+            (&scenario.trace_file, path.parent())
+        {
+            scenario.trace_file = Some(dir.join(trace_file));
+        }
+        Ok(scenario)
+    }
+
+    /// All the records of the scenario, in replay order.
+    pub fn records(&self) -> Result<Vec<TraceRecord>> {
+        let mut records = parse_trace(self.trace.join("\n").as_bytes())?;
+        if let Some(trace_file) = &self.trace_file {
+            let file = File::open(trace_file)
+                .with_context(|| format!("Can't open trace {trace_file:?}"))?;
+            records.extend(parse_trace(BufReader::new(file))?);
+        }
+        Ok(records)
+    }
+
+    /// Capacities of the filters of the simulated PDS.
+    pub fn static_capacities(
+        &self,
+    ) -> StaticCapacities<FilterId, PureDPBudget> {
+        let capacities = &self.capacities;
+        let static_capacities = StaticCapacities::new(
+            capacities.per_querier,
+            capacities.global,
+            capacities.trigger_quota,
+            capacities.source_quota,
+        );
+        match capacities.campaign_quota {
+            Some(campaign_quota) => {
+                static_capacities.with_campaign_quota(campaign_quota)
+            }
+            None => static_capacities,
+        }
+    }
+
+    /// Replays the scenario on a fresh PDS.
+    pub fn run(&self) -> Result<SimulationResults> {
+        let records = self.records()?;
+        let capacities = self.static_capacities();
+        match &self.batch {
+            None => {
+                let filters = PpaFilterStorage::new(capacities)?;
+                let mut pds = PpaPds::<_>::new(filters, PpaEventStorage::new());
+                simulate(&records, &mut pds)
+            }
+            Some(batch) => {
+                let filters = HashMapFilterStorage::new(capacities)?;
+                let pds =
+                    PrivateDataService::new(filters, PpaEventStorage::new());
+                let mut batch_pds = BatchPpaPds::new(pds, batch.n_releases)?;
+                simulate_batch(
+                    &records,
+                    &mut batch_pds,
+                    batch.interval_duration,
+                    batch.n_scheduling_attempts,
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_scenario() -> Result<()> {
+        let mut scenario: Scenario = serde_json::from_str(
+            r#"{
+                "capacities": {
+                    "per_querier": 2.0,
+                    "global": 20.0,
+                    "trigger_quota": 10.0,
+                    "source_quota": 10.0
+                },
+                "trace": [
+                    "event,1,10,1,blog.com,shoes.com,adtech.com,1,0",
+                    "query,1,30,1,1,shoes.com,blog.com,adtech.com,1,1,1,4",
+                    "query,2,40,1,1,shoes.com,blog.com,adtech.com,1,1,1,4",
+                    "query,3,50,1,1,shoes.com,blog.com,adtech.com,1,1,1,4"
+                ]
+            }"#,
+        )?;
+        let results = scenario.run()?;
+        assert!((results.allocation_rate() - 2.0 / 3.0).abs() < 1e-9);
+        assert_eq!(results.oob_filter_counts().get("PerQuerier"), Some(&1));
+
+        scenario.batch = Some(BatchScenario {
+            n_releases: 1,
+            interval_duration: 100,
+            n_scheduling_attempts: 1,
+        });
+        let results = scenario.run()?;
+        assert_eq!(results.queries.len(), 3);
+
+        // Typos in the scenario are rejected.
+        assert!(serde_json::from_str::<Scenario>(
+            r#"{"capacities": {"per_querier": 1.0, "global": 1.0, "trigger_quota": 1.0, "source_quota": 1.0, "golbal": 1.0}}"#
+        )
+        .is_err());
+
+        Ok(())
+    }
+}
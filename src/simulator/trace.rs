@@ -0,0 +1,137 @@
+//! Plain-text traces of events and queries, one record per line.
+//!
+//! Each line is a comma-separated record, starting with its kind:
+//! ```text
+//! event,<id>,<timestamp>,<epoch>,<source>,<triggers>,<queriers>,<histogram_index>,<filter_data>
+//! query,<id>,<timestamp>,<start_epoch>,<end_epoch>,<trigger>,<sources>,<queriers>,<attributable_value>,<max_attributable_value>,<epsilon>,<histogram_size>
+//! ```
+//! Lists of URIs are separated by `;`. Empty lines and lines starting with
+//! `#` are ignored. Records are replayed in the order of the file.
+
+use std::{io::BufRead, str::FromStr};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    events::{ppa_event::PpaEvent, traits::EventUris},
+    queries::{
+        ppa_histogram::{
+            FilterDataPredicate, PpaHistogramConfig, PpaHistogramRequest,
+            PpaRelevantEventSelector, RequestedBuckets,
+        },
+        traits::ReportRequestUris,
+    },
+};
+
+#[derive(Debug, Clone)]
+pub enum TraceRecord {
+    Event(PpaEvent),
+    Query(TraceQuery),
+}
+
+/// A query from the trace. Turned into a `PpaHistogramRequest` when it is
+/// replayed.
+#[derive(Debug, Clone)]
+pub struct TraceQuery {
+    pub id: u64,
+    pub timestamp: u64,
+    pub config: PpaHistogramConfig,
+    pub uris: ReportRequestUris<String>,
+}
+
+impl TraceQuery {
+    /// Builds the request for this query, relevant for all the events
+    /// matching its URIs.
+    pub fn to_request(&self) -> Result<PpaHistogramRequest> {
+        let request = PpaHistogramRequest::from_config(
+            &self.config,
+            PpaRelevantEventSelector {
+                report_request_uris: self.uris.clone(),
+                is_matching_event: FilterDataPredicate::Any,
+                requested_buckets: RequestedBuckets::AllBuckets,
+                lookback: None,
+            },
+        )?;
+        Ok(request)
+    }
+}
+
+/// Parses a whole trace.
+pub fn parse_trace(reader: impl BufRead) -> Result<Vec<TraceRecord>> {
+    let mut records = vec![];
+    for (line_number, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let record = parse_record(line).with_context(|| {
+            format!("Invalid trace line {}", line_number + 1)
+        })?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+fn parse_record(line: &str) -> Result<TraceRecord> {
+    let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+    match fields.as_slice() {
+        ["event", id, timestamp, epoch, source, triggers, queriers, histogram_index, filter_data] => {
+            Ok(TraceRecord::Event(PpaEvent {
+                id: parse(id)?,
+                timestamp: parse(timestamp)?,
+                epoch_number: parse(epoch)?,
+                histogram_index: parse(histogram_index)?,
+                uris: EventUris {
+                    source_uri: source.to_string(),
+                    trigger_uris: parse_uris(triggers),
+                    querier_uris: parse_uris(queriers),
+                },
+                filter_data: parse(filter_data)?,
+                priority: 0,
+            }))
+        }
+        ["query", id, timestamp, start_epoch, end_epoch, trigger, sources, queriers, attributable_value, max_attributable_value, epsilon, histogram_size] => {
+            Ok(TraceRecord::Query(TraceQuery {
+                id: parse(id)?,
+                timestamp: parse(timestamp)?,
+                config: PpaHistogramConfig {
+                    start_epoch: parse(start_epoch)?,
+                    end_epoch: parse(end_epoch)?,
+                    epochs: None,
+                    value_policy: None,
+                    lookback: None,
+                    epsilon_grid: None,
+                    attributable_value: parse(attributable_value)?,
+                    max_attributable_value: parse(max_attributable_value)?,
+                    requested_epsilon: parse(epsilon)?,
+                    histogram_size: parse(histogram_size)?,
+                },
+                uris: ReportRequestUris {
+                    trigger_uri: trigger.to_string(),
+                    source_uris: parse_uris(sources),
+                    querier_uris: parse_uris(queriers),
+                    campaign_id: None,
+                },
+            }))
+        }
+        _ => bail!("Unknown record kind or wrong number of fields: {line}"),
+    }
+}
+
+fn parse<T: FromStr>(field: &str) -> Result<T>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    field
+        .parse()
+        .with_context(|| format!("Invalid field: {field}"))
+}
+
+fn parse_uris(field: &str) -> Vec<String> {
+    field
+        .split(';')
+        .filter(|uri| !uri.is_empty())
+        .map(str::to_string)
+        .collect()
+}
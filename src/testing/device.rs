@@ -0,0 +1,255 @@
+//! [Experimental] Simulated device for scenario tests. A private data service
+//! driven by a mock clock: epochs follow the clock, events are registered
+//! relative to the current time, and requests can target the most recent
+//! epochs, so that tests don't have to hand-pick epoch numbers.
+
+use std::mem::take;
+
+use crate::{
+    budget::{pure_dp_filter::PureDPBudget, traits::FilterStorage},
+    error::PdsError,
+    events::{ppa_event::PpaEvent, traits::EventUris},
+    pds::{
+        aliases::{PpaEventStorage, PpaFilterStorage, PpaPds},
+        private_data_service::{PdsReport, PrivateDataService},
+        quotas::{FilterId, StaticCapacities},
+    },
+    queries::{
+        epoch_selection::EpochSelection,
+        ppa_histogram::{Lookback, PpaHistogramRequest},
+    },
+    util::clock::{Clock, MockClock},
+};
+
+/// Device whose epochs last `epoch_duration` seconds, starting with epoch 0
+/// at time 0.
+pub struct SimulatedDevice {
+    pub pds: PpaPds,
+
+    /// Shared with the PDS and its event storage.
+    pub clock: MockClock,
+
+    epoch_duration: u64,
+    next_event_id: u64,
+}
+
+impl SimulatedDevice {
+    /// Device at time 0, with empty filters and no events.
+    pub fn new(
+        capacities: StaticCapacities<FilterId, PureDPBudget>,
+        epoch_duration: u64,
+    ) -> Result<Self, PdsError> {
+        if epoch_duration == 0 {
+            return Err(PdsError::InvalidConfig(
+                "epochs must last at least one second".into(),
+            ));
+        }
+        let clock = MockClock::new(0);
+        let filters = PpaFilterStorage::new(capacities)?;
+        let events = PpaEventStorage::new().with_clock(clock.clone());
+        let mut pds =
+            PrivateDataService::new(filters, events).with_clock(clock.clone());
+        pds.set_current_epoch(0);
+        Ok(Self {
+            pds,
+            clock,
+            epoch_duration,
+            next_event_id: 0,
+        })
+    }
+
+    /// Configures the private data service, e.g. with a carryover policy.
+    pub fn with_pds(mut self, f: impl FnOnce(PpaPds) -> PpaPds) -> Self {
+        self.pds = f(self.pds);
+        self
+    }
+
+    /// Configures the event storage, e.g. with a retention policy.
+    pub fn with_event_storage(
+        mut self,
+        f: impl FnOnce(PpaEventStorage) -> PpaEventStorage,
+    ) -> Self {
+        self.pds.event_storage = f(take(&mut self.pds.event_storage));
+        self
+    }
+
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    pub fn current_epoch(&self) -> u64 {
+        self.clock.current_epoch(self.epoch_duration)
+    }
+
+    /// Moves the clock forward, and the current epoch of the PDS with it.
+    pub fn advance(&mut self, seconds: u64) {
+        self.clock.advance(seconds);
+        self.pds.set_current_epoch(self.current_epoch());
+    }
+
+    /// Moves the clock forward by whole epochs.
+    pub fn advance_epochs(&mut self, n_epochs: u64) {
+        self.advance(n_epochs * self.epoch_duration);
+    }
+
+    /// Event that happened `ago` seconds before now, in the epoch of its
+    /// timestamp. IDs follow the order in which events are created.
+    pub fn event(
+        &mut self,
+        ago: u64,
+        histogram_index: u64,
+        uris: EventUris<String>,
+    ) -> PpaEvent {
+        let timestamp = self.now().saturating_sub(ago);
+        let id = self.next_event_id;
+        self.next_event_id += 1;
+        PpaEvent {
+            id,
+            timestamp,
+            epoch_number: timestamp / self.epoch_duration,
+            histogram_index,
+            uris,
+            filter_data: 0,
+            priority: 0,
+        }
+    }
+
+    /// Registers an event that happened `ago` seconds before now, see
+    /// `event`, and returns it.
+    pub fn register_event(
+        &mut self,
+        ago: u64,
+        histogram_index: u64,
+        uris: EventUris<String>,
+    ) -> Result<PpaEvent, PdsError> {
+        let event = self.event(ago, histogram_index, uris);
+        self.pds.register_event(event.clone())?;
+        Ok(event)
+    }
+
+    /// The `n_epochs` most recent epochs, up to the current one.
+    pub fn last_epochs(&self, n_epochs: u64) -> EpochSelection {
+        let end = self.current_epoch();
+        EpochSelection::Range {
+            start: (end + 1).saturating_sub(n_epochs),
+            end,
+        }
+    }
+
+    /// Window of the last `duration` seconds, for a conversion happening now.
+    pub fn lookback(&self, duration: u64) -> Lookback {
+        Lookback {
+            trigger_time: self.now(),
+            duration,
+        }
+    }
+
+    pub fn compute_report(
+        &mut self,
+        request: &PpaHistogramRequest,
+    ) -> Result<PdsReport<PpaHistogramRequest>, PdsError> {
+        self.pds.compute_report(request)
+    }
+
+    /// Remaining budget of a filter.
+    /// WARNING: this is for tests only, devices never reveal it.
+    pub fn remaining_budget(
+        &mut self,
+        filter_id: &FilterId,
+    ) -> Result<PureDPBudget, PdsError> {
+        self.pds.core.filter_storage.remaining_budget(filter_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::{
+        pds::{private_data_service::ReportStatus, quotas::CarryoverPolicy},
+        queries::{
+            builder::PpaHistogramRequestBuilder,
+            ppa_histogram::{FilterDataPredicate, RequestedBuckets},
+            traits::ReportRequestUris,
+        },
+        util::hashmap::HashMap,
+    };
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    fn request(
+        device: &SimulatedDevice,
+        n_epochs: u64,
+        lookback: Option<Lookback>,
+    ) -> Result<PpaHistogramRequest> {
+        let mut builder = PpaHistogramRequestBuilder::new()
+            .epochs(device.last_epochs(n_epochs))?
+            .value(1.0, 1.0, 1.0)?;
+        if let Some(lookback) = lookback {
+            builder = builder.lookback(lookback);
+        }
+        Ok(builder
+            .uris(ReportRequestUris::mock())?
+            .selector(
+                FilterDataPredicate::Any,
+                8,
+                RequestedBuckets::AllBuckets,
+            )?
+            .build())
+    }
+
+    #[test]
+    fn test_simulated_device() -> Result<()> {
+        let mut device = SimulatedDevice::new(StaticCapacities::mock(), DAY)?;
+        assert!(SimulatedDevice::new(StaticCapacities::mock(), 0).is_err());
+
+        // An impression today, and another one two days later.
+        let first = device.register_event(0, 1, EventUris::mock())?;
+        device.advance_epochs(2);
+        device.advance(DAY / 2);
+        let second = device.register_event(DAY / 4, 2, EventUris::mock())?;
+        assert_eq!((first.epoch_number, second.epoch_number), (0, 2));
+        assert_eq!(device.pds.current_epoch, Some(2));
+        assert_eq!(
+            device.last_epochs(2),
+            EpochSelection::Range { start: 1, end: 2 }
+        );
+
+        // Last touch over the last 3 epochs picks the second impression,
+        // unless the lookback window excludes it.
+        let report = device.compute_report(&request(&device, 3, None)?)?;
+        assert_eq!(
+            report.filtered_report.bin_values,
+            HashMap::from_iter([(2, 1.0)])
+        );
+        let lookback = Some(device.lookback(DAY / 8));
+        let report = device.compute_report(&request(&device, 3, lookback)?)?;
+        assert!(matches!(report.status, ReportStatus::Null { .. }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_simulated_carryover() -> Result<()> {
+        let capacities = StaticCapacities::new(2.0, 20.0, 20.0, 20.0);
+        let mut device =
+            SimulatedDevice::new(capacities, DAY)?.with_pds(|pds| {
+                pds.with_carryover_policy(
+                    CarryoverPolicy::new(0.5, 1.0).unwrap(),
+                )
+            });
+        device.register_event(0, 1, EventUris::mock())?;
+        device.compute_report(&request(&device, 1, None)?)?;
+
+        // Pruning epoch 0 once it is over carries half of its unused budget
+        // over to epoch 1.
+        device.advance_epochs(1);
+        device.pds.prune_epochs(1)?;
+        let querier_uri = ReportRequestUris::mock().querier_uris[0].clone();
+        let filter_id = FilterId::PerQuerier(1, querier_uri);
+        assert_eq!(device.remaining_budget(&filter_id)?, 2.5);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,457 @@
+//! [Experimental] Fault injection for storages, to test how the private data
+//! service recovers from storage errors. The wrappers forward every call to
+//! the wrapped storage, but can fail the Nth operation from now, or every
+//! write to a given filter, with a `PdsError::StorageError`.
+//!
+//! Recovery semantics: a report that fails with a storage error consumes no
+//! budget. Filters charged before the failure are refunded, on a best-effort
+//! basis: if a refund fails too, the budget stays consumed, which is always
+//! safe for privacy.
+
+use std::cell::Cell;
+
+use crate::{
+    budget::{
+        reservation::{Reservation, ReservationToken},
+        traits::{EpochFilterId, FilterStorage},
+    },
+    error::PdsError,
+    events::traits::{Event, EventStorage, RelevantEventSelector},
+};
+
+/// Counts operations and fails the planned one.
+#[derive(Debug, Clone, Default)]
+struct FaultPlan {
+    n_operations: Cell<u64>,
+    failing_operation: Cell<Option<u64>>,
+}
+
+impl FaultPlan {
+    /// Counts an operation, and fails it if it is the planned one.
+    fn check(&self, operation: &str) -> Result<(), PdsError> {
+        let n = self.n_operations.get();
+        self.n_operations.set(n + 1);
+        if self.failing_operation.get() == Some(n) {
+            self.failing_operation.set(None);
+            return Err(PdsError::StorageError(format!(
+                "injected failure of {operation} (operation {n})"
+            )));
+        }
+        Ok(())
+    }
+
+    fn fail_operation(&self, n: u64) {
+        self.failing_operation
+            .set(Some(self.n_operations.get() + n));
+    }
+}
+
+/// Filter storage failing on demand.
+#[derive(Debug, Clone)]
+pub struct FaultyFilterStorage<FS: FilterStorage> {
+    pub inner: FS,
+    plan: FaultPlan,
+    failing_filters: Vec<FS::FilterId>,
+}
+
+impl<FS: FilterStorage> FaultyFilterStorage<FS>
+where
+    FS::FilterId: PartialEq,
+{
+    pub fn wrap(inner: FS) -> Self {
+        Self {
+            inner,
+            plan: FaultPlan::default(),
+            failing_filters: vec![],
+        }
+    }
+
+    /// Fails the `n`th operation from now, once. 0 fails the next operation.
+    pub fn fail_operation(&mut self, n: u64) {
+        self.plan.fail_operation(n);
+    }
+
+    /// Fails every write to the given filter, until the faults are cleared.
+    pub fn fail_writes_to(&mut self, filter_id: FS::FilterId) {
+        self.failing_filters.push(filter_id);
+    }
+
+    pub fn clear_faults(&mut self) {
+        self.plan.failing_operation.set(None);
+        self.failing_filters.clear();
+    }
+
+    /// Number of operations so far, e.g. to sweep over the operations of a
+    /// call.
+    pub fn n_operations(&self) -> u64 {
+        self.plan.n_operations.get()
+    }
+}
+
+impl<FS: FilterStorage> FilterStorage for FaultyFilterStorage<FS>
+where
+    FS::FilterId: PartialEq,
+    FS::Error: From<PdsError>,
+{
+    type FilterId = FS::FilterId;
+    type Budget = FS::Budget;
+    type Filter = FS::Filter;
+    type Capacities = FS::Capacities;
+    type Error = FS::Error;
+
+    fn new(capacities: Self::Capacities) -> Result<Self, Self::Error> {
+        Ok(Self::wrap(FS::new(capacities)?))
+    }
+
+    fn capacities(&self) -> &Self::Capacities {
+        self.inner.capacities()
+    }
+
+    fn set_capacities(
+        &mut self,
+        capacities: Self::Capacities,
+        tighten_existing: bool,
+    ) -> Result<(), Self::Error> {
+        self.plan.check("set_capacities")?;
+        self.inner.set_capacities(capacities, tighten_existing)
+    }
+
+    fn get_filter(
+        &mut self,
+        filter_id: &Self::FilterId,
+    ) -> Result<Option<Self::Filter>, Self::Error> {
+        self.plan.check("get_filter")?;
+        self.inner.get_filter(filter_id)
+    }
+
+    fn set_filter(
+        &mut self,
+        filter_id: &Self::FilterId,
+        filter: Self::Filter,
+    ) -> Result<(), Self::Error> {
+        self.plan.check("set_filter")?;
+        if self.failing_filters.contains(filter_id) {
+            return Err(PdsError::StorageError(format!(
+                "injected failure of set_filter for {filter_id:?}"
+            ))
+            .into());
+        }
+        self.inner.set_filter(filter_id, filter)
+    }
+
+    fn prune(
+        &mut self,
+        is_pruned: &dyn Fn(&Self::FilterId) -> bool,
+    ) -> Result<usize, Self::Error> {
+        self.plan.check("prune")?;
+        self.inner.prune(is_pruned)
+    }
+
+    fn is_empty(&self) -> Result<bool, Self::Error> {
+        self.plan.check("is_empty")?;
+        self.inner.is_empty()
+    }
+
+    fn pruned_before(
+        &self,
+    ) -> Option<<Self::FilterId as EpochFilterId>::EpochId>
+    where
+        Self::FilterId: EpochFilterId,
+    {
+        self.inner.pruned_before()
+    }
+
+    fn set_pruned_before(
+        &mut self,
+        epoch_id: <Self::FilterId as EpochFilterId>::EpochId,
+    ) -> Result<(), Self::Error>
+    where
+        Self::FilterId: EpochFilterId,
+    {
+        self.plan.check("set_pruned_before")?;
+        self.inner.set_pruned_before(epoch_id)
+    }
+
+    fn set_reservation(
+        &mut self,
+        token: ReservationToken,
+        reservation: Reservation<Self::FilterId, Self::Budget>,
+    ) -> Result<(), Self::Error> {
+        self.plan.check("set_reservation")?;
+        self.inner.set_reservation(token, reservation)
+    }
+
+    fn take_reservation(
+        &mut self,
+        token: ReservationToken,
+    ) -> Result<Option<Reservation<Self::FilterId, Self::Budget>>, Self::Error>
+    {
+        self.plan.check("take_reservation")?;
+        self.inner.take_reservation(token)
+    }
+
+    fn reservations(
+        &self,
+    ) -> Result<
+        Vec<(ReservationToken, Reservation<Self::FilterId, Self::Budget>)>,
+        Self::Error,
+    > {
+        self.plan.check("reservations")?;
+        self.inner.reservations()
+    }
+
+    fn set_repayment(
+        &mut self,
+        filter_id: &Self::FilterId,
+        budget: Self::Budget,
+    ) -> Result<(), Self::Error> {
+        self.plan.check("set_repayment")?;
+        self.inner.set_repayment(filter_id, budget)
+    }
+
+    fn take_repayment(
+        &mut self,
+        filter_id: &Self::FilterId,
+    ) -> Result<Option<Self::Budget>, Self::Error> {
+        self.plan.check("take_repayment")?;
+        self.inner.take_repayment(filter_id)
+    }
+
+    fn get_repayment(
+        &mut self,
+        filter_id: &Self::FilterId,
+    ) -> Result<Option<Self::Budget>, Self::Error> {
+        self.plan.check("get_repayment")?;
+        self.inner.get_repayment(filter_id)
+    }
+
+    fn set_carryover(
+        &mut self,
+        filter_id: &Self::FilterId,
+        budget: Self::Budget,
+    ) -> Result<(), Self::Error> {
+        self.plan.check("set_carryover")?;
+        self.inner.set_carryover(filter_id, budget)
+    }
+
+    fn get_carryover(
+        &mut self,
+        filter_id: &Self::FilterId,
+    ) -> Result<Option<Self::Budget>, Self::Error> {
+        self.plan.check("get_carryover")?;
+        self.inner.get_carryover(filter_id)
+    }
+
+    fn generation(&self) -> u64 {
+        self.inner.generation()
+    }
+}
+
+/// Event storage failing on demand.
+#[derive(Debug, Clone, Default)]
+pub struct FaultyEventStorage<ES> {
+    pub inner: ES,
+    plan: FaultPlan,
+}
+
+impl<ES> FaultyEventStorage<ES> {
+    pub fn wrap(inner: ES) -> Self {
+        Self {
+            inner,
+            plan: FaultPlan::default(),
+        }
+    }
+
+    /// Fails the `n`th operation from now, once. 0 fails the next operation.
+    pub fn fail_operation(&mut self, n: u64) {
+        self.plan.fail_operation(n);
+    }
+
+    pub fn clear_faults(&mut self) {
+        self.plan.failing_operation.set(None);
+    }
+
+    pub fn n_operations(&self) -> u64 {
+        self.plan.n_operations.get()
+    }
+}
+
+impl<ES: EventStorage> EventStorage for FaultyEventStorage<ES>
+where
+    ES::Error: From<PdsError>,
+{
+    type Event = ES::Event;
+    type Error = ES::Error;
+
+    fn add_event(&mut self, event: Self::Event) -> Result<(), Self::Error> {
+        self.plan.check("add_event")?;
+        self.inner.add_event(event)
+    }
+
+    fn events_for_epoch(
+        &self,
+        epoch_id: &<Self::Event as Event>::EpochId,
+    ) -> Result<impl Iterator<Item = &Self::Event>, Self::Error> {
+        self.plan.check("events_for_epoch")?;
+        self.inner.events_for_epoch(epoch_id)
+    }
+
+    fn relevant_events_for_epoch(
+        &self,
+        epoch_id: &<Self::Event as Event>::EpochId,
+        selector: &impl RelevantEventSelector<Event = Self::Event>,
+    ) -> Result<Vec<Self::Event>, Self::Error> {
+        self.plan.check("relevant_events_for_epoch")?;
+        self.inner.relevant_events_for_epoch(epoch_id, selector)
+    }
+
+    fn drop_stale_events(&mut self) -> Result<usize, Self::Error> {
+        self.plan.check("drop_stale_events")?;
+        self.inner.drop_stale_events()
+    }
+
+    fn generation(&self) -> u64 {
+        self.inner.generation()
+    }
+
+    fn is_retired(&self, epoch_id: &<Self::Event as Event>::EpochId) -> bool {
+        self.inner.is_retired(epoch_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        budget::{pure_dp_filter::PureDPBudget, traits::FilterCapacities},
+        events::{ppa_event::PpaEvent, traits::EventUris},
+        pds::{
+            aliases::{PpaEventStorage, PpaFilterStorage, PpaPds},
+            private_data_service::PrivateDataService,
+            quotas::{FilterId, StaticCapacities},
+        },
+        queries::{
+            builder::PpaHistogramRequestBuilder,
+            ppa_histogram::{
+                FilterDataPredicate, PpaHistogramRequest, RequestedBuckets,
+            },
+            traits::ReportRequestUris,
+        },
+    };
+
+    type FaultyPds = PpaPds<
+        FaultyFilterStorage<PpaFilterStorage>,
+        FaultyEventStorage<PpaEventStorage>,
+    >;
+
+    fn pds_with_event() -> Result<FaultyPds, PdsError> {
+        let filters = FaultyFilterStorage::new(StaticCapacities::mock())?;
+        let events = FaultyEventStorage::wrap(PpaEventStorage::new());
+        let mut pds = PrivateDataService::new(filters, events);
+        pds.register_event(PpaEvent {
+            id: 1,
+            timestamp: 0,
+            epoch_number: 1,
+            histogram_index: 3,
+            uris: EventUris::mock(),
+            filter_data: 1,
+            priority: 0,
+        })?;
+        Ok(pds)
+    }
+
+    fn request() -> Result<PpaHistogramRequest, PdsError> {
+        Ok(PpaHistogramRequestBuilder::new()
+            .epoch_range(1, 1)?
+            .value(1.0, 1.0, 0.5)?
+            .uris(ReportRequestUris::mock())?
+            .selector(
+                FilterDataPredicate::Any,
+                5,
+                RequestedBuckets::AllBuckets,
+            )?
+            .build())
+    }
+
+    fn filter_ids() -> Vec<FilterId> {
+        let uris = ReportRequestUris::mock();
+        vec![
+            FilterId::PerQuerier(1, uris.querier_uris[0].clone()),
+            FilterId::Global(1),
+            FilterId::TriggerQuota(1, uris.trigger_uri.clone()),
+            FilterId::SourceQuota(1, uris.source_uris[0].clone()),
+        ]
+    }
+
+    /// Asserts that no budget was consumed from the filters of the request.
+    fn assert_untouched(pds: &mut FaultyPds) -> Result<(), PdsError> {
+        pds.core.filter_storage.clear_faults();
+        for filter_id in filter_ids() {
+            let capacity =
+                pds.core.filter_storage.capacities().capacity(&filter_id)?;
+            let remaining =
+                pds.core.filter_storage.remaining_budget(&filter_id)?;
+            assert_eq!(remaining, capacity, "{filter_id:?} was charged");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_phase_2_failure_rolls_back() -> Result<(), PdsError> {
+        // Phase 1 passes, but the last filter can't be written.
+        let mut pds = pds_with_event()?;
+        let uris = ReportRequestUris::mock();
+        pds.core
+            .filter_storage
+            .fail_writes_to(FilterId::SourceQuota(
+                1,
+                uris.source_uris[0].clone(),
+            ));
+        let error = pds.compute_report(&request()?).unwrap_err();
+        assert!(matches!(error, PdsError::StorageError(_)));
+        assert_untouched(&mut pds)?;
+
+        // Once the storage is back, the request goes through.
+        let report = pds.compute_report(&request()?)?;
+        assert!(!report.filtered_report.bin_values.is_empty());
+        let remaining = pds
+            .core
+            .filter_storage
+            .remaining_budget(&FilterId::Global(1))?;
+        assert_eq!(remaining, PureDPBudget::from(19.5));
+        Ok(())
+    }
+
+    #[test]
+    fn test_every_filter_failure_rolls_back() -> Result<(), PdsError> {
+        // Fail each operation of the request in turn. Either the report goes
+        // through, or it fails without consuming anything.
+        let mut n = 0;
+        loop {
+            let mut pds = pds_with_event()?;
+            pds.core.filter_storage.fail_operation(n);
+            match pds.compute_report(&request()?) {
+                Ok(_) => break,
+                Err(error) => {
+                    assert!(matches!(error, PdsError::StorageError(_)));
+                    assert_untouched(&mut pds)?;
+                }
+            }
+            n += 1;
+        }
+        assert!(n > 0, "the request didn't touch the filters");
+        Ok(())
+    }
+
+    #[test]
+    fn test_event_storage_failure() -> Result<(), PdsError> {
+        let mut pds = pds_with_event()?;
+        pds.event_storage.fail_operation(0);
+        assert!(pds.compute_report(&request()?).is_err());
+        assert_untouched(&mut pds)?;
+
+        // Faults only fire once.
+        pds.compute_report(&request()?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+//! [Experimental] Fleet of simulated devices, each with its own private data
+//! service. Routes events and requests to devices by ID, and aggregates the
+//! per-device reports like an aggregation service would, before noise.
+
+use crate::{
+    budget::{pure_dp_filter::PureDPBudget, traits::FilterStorage},
+    error::PdsError,
+    events::traits::EventStorage,
+    pds::{
+        private_data_service::{PdsReport, PrivateDataService},
+        quotas::FilterId,
+    },
+    queries::{
+        histogram::{BucketKey, HistogramReport},
+        traits::EpochReportRequest,
+    },
+    reports::aggregation::sum_histograms,
+    util::hashmap::HashMap,
+};
+
+pub type DeviceId = u64;
+
+/// Creates the private data service of a device the first time it is used.
+pub type DeviceFactory<Q, FS, ES, ERR> =
+    Box<dyn Fn(DeviceId) -> Result<PrivateDataService<Q, FS, ES, ERR>, ERR>>;
+
+/// Many devices, each with an independent private data service.
+pub struct Fleet<Q, FS, ES, ERR>
+where
+    Q: EpochReportRequest,
+    FS: FilterStorage<
+        Budget = PureDPBudget,
+        FilterId = FilterId<Q::EpochId, Q::Uri>,
+    >,
+    ES: EventStorage<Event = Q::Event>,
+    ERR: From<FS::Error> + From<ES::Error> + From<PdsError>,
+{
+    /// Devices that received at least one event or request.
+    pub devices: HashMap<DeviceId, PrivateDataService<Q, FS, ES, ERR>>,
+
+    new_device: DeviceFactory<Q, FS, ES, ERR>,
+}
+
+/// Reports of the devices that answered a request.
+#[derive(Debug)]
+pub struct FleetReport<Q: EpochReportRequest> {
+    pub device_reports: Vec<(DeviceId, PdsReport<Q>)>,
+}
+
+impl<Q, FS, ES, ERR> Fleet<Q, FS, ES, ERR>
+where
+    Q: EpochReportRequest,
+    FS: FilterStorage<
+        Budget = PureDPBudget,
+        FilterId = FilterId<Q::EpochId, Q::Uri>,
+    >,
+    ES: EventStorage<Event = Q::Event>,
+    ERR: From<FS::Error> + From<ES::Error> + From<PdsError>,
+{
+    /// Creates an empty fleet. Devices are created by `new_device` the first
+    /// time they get an event or a request, so they can all share the same
+    /// configuration or get a different one based on their ID.
+    pub fn new(
+        new_device: impl Fn(DeviceId) -> Result<PrivateDataService<Q, FS, ES, ERR>, ERR>
+            + 'static,
+    ) -> Self {
+        Self {
+            devices: HashMap::new(),
+            new_device: Box::new(new_device),
+        }
+    }
+
+    /// Gets the private data service of a device, creating it if needed.
+    pub fn device(
+        &mut self,
+        device_id: DeviceId,
+    ) -> Result<&mut PrivateDataService<Q, FS, ES, ERR>, ERR> {
+        if !self.devices.contains_key(&device_id) {
+            let device = (self.new_device)(device_id)?;
+            self.devices.insert(device_id, device);
+        }
+        Ok(self.devices.get_mut(&device_id).unwrap())
+    }
+
+    /// Registers an event on the given device.
+    pub fn register_event(
+        &mut self,
+        device_id: DeviceId,
+        event: Q::Event,
+    ) -> Result<(), ERR> {
+        self.device(device_id)?.register_event(event)
+    }
+
+    /// Computes a report on the given device.
+    pub fn compute_report(
+        &mut self,
+        device_id: DeviceId,
+        request: &Q,
+    ) -> Result<PdsReport<Q>, ERR> {
+        self.device(device_id)?.compute_report(request)
+    }
+
+    /// Sends the same request to each of the given devices, e.g. all the
+    /// devices that converted, and collects their reports.
+    pub fn compute_reports(
+        &mut self,
+        device_ids: impl IntoIterator<Item = DeviceId>,
+        request: &Q,
+    ) -> Result<FleetReport<Q>, ERR> {
+        let mut device_reports = vec![];
+        for device_id in device_ids {
+            let report = self.compute_report(device_id, request)?;
+            device_reports.push((device_id, report));
+        }
+        Ok(FleetReport { device_reports })
+    }
+
+    /// Remaining budget of a filter on each device of the fleet.
+    /// WARNING: this is for experiments only, devices never reveal it.
+    pub fn remaining_budgets(
+        &mut self,
+        filter_id: &FilterId<Q::EpochId, Q::Uri>,
+    ) -> Result<HashMap<DeviceId, PureDPBudget>, ERR> {
+        let mut remaining_budgets = HashMap::new();
+        for (device_id, device) in self.devices.iter_mut() {
+            let remaining_budget =
+                device.core.filter_storage.remaining_budget(filter_id)?;
+            remaining_budgets.insert(*device_id, remaining_budget);
+        }
+        Ok(remaining_budgets)
+    }
+}
+
+impl<Q: EpochReportRequest> FleetReport<Q> {
+    /// Number of devices that dropped some epochs because of out-of-budget
+    /// filters.
+    pub fn n_out_of_budget(&self) -> usize {
+        self.device_reports
+            .iter()
+            .filter(|(_, report)| report.status.is_out_of_budget())
+            .count()
+    }
+}
+
+impl<Q, BK> FleetReport<Q>
+where
+    Q: EpochReportRequest<Report = HistogramReport<BK>>,
+    BK: BucketKey,
+{
+    /// Sums the filtered histograms of all the devices, i.e. the aggregate
+    /// the querier would get before noise.
+    pub fn aggregate_filtered(&self) -> HashMap<BK, f64> {
+        sum_histograms(
+            self.device_reports
+                .iter()
+                .map(|(_, report)| &report.filtered_report),
+        )
+    }
+
+    /// Sums the unfiltered histograms of all the devices, i.e. the aggregate
+    /// with infinite budget. Useful to measure the utility loss.
+    pub fn aggregate_unfiltered(&self) -> HashMap<BK, f64> {
+        sum_histograms(
+            self.device_reports
+                .iter()
+                .map(|(_, report)| &report.unfiltered_report),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::{
+        events::{ppa_event::PpaEvent, traits::EventUris},
+        pds::{
+            aliases::{PpaEventStorage, PpaFilterStorage},
+            quotas::StaticCapacities,
+        },
+        queries::{
+            builder::PpaHistogramRequestBuilder,
+            ppa_histogram::{FilterDataPredicate, RequestedBuckets},
+            traits::ReportRequestUris,
+        },
+    };
+
+    #[test]
+    fn test_fleet() -> Result<()> {
+        let mut fleet = Fleet::new(|_| {
+            let capacities = StaticCapacities::new(1.5, 20.0, 10.0, 10.0);
+            let filters = PpaFilterStorage::new(capacities)?;
+            Ok::<_, PdsError>(PrivateDataService::new(
+                filters,
+                PpaEventStorage::new(),
+            ))
+        });
+
+        // Devices 1 and 2 saw an impression, device 3 didn't.
+        for device_id in [1, 2] {
+            fleet.register_event(
+                device_id,
+                PpaEvent {
+                    id: device_id,
+                    timestamp: 0,
+                    epoch_number: 1,
+                    histogram_index: device_id,
+                    uris: EventUris::mock(),
+                    filter_data: 1,
+                    priority: 0,
+                },
+            )?;
+        }
+
+        let request = PpaHistogramRequestBuilder::new()
+            .epoch_range(1, 1)?
+            .value(1.0, 1.0, 1.0)?
+            .uris(ReportRequestUris::mock())?
+            .selector(
+                FilterDataPredicate::Any,
+                5,
+                RequestedBuckets::AllBuckets,
+            )?
+            .build();
+
+        let report = fleet.compute_reports([1, 2, 3], &request)?;
+        assert_eq!(fleet.devices.len(), 3);
+        assert_eq!(report.n_out_of_budget(), 0);
+        assert_eq!(
+            report.aggregate_filtered(),
+            HashMap::from_iter([(1, 1.0), (2, 1.0)])
+        );
+
+        // Only devices with events paid for the first report, so device 3
+        // can still answer the second one.
+        let report = fleet.compute_reports([1, 2, 3], &request)?;
+        assert_eq!(report.n_out_of_budget(), 2);
+        assert!(report.aggregate_filtered().is_empty());
+        assert_eq!(report.aggregate_unfiltered().len(), 2);
+
+        let querier_uri = ReportRequestUris::mock().querier_uris[0].clone();
+        let remaining_budgets =
+            fleet.remaining_budgets(&FilterId::PerQuerier(1, querier_uri))?;
+        assert_eq!(remaining_budgets[&1], 0.5);
+        assert_eq!(remaining_budgets[&3], 1.5);
+
+        Ok(())
+    }
+}
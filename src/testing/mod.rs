@@ -0,0 +1,5 @@
+//! [Experimental] Utilities to run end-to-end experiments on top of pdslib.
+
+pub mod device;
+pub mod faults;
+pub mod fleet;
@@ -0,0 +1,78 @@
+use std::{
+    cell::Cell,
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Source of time, in seconds since the Unix epoch. Components that need the
+/// time take a `Clock`, so that runs can be replayed with a `MockClock`.
+pub trait Clock {
+    /// Current time, in seconds since the Unix epoch.
+    fn now(&self) -> u64;
+
+    /// Index of the epoch containing the current time, for epochs of
+    /// `epoch_duration` seconds starting at the Unix epoch.
+    fn current_epoch(&self, epoch_duration: u64) -> u64 {
+        self.now() / epoch_duration
+    }
+}
+
+/// Clock reading the system time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default()
+    }
+}
+
+/// Clock that only moves when told to, for deterministic simulations.
+/// Clones share the same time.
+#[derive(Debug, Clone, Default)]
+pub struct MockClock {
+    now: Rc<Cell<u64>>,
+}
+
+impl MockClock {
+    pub fn new(now: u64) -> Self {
+        Self {
+            now: Rc::new(Cell::new(now)),
+        }
+    }
+
+    pub fn set(&self, now: u64) {
+        self.now.set(now);
+    }
+
+    pub fn advance(&self, seconds: u64) {
+        self.now.set(self.now.get() + seconds);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> u64 {
+        self.now.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new(10);
+        let shared_clock = clock.clone();
+
+        shared_clock.advance(15);
+        assert_eq!(clock.now(), 25);
+        assert_eq!(clock.current_epoch(10), 2);
+
+        clock.set(5);
+        assert_eq!(shared_clock.current_epoch(10), 0);
+    }
+}
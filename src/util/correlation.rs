@@ -0,0 +1,91 @@
+//! Correlation IDs for log records. Each call to
+//! `PrivateDataService::compute_report` or
+//! `BatchPrivateDataService::schedule_batch` runs in its own
+//! `CorrelationScope`, and the logging macros of this module attach the ID of
+//! the current scope to the records as a `correlation_id` key-value, so that
+//! interleaved requests can be untangled in experiments. Loggers only show
+//! it if they print key-values, e.g. with a JSON encoder.
+
+use std::{
+    cell::Cell,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Identifier of a top-level operation, unique within the process.
+pub type CorrelationId = u64;
+
+static NEXT_CORRELATION_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static CURRENT_CORRELATION_ID: Cell<Option<CorrelationId>> =
+        const { Cell::new(None) };
+}
+
+/// ID of the innermost scope on this thread, if any.
+pub fn current_correlation_id() -> Option<CorrelationId> {
+    CURRENT_CORRELATION_ID.with(Cell::get)
+}
+
+/// Sets the correlation ID of the current thread until dropped. Scopes nest:
+/// a `compute_report` inside a `schedule_batch` gets its own ID, logged with
+/// the ID of the batch, and the batch ID is restored when it returns.
+#[derive(Debug)]
+pub struct CorrelationScope {
+    id: CorrelationId,
+    parent: Option<CorrelationId>,
+}
+
+impl CorrelationScope {
+    /// Enters a scope with a fresh ID.
+    pub fn enter(operation: &str) -> Self {
+        let id = NEXT_CORRELATION_ID.fetch_add(1, Ordering::Relaxed);
+        let parent =
+            CURRENT_CORRELATION_ID.with(|current| current.replace(Some(id)));
+        log::debug!(
+            correlation_id = id, parent_correlation_id = parent;
+            "Starting {operation}"
+        );
+        Self { id, parent }
+    }
+
+    pub fn id(&self) -> CorrelationId {
+        self.id
+    }
+}
+
+impl Drop for CorrelationScope {
+    fn drop(&mut self) {
+        CURRENT_CORRELATION_ID.with(|current| current.set(self.parent));
+    }
+}
+
+/// `log::debug!` with the current correlation ID as a key-value.
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        log::debug!(
+            correlation_id = $crate::util::correlation::current_correlation_id();
+            $($arg)+
+        )
+    };
+}
+pub(crate) use debug;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nested_scopes() {
+        assert_eq!(current_correlation_id(), None);
+        let outer = CorrelationScope::enter("outer");
+        assert_eq!(current_correlation_id(), Some(outer.id()));
+        {
+            let inner = CorrelationScope::enter("inner");
+            assert_ne!(inner.id(), outer.id());
+            assert_eq!(current_correlation_id(), Some(inner.id()));
+        }
+        assert_eq!(current_correlation_id(), Some(outer.id()));
+        drop(outer);
+        assert_eq!(current_correlation_id(), None);
+    }
+}
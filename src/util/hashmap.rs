@@ -1,5 +1,284 @@
-#[cfg(not(feature = "ahash"))]
+#[cfg(all(
+    feature = "std",
+    not(feature = "ahash"),
+    not(feature = "deterministic")
+))]
 pub use std::collections::{HashMap, HashSet};
 
-#[cfg(feature = "ahash")]
+#[cfg(all(feature = "ahash", not(feature = "deterministic")))]
 pub use ahash::{AHashMap as HashMap, AHashSet as HashSet};
+#[cfg(feature = "deterministic")]
+pub use deterministic::{FixedState, HashMap, HashSet};
+// Without `std` there is no `RandomState`, so maps use the default hasher
+// of `hashbrown`.
+#[cfg(not(feature = "std"))]
+pub use hashbrown::{HashMap, HashSet};
+
+/// Maps and sets with a fixed hasher, so that iteration order, e.g. the order
+/// in which events are attributed or buckets are serialized, only depends on
+/// the inserted keys and not on a per-process random seed. Reports are then
+/// reproducible across runs. Same API as the `ahash` wrappers: everything
+/// else is reached through `Deref`.
+#[cfg(feature = "deterministic")]
+mod deterministic {
+    use std::{
+        borrow::Borrow,
+        collections::{self, hash_map::DefaultHasher},
+        fmt,
+        hash::{BuildHasherDefault, Hash},
+        ops::{Deref, DerefMut, Index},
+    };
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    /// SipHash with fixed keys.
+    pub type FixedState = BuildHasherDefault<DefaultHasher>;
+
+    #[derive(Clone)]
+    pub struct HashMap<K, V>(collections::HashMap<K, V, FixedState>);
+
+    #[derive(Clone)]
+    pub struct HashSet<T>(collections::HashSet<T, FixedState>);
+
+    impl<K, V> HashMap<K, V> {
+        pub fn new() -> Self {
+            Self(collections::HashMap::default())
+        }
+
+        pub fn with_capacity(capacity: usize) -> Self {
+            Self(collections::HashMap::with_capacity_and_hasher(
+                capacity,
+                FixedState::default(),
+            ))
+        }
+    }
+
+    impl<T> HashSet<T> {
+        pub fn new() -> Self {
+            Self(collections::HashSet::default())
+        }
+
+        pub fn with_capacity(capacity: usize) -> Self {
+            Self(collections::HashSet::with_capacity_and_hasher(
+                capacity,
+                FixedState::default(),
+            ))
+        }
+    }
+
+    impl<K, V> Default for HashMap<K, V> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<T> Default for HashSet<T> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<K, V> Deref for HashMap<K, V> {
+        type Target = collections::HashMap<K, V, FixedState>;
+
+        fn deref(&self) -> &Self::Target {
+            &self.0
+        }
+    }
+
+    impl<K, V> DerefMut for HashMap<K, V> {
+        fn deref_mut(&mut self) -> &mut Self::Target {
+            &mut self.0
+        }
+    }
+
+    impl<T> Deref for HashSet<T> {
+        type Target = collections::HashSet<T, FixedState>;
+
+        fn deref(&self) -> &Self::Target {
+            &self.0
+        }
+    }
+
+    impl<T> DerefMut for HashSet<T> {
+        fn deref_mut(&mut self) -> &mut Self::Target {
+            &mut self.0
+        }
+    }
+
+    impl<K: fmt::Debug, V: fmt::Debug> fmt::Debug for HashMap<K, V> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.0.fmt(f)
+        }
+    }
+
+    impl<T: fmt::Debug> fmt::Debug for HashSet<T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.0.fmt(f)
+        }
+    }
+
+    impl<K: Eq + Hash, V: PartialEq> PartialEq for HashMap<K, V> {
+        fn eq(&self, other: &Self) -> bool {
+            self.0 == other.0
+        }
+    }
+
+    impl<K: Eq + Hash, V: Eq> Eq for HashMap<K, V> {}
+
+    impl<T: Eq + Hash> PartialEq for HashSet<T> {
+        fn eq(&self, other: &Self) -> bool {
+            self.0 == other.0
+        }
+    }
+
+    impl<T: Eq + Hash> Eq for HashSet<T> {}
+
+    impl<K, Q, V> Index<&Q> for HashMap<K, V>
+    where
+        K: Eq + Hash + Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        type Output = V;
+
+        fn index(&self, key: &Q) -> &V {
+            &self.0[key]
+        }
+    }
+
+    impl<K: Eq + Hash, V> FromIterator<(K, V)> for HashMap<K, V> {
+        fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+            Self(collections::HashMap::from_iter(iter))
+        }
+    }
+
+    impl<T: Eq + Hash> FromIterator<T> for HashSet<T> {
+        fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+            Self(collections::HashSet::from_iter(iter))
+        }
+    }
+
+    impl<K: Eq + Hash, V, const N: usize> From<[(K, V); N]> for HashMap<K, V> {
+        fn from(entries: [(K, V); N]) -> Self {
+            Self::from_iter(entries)
+        }
+    }
+
+    impl<T: Eq + Hash, const N: usize> From<[T; N]> for HashSet<T> {
+        fn from(items: [T; N]) -> Self {
+            Self::from_iter(items)
+        }
+    }
+
+    impl<K: Eq + Hash, V> Extend<(K, V)> for HashMap<K, V> {
+        fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+            self.0.extend(iter)
+        }
+    }
+
+    impl<T: Eq + Hash> Extend<T> for HashSet<T> {
+        fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+            self.0.extend(iter)
+        }
+    }
+
+    impl<'a, K: Eq + Hash + Copy, V: Copy> Extend<(&'a K, &'a V)>
+        for HashMap<K, V>
+    {
+        fn extend<I: IntoIterator<Item = (&'a K, &'a V)>>(&mut self, iter: I) {
+            self.0.extend(iter)
+        }
+    }
+
+    impl<'a, T: Eq + Hash + Copy> Extend<&'a T> for HashSet<T> {
+        fn extend<I: IntoIterator<Item = &'a T>>(&mut self, iter: I) {
+            self.0.extend(iter)
+        }
+    }
+
+    impl<K, V> IntoIterator for HashMap<K, V> {
+        type Item = (K, V);
+        type IntoIter = collections::hash_map::IntoIter<K, V>;
+
+        fn into_iter(self) -> Self::IntoIter {
+            self.0.into_iter()
+        }
+    }
+
+    impl<'a, K, V> IntoIterator for &'a HashMap<K, V> {
+        type Item = (&'a K, &'a V);
+        type IntoIter = collections::hash_map::Iter<'a, K, V>;
+
+        fn into_iter(self) -> Self::IntoIter {
+            self.0.iter()
+        }
+    }
+
+    impl<'a, K, V> IntoIterator for &'a mut HashMap<K, V> {
+        type Item = (&'a K, &'a mut V);
+        type IntoIter = collections::hash_map::IterMut<'a, K, V>;
+
+        fn into_iter(self) -> Self::IntoIter {
+            self.0.iter_mut()
+        }
+    }
+
+    impl<T> IntoIterator for HashSet<T> {
+        type Item = T;
+        type IntoIter = collections::hash_set::IntoIter<T>;
+
+        fn into_iter(self) -> Self::IntoIter {
+            self.0.into_iter()
+        }
+    }
+
+    impl<'a, T> IntoIterator for &'a HashSet<T> {
+        type Item = &'a T;
+        type IntoIter = collections::hash_set::Iter<'a, T>;
+
+        fn into_iter(self) -> Self::IntoIter {
+            self.0.iter()
+        }
+    }
+
+    impl<K: Serialize, V: Serialize> Serialize for HashMap<K, V> {
+        fn serialize<S: Serializer>(
+            &self,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            self.0.serialize(serializer)
+        }
+    }
+
+    impl<'de, K, V> Deserialize<'de> for HashMap<K, V>
+    where
+        K: Deserialize<'de> + Eq + Hash,
+        V: Deserialize<'de>,
+    {
+        fn deserialize<D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Self, D::Error> {
+            collections::HashMap::deserialize(deserializer).map(Self)
+        }
+    }
+
+    impl<T: Serialize> Serialize for HashSet<T> {
+        fn serialize<S: Serializer>(
+            &self,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            self.0.serialize(serializer)
+        }
+    }
+
+    impl<'de, T> Deserialize<'de> for HashSet<T>
+    where
+        T: Deserialize<'de> + Eq + Hash,
+    {
+        fn deserialize<D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Self, D::Error> {
+            collections::HashSet::deserialize(deserializer).map(Self)
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! URI interning. Large simulations clone the same few URIs into every event,
+//! filter ID and request, so `InternedUri` replaces each of them by a 4-byte
+//! symbol that is cheap to copy, compare and hash. It can be used as the URI
+//! type `U` anywhere a `String` would be.
+
+use std::{
+    fmt,
+    sync::{OnceLock, RwLock},
+};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::util::hashmap::HashMap;
+
+/// Symbol table shared by all the `InternedUri`s of the process.
+#[derive(Debug, Default)]
+struct Interner {
+    symbols: HashMap<&'static str, u32>,
+    strings: Vec<&'static str>,
+}
+
+fn interner() -> &'static RwLock<Interner> {
+    static INTERNER: OnceLock<RwLock<Interner>> = OnceLock::new();
+    INTERNER.get_or_init(Default::default)
+}
+
+/// URI stored once in a process-wide interner. Two `InternedUri`s are equal
+/// if and only if they were interned from the same string.
+///
+/// NOTE: interned strings are never freed, so only intern URIs from a bounded
+/// set, such as the sites of a simulation.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InternedUri(u32);
+
+impl InternedUri {
+    /// Interns `uri`, or returns the existing symbol if it was already
+    /// interned.
+    pub fn new(uri: &str) -> Self {
+        if let Some(symbol) = interner().read().unwrap().symbols.get(uri) {
+            return InternedUri(*symbol);
+        }
+
+        // Another thread might have interned the URI since we released the
+        // read lock, so check again.
+        let mut interner = interner().write().unwrap();
+        if let Some(symbol) = interner.symbols.get(uri) {
+            return InternedUri(*symbol);
+        }
+        let symbol = u32::try_from(interner.strings.len())
+            .expect("more than u32::MAX interned URIs");
+        let uri: &'static str = Box::leak(uri.into());
+        interner.strings.push(uri);
+        interner.symbols.insert(uri, symbol);
+        InternedUri(symbol)
+    }
+
+    /// Returns the interned symbol, which only identifies the URI within
+    /// this process.
+    pub fn symbol(&self) -> u32 {
+        self.0
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        interner().read().unwrap().strings[self.0 as usize]
+    }
+}
+
+impl From<&str> for InternedUri {
+    fn from(uri: &str) -> Self {
+        InternedUri::new(uri)
+    }
+}
+
+impl From<&String> for InternedUri {
+    fn from(uri: &String) -> Self {
+        InternedUri::new(uri)
+    }
+}
+
+impl From<String> for InternedUri {
+    fn from(uri: String) -> Self {
+        InternedUri::new(&uri)
+    }
+}
+
+impl From<InternedUri> for String {
+    fn from(uri: InternedUri) -> Self {
+        uri.as_str().to_string()
+    }
+}
+
+impl fmt::Debug for InternedUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for InternedUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Serialized as the URI string, since symbols are process-specific.
+impl Serialize for InternedUri {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for InternedUri {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        let uri = String::deserialize(deserializer)?;
+        Ok(InternedUri::new(&uri))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interned_uri() {
+        let uri = InternedUri::new("interner-test.com");
+        assert_eq!(uri, InternedUri::from("interner-test.com".to_string()));
+        assert_ne!(uri, InternedUri::new("other-interner-test.com"));
+        assert_eq!(uri.as_str(), "interner-test.com");
+        assert_eq!(format!("{uri:?}"), "\"interner-test.com\"");
+        assert_eq!(std::mem::size_of::<InternedUri>(), 4);
+
+        // Symbols never leave the process.
+        let json = serde_json::to_string(&uri).unwrap();
+        assert_eq!(json, "\"interner-test.com\"");
+        let uri2: InternedUri = serde_json::from_str(&json).unwrap();
+        assert_eq!(uri2.symbol(), uri.symbol());
+    }
+}
@@ -0,0 +1,72 @@
+//! Float functions that are only in `std`, with a `libm` fallback for the
+//! `no_std` core.
+
+#[cfg(feature = "std")]
+pub fn exp(x: f64) -> f64 {
+    x.exp()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn exp(x: f64) -> f64 {
+    libm::exp(x)
+}
+
+#[cfg(feature = "std")]
+pub fn ln(x: f64) -> f64 {
+    x.ln()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn ln(x: f64) -> f64 {
+    libm::log(x)
+}
+
+#[cfg(feature = "std")]
+pub fn sqrt(x: f64) -> f64 {
+    x.sqrt()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn sqrt(x: f64) -> f64 {
+    libm::sqrt(x)
+}
+
+#[cfg(feature = "std")]
+pub fn round(x: f64) -> f64 {
+    x.round()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn round(x: f64) -> f64 {
+    libm::round(x)
+}
+
+#[cfg(feature = "std")]
+pub fn powi(x: f64, n: i32) -> f64 {
+    x.powi(n)
+}
+
+#[cfg(not(feature = "std"))]
+pub fn powi(x: f64, n: i32) -> f64 {
+    libm::pow(x, f64::from(n))
+}
+
+#[cfg(feature = "std")]
+pub fn floor(x: f64) -> f64 {
+    x.floor()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn floor(x: f64) -> f64 {
+    libm::floor(x)
+}
+
+#[cfg(feature = "std")]
+pub fn ceil(x: f64) -> f64 {
+    x.ceil()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn ceil(x: f64) -> f64 {
+    libm::ceil(x)
+}
@@ -1,2 +1,16 @@
+#[cfg(feature = "std")]
+pub mod clock;
+#[cfg(feature = "std")]
+pub mod correlation;
 pub mod hashmap;
+#[cfg(feature = "std")]
+pub mod interner;
+pub mod math;
+#[cfg(feature = "std")]
+pub mod oracle;
+pub mod parallel;
+pub mod rng;
+#[cfg(feature = "std")]
+pub mod spans;
+#[cfg(feature = "std")]
 pub mod tests;
@@ -0,0 +1,64 @@
+use std::hash::Hash;
+
+use crate::{
+    budget::{pure_dp_filter::PureDPBudget, traits::FilterCapacities},
+    pds::quotas::PdsFilterStatus,
+    util::hashmap::HashMap,
+};
+
+/// Reference accountant for testing. Tracks consumed budget with plain
+/// floats, and follows the atomic semantics of
+/// `PrivateDataServiceCore::deduct_budget`: either all the filters can
+/// consume their loss and they all do, or none of them consumes anything.
+#[derive(Debug)]
+pub struct OracleAccountant<C: FilterCapacities> {
+    capacities: C,
+    consumed: HashMap<C::FilterId, PureDPBudget>,
+}
+
+impl<C> OracleAccountant<C>
+where
+    C: FilterCapacities<Budget = PureDPBudget>,
+    C::FilterId: Clone + Hash,
+{
+    pub fn new(capacities: C) -> Self {
+        Self {
+            capacities,
+            consumed: HashMap::new(),
+        }
+    }
+
+    /// Budget consumed so far by the given filter.
+    pub fn consumed(&self, filter_id: &C::FilterId) -> PureDPBudget {
+        self.consumed.get(filter_id).copied().unwrap_or_default()
+    }
+
+    /// IDs of all the filters that have been charged so far.
+    pub fn filter_ids(&self) -> impl Iterator<Item = &C::FilterId> {
+        self.consumed.keys()
+    }
+
+    /// Deducts the losses from the filters, atomically.
+    #[allow(clippy::type_complexity)]
+    pub fn deduct(
+        &mut self,
+        filters_to_consume: &HashMap<C::FilterId, &PureDPBudget>,
+    ) -> Result<PdsFilterStatus<C::FilterId>, C::Error> {
+        let mut oob_filters = vec![];
+        for (filter_id, loss) in filters_to_consume {
+            let capacity = self.capacities.capacity(filter_id)?;
+            if self.consumed(filter_id) + **loss > capacity {
+                oob_filters.push(filter_id.clone());
+            }
+        }
+
+        if !oob_filters.is_empty() {
+            return Ok(PdsFilterStatus::OutOfBudget(oob_filters));
+        }
+
+        for (filter_id, loss) in filters_to_consume {
+            *self.consumed.entry(filter_id.clone()).or_default() += **loss;
+        }
+        Ok(PdsFilterStatus::Continue)
+    }
+}
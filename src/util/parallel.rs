@@ -0,0 +1,43 @@
+//! Optional data parallelism. With the `parallel` feature, independent work
+//! items are spread over the rayon thread pool, otherwise they are processed
+//! sequentially.
+
+use alloc::vec::Vec;
+
+/// Types that can be shared across threads with the `parallel` feature, i.e.
+/// `Send + Sync`. Without the feature, all types are `ThreadSafe`, so
+/// single-threaded embedders can keep using `Rc` and other non-`Sync` types.
+#[cfg(feature = "parallel")]
+pub trait ThreadSafe: Send + Sync {}
+
+#[cfg(feature = "parallel")]
+impl<T: Send + Sync + ?Sized> ThreadSafe for T {}
+
+/// Types that can be shared across threads with the `parallel` feature, i.e.
+/// `Send + Sync`. Without the feature, all types are `ThreadSafe`, so
+/// single-threaded embedders can keep using `Rc` and other non-`Sync` types.
+#[cfg(not(feature = "parallel"))]
+pub trait ThreadSafe {}
+
+#[cfg(not(feature = "parallel"))]
+impl<T: ?Sized> ThreadSafe for T {}
+
+/// Maps `f` over `items`, in parallel with the `parallel` feature. Results
+/// are in the order of `items` either way.
+pub fn par_map<T, R, F>(items: &[T], f: F) -> Vec<R>
+where
+    T: ThreadSafe,
+    R: ThreadSafe,
+    F: Fn(&T) -> R + ThreadSafe,
+{
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+        items.par_iter().map(f).collect()
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    {
+        items.iter().map(f).collect()
+    }
+}
@@ -0,0 +1,15 @@
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+
+/// RNG for on-device randomness. ChaCha8 output is portable across platforms
+/// and versions, so a seeded run can be replayed bit-for-bit.
+pub type PdsRng = ChaCha8Rng;
+
+/// Creates an RNG from the given seed, or from OS entropy if there is none.
+/// Seeds must only be set for simulations, never on real devices.
+pub fn new_rng(seed: Option<u64>) -> PdsRng {
+    match seed {
+        Some(seed) => PdsRng::seed_from_u64(seed),
+        None => PdsRng::from_entropy(),
+    }
+}
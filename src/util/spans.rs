@@ -0,0 +1,59 @@
+//! Spans around the main operations, e.g. relevant-event selection,
+//! per-epoch accounting, filter deductions and batch phases. With the
+//! `tracing` feature, they are `tracing` spans at the debug level, with an
+//! `elapsed_us` field recorded when the span ends, so hosts can attach their
+//! own subscribers for flamegraphs or latency analysis. Without it, spans are
+//! no-ops.
+
+#[cfg(feature = "tracing")]
+use std::time::Instant;
+
+/// Entered span that records its duration when dropped.
+#[cfg(feature = "tracing")]
+#[derive(Debug)]
+pub struct TimedSpan {
+    span: tracing::span::EnteredSpan,
+    start: Instant,
+}
+
+#[cfg(feature = "tracing")]
+impl TimedSpan {
+    /// Enters `span`, which must have an `elapsed_us` field.
+    pub fn enter(span: tracing::Span) -> Self {
+        Self {
+            span: span.entered(),
+            start: Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl Drop for TimedSpan {
+    fn drop(&mut self) {
+        let elapsed_us = self.start.elapsed().as_micros() as u64;
+        self.span.record("elapsed_us", elapsed_us);
+    }
+}
+
+/// No-op span, without the `tracing` feature.
+#[cfg(not(feature = "tracing"))]
+#[derive(Debug)]
+pub struct TimedSpan;
+
+/// Enters a `TimedSpan` with the given name and `tracing` fields, e.g.
+/// `timed_span!("deduct_budget", dry_run)`. Fields are not evaluated without
+/// the `tracing` feature.
+macro_rules! timed_span {
+    ($name:literal $(, $($fields:tt)+)?) => {{
+        #[cfg(feature = "tracing")]
+        let span = $crate::util::spans::TimedSpan::enter(tracing::debug_span!(
+            $name,
+            elapsed_us = tracing::field::Empty
+            $(, $($fields)+)?
+        ));
+        #[cfg(not(feature = "tracing"))]
+        let span = $crate::util::spans::TimedSpan;
+        span
+    }};
+}
+pub(crate) use timed_span;
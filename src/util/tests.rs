@@ -47,6 +47,7 @@ impl ReportRequestUris<String> {
             trigger_uri: "shoes.com".to_string(),
             source_uris: vec!["blog.com".to_string()],
             querier_uris: vec!["adtech.com".to_string()],
+            campaign_id: None,
         }
     }
 }
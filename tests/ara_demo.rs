@@ -0,0 +1,153 @@
+mod common;
+
+use std::collections::BTreeMap;
+
+use common::logging;
+use log::info;
+use pdslib::{
+    budget::traits::FilterStorage,
+    events::{
+        ara_event::{AraEvent, AraFilterData},
+        traits::EventUris,
+    },
+    pds::{
+        aliases::{AraEventStorage, AraPds, PpaFilterStorage},
+        quotas::StaticCapacities,
+    },
+    queries::{
+        ara_histogram::{
+            AraHistogramConfig, AraHistogramRequest, AraRelevantEventSelector,
+            AraTriggerData,
+        },
+        epoch_selection::EpochSelection,
+        traits::ReportRequestUris,
+    },
+};
+
+fn filter_data(name: &str, values: &[&str]) -> AraFilterData {
+    BTreeMap::from([(
+        name.to_string(),
+        values.iter().map(|value| value.to_string()).collect(),
+    )])
+}
+
+fn source(
+    id: u64,
+    timestamp: u64,
+    priority: i64,
+    uris: EventUris<String>,
+) -> AraEvent {
+    AraEvent {
+        id,
+        source_event_id: 100 + id,
+        timestamp,
+        epoch_number: 1,
+        expiry: 1000,
+        priority,
+        filter_data: filter_data("product", &["shoes"]),
+        aggregation_keys: BTreeMap::from([
+            ("campaignCounts".to_string(), 0x159 + (id as u128) * 0x10000),
+            ("geoValue".to_string(), 0x5),
+        ]),
+        uris,
+    }
+}
+
+fn request(
+    filters: AraFilterData,
+    trigger_time: u64,
+) -> anyhow::Result<AraHistogramRequest> {
+    Ok(AraHistogramRequest::new(
+        AraHistogramConfig {
+            epochs: EpochSelection::Range { start: 1, end: 2 },
+            aggregatable_trigger_data: vec![
+                AraTriggerData {
+                    key_piece: 0x400,
+                    source_keys: vec!["campaignCounts".to_string()],
+                },
+                AraTriggerData {
+                    key_piece: 0xA80,
+                    source_keys: vec!["geoValue".to_string()],
+                },
+            ],
+            aggregatable_values: BTreeMap::from([
+                ("campaignCounts".to_string(), 32768.0),
+                ("geoValue".to_string(), 1664.0),
+            ]),
+            max_attributable_value: 65536.0,
+            requested_epsilon: 0.25,
+        },
+        AraRelevantEventSelector {
+            report_request_uris: ReportRequestUris::mock(),
+            filters,
+            trigger_time,
+        },
+    )?)
+}
+
+#[test]
+fn main() -> Result<(), anyhow::Error> {
+    logging::init_default_logging();
+    let capacities = StaticCapacities::mock();
+    let filters = PpaFilterStorage::new(capacities)?;
+    let events = AraEventStorage::new();
+    let mut pds = AraPds::<_>::new(filters, events);
+
+    // Sources 2 to 4 are irrelevant for the request URIs.
+    pds.register_event(source(1, 10, 0, EventUris::mock()))?;
+    pds.register_event(source(
+        2,
+        20,
+        0,
+        EventUris {
+            source_uri: "blog_off_brand.com".to_string(),
+            ..EventUris::mock()
+        },
+    ))?;
+    pds.register_event(source(
+        3,
+        20,
+        0,
+        EventUris {
+            trigger_uris: vec!["shoes_off_brand.com".to_string()],
+            ..EventUris::mock()
+        },
+    ))?;
+    pds.register_event(source(
+        4,
+        20,
+        0,
+        EventUris {
+            querier_uris: vec!["adtech_off_brand.com".to_string()],
+            ..EventUris::mock()
+        },
+    ))?;
+
+    // Each source key is combined with the trigger pieces that list it.
+    let report1 =
+        pds.compute_report(&request(filter_data("product", &["shoes"]), 100)?)?;
+    info!("Report1: {report1:?}");
+    let bin_values1 = &report1.filtered_report.bin_values;
+    assert_eq!(bin_values1.len(), 2);
+    assert_eq!(bin_values1.get(&(0x10559)), Some(&32768.0));
+    assert_eq!(bin_values1.get(&(0xA85)), Some(&1664.0));
+
+    // Sources that don't match the trigger filters are not attributed.
+    let report2 =
+        pds.compute_report(&request(filter_data("product", &["hats"]), 100)?)?;
+    assert!(report2.filtered_report.bin_values.is_empty());
+
+    // Neither are expired sources.
+    let report3 = pds
+        .compute_report(&request(filter_data("product", &["shoes"]), 2000)?)?;
+    assert!(report3.filtered_report.bin_values.is_empty());
+
+    // A higher priority wins over recency.
+    pds.register_event(source(5, 30, 0, EventUris::mock()))?;
+    pds.register_event(source(6, 5, 10, EventUris::mock()))?;
+    let report4 =
+        pds.compute_report(&request(filter_data("product", &["shoes"]), 100)?)?;
+    assert!(report4.filtered_report.bin_values.contains_key(&(0x60559)));
+
+    Ok(())
+}
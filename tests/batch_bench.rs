@@ -0,0 +1,98 @@
+#![cfg(feature = "experimental")]
+
+use std::time::Instant;
+
+use pdslib::{
+    budget::{
+        hashmap_filter_storage::HashMapFilterStorage,
+        release_filter::PureDPBudgetReleaseFilter, traits::FilterStorage as _,
+    },
+    events::{
+        ppa_event::PpaEvent,
+        traits::{EventStorage as _, EventUris},
+    },
+    pds::{
+        aliases::PpaEventStorage,
+        batch_pds::{BatchPrivateDataService, BatchedRequest},
+        private_data_service::PrivateDataService,
+        quotas::StaticCapacities,
+    },
+    queries::{
+        builder::PpaHistogramRequestBuilder,
+        ppa_histogram::{FilterDataPredicate, RequestedBuckets},
+        traits::ReportRequestUris,
+    },
+};
+
+const PENDING_REQUESTS: u64 = 10_000;
+const SOURCES: u64 = 100;
+
+/// Batch phase with 10k pending requests over 100 sources, where the global
+/// filter only fits half of them, so most requests reach the batch phase.
+#[test]
+#[ignore]
+fn bench_batch_phase() -> anyhow::Result<()> {
+    let source_uri = |source: u64| format!("source-{source}.example.com");
+
+    let mut events = PpaEventStorage::new();
+    for source in 0..SOURCES {
+        events.add_event(PpaEvent {
+            id: source,
+            timestamp: source,
+            epoch_number: 1,
+            histogram_index: 0,
+            uris: EventUris {
+                source_uri: source_uri(source),
+                trigger_uris: vec!["trigger.example.com".to_string()],
+                querier_uris: vec!["querier.example.com".to_string()],
+            },
+            filter_data: 0,
+            priority: 0,
+        })?;
+    }
+
+    let capacities = StaticCapacities::new(1000.0, 500.0, 1000.0, 5.0);
+    let filters: HashMapFilterStorage<PureDPBudgetReleaseFilter, _> =
+        HashMapFilterStorage::new(capacities)?;
+    let pds: PrivateDataService<_, _, _, anyhow::Error> =
+        PrivateDataService::new(filters, events);
+    let mut batch_pds = BatchPrivateDataService::new(pds, 1)?;
+
+    for request_id in 0..PENDING_REQUESTS {
+        let request = PpaHistogramRequestBuilder::new()
+            .epoch_range(1, 1)?
+            .value(1.0, 1.0, 0.1)?
+            .uris(ReportRequestUris {
+                trigger_uri: "trigger.example.com".to_string(),
+                source_uris: vec![
+                    source_uri(request_id % SOURCES),
+                    source_uri((request_id * 7 + 1) % SOURCES),
+                ],
+                querier_uris: vec!["querier.example.com".to_string()],
+                campaign_id: None,
+            })?
+            .selector(
+                FilterDataPredicate::Any,
+                1,
+                RequestedBuckets::AllBuckets,
+            )?
+            .build();
+        batch_pds.register_report_request(BatchedRequest::new(
+            request_id, 2, request,
+        ))?;
+    }
+
+    let start = Instant::now();
+    batch_pds.schedule_batch()?;
+    let elapsed = start.elapsed();
+
+    // Allocated requests leave the arena, their reports are delayed.
+    let pending = batch_pds.requests.len();
+    println!(
+        "Batch phase over {PENDING_REQUESTS} pending requests: {elapsed:?}, \
+         {} allocated, {pending} still pending",
+        PENDING_REQUESTS as usize - pending,
+    );
+    assert!(pending > 0 && pending < PENDING_REQUESTS as usize);
+    Ok(())
+}
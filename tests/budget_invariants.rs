@@ -0,0 +1,292 @@
+//! Property-based tests for the budget invariants of the PDS, checked against
+//! the reference `OracleAccountant`.
+
+use pdslib::{
+    budget::{pure_dp_filter::PureDPBudget, traits::FilterStorage as _},
+    events::{
+        ppa_event::PpaEvent, relevant_events::RelevantEvents, traits::EventUris,
+    },
+    pds::{
+        accounting::{compute_epoch_loss, compute_epoch_source_losses},
+        aliases::{PpaEventStorage, PpaFilterStorage, PpaPds},
+        quotas::{FilterId, PdsFilterStatus, StaticCapacities},
+    },
+    queries::{
+        builder::PpaHistogramRequestBuilder,
+        ppa_histogram::{
+            FilterDataPredicate, PpaHistogramRequest, RequestedBuckets,
+        },
+        traits::{EpochReportRequest, ReportRequestUris},
+    },
+    util::{
+        hashmap::{HashMap, HashSet},
+        oracle::OracleAccountant,
+    },
+};
+use proptest::prelude::*;
+
+type Capacities = StaticCapacities<FilterId, PureDPBudget>;
+
+const SOURCES: [&str; 2] = ["blog.com", "news.com"];
+
+#[derive(Debug, Clone)]
+enum Op {
+    RegisterEvent {
+        epoch: u64,
+        source: usize,
+        histogram_index: u64,
+        filter_data: u64,
+    },
+    Report {
+        start_epoch: u64,
+        n_epochs: u64,
+        /// Index in `SOURCES`, or all sources if out of bounds.
+        sources: usize,
+        requested_epsilon: f64,
+        attributable_value: f64,
+    },
+    PassiveLoss {
+        epochs: Vec<u64>,
+        privacy_budget: f64,
+    },
+}
+
+fn op_strategy() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        (1..=4u64, 0..2usize, 0..4u64, 0..2u64).prop_map(
+            |(epoch, source, histogram_index, filter_data)| {
+                Op::RegisterEvent {
+                    epoch,
+                    source,
+                    histogram_index,
+                    filter_data,
+                }
+            }
+        ),
+        (1..=4u64, 0..3u64, 0..3usize, 0.1..5.0f64, 1.0..10.0f64).prop_map(
+            |(start_epoch, n_epochs, sources, eps, value)| Op::Report {
+                start_epoch,
+                n_epochs,
+                sources,
+                requested_epsilon: eps,
+                attributable_value: value,
+            }
+        ),
+        (prop::collection::vec(1..=4u64, 1..3), 0.0..2.0f64).prop_map(
+            |(epochs, privacy_budget)| Op::PassiveLoss {
+                epochs,
+                privacy_budget,
+            }
+        ),
+    ]
+}
+
+fn report_uris(sources: usize) -> ReportRequestUris<String> {
+    let source_uris = match SOURCES.get(sources) {
+        Some(source) => vec![source.to_string()],
+        None => SOURCES.iter().map(|s| s.to_string()).collect(),
+    };
+    ReportRequestUris {
+        source_uris,
+        ..ReportRequestUris::mock()
+    }
+}
+
+fn oob_set(status: PdsFilterStatus<FilterId>) -> Option<HashSet<FilterId>> {
+    match status {
+        PdsFilterStatus::Continue => None,
+        PdsFilterStatus::OutOfBudget(filters) => {
+            Some(filters.into_iter().collect())
+        }
+    }
+}
+
+/// Replays the Cookie Monster accounting on the oracle, and returns the
+/// epochs that should be dropped from the report.
+fn expected_dropped_epochs(
+    oracle: &mut OracleAccountant<Capacities>,
+    pds: &mut PpaPds,
+    request: &PpaHistogramRequest,
+    relevant_events: &RelevantEvents<PpaEvent>,
+) -> HashSet<u64> {
+    let epochs = request.epoch_ids();
+    let num_epochs = epochs.len();
+    let unfiltered_report = request.compute_report(relevant_events);
+
+    let mut dropped_epochs = HashSet::new();
+    for epoch_id in epochs {
+        let loss = compute_epoch_loss(
+            request,
+            relevant_events.for_epoch(&epoch_id),
+            &unfiltered_report,
+            num_epochs,
+        );
+        let source_losses = compute_epoch_source_losses(
+            request,
+            relevant_events.sources_for_epoch(&epoch_id),
+            &unfiltered_report,
+            num_epochs,
+        );
+        let filters_to_consume = pds.core.filters_to_consume(
+            epoch_id,
+            &loss,
+            &source_losses,
+            request.report_uris(),
+        );
+        if oracle.deduct(&filters_to_consume).unwrap()
+            != PdsFilterStatus::Continue
+        {
+            dropped_epochs.insert(epoch_id);
+        }
+    }
+    dropped_epochs
+}
+
+fn run_ops(capacities: Capacities, ops: Vec<Op>) -> Result<(), TestCaseError> {
+    let filters = PpaFilterStorage::new(capacities.clone()).unwrap();
+    let mut pds = PpaPds::<_>::new(filters, PpaEventStorage::new());
+    let mut oracle = OracleAccountant::new(capacities.clone());
+
+    for (i, op) in ops.into_iter().enumerate() {
+        match op {
+            Op::RegisterEvent {
+                epoch,
+                source,
+                histogram_index,
+                filter_data,
+            } => {
+                let event = PpaEvent {
+                    id: i as u64,
+                    timestamp: i as u64,
+                    epoch_number: epoch,
+                    histogram_index,
+                    uris: EventUris {
+                        source_uri: SOURCES[source].to_string(),
+                        ..EventUris::mock()
+                    },
+                    filter_data,
+                    priority: 0,
+                };
+                pds.register_event(event).unwrap();
+            }
+            Op::Report {
+                start_epoch,
+                n_epochs,
+                sources,
+                requested_epsilon,
+                attributable_value,
+            } => {
+                let request = PpaHistogramRequestBuilder::new()
+                    .epoch_range(start_epoch, start_epoch + n_epochs)
+                    .and_then(|builder| {
+                        builder.value(
+                            attributable_value,
+                            attributable_value,
+                            requested_epsilon,
+                        )
+                    })
+                    .and_then(|builder| builder.uris(report_uris(sources)))
+                    .and_then(|builder| {
+                        builder.selector(
+                            FilterDataPredicate::Equals(0),
+                            4,
+                            RequestedBuckets::AllBuckets,
+                        )
+                    })
+                    .map_err(|e| TestCaseError::fail(e.to_string()))?
+                    .build();
+
+                let mut relevant_events = RelevantEvents::from_event_storage(
+                    &pds.event_storage,
+                    &request.epoch_ids(),
+                    request.relevant_event_selector(),
+                )
+                .unwrap();
+                let dropped_epochs = expected_dropped_epochs(
+                    &mut oracle,
+                    &mut pds,
+                    &request,
+                    &relevant_events,
+                );
+
+                let report = pds.compute_report(&request).unwrap();
+
+                // Dropped epochs never contribute to the filtered report.
+                for epoch_id in &dropped_epochs {
+                    relevant_events.drop_epoch(epoch_id);
+                }
+                let expected_report = request.compute_report(&relevant_events);
+                prop_assert_eq!(
+                    &report.filtered_report.bin_values,
+                    &expected_report.bin_values
+                );
+            }
+            Op::PassiveLoss {
+                epochs,
+                privacy_budget,
+            } => {
+                let uris = ReportRequestUris::mock();
+                let source_losses = HashMap::new();
+                for epoch_id in epochs {
+                    let filters_to_consume = pds.core.filters_to_consume(
+                        epoch_id,
+                        &privacy_budget,
+                        &source_losses,
+                        &uris,
+                    );
+
+                    // Dry run agrees with the oracle.
+                    let check_status =
+                        pds.core.deduct_budget(&filters_to_consume, true);
+                    let expected_status =
+                        oracle.deduct(&filters_to_consume).unwrap();
+                    let check_status = oob_set(check_status.unwrap());
+                    prop_assert_eq!(&check_status, &oob_set(expected_status));
+                    if check_status.is_some() {
+                        break;
+                    }
+
+                    // Dry run followed by consume never diverges.
+                    let consume_status =
+                        pds.core.deduct_budget(&filters_to_consume, false);
+                    prop_assert_eq!(
+                        consume_status.unwrap(),
+                        PdsFilterStatus::Continue
+                    );
+                }
+            }
+        }
+
+        // Filters match the oracle and never exceed their capacity.
+        for filter_id in oracle.filter_ids() {
+            let filter = pds
+                .core
+                .filter_storage
+                .get_filter(filter_id)
+                .unwrap()
+                .expect("charged filters are stored");
+            prop_assert!(filter.consumed <= filter.capacity.unwrap());
+            prop_assert!(
+                (filter.consumed - oracle.consumed(filter_id)).abs() < 1e-9,
+                "{filter_id:?} consumed {}, expected {}",
+                filter.consumed,
+                oracle.consumed(filter_id)
+            );
+        }
+    }
+    Ok(())
+}
+
+proptest! {
+    #[test]
+    fn budget_invariants(
+        ops in prop::collection::vec(op_strategy(), 1..40),
+        per_querier in 0.5..4.0f64,
+        global in 1.0..8.0f64,
+        quotas in 0.5..4.0f64,
+    ) {
+        let capacities =
+            StaticCapacities::new(per_querier, global, quotas, quotas);
+        run_ops(capacities, ops)?;
+    }
+}
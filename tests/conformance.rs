@@ -0,0 +1,125 @@
+//! Conformance test vectors for the Cookie Monster accounting, with the
+//! Big Bird quotas. Each vector in `tests/conformance/vectors.json` gives the
+//! capacities, the events registered on the device and a sequence of
+//! requests, with the expected report and out-of-budget filters of each
+//! request and the budget consumed by each filter at the end. Alternative
+//! implementations and refactors can replay the same vectors: budgets and
+//! report values are compared bit for bit, so vectors only use values that
+//! are exact in binary floating point.
+
+mod common;
+
+use common::logging;
+use pdslib::{
+    budget::{pure_dp_filter::PureDPBudget, traits::FilterStorage as _},
+    events::ppa_event::PpaEvent,
+    pds::{
+        aliases::{PpaEventStorage, PpaFilterStorage, PpaPds},
+        private_data_service::ReportStatus,
+        quotas::{FilterId, StaticCapacities},
+    },
+    queries::ppa_histogram::{PpaHistogramRequest, PpaHistogramRequestSpec},
+    util::hashmap::HashMap,
+};
+use serde::Deserialize;
+
+const VECTORS: &str = include_str!("conformance/vectors.json");
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TestVector {
+    name: String,
+
+    /// What the vector covers, for humans only.
+    #[allow(dead_code)]
+    description: String,
+
+    capacities: StaticCapacities<FilterId, PureDPBudget>,
+    events: Vec<PpaEvent>,
+    requests: Vec<RequestVector>,
+
+    /// Budget consumed by each listed filter after all the requests. Filters
+    /// that must not be charged are listed with 0.
+    consumed: Vec<(FilterId, PureDPBudget)>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RequestVector {
+    request: PpaHistogramRequestSpec,
+
+    /// Filtered report, as returned to the querier.
+    report: HashMap<u64, f64>,
+
+    status: ReportStatus<u64>,
+
+    /// Filters that were out of budget, in any order. They are only
+    /// reported with the `experimental` feature.
+    #[serde(default)]
+    #[cfg_attr(not(feature = "experimental"), allow(dead_code))]
+    oob_filters: Vec<FilterId>,
+}
+
+fn run_vector(vector: TestVector) -> Result<(), anyhow::Error> {
+    let filters = PpaFilterStorage::new(vector.capacities)?;
+    let mut pds = PpaPds::<_>::new(filters, PpaEventStorage::new());
+    for event in vector.events {
+        pds.register_event(event)?;
+    }
+
+    for (i, request_vector) in vector.requests.into_iter().enumerate() {
+        let request = PpaHistogramRequest::try_from(request_vector.request)?;
+        let report = pds.compute_report(&request)?;
+        assert_eq!(
+            report.filtered_report.bin_values, request_vector.report,
+            "{}: report of request {i}",
+            vector.name
+        );
+
+        assert_eq!(
+            report.status, request_vector.status,
+            "{}: status of request {i}",
+            vector.name
+        );
+
+        #[cfg(feature = "experimental")]
+        {
+            let mut oob_filters = report.oob_filters;
+            let mut expected_oob_filters = request_vector.oob_filters;
+            oob_filters.sort_by_key(|filter_id| format!("{filter_id:?}"));
+            expected_oob_filters
+                .sort_by_key(|filter_id| format!("{filter_id:?}"));
+            assert_eq!(
+                oob_filters, expected_oob_filters,
+                "{}: out-of-budget filters of request {i}",
+                vector.name
+            );
+        }
+    }
+
+    for (filter_id, expected) in vector.consumed {
+        let consumed = pds
+            .core
+            .filter_storage
+            .get_filter(&filter_id)?
+            .map_or(0.0, |filter| filter.consumed);
+        assert_eq!(
+            consumed, expected,
+            "{}: budget consumed by {filter_id:?}",
+            vector.name
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_conformance_vectors() -> Result<(), anyhow::Error> {
+    logging::init_default_logging();
+
+    let vectors: Vec<TestVector> = serde_json::from_str(VECTORS)?;
+    assert!(!vectors.is_empty());
+    for vector in vectors {
+        run_vector(vector)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,71 @@
+#![cfg(feature = "deterministic")]
+
+mod common;
+
+use std::thread;
+
+use common::logging;
+use pdslib::{
+    budget::traits::FilterStorage,
+    events::{ppa_event::PpaEvent, traits::EventUris},
+    pds::{
+        aliases::{PpaEventStorage, PpaFilterStorage, PpaPds},
+        quotas::StaticCapacities,
+    },
+    queries::{
+        builder::PpaHistogramRequestBuilder,
+        ppa_histogram::{
+            AttributionLogic, FilterDataPredicate, RequestedBuckets,
+        },
+        traits::ReportRequestUris,
+    },
+};
+
+/// Registers one event per epoch, each in its own bucket, and serializes the
+/// report of an `EpochLastTouch` request, whose buckets are serialized in
+/// the iteration order of the map.
+fn serialized_report() -> Result<Vec<u8>, anyhow::Error> {
+    let filters = PpaFilterStorage::new(StaticCapacities::mock())?;
+    let mut pds = PpaPds::<_>::new(filters, PpaEventStorage::new());
+
+    for epoch in 1..=8 {
+        pds.register_event(PpaEvent {
+            id: epoch,
+            timestamp: epoch,
+            epoch_number: epoch,
+            histogram_index: epoch * 17,
+            uris: EventUris::mock(),
+            filter_data: 1,
+            priority: 0,
+        })?;
+    }
+
+    let request = PpaHistogramRequestBuilder::new()
+        .epoch_range(1, 8)?
+        .value(8.0, 8.0, 1.0)?
+        .uris(ReportRequestUris::mock())?
+        .selector(FilterDataPredicate::Any, 256, RequestedBuckets::AllBuckets)?
+        .attribution_logic(AttributionLogic::EpochLastTouch)
+        .build();
+    let report = pds.compute_report(&request)?;
+    assert_eq!(report.filtered_report.bin_values.len(), 8);
+
+    Ok(serde_json::to_vec(&report.filtered_report)?)
+}
+
+#[test]
+fn test_reports_are_byte_identical_across_runs() -> Result<(), anyhow::Error> {
+    logging::init_default_logging();
+
+    // With the default hasher, each thread seeds its maps differently, like
+    // separate runs would.
+    let reports = (0..4)
+        .map(|_| thread::spawn(serialized_report))
+        .collect::<Vec<_>>()
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    assert!(reports.windows(2).all(|pair| pair[0] == pair[1]));
+    Ok(())
+}
@@ -11,10 +11,8 @@ use pdslib::{
         quotas::StaticCapacities,
     },
     queries::{
-        ppa_histogram::{
-            PpaHistogramConfig, PpaHistogramRequest, PpaRelevantEventSelector,
-            RequestedBuckets,
-        },
+        builder::PpaHistogramRequestBuilder,
+        ppa_histogram::{FilterDataPredicate, RequestedBuckets},
         traits::ReportRequestUris,
     },
 };
@@ -39,6 +37,7 @@ fn bench_compute_report() -> anyhow::Result<()> {
         trigger_uri: "trigger",
         source_uris: vec!["source"],
         querier_uris: vec!["querier"],
+        campaign_id: None,
     };
 
     // we start at 100 so we can subtract 100 without overflowing
@@ -52,25 +51,22 @@ fn bench_compute_report() -> anyhow::Result<()> {
                 histogram_index: event_id,
                 uris: event_uris.clone(),
                 filter_data: 0,
+                priority: 0,
             };
             pds.event_storage.add_event(event)?;
         }
 
         // compute the report for those 3 events
-        let request_config = PpaHistogramConfig {
-            start_epoch: epoch_id - 100,
-            end_epoch: epoch_id + 1,
-            attributable_value: 1.0,
-            max_attributable_value: 2.0,
-            requested_epsilon: 1.0,
-            histogram_size: 1001,
-        };
-        let selector = PpaRelevantEventSelector {
-            report_request_uris: report_uris.clone(),
-            is_matching_event: Box::new(|_| true),
-            requested_buckets: RequestedBuckets::AllBuckets,
-        };
-        let request = PpaHistogramRequest::new(&request_config, selector)?;
+        let request = PpaHistogramRequestBuilder::new()
+            .epoch_range(epoch_id - 100, epoch_id + 1)?
+            .value(1.0, 2.0, 1.0)?
+            .uris(report_uris.clone())?
+            .selector(
+                FilterDataPredicate::Any,
+                1001,
+                RequestedBuckets::AllBuckets,
+            )?
+            .build();
 
         let report = pds.compute_report(&request)?;
 
@@ -10,10 +10,8 @@ use pdslib::{
         quotas::StaticCapacities,
     },
     queries::{
-        ppa_histogram::{
-            PpaHistogramConfig, PpaHistogramRequest, PpaRelevantEventSelector,
-        },
-        traits::ReportRequestUris,
+        builder::PpaHistogramRequestBuilder,
+        ppa_histogram::FilterDataPredicate, traits::ReportRequestUris,
     },
 };
 
@@ -44,6 +42,7 @@ fn main() -> Result<(), anyhow::Error> {
         trigger_uri: "shoes.com".to_string(),
         source_uris: vec!["blog.com".to_string()],
         querier_uris: vec!["adtech.com".to_string()],
+        campaign_id: None,
     };
 
     let event1 = PpaEvent {
@@ -53,6 +52,7 @@ fn main() -> Result<(), anyhow::Error> {
         histogram_index: 0x559, // 0x559 = "campaignCounts".to_string() | 0x400
         uris: sample_event_uris.clone(),
         filter_data: 1,
+        priority: 0,
     };
 
     let event_irr_1 = PpaEvent {
@@ -62,6 +62,7 @@ fn main() -> Result<(), anyhow::Error> {
         histogram_index: 0x559, // 0x559 = "campaignCounts".to_string() | 0x400
         uris: event_uris_irrelevant_due_to_source.clone(),
         filter_data: 1,
+        priority: 0,
     };
 
     let event_irr_2 = PpaEvent {
@@ -71,6 +72,7 @@ fn main() -> Result<(), anyhow::Error> {
         histogram_index: 0x559, // 0x559 = "campaignCounts".to_string() | 0x400
         uris: event_uris_irrelevant_due_to_trigger.clone(),
         filter_data: 1,
+        priority: 0,
     };
 
     let event_irr_3 = PpaEvent {
@@ -80,6 +82,7 @@ fn main() -> Result<(), anyhow::Error> {
         histogram_index: 0x559, // 0x559 = "campaignCounts".to_string() | 0x400
         uris: event_uris_irrelevant_due_to_querier.clone(),
         filter_data: 1,
+        priority: 0,
     };
 
     pds.register_event(event1.clone())?;
@@ -88,24 +91,12 @@ fn main() -> Result<(), anyhow::Error> {
     pds.register_event(event_irr_3.clone()).unwrap();
 
     // Test basic attribution
-    let request1 = PpaHistogramRequest::new(
-        &PpaHistogramConfig {
-            start_epoch: 1,
-            end_epoch: 2,
-            attributable_value: 32768.0,
-            max_attributable_value: 65536.0,
-            requested_epsilon: 1.0,
-            histogram_size: 2048,
-        },
-        PpaRelevantEventSelector {
-            report_request_uris: sample_report_request_uris.clone(),
-            is_matching_event: Box::new(|event_filter_data: u64| {
-                event_filter_data == 1
-            }),
-            requested_buckets: vec![0x559].into(),
-        }, // Not filtering yet.
-    )
-    .unwrap();
+    let request1 = PpaHistogramRequestBuilder::new()
+        .epoch_range(1, 2)?
+        .value(32768.0, 65536.0, 1.0)?
+        .uris(sample_report_request_uris.clone())?
+        .selector(FilterDataPredicate::Equals(1), 2048, vec![0x559].into())?
+        .build();
 
     let report1 = pds.compute_report(&request1).unwrap();
     info!("Report1: {report1:?}");
@@ -118,43 +109,23 @@ fn main() -> Result<(), anyhow::Error> {
     assert_eq!(bin_values1.get(&0x559), Some(&32768.0));
 
     // Test error case when requested_epsilon is 0.
-    let request2 = PpaHistogramRequest::new(
-        &PpaHistogramConfig {
-            start_epoch: 1,
-            end_epoch: 2,
-            attributable_value: 32768.0,
-            max_attributable_value: 65536.0,
-            requested_epsilon: 0.0, // This should fail.
-            histogram_size: 2048,
-        },
-        PpaRelevantEventSelector {
-            report_request_uris: sample_report_request_uris.clone(),
-            is_matching_event: Box::new(|event_filter_data: u64| {
-                event_filter_data == 1
-            }),
-            requested_buckets: vec![0x559].into(),
-        }, // Not filtering yet.
-    );
+    let request2 = PpaHistogramRequestBuilder::new()
+        .epoch_range(1, 2)?
+        .value(32768.0, 65536.0, 0.0); // This should fail.
     assert!(request2.is_err());
 
-    let request3 = PpaHistogramRequest::new(
-        &PpaHistogramConfig {
-            start_epoch: 1,
-            end_epoch: 2,
-            attributable_value: 32768.0,
-            max_attributable_value: 65536.0,
-            requested_epsilon: 1.0,
-            histogram_size: 2048,
-        },
-        PpaRelevantEventSelector {
-            report_request_uris: sample_report_request_uris.clone(),
-            is_matching_event: Box::new(|event_filter_data: u64| {
+    let request3 = PpaHistogramRequestBuilder::new()
+        .epoch_range(1, 2)?
+        .value(32768.0, 65536.0, 1.0)?
+        .uris(sample_report_request_uris.clone())?
+        .selector(
+            FilterDataPredicate::custom(|event_filter_data: u64| {
                 event_filter_data != 1
             }),
-            requested_buckets: vec![0x559].into(),
-        }, // Not filtering yet.
-    )
-    .unwrap();
+            2048,
+            vec![0x559].into(),
+        )?
+        .build();
 
     let report3 = pds.compute_report(&request3).unwrap();
     info!("Report3: {report3:?}");
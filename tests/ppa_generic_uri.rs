@@ -9,21 +9,20 @@ use pdslib::{
     },
     pds::{private_data_service::PrivateDataService, quotas::StaticCapacities},
     queries::{
+        builder::PpaHistogramRequestBuilder,
         ppa_histogram::{
-            PpaHistogramConfig, PpaHistogramRequest, PpaRelevantEventSelector,
-            RequestedBuckets,
+            FilterDataPredicate, PpaHistogramRequest, RequestedBuckets,
         },
         traits::ReportRequestUris,
     },
 };
 
-#[derive(Hash, PartialEq, Eq, Clone, Debug)]
+#[derive(Hash, PartialEq, Eq, Clone, Debug, serde::Serialize)]
 struct CustomUri;
 
 // The recommended way of using generic types in your code is to type-alias
 // the commonly used types, to not have to repeat the generic bounds everywhere.
 type TestEvent = PpaEvent<CustomUri>;
-type TestRelevantEventSelector = PpaRelevantEventSelector<CustomUri>;
 type TestHistogramRequest = PpaHistogramRequest<CustomUri>;
 
 #[test]
@@ -50,6 +49,7 @@ fn main() -> Result<(), anyhow::Error> {
         trigger_uri: CustomUri {},
         source_uris: vec![CustomUri {}],
         querier_uris: vec![CustomUri {}],
+        campaign_id: None,
     };
 
     let event = TestEvent {
@@ -59,27 +59,22 @@ fn main() -> Result<(), anyhow::Error> {
         histogram_index: 1,
         uris: event_uris.clone(),
         filter_data: 1,
-    };
-
-    let always_relevant_event_selector = TestRelevantEventSelector {
-        report_request_uris: report_uris.clone(),
-        is_matching_event: Box::new(|_| true),
-        requested_buckets: RequestedBuckets::AllBuckets,
+        priority: 0,
     };
 
     pds.register_event(event.clone())?;
 
-    let config = PpaHistogramConfig {
-        start_epoch: 1,
-        end_epoch: 1,
-        attributable_value: 1.0,
-        max_attributable_value: 1.0,
-        requested_epsilon: 1.0,
-        histogram_size: 1,
-    };
-    let report_request =
-        TestHistogramRequest::new(&config, always_relevant_event_selector)
-            .unwrap();
+    let report_request: TestHistogramRequest =
+        PpaHistogramRequestBuilder::new()
+            .epoch_range(1, 1)?
+            .value(1.0, 1.0, 1.0)?
+            .uris(report_uris.clone())?
+            .selector(
+                FilterDataPredicate::Any,
+                1,
+                RequestedBuckets::AllBuckets,
+            )?
+            .build();
     let _report = pds.compute_report(&report_request)?;
 
     Ok(())
@@ -34,6 +34,7 @@ fn main() -> Result<(), anyhow::Error> {
         trigger_uri: "shoes.com".to_string(),
         source_uris: vec!["blog.com".to_string()],
         querier_uris: vec!["adtech.com".to_string()],
+        campaign_id: None,
     };
 
     // Create an impression (event, with very basic metadata).
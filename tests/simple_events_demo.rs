@@ -9,9 +9,8 @@ use pdslib::{
         quotas::StaticCapacities,
     },
     queries::{
-        simple_last_touch_histogram::{
-            SimpleLastTouchHistogramRequest, SimpleRelevantEventSelector,
-        },
+        builder::SimpleLastTouchHistogramRequestBuilder,
+        simple_last_touch_histogram::SimpleRelevantEventSelector,
         traits::ReportRequestUris,
     },
 };
@@ -36,6 +35,7 @@ fn main() -> Result<(), anyhow::Error> {
         trigger_uri: "shoes.com".to_string(),
         source_uris: vec!["blog.com".to_string()],
         querier_uris: vec!["adtech.com".to_string()],
+        campaign_id: None,
     };
 
     let event = SimpleEvent {
@@ -67,16 +67,19 @@ fn main() -> Result<(), anyhow::Error> {
         lambda: always_relevant_event,
     };
 
+    // Queries have an epsilon of 5.0 for a global sensitivity of 5.0, so each
+    // report costs its own global sensitivity.
+    let request =
+        |epoch_start, epoch_end, report_global_sensitivity, selector| {
+            SimpleLastTouchHistogramRequestBuilder::new()
+                .epoch_range(epoch_start, epoch_end)?
+                .noise(report_global_sensitivity, 5.0, 5.0)?
+                .uris(sample_report_uris.clone())
+                .map(|builder| builder.selector(selector))
+        };
+
     pds.register_event(event.clone())?;
-    let report_request = SimpleLastTouchHistogramRequest {
-        epoch_start: 1,
-        epoch_end: 1,
-        report_global_sensitivity: 3.0,
-        query_global_sensitivity: 5.0,
-        requested_epsilon: 5.0,
-        is_relevant_event: always_relevant_event_selector,
-        report_uris: sample_report_uris.clone(),
-    };
+    let report_request = request(1, 1, 3.0, always_relevant_event_selector)?;
     let report = pds.compute_report(&report_request)?;
     let bucket = Some((event.event_key, 3.0));
     assert_eq!(report.filtered_report.bin_value, bucket);
@@ -84,93 +87,50 @@ fn main() -> Result<(), anyhow::Error> {
     // Test having multiple events in one epoch
     pds.register_event(event2.clone())?;
 
-    let report_request2 = SimpleLastTouchHistogramRequest {
-        epoch_start: 1,
-        epoch_end: 1, //test restricting the end epoch
-        report_global_sensitivity: 0.1, /* Even 0.1 should be enough to go
-                       * over the
-                       * limit as the current budget left
-                       * for
-                       * epoch 1 is 0. */
-        query_global_sensitivity: 5.0,
-        requested_epsilon: 5.0,
-        is_relevant_event: always_relevant_event_selector,
-        report_uris: sample_report_uris.clone(),
-    };
+    // Restricting the end epoch. Even 0.1 should be enough to go over the
+    // limit, as the current budget left for epoch 1 is 0.
+    let report_request2 = request(1, 1, 0.1, always_relevant_event_selector)?;
     let report2 = pds.compute_report(&report_request2)?;
     // Allocated budget for epoch 1 is 3.0, but 3.0 has already been consumed in
     // the last request, so the budget is depleted. Now, the null report should
     // be returned for this additional query.
     assert_eq!(report2.filtered_report.bin_value, None);
 
-    let report_request2 = SimpleLastTouchHistogramRequest {
-        epoch_start: 1,
-        epoch_end: 2,
-        report_global_sensitivity: 3.0,
-        query_global_sensitivity: 5.0,
-        requested_epsilon: 5.0,
-        is_relevant_event: always_relevant_event_selector,
-        report_uris: sample_report_uris.clone(),
-    };
+    let report_request2 = request(1, 2, 3.0, always_relevant_event_selector)?;
     let report2 = pds.compute_report(&report_request2)?;
     let bucket2 = Some((event2.event_key, 3.0));
     assert_eq!(report2.filtered_report.bin_value, bucket2);
 
-    // Test request for epoch empty yet.
-    let report_request3_empty = SimpleLastTouchHistogramRequest {
-        epoch_start: 3, // Epoch 3 not created yet.
-        epoch_end: 3,   // Epoch 3 not created yet.
-        report_global_sensitivity: 0.0,
-        query_global_sensitivity: 5.0,
-        requested_epsilon: 5.0,
-        is_relevant_event: always_relevant_event_selector,
-        report_uris: sample_report_uris.clone(),
-    };
+    // Test request for epoch empty yet, epoch 3 is not created yet.
+    let report_request3_empty =
+        request(3, 3, 0.0, always_relevant_event_selector)?;
     let report3_empty = pds.compute_report(&report_request3_empty)?;
     assert_eq!(report3_empty.filtered_report.bin_value, None);
 
     // Test restricting report_global_sensitivity
     pds.register_event(event4.clone())?;
-    let report_request3_over_budget = SimpleLastTouchHistogramRequest {
-        epoch_start: 1,
-        epoch_end: 3,
-        report_global_sensitivity: 4.0,
-        query_global_sensitivity: 5.0,
-        requested_epsilon: 5.0,
-        is_relevant_event: always_relevant_event_selector,
-        report_uris: sample_report_uris.clone(),
-    };
+    let report_request3_over_budget =
+        request(1, 3, 4.0, always_relevant_event_selector)?;
     let report3_over_budget =
         pds.compute_report(&report_request3_over_budget)?;
     assert_eq!(report3_over_budget.filtered_report.bin_value, None);
 
     // This tests the case where we meet the first event in epoch 3, below the
     // budget not used.
-    let report_request3 = SimpleLastTouchHistogramRequest {
-        epoch_start: 1,
-        epoch_end: 3,
-        report_global_sensitivity: 3.0,
-        query_global_sensitivity: 5.0,
-        requested_epsilon: 5.0,
-        is_relevant_event: always_relevant_event_selector,
-        report_uris: sample_report_uris.clone(),
-    };
+    let report_request3 = request(1, 3, 3.0, always_relevant_event_selector)?;
     let report3 = pds.compute_report(&report_request3)?;
     let bucket3 = Some((event3.event_key, 3.0));
     assert_eq!(report3.filtered_report.bin_value, bucket3);
 
     // Check that irrelevant events are ignored
-    let report_request4 = SimpleLastTouchHistogramRequest {
-        epoch_start: 1,
-        epoch_end: 3,
-        report_global_sensitivity: 3.0,
-        query_global_sensitivity: 5.0,
-        requested_epsilon: 5.0,
-        is_relevant_event: SimpleRelevantEventSelector {
+    let report_request4 = request(
+        1,
+        3,
+        3.0,
+        SimpleRelevantEventSelector {
             lambda: |e: &SimpleEvent| e.event_key == 1,
         },
-        report_uris: sample_report_uris.clone(),
-    };
+    )?;
     let report4 = pds.compute_report(&report_request4)?;
     let bucket4: Option<(u64, f64)> = None;
     assert_eq!(report4.filtered_report.bin_value, bucket4);
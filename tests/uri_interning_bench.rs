@@ -0,0 +1,101 @@
+use std::time::{Duration, Instant};
+
+use pdslib::{
+    budget::traits::FilterStorage as _,
+    events::{
+        ppa_event::PpaEvent,
+        traits::{EventStorage as _, EventUris, Uri},
+    },
+    pds::{
+        aliases::{PpaEventStorage, PpaFilterStorage, PpaPds},
+        quotas::StaticCapacities,
+    },
+    queries::{
+        builder::PpaHistogramRequestBuilder,
+        ppa_histogram::{FilterDataPredicate, RequestedBuckets},
+        traits::ReportRequestUris,
+    },
+    util::interner::InternedUri,
+};
+
+/// Registers events and computes reports with URIs of type `U`, and returns
+/// the elapsed time along with the total value of each report.
+fn run<U: Uri>(
+    event_uris: EventUris<U>,
+    report_uris: ReportRequestUris<U>,
+) -> anyhow::Result<(Duration, Vec<f64>)> {
+    let start = Instant::now();
+    let filters = PpaFilterStorage::<U>::new(StaticCapacities::mock())?;
+    let mut pds = PpaPds::<PpaFilterStorage<U>, PpaEventStorage<U>, U>::new(
+        filters,
+        PpaEventStorage::<U>::new(),
+    );
+
+    let mut bin_values = vec![];
+    for epoch_id in 10..1000 {
+        for event_id in 0..100 {
+            pds.event_storage.add_event(PpaEvent {
+                id: event_id,
+                timestamp: epoch_id * 100 + event_id,
+                epoch_number: epoch_id,
+                histogram_index: event_id,
+                uris: event_uris.clone(),
+                filter_data: 0,
+                priority: 0,
+            })?;
+        }
+
+        let request = PpaHistogramRequestBuilder::new()
+            .epoch_range(epoch_id - 10, epoch_id)?
+            .value(1.0, 2.0, 1.0)?
+            .uris(report_uris.clone())?
+            .selector(
+                FilterDataPredicate::Any,
+                101,
+                RequestedBuckets::AllBuckets,
+            )?
+            .build();
+        let report = pds.compute_report(&request)?;
+        bin_values.push(report.filtered_report.bin_values.values().sum());
+    }
+
+    Ok((start.elapsed(), bin_values))
+}
+
+#[test]
+#[ignore]
+fn bench_uri_interning() -> anyhow::Result<()> {
+    // Long URIs, as with real sites, to make the cost of cloning visible.
+    let uri = |site: &str| format!("https://www.{site}.example.com/path");
+    let event_uris = EventUris {
+        source_uri: uri("source"),
+        trigger_uris: vec![uri("trigger")],
+        querier_uris: vec![uri("querier"), uri("other-querier")],
+    };
+    let report_uris = ReportRequestUris {
+        trigger_uri: uri("trigger"),
+        source_uris: vec![uri("source")],
+        querier_uris: vec![uri("querier")],
+        campaign_id: None,
+    };
+
+    let (string_time, string_bins) =
+        run(event_uris.clone(), report_uris.clone())?;
+    let (interned_time, interned_bins) = run(
+        event_uris.map_uris(InternedUri::from),
+        report_uris.map_uris(InternedUri::from),
+    )?;
+
+    // Interning doesn't change the results.
+    assert_eq!(string_bins, interned_bins);
+
+    println!(
+        "String URIs: {string_time:?}, {} bytes per URI plus the heap copy",
+        size_of::<String>()
+    );
+    println!(
+        "Interned URIs: {interned_time:?}, {} bytes per URI",
+        size_of::<InternedUri>()
+    );
+    Ok(())
+}